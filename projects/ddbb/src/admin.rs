@@ -0,0 +1,173 @@
+// src/admin.rs
+
+/*
+* Administrative commands
+*
+* What an operator does to a running database, without restarting the process that has it
+* open:
+*
+*   COMPACT                         rewrite the log into the snapshot, see `LogManager::compact`
+*   FLUSH                           the same if anything was written since the last one, see
+*                                   `LogManager::flush` and flush.rs
+*   BACKUP <dir>                    write a copy of the database to <dir>, see
+*                                   `LogManager::backup`
+*   STATS                           storage statistics, as pairs "<name> <value>", and the
+*                                   latencies of histogram.rs if they are recorded
+*   MERKLE <depth> [<leaf>]         the Merkle tree of the pairs, as pairs "<node> <hash>", or
+*                                   the pairs of one of its leaves, see merkle.rs
+*
+* The TCP server takes them as requests next to the queries (see server.rs), the command line
+* tool as commands (see main.rs). Keywords are case insensitive like the ones of query.rs, and
+* the directory of a BACKUP is one token, on the file system of the process that runs it.
+*
+* They are not queries: they touch no key in particular but the whole database, so with
+* authentication they need access to every key, read-write (see `Acl::check_admin`), and they
+* cannot be batched. A COMPACT, a BACKUP or a MERKLE holds the database for as long as it
+* writes or reads, which is about as long as reading all of it.
+*/
+
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use crate::merkle::{self, MerkleTree};
+use crate::query::QueryResult;
+use crate::scan::ScanOptions;
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
+
+/// An administrative command, see the top of this file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Admin {
+    Compact,
+    Flush,
+    Backup(String),
+    Stats,
+    Merkle { depth: u32, leaf: Option<usize> },
+}
+
+impl Admin {
+    /// The command of `input`, None if it is not one (it may be a query).
+    pub fn parse(input: &str) -> Result<Option<Self>> {
+        let fields: Vec<&str> = input.split_whitespace().collect();
+        let Some((command, arguments)) = fields.split_first() else {
+            return Ok(None);
+        };
+        let command = command.to_ascii_uppercase();
+        let admin = match (command.as_str(), arguments) {
+            ("COMPACT", []) => Admin::Compact,
+            ("FLUSH", []) => Admin::Flush,
+            ("STATS", []) => Admin::Stats,
+            ("BACKUP", [dir]) => Admin::Backup(dir.to_string()),
+            ("BACKUP", _) => return Err(Error::InvalidArgument("BACKUP takes a directory".to_string())),
+            ("MERKLE", [depth, leaf @ ..]) if leaf.len() <= 1 => {
+                let bad = |number: &str| Error::InvalidArgument(format!("bad MERKLE {}", number));
+                let depth = depth.parse().map_err(|_| bad(depth))?;
+                let leaf = leaf.first().map(|leaf| leaf.parse().map_err(|_| bad(leaf))).transpose()?;
+                Admin::Merkle { depth, leaf }
+            }
+            ("MERKLE", _) => {
+                return Err(Error::InvalidArgument("MERKLE takes a depth, and maybe a leaf".to_string()));
+            }
+            ("COMPACT" | "FLUSH" | "STATS", _) => {
+                return Err(Error::InvalidArgument(format!("{} takes no arguments", command)));
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(admin))
+    }
+}
+
+impl Display for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Admin::Compact => write!(f, "COMPACT"),
+            Admin::Flush => write!(f, "FLUSH"),
+            Admin::Backup(dir) => write!(f, "BACKUP {}", dir),
+            Admin::Stats => write!(f, "STATS"),
+            Admin::Merkle { depth, leaf: None } => write!(f, "MERKLE {}", depth),
+            Admin::Merkle { depth, leaf: Some(leaf) } => write!(f, "MERKLE {} {}", depth, leaf),
+        }
+    }
+}
+
+/// Run `admin` on `db`: Done, or Pairs for STATS and MERKLE.
+pub fn execute<K, V>(db: &mut LogManager<K, V>, admin: &Admin) -> Result<QueryResult>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    match admin {
+        Admin::Compact => db.compact()?,
+        Admin::Flush => db.flush()?,
+        Admin::Backup(dir) => db.backup(dir)?,
+        Admin::Stats => return Ok(QueryResult::Pairs { pairs: stats(db), next: None }),
+        Admin::Merkle { depth, leaf } => {
+            if *depth > merkle::MAX_DEPTH || leaf.is_some_and(|leaf| leaf >> depth != 0) {
+                let most = merkle::MAX_DEPTH;
+                let message = format!("{}: the depth is at most {}, a leaf below 2^depth", admin, most);
+                return Err(Error::InvalidArgument(message));
+            }
+            let page = db.scan(.., &ScanOptions::default())?;
+            let mut pairs: Vec<(String, String)> =
+                page.pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            match leaf {
+                Some(leaf) => pairs.retain(|(key, _)| merkle::leaf(key, *depth) == *leaf),
+                None => {
+                    let strs = pairs.iter().map(|(key, value)| (key.as_str(), value.as_str()));
+                    let tree = MerkleTree::build(*depth, strs)?;
+                    let nodes = tree.nodes().iter().enumerate();
+                    pairs = nodes.map(|(i, hash)| ((i + 1).to_string(), format!("{:016x}", hash))).collect();
+                }
+            }
+            return Ok(QueryResult::Pairs { pairs, next: None });
+        }
+    }
+    Ok(QueryResult::Done)
+}
+
+/// The statistics of STATS, names and values without whitespace.
+pub fn stats<K, V>(db: &LogManager<K, V>) -> Vec<(String, String)>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    let tombstones = db.tombstone_stats();
+    let flushes = db.flush_stats();
+    let recovery = db.recovery_report();
+    let mut stats: Vec<(String, String)> = [
+        ("pairs", db.count_range::<std::ops::RangeFull>(..).to_string()),
+        ("tombstones", tombstones.live.to_string()),
+        ("tombstones_collected", tombstones.collected_total.to_string()),
+        ("flushes", flushes.flushes.to_string()),
+        ("flush_stall_ms", flushes.stall.as_millis().to_string()),
+        ("checkpoint", db.checkpoint().map_or("none".to_string(), |generation| generation.to_string())),
+        ("lsn", db.lsn().to_string()),
+        ("recovery_records_replayed", recovery.records_replayed.to_string()),
+        ("recovery_corrupt_records_skipped", recovery.corrupt_records_skipped.to_string()),
+        ("recovery_torn_bytes_discarded", recovery.torn_bytes_discarded.to_string()),
+        ("recovery_ms", recovery.elapsed.as_millis().to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect();
+    if let Some(latencies) = db.latency_histograms() {
+        // insert_count, insert_mean_us, insert_p50_us, ... in microseconds
+        for (operation, histogram) in latencies.by_operation() {
+            stats.push((format!("{}_count", operation), histogram.count().to_string()));
+            let percentiles = [
+                ("mean", histogram.mean()),
+                ("p50", histogram.percentile(50.0)),
+                ("p99", histogram.percentile(99.0)),
+                ("p999", histogram.percentile(99.9)),
+                ("max", histogram.max()),
+            ];
+            for (name, latency) in percentiles {
+                stats.push((format!("{}_{}_us", operation, name), latency.as_micros().to_string()));
+            }
+        }
+    }
+    stats
+}
@@ -0,0 +1,224 @@
+// src/auth.rs
+
+/*
+* Authentication and access control
+*
+* A server (server.rs, http.rs) given an `Auth` in its `ServerOptions` only answers clients
+* that proved who they are, and only with what they may see. `Auth` is a list of credentials,
+* each with the `Acl` of whoever holds them:
+*
+*   token <token> <access> [prefix...]
+*   user <name> <password> <access> [prefix...]
+*
+* (the format of `Auth::parse`, one per line, # starts a comment). <access> is "read-only"
+* (GET, SCAN and COUNT) or "read-write" (all queries). The prefixes restrict the keys to those
+* that start with one of them, no prefix is every key. A token is one secret, for programs; a
+* user is a name and a password, for people.
+*
+* On the TCP protocol a connection is unauthenticated until it sends
+*
+*   AUTH <token>                     OK, or ERR permission denied: ...
+*   AUTH <name> <password>
+*
+* and every query before that fails with "permission denied". An AUTH that fails leaves the
+* connection unauthenticated, one that succeeds replaces the Acl of an earlier one. Over HTTP
+* the credentials come with every request, see http.rs.
+*
+* What an Acl allows
+*
+* `Acl::check` looks at the keys a query could touch, not at the ones it happens to find: a
+* GET, SET or DEL of a key outside the prefixes fails even if the key is not there, and so
+* does a SCAN or COUNT that could reach such a key. A prefix query is allowed inside one of
+* the prefixes ("user:1*" with the prefix "user:"), a range if both of its ends are inside the
+* same prefix ("user:1..user:5"; the keys of a prefix are contiguous, so everything between
+* is too). An open-ended range only works with access to every key, and so do the commands
+* of admin.rs (COMPACT, BACKUP, ...), which also need read-write access.
+*
+* Secrets
+*
+* The secrets are kept as given, this is for a configuration file the server's owner keeps
+* private, and compared in time that does not depend on where they differ. `Credentials` do
+* not print their secrets with `Debug`, so they stay out of logs.
+*/
+
+use crate::error::{Error, Result};
+use crate::query::{Keys, Query};
+use std::fmt;
+use std::path::Path;
+
+/// What the holder of some credentials may do, see the top of this file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+    /// Only GET, SCAN and COUNT.
+    pub read_only: bool,
+    /// Only the keys that start with one of these, None for every key.
+    pub prefixes: Option<Vec<String>>,
+}
+
+impl Acl {
+    /// Ok if the query is allowed, a PermissionDenied if not.
+    pub fn check(&self, query: &Query) -> Result<()> {
+        if self.read_only && matches!(query, Query::Set(..) | Query::Del(_)) {
+            return Err(denied("the access is read-only".to_string()));
+        }
+        let allowed = match query {
+            Query::Get(key) | Query::Set(key, _) | Query::Del(key) => self.inside(key),
+            Query::Scan { keys, .. } | Query::Count(keys) => self.covers(keys),
+        };
+        if !allowed {
+            return Err(denied(format!("no access to the keys of {}", query)));
+        }
+        Ok(())
+    }
+
+    /// Ok if all of `keys` may be read, a PermissionDenied if not.
+    pub fn check_keys(&self, keys: &Keys) -> Result<()> {
+        if !self.covers(keys) {
+            return Err(denied(format!("no access to the keys {}", keys)));
+        }
+        Ok(())
+    }
+
+    fn inside(&self, key: &str) -> bool {
+        match &self.prefixes {
+            Some(prefixes) => prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())),
+            None => true,
+        }
+    }
+
+    fn covers(&self, keys: &Keys) -> bool {
+        let Some(prefixes) = &self.prefixes else {
+            return true;
+        };
+        match keys {
+            Keys::Prefix(prefix) => self.inside(prefix),
+            Keys::Range { start: Some(start), end: Some(end) } => prefixes
+                .iter()
+                .any(|prefix| start.starts_with(prefix.as_str()) && end.starts_with(prefix.as_str())),
+            Keys::Range { .. } => false,
+        }
+    }
+
+    /// Ok if the commands of admin.rs are allowed, which takes read-write access to every key.
+    pub fn check_admin(&self) -> Result<()> {
+        if self.read_only || self.prefixes.is_some() {
+            return Err(denied("admin commands need read-write access to every key".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// The proof of who a client is.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Token(String),
+    User { name: String, password: String },
+}
+
+impl Credentials {
+    /// From the fields after AUTH, see the top of this file.
+    pub(crate) fn from_fields(fields: &[&str]) -> Result<Self> {
+        match fields {
+            [token] => Ok(Credentials::Token(token.to_string())),
+            [name, password] => {
+                Ok(Credentials::User { name: name.to_string(), password: password.to_string() })
+            }
+            _ => Err(Error::InvalidArgument("AUTH takes a token, or a user name and a password".to_string())),
+        }
+    }
+
+    /// The AUTH request for these credentials.
+    pub(crate) fn request(&self) -> Result<String> {
+        let fields = match self {
+            Credentials::Token(token) => vec![token],
+            Credentials::User { name, password } => vec![name, password],
+        };
+        if fields.iter().any(|field| field.is_empty() || field.contains(char::is_whitespace)) {
+            return Err(Error::InvalidArgument("credentials cannot be empty or hold whitespace".to_string()));
+        }
+        Ok(format!("AUTH {}", fields.iter().map(|field| field.as_str()).collect::<Vec<_>>().join(" ")))
+    }
+
+    fn matches(&self, other: &Credentials) -> bool {
+        match (self, other) {
+            (Credentials::Token(a), Credentials::Token(b)) => same_secret(a, b),
+            (Credentials::User { name: a, password: p }, Credentials::User { name: b, password: q }) => {
+                a == b && same_secret(p, q)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => write!(f, "Token(..)"),
+            Credentials::User { name, .. } => write!(f, "User {{ name: {:?}, .. }}", name),
+        }
+    }
+}
+
+// Equal or not, in a time that only depends on the lengths
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn denied(message: String) -> Error {
+    Error::PermissionDenied(message)
+}
+
+/// The credentials a server accepts, see the top of this file.
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    entries: Vec<(Credentials, Acl)>,
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept `credentials`, for what `acl` allows.
+    pub fn add(&mut self, credentials: Credentials, acl: Acl) {
+        self.entries.push((credentials, acl));
+    }
+
+    /// From the lines of the format at the top of this file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut auth = Auth::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let bad = || Error::InvalidArgument(format!("bad line {}: {}", number + 1, line.trim()));
+            let (credentials, rest) = match fields[..] {
+                [] => continue,
+                ["token", token, ref rest @ ..] => (Credentials::Token(token.to_string()), rest),
+                ["user", name, password, ref rest @ ..] => {
+                    (Credentials::User { name: name.to_string(), password: password.to_string() }, rest)
+                }
+                _ => return Err(bad()),
+            };
+            let (read_only, prefixes) = match rest {
+                ["read-only", prefixes @ ..] => (true, prefixes),
+                ["read-write", prefixes @ ..] => (false, prefixes),
+                _ => return Err(bad()),
+            };
+            let prefixes: Vec<String> = prefixes.iter().map(|prefix| prefix.to_string()).collect();
+            let prefixes = (!prefixes.is_empty()).then_some(prefixes);
+            auth.add(credentials, Acl { read_only, prefixes });
+        }
+        Ok(auth)
+    }
+
+    /// Same as `parse`, reading the file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The Acl of `credentials`, a PermissionDenied if they are not accepted.
+    pub fn authenticate(&self, credentials: &Credentials) -> Result<&Acl> {
+        let entry = self.entries.iter().find(|(known, _)| known.matches(credentials));
+        entry.map(|(_, acl)| acl).ok_or_else(|| denied("bad credentials".to_string()))
+    }
+}
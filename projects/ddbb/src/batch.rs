@@ -0,0 +1,81 @@
+// src/batch.rs
+
+/*
+* Write batches
+*
+* A `WriteBatch` is a list of inserts and deletes that `LogManager::write_batch` applies as
+* one: after a crash either all of them are there or none is, and a reader of the LogManager
+* never sees some of them without the others.
+*
+*   let mut batch = WriteBatch::new();
+*   batch.insert(from, balance - 10);
+*   batch.insert(to, other_balance + 10);
+*   db.write_batch(batch)?;
+*
+* The writes apply in the order they were added, so a key written twice ends up with the last
+* of them. An insert that would break a unique index (see index.rs) fails the whole batch, and
+* then nothing of it is applied or logged. Checking that is done against the state the earlier
+* writes of the batch made: deleting a key and giving its indexed value to another one in the
+* same batch is fine.
+*
+* The batch is one record of the log (see log.rs), "BATCH <n>" followed by the n writes. The
+* checksum covers all of them and a torn or damaged record is dropped as a whole, which is
+* what makes the batch atomic on disk. Like every record it is one line, so a batch is meant
+* to be a handful to a few thousand writes, not a bulk load.
+*/
+
+/// One write of a `WriteBatch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp<K, V> {
+    Insert(K, V),
+    Delete(K),
+}
+
+impl<K, V> BatchOp<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            BatchOp::Insert(key, _) | BatchOp::Delete(key) => key,
+        }
+    }
+}
+
+/// Writes applied together, see the top of this file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteBatch<K, V> {
+    ops: Vec<BatchOp<K, V>>,
+}
+
+impl<K, V> WriteBatch<K, V> {
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> &mut Self {
+        self.ops.push(BatchOp::Insert(key, value));
+        self
+    }
+
+    pub fn delete(&mut self, key: K) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key));
+        self
+    }
+
+    /// The writes, in the order they apply.
+    pub fn ops(&self) -> &[BatchOp<K, V>] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl<K, V> Default for WriteBatch<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
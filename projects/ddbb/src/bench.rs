@@ -0,0 +1,314 @@
+// src/bench.rs
+
+/*
+* Benchmarks
+*
+* A `Workload` is a YCSB-style benchmark: it loads `records` pairs into a store, then runs
+* `operations` operations against it, each one of
+*
+*   read      get a key
+*   update    set a key to a new value
+*   insert    set a key that was not loaded yet (the next one after the last)
+*   scan      read the pairs of up to `max_scan` keys in a row
+*   rmw       read a key, then set it (read-modify-write)
+*
+* in the proportions of the workload, with the keys picked by its `Distribution`:
+*
+*   Uniform   every key as likely as the others
+*   Zipfian   a few keys take most of the operations (constant 0.99, as YCSB), spread over the
+*             keyspace so that the hot keys are not all next to each other
+*   Latest    like Zipfian, the hottest keys being the last ones inserted
+*
+* The keys are "user" and 10 digits, so that scans go through them in order, and the values
+* `value_size` random letters. `Workload::preset` gives the core workloads of YCSB:
+*
+*   A  50% read, 50% update, zipfian         (a session store)
+*   B  95% read, 5% update, zipfian          (photo tagging)
+*   C  100% read, zipfian                    (a cache)
+*   D  95% read, 5% insert, latest           (status updates)
+*   E  95% scan, 5% insert, zipfian          (threaded conversations)
+*   F  50% read, 50% rmw, zipfian            (a user database)
+*
+* `run` drives any store of testing.rs (a LogManager or an LsmTree, or a `Subject` of your own)
+* on the thread that calls it, and reports the throughput of both phases and the latencies of
+* every kind of operation: mean, percentiles and maximum, from every single one of them. The
+* command line tool runs it as `ddbb <dir> bench` (see main.rs).
+*/
+
+use crate::error::{Error, Result};
+use crate::shard;
+use crate::testing::Subject;
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+/// How a workload picks its keys, see the top of this file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    Uniform,
+    Zipfian,
+    Latest,
+}
+
+/// A benchmark, see the top of this file. The proportions do not have to add up to 1, they
+/// are weights.
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub records: u64,
+    pub operations: u64,
+    pub read: f64,
+    pub update: f64,
+    pub insert: f64,
+    pub scan: f64,
+    pub rmw: f64,
+    pub distribution: Distribution,
+    pub value_size: usize,
+    /// The most keys a scan reads, the number is picked uniformly from 1.
+    pub max_scan: u64,
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            records: 10_000,
+            operations: 10_000,
+            read: 0.5,
+            update: 0.5,
+            insert: 0.0,
+            scan: 0.0,
+            rmw: 0.0,
+            distribution: Distribution::Zipfian,
+            value_size: 100,
+            max_scan: 100,
+            seed: 0,
+        }
+    }
+}
+
+impl Workload {
+    /// The core workload `name` of YCSB, "a" to "f", see the top of this file.
+    pub fn preset(name: &str) -> Result<Self> {
+        let mix = |read, update, insert, scan, rmw, distribution| Workload {
+            read,
+            update,
+            insert,
+            scan,
+            rmw,
+            distribution,
+            ..Workload::default()
+        };
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "a" => mix(0.5, 0.5, 0.0, 0.0, 0.0, Distribution::Zipfian),
+            "b" => mix(0.95, 0.05, 0.0, 0.0, 0.0, Distribution::Zipfian),
+            "c" => mix(1.0, 0.0, 0.0, 0.0, 0.0, Distribution::Zipfian),
+            "d" => mix(0.95, 0.0, 0.05, 0.0, 0.0, Distribution::Latest),
+            "e" => mix(0.0, 0.0, 0.05, 0.95, 0.0, Distribution::Zipfian),
+            "f" => mix(0.5, 0.0, 0.0, 0.0, 0.5, Distribution::Zipfian),
+            _ => return Err(Error::InvalidArgument(format!("there is no workload {}, only a to f", name))),
+        })
+    }
+
+    /// Load the records into `store`, then run the operations, see the top of this file.
+    pub fn run<S: Subject>(&self, store: &mut S) -> Result<BenchReport> {
+        let weights = [self.read, self.update, self.insert, self.scan, self.rmw];
+        let negative = weights.iter().any(|weight| *weight < 0.0);
+        if self.records == 0 || negative || weights.iter().sum::<f64>() <= 0.0 {
+            let message = "a workload needs records, and operations with positive proportions";
+            return Err(Error::InvalidArgument(message.to_string()));
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let started = Instant::now();
+        for i in 0..self.records {
+            store.set(&key(i), &self.value(&mut rng))?;
+        }
+        let load = Phase { operations: self.records, elapsed: started.elapsed() };
+
+        let mut latencies: [Vec<Duration>; 5] = Default::default();
+        let mut zipfian = Zipfian::new(self.records);
+        let mut count = self.records;
+        let total: f64 = weights.iter().sum();
+        let started = Instant::now();
+        for _ in 0..self.operations {
+            let mut pick = rng.gen::<f64>() * total;
+            let kind = weights.iter().position(|weight| {
+                pick -= weight;
+                pick < 0.0
+            });
+            let kind = kind.unwrap_or_else(|| weights.iter().rposition(|weight| *weight > 0.0).unwrap());
+            let i = match self.distribution {
+                Distribution::Uniform => rng.gen_range(0..count),
+                Distribution::Zipfian => shard::hash(&zipfian.next(&mut rng, count).to_string()) % count,
+                Distribution::Latest => count - 1 - zipfian.next(&mut rng, count),
+            };
+            let value = self.value(&mut rng);
+            let scan = rng.gen_range(1..=self.max_scan.max(1));
+            let operation = Instant::now();
+            match kind {
+                0 => {
+                    store.get(&key(i))?;
+                }
+                1 => store.set(&key(i), &value)?,
+                2 => {
+                    store.set(&key(count), &value)?;
+                    count += 1;
+                }
+                3 => {
+                    store.range(&key(i), &key(i + scan))?;
+                }
+                _ => {
+                    store.get(&key(i))?;
+                    store.set(&key(i), &value)?;
+                }
+            }
+            latencies[kind].push(operation.elapsed());
+        }
+        let run = Phase { operations: self.operations, elapsed: started.elapsed() };
+
+        let names = ["read", "update", "insert", "scan", "rmw"];
+        let latencies = names
+            .into_iter()
+            .zip(latencies)
+            .filter(|(_, latencies)| !latencies.is_empty())
+            .map(|(name, latencies)| Latencies::of(name, latencies))
+            .collect();
+        Ok(BenchReport { load, run, latencies })
+    }
+
+    fn value(&self, rng: &mut StdRng) -> String {
+        rng.sample_iter(&Alphanumeric).take(self.value_size.max(1)).map(char::from).collect()
+    }
+}
+
+/// The key of record `i`.
+pub fn key(i: u64) -> String {
+    format!("user{:010}", i)
+}
+
+/// Ranks from 0 (the most likely) with the Zipfian distribution of YCSB, for a number of items
+/// that may grow.
+pub struct Zipfian {
+    items: u64,
+    zeta: f64,
+    zeta2: f64,
+}
+
+const THETA: f64 = 0.99;
+
+impl Zipfian {
+    pub fn new(items: u64) -> Self {
+        let mut zipfian = Zipfian { items: 0, zeta: 0.0, zeta2: zeta(0, 2, 0.0) };
+        zipfian.resize(items);
+        zipfian
+    }
+
+    /// The next rank, below `items`.
+    pub fn next(&mut self, rng: &mut impl Rng, items: u64) -> u64 {
+        if items != self.items {
+            self.resize(items);
+        }
+        let alpha = 1.0 / (1.0 - THETA);
+        let eta = (1.0 - (2.0 / items as f64).powf(1.0 - THETA)) / (1.0 - self.zeta2 / self.zeta);
+        let u: f64 = rng.gen();
+        let uz = u * self.zeta;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(THETA) {
+            return 1.min(items - 1);
+        }
+        ((items as f64 * (eta * u - eta + 1.0).powf(alpha)) as u64).min(items - 1)
+    }
+
+    // Adding to the sum when there are more items, instead of starting over
+    fn resize(&mut self, items: u64) {
+        self.zeta =
+            if items >= self.items { zeta(self.items, items, self.zeta) } else { zeta(0, items, 0.0) };
+        self.items = items;
+    }
+}
+
+// The sum of 1 / i^THETA for i from 1 to `to`, with `sum` that of the first `from`
+fn zeta(from: u64, to: u64, sum: f64) -> f64 {
+    (from..to).fold(sum, |sum, i| sum + 1.0 / ((i + 1) as f64).powf(THETA))
+}
+
+/// The operations of a phase of a benchmark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Phase {
+    pub operations: u64,
+    pub elapsed: Duration,
+}
+
+impl Phase {
+    /// Operations per second.
+    pub fn throughput(&self) -> f64 {
+        self.operations as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// The latencies of one kind of operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Latencies {
+    pub operation: &'static str,
+    pub count: usize,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl Latencies {
+    fn of(operation: &'static str, mut latencies: Vec<Duration>) -> Self {
+        latencies.sort();
+        let count = latencies.len();
+        // the nearest rank
+        let percentile = |p: f64| latencies[((p * count as f64).ceil() as usize).clamp(1, count) - 1];
+        Latencies {
+            operation,
+            count,
+            mean: latencies.iter().sum::<Duration>() / count as u32,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+            max: latencies[count - 1],
+        }
+    }
+}
+
+/// What `Workload::run` measured.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchReport {
+    pub load: Phase,
+    pub run: Phase,
+    /// By kind of operation, those the run did.
+    pub latencies: Vec<Latencies>,
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, phase) in [("load", &self.load), ("run", &self.run)] {
+            writeln!(
+                f,
+                "{}: {} operations in {:.3}s, {:.0} ops/s",
+                name,
+                phase.operations,
+                phase.elapsed.as_secs_f64(),
+                phase.throughput()
+            )?;
+        }
+        for l in &self.latencies {
+            writeln!(
+                f,
+                "{:<6} count {}  mean {:?}  p50 {:?}  p95 {:?}  p99 {:?}  p99.9 {:?}  max {:?}",
+                l.operation, l.count, l.mean, l.p50, l.p95, l.p99, l.p999, l.max
+            )?;
+        }
+        Ok(())
+    }
+}
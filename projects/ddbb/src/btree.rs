@@ -61,6 +61,7 @@
 */
 
 use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
 
 const B: usize = 3; // minimum degree
 
@@ -73,6 +74,7 @@ pub struct BTree<K: Ord + Clone + Debug, V: Clone + Debug> {
 pub struct Node<K: Ord + Clone + Debug, V: Clone + Debug> {
     keys: Vec<K>,
     values: Vec<V>,
+    #[allow(clippy::vec_box)]
     children: Vec<Box<Node<K, V>>>,
 }
 
@@ -117,6 +119,102 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
         }
     }
 
+    pub fn range<R: RangeBounds<K>>(&self, range: &R) -> Vec<(K, V)> {
+        // Same as traverse, but only the pairs whose key is in `range`
+        let mut kv_pairs = Vec::new();
+        self.visit_range(range, |key, value| {
+            kv_pairs.push((key.clone(), value.clone()));
+            true
+        });
+        kv_pairs
+    }
+
+    pub fn visit_range<R: RangeBounds<K>>(&self, range: &R, mut visit: impl FnMut(&K, &V) -> bool) {
+        // Hands the pairs whose key is in `range` to `visit` in key order, without cloning them,
+        // until `visit` returns false
+        if let Some(root) = &self.root {
+            Self::range_dfs(root, range, &mut visit);
+        }
+    }
+
+    pub fn visit_range_rev<R: RangeBounds<K>>(&self, range: &R, mut visit: impl FnMut(&K, &V) -> bool) {
+        // Same as visit_range, in descending key order
+        if let Some(root) = &self.root {
+            Self::range_dfs_rev(root, range, &mut visit);
+        }
+    }
+
+    // Like dfs(), but it skips the children left of the range and stops at the first key past
+    // its end (or when `visit` says so), returns false once it got there so the callers stop
+    // as well
+    fn range_dfs<R: RangeBounds<K>, F: FnMut(&K, &V) -> bool>(node: &Node<K, V>, range: &R, visit: &mut F) -> bool {
+        for i in 0..node.keys.len() {
+            let key = &node.keys[i];
+            let after_start = match range.start_bound() {
+                Bound::Included(start) | Bound::Excluded(start) => start < key,
+                Bound::Unbounded => true,
+            };
+            if after_start {
+                if let Some(child) = node.children.get(i) {
+                    if !Self::range_dfs(child, range, visit) {
+                        return false;
+                    }
+                }
+            }
+            let before_end = match range.end_bound() {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+            if !before_end {
+                return false;
+            }
+            if range.contains(key) && !visit(key, &node.values[i]) {
+                return false;
+            }
+        }
+
+        match node.children.last() {
+            Some(child) => Self::range_dfs(child, range, visit),
+            None => true,
+        }
+    }
+
+    // Mirror image of range_dfs: from the last key to the first, children[i + 1] (the keys
+    // right of keys[i]) before keys[i], stopping at the first key before the start of the range
+    fn range_dfs_rev<R: RangeBounds<K>, F: FnMut(&K, &V) -> bool>(node: &Node<K, V>, range: &R, visit: &mut F) -> bool {
+        for i in (0..node.keys.len()).rev() {
+            let key = &node.keys[i];
+            let before_end = match range.end_bound() {
+                Bound::Included(end) | Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+            if before_end {
+                if let Some(child) = node.children.get(i + 1) {
+                    if !Self::range_dfs_rev(child, range, visit) {
+                        return false;
+                    }
+                }
+            }
+            let after_start = match range.start_bound() {
+                Bound::Included(start) => key >= start,
+                Bound::Excluded(start) => key > start,
+                Bound::Unbounded => true,
+            };
+            if !after_start {
+                return false;
+            }
+            if range.contains(key) && !visit(key, &node.values[i]) {
+                return false;
+            }
+        }
+
+        match node.children.first() {
+            Some(child) => Self::range_dfs_rev(child, range, visit),
+            None => true,
+        }
+    }
+
     pub fn insert(&mut self, key: K, value: V) {
         // Insert key-value pair and handle tree updates
         if let Some(root) = &mut self.root { // if root is not None
@@ -142,7 +240,6 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
     }
 
     pub fn delete(&mut self, key: &K) -> Option<V> {
-        println!("Deleting {:?} from root", key);
         if let Some(root) = &mut self.root {
             let deleted_value = root.delete(key);
             if root.keys.is_empty() {
@@ -160,7 +257,29 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
 
     pub fn search(&self, key: &K) -> Option<&V> {
         // Search for a key and return the associated value if found
-        self.root.as_ref().and_then(|root| root.search(key))
+        self.search_counting(key, &mut 0)
+    }
+
+    pub fn search_counting(&self, key: &K, nodes_visited: &mut u64) -> Option<&V> {
+        // Same as search, adding the nodes it went through to `nodes_visited` (see explain.rs)
+        self.root.as_ref().and_then(|root| root.search(key, nodes_visited))
+    }
+
+    pub fn search_mut(&mut self, key: &K) -> Option<&mut V> {
+        // Same as search, but hands out a mutable reference so the value can be replaced in place
+        self.root.as_mut().and_then(|root| root.search_mut(key))
+    }
+
+    pub fn upsert(&mut self, key: K, value: V) -> Option<V> {
+        // Unlike insert, which keeps the existing value of a duplicate key, upsert overwrites it
+        // and hands back the old value
+        match self.search_mut(&key) {
+            Some(existing) => Some(std::mem::replace(existing, value)),
+            None => {
+                self.insert(key, value);
+                None
+            }
+        }
     }
 
 
@@ -173,6 +292,12 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<K, V> {
     }
 }
 
+impl<K: Ord + Clone + Debug, V: Clone + Debug> Default for BTree<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
     // Helper methods for B-tree operations (insert, delete, search, etc.)
     // Methods like split, merge, and other utility methods will be implemented here
@@ -280,31 +405,41 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         }
     }
 
-    fn search(&self, key: &K) -> Option<&V> {
+    fn search(&self, key: &K, nodes_visited: &mut u64) -> Option<&V> {
+        *nodes_visited += 1;
         match self.keys.binary_search(key) {
             Ok(index) => Some(&self.values[index]),
             Err(index) => {
                 if self.children.is_empty() {
                     None
                 } else {
-                    println!("Searching value '{:?}' in node: {:?}, next index: {:?}", key, self.values, index);
-                    self.children[index].search(key)
+                    self.children[index].search(key, nodes_visited)
+                }
+            }
+        }
+    }
+
+    fn search_mut(&mut self, key: &K) -> Option<&mut V> {
+        match self.keys.binary_search(key) {
+            Ok(index) => Some(&mut self.values[index]),
+            Err(index) => {
+                if self.children.is_empty() {
+                    None
+                } else {
+                    self.children[index].search_mut(key)
                 }
             }
         }
     }
 
     pub fn delete(&mut self, key: &K) -> Option<V> {
-        println!("Deleting key '{:?}' from node: {:?}", key, self.keys);
-        match self.keys.binary_search(&key) {
+        match self.keys.binary_search(key) {
             Ok(index) => {
-                println!("Found key at index: {:?}", index);
                 if self.children.is_empty() {
                     // Case 1: The key is in the current node and it's a leaf node
                     // Then we just simply remove the key and value
-                    println!("Case 1: The key '{:?}' is on the leaf node, remove it directly.", key);
                     self.keys.remove(index);
-                    return Some(self.values.remove(index));
+                    Some(self.values.remove(index))
                 } else {
                     // Case 2: The key is in the current node and it's an internal node
                     // To maintain the B-Tree properties, we cannot just remove the key and its
@@ -323,10 +458,9 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         * smaller than the key at index (keys). that is called the left part of tree.
                         */
                         let (pred_key, pred_value) = self.children[index].find_predecessor();
-                        println!("Case 2a: The key '{:?}' is deleted since it is on the internal node", key);
                         self.keys[index] = pred_key.clone();
                         self.values[index] = pred_value.clone();
-                        return self.children[index].delete(&pred_key); // recursive
+                        self.children[index].delete(&pred_key) // recursive
                     } else if self.children[index + 1].keys.len() >= B {
                         // Case 2b: If the left child doesn't have enough keys, we check if the
                         // right child has at least B keys. If it does, we find the successor of 
@@ -334,10 +468,9 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         // the key and its value in the current node, and then recursively delete
                         // the successor key from the left child.
                         let (succ_key, succ_value) = self.children[index + 1].find_successor();
-                        println!("Case 2b: The key '{:?}' is deleted since it is on the internal node", key);
                         self.keys[index] = succ_key.clone();
                         self.values[index] = succ_value.clone();
-                        return self.children[index + 1].delete(&succ_key); // recursive
+                        self.children[index + 1].delete(&succ_key) // recursive
                     } else {
                         // Case 2c: If both the left and right children have less than B keys
                         // we merge the current node with the left child and then recursively
@@ -365,22 +498,18 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         *
                         * Then we perform delete on that left child.
                         */
-                        println!("Case 2c: The key '{:?}' is removed on merge_with_left, \
-                            and we move our left and right sibling together", key);
                         self.merge_with_left(index+1); 
                         self.children.remove(index+1);
-                        return self.children[index].delete(key);
+                        self.children[index].delete(key)
                     }
                 }
             }
 
             Err(index) => {
                 // Case 3: The key is not in the current node
-                println!("Case 3: The key '{:?}' is not in the current node, the desired index is {:?}", key, index);
                 if self.children.is_empty() {
                     // Case 3a: If the current node is a leaf node, then the key is not in the tree
-                    println!("Case 3a: If the current node is a leaf node, then the key is not in the tree, NOT FOUND");
-                    return None;
+                    None
                 } else {
                     // Case 3b: If the current node is an internal node, we need to ensure that the
                     // child node at the target index has at least B keys before recursively
@@ -389,7 +518,6 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         if index > 0 && self.children[index - 1].keys.len() >= B {
                             // Case 3b1: If the left sibling (at index-1) exists and has at least B
                             // keys, borrow a key from the left sibling
-                            println!("Case 3b1: If the left sibling (at index-1) exists and has at least B keys, borrow a key from the left sibling");
                             self.borrow_from_left(index);
                             let borrowed_key = self.keys.remove(index);
                             let borrowed_value = self.values.remove(index);
@@ -398,7 +526,6 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         } else if index < self.children.len() - 1 && self.children[index + 1].keys.len() >= B {
                             // Case 3b2: If the right sibling (at index+1) exists and has at least
                             // B keys, borrow a key from the right sibling
-                            println!("Case 3b2: If the right sibling (at index+1) exists and has at least B keys, borrow a key from the right sibling");
                             self.borrow_from_right(index);
                             let borrowed_key = self.keys.remove(index+1);
                             let borrowed_value = self.values.remove(index+1);
@@ -407,13 +534,11 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
                         } else if index > 0 {
                             // Case 3b3: if the left sibling exists but has less than B keys, merge the child
                             // with the left sibling
-                            println!("Case 3b3: if the left sibling exists but has less than B keys, merge the child with the left sibling");
                             self.merge_with_left(index);
                             self.children.remove(index);
                             return self.children[index-1].delete(key);
                         } else {
                             // Case 3b4: if the left sibling doesn't exist, merge the child with the right sibling
-                            println!("Case 3b4: if the left sibling doesn't exist, merge the child with the right sibling");
                             self.merge_with_right(index);
                             self.children.remove(index+1);
                         }
@@ -455,10 +580,8 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         let right_sibling_key = right_sibling.keys.remove(0);
         let right_sibling_value = right_sibling.values.remove(0);
 
-        println!("right_sibling_key: {:?}", right_sibling_key);
         self.keys.insert(index, right_sibling_key);
         self.values.insert(index, right_sibling_value);
-        println!("self.keys: {:?}", self.keys);
 
         // Move the leftmost child of the right sibling to the rightmost child of the current node
         if !right_sibling.children.is_empty() {
@@ -477,10 +600,8 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         let (left_children, right_children) = self.children.split_at_mut(index);
 
         let left_sibling = &mut left_children[index - 1]; // merge the current node with the left sibling
-        println!("left_sibling.keys: {:?}", left_sibling.keys);
 
         let current_node = &mut right_children[0];        // the right child of the key you want to delete
-        println!("current_node.keys: {:?}", current_node.keys);
 
         // 2. move the deleted key and value to the left sibling, as well as the right child keys
         // so after that the left sibling will have 2B-1 keys in total
@@ -491,8 +612,6 @@ impl<K: Ord + Clone + Debug, V: Clone + Debug> Node<K, V> {
         left_sibling.keys.append(&mut current_node.keys);
         left_sibling.values.append(&mut current_node.values);
 
-        println!("left_sibling.keys: {:?}", left_sibling.keys);
-
         if !current_node.children.is_empty() {
             left_sibling.children.append(&mut current_node.children);
         }
@@ -0,0 +1,130 @@
+// src/cache.rs
+
+/*
+* Block cache
+*
+* Every lookup in a table reads and checks one of its data blocks (see sstable.rs), and a hot
+* range of keys keeps reading the same few blocks. The block cache keeps decoded blocks in
+* memory so those reads skip the disk, the checksum and the decoding.
+*
+* One cache is shared by all the tables of an LsmTree (and can be shared between trees, see
+* `Options::block_cache`), and holds at most `capacity` bytes of block data. When it is full
+* the least recently used block is evicted: every block remembers the tick of its last use,
+* and `lru` orders the blocks by that tick.
+*
+* Blocks are keyed by (table id, block offset). Every opened table gets a new id, so the
+* blocks of a removed table are never handed out again, they just age out of the cache.
+*/
+
+use crate::sstable::Block;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type BlockKey = (u64, u64);
+
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Hit and miss counters of a BlockCache, see `BlockCache::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Bytes of block data held right now.
+    pub usage: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    /// Share of the lookups answered from memory, 0 before the first one.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// A size-bounded LRU cache of decoded table blocks, safe to share between threads.
+#[derive(Debug)]
+pub struct BlockCache {
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    lru: BTreeMap<u64, BlockKey>, // last use tick -> block, oldest first
+    tick: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    block: Arc<Block>,
+    charge: usize,
+    tick: u64,
+}
+
+impl BlockCache {
+    /// A cache holding up to `capacity` bytes of blocks.
+    pub fn new(capacity: usize) -> Self {
+        let stats = CacheStats { capacity, ..CacheStats::default() };
+        BlockCache { state: Mutex::new(CacheState { stats, ..CacheState::default() }) }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub(crate) fn get(&self, key: BlockKey) -> Option<Arc<Block>> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let CacheState { blocks, lru, stats, .. } = &mut *state;
+        match blocks.get_mut(&key) {
+            Some(cached) => {
+                lru.remove(&cached.tick);
+                lru.insert(tick, key);
+                cached.tick = tick;
+                stats.hits += 1;
+                Some(cached.block.clone())
+            }
+            None => {
+                stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache `block`, which takes `charge` bytes, evicting the least recently used blocks to
+    /// make room.
+    pub(crate) fn insert(&self, key: BlockKey, block: Arc<Block>, charge: usize) {
+        let mut state = self.state.lock().unwrap();
+        if charge > state.stats.capacity {
+            return;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(old) = state.blocks.insert(key, CachedBlock { block, charge, tick }) {
+            // read by two threads at once
+            state.lru.remove(&old.tick);
+            state.stats.usage -= old.charge;
+        }
+        state.lru.insert(tick, key);
+        state.stats.usage += charge;
+
+        while state.stats.usage > state.stats.capacity {
+            let (_, oldest) = state.lru.pop_first().expect("usage is only above 0 with blocks cached");
+            let evicted = state.blocks.remove(&oldest).unwrap();
+            state.stats.usage -= evicted.charge;
+            state.stats.evictions += 1;
+        }
+    }
+}
+
+/// A new id for an opened table, see the top of the file.
+pub(crate) fn next_table_id() -> u64 {
+    NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed)
+}
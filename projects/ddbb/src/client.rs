@@ -0,0 +1,848 @@
+// src/client.rs
+
+/*
+* Client
+*
+* `Client` talks the protocol of server.rs, so a Rust program can use a ddbb server without
+* writing request lines and parsing responses itself:
+*
+*   let mut client = Client::connect("127.0.0.1:7878")?;
+*   client.set("user:1", "ann")?;
+*   assert_eq!(client.get("user:1")?, Some("ann".to_string()));
+*
+* Errors
+*
+* The errors are the `Error`s of the library, the same as with an embedded LogManager: an ERR
+* from the server comes back as the Error the server printed (an InvalidArgument for a bad
+* query, a UniqueViolation, ...), a connection that failed or timed out is an Io error, and a
+* response that does not follow the protocol is a Corruption.
+*
+* Connections and retries
+*
+* A client holds one connection, opened by `connect` and opened again when a request finds it
+* broken. A request that fails with an Io error (the server restarted, the connection was
+* dropped, a timeout) is sent again on a new connection, up to `ClientOptions::retries` times,
+* waiting `retry_backoff` before the first retry and twice as long before every next one.
+* Every query of the protocol is safe to send twice: SET and DEL leave the same state when
+* applied again, the others only read. Errors the server answered with are not retried.
+*
+* With `ClientOptions::credentials` every connection starts with an AUTH (see auth.rs), so a
+* connection opened again for a retry is authenticated like the first one; credentials the
+* server does not accept are a PermissionDenied of `connect`. With `ClientOptions::tls` the
+* connections are TLS, see tls.rs. A handshake that fails (the
+* server's certificate is not trusted, say) is an Io error like any failure to connect.
+*
+* After a failure other than an ERR the connection may be out of step with the server (half of
+* a response was read, say), so it is closed and the next request opens a new one.
+*
+* Administration
+*
+* `compact`, `flush`, `backup`, `stats`, `merkle` and `merkle_leaf` send the commands of
+* admin.rs. They are retried like the queries, except BACKUP: the first one may have made the
+* copy, and a second one would then fail on the directory it left. `cluster` asks a node of a
+* cluster for the members it knows (see membership.rs), and is retried too.
+*
+* Subscriptions
+*
+* `subscribe` opens a connection of its own for a SUBSCRIBE (see server.rs), so the client
+* stays free for requests, and `Subscription::recv` waits for the events, as long as it takes
+* (`io_timeout` is for requests, not for them). A subscription is not retried: the events of
+* the time it was broken are gone, so an Io error of `recv` (the server could not keep up with
+* the client, the connection was dropped) means scanning the keys again and subscribing again.
+*
+* Pipelines and batches
+*
+* `pipeline` sends many queries without waiting for each answer (see server.rs), so they cost
+* about one round trip instead of one each, and gives back the result of every query, an ERR
+* of one not stopping the others. `write_batch` sends a `WriteBatch` (see batch.rs) between
+* MULTI and EXEC the same way, and the server applies all of it or nothing. Both send their
+* requests in windows of `PIPELINE_WINDOW` and read the responses of a window before sending
+* the next one, so neither side fills the buffers of the connection with responses nobody
+* reads. Both are retried as a whole on an Io error: sending the same writes again, in the
+* same order, leaves the same state.
+*
+* `prepare` sends a batch the same way with PREPARE instead of EXEC, the first phase of a
+* two-phase commit (see shard.rs), and `commit_prepared` and `abort_prepared` end it. None of
+* them is retried: once the first went through, a second one fails.
+*
+* Async
+*
+* `AsyncClient` has the same requests as async functions, for programs that run on an async
+* executor. It does not depend on any executor: a thread of its own runs a `Client` and
+* completes the futures, so requests are sent one at a time, in the order they were made, and
+* the executor thread never blocks on the network.
+*/
+
+use crate::admin::Admin;
+use crate::auth::Credentials;
+use crate::batch::{BatchOp, WriteBatch};
+use crate::error::{Error, Result};
+use crate::merkle::MerkleTree;
+use crate::query::{Keys, Query, QueryResult};
+use crate::replication::{self, Checkpoint, Shipment};
+use crate::scan::{Page, ScanOptions};
+use crate::tls::{ClientTls, Stream};
+use crate::watch::Event;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+/// How many requests a pipeline sends before it reads their responses.
+pub const PIPELINE_WINDOW: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// How many times a request that failed with an Io error is sent again.
+    pub retries: usize,
+    /// Wait before the first retry, doubled for every next one.
+    pub retry_backoff: Duration,
+    /// Limit on opening a connection, None to wait as long as the OS does.
+    pub connect_timeout: Option<Duration>,
+    /// Limit on every read and write of a connection, None for no limit.
+    pub io_timeout: Option<Duration>,
+    /// Connect with TLS, trusting these certificates.
+    pub tls: Option<ClientTls>,
+    /// Authenticate every connection with these.
+    pub credentials: Option<Credentials>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            retries: 3,
+            retry_backoff: Duration::from_millis(50),
+            connect_timeout: Some(Duration::from_secs(5)),
+            io_timeout: Some(Duration::from_secs(30)),
+            tls: None,
+            credentials: None,
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+}
+
+/// A connection to a server, see the top of this file.
+pub struct Client {
+    addresses: Vec<SocketAddr>,
+    options: ClientOptions,
+    connection: Option<Connection>,
+}
+
+impl Client {
+    pub fn connect(address: impl ToSocketAddrs) -> Result<Self> {
+        Self::connect_with(address, ClientOptions::default())
+    }
+
+    /// Same as `connect`, with non-default `ClientOptions`.
+    pub fn connect_with(address: impl ToSocketAddrs, options: ClientOptions) -> Result<Self> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        if addresses.is_empty() {
+            return Err(Error::InvalidArgument("the address resolves to nothing".to_string()));
+        }
+        let mut client = Client { addresses, options, connection: None };
+        client.connection = Some(client.open()?);
+        Ok(client)
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.query(&Query::Get(key.to_string()))? {
+            QueryResult::Value(value) => Ok(value),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.done(&Query::Set(key.to_string(), value.to_string()))
+    }
+
+    pub fn del(&mut self, key: &str) -> Result<()> {
+        self.done(&Query::Del(key.to_string()))
+    }
+
+    /// A page of the pairs of `keys`, see scan.rs.
+    pub fn scan(&mut self, keys: Keys, options: ScanOptions) -> Result<Page<String, String>> {
+        self.page(&Query::Scan { keys, options, reverse: false })
+    }
+
+    /// Same as `scan`, in descending key order.
+    pub fn scan_rev(&mut self, keys: Keys, options: ScanOptions) -> Result<Page<String, String>> {
+        self.page(&Query::Scan { keys, options, reverse: true })
+    }
+
+    pub fn count(&mut self, keys: Keys) -> Result<usize> {
+        match self.query(&Query::Count(keys))? {
+            QueryResult::Count(count) => Ok(count),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn compact(&mut self) -> Result<()> {
+        self.admin(&Admin::Compact)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.admin(&Admin::Flush)
+    }
+
+    /// Have the server write a copy of its database to `dir`, a directory on its machine.
+    pub fn backup(&mut self, dir: &str) -> Result<()> {
+        if dir.is_empty() || dir.contains(char::is_whitespace) {
+            let message = "the directory cannot be empty or hold whitespace";
+            return Err(Error::InvalidArgument(message.to_string()));
+        }
+        let request = Admin::Backup(dir.to_string()).to_string();
+        match self.send(&mut |connection: &mut Connection| request_response(connection, &request))?? {
+            QueryResult::Done => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// The statistics of the server's database, see admin.rs.
+    pub fn stats(&mut self) -> Result<Vec<(String, String)>> {
+        self.admin_pairs(&Admin::Stats)
+    }
+
+    /// The Merkle tree of the server's database, see merkle.rs.
+    pub fn merkle(&mut self, depth: u32) -> Result<MerkleTree> {
+        MerkleTree::from_pairs(depth, &self.admin_pairs(&Admin::Merkle { depth, leaf: None })?)
+    }
+
+    /// The pairs of the server's database in a leaf of its Merkle tree, see merkle.rs.
+    pub fn merkle_leaf(&mut self, depth: u32, leaf: usize) -> Result<Vec<(String, String)>> {
+        self.admin_pairs(&Admin::Merkle { depth, leaf: Some(leaf) })
+    }
+
+    fn admin_pairs(&mut self, admin: &Admin) -> Result<Vec<(String, String)>> {
+        let request = admin.to_string();
+        match self.retrying(|connection| request_response(connection, &request))?? {
+            QueryResult::Pairs { pairs, next: None } => Ok(pairs),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn admin(&mut self, admin: &Admin) -> Result<()> {
+        let request = admin.to_string();
+        match self.retrying(|connection| request_response(connection, &request))?? {
+            QueryResult::Done => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// The writes of the keys that start with `prefix`, on a new connection, see the top of
+    /// this file.
+    pub fn subscribe(&self, prefix: &str) -> Result<Subscription> {
+        if prefix.contains(char::is_whitespace) {
+            return Err(Error::InvalidArgument(format!("{:?} has whitespace", prefix)));
+        }
+        let mut connection = self.open()?;
+        match request_response(&mut connection, format!("SUBSCRIBE {}", prefix).trim_end())?? {
+            QueryResult::Done => {}
+            other => return Err(unexpected(&other)),
+        }
+        connection.reader.get_ref().set_read_timeout(None)?;
+        Ok(Subscription { connection })
+    }
+
+    /// Have the server ship the writes after `from` (everything if None), on the connection of
+    /// this client, see replication.rs.
+    pub(crate) fn replicate(mut self, from: Option<u64>) -> Result<Replication> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.open()?,
+        };
+        let request = from.map_or("REPLICATE".to_string(), |lsn| format!("REPLICATE {}", lsn));
+        match request_response(&mut connection, &request)?? {
+            QueryResult::Done => {}
+            other => return Err(unexpected(&other)),
+        }
+        connection.reader.get_ref().set_read_timeout(Some(replication::LEADER_TIMEOUT))?;
+        Ok(Replication { connection })
+    }
+
+    /// Send the message of a Raft node to the node of the server and wait for its reply, both
+    /// JSON, see raft.rs. Not retried, Raft sends again what it needs to.
+    pub(crate) fn raft(&mut self, message: &str) -> Result<String> {
+        self.message("RAFT", message)
+    }
+
+    /// Send the digests of a node of a cluster to the node of the server and wait for its
+    /// own, see membership.rs. Not retried, the next round of gossip goes out anyway.
+    pub(crate) fn gossip(&mut self, digests: &str) -> Result<String> {
+        self.message("GOSSIP", digests)
+    }
+
+    /// The members of the cluster of the server, with their state ("alive", "suspect" or
+    /// "dead"), see membership.rs.
+    pub fn cluster(&mut self) -> Result<Vec<(String, String)>> {
+        match self.retrying(|connection| request_response(connection, "CLUSTER"))?? {
+            QueryResult::Pairs { pairs, next: None } => Ok(pairs),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    // A request "<kind> <message>" answered with "<kind> <reply>", between the nodes
+    fn message(&mut self, kind: &str, message: &str) -> Result<String> {
+        let request = format!("{} {}", kind, message);
+        let prefix = format!("{} ", kind);
+        self.send(&mut |connection| {
+            write_line(&mut connection.writer, &request)?;
+            connection.writer.flush()?;
+            let line = read_line(&mut connection.reader)?;
+            match line.strip_prefix(prefix.as_str()) {
+                Some(reply) => Ok(reply.to_string()),
+                None => match line.strip_prefix("ERR ") {
+                    Some(message) => Err(Error::from_message(message)),
+                    None => Err(bad_response(&line)),
+                },
+            }
+        })
+    }
+
+    /// Send any query, retrying it as described at the top of this file.
+    pub fn query(&mut self, query: &Query) -> Result<QueryResult> {
+        check(query)?;
+        let request = query.to_string();
+        self.retrying(|connection| request_response(connection, &request))?
+    }
+
+    /// Send all of `queries` at once, see the top of this file. The results are in the order
+    /// of the queries.
+    pub fn pipeline(&mut self, queries: &[Query]) -> Result<Vec<Result<QueryResult>>> {
+        let requests = requests(queries)?;
+        self.retrying(|connection| exchange(connection, &requests, read_response))
+    }
+
+    /// Apply all of `batch` or, if the server fails any of it, nothing.
+    pub fn write_batch(&mut self, batch: &WriteBatch<String, String>) -> Result<()> {
+        let requests = batch_requests(batch, "EXEC")?;
+        let replies = self.retrying(|connection| exchange(connection, &requests, read_batch_reply))?;
+        batch_replies(replies)
+    }
+
+    /// Have the server prepare `batch` as `id`, see `LogManager::prepare`.
+    pub fn prepare(&mut self, id: &str, batch: &WriteBatch<String, String>) -> Result<()> {
+        let requests = batch_requests(batch, &format!("PREPARE {}", check_id(id)?))?;
+        let replies = self.send(&mut |connection: &mut Connection| {
+            exchange(connection, &requests, read_batch_reply)
+        })?;
+        batch_replies(replies)
+    }
+
+    /// Have the server apply the batch it prepared as `id`, and sync that.
+    pub fn commit_prepared(&mut self, id: &str) -> Result<()> {
+        let request = format!("COMMIT {}", check_id(id)?);
+        match self.send(&mut |connection: &mut Connection| request_response(connection, &request))?? {
+            QueryResult::Done => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// Have the server drop the batch it prepared as `id`, and sync that.
+    pub fn abort_prepared(&mut self, id: &str) -> Result<()> {
+        let request = format!("ABORT {}", check_id(id)?);
+        match self.send(&mut |connection: &mut Connection| request_response(connection, &request))?? {
+            QueryResult::Done => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// The batches the server has prepared and not committed or aborted, with the number of
+    /// their writes, see `LogManager::prepared`.
+    pub fn prepared(&mut self) -> Result<Vec<(String, usize)>> {
+        let pairs = match self.retrying(|connection| request_response(connection, "PREPARED"))?? {
+            QueryResult::Pairs { pairs, next: None } => pairs,
+            other => return Err(unexpected(&other)),
+        };
+        let count = |(id, writes): (String, String)| match writes.parse() {
+            Ok(writes) => Ok((id, writes)),
+            Err(_) => Err(bad_response(&format!("{} {}", id, writes))),
+        };
+        pairs.into_iter().map(count).collect()
+    }
+
+    // Run one attempt after another, retrying as described at the top of this file. The
+    // outer error of an attempt is a failure to get an answer, the inner one the ERR the server
+    // answered with.
+    fn retrying<T>(&mut self, mut attempt: impl FnMut(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut backoff = self.options.retry_backoff;
+        let mut retries = 0;
+        loop {
+            match self.send(&mut attempt) {
+                Ok(answer) => return Ok(answer),
+                Err(Error::Io(e)) if retries < self.options.retries => {
+                    eprintln!("Request failed ({}), retrying in {:?}", e, backoff);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn done(&mut self, query: &Query) -> Result<()> {
+        match self.query(query)? {
+            QueryResult::Done => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn page(&mut self, query: &Query) -> Result<Page<String, String>> {
+        match self.query(query)? {
+            QueryResult::Pairs { pairs, next } => Ok(Page { pairs, next }),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    // One attempt, on the current connection or a new one
+    fn send<T>(&mut self, attempt: &mut impl FnMut(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.open()?,
+        };
+        let response = attempt(&mut connection);
+        if response.is_ok() {
+            self.connection = Some(connection);
+        }
+        response
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let mut last_error = None;
+        for address in &self.addresses {
+            let stream = match self.options.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_read_timeout(self.options.io_timeout)?;
+                    stream.set_write_timeout(self.options.io_timeout)?;
+                    stream.set_nodelay(true)?;
+                    let stream = Stream::connect(stream, self.options.tls.as_ref())?;
+                    let reader = BufReader::new(stream.try_clone()?);
+                    let mut connection = Connection { reader, writer: BufWriter::new(stream) };
+                    if let Some(credentials) = &self.options.credentials {
+                        write_line(&mut connection.writer, &credentials.request()?)?;
+                        connection.writer.flush()?;
+                        match read_response(&mut connection.reader)?? {
+                            QueryResult::Done => {}
+                            other => return Err(unexpected(&other)),
+                        }
+                    }
+                    return Ok(connection);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("there is at least one address").into())
+    }
+}
+
+/// The events of `Client::subscribe`.
+pub struct Subscription {
+    connection: Connection,
+}
+
+impl Subscription {
+    /// The next event, waiting for it.
+    pub fn recv(&mut self) -> Result<Event<String, String>> {
+        let line = read_line(&mut self.connection.reader)?;
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            ["EVENT", "SET", key, value] => Ok(Event::Set(key.to_string(), value.to_string())),
+            ["EVENT", "DEL", key] => Ok(Event::Delete(key.to_string())),
+            _ => match line.strip_prefix("ERR ") {
+                Some(message) => Err(Error::from_message(message)),
+                None => Err(bad_response(&line)),
+            },
+        }
+    }
+}
+
+/// What a leader ships, see `Client::replicate`.
+pub(crate) struct Replication {
+    connection: Connection,
+}
+
+impl Replication {
+    /// The next thing the leader ships, waiting for it.
+    pub fn recv(&mut self) -> Result<Shipment> {
+        let reader = &mut self.connection.reader;
+        let line = read_line(reader)?;
+        let lsn = |lsn: &str| lsn.parse::<u64>().map_err(|_| bad_response(&line));
+        let fields: Vec<&str> = line.splitn(3, ' ').collect();
+        match fields[..] {
+            ["WAL", write, record] => Ok(Shipment::Write(lsn(write)?, record.to_string())),
+            ["LSN", leader] => Ok(Shipment::Heartbeat(lsn(leader)?)),
+            ["CHECKPOINT", at, count] => {
+                let count: usize = count.parse().map_err(|_| bad_response(&line))?;
+                let mut checkpoint = Checkpoint { lsn: lsn(at)?, files: Vec::new() };
+                for _ in 0..count {
+                    let file = read_line(reader)?;
+                    let ["FILE", name, size] = file.split(' ').collect::<Vec<_>>()[..] else {
+                        return Err(bad_response(&file));
+                    };
+                    let size: usize = size.parse().map_err(|_| bad_response(&file))?;
+                    let mut content = vec![0; size];
+                    reader.read_exact(&mut content)?;
+                    checkpoint.files.push((name.to_string(), content));
+                }
+                Ok(Shipment::Checkpoint(checkpoint))
+            }
+            _ => match line.strip_prefix("ERR ") {
+                Some(message) => Err(Error::from_message(message)),
+                None => Err(bad_response(&line)),
+            },
+        }
+    }
+}
+
+// Keys, values and tokens are whitespace separated on the wire, see server.rs
+fn check(query: &Query) -> Result<()> {
+    let mut fields = Vec::new();
+    match query {
+        Query::Get(key) | Query::Del(key) => fields.push(key),
+        Query::Set(key, value) => fields.extend([key, value]),
+        Query::Scan { keys, options, .. } => {
+            fields.extend(keys_fields(keys));
+            fields.extend(&options.resume);
+        }
+        Query::Count(keys) => fields.extend(keys_fields(keys)),
+    }
+    for field in fields {
+        if field.contains(char::is_whitespace) {
+            return Err(Error::InvalidArgument(format!("{:?} has whitespace", field)));
+        }
+    }
+    match query {
+        Query::Get(key) | Query::Del(key) | Query::Set(key, _) if key.is_empty() => {
+            Err(Error::InvalidArgument("empty key".to_string()))
+        }
+        Query::Set(_, value) if value.is_empty() => Err(Error::InvalidArgument("empty value".to_string())),
+        _ => Ok(()),
+    }
+}
+
+fn check_id(id: &str) -> Result<&str> {
+    if id.is_empty() || id.contains(char::is_whitespace) {
+        return Err(Error::InvalidArgument(format!("bad transaction id {:?}", id)));
+    }
+    Ok(id)
+}
+
+// MULTI, the writes of `batch` and `last`
+fn batch_requests(batch: &WriteBatch<String, String>, last: &str) -> Result<Vec<String>> {
+    let queries: Vec<Query> = batch
+        .ops()
+        .iter()
+        .map(|op| match op {
+            BatchOp::Insert(key, value) => Query::Set(key.clone(), value.clone()),
+            BatchOp::Delete(key) => Query::Del(key.clone()),
+        })
+        .collect();
+    let mut requests = requests(&queries)?;
+    requests.insert(0, "MULTI".to_string());
+    requests.push(last.to_string());
+    Ok(requests)
+}
+
+// What the replies of `batch_requests` say
+fn batch_replies(replies: Vec<Option<Result<QueryResult>>>) -> Result<()> {
+    let mut replies = replies.into_iter();
+    let last = replies.next_back().expect("there is an EXEC or a PREPARE");
+    batch_done(replies.next().expect("there is a MULTI"))?;
+    // the first error tells more than the last one, which only says the batch failed
+    for reply in replies {
+        match reply {
+            None => {}
+            Some(Err(e)) => return Err(e),
+            Some(Ok(other)) => return Err(unexpected(&other)),
+        }
+    }
+    batch_done(last)
+}
+
+fn requests(queries: &[Query]) -> Result<Vec<String>> {
+    queries.iter().map(|query| check(query).map(|_| query.to_string())).collect()
+}
+
+fn request_response(connection: &mut Connection, request: &str) -> Result<Result<QueryResult>> {
+    write_line(&mut connection.writer, request)?;
+    connection.writer.flush()?;
+    read_response(&mut connection.reader)
+}
+
+fn write_line(writer: &mut impl Write, line: &str) -> Result<()> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+// Send the requests in windows, see the top of this file, and read a response for each
+fn exchange<T>(
+    connection: &mut Connection,
+    requests: &[String],
+    read: impl Fn(&mut BufReader<Stream>) -> Result<T>,
+) -> Result<Vec<T>> {
+    let mut responses = Vec::with_capacity(requests.len());
+    for window in requests.chunks(PIPELINE_WINDOW) {
+        for request in window {
+            write_line(&mut connection.writer, request)?;
+        }
+        connection.writer.flush()?;
+        for _ in window {
+            responses.push(read(&mut connection.reader)?);
+        }
+    }
+    Ok(responses)
+}
+
+fn keys_fields(keys: &Keys) -> Vec<&String> {
+    match keys {
+        Keys::Range { start, end } => start.iter().chain(end).collect(),
+        Keys::Prefix(prefix) => vec![prefix],
+    }
+}
+
+fn unexpected(result: &QueryResult) -> Error {
+    Error::Corruption(format!("unexpected response {:?}", result))
+}
+
+fn bad_response(line: &str) -> Error {
+    Error::Corruption(format!("bad response {:?}", line))
+}
+
+// One response of server.rs, an ERR as the inner Error it stands for
+fn read_response(reader: &mut impl BufRead) -> Result<Result<QueryResult>> {
+    let line = read_line(reader)?;
+    parse_response(line, reader)
+}
+
+// Same as `read_response`, None for the QUEUED of a write between MULTI and EXEC
+fn read_batch_reply(reader: &mut impl BufRead) -> Result<Option<Result<QueryResult>>> {
+    let line = read_line(reader)?;
+    if line == "QUEUED" {
+        return Ok(None);
+    }
+    parse_response(line, reader).map(Some)
+}
+
+// The OK of MULTI or EXEC
+fn batch_done(reply: Option<Result<QueryResult>>) -> Result<()> {
+    match reply {
+        Some(Ok(QueryResult::Done)) => Ok(()),
+        Some(Err(e)) => Err(e),
+        Some(Ok(other)) => Err(unexpected(&other)),
+        None => Err(bad_response("QUEUED")),
+    }
+}
+
+// The response that starts with `line`, reading the rest of it
+fn parse_response(line: String, reader: &mut impl BufRead) -> Result<Result<QueryResult>> {
+    let (kind, rest) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+    if kind == "ERR" {
+        return Ok(Err(Error::from_message(rest)));
+    }
+    let result = match kind {
+        "VALUE" => QueryResult::Value(Some(rest.to_string())),
+        "NOT_FOUND" if rest.is_empty() => QueryResult::Value(None),
+        "OK" if rest.is_empty() => QueryResult::Done,
+        "COUNT" => QueryResult::Count(rest.parse().map_err(|_| bad_response(&line))?),
+        "PAIRS" => {
+            let (count, next) = match rest.split_once(" NEXT ") {
+                Some((count, next)) => (count, Some(next.to_string())),
+                None => (rest, None),
+            };
+            let count: usize = count.parse().map_err(|_| bad_response(&line))?;
+            let mut pairs = Vec::with_capacity(count);
+            for _ in 0..count {
+                let pair = read_line(reader)?;
+                let (key, value) = pair.split_once(' ').ok_or_else(|| bad_response(&pair))?;
+                pairs.push((key.to_string(), value.to_string()));
+            }
+            QueryResult::Pairs { pairs, next }
+        }
+        _ => return Err(bad_response(&line)),
+    };
+    Ok(Ok(result))
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection").into());
+    }
+    match line.strip_suffix('\n') {
+        Some(line) => Ok(line.to_string()),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the response was cut short").into()),
+    }
+}
+
+// ####################################################################################
+
+type Job = Box<dyn FnOnce(&mut Client) + Send>;
+
+/// The requests of `Client` as async functions, see the top of this file.
+pub struct AsyncClient {
+    jobs: Sender<Job>,
+}
+
+impl AsyncClient {
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<Self> {
+        Self::connect_with(address, ClientOptions::default()).await
+    }
+
+    pub async fn connect_with(address: impl ToSocketAddrs, options: ClientOptions) -> Result<Self> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let (reply, connected) = reply();
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::spawn(move || {
+            let mut client = match Client::connect_with(&addresses[..], options) {
+                Ok(client) => client,
+                Err(e) => return reply.send(Err(e)),
+            };
+            reply.send(Ok(()));
+            // until the AsyncClient is dropped
+            for job in queue {
+                job(&mut client);
+            }
+        });
+        connected.await?;
+        Ok(AsyncClient { jobs })
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        let key = key.to_string();
+        self.run(move |client| client.get(&key)).await
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<()> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.run(move |client| client.set(&key, &value)).await
+    }
+
+    pub async fn del(&self, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.run(move |client| client.del(&key)).await
+    }
+
+    pub async fn scan(&self, keys: Keys, options: ScanOptions) -> Result<Page<String, String>> {
+        self.run(move |client| client.scan(keys, options)).await
+    }
+
+    pub async fn scan_rev(&self, keys: Keys, options: ScanOptions) -> Result<Page<String, String>> {
+        self.run(move |client| client.scan_rev(keys, options)).await
+    }
+
+    pub async fn count(&self, keys: Keys) -> Result<usize> {
+        self.run(move |client| client.count(keys)).await
+    }
+
+    pub async fn query(&self, query: Query) -> Result<QueryResult> {
+        self.run(move |client| client.query(&query)).await
+    }
+
+    pub async fn compact(&self) -> Result<()> {
+        self.run(|client| client.compact()).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|client| client.flush()).await
+    }
+
+    pub async fn backup(&self, dir: &str) -> Result<()> {
+        let dir = dir.to_string();
+        self.run(move |client| client.backup(&dir)).await
+    }
+
+    pub async fn stats(&self) -> Result<Vec<(String, String)>> {
+        self.run(|client| client.stats()).await
+    }
+
+    pub async fn pipeline(&self, queries: Vec<Query>) -> Result<Vec<Result<QueryResult>>> {
+        self.run(move |client| client.pipeline(&queries)).await
+    }
+
+    pub async fn write_batch(&self, batch: WriteBatch<String, String>) -> Result<()> {
+        self.run(move |client| client.write_batch(&batch)).await
+    }
+
+    fn run<T, F>(&self, request: F) -> Reply<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Client) -> Result<T> + Send + 'static,
+    {
+        let (sender, reply) = reply();
+        // if the thread is gone, the job is dropped and so is the sender, which fails the reply
+        let _ = self.jobs.send(Box::new(move |client| sender.send(request(client))));
+        reply
+    }
+}
+
+struct Slot<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+// The future of a request run by the thread of an AsyncClient
+struct Reply<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+// Completes a Reply, with an error if it is dropped before it did
+struct ReplySender<T> {
+    slot: Option<Arc<Mutex<Slot<T>>>>,
+}
+
+fn reply<T>() -> (ReplySender<T>, Reply<T>) {
+    let slot = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+    (ReplySender { slot: Some(slot.clone()) }, Reply { slot })
+}
+
+impl<T> ReplySender<T> {
+    fn send(mut self, result: Result<T>) {
+        self.complete(result);
+    }
+
+    fn complete(&mut self, result: Result<T>) {
+        if let Some(slot) = self.slot.take() {
+            let mut slot = slot.lock().unwrap();
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for ReplySender<T> {
+    fn drop(&mut self) {
+        let stopped = io::Error::new(io::ErrorKind::BrokenPipe, "the client thread stopped");
+        self.complete(Err(stopped.into()));
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
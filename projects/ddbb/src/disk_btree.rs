@@ -0,0 +1,784 @@
+// src/disk_btree.rs
+
+/*
+* Disk-resident B+ Tree
+*
+* Unlike btree.rs, which keeps every node in memory, this tree stores one node per page of a
+* single database file (see pager.rs) and reads nodes on demand, so the data set can be much
+* larger than RAM. Keys and values are byte strings ordered lexicographically.
+*
+* It is a B+ tree rather than the B tree of btree.rs:
+* - leaf nodes hold the key-value pairs
+* - internal nodes only hold separator keys to navigate, keys[i] is the smallest key of the
+*   subtree children[i + 1]
+*
+* keys:     [    "g"     ,     "p"     ]
+* children: [C0 (< "g"),  C1 ("g".."p"),  C2 (>= "p")]
+*
+* Since keys and values have variable sizes, a node is split when its encoding no longer fits
+* in the payload of a page (instead of after 2 * B - 1 keys), and after a delete a node that
+* uses less than a quarter of its page is merged with a sibling when both fit in one page.
+*
+* ############################################################################################
+*
+* Copy-on-write
+*
+* A page that belongs to the committed tree is never overwritten. Modifying a node writes it to
+* a fresh page, which means its parent changes too, up to the root:
+*
+*          committed                 after inserting into C1
+*          [R]                       [R]        [R']
+*         /   \                     /   \      /    \
+*       [C0] [C1]                 [C0] [C1]  (C0)  [C1']
+*
+* `commit` makes the fresh pages durable and then publishes R' in the meta page in a single
+* page write. A crash before that point simply reopens R, so the file never contains half an
+* update. A page written during the current transaction is not part of the committed tree yet,
+* so it is updated in place until the next commit.
+*
+* ############################################################################################
+*
+* Vacuum
+*
+* The free list lets freed pages be reused, but the file never shrinks, and deletes leave
+* nodes that are far from full (a node is only merged once it is under a quarter of a page and
+* fits in one page with a sibling). `vacuum` rewrites the committed tree into a new file:
+*
+* 1. the leaves are read in key order and packed into pages filled as much as possible
+* 2. the internal levels are built bottom up over them, packed the same way
+* 3. the new file is committed, then renamed over the old one
+*
+* Until the rename the old file is untouched, so a crash leaves either the old or the new
+* file, both complete. A leftover `<file>.vacuum` from a crash before the rename is removed on
+* open. The packed tree has no free pages, and the first inserts into full leaves split them.
+*/
+
+use crate::error::{Error, Result};
+use crate::explain::{self, Pairs, ReadStats};
+use crate::pager::{FileStats, PageId, Pager, DEFAULT_CACHE_PAGES, META_SLOTS, PAGE_PAYLOAD, PAGE_SIZE};
+use crate::vfs::{Vfs, VfsLock};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const LEAF: u8 = 1;
+const INTERNAL: u8 = 2;
+const NODE_HEADER: usize = 3; // kind (u8) + number of keys (u16)
+
+/// Largest key + value accepted, small enough that a split always produces two nodes that fit.
+pub const MAX_ENTRY_SIZE: usize = (PAGE_PAYLOAD - NODE_HEADER) / 4 - 8;
+
+// below this a node is merged with a sibling if possible
+const MIN_FILL: usize = PAGE_PAYLOAD / 4;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Leaf { keys: Vec<Vec<u8>>, values: Vec<Vec<u8>> },
+    Internal { keys: Vec<Vec<u8>>, children: Vec<PageId> },
+}
+
+impl Node {
+    fn encoded_size(&self) -> usize {
+        match self {
+            Node::Leaf { keys, values } => {
+                NODE_HEADER + keys.iter().zip(values).map(|(k, v)| 4 + k.len() + v.len()).sum::<usize>()
+            }
+            Node::Internal { keys, .. } => {
+                NODE_HEADER + 4 + keys.iter().map(|k| 6 + k.len()).sum::<usize>()
+            }
+        }
+    }
+
+    /*
+    * Leaf:     LEAF     | count u16 | (key len u16, key, value len u16, value) * count
+    * Internal: INTERNAL | count u16 | children[0] u32 | (key len u16, key, child u32) * count
+    */
+    fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_PAYLOAD);
+        match self {
+            Node::Leaf { keys, values } => {
+                page.push(LEAF);
+                page.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                for (key, value) in keys.iter().zip(values) {
+                    page.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    page.extend_from_slice(key);
+                    page.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                    page.extend_from_slice(value);
+                }
+            }
+            Node::Internal { keys, children } => {
+                page.push(INTERNAL);
+                page.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                page.extend_from_slice(&children[0].to_le_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    page.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    page.extend_from_slice(key);
+                    page.extend_from_slice(&child.to_le_bytes());
+                }
+            }
+        }
+        page
+    }
+
+    fn decode(id: PageId, page: &[u8]) -> Result<Node> {
+        let mut reader = PageReader { page, pos: 0 };
+        let node = (|| {
+            let kind = reader.bytes(1)?[0];
+            let count = reader.u16()? as usize;
+            match kind {
+                LEAF => {
+                    let mut keys = Vec::with_capacity(count);
+                    let mut values = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let len = reader.u16()? as usize;
+                        keys.push(reader.bytes(len)?.to_vec());
+                        let len = reader.u16()? as usize;
+                        values.push(reader.bytes(len)?.to_vec());
+                    }
+                    Some(Node::Leaf { keys, values })
+                }
+                INTERNAL => {
+                    let mut keys = Vec::with_capacity(count);
+                    let mut children = vec![reader.u32()?];
+                    for _ in 0..count {
+                        let len = reader.u16()? as usize;
+                        keys.push(reader.bytes(len)?.to_vec());
+                        children.push(reader.u32()?);
+                    }
+                    Some(Node::Internal { keys, children })
+                }
+                _ => None,
+            }
+        })();
+        node.ok_or_else(|| Error::Corruption(format!("page {} is not a valid tree node", id)))
+    }
+
+    // Move the upper half of an overflowing node into a new node, returns the separator key
+    // that goes up to the parent together with the new right node.
+    fn split(&mut self) -> (Vec<u8>, Node) {
+        // split by bytes rather than by count, entries have different sizes
+        let half = self.encoded_size() / 2;
+        match self {
+            Node::Leaf { keys, values } => {
+                let mut size = NODE_HEADER;
+                let mut mid = 0;
+                while mid < keys.len() - 1 && size < half {
+                    size += 4 + keys[mid].len() + values[mid].len();
+                    mid += 1;
+                }
+                let mid = mid.max(1);
+                let right_keys = keys.split_off(mid);
+                let right_values = values.split_off(mid);
+                (right_keys[0].clone(), Node::Leaf { keys: right_keys, values: right_values })
+            }
+            Node::Internal { keys, children } => {
+                // the middle key moves up to the parent, it does not stay in either half
+                let mut size = NODE_HEADER + 4;
+                let mut mid = 0;
+                while mid < keys.len() - 2 && size < half {
+                    size += 6 + keys[mid].len();
+                    mid += 1;
+                }
+                let mid = mid.max(1);
+                let right_keys = keys.split_off(mid + 1);
+                let separator = keys.pop().unwrap();
+                let right_children = children.split_off(mid + 1);
+                (separator, Node::Internal { keys: right_keys, children: right_children })
+            }
+        }
+    }
+
+    // Append `right` (the next sibling) to this node, `separator` is the parent key between them
+    fn merge(&mut self, separator: Vec<u8>, right: Node) {
+        match (self, right) {
+            (Node::Leaf { keys, values }, Node::Leaf { keys: mut rk, values: mut rv }) => {
+                keys.append(&mut rk);
+                values.append(&mut rv);
+            }
+            (Node::Internal { keys, children }, Node::Internal { keys: mut rk, children: mut rc }) => {
+                keys.push(separator);
+                keys.append(&mut rk);
+                children.append(&mut rc);
+            }
+            _ => unreachable!("siblings are always on the same level"),
+        }
+    }
+}
+
+struct PageReader<'a> {
+    page: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PageReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.page.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+}
+
+// Separator key and page of the new right sibling created when a node is split
+type Split = Option<(Vec<u8>, PageId)>;
+
+// Index of the child whose subtree may contain `key`
+fn child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    keys.partition_point(|k| k.as_slice() <= key)
+}
+
+/// Result of `DiskBTree::verify_integrity`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Pages read back and checked, tree nodes and free list pages.
+    pub pages_checked: u32,
+    /// Pages that fail their checksum or do not decode, the subtrees below them are not checked.
+    pub corrupted_pages: Vec<PageId>,
+    /// Pages neither reachable from the root nor free, the space is lost until a rebuild.
+    pub leaked_pages: Vec<PageId>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupted_pages.is_empty() && self.leaked_pages.is_empty()
+    }
+}
+
+/// Result of `DiskBTree::vacuum`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VacuumReport {
+    pub pages_before: u32,
+    pub pages_after: u32,
+    /// How much smaller the file got.
+    pub bytes_reclaimed: u64,
+}
+
+pub struct DiskBTree {
+    vfs: Arc<dyn Vfs>,
+    path: PathBuf,
+    cache_pages: usize,
+    pager: Pager,
+    root: PageId,          // working root, 0 when the tree is empty
+    fresh: HashSet<PageId>, // pages written since the last commit, safe to overwrite
+    _lock: Box<dyn VfsLock>,
+}
+
+impl DiskBTree {
+    /// Open (or create) the database file at `path`.
+    pub fn open(vfs: Arc<dyn Vfs>, path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_cache(vfs, path, DEFAULT_CACHE_PAGES)
+    }
+
+    /// Same as `open`, keeping at most `cache_pages` pages in memory.
+    pub fn open_with_cache(vfs: Arc<dyn Vfs>, path: impl AsRef<Path>, cache_pages: usize) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            vfs.create_dir_all(dir)?;
+        }
+        let lock = vfs.lock(&lock_path(path))?;
+        // a vacuum that crashed before its rename
+        if vfs.exists(&vacuum_path(path)) {
+            vfs.remove(&vacuum_path(path))?;
+        }
+        let pager = Pager::open(vfs.as_ref(), path, cache_pages)?;
+        Ok(DiskBTree {
+            vfs,
+            path: path.to_path_buf(),
+            cache_pages,
+            root: pager.meta().root,
+            pager,
+            fresh: HashSet::new(),
+            _lock: lock,
+        })
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_traced(key, None)
+    }
+
+    /// Same as `get`, also returning what the lookup read, see explain.rs.
+    pub fn explain_get(&mut self, key: &[u8]) -> Result<(Option<Vec<u8>>, ReadStats)> {
+        let stats = Cell::default();
+        let value = self.get_traced(key, Some(&stats))?;
+        Ok((value, stats.get()))
+    }
+
+    fn get_traced(&mut self, key: &[u8], stats: Option<&Cell<ReadStats>>) -> Result<Option<Vec<u8>>> {
+        let mut id = self.root;
+        while id != 0 {
+            match self.load_traced(id, stats)? {
+                Node::Leaf { keys, mut values } => {
+                    return Ok(keys
+                        .binary_search_by(|k| k.as_slice().cmp(key))
+                        .ok()
+                        .map(|i| values.swap_remove(i)));
+                }
+                Node::Internal { keys, children } => id = children[child_index(&keys, key)],
+            }
+        }
+        Ok(None)
+    }
+
+    /// Insert or overwrite `key`. The change is visible right away and durable after `commit`.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() + value.len() > MAX_ENTRY_SIZE {
+            return Err(Error::InvalidArgument(format!(
+                "key and value take {} bytes, at most {} fit in a page",
+                key.len() + value.len(),
+                MAX_ENTRY_SIZE
+            )));
+        }
+
+        if self.root == 0 {
+            let leaf = Node::Leaf { keys: vec![key.to_vec()], values: vec![value.to_vec()] };
+            self.root = self.store(None, &leaf)?;
+            return Ok(());
+        }
+
+        let (root, split) = self.insert_into(self.root, key, value)?;
+        self.root = match split {
+            None => root,
+            Some((separator, right)) => {
+                // the root was split, the tree grows by one level
+                let new_root = Node::Internal { keys: vec![separator], children: vec![root, right] };
+                self.store(None, &new_root)?
+            }
+        };
+        Ok(())
+    }
+
+    /// Remove `key`, returns whether it was present.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        if self.root == 0 {
+            return Ok(false);
+        }
+        let root = match self.delete_from(self.root, key)? {
+            Some(root) => root,
+            None => return Ok(false),
+        };
+
+        // shrink the tree when the root lost its last key
+        self.root = match self.load(root)? {
+            Node::Leaf { keys, .. } if keys.is_empty() => {
+                self.retire(root);
+                0
+            }
+            Node::Internal { keys, children } if keys.is_empty() => {
+                self.retire(root);
+                children[0]
+            }
+            _ => root,
+        };
+        Ok(true)
+    }
+
+    /// Every pair whose key is inside `range`, in ascending key order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let lo = range.start_bound().map(|k| k.as_slice());
+        let hi = range.end_bound().map(|k| k.as_slice());
+        let mut pairs = Vec::new();
+        if self.root != 0 {
+            self.scan(self.root, lo, hi, false, &mut pairs, None)?;
+        }
+        Ok(pairs)
+    }
+
+    /// Same as `range`, also returning what the scan read, see explain.rs.
+    pub fn explain_range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Result<(Pairs, ReadStats)> {
+        let lo = range.start_bound().map(|k| k.as_slice());
+        let hi = range.end_bound().map(|k| k.as_slice());
+        let stats = Cell::default();
+        let mut pairs = Vec::new();
+        if self.root != 0 {
+            self.scan(self.root, lo, hi, false, &mut pairs, Some(&stats))?;
+        }
+        Ok((pairs, stats.get()))
+    }
+
+    /// Same as `range`, in descending key order. The pages are walked from the end of the
+    /// range down, not collected and reversed.
+    pub fn range_rev<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let lo = range.start_bound().map(|k| k.as_slice());
+        let hi = range.end_bound().map(|k| k.as_slice());
+        let mut pairs = Vec::new();
+        if self.root != 0 {
+            self.scan(self.root, lo, hi, true, &mut pairs, None)?;
+        }
+        Ok(pairs)
+    }
+
+    pub fn traverse(&mut self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.range(..)
+    }
+
+    /// How many pages of the file are in use and how many are free.
+    pub fn file_stats(&self) -> FileStats {
+        self.pager.file_stats()
+    }
+
+    /// Read every page of the tree and of the free list from the file, bypassing the cache, and
+    /// check its checksum and encoding. Also reports pages that nothing refers to.
+    pub fn verify_integrity(&mut self) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+        let mut seen = HashSet::new();
+
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            if id == 0 || !seen.insert(id) {
+                continue;
+            }
+            report.pages_checked += 1;
+            let node = self.pager.read_from_disk(id).and_then(|page| Node::decode(id, &page));
+            match node {
+                Ok(Node::Internal { children, .. }) => stack.extend(children),
+                Ok(Node::Leaf { .. }) => {}
+                Err(Error::Corruption(_)) => report.corrupted_pages.push(id),
+                Err(e) => return Err(e),
+            }
+        }
+
+        for id in self.pager.freelist_pages().to_vec() {
+            seen.insert(id);
+            report.pages_checked += 1;
+            match self.pager.read_from_disk(id) {
+                Ok(_) => {}
+                Err(Error::Corruption(_)) => report.corrupted_pages.push(id),
+                Err(e) => return Err(e),
+            }
+        }
+
+        seen.extend(self.pager.free_pages());
+        report.leaked_pages = (META_SLOTS..self.pager.page_count()).filter(|id| !seen.contains(id)).collect();
+        report.corrupted_pages.sort_unstable();
+        Ok(report)
+    }
+
+    /// Make every change since the last commit durable, atomically.
+    pub fn commit(&mut self) -> Result<()> {
+        self.pager.commit(self.root)?;
+        self.fresh.clear();
+        Ok(())
+    }
+
+    /// Rewrite the committed tree compactly into a new file and swap it in, see the top of the
+    /// file. Fails if there are uncommitted changes.
+    pub fn vacuum(&mut self) -> Result<VacuumReport> {
+        if !self.fresh.is_empty() || self.root != self.pager.meta().root {
+            return Err(Error::InvalidArgument("commit or roll back before a vacuum".to_string()));
+        }
+        let pages_before = self.pager.page_count();
+        let temp = vacuum_path(&self.path);
+        if self.vfs.exists(&temp) {
+            self.vfs.remove(&temp)?;
+        }
+
+        let mut packer = Packer::new(Pager::open(self.vfs.as_ref(), &temp, self.cache_pages)?);
+        if self.root != 0 {
+            self.pack_leaves(self.root, &mut packer)?;
+        }
+        let (mut pager, root) = packer.finish()?;
+        pager.commit(root)?;
+        let pages_after = pager.page_count();
+        drop(pager);
+
+        self.vfs.rename(&temp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            self.vfs.sync_dir(dir)?;
+        }
+        self.pager = Pager::open(self.vfs.as_ref(), &self.path, self.cache_pages)?;
+        self.root = root;
+        Ok(VacuumReport {
+            pages_before,
+            pages_after,
+            bytes_reclaimed: pages_before.saturating_sub(pages_after) as u64 * PAGE_SIZE as u64,
+        })
+    }
+
+    /// Throw away every change since the last commit.
+    pub fn rollback(&mut self) {
+        self.pager.rollback();
+        self.root = self.pager.meta().root;
+        self.fresh.clear();
+    }
+
+    fn load(&mut self, id: PageId) -> Result<Node> {
+        self.load_traced(id, None)
+    }
+
+    fn load_traced(&mut self, id: PageId, stats: Option<&Cell<ReadStats>>) -> Result<Node> {
+        explain::record(stats, |stats| stats.nodes_visited += 1);
+        let page = self.pager.read_traced(id, stats)?;
+        Node::decode(id, &page)
+    }
+
+    // Write `node`, which replaces the page `old` (if any), and return the page it ended up on
+    fn store(&mut self, old: Option<PageId>, node: &Node) -> Result<PageId> {
+        let id = match old {
+            Some(old) if self.fresh.contains(&old) => old,
+            _ => {
+                if let Some(old) = old {
+                    self.retire(old);
+                }
+                let id = self.pager.allocate();
+                self.fresh.insert(id);
+                id
+            }
+        };
+        self.pager.write(id, node.encode())?;
+        Ok(id)
+    }
+
+    // `id` is no longer part of the working tree, hand it back to the free list
+    fn retire(&mut self, id: PageId) {
+        if self.fresh.remove(&id) {
+            self.pager.release(id);
+        } else {
+            self.pager.free(id);
+        }
+    }
+
+    // Returns the new page of the subtree, and the separator and page of its new right sibling
+    // if the node had to be split
+    fn insert_into(&mut self, id: PageId, key: &[u8], value: &[u8]) -> Result<(PageId, Split)> {
+        let mut node = self.load(id)?;
+        match &mut node {
+            Node::Leaf { keys, values } => match keys.binary_search_by(|k| k.as_slice().cmp(key)) {
+                Ok(i) => values[i] = value.to_vec(),
+                Err(i) => {
+                    keys.insert(i, key.to_vec());
+                    values.insert(i, value.to_vec());
+                }
+            },
+            Node::Internal { keys, children } => {
+                let i = child_index(keys, key);
+                let (child, split) = self.insert_into(children[i], key, value)?;
+                children[i] = child;
+                if let Some((separator, right)) = split {
+                    keys.insert(i, separator);
+                    children.insert(i + 1, right);
+                }
+            }
+        }
+
+        if node.encoded_size() <= PAGE_PAYLOAD {
+            return Ok((self.store(Some(id), &node)?, None));
+        }
+        let (separator, right) = node.split();
+        let left = self.store(Some(id), &node)?;
+        let right = self.store(None, &right)?;
+        Ok((left, Some((separator, right))))
+    }
+
+    // Returns the new page of the subtree, None if the key was not found (nothing changed)
+    fn delete_from(&mut self, id: PageId, key: &[u8]) -> Result<Option<PageId>> {
+        let mut node = self.load(id)?;
+        match &mut node {
+            Node::Leaf { keys, values } => match keys.binary_search_by(|k| k.as_slice().cmp(key)) {
+                Ok(i) => {
+                    keys.remove(i);
+                    values.remove(i);
+                }
+                Err(_) => return Ok(None),
+            },
+            Node::Internal { keys, children } => {
+                let i = child_index(keys, key);
+                match self.delete_from(children[i], key)? {
+                    Some(child) => children[i] = child,
+                    None => return Ok(None),
+                }
+                self.rebalance(keys, children, i)?;
+            }
+        }
+        Ok(Some(self.store(Some(id), &node)?))
+    }
+
+    // Merge children[i] with a sibling when it became too small and the two fit in one page
+    fn rebalance(&mut self, keys: &mut Vec<Vec<u8>>, children: &mut Vec<PageId>, i: usize) -> Result<()> {
+        if children.len() < 2 || self.load(children[i])?.encoded_size() >= MIN_FILL {
+            return Ok(());
+        }
+
+        // merge with the left sibling if there is one, otherwise with the right one
+        let (left, right) = if i > 0 { (i - 1, i) } else { (i, i + 1) };
+        let mut merged = self.load(children[left])?;
+        merged.merge(keys[left].clone(), self.load(children[right])?);
+        if merged.encoded_size() > PAGE_PAYLOAD {
+            // the sibling is full enough, leave the small node as it is
+            return Ok(());
+        }
+
+        children[left] = self.store(Some(children[left]), &merged)?;
+        self.retire(children[right]);
+        children.remove(right);
+        keys.remove(left);
+        Ok(())
+    }
+
+    // Add the pairs of the subtree `id` to `packer`, in key order
+    fn pack_leaves(&mut self, id: PageId, packer: &mut Packer) -> Result<()> {
+        match self.load(id)? {
+            Node::Leaf { keys, values } => {
+                for (key, value) in keys.into_iter().zip(values) {
+                    packer.add(key, value)?;
+                }
+            }
+            Node::Internal { children, .. } => {
+                for child in children {
+                    self.pack_leaves(child, packer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(
+        &mut self,
+        id: PageId,
+        lo: Bound<&[u8]>,
+        hi: Bound<&[u8]>,
+        reverse: bool,
+        pairs: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        stats: Option<&Cell<ReadStats>>,
+    ) -> Result<()> {
+        match self.load_traced(id, stats)? {
+            Node::Leaf { keys, values } => {
+                explain::record(stats, |stats| stats.entries_scanned += keys.len() as u64);
+                let mut leaf: Vec<_> = keys.into_iter().zip(values).collect();
+                if reverse {
+                    leaf.reverse();
+                }
+                for (key, value) in leaf {
+                    if (lo, hi).contains(key.as_slice()) {
+                        pairs.push((key, value));
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                let mut order: Vec<_> = children.into_iter().enumerate().collect();
+                if reverse {
+                    order.reverse();
+                }
+                for (i, child) in order {
+                    // children[i] holds keys in [keys[i - 1], keys[i]), skip it when that
+                    // interval does not overlap the range
+                    let below = i < keys.len()
+                        && match lo {
+                            Bound::Included(lo) | Bound::Excluded(lo) => keys[i].as_slice() <= lo,
+                            Bound::Unbounded => false,
+                        };
+                    let above = i > 0
+                        && match hi {
+                            Bound::Included(hi) => keys[i - 1].as_slice() > hi,
+                            Bound::Excluded(hi) => keys[i - 1].as_slice() >= hi,
+                            Bound::Unbounded => false,
+                        };
+                    // going up, nothing after a child above the range is in it, and going down
+                    // nothing after a child below it
+                    if (above && !reverse) || (below && reverse) {
+                        break;
+                    }
+                    if !below && !above {
+                        self.scan(child, lo, hi, reverse, pairs, stats)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Builds a tree bottom up from pairs added in key order, every node as full as it can be
+struct Packer {
+    pager: Pager,
+    leaf: Node,
+    level: Vec<(Vec<u8>, PageId)>, // smallest key and page of every leaf written so far
+}
+
+impl Packer {
+    fn new(pager: Pager) -> Self {
+        Packer { pager, leaf: Node::Leaf { keys: Vec::new(), values: Vec::new() }, level: Vec::new() }
+    }
+
+    fn add(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        if self.leaf.encoded_size() + 4 + key.len() + value.len() > PAGE_PAYLOAD {
+            self.write_leaf()?;
+        }
+        if let Node::Leaf { keys, values } = &mut self.leaf {
+            keys.push(key);
+            values.push(value);
+        }
+        Ok(())
+    }
+
+    fn write_leaf(&mut self) -> Result<()> {
+        let leaf = std::mem::replace(&mut self.leaf, Node::Leaf { keys: Vec::new(), values: Vec::new() });
+        if let Node::Leaf { keys, .. } = &leaf {
+            if let Some(first) = keys.first() {
+                let id = self.pager.allocate();
+                self.level.push((first.clone(), id));
+                self.pager.write(id, leaf.encode())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write the internal levels, returns the pager and the root (0 for an empty tree)
+    fn finish(mut self) -> Result<(Pager, PageId)> {
+        self.write_leaf()?;
+        let mut level = std::mem::take(&mut self.level);
+        while level.len() > 1 {
+            // group the nodes of the level into parents that fit in a page
+            let mut groups: Vec<Vec<(Vec<u8>, PageId)>> = vec![Vec::new()];
+            let mut size = NODE_HEADER + 4;
+            for (key, id) in level {
+                let current = groups.last_mut().unwrap();
+                if !current.is_empty() && size + 6 + key.len() > PAGE_PAYLOAD {
+                    size = NODE_HEADER + 4;
+                    groups.push(Vec::new());
+                } else if !current.is_empty() {
+                    size += 6 + key.len();
+                }
+                groups.last_mut().unwrap().push((key, id));
+            }
+            // an internal node needs two children, borrow one from the previous node
+            if groups.len() > 1 && groups.last().unwrap().len() == 1 {
+                let previous = groups.len() - 2;
+                let borrowed = groups[previous].pop().unwrap();
+                groups.last_mut().unwrap().insert(0, borrowed);
+            }
+
+            level = Vec::new();
+            for group in groups {
+                let first = group[0].0.clone();
+                let children = group.iter().map(|&(_, id)| id).collect();
+                let keys = group.into_iter().skip(1).map(|(key, _)| key).collect();
+                let id = self.pager.allocate();
+                self.pager.write(id, Node::Internal { keys, children }.encode())?;
+                level.push((first, id));
+            }
+        }
+        let root = level.first().map_or(0, |&(_, id)| id);
+        Ok((self.pager, root))
+    }
+}
+
+fn vacuum_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".vacuum");
+    path.with_file_name(name)
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
@@ -0,0 +1,90 @@
+// src/error.rs
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying file system (see `Vfs`) failed.
+    Io(io::Error),
+    /// Data read back from disk is not what was written.
+    Corruption(String),
+    /// The caller passed something the store cannot accept (a key that is too large, ...).
+    InvalidArgument(String),
+    /// A write would give two keys the same value in a unique index, `existing` is the key
+    /// that already has it.
+    UniqueViolation { index: String, existing: String },
+    /// The credentials of a client are missing or bad, or do not allow what it asked for (see
+    /// auth.rs).
+    PermissionDenied(String),
+    /// A transaction cannot go on, as another one committed a write to a key it uses since it
+    /// started (see txn.rs). Trying it again from the start is the way out.
+    Conflict(String),
+    /// The store is too far behind with its flushes and compactions to take more writes for
+    /// now (see flush.rs). Trying again later, once they caught up, is the way out.
+    WriteStall(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption(msg) => write!(f, "corruption: {}", msg),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::UniqueViolation { index, existing } => {
+                write!(f, "unique index {} already has this value for key {}", index, existing)
+            }
+            Error::PermissionDenied(msg) => write!(f, "permission denied: {}", msg),
+            Error::Conflict(msg) => write!(f, "conflict: {}", msg),
+            Error::WriteStall(msg) => write!(f, "write stall: {}", msg),
+        }
+    }
+}
+
+impl Error {
+    /// The error `message` (an `Error` as printed) stands for, so the client (client.rs) can
+    /// give back the errors of a server as they were. A message that is none of them is taken
+    /// as an InvalidArgument.
+    pub(crate) fn from_message(message: &str) -> Error {
+        if let Some(message) = message.strip_prefix("I/O error: ") {
+            return Error::Io(io::Error::other(message.to_string()));
+        }
+        if let Some(message) = message.strip_prefix("corruption: ") {
+            return Error::Corruption(message.to_string());
+        }
+        if let Some(message) = message.strip_prefix("invalid argument: ") {
+            return Error::InvalidArgument(message.to_string());
+        }
+        if let Some(message) = message.strip_prefix("permission denied: ") {
+            return Error::PermissionDenied(message.to_string());
+        }
+        if let Some(message) = message.strip_prefix("conflict: ") {
+            return Error::Conflict(message.to_string());
+        }
+        if let Some(message) = message.strip_prefix("write stall: ") {
+            return Error::WriteStall(message.to_string());
+        }
+        let unique = message.strip_prefix("unique index ").unwrap_or_default();
+        if let Some((index, existing)) = unique.split_once(" already has this value for key ") {
+            return Error::UniqueViolation { index: index.to_string(), existing: existing.to_string() };
+        }
+        Error::InvalidArgument(message.to_string())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,60 @@
+// src/explain.rs
+
+/*
+* Read statistics
+*
+* A slow lookup is easier to diagnose from what it did than from how long it took. The
+* `explain_*` methods (`LsmTree::explain_get`, `DiskBTree::explain_range`, ...) do the same
+* as the plain read and return what that read went through along with its result:
+*
+*   nodes_visited     nodes of an in-memory tree (a memtable, the tree of a LogManager) or
+*                     pages of a DiskBTree the search went down through
+*   tables_searched   SSTables a block was read from
+*   tables_skipped    SSTables ruled out without reading a block, by their key range or their
+*                     block index. There are no bloom filters, these checks stand in for them
+*   blocks_read       blocks of a table or pages of a DiskBTree, from the cache or the file
+*   cache_hits        the part of blocks_read found in a cache
+*   bytes_decoded     bytes read from files and decoded, the blocks that were not cached
+*   entries_scanned   keys a scan went through, including the deleted ones it did not return
+*
+* The plain reads do not collect anything. The counters are passed down the read path as an
+* `Option<&Cell<ReadStats>>`, a shared reference so that the sources of a merge can all count
+* into it, None when nobody asked.
+*/
+
+use std::cell::Cell;
+
+/// What one read did, see the top of this file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    pub nodes_visited: u64,
+    pub tables_searched: u64,
+    pub tables_skipped: u64,
+    pub blocks_read: u64,
+    pub cache_hits: u64,
+    pub bytes_decoded: u64,
+    pub entries_scanned: u64,
+}
+
+/// The pairs an `explain_range` returns along with its stats.
+pub type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Update `stats` if the read is explained.
+pub(crate) fn record(stats: Option<&Cell<ReadStats>>, update: impl FnOnce(&mut ReadStats)) {
+    if let Some(stats) = stats {
+        let mut current = stats.get();
+        update(&mut current);
+        stats.set(current);
+    }
+}
+
+/// A block of `size` bytes was read, from a cache if `cached`.
+pub(crate) fn record_block(stats: Option<&Cell<ReadStats>>, cached: bool, size: usize) {
+    record(stats, |stats| {
+        stats.blocks_read += 1;
+        match cached {
+            true => stats.cache_hits += 1,
+            false => stats.bytes_decoded += size as u64,
+        }
+    });
+}
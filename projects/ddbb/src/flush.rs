@@ -0,0 +1,118 @@
+// src/flush.rs
+
+/*
+* Flush policy
+*
+* Both engines keep recent writes in memory and in a log, and every now and then flush them
+* to a table: an LsmTree writes its memtable out as a level 0 run, a LogManager writes a new
+* snapshot and starts an empty log. The longer they wait the longer the log gets, and with it
+* the replay on the next open. A flush is due when the writes since the last one reach any of
+*
+* - `Options::write_buffer_size` bytes of log records
+* - `Options::write_buffer_max_entries` records
+* - `Options::write_buffer_max_age`, counted from the oldest of them
+*
+* and `flush()` forces one. There is no background thread: the limits are checked on every
+* write, so a buffer that is too old is only flushed by the next write (or `flush()`).
+*
+* Flushes run on the writing thread, so the write that fills the buffer waits for the flush
+* (and, in an LsmTree, for the compactions it triggers) before it returns. That is the
+* backpressure that keeps writers from outrunning the disk, and the time those writes spent
+* waiting is reported as `FlushStats::stall`.
+*
+* Write stalls
+*
+* An LsmTree can leave its compactions to another thread instead (`Options::auto_compaction`
+* off, and `LsmTree::compact_pending`), and then nothing keeps the level 0 runs from piling up
+* but the stall triggers, checked before every write:
+*
+* - from `Options::l0_slowdown_trigger` runs on, every write first sleeps for
+*   `Options::slowdown_delay`, which gives the compactions some room
+* - from `Options::l0_stop_trigger` runs on, the memtable is not flushed (another run would
+*   only make reads slower), and it grows past the write buffer
+* - once it holds `Options::max_write_buffer_size` bytes on top of that, writes fail with
+*   `Error::WriteStall`, and succeed again once the compactions have caught up
+*
+* With compactions on the writing thread the runs only pile up when the compactions fail, and
+* a write that would stall runs them first. `FlushStats::slowed` and `FlushStats::stopped`
+* count the writes that were delayed and refused, the delays are part of the stall time.
+*/
+
+use crate::options::Options;
+use std::time::{Duration, Instant};
+
+/// Why a flush happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushReason {
+    Size,
+    Entries,
+    Age,
+    Manual,
+}
+
+/// Flushes done since the engine was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlushStats {
+    pub flushes: u64,
+    pub by_size: u64,
+    pub by_entries: u64,
+    pub by_age: u64,
+    pub manual: u64,
+    /// Time writes spent waiting for the flushes they triggered, and slowed down.
+    pub stall: Duration,
+    /// Writes delayed by `Options::slowdown_delay`.
+    pub slowed: u64,
+    /// Writes refused with a WriteStall.
+    pub stopped: u64,
+}
+
+impl FlushStats {
+    pub(crate) fn record(&mut self, reason: FlushReason, took: Duration) {
+        self.flushes += 1;
+        match reason {
+            FlushReason::Size => self.by_size += 1,
+            FlushReason::Entries => self.by_entries += 1,
+            FlushReason::Age => self.by_age += 1,
+            FlushReason::Manual => self.manual += 1,
+        }
+        if reason != FlushReason::Manual {
+            self.stall += took;
+        }
+    }
+}
+
+/// The writes since the last flush.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct WriteBuffer {
+    pub bytes: usize,
+    pub entries: usize,
+    oldest: Option<Instant>,
+}
+
+impl WriteBuffer {
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        self.entries += 1;
+        self.oldest.get_or_insert_with(Instant::now);
+    }
+
+    /// The limit the buffer has reached, None if it can keep growing.
+    pub fn due(&self, options: &Options) -> Option<FlushReason> {
+        if self.entries == 0 {
+            return None;
+        }
+        if self.bytes >= options.write_buffer_size {
+            Some(FlushReason::Size)
+        } else if options.write_buffer_max_entries.is_some_and(|max| self.entries >= max) {
+            Some(FlushReason::Entries)
+        } else if options.write_buffer_max_age.zip(self.oldest).is_some_and(|(max, oldest)| oldest.elapsed() >= max) {
+            Some(FlushReason::Age)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+}
@@ -0,0 +1,232 @@
+// src/histogram.rs
+
+/*
+* Latency histograms
+*
+* With `Options::latency_histograms` on, a LogManager or an LsmTree times its inserts, searches,
+* deletes, scans and batches, and counts every latency in the histogram of its kind of operation. The
+* histograms are read with `LogManager::latency_histograms` and `LsmMetrics::latencies`, and
+* are the latency lines of STATS (see admin.rs), so the tail latencies that a flush, a
+* compaction or a sync add to some of the operations show up next to the other statistics.
+*
+* A histogram is HDR-style: the latencies, in nanoseconds, are counted in buckets whose width
+* grows with them, 16 buckets per power of two, so every bucket is within 1/16 (6.25%) of the
+* latencies it counts, from 1ns to centuries, in under a thousand counters. A percentile is the
+* upper end of the bucket it falls in, capped at the largest latency recorded. The mean and the
+* maximum are exact.
+*
+* Recording is cheap: reading the clock twice and a few relaxed atomic additions, with no lock,
+* so the searches that run in parallel on a shared LogManager do not wait for each other. With
+* the option off (the default) nothing is allocated and the clock is not even read. Only the
+* operations that succeed are counted. An insert is `insert` (and `set_path`), a search
+* `search` or `get` (and `get_at`), a scan `range`, `scan` and their variants, and a batch
+* `write_batch` or `commit_prepared` of a LogManager, counted once whatever its writes.
+*/
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Buckets per power of two, and their number for every u64 of nanoseconds
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+/// The latencies of one kind of operation, see the top of this file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>, // by bucket, empty until something is recorded
+    count: u64,
+    sum: u64, // nanoseconds
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram::default()
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = nanos(latency);
+        if self.counts.is_empty() {
+            self.counts = vec![0; BUCKETS];
+        }
+        self.counts[bucket(nanos)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    /// Add the latencies of `other`, of another LogManager say.
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.counts.is_empty() {
+            return;
+        }
+        if self.counts.is_empty() {
+            self.counts = vec![0; BUCKETS];
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_nanos(self.sum.checked_div(self.count).unwrap_or(0))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// The latency `percent` percent of the operations took at most (99.9 for the p99.9), to
+    /// within 1/16, zero if nothing was recorded.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        // the nearest rank
+        let rank = ((percent.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(highest(i).min(self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+/// The histograms of a LogManager or an LsmTree, by kind of operation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistograms {
+    pub insert: Histogram,
+    pub search: Histogram,
+    pub delete: Histogram,
+    pub scan: Histogram,
+    pub batch: Histogram,
+}
+
+impl LatencyHistograms {
+    /// The histograms with the names of their operations.
+    pub fn by_operation(&self) -> [(&'static str, &Histogram); 5] {
+        [
+            ("insert", &self.insert),
+            ("search", &self.search),
+            ("delete", &self.delete),
+            ("scan", &self.scan),
+            ("batch", &self.batch),
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Operation {
+    Insert,
+    Search,
+    Delete,
+    Scan,
+    Batch,
+}
+
+/// Where a store records its latencies, nothing when `Options::latency_histograms` is off.
+pub(crate) struct LatencyRecorder(Option<Box<[AtomicHistogram; 5]>>);
+
+impl LatencyRecorder {
+    pub(crate) fn new(enabled: bool) -> Self {
+        LatencyRecorder(enabled.then(Box::default))
+    }
+
+    /// When an operation starts, None when nothing is recorded.
+    pub(crate) fn start(&self) -> Option<Instant> {
+        self.0.as_ref().map(|_| Instant::now())
+    }
+
+    /// Count the latency of an operation that started at `started`.
+    pub(crate) fn record(&self, operation: Operation, started: Option<Instant>) {
+        if let (Some(histograms), Some(started)) = (&self.0, started) {
+            histograms[operation as usize].record(nanos(started.elapsed()));
+        }
+    }
+
+    /// A copy of the histograms, None when nothing is recorded.
+    pub(crate) fn histograms(&self) -> Option<LatencyHistograms> {
+        let [insert, search, delete, scan, batch] = self.0.as_deref()?;
+        Some(LatencyHistograms {
+            insert: insert.load(),
+            search: search.load(),
+            delete: delete.load(),
+            scan: scan.load(),
+            batch: batch.load(),
+        })
+    }
+}
+
+// A Histogram that `&self` records into
+struct AtomicHistogram {
+    counts: Box<[AtomicU64]>,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> Self {
+        AtomicHistogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AtomicHistogram {
+    fn record(&self, nanos: u64) {
+        self.counts[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    // The counts may be a few operations apart from each other while others record, the
+    // total is that of the buckets
+    fn load(&self) -> Histogram {
+        let counts: Vec<u64> = self.counts.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        let count = counts.iter().sum();
+        if count == 0 {
+            return Histogram::default();
+        }
+        let sum = self.sum.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        Histogram { counts, count, sum, max }
+    }
+}
+
+fn nanos(latency: Duration) -> u64 {
+    latency.as_nanos().min(u64::MAX as u128) as u64
+}
+
+// The bucket of `nanos`: exact below 2 * SUB_BUCKETS, then SUB_BUCKETS per power of two
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+    let shift = 63 - nanos.leading_zeros() - SUB_BUCKET_BITS;
+    ((shift as u64 + 1) * SUB_BUCKETS + ((nanos >> shift) - SUB_BUCKETS)) as usize
+}
+
+// The largest latency of bucket `i`
+fn highest(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB_BUCKETS {
+        return i;
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let lowest = (SUB_BUCKETS + i % SUB_BUCKETS) << shift;
+    lowest + ((1 << shift) - 1)
+}
@@ -0,0 +1,428 @@
+// src/http.rs
+
+/*
+* HTTP front end
+*
+* `HttpServer` serves the same LogManager as the TCP server of server.rs (String keys and
+* values) over HTTP, for scripts, browsers and the health checks of whatever runs the process.
+* `ddbb <dir> serve-http <address>` runs one (see main.rs).
+*
+*   GET    /keys/{key}                      200 {"key": k, "value": v}, 404 if there is no such key
+*   PUT    /keys/{key}   {"value": v}       204, inserts or overwrites the pair
+*   DELETE /keys/{key}                      204
+*   GET    /scan?prefix=p&limit=n&after=t   200 {"pairs": [{"key": k, "value": v}, ...], "next": t}
+*   GET    /health                          200 {"status": "ok"}
+*
+* The keys in paths and the query parameters are percent-encoded. Every parameter of /scan is
+* optional: no prefix is every key, and `next` is null unless a limit cut the scan short, in
+* which case it is the `after` of the next page (see scan.rs).
+*
+* Errors come back as {"error": message}, with 400 for a request the store cannot accept (like
+* a key or value with whitespace in it, which the log cannot hold), 409 for a unique index
+* violation, 404 and 405 for a path or method that does not exist, and 500 for the rest.
+*
+* With an `Auth` in the `ServerOptions` (see auth.rs) every request but /health needs
+* "Authorization: Bearer <token>", or "Authorization: Basic ..." with a user name and password,
+* and is checked against their Acl. Missing or bad credentials are a 401, a request the Acl
+* does not allow a 403. Since the credentials travel with every request, this wants TLS.
+*
+* Every request runs through the query language of query.rs, like the requests of server.rs,
+* so both front ends agree on what a key, a value and a page are.
+*
+* Only what this needs of HTTP/1.1 is implemented: requests with a Content-Length body (no
+* chunked bodies), and persistent connections unless the client asks for "Connection: close"
+* or speaks HTTP/1.0. Each connection has a thread of its own, as in server.rs. With TLS in
+* the `ServerOptions` (see tls.rs) it is HTTPS. It shuts down like the server of server.rs, and
+* with `ServerOptions::follow` it is a follower like it too (a PUT or DELETE is then a 403, and
+* so is a read with `FollowerOptions::max_lag` when it is too far behind), with
+* `ServerOptions::raft` a Raft node (a PUT or DELETE to a node that is not the leader is a 403
+* naming the leader). The other nodes still reach it over the TCP protocol only, see raft.rs,
+* and so do the other members of its cluster with `ServerOptions::membership`, see
+* membership.rs.
+*/
+
+use crate::auth::{Acl, Auth, Credentials};
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use crate::query::{Keys, Query, QueryResult};
+use crate::scan::ScanOptions;
+use crate::server::{self, Node, ServerOptions, ShutdownHandle};
+use crate::tls::Stream;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+/// Largest request body accepted, in bytes. The request line and every header line are held
+/// to the same limit.
+pub const MAX_BODY: usize = 1 << 20;
+
+type Db = LogManager<String, String>;
+
+pub struct HttpServer {
+    listener: TcpListener,
+    db: Arc<Mutex<Db>>,
+    options: Arc<ServerOptions>,
+    shutdown: ShutdownHandle,
+}
+
+struct Request {
+    method: String,
+    target: String,
+    body: Vec<u8>,
+    keep_alive: bool,
+    authorization: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    body: Option<Value>,
+    // a header to send along, as Allow with a 405
+    header: Option<(&'static str, &'static str)>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Response { status, body: Some(body), header: None }
+    }
+
+    fn empty(status: u16) -> Self {
+        Response { status, body: None, header: None }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Response::json(status, json!({ "error": message.into() }))
+    }
+
+    fn from_error(error: &Error) -> Self {
+        let status = match error {
+            Error::InvalidArgument(_) => 400,
+            Error::UniqueViolation { .. } | Error::Conflict(_) => 409,
+            Error::PermissionDenied(_) => 403,
+            Error::WriteStall(_) => 503,
+            Error::Io(_) | Error::Corruption(_) => 500,
+        };
+        Response::error(status, error.to_string())
+    }
+}
+
+impl HttpServer {
+    /// Listen on `address` (port 0 picks a free port, see `local_addr`) for clients of `db`.
+    pub fn bind(address: impl ToSocketAddrs, db: Db) -> Result<Self> {
+        Self::bind_with(address, db, ServerOptions::default())
+    }
+
+    /// Same as `bind`, with non-default `ServerOptions` (TLS makes it HTTPS).
+    pub fn bind_with(address: impl ToSocketAddrs, db: Db, options: ServerOptions) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let shutdown = ShutdownHandle::new(&listener)?;
+        Ok(HttpServer { listener, db: Arc::new(Mutex::new(db)), options: Arc::new(options), shutdown })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accept connections and serve each of them on a thread of its own, until shut down.
+    pub fn serve(&self) -> Result<()> {
+        let (db, options) = (self.db.clone(), self.options.clone());
+        let (listener, shutdown) = (&self.listener, &self.shutdown);
+        server::serve_until_shutdown(listener, shutdown, &self.db, &self.options, move |stream, node| {
+            let peer = stream.peer_addr().map_or("unknown peer".to_string(), |peer| peer.to_string());
+            let stream = Stream::accept(stream, options.tls.as_ref());
+            if let Err(e) = stream.and_then(|stream| serve_connection(stream, &db, &options, node)) {
+                eprintln!("HTTP connection from {} failed: {}", peer, e);
+            }
+        })
+    }
+}
+
+fn serve_connection(
+    stream: Stream,
+    db: &Mutex<Db>,
+    options: &ServerOptions,
+    node: &Node,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()), // the client closed the connection
+            Err(response) => {
+                // the rest of the connection cannot be parsed
+                write_response(&mut writer, &response, false)?;
+                return Ok(writer.flush()?);
+            }
+        };
+        let response = handle(&request, db, options, node);
+        write_response(&mut writer, &response, request.keep_alive)?;
+        writer.flush()?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// The next request, None if the connection was closed before it started
+fn read_request(reader: &mut impl BufRead) -> std::result::Result<Option<Request>, Response> {
+    let Some(request_line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let [method, target, version] = parts[..] else {
+        return Err(Response::error(400, "bad request line"));
+    };
+    let mut keep_alive = version == "HTTP/1.1";
+    if !keep_alive && version != "HTTP/1.0" {
+        return Err(Response::error(505, format!("unsupported version {}", version)));
+    }
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| Response::error(400, "the headers are cut short"))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| Response::error(400, "bad header line"))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value.parse().map_err(|_| Response::error(400, "bad Content-Length"))?;
+            }
+            "transfer-encoding" => return Err(Response::error(501, "chunked bodies are not supported")),
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            "authorization" => authorization = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(Response::error(413, format!("the body is longer than {} bytes", MAX_BODY)));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|_| Response::error(400, "the body is cut short"))?;
+    let (method, target) = (method.to_string(), target.to_string());
+    Ok(Some(Request { method, target, body, keep_alive, authorization }))
+}
+
+// One line of the request head without its "\r\n", None at the end of the stream
+fn read_line(reader: &mut impl BufRead) -> std::result::Result<Option<String>, Response> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_BODY as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .map_err(|e| Response::error(400, e.to_string()))?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > MAX_BODY || line.last() != Some(&b'\n') {
+        return Err(Response::error(400, "the request head is cut short or too long"));
+    }
+    let line = String::from_utf8(line).map_err(|_| Response::error(400, "the request head is not UTF-8"))?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn handle(request: &Request, db: &Mutex<Db>, options: &ServerOptions, node: &Node) -> Response {
+    let (path, parameters) = match request.target.split_once('?') {
+        Some((path, parameters)) => (path, parameters),
+        None => (request.target.as_str(), ""),
+    };
+    if path == "/health" {
+        return match request.method.as_str() {
+            "GET" => Response::json(200, json!({ "status": "ok" })),
+            _ => not_allowed("GET"),
+        };
+    }
+    if path != "/scan" && !path.starts_with("/keys/") {
+        return Response::error(404, format!("no such path {}", path));
+    }
+    let acl = match authorize(request, options.auth.as_ref()) {
+        Ok(acl) => acl,
+        Err(e) => {
+            let challenge = ("WWW-Authenticate", "Bearer realm=\"ddbb\", Basic realm=\"ddbb\"");
+            return Response { header: Some(challenge), ..Response::error(401, e.to_string()) };
+        }
+    };
+    let result = match path.strip_prefix("/keys/") {
+        Some(key) => {
+            let key = percent_decode(key, false).and_then(|key| checked("key", key));
+            key.and_then(|key| handle_key(request, key, &acl, db, options, node))
+        }
+        None => match request.method.as_str() {
+            "GET" => scan(parameters, &acl, db, options, node),
+            _ => Ok(not_allowed("GET")),
+        },
+    };
+    result.unwrap_or_else(|e| Response::from_error(&e))
+}
+
+// The Acl of the request's credentials, all access if the server has no Auth
+fn authorize(request: &Request, auth: Option<&Auth>) -> Result<Acl> {
+    let Some(auth) = auth else {
+        return Ok(Acl::default());
+    };
+    let missing = || Error::PermissionDenied("the request has no credentials".to_string());
+    let authorization = request.authorization.as_deref().ok_or_else(missing)?;
+    let (scheme, value) = authorization.split_once(' ').ok_or_else(missing)?;
+    let credentials = if scheme.eq_ignore_ascii_case("Bearer") {
+        Credentials::Token(value.trim().to_string())
+    } else if scheme.eq_ignore_ascii_case("Basic") {
+        let bad = || Error::PermissionDenied("bad Basic credentials".to_string());
+        let decoded = base64_decode(value.trim()).and_then(|bytes| String::from_utf8(bytes).ok());
+        let decoded = decoded.ok_or_else(bad)?;
+        let (name, password) = decoded.split_once(':').ok_or_else(bad)?;
+        Credentials::User { name: name.to_string(), password: password.to_string() }
+    } else {
+        return Err(Error::PermissionDenied(format!("unknown authorization scheme {}", scheme)));
+    };
+    Ok(auth.authenticate(&credentials)?.clone())
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in text.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn handle_key(
+    request: &Request,
+    key: String,
+    acl: &Acl,
+    db: &Mutex<Db>,
+    options: &ServerOptions,
+    node: &Node,
+) -> Result<Response> {
+    let query = match request.method.as_str() {
+        "GET" => Query::Get(key.clone()),
+        "PUT" => {
+            let body: Value = serde_json::from_slice(&request.body)
+                .map_err(|e| Error::InvalidArgument(format!("the body is not JSON: {}", e)))?;
+            let Some(Value::String(value)) = body.get("value") else {
+                return Err(Error::InvalidArgument("the body has no string \"value\"".to_string()));
+            };
+            Query::Set(key.clone(), checked("value", value.clone())?)
+        }
+        "DELETE" => Query::Del(key.clone()),
+        _ => return Ok(not_allowed("GET, PUT, DELETE")),
+    };
+    acl.check(&query)?;
+    match server::execute(db, options, node, &query)? {
+        QueryResult::Value(Some(value)) => Ok(Response::json(200, json!({ "key": key, "value": value }))),
+        QueryResult::Value(None) => Ok(Response::error(404, format!("no such key {}", key))),
+        _ => Ok(Response::empty(204)),
+    }
+}
+
+fn scan(
+    parameters: &str,
+    acl: &Acl,
+    db: &Mutex<Db>,
+    options: &ServerOptions,
+    node: &Node,
+) -> Result<Response> {
+    let mut prefix = String::new();
+    let mut scan = ScanOptions::default();
+    for parameter in parameters.split('&').filter(|parameter| !parameter.is_empty()) {
+        let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        let value = percent_decode(value, true)?;
+        match name {
+            "prefix" => prefix = value,
+            "limit" => {
+                let limit = value.parse().map_err(|_| Error::InvalidArgument(format!("bad limit {}", value)));
+                scan.limit = Some(limit?);
+            }
+            "after" => scan.resume = Some(value),
+            _ => return Err(Error::InvalidArgument(format!("unknown parameter {}", name))),
+        }
+    }
+    let query = Query::Scan { keys: Keys::Prefix(prefix), options: scan, reverse: false };
+    acl.check(&query)?;
+    let QueryResult::Pairs { pairs, next } = server::execute(db, options, node, &query)? else {
+        unreachable!("a SCAN returns pairs");
+    };
+    let pairs: Vec<Value> = pairs.iter().map(|(key, value)| json!({ "key": key, "value": value })).collect();
+    Ok(Response::json(200, json!({ "pairs": pairs, "next": next })))
+}
+
+fn not_allowed(allow: &'static str) -> Response {
+    Response { header: Some(("Allow", allow)), ..Response::error(405, "method not allowed") }
+}
+
+// Keys and values are whitespace separated in the log, see log.rs
+fn checked(what: &str, text: String) -> Result<String> {
+    if text.is_empty() || text.contains(char::is_whitespace) {
+        return Err(Error::InvalidArgument(format!("the {} {:?} is empty or has whitespace", what, text)));
+    }
+    Ok(text)
+}
+
+fn percent_decode(text: &str, plus_is_space: bool) -> Result<String> {
+    let bad = || Error::InvalidArgument(format!("bad percent encoding in {}", text));
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut input = text.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'%' => {
+                let hex = [input.next().ok_or_else(bad)?, input.next().ok_or_else(bad)?];
+                let hex = std::str::from_utf8(&hex).map_err(|_| bad())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| bad())?);
+            }
+            b'+' if plus_is_space => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| bad())
+}
+
+fn write_response(out: &mut impl Write, response: &Response, keep_alive: bool) -> Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Internal Server Error",
+    };
+    let body = response.body.as_ref().map(|body| body.to_string()).unwrap_or_default();
+    write!(out, "HTTP/1.1 {} {}\r\n", response.status, reason)?;
+    if response.body.is_some() {
+        write!(out, "Content-Type: application/json\r\n")?;
+    }
+    if let Some((name, value)) = response.header {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    if !keep_alive {
+        write!(out, "Connection: close\r\n")?;
+    }
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    Ok(())
+}
@@ -0,0 +1,118 @@
+// src/index.rs
+
+/*
+* Secondary indexes
+*
+* A LogManager finds a value by its key. A secondary index finds the keys whose values have
+* some property, say every user living in a given city, without scanning every pair:
+*
+*   primary:  "alice" -> User { city: "Oslo" }       index "city":  "Lima" -> ["carol"]
+*             "bob"   -> User { city: "Oslo" }                      "Oslo" -> ["alice", "bob"]
+*             "carol" -> User { city: "Lima" }
+*
+* An index is declared with an extractor, a function from a value to its index key (None
+* leaves the pair out of the index). Index keys are encoded with keycodec.rs, so any
+* `OrderedKey` (an integer, a string, a tuple of them, ...) can be used.
+*
+* The index is derived from the primary data and nothing else: it is kept up to date by every
+* insert and delete (the old value of an overwritten key is removed from it first), and it
+* lives only in memory. It is built from the pairs already there when it is declared, after
+* the recovery of `open`, so it always matches the data that survived a crash and can never
+* drift from it the way hand-maintained reverse mappings do. The price is the rebuild on
+* every open, one pass over the pairs per index.
+*
+* ####################################################################################
+*
+* Unique indexes
+*
+* A unique index allows at most one key per index key. The check runs before the write touches
+* the tree or the log: a conflicting insert fails with `Error::UniqueViolation` and changes
+* nothing, neither the pair nor any index. Overwriting a key with a value that keeps its own
+* index key is not a conflict. Declaring a unique index over pairs that already share an index
+* key fails the same way, so a unique index never holds a duplicate.
+*
+* The inserts of a prepared batch (see `LogManager::prepare`) hold their index keys until the
+* batch is committed or aborted: the commit applies them without checking again, so a write of
+* another key with one of them is a conflict in the meantime.
+*/
+
+use crate::btree::BTree;
+use crate::keycodec::{self, OrderedKey};
+use std::fmt::Debug;
+
+type Extractor<V> = Box<dyn Fn(&V) -> Option<Vec<u8>> + Send>;
+
+pub(crate) struct SecondaryIndex<K: Ord + Clone + Debug, V> {
+    extractor: Extractor<V>,
+    unique: bool,
+    // encoded index key -> primary keys, sorted
+    entries: BTree<Vec<u8>, Vec<K>>,
+}
+
+impl<K: Ord + Clone + Debug, V> SecondaryIndex<K, V> {
+    pub fn new<I: OrderedKey>(extractor: impl Fn(&V) -> Option<I> + Send + 'static, unique: bool) -> Self {
+        SecondaryIndex {
+            extractor: Box::new(move |value| extractor(value).map(|index_key| keycodec::encode(&index_key))),
+            unique,
+            entries: BTree::new(),
+        }
+    }
+
+    /// The encoded index key of `value`, None if it is not indexed.
+    pub fn index_key(&self, value: &V) -> Option<Vec<u8>> {
+        (self.extractor)(value)
+    }
+
+    /// For a unique index, the other key that already has the index key of `value`.
+    pub fn conflict(&self, key: &K, value: &V) -> Option<&K> {
+        if !self.unique {
+            return None;
+        }
+        let index_key = self.index_key(value)?;
+        self.lookup(&index_key).iter().find(|other| *other != key)
+    }
+
+    /// For a unique index, the key of `pairs` other than `key` that has the index key of
+    /// `value`.
+    pub fn conflict_in<'a>(&self, key: &K, value: &V, pairs: &'a [(K, V)]) -> Option<&'a K> {
+        if !self.unique {
+            return None;
+        }
+        let index_key = self.index_key(value)?;
+        let taken = |(other, value): &&(K, V)| {
+            other != key && self.index_key(value).as_ref() == Some(&index_key)
+        };
+        pairs.iter().find(taken).map(|(other, _)| other)
+    }
+
+    pub fn add(&mut self, key: &K, value: &V) {
+        let Some(index_key) = self.index_key(value) else { return };
+        match self.entries.search_mut(&index_key) {
+            Some(keys) => {
+                if let Err(i) = keys.binary_search(key) {
+                    keys.insert(i, key.clone());
+                }
+            }
+            None => {
+                self.entries.insert(index_key, vec![key.clone()]);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K, value: &V) {
+        let Some(index_key) = self.index_key(value) else { return };
+        if let Some(keys) = self.entries.search_mut(&index_key) {
+            if let Ok(i) = keys.binary_search(key) {
+                keys.remove(i);
+            }
+            if keys.is_empty() {
+                self.entries.delete(&index_key);
+            }
+        }
+    }
+
+    /// Primary keys of the pairs whose index key encodes to `index_key`, in key order.
+    pub fn lookup(&self, index_key: &[u8]) -> &[K] {
+        self.entries.search(&index_key.to_vec()).map_or(&[], Vec::as_slice)
+    }
+}
@@ -0,0 +1,167 @@
+// src/json.rs
+
+/*
+* JSON values
+*
+* `Json` is a JSON document usable as the value type of a LogManager. Its text form is compact
+* JSON where every whitespace character inside a string is written as a \uXXXX escape, so a
+* document is always a single token of the log (see log.rs) and parses back to itself.
+*
+* A path points inside a document:
+*
+*   $                 the whole document
+*   $.user.name       member "name" of member "user"
+*   $.items[2].id     member "id" of the third element of "items"
+*
+* Member names are taken as they are, up to the next '.' or '[', and cannot contain whitespace.
+*
+* ####################################################################################
+*
+* Patches
+*
+* `LogManager::set_path` replaces the part of a document a path points to. Instead of logging
+* the whole new document it logs a patch record, which is as long as the path and the new part:
+*
+*   PATCH <key> <path> <value>
+*
+* Replay applies the patch to the document the key has at that point of the log. A patch never
+* fails halfway: `set_path` applies it to a copy first, and only a patch that applied cleanly
+* is logged. Setting a path creates what is missing on the way, a missing document or member,
+* or a null, becomes an empty object. An array index must be inside the array, or one past its
+* end to append. Anything else (a member of a number, an index of an object) is an error.
+*
+* Snapshots (compaction) write the whole documents, so a patch is only replayed until the next
+* compaction.
+*/
+
+use crate::error::{Error, Result};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// A JSON document, see the top of this file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Json(pub Value);
+
+impl From<Value> for Json {
+    fn from(value: Value) -> Self {
+        Json(value)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // compact JSON has whitespace only inside strings, where an escape means the same
+        for c in self.0.to_string().chars() {
+            if c.is_whitespace() {
+                write!(f, "\\u{:04x}", c as u32)?;
+            } else {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Json {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map(Json).map_err(|e| Error::InvalidArgument(format!("bad JSON: {}", e)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Member(String),
+    Index(usize),
+}
+
+/// A path inside a JSON document, like `$.user.name`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonPath {
+    steps: Vec<Step>,
+}
+
+impl FromStr for JsonPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bad = |why: &str| Error::InvalidArgument(format!("bad JSON path {:?}: {}", s, why));
+        let mut rest = s.strip_prefix('$').ok_or_else(|| bad("it does not start with $"))?;
+        let mut steps = Vec::new();
+        while let Some(c) = rest.chars().next() {
+            if c == '.' {
+                let end = rest[1..].find(['.', '[']).map_or(rest.len(), |i| i + 1);
+                let name = &rest[1..end];
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    return Err(bad("a member name is empty or contains whitespace"));
+                }
+                steps.push(Step::Member(name.to_string()));
+                rest = &rest[end..];
+            } else if c == '[' {
+                let end = rest.find(']').ok_or_else(|| bad("an index is missing its ]"))?;
+                steps.push(Step::Index(rest[1..end].parse().map_err(|_| bad("an index is not a number"))?));
+                rest = &rest[end + 1..];
+            } else {
+                return Err(bad("expected . or ["));
+            }
+        }
+        Ok(JsonPath { steps })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$")?;
+        for step in &self.steps {
+            match step {
+                Step::Member(name) => write!(f, ".{}", name)?,
+                Step::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Json {
+    /// The part of the document `path` points to, None if there is nothing there.
+    pub fn get_path(&self, path: &JsonPath) -> Option<&Value> {
+        path.steps.iter().try_fold(&self.0, |value, step| match step {
+            Step::Member(name) => value.as_object()?.get(name),
+            Step::Index(i) => value.as_array()?.get(*i),
+        })
+    }
+
+    /// Replace the part of the document `path` points to with `new`, see the top of this file.
+    /// On error the document is left as it was.
+    pub fn set_path(&mut self, path: &JsonPath, new: Value) -> Result<()> {
+        let mut patched = self.0.clone();
+        let mut value = &mut patched;
+        for (depth, step) in path.steps.iter().enumerate() {
+            let cannot = |what: &str| {
+                let at = JsonPath { steps: path.steps[..depth].to_vec() };
+                Error::InvalidArgument(format!("{} is not {}, cannot set {}", at, what, path))
+            };
+            value = match step {
+                Step::Member(name) => {
+                    if value.is_null() {
+                        *value = Value::Object(Default::default());
+                    }
+                    let object = value.as_object_mut().ok_or_else(|| cannot("an object"))?;
+                    object.entry(name.clone()).or_insert(Value::Null)
+                }
+                Step::Index(i) => {
+                    let array = value.as_array_mut().ok_or_else(|| cannot("an array"))?;
+                    if *i == array.len() {
+                        array.push(Value::Null);
+                    }
+                    array.get_mut(*i).ok_or_else(|| cannot("that long an array"))?
+                }
+            };
+        }
+        *value = new;
+        self.0 = patched;
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+// src/keycodec.rs
+
+/*
+* Order-preserving key encoding
+*
+* The on-disk structures (sstable.rs, disk_btree.rs, lsm.rs) compare keys as byte strings. A
+* key made of several fields, like (tenant, timestamp), only sorts right there if its encoding
+* compares byte by byte the way the fields compare one after the other. Concatenating the
+* fields as text does not: "9" > "10", and ("ab", "c") and ("a", "bc") both become "abc".
+*
+* `encode` turns anything implementing `OrderedKey` into such a byte string and `decode` reads
+* it back:
+*
+* unsigned integers   big endian, fixed width
+* signed integers     big endian with the sign bit flipped, so negative numbers come first
+* bool                0 or 1
+* String, Vec<u8>     the bytes with every 0x00 escaped as 0x00 0xFF, then 0x00 0x01
+* Option<T>           0x00 for None, 0x01 followed by T for Some (None sorts first)
+* tuples              the fields one after the other
+*
+* The terminator 0x00 0x01 is smaller than any escaped or plain byte that can follow it, so a
+* string sorts before every longer string it is a prefix of, and the next field never takes
+* part in comparing two different strings. Since every field knows where it ends, the encoding
+* of the first fields of a tuple is a prefix of the encoding of the whole tuple: scanning the
+* keys that start with `encode(&("tenant",))` finds every ("tenant", _) key, in order.
+*
+* The encoding has no type tags, a key has to be decoded with the type it was encoded with.
+*
+* ####################################################################################
+*
+* Encoded keys in a LogManager
+*
+* A LogManager (log.rs) needs keys that print to a single token and parse back, which a tuple
+* does not. `EncodedKey<T>` wraps the encoding of a T: it prints as the encoding in hex, so
+* the log holds ("ann", 5u64) as 616e6e00010000000000000005 (not readable, but hex sorts
+* like the bytes it stands for), and it compares by the encoding, which is the order of T.
+*
+* Since the keys starting with the encoding of a tuple prefix are contiguous in that order,
+* `prefix_range` turns a prefix into a range of keys, which is what `LogManager::scan_prefix`
+* scans: every (tenant, _) key of one tenant, without knowing any key of it.
+*/
+
+use crate::error::{Error, Result};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::str::FromStr;
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+/// A type whose values can be encoded into byte strings that sort like the values.
+pub trait OrderedKey: Sized {
+    /// Append the encoding of `self` to `out`.
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// Read a value from the start of `input`, advancing it past the value.
+    fn decode_from(input: &mut &[u8]) -> Result<Self>;
+}
+
+/// The order-preserving encoding of `key`.
+pub fn encode<T: OrderedKey>(key: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    key.encode_to(&mut out);
+    out
+}
+
+/// Decode a key produced by `encode`, failing if `data` holds anything after it.
+pub fn decode<T: OrderedKey>(mut data: &[u8]) -> Result<T> {
+    let key = T::decode_from(&mut data)?;
+    if !data.is_empty() {
+        return Err(Error::Corruption(format!("{} bytes left after the key", data.len())));
+    }
+    Ok(key)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(Error::Corruption("key ends in the middle of a field".to_string()));
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                let bytes = take(input, std::mem::size_of::<$t>())?;
+                Ok(<$t>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                out.extend_from_slice(&flipped.to_be_bytes());
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                let bytes = take(input, std::mem::size_of::<$t>())?;
+                let flipped = <$u>::from_be_bytes(bytes.try_into().unwrap());
+                Ok((flipped ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl OrderedKey for bool {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        match take(input, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(Error::Corruption(format!("{:#04x} is not a bool", byte))),
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == ESCAPE {
+            out.push(ESCAPED_ZERO);
+        }
+    }
+    out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+}
+
+fn decode_bytes(input: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = take(input, 1)?[0];
+        if byte != ESCAPE {
+            bytes.push(byte);
+            continue;
+        }
+        match take(input, 1)?[0] {
+            ESCAPED_ZERO => bytes.push(ESCAPE),
+            TERMINATOR => return Ok(bytes),
+            other => return Err(Error::Corruption(format!("{:#04x} after an escape byte", other))),
+        }
+    }
+}
+
+impl OrderedKey for Vec<u8> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        decode_bytes(input)
+    }
+}
+
+impl OrderedKey for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(decode_bytes(input)?).map_err(|_| Error::Corruption("string key is not UTF-8".to_string()))
+    }
+}
+
+impl<T: OrderedKey> OrderedKey for Option<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode_to(out);
+            }
+        }
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        match take(input, 1)?[0] {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode_from(input)?)),
+            byte => Err(Error::Corruption(format!("{:#04x} is not an option tag", byte))),
+        }
+    }
+}
+
+macro_rules! tuple_key {
+    ($($name:ident),+) => {
+        impl<$($name: OrderedKey),+> OrderedKey for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(out);)+
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_from(input)?,)+))
+            }
+        }
+    };
+}
+
+tuple_key!(A);
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+tuple_key!(A, B, C, D, E);
+tuple_key!(A, B, C, D, E, F);
+
+/// A key of type T kept as its encoding, see the top of this file.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EncodedKey<T> {
+    bytes: Vec<u8>,
+    key: PhantomData<fn() -> T>,
+}
+
+impl<T: OrderedKey> EncodedKey<T> {
+    pub fn new(key: &T) -> Self {
+        EncodedKey { bytes: encode(key), key: PhantomData }
+    }
+
+    /// The key this is the encoding of.
+    pub fn get(&self) -> T {
+        // the bytes were either encoded from a T or checked by from_str
+        decode(&self.bytes).expect("invalid encoded key")
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<T: OrderedKey> From<T> for EncodedKey<T> {
+    fn from(key: T) -> Self {
+        EncodedKey::new(&key)
+    }
+}
+
+impl<T: OrderedKey + fmt::Debug> fmt::Debug for EncodedKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EncodedKey").field(&self.get()).finish()
+    }
+}
+
+impl<T> fmt::Display for EncodedKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.bytes {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: OrderedKey> FromStr for EncodedKey<T> {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bad = || Error::InvalidArgument(format!("{:?} is not a hex encoded key", s));
+        if !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(bad());
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| bad()))
+            .collect::<Result<Vec<u8>>>()?;
+        decode::<T>(&bytes)?;
+        Ok(EncodedKey { bytes, key: PhantomData })
+    }
+}
+
+/// The range of the keys whose encoding starts with the encoding of `prefix`, usually the
+/// first fields of a tuple key.
+pub fn prefix_range<T, P: OrderedKey>(prefix: &P) -> (Bound<EncodedKey<T>>, Bound<EncodedKey<T>>) {
+    let start = encode(prefix);
+    // the smallest byte string above every string starting with the prefix
+    let mut end = start.clone();
+    while end.last() == Some(&0xFF) {
+        end.pop();
+    }
+    let end = match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(EncodedKey { bytes: end, key: PhantomData })
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(EncodedKey { bytes: start, key: PhantomData }), end)
+}
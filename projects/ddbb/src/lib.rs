@@ -1,2 +1,40 @@
+pub mod admin;
+pub mod auth;
+pub mod batch;
+pub mod bench;
 pub mod btree;
+pub mod cache;
+pub mod client;
+pub mod disk_btree;
+pub mod error;
+pub mod explain;
+pub mod flush;
+pub mod histogram;
+pub mod http;
+pub mod index;
+pub mod json;
+pub mod keycodec;
 pub mod log;
+pub mod lock;
+pub mod lsm;
+pub mod manifest;
+pub mod membership;
+pub mod merkle;
+pub mod options;
+pub mod pager;
+pub mod partition;
+pub mod query;
+pub mod quorum;
+pub mod raft;
+pub mod replication;
+pub mod scan;
+pub mod server;
+pub mod shard;
+pub mod sstable;
+pub mod table;
+pub mod testing;
+pub mod text;
+pub mod tls;
+pub mod txn;
+pub mod vfs;
+pub mod watch;
@@ -0,0 +1,205 @@
+// src/lock.rs
+
+/*
+* Locks
+*
+* A `LockManager` hands out locks on keys to transactions (see txn.rs), so that two of them
+* only wait for each other when they touch the same keys, not for one mutex around the whole
+* store. A lock is either
+*
+*   Shared      many transactions may hold it at once, to read the key
+*   Exclusive   one transaction holds it, and nobody else holds any lock on the key, to write it
+*
+* A transaction asks for a lock with `lock` and gives back all of its locks at once with
+* `unlock_all` (when it commits or rolls back), or a single one with `unlock`. Asking again for
+* a lock it holds is free, and so is a Shared lock when it holds the Exclusive one. A
+* transaction that holds the Shared lock and asks for the Exclusive one upgrades it, once it is
+* the only one left holding the key.
+*
+* Wait queues
+*
+* A lock that cannot be granted right away queues up behind the others waiting for the key, and
+* the queue is served in order: the first waiter gets the lock as soon as it is compatible with
+* the holders, then the next one, so a stream of readers cannot starve a writer waiting behind
+* them. An upgrade goes to the front of the queue, as its transaction already holds the key
+* and everyone behind it would wait for it anyway.
+*
+* Timeouts
+*
+* A waiter gives up after the timeout it asked with, and `lock` fails with an Io error of kind
+* TimedOut; its place in the queue goes to the next one. That is also how deadlocks end: two
+* transactions that each wait for a key the other holds both time out, and the one that rolls
+* back first lets the other go on. There is no deadlock detection.
+*/
+
+use crate::error::{Error, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Display};
+use std::hash::Hash;
+use std::io;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The transaction a lock belongs to.
+pub type TxnId = u64;
+
+/// The kinds of lock, see the top of this file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl Display for LockMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockMode::Shared => write!(f, "shared"),
+            LockMode::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+// The holders and waiters of a key
+struct KeyLock {
+    holders: HashMap<TxnId, LockMode>,
+    waiting: VecDeque<(TxnId, LockMode)>,
+}
+
+impl KeyLock {
+    // Whether `txn` can hold the key in `mode` along with the other holders
+    fn compatible(&self, txn: TxnId, mode: LockMode) -> bool {
+        let mut others = self.holders.iter().filter(|(holder, _)| **holder != txn);
+        match mode {
+            LockMode::Shared => others.all(|(_, held)| *held == LockMode::Shared),
+            LockMode::Exclusive => others.count() == 0,
+        }
+    }
+}
+
+struct State<K> {
+    keys: HashMap<K, KeyLock>,
+    // the keys each transaction holds a lock on
+    held: HashMap<TxnId, Vec<K>>,
+}
+
+/// Shared and exclusive locks on keys of type K, see the top of this file.
+pub struct LockManager<K> {
+    state: Mutex<State<K>>,
+    changed: Condvar,
+}
+
+impl<K: Hash + Eq + Clone + Debug> LockManager<K> {
+    pub fn new() -> Self {
+        let state = State { keys: HashMap::new(), held: HashMap::new() };
+        LockManager { state: Mutex::new(state), changed: Condvar::new() }
+    }
+
+    /// Lock `key` in `mode` for `txn`, waiting at most `timeout` for the transactions that hold
+    /// it or are ahead in its queue.
+    pub fn lock(&self, txn: TxnId, key: &K, mode: LockMode, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let lock = state.keys.entry(key.clone()).or_insert_with(|| KeyLock {
+            holders: HashMap::new(),
+            waiting: VecDeque::new(),
+        });
+        let held = lock.holders.get(&txn).copied();
+        if held == Some(LockMode::Exclusive) || held == Some(mode) {
+            return Ok(());
+        }
+        if lock.waiting.is_empty() && lock.compatible(txn, mode) {
+            lock.holders.insert(txn, mode);
+            if held.is_none() {
+                state.held.entry(txn).or_default().push(key.clone());
+            }
+            return Ok(());
+        }
+        if held.is_some() {
+            lock.waiting.push_front((txn, mode));
+        } else {
+            lock.waiting.push_back((txn, mode));
+        }
+
+        loop {
+            let state = &mut *guard;
+            let lock = state.keys.get_mut(key).unwrap();
+            if lock.waiting.front() == Some(&(txn, mode)) && lock.compatible(txn, mode) {
+                lock.waiting.pop_front();
+                lock.holders.insert(txn, mode);
+                if held.is_none() {
+                    state.held.entry(txn).or_default().push(key.clone());
+                }
+                // the next waiter may be compatible too
+                self.changed.notify_all();
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                lock.waiting.retain(|waiter| *waiter != (txn, mode));
+                if lock.holders.is_empty() && lock.waiting.is_empty() {
+                    state.keys.remove(key);
+                }
+                self.changed.notify_all();
+                let message =
+                    format!("transaction {} waited {:?} for a {} lock on {:?}", txn, timeout, mode, key);
+                return Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, message)));
+            }
+            guard = self.changed.wait_timeout(guard, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Give back the lock `txn` holds on `key`, if any.
+    pub fn unlock(&self, txn: TxnId, key: &K) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(keys) = state.held.get_mut(&txn) {
+            keys.retain(|held| held != key);
+            if keys.is_empty() {
+                state.held.remove(&txn);
+            }
+        }
+        Self::release(&mut state, txn, key);
+        self.changed.notify_all();
+    }
+
+    /// Give back every lock `txn` holds.
+    pub fn unlock_all(&self, txn: TxnId) {
+        let mut state = self.state.lock().unwrap();
+        for key in state.held.remove(&txn).unwrap_or_default() {
+            Self::release(&mut state, txn, &key);
+        }
+        self.changed.notify_all();
+    }
+
+    /// The transactions holding a lock on `key`, in the order of their ids.
+    pub fn holders(&self, key: &K) -> Vec<(TxnId, LockMode)> {
+        let state = self.state.lock().unwrap();
+        let mut holders: Vec<(TxnId, LockMode)> = match state.keys.get(key) {
+            Some(lock) => lock.holders.iter().map(|(txn, mode)| (*txn, *mode)).collect(),
+            None => Vec::new(),
+        };
+        holders.sort_by_key(|(txn, _)| *txn);
+        holders
+    }
+
+    /// The transactions waiting for a lock on `key`, first in line first.
+    pub fn waiting(&self, key: &K) -> Vec<(TxnId, LockMode)> {
+        let state = self.state.lock().unwrap();
+        state.keys.get(key).map(|lock| lock.waiting.iter().copied().collect()).unwrap_or_default()
+    }
+
+    fn release(state: &mut State<K>, txn: TxnId, key: &K) {
+        if let Some(lock) = state.keys.get_mut(key) {
+            lock.holders.remove(&txn);
+            if lock.holders.is_empty() && lock.waiting.is_empty() {
+                state.keys.remove(key);
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Debug> Default for LockManager<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,14 +1,34 @@
+use crate::batch::{BatchOp, WriteBatch};
 use crate::btree::BTree;
-use std::fs::{File, OpenOptions};
-use std::fs;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use crate::error::{Error, Result};
+use crate::explain::ReadStats;
+use crate::flush::{FlushReason, FlushStats, WriteBuffer};
+use crate::histogram::{LatencyHistograms, LatencyRecorder, Operation};
+use crate::index::SecondaryIndex;
+use crate::json::{Json, JsonPath};
+use crate::keycodec::{self, EncodedKey, OrderedKey};
+use crate::options::Options;
+use crate::replication::{Checkpoint, Snapshot};
+use crate::scan::{self, Page, ScanOptions};
+use crate::sstable::{Table, TableWriter};
+use crate::text::TextIndex;
+use crate::vfs::{OpenOptions, RealFs, Vfs, VfsFile, VfsLock};
+use crate::watch::{Event, Watch, Watchers};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::ops::{Add, RangeBounds};
 use std::str::FromStr;
 use std::fmt::{Debug, Display};
-use std::path::Path;
-use std::mem;
-use std::io::sink;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const LOG_FILE: &str = "log.txt";
+const TEMP_LOG_FILE: &str = "temp_log.txt";
+const DUMMY_FILE: &str = "dummy.txt";
+const LOCK_FILE: &str = "LOCK";
+const SNAPSHOT_FILE: &str = "data.sst";
+const TEMP_SNAPSHOT_FILE: &str = "temp_data.sst";
 
 pub struct LogManager<K, V>
 where
@@ -18,7 +38,199 @@ where
     <V as FromStr>::Err: Debug,
 {
     btree: BTree<K, V>,
-    log_file: File,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    log_file: Box<dyn VfsFile>,
+    lock: Option<Box<dyn VfsLock>>,
+    options: Options,
+    tombstones: BTree<K, u64>, // deleted key -> deletion time in ms since the epoch
+    tombstone_stats: TombstoneStats,
+    checkpoint: u64, // generation of the last compaction, see persist_data
+    recovery_report: RecoveryReport,
+    buffer: WriteBuffer, // the records in the log
+    flush_stats: FlushStats,
+    indexes: HashMap<String, SecondaryIndex<K, V>>, // see index.rs
+    text_index: Option<TextIndex<K>>,                // see text.rs
+    watchers: Watchers<K, V>,                        // see watch.rs
+    lsn: u64,                                        // of the last write, see replication.rs
+    backlog: VecDeque<(u64, String)>,                // the last writes, for the followers
+    backlog_bytes: usize,
+    resync: bool, // an install_snapshot did not finish
+    prepared: BTreeMap<String, String>, // id -> BATCH payload, see prepare
+    latencies: LatencyRecorder,         // see histogram.rs
+}
+
+/// Garbage collection accounting of tombstones (deleted keys kept around by compaction).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TombstoneStats {
+    /// Tombstones currently tracked, they are written back by the next compaction unless their
+    /// retention window has passed.
+    pub live: usize,
+    /// Tombstones the last compaction kept because they were still inside the window.
+    pub retained_last_compaction: usize,
+    /// Tombstones the last compaction dropped.
+    pub collected_last_compaction: usize,
+    /// Tombstones dropped by every compaction since the LogManager was opened.
+    pub collected_total: u64,
+}
+
+/// Statistics about the log replay done while opening a LogManager, so operators can spot
+/// unusually long replays or skipped records.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// INSERT, DELETE and PATCH records applied to the tree, from the snapshot and the log,
+    /// counting every write of a BATCH.
+    pub records_replayed: usize,
+    /// Size of the log that was read.
+    pub bytes_scanned: u64,
+    /// Records of the log and entries of the snapshot whose checksum or content did not match
+    /// and were ignored.
+    pub corrupt_records_skipped: usize,
+    /// Incomplete record at the end of the log (a torn write) that was cut off.
+    pub torn_bytes_discarded: u64,
+    /// Generation of the compaction the log started from, None if it was never compacted.
+    pub checkpoint: Option<u64>,
+    pub elapsed: Duration,
+}
+
+/// Aggregates of a field of the values in a range, see `LogManager::aggregate_range`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Aggregate<T> {
+    /// Pairs in the range whose value has the field.
+    pub count: usize,
+    pub min: Option<T>,
+    pub max: Option<T>,
+    /// Sum of the fields, T::default() if there are none.
+    pub sum: T,
+}
+
+enum Replayed {
+    Record,
+    Batch(usize),
+    Checkpoint(u64, u64),
+    TextIndex,
+    Resync,
+    Prepared,
+}
+
+// The pair and the tombstone of a key before a write of a batch
+type Undo<K, V> = (K, Option<V>, Option<u64>);
+
+// A write of a BATCH record
+enum LoggedWrite<K, V> {
+    Insert(K, V),
+    Delete(K, u64),
+}
+
+/*
+* Log format
+*
+* Every line of the log is one record: "<crc32 of the payload, 8 hex digits> <payload>\n", and
+* the payload is one of:
+*
+* INSERT <key> <value>
+* DELETE <key> <deletion time, ms since the epoch>
+* PATCH <key> <JSON path> <JSON value>   (see json.rs)
+* BATCH <n> <write>...        (n writes, each "INSERT <key> <value>" or "DELETE <key> <time>",
+*                              applied together, see batch.rs)
+* CHECKPOINT <generation> <LSN>   (first line of a compacted log)
+* TEXT_INDEX <ON or OFF>       (see text.rs)
+* RESYNC                      (an install_snapshot started, see replication.rs)
+* PREPARE <id> BATCH <n> <write>...   (a prepared batch, see below)
+* COMMIT <id> BATCH <n> <write>...    (the prepared batch <id> is applied)
+* ABORT <id>                  (the prepared batch <id> is dropped)
+*
+* Compaction writes the live pairs and retained tombstones to an SSTable (see sstable.rs),
+* data.sst, and starts a new log holding only the CHECKPOINT record. Recovery loads data.sst
+* and then replays the log on top of it. The table keys are the keys in text form, its values
+* are "I<value>" for a pair and "D<deletion time>" for a tombstone.
+*
+* A key or a value is one token of its record, so the writes refuse the keys and values whose
+* text form is empty or has whitespace: they would be logged as a record that replays as
+* another write, or as none.
+*
+* Lines without a checksum are logs written before checksums existed, they are still replayed.
+* Their DELETE records hold the key as `{:?}` wrote it, quoted for a String. A DELETE without a
+* time (also from older logs) is treated as an already expired tombstone.
+*
+* Every INSERT, DELETE, PATCH and BATCH record is a write, and the writes are numbered from 1
+* in the order they were logged, their LSN (log sequence number). The CHECKPOINT record holds
+* the LSN of the last write before it, so the numbers go on across compactions, and recovery
+* counts the writes it replays from there. A CHECKPOINT without one, from a log written before
+* LSNs existed, counts from 0.
+*
+* Prepared batches
+*
+* `prepare` is the first phase of a two-phase commit (see shard.rs): it checks that a batch
+* can be applied and logs it under an id, without applying it, and syncs the log, so the
+* promise to apply it holds across a crash. `commit_prepared` applies it, `abort_prepared`
+* drops it, whichever the coordinator decided. Those two only append their record, like any
+* write: a coordinator syncs it, with `commit_prepared_durable` and `abort_prepared_durable`,
+* before it forgets its decision, or a crash could leave the batch prepared with nothing left
+* to finish it. A COMMIT record holds all of the batch, so it
+* is a write like a BATCH and a follower applies it without the PREPARE (which is not a
+* write, it has no LSN). Recovery keeps the batches prepared but neither committed nor
+* aborted, and a compaction writes their PREPARE records again after the CHECKPOINT. The keys
+* of a prepared batch are not held off in between: the commit applies the batch as it was
+* prepared, whatever was written since, without checking the unique indexes again. Those hold
+* the index keys of its inserts instead, see index.rs.
+*/
+
+// Whether the record `payload` is a write, which has an LSN
+fn is_write(payload: &str) -> bool {
+    matches!(payload.split(' ').next(), Some("INSERT" | "DELETE" | "PATCH" | "BATCH" | "COMMIT"))
+}
+
+fn not_prepared(id: &str) -> Error {
+    Error::InvalidArgument(format!("there is no prepared batch {}", id))
+}
+
+fn unknown_index(name: &str) -> Error {
+    Error::InvalidArgument(format!("there is no index {}", name))
+}
+
+// Fail unless `text`, the text form of a key or value, is one token of a record, see the top
+// of this file
+fn check_token(what: &str, text: &str) -> Result<()> {
+    if text.is_empty() || text.contains(char::is_whitespace) {
+        return Err(Error::InvalidArgument(format!("{} {:?} is empty or has whitespace", what, text)));
+    }
+    Ok(())
+}
+
+// A line without a checksum as the record it is today, None if it has one. The DELETE records
+// of those logs hold the key as {:?} wrote it
+fn legacy_record(line: &str) -> Option<String> {
+    if line.starts_with("INSERT ") {
+        return Some(line.to_string());
+    }
+    let key = line.strip_prefix("DELETE ")?;
+    match key.strip_prefix('"').and_then(|key| key.strip_suffix('"')) {
+        Some(quoted) => Some(format!("DELETE {}", quoted.replace(r#"\""#, "\"").replace(r"\\", r"\"))),
+        None => Some(line.to_string()),
+    }
+}
+
+// Whether the record has no token left, see `replay`
+fn end<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Option<()> {
+    tokens.next().is_none().then_some(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+pub(crate) fn frame(payload: &str) -> String {
+    format!("{:08x} {}\n", crc32fast::hash(payload.as_bytes()), payload)
+}
+
+pub(crate) fn unframe(line: &str) -> Option<&str> {
+    if line.starts_with("INSERT ") || line.starts_with("DELETE ") {
+        return Some(line);
+    }
+    let (checksum, payload) = line.split_once(' ')?;
+    let checksum = u32::from_str_radix(checksum, 16).ok()?;
+    (checksum == crc32fast::hash(payload.as_bytes())).then_some(payload)
 }
 
 impl<K: Ord + Clone + Debug + FromStr, V: Clone + Debug + FromStr> LogManager<K, V>
@@ -28,125 +240,1222 @@ where
     <K as FromStr>::Err: Debug,
     <V as FromStr>::Err: Debug,
 {
+    /// Open the log in the current working directory on the real file system.
     pub fn new() -> Self {
-        let btree = BTree::new();
+        Self::open(Arc::new(RealFs), ".").unwrap()
+    }
+
+    /// Open (or create) the database stored in `dir` through the given `Vfs`.
+    ///
+    /// The directory is locked for as long as the LogManager is alive (or until `shutdown`), so
+    /// two managers can never append to the same log at once.
+    pub fn open(vfs: Arc<dyn Vfs>, dir: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(vfs, dir, Options::default())
+    }
+
+    /// Same as `open`, with non-default `Options`.
+    pub fn open_with(vfs: Arc<dyn Vfs>, dir: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        vfs.create_dir_all(&dir)?;
+        let lock = vfs.lock(&dir.join(LOCK_FILE))?;
 
-        // Open or create the log file
-        let log_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(LOG_FILE)
-            .unwrap();
+        // Open or create the log file, and make sure its directory entry survives a crash
+        let log_file = vfs.open(
+            &dir.join(LOG_FILE),
+            OpenOptions::new().read(true).append(true).create(true),
+        )?;
+        vfs.sync_dir(&dir)?;
 
-        let mut log_manager = LogManager { btree, log_file };
+        let latencies = LatencyRecorder::new(options.latency_histograms);
+        let mut log_manager = LogManager {
+            btree: BTree::new(),
+            vfs,
+            dir,
+            log_file,
+            lock: Some(lock),
+            options,
+            tombstones: BTree::new(),
+            tombstone_stats: TombstoneStats::default(),
+            checkpoint: 0,
+            recovery_report: RecoveryReport::default(),
+            buffer: WriteBuffer::default(),
+            flush_stats: FlushStats::default(),
+            indexes: HashMap::new(),
+            text_index: None,
+            watchers: Watchers::new(),
+            lsn: 0,
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            resync: false,
+            prepared: BTreeMap::new(),
+            latencies,
+        };
 
         // Recover the state from the log file
-        log_manager.recover_state();
+        log_manager.recovery_report = log_manager.recover_state()?;
 
-        log_manager
+        Ok(log_manager)
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        self.btree.insert(key.clone(), value.clone());
-        Self::write_log(&mut self.log_file, format!("INSERT {} {}\n", key, value));
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        let started = self.latencies.start();
+        check_token("key", &key.to_string())?;
+        check_token("value", &value.to_string())?;
+        self.check_unique(&key, &value)?;
+        self.apply_insert(key.clone(), value.clone());
+        let written = self.append(format!("INSERT {} {}", key, value))?;
+        self.watchers.notify(Event::Set(key, value));
+        self.after_write(written)?;
+        self.latencies.record(Operation::Insert, started);
+        Ok(())
     }
 
-    pub fn delete(&mut self, key: &K) {
-        self.btree.delete(key);
-        Self::write_log(&mut self.log_file, format!("DELETE {:?}\n", key));
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        let started = self.latencies.start();
+        check_token("key", &key.to_string())?;
+        let deleted_at = now_millis();
+        self.apply_delete(key.clone(), deleted_at);
+        let written = self.append(format!("DELETE {} {}", key, deleted_at))?;
+        self.watchers.notify(Event::Delete(key.clone()));
+        self.after_write(written)?;
+        self.latencies.record(Operation::Delete, started);
+        Ok(())
+    }
+
+    /// Apply every write of `batch`, or none of them, see batch.rs.
+    pub fn write_batch(&mut self, batch: WriteBatch<K, V>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let started = self.latencies.start();
+        let deleted_at = now_millis();
+        self.apply_batch(&batch, deleted_at)?;
+
+        let written = self.append(Self::batch_payload(&batch, deleted_at))?;
+        for op in batch.ops() {
+            self.watchers.notify(match op {
+                BatchOp::Insert(key, value) => Event::Set(key.clone(), value.clone()),
+                BatchOp::Delete(key) => Event::Delete(key.clone()),
+            });
+        }
+        self.after_write(written)?;
+        self.latencies.record(Operation::Batch, started);
+        Ok(())
+    }
+
+    // Apply the writes of `batch` to the tree, or none of them if one breaks a unique index,
+    // returns what undoes them
+    fn apply_batch(&mut self, batch: &WriteBatch<K, V>, deleted_at: u64) -> Result<Vec<Undo<K, V>>> {
+        for op in batch.ops() {
+            check_token("key", &op.key().to_string())?;
+            if let BatchOp::Insert(_, value) = op {
+                check_token("value", &value.to_string())?;
+            }
+        }
+        let mut undo = Vec::with_capacity(batch.len());
+        for op in batch.ops() {
+            if let BatchOp::Insert(key, value) = op {
+                if let Err(e) = self.check_unique(key, value) {
+                    self.restore(undo);
+                    return Err(e);
+                }
+            }
+            let key = op.key();
+            undo.push((key.clone(), self.btree.search(key).cloned(), self.tombstones.search(key).copied()));
+            match op {
+                BatchOp::Insert(key, value) => self.apply_insert(key.clone(), value.clone()),
+                BatchOp::Delete(key) => self.apply_delete(key.clone(), deleted_at),
+            }
+        }
+        Ok(undo)
+    }
+
+    /// Check that `batch` can be applied and log it as `id` without applying it, the first
+    /// phase of a two-phase commit, see the top of this file. An empty batch is fine.
+    pub fn prepare(&mut self, id: &str, batch: WriteBatch<K, V>) -> Result<()> {
+        if id.is_empty() || id.contains(char::is_whitespace) {
+            return Err(Error::InvalidArgument(format!("bad transaction id {:?}", id)));
+        }
+        if self.prepared.contains_key(id) {
+            return Err(Error::InvalidArgument(format!("{} is prepared already", id)));
+        }
+        let deleted_at = now_millis();
+        let undo = self.apply_batch(&batch, deleted_at)?;
+        self.restore(undo);
+        let payload = Self::batch_payload(&batch, deleted_at);
+        Self::write_log(&mut self.log_file, format!("PREPARE {} {}", id, payload))?;
+        self.log_file.sync()?;
+        self.prepared.insert(id.to_string(), payload);
+        Ok(())
+    }
+
+    /// Apply the batch prepared as `id`. The COMMIT record is not synced, see the top of this
+    /// file.
+    pub fn commit_prepared(&mut self, id: &str) -> Result<()> {
+        let started = self.latencies.start();
+        let payload = self.prepared.get(id).ok_or_else(|| not_prepared(id))?;
+        let writes = Self::parse_batch(&mut payload.split_whitespace().skip(1))
+            .ok_or_else(|| Error::Corruption(format!("bad prepared batch {}", id)))?;
+        let written = self.append(format!("COMMIT {} {}", id, payload))?;
+        self.prepared.remove(id);
+        for write in writes {
+            match write {
+                LoggedWrite::Insert(key, value) => {
+                    self.apply_insert(key.clone(), value.clone());
+                    self.watchers.notify(Event::Set(key, value));
+                }
+                LoggedWrite::Delete(key, deleted_at) => {
+                    self.apply_delete(key.clone(), deleted_at);
+                    self.watchers.notify(Event::Delete(key));
+                }
+            }
+        }
+        self.after_write(written)?;
+        self.latencies.record(Operation::Batch, started);
+        Ok(())
+    }
+
+    /// Same as `commit_prepared`, with the COMMIT record synced.
+    pub fn commit_prepared_durable(&mut self, id: &str) -> Result<()> {
+        self.commit_prepared(id)?;
+        self.sync()
+    }
+
+    /// Drop the batch prepared as `id`. The ABORT record is not synced, see the top of this
+    /// file.
+    pub fn abort_prepared(&mut self, id: &str) -> Result<()> {
+        if !self.prepared.contains_key(id) {
+            return Err(not_prepared(id));
+        }
+        Self::write_log(&mut self.log_file, format!("ABORT {}", id))?;
+        self.prepared.remove(id);
+        Ok(())
+    }
+
+    /// Same as `abort_prepared`, with the ABORT record synced.
+    pub fn abort_prepared_durable(&mut self, id: &str) -> Result<()> {
+        self.abort_prepared(id)?;
+        self.sync()
+    }
+
+    /// The ids of the batches prepared and not committed or aborted yet, in order, with the
+    /// number of their writes.
+    pub fn prepared(&self) -> Vec<(String, usize)> {
+        let writes = |payload: &String| payload.split(' ').nth(1).and_then(|n| n.parse().ok()).unwrap_or(0);
+        self.prepared.iter().map(|(id, payload)| (id.clone(), writes(payload))).collect()
+    }
+
+    /// The BATCH record that `write_batch` would log for `batch` now, for `apply_wal` to apply
+    /// elsewhere (see raft.rs).
+    pub fn batch_record(batch: &WriteBatch<K, V>) -> String {
+        Self::batch_payload(batch, now_millis())
+    }
+
+    fn batch_payload(batch: &WriteBatch<K, V>, deleted_at: u64) -> String {
+        let mut record = format!("BATCH {}", batch.len());
+        for op in batch.ops() {
+            match op {
+                BatchOp::Insert(key, value) => record += &format!(" INSERT {} {}", key, value),
+                BatchOp::Delete(key) => record += &format!(" DELETE {} {}", key, deleted_at),
+            }
+        }
+        record
+    }
+
+    // Put back the pairs and tombstones of the keys a failed batch wrote, last write first
+    fn restore(&mut self, undo: Vec<Undo<K, V>>) {
+        for (key, value, tombstone) in undo.into_iter().rev() {
+            match value {
+                Some(value) => self.apply_insert(key.clone(), value),
+                None => self.apply_delete(key.clone(), 0),
+            }
+            match tombstone {
+                Some(deleted_at) => {
+                    if self.tombstones.upsert(key, deleted_at).is_none() {
+                        self.tombstone_stats.live += 1;
+                    }
+                }
+                None => {
+                    if self.tombstones.delete(&key).is_some() {
+                        self.tombstone_stats.live -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Flush the tree to the snapshot once the log is due for it, see flush.rs
+    fn after_write(&mut self, written: usize) -> Result<()> {
+        self.buffer.add(written);
+        if let Some(reason) = self.buffer.due(&self.options) {
+            self.flush_for(reason)?;
+        }
+        Ok(())
+    }
+
+    /// Write the tree to the snapshot and start a new log now, if anything was written since
+    /// the last time. Unlike `compact`, this does nothing when the log is empty.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.flush_for(FlushReason::Manual)
+    }
+
+    /// Make every write so far durable.
+    pub fn sync(&mut self) -> Result<()> {
+        self.log_file.sync()?;
+        Ok(())
+    }
+
+    fn flush_for(&mut self, reason: FlushReason) -> Result<()> {
+        let started = Instant::now();
+        self.persist_data()?;
+        self.flush_stats.record(reason, started.elapsed());
+        Ok(())
+    }
+
+    /// The events of the writes of the keys that start with `prefix` (in text form), from now
+    /// on, see watch.rs.
+    pub fn watch(&mut self, prefix: &str) -> Watch<K, V> {
+        self.watchers.add(prefix)
+    }
+
+    pub fn tombstone_stats(&self) -> TombstoneStats {
+        self.tombstone_stats
+    }
+
+    pub fn flush_stats(&self) -> FlushStats {
+        self.flush_stats
+    }
+
+    /// The latencies of the operations since the LogManager was opened, None unless
+    /// `Options::latency_histograms` is on, see histogram.rs.
+    pub fn latency_histograms(&self) -> Option<LatencyHistograms> {
+        self.latencies.histograms()
+    }
+
+    /// Generation of the last compaction, None if the database was never compacted.
+    pub fn checkpoint(&self) -> Option<u64> {
+        (self.checkpoint > 0).then_some(self.checkpoint)
+    }
+
+    /// LSN of the last write, 0 if there was none, see replication.rs.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    /// The writes after LSN `lsn`, with their LSNs, as records of the log. None if some of them
+    /// are not in the backlog anymore (see `Options::replication_backlog`), or if `lsn` is
+    /// ahead of this log: only a `snapshot` brings a follower that far behind (or that
+    /// diverged) back.
+    pub fn wal_since(&self, lsn: u64) -> Option<Vec<(u64, String)>> {
+        if lsn >= self.lsn {
+            return (lsn == self.lsn).then(Vec::new);
+        }
+        let first = self.backlog.front()?.0;
+        if lsn + 1 < first {
+            return None;
+        }
+        Some(self.backlog.iter().skip((lsn + 1 - first) as usize).cloned().collect())
+    }
+
+    /// The pairs and the tombstones as of the last write, for a follower to start from.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        Snapshot { lsn: self.lsn, pairs: self.btree.traverse(), tombstones: self.tombstones.traverse() }
+    }
+
+    /// Apply the write `record`, a record of the log of a leader with the LSN `lsn`, and log it
+    /// with the same LSN. It must be the next one, `lsn() + 1`. Unique indexes are not
+    /// checked, the leader did, and watches get no event.
+    pub fn apply_wal(&mut self, lsn: u64, record: &str) -> Result<()> {
+        if lsn != self.lsn + 1 {
+            return Err(Error::InvalidArgument(format!("write {} does not follow write {}", lsn, self.lsn)));
+        }
+        if !is_write(record) {
+            return Err(Error::InvalidArgument(format!("not a write: {:?}", record)));
+        }
+        if !matches!(self.replay(record), Some(Replayed::Record | Replayed::Batch(_))) {
+            return Err(Error::Corruption(format!("bad write {}: {:?}", lsn, record)));
+        }
+        let written = self.append(record.to_string())?;
+        self.after_write(written)
+    }
+
+    /// Replace everything with `snapshot` (from the `snapshot` of a leader) and compact, so
+    /// the LSN goes on from the snapshot's.
+    pub fn install_snapshot(&mut self, snapshot: Snapshot<K, V>) -> Result<()> {
+        // A crash before the compaction is done leaves some of the old state, the RESYNC
+        // record tells the next open that only another snapshot fixes that
+        Self::write_log(&mut self.log_file, "RESYNC".to_string())?;
+        self.log_file.sync()?;
+        self.resync = true;
+
+        for (key, value) in self.btree.traverse() {
+            for index in self.indexes.values_mut() {
+                index.remove(&key, &value);
+            }
+        }
+        self.btree = BTree::new();
+        self.tombstones = BTree::new();
+        self.tombstone_stats.live = 0;
+        if self.text_index.is_some() {
+            self.text_index = Some(TextIndex::new());
+        }
+        for (key, value) in snapshot.pairs {
+            self.apply_insert(key, value);
+        }
+        for (key, deleted_at) in snapshot.tombstones {
+            self.apply_delete(key, deleted_at);
+        }
+        self.lsn = snapshot.lsn;
+        self.backlog.clear();
+        self.backlog_bytes = 0;
+
+        self.resync = false;
+        self.persist_data()
+    }
+
+    /// The files of the database as of the last write, for a follower to start from with
+    /// `install_checkpoint`: the snapshot table of the last compaction, if there was one, and
+    /// the log since.
+    pub fn checkpoint_files(&mut self) -> Result<Checkpoint> {
+        if self.resync {
+            return Err(Error::InvalidArgument("the database needs a snapshot itself".to_string()));
+        }
+        self.log_file.sync()?;
+        let mut files = Vec::new();
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        if self.vfs.exists(&snapshot_path) {
+            files.push((SNAPSHOT_FILE.to_string(), self.vfs.read(&snapshot_path)?));
+        }
+        files.push((LOG_FILE.to_string(), self.vfs.read(&self.dir.join(LOG_FILE))?));
+        Ok(Checkpoint { lsn: self.lsn, files })
+    }
+
+    /// Replace everything with `checkpoint` (the `checkpoint_files` of a leader): its files
+    /// become the ones of this database, and the state is recovered from them like an open
+    /// does, so the LSN goes on from the checkpoint's. Secondary indexes are kept, the text
+    /// index is on if it is on the leader.
+    pub fn install_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<()> {
+        let mut snapshot = None;
+        let mut log = None;
+        for (name, content) in checkpoint.files {
+            match name.as_str() {
+                SNAPSHOT_FILE => snapshot = Some(content),
+                LOG_FILE => log = Some(content),
+                _ => return Err(Error::InvalidArgument(format!("a checkpoint has no file {}", name))),
+            }
+        }
+        let log = log.ok_or_else(|| Error::InvalidArgument("the checkpoint has no log".to_string()))?;
+
+        // Until the new log is in place the old one is, and its RESYNC record tells the next
+        // open that the files are some of the old ones and some of the new ones
+        Self::write_log(&mut self.log_file, "RESYNC".to_string())?;
+        self.log_file.sync()?;
+        self.resync = true;
+
+        let log_path = self.dir.join(LOG_FILE);
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        let temp_log_path = self.dir.join(TEMP_LOG_FILE);
+        let temp_snapshot_path = self.dir.join(TEMP_SNAPSHOT_FILE);
+        let dummy_file_path = self.dir.join(DUMMY_FILE);
+        match snapshot {
+            Some(snapshot) => {
+                self.vfs.write(&temp_snapshot_path, &snapshot)?;
+                self.vfs.rename(&temp_snapshot_path, &snapshot_path)?;
+            }
+            None if self.vfs.exists(&snapshot_path) => self.vfs.remove(&snapshot_path)?,
+            None => {}
+        }
+        self.vfs.write(&temp_log_path, &log)?;
+        self.vfs.sync_dir(&self.dir)?;
+        // as in persist_data, the old log is closed before the new one is renamed over it
+        let dummy_file = self.vfs.open(
+            &dummy_file_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )?;
+        drop(std::mem::replace(&mut self.log_file, dummy_file));
+        self.vfs.rename(&temp_log_path, &log_path)?;
+        self.vfs.sync_dir(&self.dir)?;
+        self.log_file = self.vfs.open(
+            &log_path,
+            OpenOptions::new().read(true).write(true).append(true).create(true),
+        )?;
+        self.vfs.remove(&dummy_file_path)?;
+
+        for (key, value) in self.btree.traverse() {
+            for index in self.indexes.values_mut() {
+                index.remove(&key, &value);
+            }
+        }
+        self.btree = BTree::new();
+        self.tombstones = BTree::new();
+        self.tombstone_stats.live = 0;
+        self.text_index = None;
+        self.checkpoint = 0;
+        self.buffer = WriteBuffer::default();
+        self.lsn = 0;
+        self.backlog.clear();
+        self.backlog_bytes = 0;
+        self.resync = false;
+        self.recover_state()?;
+        if self.lsn != checkpoint.lsn {
+            Self::write_log(&mut self.log_file, "RESYNC".to_string())?;
+            self.resync = true;
+            let message = format!("the files of checkpoint {} end at write {}", checkpoint.lsn, self.lsn);
+            return Err(Error::Corruption(message));
+        }
+        Ok(())
+    }
+
+    /// The file system and directory of the database, for what keeps files next to it.
+    pub(crate) fn location(&self) -> (Arc<dyn Vfs>, &Path) {
+        (self.vfs.clone(), &self.dir)
+    }
+
+    /// True if an `install_snapshot` did not finish (the process died during it): the state is
+    /// then some of the old one and some of the new one, and a follower needs a snapshot again.
+    pub fn needs_snapshot(&self) -> bool {
+        self.resync
+    }
+
+    fn apply_insert(&mut self, key: K, value: V) {
+        // a key that comes back is no longer deleted
+        if self.tombstones.search(&key).is_some() {
+            self.tombstones.delete(&key);
+            self.tombstone_stats.live -= 1;
+        }
+        for index in self.indexes.values_mut() {
+            if let Some(old) = self.btree.search(&key) {
+                index.remove(&key, old);
+            }
+            index.add(&key, &value);
+        }
+        if let Some(text_index) = &mut self.text_index {
+            if let Some(old) = self.btree.search(&key) {
+                text_index.remove(&key, &old.to_string());
+            }
+            text_index.add(&key, &value.to_string());
+        }
+        self.btree.upsert(key, value);
+    }
+
+    fn apply_delete(&mut self, key: K, deleted_at: u64) {
+        if let Some(old) = self.btree.delete(&key) {
+            for index in self.indexes.values_mut() {
+                index.remove(&key, &old);
+            }
+            if let Some(text_index) = &mut self.text_index {
+                text_index.remove(&key, &old.to_string());
+            }
+        }
+        if self.tombstones.upsert(key, deleted_at).is_none() {
+            self.tombstone_stats.live += 1;
+        }
+    }
+
+    /// The pairs whose key is in `range`, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Vec<(K, V)> {
+        let started = self.latencies.start();
+        let pairs = self.btree.range(&range);
+        self.latencies.record(Operation::Scan, started);
+        pairs
+    }
+
+    /// One page of the pairs whose key is in `range`, with `limit`, `offset` and a resume
+    /// token to get the next page from, see scan.rs.
+    pub fn scan<R: RangeBounds<K>>(&self, range: R, options: &ScanOptions) -> Result<Page<K, V>> {
+        let started = self.latencies.start();
+        let page = self.scan_filtered(range, options, false, |_| true)?;
+        self.latencies.record(Operation::Scan, started);
+        Ok(page)
+    }
+
+    /// Same as `scan`, in descending key order: the first page ends at the end of `range`.
+    pub fn scan_rev<R: RangeBounds<K>>(&self, range: R, options: &ScanOptions) -> Result<Page<K, V>> {
+        let started = self.latencies.start();
+        let page = self.scan_filtered(range, options, true, |_| true)?;
+        self.latencies.record(Operation::Scan, started);
+        Ok(page)
+    }
+
+    // Same as scan or scan_rev, over the keys for which `filter` is true only
+    pub(crate) fn scan_filtered<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ScanOptions,
+        reverse: bool,
+        filter: impl Fn(&K) -> bool,
+    ) -> Result<Page<K, V>> {
+        if options.limit == Some(0) {
+            return Err(Error::InvalidArgument("a scan limit must be at least 1".to_string()));
+        }
+        let mut start = range.start_bound().cloned();
+        let mut end = range.end_bound().cloned();
+        if let Some(token) = &options.resume {
+            let key = scan::resume_key::<K>(token)?;
+            match reverse {
+                false => start = scan::start_after(range.start_bound(), key),
+                true => end = scan::end_before(range.end_bound(), key),
+            }
+        }
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let mut skip = options.offset;
+        let mut pairs = Vec::new();
+        let mut more = false;
+        let visit = |key: &K, value: &V| {
+            if !filter(key) {
+                return true;
+            }
+            if skip > 0 {
+                skip -= 1;
+                return true;
+            }
+            if pairs.len() == limit {
+                more = true;
+                return false;
+            }
+            pairs.push((key.clone(), value.clone()));
+            true
+        };
+        match reverse {
+            false => self.btree.visit_range(&(start, end), visit),
+            true => self.btree.visit_range_rev(&(start, end), visit),
+        }
+        let next = match pairs.last() {
+            Some((last, _)) if more => Some(scan::resume_token(&last.to_string())),
+            _ => None,
+        };
+        Ok(Page { pairs, next })
+    }
+
+    /// How many pairs have their key in `range`. The pairs are counted where they are, unlike
+    /// `range(..).len()` nothing is copied.
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        self.count_filtered(range, |_| true)
+    }
+
+    // Same as count_range, counting the keys for which `filter` is true only
+    pub(crate) fn count_filtered<R: RangeBounds<K>>(&self, range: R, filter: impl Fn(&K) -> bool) -> usize {
+        let mut count = 0;
+        self.btree.visit_range(&range, |key, _| {
+            count += filter(key) as usize;
+            true
+        });
+        count
+    }
+
+    /// Count, minimum, maximum and sum of `field(value)` over the pairs whose key is in `range`,
+    /// computed in place like `count_range`. Values for which `field` is None are left out.
+    pub fn aggregate_range<R, T>(&self, range: R, field: impl Fn(&V) -> Option<T>) -> Aggregate<T>
+    where
+        R: RangeBounds<K>,
+        T: Copy + PartialOrd + Add<Output = T> + Default,
+    {
+        let mut aggregate = Aggregate::default();
+        self.btree.visit_range(&range, |_, value| {
+            let Some(x) = field(value) else { return true };
+            aggregate.count += 1;
+            aggregate.sum = aggregate.sum + x;
+            if aggregate.min.is_none_or(|min| x < min) {
+                aggregate.min = Some(x);
+            }
+            if aggregate.max.is_none_or(|max| x > max) {
+                aggregate.max = Some(x);
+            }
+            true
+        });
+        aggregate
+    }
+
+    // Fail before anything is applied or logged, see index.rs
+    fn check_unique(&self, key: &K, value: &V) -> Result<()> {
+        let prepared = self.prepared_inserts();
+        for (name, index) in &self.indexes {
+            let existing = index.conflict(key, value).or_else(|| index.conflict_in(key, value, &prepared));
+            if let Some(existing) = existing {
+                return Err(Error::UniqueViolation { index: name.clone(), existing: existing.to_string() });
+            }
+        }
+        Ok(())
+    }
+
+    // The pairs the prepared batches insert, whose index keys they hold, see index.rs
+    fn prepared_inserts(&self) -> Vec<(K, V)> {
+        if self.indexes.is_empty() {
+            return Vec::new();
+        }
+        let parse = |payload: &String| Self::parse_batch(&mut payload.split_whitespace().skip(1));
+        self.prepared
+            .values()
+            .filter_map(parse)
+            .flatten()
+            .filter_map(|write| match write {
+                LoggedWrite::Insert(key, value) => Some((key, value)),
+                LoggedWrite::Delete(..) => None,
+            })
+            .collect()
     }
 
     pub fn search(&self, key: &K) -> Option<V> {
-        self.btree.search(key).map(|v| v.clone())
+        let started = self.latencies.start();
+        let value = self.btree.search(key).cloned();
+        self.latencies.record(Operation::Search, started);
+        value
     }
 
-    fn recover_state(&mut self) {
-        let reader = BufReader::new(&self.log_file);
+    /// Same as `search`, also returning the tree nodes it went through, see explain.rs. The
+    /// pairs are all in memory, there are no tables or blocks to count.
+    pub fn explain_search(&self, key: &K) -> (Option<V>, ReadStats) {
+        let mut stats = ReadStats::default();
+        let value = self.btree.search_counting(key, &mut stats.nodes_visited).cloned();
+        (value, stats)
+    }
 
-        for line in reader.lines() {
-            let line = line.unwrap();
-            let mut tokens = line.split_whitespace();
+    /// Declare the secondary index `name`, which maps `extractor(value)` back to the keys of the
+    /// pairs, see index.rs. It is built from the current pairs and kept up to date from then on,
+    /// but not persisted: declare it again after every `open`.
+    pub fn create_index<I: OrderedKey>(
+        &mut self,
+        name: &str,
+        extractor: impl Fn(&V) -> Option<I> + Send + 'static,
+    ) -> Result<()> {
+        self.add_index(name, SecondaryIndex::new(extractor, false))
+    }
+
+    /// Same as `create_index`, but no two keys may have the same index key: an insert that
+    /// would break this fails with `Error::UniqueViolation` and changes nothing. Fails the same
+    /// way if the current pairs already break it.
+    pub fn create_unique_index<I: OrderedKey>(
+        &mut self,
+        name: &str,
+        extractor: impl Fn(&V) -> Option<I> + Send + 'static,
+    ) -> Result<()> {
+        self.add_index(name, SecondaryIndex::new(extractor, true))
+    }
 
-            match tokens.next() {
-                Some("INSERT") => {
-                    let key_str = tokens.next().unwrap();
-                    let value_str = tokens.next().unwrap();
+    fn add_index(&mut self, name: &str, mut index: SecondaryIndex<K, V>) -> Result<()> {
+        if self.indexes.contains_key(name) {
+            return Err(Error::InvalidArgument(format!("index {} already exists", name)));
+        }
+        for (key, value) in self.btree.traverse() {
+            if let Some(existing) = index.conflict(&key, &value) {
+                return Err(Error::UniqueViolation { index: name.to_string(), existing: existing.to_string() });
+            }
+            index.add(&key, &value);
+        }
+        self.indexes.insert(name.to_string(), index);
+        // and the index keys the prepared batches hold
+        let prepared = self.prepared_inserts();
+        let index = &self.indexes[name];
+        for (i, (key, value)) in prepared.iter().enumerate() {
+            let earlier = &prepared[..i];
+            let existing = index.conflict(key, value).or_else(|| index.conflict_in(key, value, earlier));
+            if let Some(existing) = existing {
+                let existing = existing.to_string();
+                self.indexes.remove(name);
+                return Err(Error::UniqueViolation { index: name.to_string(), existing });
+            }
+        }
+        Ok(())
+    }
 
-                    println!("INSERT: key_str = {:?}, value_str = {:?}", key_str, value_str);
+    pub fn drop_index(&mut self, name: &str) -> Result<()> {
+        self.indexes.remove(name).map(|_| ()).ok_or_else(|| unknown_index(name))
+    }
 
-                    let key = key_str.parse::<K>().unwrap();
-                    let value = value_str.parse::<V>().unwrap();
+    /// The pairs whose value has `index_key` in the index `name`, in key order.
+    pub fn lookup_by_index<I: OrderedKey>(&self, name: &str, index_key: &I) -> Result<Vec<(K, V)>> {
+        let index = self.indexes.get(name).ok_or_else(|| unknown_index(name))?;
+        let keys = index.lookup(&keycodec::encode(index_key));
+        Ok(keys.iter().filter_map(|key| Some((key.clone(), self.btree.search(key)?.clone()))).collect())
+    }
+
+    /// Index the words of the values for `search_text`, see text.rs. The index is built from
+    /// the current pairs and, unlike `create_index`, comes back by itself after `open`.
+    pub fn create_text_index(&mut self) -> Result<()> {
+        if self.text_index.is_some() {
+            return Err(Error::InvalidArgument("the text index already exists".to_string()));
+        }
+        Self::write_log(&mut self.log_file, "TEXT_INDEX ON".to_string())?;
+        self.build_text_index();
+        Ok(())
+    }
+
+    pub fn drop_text_index(&mut self) -> Result<()> {
+        if self.text_index.is_none() {
+            return Err(Error::InvalidArgument("there is no text index".to_string()));
+        }
+        Self::write_log(&mut self.log_file, "TEXT_INDEX OFF".to_string())?;
+        self.text_index = None;
+        Ok(())
+    }
+
+    /// The keys whose value has every term of `query` (like "error time*"), in key order.
+    pub fn search_text(&self, query: &str) -> Result<Vec<K>> {
+        match &self.text_index {
+            Some(text_index) => text_index.search(query),
+            None => Err(Error::InvalidArgument("there is no text index".to_string())),
+        }
+    }
+
+    fn build_text_index(&mut self) {
+        let mut text_index = TextIndex::new();
+        for (key, value) in self.btree.traverse() {
+            text_index.add(&key, &value.to_string());
+        }
+        self.text_index = Some(text_index);
+    }
+
+    /// What happened while the log was replayed by `open`.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    fn recover_state(&mut self) -> Result<RecoveryReport> {
+        let started = Instant::now();
+        let mut report = RecoveryReport::default();
+
+        self.load_snapshot(&mut report)?;
+
+        let mut content = Vec::new();
+        self.log_file.read_to_end(&mut content)?;
+        report.bytes_scanned = content.len() as u64;
+
+        // Only lines terminated by a newline were written completely, anything after the last
+        // newline is a torn write from a crash. Cut it off so new entries are not glued to it.
+        let complete = content.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        if complete < content.len() {
+            report.torn_bytes_discarded = (content.len() - complete) as u64;
+            self.log_file.set_len(complete as u64)?;
+            self.log_file.sync()?;
+        }
+
+        for line in String::from_utf8_lossy(&content[..complete]).lines() {
+            let legacy = legacy_record(line);
+            let payload = legacy.as_deref().or_else(|| unframe(line));
+            match payload.and_then(|payload| Some((payload, self.replay(payload)?))) {
+                Some((payload, Replayed::Record)) => {
+                    report.records_replayed += 1;
+                    self.buffer.add(line.len() + 1);
+                    self.remember(payload.to_string());
+                }
+                Some((payload, Replayed::Batch(writes))) => {
+                    report.records_replayed += writes;
+                    self.buffer.add(line.len() + 1);
+                    self.remember(payload.to_string());
+                }
+                Some((_, Replayed::Checkpoint(generation, lsn))) => {
+                    report.checkpoint = Some(generation);
+                    self.checkpoint = generation;
+                    self.lsn = lsn;
+                }
+                Some((_, Replayed::Resync)) => self.resync = true,
+                Some((_, Replayed::TextIndex | Replayed::Prepared)) => {}
+                None => {
+                    // a damaged record is skipped instead of aborting the whole recovery
+                    report.corrupt_records_skipped += 1;
+                }
+            }
+        }
 
-                    println!("INSERT: key = {:?}, value = {:?}", key, value);
+        report.elapsed = started.elapsed();
+        Ok(report)
+    }
 
-                    self.btree.insert(key, value);
+    // Load the table written by the last compaction, if there was one
+    fn load_snapshot(&mut self, report: &mut RecoveryReport) -> Result<()> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        if !self.vfs.exists(&path) {
+            return Ok(());
+        }
+        let table = Table::open(self.vfs.as_ref(), &path)?;
+        for item in table.iter() {
+            let (key, value) = item?;
+            match self.load_snapshot_entry(&key, value.as_deref().unwrap_or_default()) {
+                Some(()) => report.records_replayed += 1,
+                None => {
+                    report.corrupt_records_skipped += 1;
                 }
-                Some("DELETE") => {
-                    let key_str = tokens.next().unwrap();
-                    println!("DELETE: key_str = {:?}", key_str);
+            }
+        }
+        Ok(())
+    }
 
-                    let key = key_str.parse::<K>().unwrap();
+    fn load_snapshot_entry(&mut self, key: &[u8], value: &[u8]) -> Option<()> {
+        let key = std::str::from_utf8(key).ok()?.parse::<K>().ok()?;
+        let value = std::str::from_utf8(value).ok()?;
+        match value.split_at_checked(1)? {
+            ("I", value) => self.apply_insert(key, value.parse::<V>().ok()?),
+            ("D", deleted_at) => self.apply_delete(key, deleted_at.parse().ok()?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // Apply one log record to the tree, None if it cannot be parsed. All of the record is
+    // parsed first, a record with trailing garbage (not what we wrote) changes nothing
+    fn replay(&mut self, payload: &str) -> Option<Replayed> {
+        let mut tokens = payload.split_whitespace();
 
-                    println!("DELETE: key = {:?}", key);
+        let replayed = match tokens.next()? {
+            "INSERT" => {
+                let key = tokens.next()?.parse::<K>().ok()?;
+                let value = tokens.next()?.parse::<V>().ok()?;
+                end(&mut tokens)?;
+                self.apply_insert(key, value);
+                Replayed::Record
+            }
+            "DELETE" => {
+                let key = tokens.next()?.parse::<K>().ok()?;
+                let deleted_at = match tokens.next() {
+                    Some(deleted_at) => deleted_at.parse::<u64>().ok()?,
+                    None => 0,
+                };
+                end(&mut tokens)?;
+                self.apply_delete(key, deleted_at);
+                Replayed::Record
+            }
+            "PATCH" => {
+                let key = tokens.next()?.parse::<K>().ok()?;
+                let path = tokens.next()?.parse::<JsonPath>().ok()?;
+                let part = tokens.next()?.parse::<Json>().ok()?;
+                end(&mut tokens)?;
 
-                    self.btree.delete(&key);
+                // only Json values are patched, through their text form like everything else here
+                let mut document = match self.btree.search(&key) {
+                    Some(value) => value.to_string().parse::<Json>().ok()?,
+                    None => Json::default(),
+                };
+                document.set_path(&path, part.0).ok()?;
+                let value = document.to_string().parse::<V>().ok()?;
+
+                self.apply_insert(key, value);
+                Replayed::Record
+            }
+            "BATCH" => {
+                // all of it parses before anything is applied, a batch is never half replayed
+                let writes = Self::parse_batch(&mut tokens)?;
+                Replayed::Batch(self.replay_writes(writes))
+            }
+            "PREPARE" => {
+                let id = tokens.next()?;
+                if tokens.next()? != "BATCH" {
+                    return None;
+                }
+                let batch: Vec<&str> = tokens.by_ref().collect();
+                Self::parse_batch(&mut batch.iter().copied())?;
+                self.prepared.insert(id.to_string(), format!("BATCH {}", batch.join(" ")));
+                Replayed::Prepared
+            }
+            "COMMIT" => {
+                let id = tokens.next()?;
+                if tokens.next()? != "BATCH" {
+                    return None;
+                }
+                let writes = Self::parse_batch(&mut tokens)?;
+                self.prepared.remove(id);
+                Replayed::Batch(self.replay_writes(writes))
+            }
+            "ABORT" => {
+                let id = tokens.next()?;
+                end(&mut tokens)?;
+                self.prepared.remove(id);
+                Replayed::Prepared
+            }
+            "CHECKPOINT" => {
+                let generation = tokens.next()?.parse().ok()?;
+                let lsn = match tokens.next() {
+                    Some(lsn) => lsn.parse().ok()?,
+                    None => 0,
+                };
+                end(&mut tokens)?;
+                Replayed::Checkpoint(generation, lsn)
+            }
+            "RESYNC" => {
+                end(&mut tokens)?;
+                Replayed::Resync
+            }
+            "TEXT_INDEX" => {
+                let state = tokens.next()?;
+                end(&mut tokens)?;
+                match state {
+                    "ON" if self.text_index.is_none() => self.build_text_index(),
+                    "ON" => {}
+                    "OFF" => self.text_index = None,
+                    _ => return None,
                 }
-                _ => panic!("Invalid log entry: {}", line),
+                Replayed::TextIndex
+            }
+            _ => return None,
+        };
+        Some(replayed)
+    }
+
+    // The writes of a BATCH record after "BATCH", all of them and nothing after, None if they
+    // do not parse
+    fn parse_batch<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Option<Vec<LoggedWrite<K, V>>> {
+        let count: usize = tokens.next()?.parse().ok()?;
+        let mut writes = Vec::new();
+        for _ in 0..count {
+            let kind = tokens.next()?;
+            let key = tokens.next()?.parse::<K>().ok()?;
+            let write = match kind {
+                "INSERT" => LoggedWrite::Insert(key, tokens.next()?.parse::<V>().ok()?),
+                "DELETE" => LoggedWrite::Delete(key, tokens.next()?.parse::<u64>().ok()?),
+                _ => return None,
+            };
+            writes.push(write);
+        }
+        tokens.next().is_none().then_some(writes)
+    }
+
+    // Apply the writes of a replayed record, returns how many there were
+    fn replay_writes(&mut self, writes: Vec<LoggedWrite<K, V>>) -> usize {
+        let count = writes.len();
+        for write in writes {
+            match write {
+                LoggedWrite::Insert(key, value) => self.apply_insert(key, value),
+                LoggedWrite::Delete(key, deleted_at) => self.apply_delete(key, deleted_at),
             }
         }
+        count
     }
 
-    pub fn shutdown(&mut self) {
-        self.persist_data();
+    /// Write the live pairs and the tombstones that are still inside their retention window to
+    /// the snapshot table, and start over with an empty log.
+    pub fn compact(&mut self) -> Result<()> {
+        self.persist_data()
     }
 
+    /// Compact the log and release the directory lock.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.persist_data()?;
+        self.lock = None;
+        Ok(())
+    }
+
+    /// Write a copy of the database to `dir`, on the same Vfs, that `open` can open like the
+    /// original. It holds every write made before the call and none made after. `dir` is created
+    /// if needed and must not hold a database already.
+    pub fn backup(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if self.vfs.exists(&dir.join(LOG_FILE)) {
+            return Err(Error::InvalidArgument(format!("{} already holds a database", dir.display())));
+        }
+        self.vfs.create_dir_all(dir)?;
+        // nobody opens the copy while it is being made
+        let _lock = self.vfs.lock(&dir.join(LOCK_FILE))?;
+        self.log_file.sync()?;
+
+        // The snapshot is never changed in place (compaction renames a new one over it), so it
+        // can be shared. The log goes in last, a copy without it is not finished.
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        if self.vfs.exists(&snapshot_path) {
+            let temp_snapshot_path = dir.join(TEMP_SNAPSHOT_FILE);
+            if self.vfs.exists(&temp_snapshot_path) {
+                self.vfs.remove(&temp_snapshot_path)?;
+            }
+            self.vfs.link(&snapshot_path, &temp_snapshot_path)?;
+            self.vfs.rename(&temp_snapshot_path, &dir.join(SNAPSHOT_FILE))?;
+        } else if self.vfs.exists(&dir.join(SNAPSHOT_FILE)) {
+            // left by a copy that did not finish
+            self.vfs.remove(&dir.join(SNAPSHOT_FILE))?;
+        }
+        let log = self.vfs.read(&self.dir.join(LOG_FILE))?;
+        self.vfs.write(&dir.join(TEMP_LOG_FILE), &log)?;
+        self.vfs.rename(&dir.join(TEMP_LOG_FILE), &dir.join(LOG_FILE))?;
+        self.vfs.sync_dir(dir)?;
+        Ok(())
+    }
+
+    // Append a write, which gets the next LSN, returns the bytes written
+    fn append(&mut self, payload: String) -> Result<usize> {
+        let written = Self::write_log(&mut self.log_file, payload.clone())?;
+        self.remember(payload);
+        Ok(written)
+    }
+
+    // Count a write that is in the log, and keep it for the followers while it fits in the
+    // backlog
+    fn remember(&mut self, payload: String) {
+        self.lsn += 1;
+        self.backlog_bytes += payload.len();
+        self.backlog.push_back((self.lsn, payload));
+        while self.backlog_bytes > self.options.replication_backlog {
+            let (_, payload) = self.backlog.pop_front().expect("the backlog is not empty");
+            self.backlog_bytes -= payload.len();
+        }
+    }
 
-    fn write_log(log_file: &mut File, entry: String) {
-        println!("Writing log entry: {}", entry);
-        log_file.write_all(entry.as_bytes()).unwrap();
-        log_file.flush().unwrap();
+    // Append a record, returns the bytes written
+    fn write_log(log_file: &mut Box<dyn VfsFile>, entry: String) -> Result<usize> {
+        let record = frame(&entry);
+        log_file.write_all(record.as_bytes())?;
+        log_file.flush()?;
+        Ok(record.len())
     }
 
-    fn persist_data(&mut self) {
-        // Create a new temporary log file
-        let temp_log_path = "temp_log.txt";
-        let mut temp_log_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(temp_log_path)
-            .unwrap();
+    fn persist_data(&mut self) -> Result<()> {
+        let log_path = self.dir.join(LOG_FILE);
+        let temp_log_path = self.dir.join(TEMP_LOG_FILE);
+        let dummy_file_path = self.dir.join(DUMMY_FILE);
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        let temp_snapshot_path = self.dir.join(TEMP_SNAPSHOT_FILE);
+
+        // A crash can leave the old log next to the new snapshot, and it is then replayed on top
+        // of it. That is harmless only if the log holds every write of the snapshot.
+        self.log_file.sync()?;
 
         let kv_pairs: Vec<_> = self.btree.traverse();
-        println!("Persisting: {:?}", kv_pairs);
 
-        // Write key-value pairs to the temporary log file
-        for (key, value) in kv_pairs {
-            println!("Persisting: {:?} {:?}", key, value);
-            Self::write_log(&mut temp_log_file, format!("INSERT {} {}\n", key, value));
+        // The table is sorted by the bytes of the keys in text form, which is not necessarily
+        // the order of K
+        let mut entries: Vec<(String, String)> = kv_pairs
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), format!("I{}", value)))
+            .collect();
+
+        // Carry over the tombstones that are still inside the retention window, the others are
+        // garbage collected once the new snapshot is in place
+        let retention = self.options.tombstone_retention.as_millis() as u64;
+        let now = now_millis();
+        let mut expired = Vec::new();
+        for (key, deleted_at) in self.tombstones.traverse() {
+            if now.saturating_sub(deleted_at) < retention {
+                entries.push((key.to_string(), format!("D{}", deleted_at)));
+            } else {
+                expired.push(key);
+            }
+        }
+        entries.sort();
+
+        let temp_snapshot = self.vfs.open(
+            &temp_snapshot_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )?;
+        let mut writer = TableWriter::new(temp_snapshot);
+        for (key, value) in &entries {
+            writer.add(key.as_bytes(), Some(value.as_bytes()))?;
         }
+        writer.finish()?;
 
-        // Close the temporary log file
+        // The new log starts with a checkpoint record, so recovery can tell which compaction it
+        // started from
+        let mut temp_log_file = self.vfs.open(
+            &temp_log_path,
+            OpenOptions::new().read(true).write(true).create(true).truncate(true),
+        )?;
+        let generation = self.checkpoint + 1;
+        Self::write_log(&mut temp_log_file, format!("CHECKPOINT {} {}", generation, self.lsn))?;
+        if self.text_index.is_some() {
+            Self::write_log(&mut temp_log_file, "TEXT_INDEX ON".to_string())?;
+        }
+        if self.resync {
+            Self::write_log(&mut temp_log_file, "RESYNC".to_string())?;
+        }
+        for (id, payload) in &self.prepared {
+            Self::write_log(&mut temp_log_file, format!("PREPARE {} {}", id, payload))?;
+        }
+        temp_log_file.sync()?;
         drop(temp_log_file);
 
-        // Replace the old log file with a sink (dummy writer) to enable dropping it
-        let dummy_file_path = "dummy.txt";
-        let old_log_file = std::mem::replace(&mut self.log_file, File::create(dummy_file_path).unwrap());
+        // The snapshot goes in first. Replaying the old log on top of the new snapshot gives the
+        // same state, but the new (empty) log on top of the old snapshot would lose data, so the
+        // snapshot rename must be durable before the log is replaced.
+        self.vfs.rename(&temp_snapshot_path, &snapshot_path)?;
+        self.vfs.sync_dir(&self.dir)?;
+
+        // Replace the old log file with a dummy file to enable dropping it
+        let dummy_file = self.vfs.open(
+            &dummy_file_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )?;
+        let old_log_file = std::mem::replace(&mut self.log_file, dummy_file);
         drop(old_log_file); // Drop the old log file
 
-        // Replace the old log file with the temporary log file
-        fs::rename(temp_log_path, LOG_FILE).unwrap();
+        // Replace the old log file with the temporary log file. Until the directory is synced
+        // a crash may still bring back the old log, which is fine since it is replayed on top of
+        // the snapshot.
+        self.vfs.rename(&temp_log_path, &log_path)?;
+        self.vfs.sync_dir(&self.dir)?;
 
         // Open the new log file
-        self.log_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(LOG_FILE)
-            .unwrap();
+        self.log_file = self.vfs.open(
+            &log_path,
+            OpenOptions::new().read(true).write(true).append(true).create(true),
+        )?;
 
         // Remove the dummy.txt file
-        fs::remove_file(dummy_file_path).unwrap();
+        self.vfs.remove(&dummy_file_path)?;
+        self.checkpoint = generation;
+        self.buffer = WriteBuffer::default();
+
+        for key in &expired {
+            self.tombstones.delete(key);
+        }
+        let stats = &mut self.tombstone_stats;
+        stats.live -= expired.len();
+        stats.retained_last_compaction = stats.live;
+        stats.collected_last_compaction = expired.len();
+        stats.collected_total += expired.len() as u64;
+        Ok(())
+    }
+}
+
+impl<K, V> Default for LogManager<K, V>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> LogManager<K, Json>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+{
+    /// The part of the document of `key` that `path` (like "$.user.name") points to, None if
+    /// there is no such key or nothing at that path. See json.rs.
+    pub fn get_path(&self, key: &K, path: &str) -> Result<Option<Json>> {
+        let path = path.parse::<JsonPath>()?;
+        Ok(self.btree.search(key).and_then(|document| document.get_path(&path)).cloned().map(Json))
+    }
+
+    /// Replace the part of the document of `key` that `path` points to with `value`, creating
+    /// the document if there is none. Only the path and `value` are logged, see json.rs.
+    pub fn set_path(&mut self, key: K, path: &str, value: Json) -> Result<()> {
+        let started = self.latencies.start();
+        let path = path.parse::<JsonPath>()?;
+        check_token("key", &key.to_string())?;
+        let mut document = self.btree.search(&key).cloned().unwrap_or_default();
+        document.set_path(&path, value.0.clone())?;
+        self.check_unique(&key, &document)?;
+
+        self.apply_insert(key.clone(), document.clone());
+        let written = self.append(format!("PATCH {} {} {}", key, path, value))?;
+        self.watchers.notify(Event::Set(key, document));
+        self.after_write(written)?;
+        self.latencies.record(Operation::Insert, started);
+        Ok(())
+    }
+}
+
+impl<T, V> LogManager<EncodedKey<T>, V>
+where
+    T: OrderedKey + Ord + Clone + Debug,
+    V: Clone + Debug + FromStr + Display,
+    <V as FromStr>::Err: Debug,
+{
+    /// The pairs whose key starts with `prefix`, in key order. With (String, u64) keys,
+    /// `scan_prefix(&("ann".to_string(),))` finds every ("ann", _) pair. See keycodec.rs.
+    pub fn scan_prefix<P: OrderedKey>(&self, prefix: &P) -> Vec<(EncodedKey<T>, V)> {
+        self.btree.range(&keycodec::prefix_range(prefix))
     }
 }
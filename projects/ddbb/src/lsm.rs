@@ -0,0 +1,1350 @@
+// src/lsm.rs
+
+/*
+* LSM Tree
+*
+* LogManager and DiskBTree update a tree for every single write. A log-structured merge tree
+* turns writes into sequential I/O instead:
+*
+* 1. a write is appended to the write-ahead log (WAL) and applied to the memtable, an in-memory
+*    BTree (see btree.rs) where a delete is stored as a tombstone
+* 2. once the memtable is due for a flush (see flush.rs for the limits) or `flush` is called,
+*    it is written out as an immutable sorted run (an SSTable, see sstable.rs) in level 0 and a
+*    new WAL is started
+* 3. compaction merges runs into the next level down, dropping overwritten values and the
+*    tombstones that have nothing left to hide
+*
+* A lookup checks the newest data first and stops at the first hit, a tombstone included:
+*
+*   memtable -> level 0 runs (newest first) -> level 1 -> level 2 -> ...
+*
+* and a range scan merges all of them, the newest version of each key winning (see MergeIter).
+* A descending scan merges them the same way from the end of the range down, reading the runs
+* block by block from their end, so a scan with a limit stops as soon as it has enough pairs.
+*
+* ############################################################################################
+*
+* Snapshots and versions
+*
+* Every write gets the next sequence number, and a key maps to its versions, newest first:
+*
+*   key -> [(seq 12, "c"), (seq 7, tombstone), (seq 3, "a")]
+*
+* `snapshot` returns a handle on the current sequence number, and `get_at` / `range_at` read
+* the newest version of each key at or before it, so a reader holding a snapshot keeps seeing
+* the tree as it was however it is written to since. Snapshots only live in memory: after a
+* restart every read sees the latest data.
+*
+* Versions are garbage collected against the oldest live snapshot (the last sequence number
+* when there is none): a reader at or after it sees at most one version at or before it, the
+* newest one, so every older version is dropped. With no snapshot a write simply replaces the
+* value in the memtable; a long-lived snapshot makes the versions written since pile up in
+* the memtable and then in the runs, until compaction (or a flush) finds it released and drops
+* them. `LsmMetrics::versions` counts the versions kept this way, so unbounded history growth
+* shows up there.
+*
+* ############################################################################################
+*
+* Leveled compaction
+*
+* Level 0 runs come straight from the memtable, so their key ranges overlap and a lookup may
+* have to read every one of them. From level 1 on, the runs of a level have disjoint key
+* ranges and a lookup reads at most one run per level. Every level holds
+* `Options::level_size_multiplier` times more bytes than the one above it:
+*
+*   L0: up to l0_compaction_trigger runs
+*   L1: level_base_bytes
+*   L2: level_base_bytes * multiplier
+*   ...
+*
+* After a flush, every level gets a score, its size divided by its limit (for level 0 the
+* number of runs divided by the trigger), and the level with the highest score >= 1 is
+* compacted:
+* - for level 0, every run of level 0 and the level 1 runs they overlap
+* - for level n, one run, picked round robin through the key space, and the runs of level n + 1
+*   it overlaps
+* are merged into new level n + 1 runs of about `Options::target_file_size` bytes each. This
+* repeats until no level is over its limit, which bounds the number of runs a read has to
+* look at (read amplification) to the level 0 runs plus one per level. With
+* `Options::auto_compaction` off, the compactions wait for `compact_pending` instead, and the
+* writes stall when they fall too far behind (see flush.rs).
+*
+* A tombstone can only be dropped when no deeper level may still hold an older value of its
+* key.
+*
+* ############################################################################################
+*
+* Ingesting files
+*
+* `ingest_file` adds an SSTable built elsewhere (say, by a bulk load sorting its input) as a
+* run, skipping the WAL, the memtable and the flush. Its entries are plain values, not lists of
+* versions: the manifest records one sequence number, newer than any write before, for all of
+* them. The run goes into the deepest level where no run of that level or the ones above it
+* overlaps its key range, so it lands below data it does not conflict with and does not have to
+* be compacted down level after level. If the memtable holds one of its keys, it is flushed
+* first so that the older write ends up in a run below the file.
+*
+* ############################################################################################
+*
+* Checkpoints
+*
+* `checkpoint_to` copies a live tree into another directory as a restore point. Runs are never
+* modified once written, so they are hard-linked (copied where that is not possible) instead of
+* copied. The WAL is the only file still being written to: it is copied as it stands, which
+* fixes the point in time of the checkpoint, every write before it and none after it. A new
+* MANIFEST holding the current Version is written last, so until it exists the directory is
+* not a tree that can be opened. No file of the tree itself is touched, and the tree can be
+* written to as soon as the call returns.
+*
+* ############################################################################################
+*
+* Files in the directory
+*
+* LOCK                 held while the tree is open
+* MANIFEST             the level of every run and the WAL to replay, see manifest.rs
+* 000009.wal           the WAL of the memtable
+* 000008.sst           sorted runs, named by file number
+* 000005.sst
+*
+* File numbers only grow, so a bigger number always holds newer data.
+*
+* A flush or a compaction replaces several files at once, which cannot be done atomically.
+* Its new runs are written and synced first, then a single VersionEdit adding them and
+* removing the runs (or the WAL) they replace is made durable in the manifest; from that point
+* on the change is done and the replaced files are removed. Open only uses the files the
+* manifest lists: a run it does not know about is the output of a flush or compaction that was
+* interrupted before its edit, and is removed.
+*
+* ############################################################################################
+*
+* WAL segments
+*
+* A WAL record is the number of its WAL and the sequence number of the write followed by an
+* entry encoded like in the data blocks of an SSTable, prefixed by the crc32 of all three:
+*
+*   [crc32][WAL number: u64][sequence: u64][key len: u32][value len: u32][key][value]
+*
+* Replay stops at the first record that is incomplete, fails its checksum (a write torn by a
+* crash) or belongs to another WAL.
+*
+* The last case comes from recycling (`Options::wal_recycle`): instead of removing the WAL a
+* flush made obsolete, up to that many of them are kept and later renamed to become a new WAL,
+* which is overwritten from the start. The records of its previous life are still there after
+* the new ones, with a valid checksum but the old number. With `Options::wal_preallocate` a new
+* WAL is extended to the size of a full write buffer before the first write, and the zeros
+* after the last record do not decode as a record either. Both spare the file system from
+* allocating space and updating file sizes while writes wait for a sync.
+*/
+
+use crate::btree::BTree;
+use crate::cache::{BlockCache, CacheStats};
+use crate::error::{Error, Result};
+use crate::explain::{self, Pairs, ReadStats};
+use crate::flush::{FlushReason, FlushStats, WriteBuffer};
+use crate::histogram::{LatencyHistograms, LatencyRecorder, Operation};
+use crate::manifest::{Manifest, Version, VersionEdit};
+use crate::options::Options;
+use crate::sstable::{self, Entry, Table, TableWriter};
+use crate::vfs::{OpenOptions, Vfs, VfsFile, VfsLock};
+use std::cell::Cell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::io::Write;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LOCK_FILE: &str = "LOCK";
+/// Number of levels, the last one is never compacted further.
+pub const LEVELS: usize = 7;
+
+// WAL record header: crc32, WAL number, sequence number
+const RECORD_HEADER: usize = 20;
+
+// The versions of a key, newest first, as (sequence number, entry)
+type Versions = Vec<(u64, Entry)>;
+type Source<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Versions)>> + 'a>;
+
+/// Counters of an LsmTree, see `LsmTree::metrics`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LsmMetrics {
+    /// Level 0 first.
+    pub levels: Vec<LevelMetrics>,
+    pub compaction: CompactionStats,
+    pub flush: FlushStats,
+    pub wal: WalStats,
+    /// All zero when the tree has no block cache.
+    pub block_cache: CacheStats,
+    pub versions: VersionStats,
+    /// None unless `Options::latency_histograms` is on, see histogram.rs.
+    pub latencies: Option<LatencyHistograms>,
+}
+
+/// Snapshots and the old versions they keep alive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VersionStats {
+    pub last_sequence: u64,
+    pub snapshots: usize,
+    /// Sequence number of the oldest live snapshot.
+    pub oldest_snapshot: Option<u64>,
+    /// Versions kept for snapshots: those besides the newest one of each key in the memtable
+    /// and in every run. Overwrites spread over several runs, waiting for compaction like
+    /// they do without snapshots, are not counted.
+    pub retained: u64,
+    /// Versions dropped by flushes and compactions since the tree was opened.
+    pub dropped: u64,
+}
+
+/// A consistent view of an LsmTree as of `LsmTree::snapshot`, read with `get_at` and `range_at`.
+/// The versions it sees are kept until it is dropped.
+pub struct Snapshot {
+    sequence: u64,
+    live: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl Snapshot {
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&self.sequence) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&self.sequence);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelMetrics {
+    pub runs: usize,
+    pub bytes: u64,
+    /// Size above which the level is compacted, 0 for level 0 (limited by its number of runs)
+    /// and for the last level.
+    pub max_bytes: u64,
+}
+
+/// WAL segments started since the tree was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalStats {
+    pub created: u64,
+    /// Old WALs that were reused, see `Options::wal_recycle`.
+    pub recycled: u64,
+}
+
+/// Work done by compaction since the tree was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub compactions: u64,
+    pub runs_read: u64,
+    pub runs_written: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub duration: Duration,
+}
+
+pub struct LsmTree {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    options: Options,
+    memtable: BTree<Vec<u8>, Versions>,
+    memtable_old_versions: u64,
+    buffer: WriteBuffer, // the writes in the memtable
+    wal: Box<dyn VfsFile>,
+    wal_path: PathBuf,
+    wal_number: u64,
+    wal_segments: WalSegments,
+    // level 0 newest run first, the other levels ordered by key
+    levels: Vec<Vec<Run>>,
+    // largest key of the last run compacted out of every level, see pick_compaction
+    compact_pointers: Vec<Vec<u8>>,
+    compaction_stats: CompactionStats,
+    flush_stats: FlushStats,
+    block_cache: Option<Arc<BlockCache>>,
+    last_sequence: u64,
+    // live snapshots, as sequence number -> number of handles
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+    versions_dropped: u64,
+    manifest: Manifest,
+    next_file: u64,
+    latencies: LatencyRecorder, // see histogram.rs
+    _lock: Box<dyn VfsLock>,
+}
+
+impl LsmTree {
+    /// Open (or create) the tree stored in `dir`.
+    pub fn open(vfs: Arc<dyn Vfs>, dir: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(vfs, dir, Options::default())
+    }
+
+    /// Same as `open`, with non-default `Options`.
+    pub fn open_with(vfs: Arc<dyn Vfs>, dir: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        vfs.create_dir_all(&dir)?;
+        let lock = vfs.lock(&dir.join(LOCK_FILE))?;
+
+        let manifest = match Manifest::open(vfs.clone(), &dir)? {
+            Some(manifest) => manifest,
+            None => {
+                // a new tree, unless there are files only a manifest could account for
+                if vfs.list(&dir)?.iter().any(|path| {
+                    let name = file_name(path);
+                    parse_wal_name(&name).is_some() || parse_run_name(&name).is_some()
+                }) {
+                    return Err(Error::Corruption(format!("{} has no MANIFEST", dir.display())));
+                }
+                let version = Version { levels: vec![Vec::new(); LEVELS], wal: 0, next_file: 1, ..Version::default() };
+                Manifest::create(vfs.clone(), &dir, version)?
+            }
+        };
+        let version = manifest.version().clone();
+
+        // drop whatever the manifest does not refer to
+        let live: HashSet<u64> = version.runs().map(|(_, number)| number).collect();
+        let mut wals = Vec::new();
+        let mut wal_segments = WalSegments::new(&options);
+        let mut next_file = version.next_file;
+        for path in vfs.list(&dir)? {
+            let name = file_name(&path);
+            if let Some(number) = parse_wal_name(&name) {
+                next_file = next_file.max(number + 1);
+                if number >= version.wal {
+                    wals.push((number, path));
+                } else {
+                    // already written to a run: kept for recycling, or its removal was lost in
+                    // a crash
+                    wal_segments.retire(vfs.as_ref(), path)?;
+                }
+            } else if let Some(number) = parse_run_name(&name) {
+                next_file = next_file.max(number + 1);
+                if !live.contains(&number) {
+                    vfs.remove(&path)?;
+                }
+            } else if name.ends_with(".tmp") {
+                vfs.remove(&path)?;
+            }
+        }
+
+        let block_cache = match (&options.block_cache, options.block_cache_size) {
+            (Some(cache), _) => Some(cache.clone()),
+            (None, 0) => None,
+            (None, size) => Some(Arc::new(BlockCache::new(size))),
+        };
+        let mut levels: Vec<Vec<Run>> = (0..LEVELS).map(|_| Vec::new()).collect();
+        for (level, number) in version.runs() {
+            if level >= LEVELS {
+                return Err(Error::Corruption(format!("manifest puts run {} in level {}", number, level)));
+            }
+            let mut run = open_run(vfs.as_ref(), &dir, number, block_cache.as_ref(), options.direct_io)?;
+            run.old_versions = version.old_versions.get(&number).copied().unwrap_or(0);
+            run.ingested = version.ingested.get(&number).copied();
+            levels[level].push(run);
+        }
+        levels[0].sort_by_key(|run| Reverse(run.number));
+        for level in &mut levels[1..] {
+            level.sort_by(|a, b| a.table.smallest().cmp(b.table.smallest()));
+        }
+
+        // replay the WALs, oldest first, into the memtable, which is then flushed like any other
+        wals.sort();
+        let mut memtable = BTree::new();
+        let mut last_sequence = version.last_sequence;
+        for (number, path) in &wals {
+            last_sequence = last_sequence.max(replay_wal(&vfs.read(path)?, *number, &mut memtable));
+        }
+        let wal_number = next_file;
+        let (wal_path, wal) = wal_segments.create(vfs.as_ref(), &dir, wal_number)?;
+
+        let latencies = LatencyRecorder::new(options.latency_histograms);
+        let mut tree = LsmTree {
+            vfs,
+            dir,
+            options,
+            memtable,
+            memtable_old_versions: 0,
+            buffer: WriteBuffer::default(),
+            wal,
+            wal_path,
+            wal_number,
+            wal_segments,
+            levels,
+            compact_pointers: vec![Vec::new(); LEVELS],
+            compaction_stats: CompactionStats::default(),
+            flush_stats: FlushStats::default(),
+            block_cache,
+            last_sequence,
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            versions_dropped: 0,
+            manifest,
+            next_file: next_file + 1,
+            latencies,
+            _lock: lock,
+        };
+        let old_wals: Vec<PathBuf> = wals.into_iter().map(|(_, path)| path).collect();
+        tree.write_memtable(wal_number, old_wals)?;
+        Ok(tree)
+    }
+
+    /// Insert or overwrite `key`. The write is in the WAL when this returns, durable after `sync`.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if value.len() >= u32::MAX as usize {
+            return Err(Error::InvalidArgument(format!("value of {} bytes is too large", value.len())));
+        }
+        let started = self.latencies.start();
+        self.write(key, Some(value))?;
+        self.latencies.record(Operation::Insert, started);
+        Ok(())
+    }
+
+    /// Remove `key`, which is recorded as a tombstone until compaction drops it.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let started = self.latencies.start();
+        self.write(key, None)?;
+        self.latencies.record(Operation::Delete, started);
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let started = self.latencies.start();
+        let value = self.get_as_of(key, u64::MAX, None)?;
+        self.latencies.record(Operation::Search, started);
+        Ok(value)
+    }
+
+    /// The value of `key` when `snapshot` was taken.
+    pub fn get_at(&self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let started = self.latencies.start();
+        let value = self.get_as_of(key, snapshot.sequence, None)?;
+        self.latencies.record(Operation::Search, started);
+        Ok(value)
+    }
+
+    /// Every pair whose key is inside `range`, in ascending key order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let started = self.latencies.start();
+        let pairs = self.range_as_of(range, u64::MAX, false, usize::MAX, None)?;
+        self.latencies.record(Operation::Scan, started);
+        Ok(pairs)
+    }
+
+    /// Same as `range`, as of when `snapshot` was taken.
+    pub fn range_at<R: RangeBounds<Vec<u8>>>(&self, snapshot: &Snapshot, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let started = self.latencies.start();
+        let pairs = self.range_as_of(range, snapshot.sequence, false, usize::MAX, None)?;
+        self.latencies.record(Operation::Scan, started);
+        Ok(pairs)
+    }
+
+    /// Same as `get`, also returning what the lookup read, see explain.rs.
+    pub fn explain_get(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, ReadStats)> {
+        let stats = Cell::default();
+        let value = self.get_as_of(key, u64::MAX, Some(&stats))?;
+        Ok((value, stats.get()))
+    }
+
+    /// Same as `range`, also returning what the scan read, see explain.rs.
+    pub fn explain_range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<(Pairs, ReadStats)> {
+        let stats = Cell::default();
+        let pairs = self.range_as_of(range, u64::MAX, false, usize::MAX, Some(&stats))?;
+        Ok((pairs, stats.get()))
+    }
+
+    /// Same as `range`, in descending key order. The memtable and the runs are merged from the
+    /// end of the range down, not collected and reversed.
+    pub fn range_rev<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.range_rev_limit(range, usize::MAX)
+    }
+
+    /// The first `limit` pairs of `range_rev`, the largest keys of the range. The merge stops
+    /// there, so the runs are only read as far down as those keys.
+    pub fn range_rev_limit<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> Result<Pairs> {
+        let started = self.latencies.start();
+        let pairs = self.range_as_of(range, u64::MAX, true, limit, None)?;
+        self.latencies.record(Operation::Scan, started);
+        Ok(pairs)
+    }
+
+    pub fn traverse(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.range(..)
+    }
+
+    /// Add the SSTable at `path`, written with a `TableWriter`, to the tree as a run of its own
+    /// without going through the WAL and the memtable, see the top of the file. Its entries
+    /// become the newest version of their keys. The file is linked into the tree (copied if it
+    /// cannot be) and must not be modified afterwards.
+    pub fn ingest_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let table = Table::open(self.vfs.as_ref(), path)?;
+        if table.is_empty() {
+            return Err(Error::InvalidArgument(format!("{} is empty", path.display())));
+        }
+        let lo = table.smallest().to_vec();
+        let hi = table.largest().to_vec();
+        drop(table);
+
+        // older writes to its keys still in the memtable have to end up below the file
+        let mut in_memtable = false;
+        self.memtable.visit_range(&(Bound::Included(&lo), Bound::Included(&hi)), |_, _| {
+            in_memtable = true;
+            false
+        });
+        if in_memtable {
+            self.flush()?;
+        }
+        // the deepest level that neither it nor a level above holds any of its key range, so
+        // every older version of its keys stays below it
+        let overlaps = |run: &Run| run.overlaps(Bound::Included(&lo), Bound::Included(&hi));
+        let level = (0..LEVELS).take_while(|&level| !self.levels[level].iter().any(overlaps)).last().unwrap_or(0);
+
+        let number = self.next_number();
+        let sequence = self.last_sequence + 1;
+        let run_path = self.dir.join(run_name(number));
+        self.vfs.link(path, &run_path)?;
+        self.vfs.open(&run_path, OpenOptions::new().write(true))?.sync()?;
+        self.vfs.sync_dir(&self.dir)?;
+        let mut run = open_run(self.vfs.as_ref(), &self.dir, number, self.block_cache.as_ref(), self.options.direct_io)?;
+        run.ingested = Some(sequence);
+        self.manifest.log_edit(&VersionEdit {
+            next_file: Some(self.next_file),
+            last_sequence: Some(sequence),
+            added: vec![(level, number)],
+            ingested: vec![(number, sequence)],
+            ..VersionEdit::default()
+        })?;
+        self.last_sequence = sequence;
+
+        if level == 0 {
+            self.levels[0].insert(0, run);
+        } else {
+            self.levels[level].push(run);
+            self.levels[level].sort_by(|a, b| a.table.smallest().cmp(b.table.smallest()));
+        }
+        self.maybe_compact()
+    }
+
+    /// Make `dir` a copy of the tree as it is now, which `open` can use as is, see the top of
+    /// the file. `dir` must be empty or not exist.
+    pub fn checkpoint_to(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        self.vfs.create_dir_all(dir)?;
+        if !self.vfs.list(dir)?.is_empty() {
+            return Err(Error::InvalidArgument(format!("{} is not empty", dir.display())));
+        }
+
+        // runs never change, a link is enough; the WAL is still written to, so it is copied
+        // up to the last write, which is where the checkpoint stands
+        for run in self.levels.iter().flatten() {
+            self.vfs.link(&run.path, &dir.join(run_name(run.number)))?;
+        }
+        self.wal.sync()?;
+        let wal = self.vfs.read(&self.wal_path)?;
+        self.vfs.write(&dir.join(file_name(&self.wal_path)), &wal)?;
+        self.vfs.sync_dir(dir)?;
+        Manifest::create(self.vfs.clone(), dir, self.manifest.version().clone())?;
+        Ok(())
+    }
+
+    /// A view of the tree as it is now, see the top of the file.
+    pub fn snapshot(&self) -> Snapshot {
+        *self.snapshots.lock().unwrap().entry(self.last_sequence).or_insert(0) += 1;
+        Snapshot { sequence: self.last_sequence, live: self.snapshots.clone() }
+    }
+
+    // The newest version of `key` at or before `sequence`
+    fn get_as_of(&self, key: &[u8], sequence: u64, stats: Option<&Cell<ReadStats>>) -> Result<Option<Vec<u8>>> {
+        let mut nodes_visited = 0;
+        let in_memtable = self.memtable.search_counting(&key.to_vec(), &mut nodes_visited);
+        explain::record(stats, |stats| stats.nodes_visited += nodes_visited);
+        if let Some(entry) = in_memtable.and_then(|versions| visible(versions, sequence)) {
+            return Ok(entry.clone());
+        }
+        // a run may only hold versions newer than `sequence`, then an older run has the answer
+        let level0 = self.levels[0].iter().filter(|run| {
+            let contains = run.contains(key);
+            explain::record(stats, |stats| stats.tables_skipped += !contains as u64);
+            contains
+        });
+        let deeper = self.levels[1..].iter().filter_map(|level| find_run(level, key));
+        for run in level0.chain(deeper) {
+            if let Some(versions) = run.versions(key, stats)? {
+                if let Some(entry) = visible(&versions, sequence) {
+                    return Ok(entry.clone());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // The pairs of `range` at `sequence`, in descending key order when `reverse`, up to `limit`
+    fn range_as_of<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        sequence: u64,
+        reverse: bool,
+        limit: usize,
+        stats: Option<&Cell<ReadStats>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let lo = range.start_bound().map(|k| k.as_slice());
+        let hi = range.end_bound().map(|k| k.as_slice());
+        let after_lo = move |key: &[u8]| match lo {
+            Bound::Included(lo) => key >= lo,
+            Bound::Excluded(lo) => key > lo,
+            Bound::Unbounded => true,
+        };
+        let before_hi = move |key: &[u8]| match hi {
+            Bound::Included(hi) => key <= hi,
+            Bound::Excluded(hi) => key < hi,
+            Bound::Unbounded => true,
+        };
+
+        // only the part of the memtable inside the range is copied
+        let memtable: Source = match reverse {
+            false => Box::new(self.memtable.range(&range).into_iter().map(Ok)),
+            true => {
+                let mut entries = Vec::new();
+                self.memtable.visit_range_rev(&range, |key, versions| {
+                    entries.push(Ok((key.clone(), versions.clone())));
+                    true
+                });
+                Box::new(entries.into_iter())
+            }
+        };
+        let mut sources = vec![memtable];
+        for run in self.levels.iter().flatten() {
+            match run.overlaps(lo, hi) {
+                true => {
+                    explain::record(stats, |stats| stats.tables_searched += 1);
+                    sources.push(match reverse {
+                        false => run.source(lo, stats),
+                        true => run.source_rev(hi, stats),
+                    });
+                }
+                false => explain::record(stats, |stats| stats.tables_skipped += 1),
+            }
+        }
+
+        let mut pairs = Vec::new();
+        for item in MergeIter::new(sources, reverse)? {
+            if pairs.len() == limit {
+                break;
+            }
+            let (key, versions) = item?;
+            explain::record(stats, |stats| stats.entries_scanned += 1);
+            // the merge starts at one end of the range and stops past the other one
+            let (from_start, before_end) = match reverse {
+                false => (after_lo(&key), before_hi(&key)),
+                true => (before_hi(&key), after_lo(&key)),
+            };
+            if !before_end {
+                break;
+            }
+            if let (true, Some(Some(value))) = (from_start, visible(&versions, sequence)) {
+                pairs.push((key, value.clone()));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Make every write so far durable.
+    pub fn sync(&mut self) -> Result<()> {
+        self.wal.sync()?;
+        Ok(())
+    }
+
+    /// Write the memtable out as a level 0 run now, instead of waiting for it to fill up.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.flush_for(FlushReason::Manual)
+    }
+
+    /// Run the compactions that are due, which a flush does itself unless
+    /// `Options::auto_compaction` is off. Returns how many there were.
+    pub fn compact_pending(&mut self) -> Result<u64> {
+        let before = self.compaction_stats.compactions;
+        self.maybe_compact()?;
+        Ok(self.compaction_stats.compactions - before)
+    }
+
+    /// Merge every run into the deepest level that holds data, dropping every version and
+    /// tombstone no snapshot can see. The memtable is left alone.
+    pub fn compact(&mut self) -> Result<()> {
+        let output_level = (1..LEVELS).rev().find(|&level| !self.levels[level].is_empty()).unwrap_or(1);
+        let inputs = (0..LEVELS)
+            .flat_map(|level| (0..self.levels[level].len()).map(move |i| (level, i)))
+            .collect();
+        self.compact_runs(inputs, output_level)
+    }
+
+    /// Number of sorted runs in each level.
+    pub fn level_runs(&self) -> Vec<usize> {
+        self.levels.iter().map(Vec::len).collect()
+    }
+
+    pub fn metrics(&self) -> LsmMetrics {
+        let levels = (0..LEVELS)
+            .map(|level| LevelMetrics {
+                runs: self.levels[level].len(),
+                bytes: self.level_bytes(level),
+                max_bytes: if level == 0 || level == LEVELS - 1 { 0 } else { self.max_bytes(level) },
+            })
+            .collect();
+        let block_cache = self.block_cache.as_ref().map(|cache| cache.stats()).unwrap_or_default();
+        let snapshots = self.snapshots.lock().unwrap();
+        let versions = VersionStats {
+            last_sequence: self.last_sequence,
+            snapshots: snapshots.values().sum(),
+            oldest_snapshot: snapshots.keys().next().copied(),
+            retained: self.memtable_old_versions
+                + self.levels.iter().flatten().map(|run| run.old_versions).sum::<u64>(),
+            dropped: self.versions_dropped,
+        };
+        LsmMetrics {
+            levels,
+            compaction: self.compaction_stats,
+            flush: self.flush_stats,
+            wal: self.wal_segments.stats,
+            block_cache,
+            versions,
+            latencies: self.latencies.histograms(),
+        }
+    }
+
+    fn write(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if key.len() >= u32::MAX as usize {
+            return Err(Error::InvalidArgument(format!("key of {} bytes is too large", key.len())));
+        }
+        self.throttle()?;
+        let sequence = self.last_sequence + 1;
+        let mut record = vec![0; 4];
+        record.extend_from_slice(&self.wal_number.to_le_bytes());
+        record.extend_from_slice(&sequence.to_le_bytes());
+        sstable::encode_entry(&mut record, key, value);
+        let checksum = crc32fast::hash(&record[4..]);
+        record[..4].copy_from_slice(&checksum.to_le_bytes());
+        self.wal.write_all(&record)?;
+        self.wal.flush()?;
+
+        self.last_sequence = sequence;
+
+        let entry = value.map(<[u8]>::to_vec);
+        let oldest = self.oldest_snapshot();
+        match self.memtable.search_mut(&key.to_vec()) {
+            Some(versions) => {
+                let before = versions.len() as u64 - 1;
+                versions.insert(0, (sequence, entry));
+                collect_garbage(versions, oldest);
+                self.memtable_old_versions = self.memtable_old_versions - before + versions.len() as u64 - 1;
+            }
+            None => {
+                self.memtable.upsert(key.to_vec(), vec![(sequence, entry)]);
+            }
+        }
+        self.buffer.add(record.len());
+        match self.buffer.due(&self.options) {
+            Some(reason) if !self.stopped() => self.flush_for(reason),
+            _ => Ok(()),
+        }
+    }
+
+    // Hold the next write back while the level 0 runs pile up, see flush.rs
+    fn throttle(&mut self) -> Result<()> {
+        if self.stopped() && self.options.auto_compaction {
+            self.maybe_compact()?;
+        }
+        if self.stopped() && self.buffer.bytes >= self.options.max_write_buffer_size {
+            self.flush_stats.stopped += 1;
+            let message = format!(
+                "{} level 0 runs wait for compaction, and {} bytes for a flush",
+                self.levels[0].len(),
+                self.buffer.bytes
+            );
+            return Err(Error::WriteStall(message));
+        }
+        if self.levels[0].len() >= self.options.l0_slowdown_trigger {
+            thread::sleep(self.options.slowdown_delay);
+            self.flush_stats.slowed += 1;
+            self.flush_stats.stall += self.options.slowdown_delay;
+        }
+        Ok(())
+    }
+
+    // Whether level 0 has too many runs for another flush
+    fn stopped(&self) -> bool {
+        self.levels[0].len() >= self.options.l0_stop_trigger
+    }
+
+    fn flush_for(&mut self, reason: FlushReason) -> Result<()> {
+        let started = Instant::now();
+        self.flush_memtable()?;
+        self.flush_stats.record(reason, started.elapsed());
+        Ok(())
+    }
+
+    // Start over with an empty memtable and WAL
+    fn flush_memtable(&mut self) -> Result<()> {
+        let number = self.next_number();
+        let (wal_path, wal) = self.wal_segments.create(self.vfs.as_ref(), &self.dir, number)?;
+        self.wal = wal;
+        self.wal_number = number;
+        let old_wal = std::mem::replace(&mut self.wal_path, wal_path);
+        self.write_memtable(number, vec![old_wal])
+    }
+
+    // Write the memtable to a level 0 run, which replaces `old_wals` by the WAL numbered
+    // `wal_number` once the manifest says so
+    fn write_memtable(&mut self, wal_number: u64, old_wals: Vec<PathBuf>) -> Result<()> {
+        // snapshots released since the writes may have left versions nobody can see
+        let oldest = self.oldest_snapshot();
+        let mut dropped = 0;
+        let entries = std::mem::take(&mut self.memtable).traverse().into_iter().map(|(key, mut versions)| {
+            dropped += collect_garbage(&mut versions, oldest);
+            Ok((key, versions))
+        });
+        self.buffer = WriteBuffer::default();
+        self.memtable_old_versions = 0;
+        let cache = self.block_cache.as_ref();
+        let direct_io = self.options.direct_io;
+        let flushed =
+            write_runs(self.vfs.as_ref(), &self.dir, cache, direct_io, &mut self.next_file, u64::MAX, entries)?;
+        self.versions_dropped += dropped;
+
+        // the run and the new WAL must be durable before the manifest refers to them
+        self.vfs.sync_dir(&self.dir)?;
+        self.manifest.log_edit(&VersionEdit {
+            next_file: Some(self.next_file),
+            wal: Some(wal_number),
+            added: flushed.iter().map(|run| (0, run.number)).collect(),
+            removed: Vec::new(),
+            last_sequence: Some(self.last_sequence),
+            old_versions: old_versions(&flushed),
+            ingested: Vec::new(),
+        })?;
+        self.levels[0].splice(0..0, flushed);
+        for path in old_wals {
+            self.wal_segments.retire(self.vfs.as_ref(), path)?;
+        }
+
+        if !self.options.auto_compaction {
+            return Ok(());
+        }
+        self.maybe_compact()
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        while let Some((inputs, output_level)) = self.pick_compaction() {
+            self.compact_runs(inputs, output_level)?;
+        }
+        Ok(())
+    }
+
+    // The runs to compact, as (level, index) pairs, and the level of the output, None when no
+    // level is over its limit
+    fn pick_compaction(&self) -> Option<(Vec<(usize, usize)>, usize)> {
+        let mut best = (1.0, None);
+        let l0_score = self.levels[0].len() as f64 / self.options.l0_compaction_trigger.max(1) as f64;
+        if l0_score >= best.0 {
+            best = (l0_score, Some(0));
+        }
+        for level in 1..LEVELS - 1 {
+            let score = self.level_bytes(level) as f64 / self.max_bytes(level) as f64;
+            if score >= best.0 {
+                best = (score, Some(level));
+            }
+        }
+        let level = best.1?;
+
+        let upper: Vec<(usize, usize)> = if level == 0 {
+            (0..self.levels[0].len()).map(|i| (0, i)).collect()
+        } else {
+            // the first run after the one compacted last time, wrapping around
+            let pointer = &self.compact_pointers[level];
+            let runs = &self.levels[level];
+            let i = runs.iter().position(|run| run.table.smallest() > pointer.as_slice()).unwrap_or(0);
+            vec![(level, i)]
+        };
+
+        // the runs of the next level that overlap the key range of the chosen ones
+        let runs = upper.iter().map(|&(l, i)| &self.levels[l][i]);
+        let lo = runs.clone().map(|run| run.table.smallest()).min()?;
+        let hi = runs.map(|run| run.table.largest()).max()?;
+        let lower = self.levels[level + 1]
+            .iter()
+            .enumerate()
+            .filter(|(_, run)| run.overlaps(Bound::Included(lo), Bound::Included(hi)))
+            .map(|(i, _)| (level + 1, i));
+        Some((upper.iter().copied().chain(lower).collect(), level + 1))
+    }
+
+    // Merge `inputs`, ordered newest first, into new runs of `output_level`
+    fn compact_runs(&mut self, inputs: Vec<(usize, usize)>, output_level: usize) -> Result<()> {
+        if inputs.is_empty() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let mut sources: Vec<Source> = Vec::new();
+        let mut bytes_read = 0;
+        for &(level, i) in &inputs {
+            let run = &self.levels[level][i];
+            sources.push(run.source(Bound::Unbounded, None));
+            bytes_read += run.table.size();
+        }
+
+        let oldest = self.oldest_snapshot();
+        let deeper = &self.levels[output_level + 1..];
+        let mut dropped = 0;
+        let merged = MergeIter::new(sources, false)?.filter_map(|item| {
+            let (key, mut versions) = match item {
+                Ok(item) => item,
+                Err(e) => return Some(Err(e)),
+            };
+            dropped += collect_garbage(&mut versions, oldest);
+            // the oldest version left is the one the oldest snapshot reads; as a tombstone it
+            // still hides older values if a deeper level may hold its key
+            if versions.last().is_some_and(|(sequence, entry)| *sequence <= oldest && entry.is_none())
+                && !deeper.iter().any(|level| find_run(level, &key).is_some())
+            {
+                versions.pop();
+                dropped += 1;
+            }
+            (!versions.is_empty()).then_some(Ok((key, versions)))
+        });
+        let outputs = write_runs(
+            self.vfs.as_ref(),
+            &self.dir,
+            self.block_cache.as_ref(),
+            self.options.direct_io,
+            &mut self.next_file,
+            self.options.target_file_size,
+            merged,
+        )?;
+        self.versions_dropped += dropped;
+
+        // swap the inputs for the outputs in the manifest, see the top of the file
+        self.vfs.sync_dir(&self.dir)?;
+        self.manifest.log_edit(&VersionEdit {
+            next_file: Some(self.next_file),
+            wal: None,
+            added: outputs.iter().map(|run| (output_level, run.number)).collect(),
+            removed: inputs.iter().map(|&(level, i)| (level, self.levels[level][i].number)).collect(),
+            last_sequence: None,
+            old_versions: old_versions(&outputs),
+            ingested: Vec::new(),
+        })?;
+        let removed: Vec<PathBuf> = inputs.iter().map(|&(level, i)| self.levels[level][i].path.clone()).collect();
+        for path in &removed {
+            self.vfs.remove(path)?;
+        }
+
+        let upper = output_level - 1;
+        if let Some(&(_, i)) = inputs.iter().find(|&&(level, _)| level == upper && upper > 0) {
+            self.compact_pointers[upper] = self.levels[upper][i].table.largest().to_vec();
+        }
+        // highest indices first, so the indices of the other inputs stay valid
+        let mut inputs = inputs;
+        inputs.sort_by(|a, b| b.cmp(a));
+        for (level, i) in inputs {
+            self.levels[level].remove(i);
+        }
+
+        let stats = &mut self.compaction_stats;
+        stats.compactions += 1;
+        stats.runs_read += removed.len() as u64;
+        stats.runs_written += outputs.len() as u64;
+        stats.bytes_read += bytes_read;
+        stats.bytes_written += outputs.iter().map(|run| run.table.size()).sum::<u64>();
+        stats.duration += started.elapsed();
+
+        let level = &mut self.levels[output_level];
+        level.extend(outputs);
+        level.sort_by(|a, b| a.table.smallest().cmp(b.table.smallest()));
+        Ok(())
+    }
+
+    fn level_bytes(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|run| run.table.size()).sum()
+    }
+
+    // Size limit of a level >= 1
+    fn max_bytes(&self, level: usize) -> u64 {
+        let multiplier = self.options.level_size_multiplier.max(1);
+        self.options.level_base_bytes.saturating_mul(multiplier.saturating_pow(level as u32 - 1))
+    }
+
+    // Sequence number of the oldest live snapshot, the last one handed out if there is none
+    fn oldest_snapshot(&self) -> u64 {
+        self.snapshots.lock().unwrap().keys().next().copied().unwrap_or(self.last_sequence)
+    }
+
+    fn next_number(&mut self) -> u64 {
+        self.next_file += 1;
+        self.next_file - 1
+    }
+}
+
+// An immutable sorted run on disk
+struct Run {
+    number: u64,
+    path: PathBuf,
+    table: Table,
+    old_versions: u64, // versions besides the newest one of each key
+    ingested: Option<u64>, // sequence number of every entry of an ingested run
+}
+
+impl Run {
+    fn overlaps(&self, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> bool {
+        let below = match lo {
+            Bound::Included(lo) => self.table.largest() < lo,
+            Bound::Excluded(lo) => self.table.largest() <= lo,
+            Bound::Unbounded => false,
+        };
+        let above = match hi {
+            Bound::Included(hi) => self.table.smallest() > hi,
+            Bound::Excluded(hi) => self.table.smallest() >= hi,
+            Bound::Unbounded => false,
+        };
+        !self.table.is_empty() && !below && !above
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.overlaps(Bound::Included(key), Bound::Included(key))
+    }
+
+    fn versions(&self, key: &[u8], stats: Option<&Cell<ReadStats>>) -> Result<Option<Versions>> {
+        match self.table.get_traced(key, stats)? {
+            Some(entry) => Ok(Some(self.decode(entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    // The keys from `lo` on with their versions
+    fn source<'a>(&'a self, lo: Bound<&[u8]>, stats: Option<&'a Cell<ReadStats>>) -> Source<'a> {
+        let entries = match lo {
+            Bound::Included(lo) | Bound::Excluded(lo) => self.table.iter_from(lo),
+            Bound::Unbounded => self.table.iter(),
+        };
+        let entries = entries.traced(stats);
+        Box::new(entries.map(move |item| {
+            let (key, entry) = item?;
+            Ok((key, self.decode(entry)?))
+        }))
+    }
+
+    // The keys up to `hi` with their versions, in descending order
+    fn source_rev<'a>(&'a self, hi: Bound<&[u8]>, stats: Option<&'a Cell<ReadStats>>) -> Source<'a> {
+        let entries = match hi {
+            Bound::Included(hi) | Bound::Excluded(hi) => self.table.iter_rev(Some(hi)),
+            Bound::Unbounded => self.table.iter_rev(None),
+        };
+        let entries = entries.traced(stats);
+        Box::new(entries.map(move |item| {
+            let (key, entry) = item?;
+            Ok((key, self.decode(entry)?))
+        }))
+    }
+
+    // An ingested run holds plain entries, the others lists of versions
+    fn decode(&self, entry: Entry) -> Result<Versions> {
+        match self.ingested {
+            Some(sequence) => Ok(vec![(sequence, entry)]),
+            None => decode_versions(entry.as_deref()),
+        }
+    }
+}
+
+/*
+* In a run, the value of a key is the list of its versions:
+*
+*   [sequence: u64][value len: u32, u32::MAX for a tombstone][value] ...
+*/
+fn encode_versions(versions: &Versions) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (sequence, entry) in versions {
+        out.extend_from_slice(&sequence.to_le_bytes());
+        out.extend_from_slice(&entry.as_ref().map_or(u32::MAX, |v| v.len() as u32).to_le_bytes());
+        out.extend_from_slice(entry.as_deref().unwrap_or_default());
+    }
+    out
+}
+
+fn decode_versions(data: Option<&[u8]>) -> Result<Versions> {
+    let corrupt = || Error::Corruption("damaged versions in a run".to_string());
+    let mut data = data.ok_or_else(corrupt)?;
+    let mut versions = Vec::new();
+    while !data.is_empty() {
+        let header = data.get(..12).ok_or_else(corrupt)?;
+        let sequence = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let entry = match u32::from_le_bytes(header[8..].try_into().unwrap()) {
+            u32::MAX => {
+                data = &data[12..];
+                None
+            }
+            len => {
+                let value = data.get(12..12 + len as usize).ok_or_else(corrupt)?;
+                data = &data[12 + len as usize..];
+                Some(value.to_vec())
+            }
+        };
+        versions.push((sequence, entry));
+    }
+    Ok(versions)
+}
+
+// The newest of `versions` at or before `sequence`
+fn visible(versions: &Versions, sequence: u64) -> Option<&Entry> {
+    versions.iter().find(|(s, _)| *s <= sequence).map(|(_, entry)| entry)
+}
+
+// Drop the versions older than the newest one at or before `oldest`, which no snapshot can
+// read, see the top of the file. Returns how many were dropped.
+fn collect_garbage(versions: &mut Versions, oldest: u64) -> u64 {
+    let keep = versions.iter().position(|(sequence, _)| *sequence <= oldest).map_or(versions.len(), |i| i + 1);
+    let dropped = versions.len() - keep;
+    versions.truncate(keep);
+    dropped as u64
+}
+
+fn old_versions(runs: &[Run]) -> Vec<(u64, u64)> {
+    runs.iter().filter(|run| run.old_versions > 0).map(|run| (run.number, run.old_versions)).collect()
+}
+
+// The run of a level >= 1 whose key range holds `key`
+fn find_run<'a>(level: &'a [Run], key: &[u8]) -> Option<&'a Run> {
+    let i = level.partition_point(|run| run.table.largest() < key);
+    level.get(i).filter(|run| run.contains(key))
+}
+
+/*
+* MergeIter combines sources that are each sorted by key into one sorted stream. The heap holds
+* the next entry of every source, ordered by (key, source index), and sources are passed newest
+* first, so when several sources have the same key the newest versions are popped first and
+* the older ones are appended after them. Reversed, the sources are sorted in descending key
+* order and the largest key is popped first, the newest source still first among equal keys.
+*/
+struct MergeIter<'a> {
+    sources: Vec<Source<'a>>,
+    heap: BinaryHeap<Head>,
+    reverse: bool,
+}
+
+// The next entry of source `source`, the greatest Head is popped first
+struct Head {
+    key: Vec<u8>,
+    source: usize,
+    versions: Versions,
+    reverse: bool,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let keys = match self.reverse {
+            false => other.key.cmp(&self.key),
+            true => self.key.cmp(&other.key),
+        };
+        keys.then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<Source<'a>>, reverse: bool) -> Result<Self> {
+        let mut merge = MergeIter { sources, heap: BinaryHeap::new(), reverse };
+        for i in 0..merge.sources.len() {
+            merge.refill(i)?;
+        }
+        Ok(merge)
+    }
+
+    fn refill(&mut self, i: usize) -> Result<()> {
+        if let Some(item) = self.sources[i].next() {
+            let (key, versions) = item?;
+            self.heap.push(Head { key, source: i, versions, reverse: self.reverse });
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<(Vec<u8>, Versions)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Head { key, source, mut versions, .. } = self.heap.pop()?;
+        if let Err(e) = self.refill(source) {
+            return Some(Err(e));
+        }
+        // older versions of the same key
+        while self.heap.peek().is_some_and(|head| head.key == key) {
+            let older = self.heap.pop().unwrap();
+            versions.extend(older.versions);
+            if let Err(e) = self.refill(older.source) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok((key, versions)))
+    }
+}
+
+// Apply every intact record of WAL `number` to `memtable`, returns the last sequence number
+// it holds (0 if none). No snapshot survives a restart, so only the newest version is kept.
+fn replay_wal(data: &[u8], number: u64, memtable: &mut BTree<Vec<u8>, Versions>) -> u64 {
+    let mut pos = 0;
+    let mut last_sequence = 0;
+    while let Some(size) = data.get(pos + RECORD_HEADER..).and_then(sstable::entry_size) {
+        let end = pos + RECORD_HEADER + size;
+        let checksum = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        if end > data.len() || crc32fast::hash(&data[pos + 4..end]) != checksum {
+            break; // torn write at the end of the log
+        }
+        if data[pos + 4..pos + 12] != number.to_le_bytes() {
+            break; // left over from before the WAL was recycled
+        }
+        let sequence = u64::from_le_bytes(data[pos + 12..pos + 20].try_into().unwrap());
+        let (key, entry) = sstable::read_entry(&mut &data[pos + RECORD_HEADER..end]).expect("entry was bounds checked");
+        memtable.upsert(key, vec![(sequence, entry)]);
+        last_sequence = sequence;
+        pos = end;
+    }
+    last_sequence
+}
+
+// Starts new WALs, by recycling old ones or creating files, see the top of the file
+struct WalSegments {
+    recycle: usize,
+    preallocate: Option<u64>,
+    direct_io: bool,
+    pool: Vec<PathBuf>, // obsolete WALs kept for reuse
+    stats: WalStats,
+}
+
+impl WalSegments {
+    fn new(options: &Options) -> Self {
+        WalSegments {
+            recycle: options.wal_recycle,
+            preallocate: options.wal_preallocate.then_some(options.write_buffer_size as u64),
+            direct_io: options.direct_io,
+            pool: Vec::new(),
+            stats: WalStats::default(),
+        }
+    }
+
+    fn create(&mut self, vfs: &dyn Vfs, dir: &Path, number: u64) -> Result<(PathBuf, Box<dyn VfsFile>)> {
+        let path = dir.join(format!("{:06}.wal", number));
+        if let Some(old) = self.pool.pop() {
+            vfs.rename(&old, &path)?;
+            let file = vfs.open(&path, OpenOptions::new().write(true).direct(self.direct_io))?;
+            self.stats.recycled += 1;
+            return Ok((path, file));
+        }
+
+        let options = OpenOptions::new().write(true).create(true).truncate(true).direct(self.direct_io);
+        let mut file = vfs.open(&path, options)?;
+        if let Some(size) = self.preallocate {
+            file.set_len(size)?;
+            file.sync()?;
+        }
+        self.stats.created += 1;
+        Ok((path, file))
+    }
+
+    // Drop a WAL whose content is in a run now
+    fn retire(&mut self, vfs: &dyn Vfs, path: PathBuf) -> Result<()> {
+        if self.pool.len() < self.recycle {
+            self.pool.push(path);
+        } else {
+            vfs.remove(&path)?;
+        }
+        Ok(())
+    }
+}
+
+// Write `entries` into new runs, starting a new one every `target_size` bytes
+fn write_runs(
+    vfs: &dyn Vfs,
+    dir: &Path,
+    cache: Option<&Arc<BlockCache>>,
+    direct_io: bool,
+    next_file: &mut u64,
+    target_size: u64,
+    entries: impl Iterator<Item = Result<(Vec<u8>, Versions)>>,
+) -> Result<Vec<Run>> {
+    let mut runs = Vec::new();
+    let mut current: Option<(u64, TableWriter, u64)> = None;
+    for item in entries {
+        let (key, versions) = item?;
+        let (_, writer, old_versions) = match &mut current {
+            Some(current) => current,
+            None => {
+                let number = *next_file;
+                *next_file += 1;
+                let path = dir.join(run_name(number));
+                let options = OpenOptions::new().write(true).create(true).truncate(true).direct(direct_io);
+                let file = vfs.open(&path, options)?;
+                current.insert((number, TableWriter::new(file), 0))
+            }
+        };
+        writer.add(&key, Some(&encode_versions(&versions)))?;
+        *old_versions += versions.len() as u64 - 1;
+        if writer.size() >= target_size {
+            let (number, writer, old_versions) = current.take().unwrap();
+            writer.finish()?;
+            runs.push(Run { old_versions, ..open_run(vfs, dir, number, cache, direct_io)? });
+        }
+    }
+    if let Some((number, writer, old_versions)) = current {
+        writer.finish()?;
+        runs.push(Run { old_versions, ..open_run(vfs, dir, number, cache, direct_io)? });
+    }
+    Ok(runs)
+}
+
+fn open_run(
+    vfs: &dyn Vfs,
+    dir: &Path,
+    number: u64,
+    cache: Option<&Arc<BlockCache>>,
+    direct_io: bool,
+) -> Result<Run> {
+    let path = dir.join(run_name(number));
+    let mut table = Table::open_with(vfs, &path, direct_io)?;
+    if let Some(cache) = cache {
+        table = table.with_cache(cache.clone());
+    }
+    Ok(Run { number, path, table, old_versions: 0, ingested: None })
+}
+
+fn run_name(number: u64) -> String {
+    format!("{:06}.sst", number)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string()
+}
+
+fn parse_wal_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".wal")?.parse().ok()
+}
+
+fn parse_run_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".sst")?.parse().ok()
+}
@@ -0,0 +1,415 @@
+// src/main.rs
+
+/*
+* ddbb command line tool
+*
+*   ddbb <dir>                  read commands from stdin, one per line (a prompt if it is a terminal)
+*   ddbb <dir> <command...>     run one command and exit
+*   ddbb <dir> serve <address>  serve the database over TCP, see server.rs
+*   ddbb <dir> serve-http <address>
+*                               serve the database over HTTP, see http.rs
+*   ddbb <dir> bench [flags]    run a benchmark on the database, see below
+*
+* on the database in <dir> (created if needed), with String keys and values. The commands are
+* the queries of query.rs, plus a few that only make sense here:
+*
+*   get <key>                   set <key> <value>            del <key>
+*   scan <keys> [limit <n>] [offset <n>] [after <token>] [rev]
+*   count <keys>
+*   compact                     rewrite the log into the snapshot, see log.rs
+*   flush                       the same, if anything was written since the last one
+*   backup <dir>                write a copy of the database to <dir>
+*   stats                       pairs, tombstones, flushes and what the last open recovered
+*   merkle <depth> [<leaf>]     the Merkle tree of the pairs, or the pairs of a leaf of it
+*   help, quit
+*
+* (compact, flush, backup, stats and merkle are the commands of admin.rs, which clients of a server
+* send it too)
+*
+* Both servers take flags after the address:
+*
+*   --tls-cert <file> --tls-key <file>
+*                               accept only TLS connections, with the PEM certificate chain and
+*                               private key in those files (see tls.rs)
+*   --auth <file>               serve only the clients with the credentials in the file, what
+*                               their ACLs allow (see auth.rs for the format)
+*   --follow <address>          be a follower of the server at <address>: replicate its writes
+*                               into <dir> and serve reads only (see replication.rs; a leader
+*                               with TLS or authentication takes the library's FollowerOptions)
+*   --max-lag <n>               with --follow, refuse reads while more than <n> writes behind
+*                               the leader, or not connected to it (see replication.rs)
+*   --raft-id <address> --raft-peers <address,...>
+*                               be the node <address> (the address of this server as the others
+*                               reach it) of a Raft cluster with the nodes at the other
+*                               addresses (see raft.rs; the same caveat as --follow)
+*   --gossip-id <address> [--gossip-seeds <address,...>]
+*                               be the member <address> of a cluster, joining it through the
+*                               members at the seed addresses, and answer CLUSTER with its
+*                               members (see membership.rs; the same caveat as --follow)
+*   --latency-histograms        record the latencies of the operations, which STATS then
+*                               reports (see histogram.rs)
+*
+* and stop on SIGTERM or SIGINT: they finish the requests they are serving, compact the log
+* and exit with 0, or 1 if that failed (see server.rs). A second signal exits at once, with 1,
+* leaving the log to be replayed by the next open.
+*
+* bench loads records into the database and runs a workload on it (see bench.rs), then prints
+* the throughput and the latency percentiles of every kind of operation. It writes keys
+* "user0000000000" and up, so point it at a directory of its own. Its flags:
+*
+*   --workload <a-f>            one of the core workloads of YCSB, a by default
+*   --records <n>               the pairs loaded first, 10000 by default
+*   --operations <n>            the operations of the workload, 10000 by default
+*   --read <percent>            reads and updates only, that many percent reads
+*   --distribution <uniform | zipfian | latest>
+*   --value-size <bytes>        100 by default
+*   --seed <n>
+*   --engine <log | lsm>        a LogManager (log.rs, the default) or an LsmTree (lsm.rs) in <dir>
+*
+* A one-shot command exits with 1 if it failed, and with 2 for a get of a missing key, so
+* scripts can tell "not found" from an empty value. Results go to stdout and everything else
+* (errors, the diagnostics of the library) to stderr.
+*/
+
+use ddbb::admin::{self, Admin};
+use ddbb::auth::Auth;
+use ddbb::bench::{Distribution, Workload};
+use ddbb::error::{Error, Result};
+use ddbb::http::HttpServer;
+use ddbb::log::LogManager;
+use ddbb::lsm::LsmTree;
+use ddbb::membership::MembershipOptions;
+use ddbb::options::Options;
+use ddbb::query::{self, QueryResult};
+use ddbb::raft::RaftOptions;
+use ddbb::replication::FollowerOptions;
+use ddbb::server::{Server, ServerOptions, ShutdownHandle};
+use ddbb::testing::Subject;
+use ddbb::tls::ServerTls;
+use ddbb::vfs::RealFs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+type Db = LogManager<String, String>;
+
+const USAGE: &str = "usage: ddbb <dir> [command]
+       ddbb <dir> serve <address> [flags]
+       ddbb <dir> serve-http <address> [flags]
+       ddbb <dir> bench [flags]
+
+flags of serve and serve-http:
+  --tls-cert <file> --tls-key <file>
+  --auth <file>
+  --follow <address> [--max-lag <n>]
+  --raft-id <address> --raft-peers <address,...>
+  --gossip-id <address> [--gossip-seeds <address,...>]
+  --latency-histograms
+
+flags of bench:
+  --workload <a-f>
+  --records <n>
+  --operations <n>
+  --read <percent>
+  --distribution <uniform | zipfian | latest>
+  --value-size <bytes>
+  --seed <n>
+  --engine <log | lsm>
+
+commands:
+  get <key>
+  set <key> <value>
+  del <key>
+  scan <a..b | prefix*> [limit <n>] [offset <n>] [after <token>] [rev]
+  count <a..b | prefix*>
+  compact
+  flush
+  backup <dir>
+  stats
+  merkle <depth> [<leaf>]
+  help
+  quit (only when reading commands from stdin)";
+
+enum Outcome {
+    Done,
+    NotFound,
+    Quit,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(dir) = args.first() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(1);
+    };
+    if args.get(1).is_some_and(|command| command.eq_ignore_ascii_case("bench")) {
+        return match bench(dir, &args[2..]) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(1)
+            }
+        };
+    }
+    let command = args.get(1).map(|command| command.to_ascii_lowercase());
+    let serving = matches!(command.as_deref(), Some("serve" | "serve-http"));
+    let latency_histograms = serving && args[2..].iter().any(|flag| flag == "--latency-histograms");
+    let options = Options { latency_histograms, ..Options::default() };
+    let mut db: Db = match LogManager::open_with(Arc::new(RealFs), dir, options) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("cannot open {}: {}", dir, e);
+            return ExitCode::from(1);
+        }
+    };
+
+    if let Some(command @ ("serve" | "serve-http")) = command.as_deref() {
+        let [_, _, address, flags @ ..] = &args[..] else {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(1);
+        };
+        let http = command == "serve-http";
+        return match serve_options(flags).and_then(|options| serve(db, address, options, http)) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(1)
+            }
+        };
+    }
+    if args.len() > 1 {
+        return match run(&mut db, &args[1..].join(" ")) {
+            Ok(Outcome::NotFound) => ExitCode::from(2),
+            Ok(_) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(1)
+            }
+        };
+    }
+    repl(&mut db)
+}
+
+// The flags after the address of serve and serve-http
+fn serve_options(flags: &[String]) -> Result<ServerOptions> {
+    let (mut cert, mut key, mut auth, mut follow) = (None, None, None, None);
+    let (mut raft_id, mut raft_peers, mut max_lag) = (None, None, None);
+    let (mut gossip_id, mut gossip_seeds) = (None, None);
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        if flag == "--latency-histograms" {
+            continue; // an Option of the database, see main
+        }
+        let what = match flag.as_str() {
+            "--follow" | "--raft-id" | "--gossip-id" => "address",
+            "--raft-peers" | "--gossip-seeds" => "addresses",
+            "--max-lag" => "number of writes",
+            _ => "file",
+        };
+        let missing = || Error::InvalidArgument(format!("{} is missing its {}", flag, what));
+        let file = flags.next().ok_or_else(missing)?;
+        match flag.as_str() {
+            "--tls-cert" => cert = Some(file),
+            "--tls-key" => key = Some(file),
+            "--auth" => auth = Some(Auth::from_file(file)?),
+            "--follow" => follow = Some(FollowerOptions::new(file)),
+            "--raft-id" => raft_id = Some(file),
+            "--gossip-id" => gossip_id = Some(file),
+            "--max-lag" => {
+                let bad = || Error::InvalidArgument(format!("bad --max-lag {}", file));
+                max_lag = Some(file.parse::<u64>().map_err(|_| bad())?);
+            }
+            "--raft-peers" => {
+                raft_peers = Some(file.split(',').filter(|peer| !peer.is_empty()).collect::<Vec<_>>());
+            }
+            "--gossip-seeds" => {
+                gossip_seeds = Some(file.split(',').filter(|seed| !seed.is_empty()).collect::<Vec<_>>());
+            }
+            _ => return Err(Error::InvalidArgument(format!("unknown flag {}", flag))),
+        }
+    }
+    let tls = match (cert, key) {
+        (Some(cert), Some(key)) => Some(ServerTls::from_pem_files(cert, key)?),
+        (None, None) => None,
+        _ => return Err(Error::InvalidArgument("--tls-cert and --tls-key go together".to_string())),
+    };
+    match (&mut follow, max_lag) {
+        (Some(follow), max_lag) => follow.max_lag = max_lag,
+        (None, Some(_)) => return Err(Error::InvalidArgument("--max-lag goes with --follow".to_string())),
+        (None, None) => {}
+    }
+    let raft = match (raft_id, raft_peers) {
+        (Some(id), Some(peers)) => Some(RaftOptions::new(id, &peers)),
+        (None, None) => None,
+        _ => return Err(Error::InvalidArgument("--raft-id and --raft-peers go together".to_string())),
+    };
+    let membership = match (gossip_id, gossip_seeds) {
+        (Some(id), seeds) => Some(MembershipOptions::new(id, &seeds.unwrap_or_default())),
+        (None, None) => None,
+        (None, Some(_)) => {
+            return Err(Error::InvalidArgument("--gossip-seeds goes with --gossip-id".to_string()));
+        }
+    };
+    Ok(ServerOptions { tls, auth, follow, raft, membership })
+}
+
+// Run the benchmark of the flags on the database in `dir`
+fn bench(dir: &str, flags: &[String]) -> Result<()> {
+    let mut pairs = Vec::new();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let missing = || Error::InvalidArgument(format!("{} is missing its value", flag));
+        let value = flags.next().ok_or_else(missing)?;
+        pairs.push((flag.as_str(), value.as_str()));
+    }
+    // the workload first, the other flags change it
+    let mut workload = match pairs.iter().find(|(flag, _)| *flag == "--workload") {
+        Some((_, name)) => Workload::preset(name)?,
+        None => Workload::default(),
+    };
+    let mut engine = "log";
+    for (flag, value) in pairs {
+        let bad = || Error::InvalidArgument(format!("bad {} {}", flag, value));
+        match flag {
+            "--workload" => {}
+            "--records" => workload.records = value.parse().map_err(|_| bad())?,
+            "--operations" => workload.operations = value.parse().map_err(|_| bad())?,
+            "--value-size" => workload.value_size = value.parse().map_err(|_| bad())?,
+            "--seed" => workload.seed = value.parse().map_err(|_| bad())?,
+            "--read" => {
+                let read = value.parse::<f64>().ok().filter(|read| (0.0..=100.0).contains(read));
+                let read = read.ok_or_else(bad)?;
+                let (read, update) = (read / 100.0, 1.0 - read / 100.0);
+                workload = Workload { read, update, insert: 0.0, scan: 0.0, rmw: 0.0, ..workload };
+            }
+            "--distribution" => {
+                workload.distribution = match value {
+                    "uniform" => Distribution::Uniform,
+                    "zipfian" => Distribution::Zipfian,
+                    "latest" => Distribution::Latest,
+                    _ => return Err(bad()),
+                }
+            }
+            "--engine" if value == "log" || value == "lsm" => engine = value,
+            "--engine" => return Err(bad()),
+            _ => return Err(Error::InvalidArgument(format!("unknown flag {}", flag))),
+        }
+    }
+    let (vfs, options) = (Arc::new(RealFs), Options::default());
+    let report = if engine == "lsm" {
+        workload.run(&mut <LsmTree as Subject>::open(vfs, Path::new(dir), &options)?)?
+    } else {
+        workload.run(&mut <Db as Subject>::open(vfs, Path::new(dir), &options)?)?
+    };
+    print!("{}", report);
+    Ok(())
+}
+
+fn serve(db: Db, address: &str, options: ServerOptions, http: bool) -> Result<()> {
+    let tls = if options.tls.is_some() { " with TLS" } else { "" };
+    if http {
+        let server = HttpServer::bind_with(address, db, options)?;
+        stop_on_signals(server.shutdown_handle())?;
+        eprintln!("Serving HTTP on {}{}", server.local_addr()?, tls);
+        return server.serve();
+    }
+    let server = Server::bind_with(address, db, options)?;
+    stop_on_signals(server.shutdown_handle())?;
+    eprintln!("Serving on {}{}", server.local_addr()?, tls);
+    server.serve()
+}
+
+#[cfg(unix)]
+fn stop_on_signals(shutdown: ShutdownHandle) -> Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    std::thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            eprintln!("Got signal {}, shutting down (again to exit at once)", signal);
+            shutdown.shutdown();
+        }
+        if signals.next().is_some() {
+            eprintln!("Exiting without shutting down");
+            std::process::exit(1);
+        }
+    });
+    Ok(())
+}
+
+// Only Unix signals are handled, elsewhere the process just ends
+#[cfg(not(unix))]
+fn stop_on_signals(_shutdown: ShutdownHandle) -> Result<()> {
+    Ok(())
+}
+
+fn repl(db: &mut Db) -> ExitCode {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            print!("ddbb> ");
+            let _ = io::stdout().flush();
+        }
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                eprintln!("cannot read stdin: {}", e);
+                return ExitCode::from(1);
+            }
+            None => return ExitCode::SUCCESS,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match run(db, &line) {
+            Ok(Outcome::Quit) => return ExitCode::SUCCESS,
+            Ok(_) => {}
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+fn run(db: &mut Db, command: &str) -> Result<Outcome> {
+    match command.trim().to_ascii_lowercase().as_str() {
+        "help" => println!("{}", USAGE),
+        "quit" | "exit" => return Ok(Outcome::Quit),
+        _ => match Admin::parse(command)? {
+            Some(Admin::Stats) => print_stats(db),
+            Some(admin) => return print_result(admin::execute(db, &admin)?),
+            None => return print_result(query::run(db, command)?),
+        },
+    }
+    Ok(Outcome::Done)
+}
+
+fn print_result(result: QueryResult) -> Result<Outcome> {
+    match result {
+        QueryResult::Value(Some(value)) => println!("{}", value),
+        QueryResult::Value(None) => {
+            println!("(not found)");
+            return Ok(Outcome::NotFound);
+        }
+        QueryResult::Done => println!("OK"),
+        QueryResult::Pairs { pairs, next } => {
+            for (key, value) in pairs {
+                println!("{} {}", key, value);
+            }
+            if let Some(next) = next {
+                println!("(more: after {})", next);
+            }
+        }
+        QueryResult::Count(count) => println!("{}", count),
+    }
+    Ok(Outcome::Done)
+}
+
+fn print_stats(db: &Db) {
+    for (name, value) in admin::stats(db) {
+        println!("{}: {}", name.replace('_', " "), value);
+    }
+}
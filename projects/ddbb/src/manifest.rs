@@ -0,0 +1,258 @@
+// src/manifest.rs
+
+/*
+* Manifest
+*
+* The manifest records which files make up the current state of an LsmTree: the runs of every
+* level and the WAL the memtable is being rebuilt from. Every change to that set (a flush, a
+* compaction) is appended to it as a VersionEdit and synced before the old files are removed,
+* so the edit is the single point at which the change happens. Recovery replays the edits to
+* get the last Version instead of guessing from the files it finds in the directory: a run
+* that is not in the manifest is the output of an interrupted flush or compaction and is
+* deleted, however complete it looks.
+*
+* The file holds one edit per line, framed like the records of the LogManager log:
+*
+* <crc32 of the edit, 8 hex digits> <edit>\n
+*
+* where the edit is a list of fields
+*
+* next <n>             the next unused file number
+* wal <n>              WALs numbered below n hold nothing that is not in a run
+* add <level> <n>      run n was added to the level
+* remove <level> <n>   run n was removed from the level
+* seq <n>              the last sequence number handed out to a write in a run
+* versions <n> <c>     run n holds c versions of its keys besides the newest one of each
+* ingested <n> <s>     run n was ingested, all of its entries have sequence number s
+*
+* An incomplete or damaged last line is a torn write from a crash and is ignored (that edit
+* never happened), a damaged line before it means the manifest itself is corrupt.
+*
+* The manifest grows with every flush, so once it is bigger than REWRITE_BYTES it is replaced
+* by a new one holding a single edit that describes the whole current Version, written to a
+* temporary file and renamed over the old one.
+*/
+
+use crate::error::{Error, Result};
+use crate::vfs::{OpenOptions, Vfs, VfsFile};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const TEMP_MANIFEST_FILE: &str = "MANIFEST.tmp";
+const REWRITE_BYTES: u64 = 1 << 20;
+
+/// The set of live files of an LsmTree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Version {
+    /// File numbers of the runs of every level.
+    pub levels: Vec<Vec<u64>>,
+    /// Number of the oldest WAL that still has to be replayed.
+    pub wal: u64,
+    pub next_file: u64,
+    pub last_sequence: u64,
+    /// Older versions kept by each run, by file number, absent when it has none.
+    pub old_versions: BTreeMap<u64, u64>,
+    /// Sequence number of the entries of each ingested run, by file number.
+    pub ingested: BTreeMap<u64, u64>,
+}
+
+/// A change to a Version.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VersionEdit {
+    pub next_file: Option<u64>,
+    pub wal: Option<u64>,
+    /// (level, file number) of new runs.
+    pub added: Vec<(usize, u64)>,
+    /// (level, file number) of runs that are no longer part of the tree.
+    pub removed: Vec<(usize, u64)>,
+    pub last_sequence: Option<u64>,
+    /// (file number, count) of the older versions kept by the added runs.
+    pub old_versions: Vec<(u64, u64)>,
+    /// (file number, sequence number) of the added runs that were ingested.
+    pub ingested: Vec<(u64, u64)>,
+}
+
+impl Version {
+    pub fn apply(&mut self, edit: &VersionEdit) {
+        if let Some(next_file) = edit.next_file {
+            self.next_file = next_file;
+        }
+        if let Some(wal) = edit.wal {
+            self.wal = wal;
+        }
+        if let Some(last_sequence) = edit.last_sequence {
+            self.last_sequence = last_sequence;
+        }
+        for &(level, number) in &edit.removed {
+            if let Some(runs) = self.levels.get_mut(level) {
+                runs.retain(|&n| n != number);
+            }
+            self.old_versions.remove(&number);
+            self.ingested.remove(&number);
+        }
+        for &(level, number) in &edit.added {
+            if self.levels.len() <= level {
+                self.levels.resize(level + 1, Vec::new());
+            }
+            self.levels[level].push(number);
+        }
+        for &(number, count) in &edit.old_versions {
+            self.old_versions.insert(number, count);
+        }
+        for &(number, sequence) in &edit.ingested {
+            self.ingested.insert(number, sequence);
+        }
+    }
+
+    /// Every run of the version, as (level, file number).
+    pub fn runs(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.levels.iter().enumerate().flat_map(|(level, runs)| runs.iter().map(move |&n| (level, n)))
+    }
+}
+
+impl VersionEdit {
+    fn encode(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(next_file) = self.next_file {
+            fields.push(format!("next {}", next_file));
+        }
+        if let Some(wal) = self.wal {
+            fields.push(format!("wal {}", wal));
+        }
+        for (level, number) in &self.added {
+            fields.push(format!("add {} {}", level, number));
+        }
+        for (level, number) in &self.removed {
+            fields.push(format!("remove {} {}", level, number));
+        }
+        if let Some(last_sequence) = self.last_sequence {
+            fields.push(format!("seq {}", last_sequence));
+        }
+        for (number, count) in &self.old_versions {
+            fields.push(format!("versions {} {}", number, count));
+        }
+        for (number, sequence) in &self.ingested {
+            fields.push(format!("ingested {} {}", number, sequence));
+        }
+        fields.join(" ")
+    }
+
+    fn decode(payload: &str) -> Option<VersionEdit> {
+        let mut edit = VersionEdit::default();
+        let mut tokens = payload.split_whitespace();
+        while let Some(field) = tokens.next() {
+            let mut number = || tokens.next()?.parse::<u64>().ok();
+            match field {
+                "next" => edit.next_file = Some(number()?),
+                "wal" => edit.wal = Some(number()?),
+                "add" => edit.added.push((number()? as usize, number()?)),
+                "remove" => edit.removed.push((number()? as usize, number()?)),
+                "seq" => edit.last_sequence = Some(number()?),
+                "versions" => edit.old_versions.push((number()?, number()?)),
+                "ingested" => edit.ingested.push((number()?, number()?)),
+                _ => return None,
+            }
+        }
+        Some(edit)
+    }
+}
+
+pub struct Manifest {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    file: Box<dyn VfsFile>,
+    version: Version,
+    size: u64,
+}
+
+impl Manifest {
+    /// Start a new manifest in `dir` holding `version`, replacing any existing one.
+    pub fn create(vfs: Arc<dyn Vfs>, dir: impl AsRef<Path>, version: Version) -> Result<Manifest> {
+        let dir = dir.as_ref().to_path_buf();
+        let snapshot = VersionEdit {
+            next_file: Some(version.next_file),
+            wal: Some(version.wal),
+            added: version.runs().collect(),
+            removed: Vec::new(),
+            last_sequence: Some(version.last_sequence),
+            old_versions: version.old_versions.iter().map(|(&number, &count)| (number, count)).collect(),
+            ingested: version.ingested.iter().map(|(&number, &sequence)| (number, sequence)).collect(),
+        };
+        let line = frame(&snapshot.encode());
+
+        let temp_path = dir.join(TEMP_MANIFEST_FILE);
+        vfs.write(&temp_path, line.as_bytes())?;
+        vfs.rename(&temp_path, &dir.join(MANIFEST_FILE))?;
+        vfs.sync_dir(&dir)?;
+
+        let file = vfs.open(&dir.join(MANIFEST_FILE), OpenOptions::new().append(true))?;
+        Ok(Manifest { vfs, dir, file, version, size: line.len() as u64 })
+    }
+
+    /// Replay the manifest in `dir`, None if there is none.
+    pub fn open(vfs: Arc<dyn Vfs>, dir: impl AsRef<Path>) -> Result<Option<Manifest>> {
+        let dir = dir.as_ref().to_path_buf();
+        let path = dir.join(MANIFEST_FILE);
+        if !vfs.exists(&path) {
+            return Ok(None);
+        }
+
+        let mut file = vfs.open(&path, OpenOptions::new().read(true).append(true))?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut version = Version::default();
+        let mut valid = 0;
+        let text = String::from_utf8_lossy(&content);
+        let mut lines = text.split_inclusive('\n').peekable();
+        while let Some(line) = lines.next() {
+            match line.strip_suffix('\n').and_then(unframe).and_then(VersionEdit::decode) {
+                Some(edit) => version.apply(&edit),
+                // the last edit was torn by a crash
+                None if lines.peek().is_none() => break,
+                None => {
+                    return Err(Error::Corruption(format!("manifest line {:?} is damaged", line)));
+                }
+            }
+            valid += line.len();
+        }
+        if valid < content.len() {
+            // drop the torn edit so the next one starts on a line of its own
+            file.set_len(valid as u64)?;
+            file.sync()?;
+        }
+
+        Ok(Some(Manifest { vfs, dir, file, version, size: valid as u64 }))
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// Make `edit` durable and apply it to the version.
+    pub fn log_edit(&mut self, edit: &VersionEdit) -> Result<()> {
+        let line = frame(&edit.encode());
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync()?;
+        self.version.apply(edit);
+        self.size += line.len() as u64;
+
+        if self.size > REWRITE_BYTES {
+            *self = Manifest::create(self.vfs.clone(), &self.dir, self.version.clone())?;
+        }
+        Ok(())
+    }
+}
+
+fn frame(payload: &str) -> String {
+    format!("{:08x} {}\n", crc32fast::hash(payload.as_bytes()), payload)
+}
+
+fn unframe(line: &str) -> Option<&str> {
+    let (checksum, payload) = line.split_once(' ')?;
+    let checksum = u32::from_str_radix(checksum, 16).ok()?;
+    (checksum == crc32fast::hash(payload.as_bytes())).then_some(payload)
+}
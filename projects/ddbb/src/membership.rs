@@ -0,0 +1,365 @@
+// src/membership.rs
+
+/*
+* Cluster membership
+*
+* The servers of a cluster are more than the peers one of them was started with: servers join
+* and leave, crash and come back, and whatever spreads data or work over them (the shards of
+* shard.rs, the followers of a Coordinator, see quorum.rs) wants to know which of them there
+* are and which of them are up. A `Membership` finds that out by gossip: every node knows a few
+* others to start with, its seeds, and learns of the rest from whoever it talks to.
+*
+*   ddbb dir serve 0.0.0.0:7001 --gossip-id host1:7001
+*   ddbb dir serve 0.0.0.0:7001 --gossip-id host2:7001 --gossip-seeds host1:7001
+*
+* Heartbeats
+*
+* A node has a heartbeat, a counter it counts up every `interval`, and a generation, the time
+* it started in milliseconds since the epoch, so a node that restarts is newer than everything
+* it said before even though its counter starts over. Every interval it sends what it knows, the
+* generation and heartbeat of every member it thinks is alive (itself included), to one of them
+* picked at random, and gets what that one knows back: both keep the newest of each member, and
+* note when the heartbeat of a member last went up. A heartbeat goes around the cluster in about
+* log n intervals for n nodes.
+*
+* When the heartbeat of a member has not gone up for `suspect_after` it is suspect, and for
+* `dead_after` it is dead, until a newer heartbeat comes. Each node decides that for itself, by
+* its own clock: nothing but the heartbeats travel, and the times are never compared between
+* nodes. A member that is dead is not passed on (the others find out on their own, and one
+* that never heard of it does not need to), but it is kept, and now and then it is what a node
+* talks to, with the seeds, so that the cluster heals when a node or a network comes back. A
+* node that knows no live member talks to those every interval.
+*
+* Messages
+*
+* The nodes talk over the TCP protocol of server.rs, with `MembershipOptions::client` (like the
+* RAFT requests of raft.rs, they need the access of the commands of admin.rs): "GOSSIP
+* <digests>" is answered with "GOSSIP <digests>", both JSON on one line. A server with
+* `ServerOptions::membership` answers "CLUSTER" with a line "<address> <state>" for every
+* member it knows, itself included, by address, the cluster status an operator or a client
+* (`Client::cluster`) can ask any node for. Its STATS have the cluster_ lines of
+* `Membership::stats` too.
+*
+* Membership does not move any data or elect anything, and a member is an address, the one
+* the node was given as its id: the same server reached under two addresses is two members.
+*/
+
+use crate::client::{Client, ClientOptions};
+use crate::error::{Error, Result};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt::{self, Display};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A member of a cluster, see the top of this file.
+#[derive(Clone, Debug)]
+pub struct MembershipOptions {
+    /// Address of the server of this node, as the other nodes reach it.
+    pub id: String,
+    /// Addresses of nodes to join the cluster through, any of its members.
+    pub seeds: Vec<String>,
+    /// How often the node counts its heartbeat up and gossips.
+    pub interval: Duration,
+    /// How long the heartbeat of a member may stand still before it is suspect.
+    pub suspect_after: Duration,
+    /// How long the heartbeat of a member may stand still before it is dead.
+    pub dead_after: Duration,
+    /// How to connect to the other nodes: TLS, credentials, timeouts.
+    pub client: ClientOptions,
+}
+
+impl MembershipOptions {
+    pub fn new(id: &str, seeds: &[&str]) -> Self {
+        MembershipOptions {
+            id: id.to_string(),
+            seeds: seeds.iter().map(|seed| seed.to_string()).collect(),
+            interval: Duration::from_millis(200),
+            suspect_after: Duration::from_secs(2),
+            dead_after: Duration::from_secs(10),
+            // a node that does not answer is left for this round, the next one goes elsewhere
+            client: ClientOptions {
+                retries: 0,
+                connect_timeout: Some(Duration::from_millis(500)),
+                io_timeout: Some(Duration::from_secs(2)),
+                ..ClientOptions::default()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Liveness {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl Display for Liveness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Liveness::Alive => write!(f, "alive"),
+            Liveness::Suspect => write!(f, "suspect"),
+            Liveness::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+/// What a node knows of a member, see `Membership::members`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub address: String,
+    pub liveness: Liveness,
+    pub generation: u64,
+    pub heartbeat: u64,
+    /// How long ago its heartbeat last went up, as far as this node saw.
+    pub silent: Duration,
+}
+
+// The newest a node knows of a member, what the messages carry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Digest {
+    address: String,
+    generation: u64,
+    heartbeat: u64,
+}
+
+// A member other than the node itself
+struct Known {
+    generation: u64,
+    heartbeat: u64,
+    // when its heartbeat last went up
+    seen: Instant,
+}
+
+struct State {
+    generation: u64,
+    heartbeat: u64,
+    members: HashMap<String, Known>,
+    stopped: bool,
+}
+
+/// A node of a cluster, gossiping on a thread of its own until `stop`, see the top of this
+/// file.
+pub struct Membership {
+    options: MembershipOptions,
+    state: Mutex<State>,
+    // for the thread to notice `stop` without waiting out its interval
+    changed: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Membership {
+    /// Join the cluster of `options`, and keep gossiping.
+    pub fn start(options: MembershipOptions) -> Arc<Self> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let state = State {
+            generation: since_epoch.as_millis() as u64,
+            heartbeat: 0,
+            members: HashMap::new(),
+            stopped: false,
+        };
+        let membership = Arc::new(Membership {
+            options,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            thread: Mutex::default(),
+        });
+        let gossiping = membership.clone();
+        *membership.thread.lock().unwrap() = Some(thread::spawn(move || gossiping.gossip()));
+        membership
+    }
+
+    /// Every member the node knows, itself included, by address.
+    pub fn members(&self) -> Vec<Member> {
+        let state = self.state.lock().unwrap();
+        let mut members: Vec<Member> = state
+            .members
+            .iter()
+            .map(|(address, known)| Member {
+                address: address.clone(),
+                liveness: self.liveness(known),
+                generation: known.generation,
+                heartbeat: known.heartbeat,
+                silent: known.seen.elapsed(),
+            })
+            .collect();
+        members.push(Member {
+            address: self.options.id.clone(),
+            liveness: Liveness::Alive,
+            generation: state.generation,
+            heartbeat: state.heartbeat,
+            silent: Duration::ZERO,
+        });
+        members.sort_unstable_by(|a, b| a.address.cmp(&b.address));
+        members
+    }
+
+    /// The addresses of the members that are alive, itself included, by address.
+    pub fn alive(&self) -> Vec<String> {
+        let members = self.members().into_iter();
+        members.filter(|member| member.liveness == Liveness::Alive).map(|member| member.address).collect()
+    }
+
+    /// Stop gossiping. The other nodes find the node dead after their `dead_after`.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// The cluster_ statistics of the STATS of a server.
+    pub(crate) fn stats(&self) -> Vec<(String, String)> {
+        let members = self.members();
+        let count = |liveness| members.iter().filter(|member| member.liveness == liveness).count();
+        [
+            ("cluster_members", members.len()),
+            ("cluster_alive", count(Liveness::Alive)),
+            ("cluster_suspect", count(Liveness::Suspect)),
+            ("cluster_dead", count(Liveness::Dead)),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    /// Answer the digests of a GOSSIP request from another node with its own, see the top of
+    /// this file.
+    pub(crate) fn handle(&self, message: &str) -> Result<String> {
+        let digests: Vec<Digest> = serde_json::from_str(message)
+            .map_err(|e| Error::InvalidArgument(format!("bad gossip: {}", e)))?;
+        let mut state = self.state.lock().unwrap();
+        self.merge(&mut state, digests);
+        to_json(&self.digests(&state))
+    }
+
+    fn liveness(&self, known: &Known) -> Liveness {
+        let silent = known.seen.elapsed();
+        if silent >= self.options.dead_after {
+            Liveness::Dead
+        } else if silent >= self.options.suspect_after {
+            Liveness::Suspect
+        } else {
+            Liveness::Alive
+        }
+    }
+
+    // What the node passes on: itself and the members it does not think are dead
+    fn digests(&self, state: &State) -> Vec<Digest> {
+        let own = Digest {
+            address: self.options.id.clone(),
+            generation: state.generation,
+            heartbeat: state.heartbeat,
+        };
+        let others = state.members.iter().filter(|(_, known)| self.liveness(known) != Liveness::Dead);
+        let others = others.map(|(address, known)| Digest {
+            address: address.clone(),
+            generation: known.generation,
+            heartbeat: known.heartbeat,
+        });
+        std::iter::once(own).chain(others).collect()
+    }
+
+    // Keep the newest of every member
+    fn merge(&self, state: &mut State, digests: Vec<Digest>) {
+        for Digest { address, generation, heartbeat } in digests {
+            if address == self.options.id {
+                continue;
+            }
+            let newer = Known { generation, heartbeat, seen: Instant::now() };
+            match state.members.get_mut(&address) {
+                Some(known) if (generation, heartbeat) <= (known.generation, known.heartbeat) => {}
+                Some(known) => *known = newer,
+                None => {
+                    state.members.insert(address, newer);
+                }
+            }
+        }
+    }
+
+    // The thread of the node: a round of gossip every interval, until stopped
+    fn gossip(&self) {
+        let mut clients: HashMap<String, Client> = HashMap::new();
+        let mut failing: Vec<String> = Vec::new();
+        loop {
+            let (digests, targets) = {
+                let mut state = self.state.lock().unwrap();
+                if state.stopped {
+                    return;
+                }
+                state.heartbeat += 1;
+                (self.digests(&state), self.targets(&state))
+            };
+            for target in targets {
+                match self.send(&mut clients, &target, &digests) {
+                    Ok(reply) => {
+                        failing.retain(|address| *address != target);
+                        self.merge(&mut self.state.lock().unwrap(), reply);
+                    }
+                    Err(e) => {
+                        clients.remove(&target);
+                        if !failing.contains(&target) {
+                            eprintln!("Gossip: cannot reach {}: {}", target, e);
+                            failing.push(target);
+                        }
+                    }
+                }
+            }
+            let state = self.state.lock().unwrap();
+            if !state.stopped {
+                let _ = self.changed.wait_timeout(state, self.options.interval).unwrap();
+            }
+        }
+    }
+
+    // Whom to gossip with this round: a live member at random, and now and then (always, with
+    // none) a dead member or a seed
+    fn targets(&self, state: &State) -> Vec<String> {
+        let mut rng = rand::thread_rng();
+        let mut live = Vec::new();
+        let mut others: Vec<&String> = Vec::new();
+        for (address, known) in &state.members {
+            match self.liveness(known) {
+                Liveness::Dead => others.push(address),
+                _ => live.push(address),
+            }
+        }
+        let seeds = self.options.seeds.iter();
+        others.extend(seeds.filter(|seed| **seed != self.options.id && !live.contains(seed)));
+
+        let mut targets = Vec::new();
+        if let Some(&target) = live.choose(&mut rng) {
+            targets.push(target.clone());
+        }
+        if live.is_empty() || rng.gen_range(0..=live.len()) == 0 {
+            if let Some(&target) = others.choose(&mut rng) {
+                targets.push(target.clone());
+            }
+        }
+        targets
+    }
+
+    fn send(
+        &self,
+        clients: &mut HashMap<String, Client>,
+        target: &str,
+        digests: &[Digest],
+    ) -> Result<Vec<Digest>> {
+        let client = match clients.entry(target.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Client::connect_with(target, self.options.client.clone())?),
+        };
+        let reply = client.gossip(&to_json(digests)?)?;
+        serde_json::from_str(&reply).map_err(|e| Error::Corruption(format!("bad gossip reply: {}", e)))
+    }
+}
+
+fn to_json(digests: &[Digest]) -> Result<String> {
+    serde_json::to_string(digests).map_err(|e| Error::Io(io::Error::other(e)))
+}
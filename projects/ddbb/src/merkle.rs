@@ -0,0 +1,152 @@
+// src/merkle.rs
+
+/*
+* Merkle trees
+*
+* Two replicas of the same keys (see shard.rs) can drift apart without anyone noticing: a
+* write one of them missed without a hint, a restore from an old backup. Comparing them pair
+* by pair means reading all of both, and sending all of one to the other. A `MerkleTree`
+* sums a replica up in a few thousand hashes instead, so that two replicas only compare those,
+* and then only read and send the keys where the hashes differ.
+*
+* The tree splits the 64-bit hashes of the keys (the hashes of the ring of shard.rs) into
+* 2^depth ranges of the same width, its leaves: leaf i holds the keys whose hash starts with
+* the depth bits of i. The hash of a leaf is the XOR of the hashes of its pairs ("<key>
+* <value>", which is unambiguous as keys hold no whitespace), so it does not depend on the
+* order of the pairs, and 0 with none. Every other node hashes its two children, up to the
+* root. The nodes are numbered like a heap: the root is 1, the children of n are 2n and 2n +
+* 1, the leaves 2^depth to 2^(depth + 1) - 1.
+*
+* Two trees of the same depth that have the same hash at a node have the same pairs under it
+* (short of a collision of 64-bit hashes), so `differences` only goes down the nodes that
+* differ and returns the leaves where the pairs do. Since the leaves are ranges of the hashes
+* of the ring, the keys of one leaf have the same replicas, unless the leaf holds a point of
+* the ring: the trees of two replicas differ at the leaves of the keys only one of them
+* should have too, and the caller sorts those out.
+*
+* The servers of server.rs build the tree of their LogManager with the MERKLE command of
+* admin.rs ("MERKLE <depth>", the nodes as pairs "<node> <hash>", the hashes in hex), and give
+* the pairs of a leaf with "MERKLE <depth> <leaf>". Both read all of the store.
+*/
+
+use crate::error::{Error, Result};
+use crate::shard;
+
+/// The deepest tree, 2^MAX_DEPTH leaves.
+pub const MAX_DEPTH: u32 = 20;
+
+/// The hashes of the pairs of a store, see the top of this file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTree {
+    depth: u32,
+    // by node, 0 is not one
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// The tree of `pairs`, with 2^depth leaves.
+    pub fn build<'a>(depth: u32, pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self> {
+        check_depth(depth)?;
+        let mut nodes = vec![0; 2 << depth];
+        for (key, value) in pairs {
+            nodes[(1 << depth) + leaf(key, depth)] ^= shard::hash(&format!("{} {}", key, value));
+        }
+        for node in (1..1 << depth).rev() {
+            nodes[node] = combine(nodes[2 * node], nodes[2 * node + 1]);
+        }
+        Ok(MerkleTree { depth, nodes })
+    }
+
+    /// The tree with these nodes, from 1, as `nodes` gives them.
+    pub fn from_nodes(depth: u32, nodes: &[u64]) -> Result<Self> {
+        check_depth(depth)?;
+        if nodes.len() != (2 << depth) - 1 {
+            let expected = (2 << depth) - 1;
+            let message = format!("a tree of depth {} has {} nodes, not {}", depth, expected, nodes.len());
+            return Err(Error::Corruption(message));
+        }
+        let tree = MerkleTree { depth, nodes: [0].into_iter().chain(nodes.iter().copied()).collect() };
+        let nodes = &tree.nodes;
+        if (1..1 << depth).any(|node| nodes[node] != combine(nodes[2 * node], nodes[2 * node + 1])) {
+            return Err(Error::Corruption("the nodes of the tree do not add up".to_string()));
+        }
+        Ok(tree)
+    }
+
+    /// The tree of the pairs "<node> <hash>" of a MERKLE, see the top of this file.
+    pub fn from_pairs(depth: u32, pairs: &[(String, String)]) -> Result<Self> {
+        let mut nodes = Vec::with_capacity(pairs.len());
+        for (i, (node, hash)) in pairs.iter().enumerate() {
+            match u64::from_str_radix(hash, 16) {
+                Ok(hash) if *node == (i + 1).to_string() => nodes.push(hash),
+                _ => {
+                    return Err(Error::Corruption(format!("bad node of a Merkle tree: {} {}", node, hash)));
+                }
+            }
+        }
+        Self::from_nodes(depth, &nodes)
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn root(&self) -> u64 {
+        self.nodes[1]
+    }
+
+    /// The hashes of the nodes, the root first, see the top of this file.
+    pub fn nodes(&self) -> &[u64] {
+        &self.nodes[1..]
+    }
+
+    /// The leaves where the pairs of the two trees differ, in order.
+    pub fn differences(&self, other: &MerkleTree) -> Result<Vec<usize>> {
+        if self.depth != other.depth {
+            let message = format!("trees of depths {} and {} do not compare", self.depth, other.depth);
+            return Err(Error::InvalidArgument(message));
+        }
+        let mut leaves = Vec::new();
+        let mut differing = vec![1];
+        while let Some(node) = differing.pop() {
+            if self.nodes[node] == other.nodes[node] {
+                continue;
+            }
+            if node >= 1 << self.depth {
+                leaves.push(node - (1 << self.depth));
+            } else {
+                differing.extend([2 * node + 1, 2 * node]);
+            }
+        }
+        Ok(leaves)
+    }
+}
+
+/// The leaf of `key` in a tree of this depth.
+pub fn leaf(key: &str, depth: u32) -> usize {
+    leaf_of_hash(shard::hash(key), depth)
+}
+
+/// The leaf of the keys with this hash in a tree of this depth.
+pub fn leaf_of_hash(hash: u64, depth: u32) -> usize {
+    hash.checked_shr(64 - depth).unwrap_or(0) as usize
+}
+
+/// The first and the last hash of `leaf` in a tree of this depth.
+pub fn leaf_hashes(leaf: usize, depth: u32) -> (u64, u64) {
+    let width = 1u128 << (64 - depth);
+    let first = leaf as u128 * width;
+    (first as u64, (first + width - 1) as u64)
+}
+
+fn combine(left: u64, right: u64) -> u64 {
+    shard::hash(&format!("{:016x}{:016x}", left, right))
+}
+
+fn check_depth(depth: u32) -> Result<()> {
+    if depth > MAX_DEPTH {
+        let message = format!("a Merkle tree is at most {} deep, not {}", MAX_DEPTH, depth);
+        return Err(Error::InvalidArgument(message));
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+// src/options.rs
+
+use crate::cache::BlockCache;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Tunables of the storage engines, passed to `LogManager::open_with` or `LsmTree::open_with`.
+///
+/// All fields are public, so override the ones you care about and take the rest from
+/// `Options::default()`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// How long a DELETE survives compaction as a tombstone before it is garbage collected.
+    ///
+    /// A reader replaying the log from an older position (a lagging replica for example) must
+    /// see the delete, otherwise the key would come back to life. Zero keeps the old behaviour
+    /// of dropping every delete at the first compaction.
+    pub tombstone_retention: Duration,
+    /// Bytes of log records after which the writes kept in memory are flushed to a table (a
+    /// sorted run of an LsmTree, the snapshot of a LogManager), see flush.rs. Bigger buffers
+    /// mean fewer, larger flushes but a longer log replay on open.
+    pub write_buffer_size: usize,
+    /// Number of writes after which they are flushed, None for no limit.
+    pub write_buffer_max_entries: Option<usize>,
+    /// How old the oldest unflushed write may get before the writes are flushed, None for no
+    /// limit. Checked on every write, there is no background flush.
+    pub write_buffer_max_age: Option<Duration>,
+    /// Extend every new WAL of an LsmTree to `write_buffer_size` bytes up front, so appending
+    /// to it does not have to update the size of the file on every sync.
+    pub wal_preallocate: bool,
+    /// Number of old WALs an LsmTree keeps around to be overwritten by new ones instead of
+    /// creating and removing a file for every flush, 0 disables recycling.
+    pub wal_recycle: usize,
+    /// Read and write the WALs and runs of an LsmTree with direct I/O (O_DIRECT, Linux only),
+    /// so their data is cached by the block cache alone instead of also by the OS.
+    pub direct_io: bool,
+    /// Number of level 0 runs of an LsmTree that triggers their compaction into level 1.
+    pub l0_compaction_trigger: usize,
+    /// Compact the levels of an LsmTree that are over their limit after every flush, on the
+    /// writing thread. Turned off, the compactions wait for `LsmTree::compact_pending`, and the
+    /// stall triggers below keep the writes from outrunning them, see flush.rs.
+    pub auto_compaction: bool,
+    /// Number of level 0 runs of an LsmTree from which every write is delayed by
+    /// `slowdown_delay`.
+    pub l0_slowdown_trigger: usize,
+    /// Number of level 0 runs of an LsmTree from which the memtable is not flushed any more, and
+    /// writes are refused once it holds `max_write_buffer_size` bytes. Keep it above
+    /// `l0_compaction_trigger`, or the writes stop for good.
+    pub l0_stop_trigger: usize,
+    /// How long a write waits when the writes are slowed down.
+    pub slowdown_delay: Duration,
+    /// Bytes of WAL records the memtable of an LsmTree may hold while its flush is held back,
+    /// writes fail with `Error::WriteStall` beyond.
+    pub max_write_buffer_size: usize,
+    /// Size limit of level 1 of an LsmTree, in bytes.
+    pub level_base_bytes: u64,
+    /// How much bigger every level of an LsmTree is than the one above it. Bigger multipliers
+    /// mean fewer levels (so fewer runs to check on a read) but more rewriting per compaction.
+    pub level_size_multiplier: u64,
+    /// Size at which compaction starts a new output run.
+    pub target_file_size: u64,
+    /// Bytes of decoded table blocks an LsmTree keeps in memory for reads, 0 disables the cache.
+    pub block_cache_size: usize,
+    /// A cache to share with other trees, used instead of a new one of `block_cache_size`.
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// Bytes of its last writes a LogManager keeps in memory for the followers that replicate
+    /// it (see replication.rs). A follower further behind than that, after a restart say, gets
+    /// all of the database again instead of the writes it missed.
+    pub replication_backlog: usize,
+    /// Time the inserts, searches, deletes, scans and batches of a LogManager or an LsmTree and keep
+    /// histograms of their latencies, see histogram.rs.
+    pub latency_histograms: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            tombstone_retention: Duration::ZERO,
+            write_buffer_size: 4 << 20,
+            write_buffer_max_entries: None,
+            write_buffer_max_age: None,
+            wal_preallocate: false,
+            wal_recycle: 0,
+            direct_io: false,
+            l0_compaction_trigger: 4,
+            auto_compaction: true,
+            l0_slowdown_trigger: 8,
+            l0_stop_trigger: 12,
+            slowdown_delay: Duration::from_millis(1),
+            max_write_buffer_size: 16 << 20,
+            level_base_bytes: 64 << 20,
+            level_size_multiplier: 10,
+            target_file_size: 8 << 20,
+            block_cache_size: 8 << 20,
+            block_cache: None,
+            replication_backlog: 1 << 20,
+            latency_histograms: false,
+        }
+    }
+}
@@ -0,0 +1,417 @@
+// src/pager.rs
+
+/*
+* Pager
+*
+* The pager splits a single database file into fixed size pages and hands them out by number,
+* it knows nothing about what is stored inside a page (see disk_btree.rs for that).
+*
+* File layout:
+*
+* page 0   meta slot A
+* page 1   meta slot B
+* page 2.. data pages
+*
+* The meta page describes the committed state of the file (root page of the tree, number of
+* pages, ...). There are two slots and every commit overwrites the older one, with a sequence
+* number and a checksum. If the machine dies while a meta page is being written, the torn slot
+* fails its checksum and the other slot, which still describes the previous commit, is used.
+*
+* The pager never decides by itself when data pages are safe to overwrite, that is the job of
+* its user. The disk B-tree only writes pages that are unreachable from the committed root, so
+* the file is consistent at every commit.
+*
+* ############################################################################################
+*
+* Free list
+*
+* Pages dropped by the tree (after a merge, a delete or simply because a node was copied on
+* write) are recycled instead of growing the file forever. There are two kinds of free pages:
+*
+* - free:    not used by the committed state, `allocate` can hand them out right away
+* - pending: dropped during the current transaction, but still reachable from the committed
+*            root, so they only become free once the next commit is durable
+*
+* At commit the whole free list is written into a chain of free list pages, whose head is
+* stored in the meta page:
+*
+* FREELIST | next page u32 (0 = end) | count u16 | page ids u32 * count
+*
+* The chain is written into pages that are already free, so publishing the meta page switches
+* to the new list atomically together with the new root. The pages of the previous chain are
+* free after that commit.
+*
+* ############################################################################################
+*
+* Checksums
+*
+* Every data page starts with a 4 byte header, the crc32 of the page number followed by the
+* rest of the page. `read` verifies it, so a page damaged on disk (or written at the wrong
+* offset) is reported as corruption instead of being decoded into wrong results. Users of the
+* pager only see the payload, PAGE_PAYLOAD bytes after the header.
+*/
+
+use crate::error::{Error, Result};
+use crate::explain::{self, ReadStats};
+use crate::vfs::{OpenOptions, Vfs, VfsFile};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const PAGE_SIZE: usize = 4096;
+const PAGE_HEADER: usize = 4; // crc32
+/// Bytes of a page available to the user of the pager.
+pub const PAGE_PAYLOAD: usize = PAGE_SIZE - PAGE_HEADER;
+
+pub type PageId = u32;
+
+const MAGIC: &[u8; 8] = b"DDBBPAGE";
+const VERSION: u32 = 2; // 2: checksummed data pages
+/// Pages 0 and 1 hold the meta slots, data pages start after them.
+pub const META_SLOTS: u32 = 2;
+const META_LEN: usize = 40; // bytes covered by the meta checksum
+
+const FREELIST: u8 = 3; // page kind, 1 and 2 are the nodes of disk_btree.rs
+const FREELIST_HEADER: usize = 7;
+const FREELIST_CAPACITY: usize = (PAGE_PAYLOAD - FREELIST_HEADER) / 4;
+
+/// Pages kept in memory when no capacity is given to `Pager::open`.
+pub const DEFAULT_CACHE_PAGES: usize = 256;
+
+/// The committed state of the file, stored in the meta pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Meta {
+    pub seq: u64,
+    /// Root page of the tree, 0 when the tree is empty (page 0 is never a data page).
+    pub root: PageId,
+    pub page_count: u32,
+    /// First page of the free list chain, 0 when there are no free pages.
+    pub free_head: PageId,
+    pub free_count: u32,
+}
+
+/// Page usage of the database file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileStats {
+    pub page_size: usize,
+    /// Every page in the file, including the meta pages.
+    pub total_pages: u32,
+    /// Pages holding live data.
+    pub used_pages: u32,
+    /// Pages that can be reused, or will be after the next commit.
+    pub free_pages: u32,
+    /// Pages storing the free list itself.
+    pub freelist_pages: u32,
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        let mut page = vec![0; PAGE_SIZE];
+        page[0..8].copy_from_slice(MAGIC);
+        page[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        page[12..16].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        page[16..24].copy_from_slice(&self.seq.to_le_bytes());
+        page[24..28].copy_from_slice(&self.root.to_le_bytes());
+        page[28..32].copy_from_slice(&self.page_count.to_le_bytes());
+        page[32..36].copy_from_slice(&self.free_head.to_le_bytes());
+        page[36..40].copy_from_slice(&self.free_count.to_le_bytes());
+        let checksum = crc32fast::hash(&page[..META_LEN]);
+        page[META_LEN..META_LEN + 4].copy_from_slice(&checksum.to_le_bytes());
+        page
+    }
+
+    // None if the slot is torn or was never written
+    fn decode(page: &[u8]) -> Option<Meta> {
+        let checksum = u32::from_le_bytes(page[META_LEN..META_LEN + 4].try_into().unwrap());
+        if &page[0..8] != MAGIC || checksum != crc32fast::hash(&page[..META_LEN]) {
+            return None;
+        }
+        let version = u32::from_le_bytes(page[8..12].try_into().unwrap());
+        let page_size = u32::from_le_bytes(page[12..16].try_into().unwrap());
+        if version != VERSION || page_size as usize != PAGE_SIZE {
+            return None;
+        }
+        Some(Meta {
+            seq: u64::from_le_bytes(page[16..24].try_into().unwrap()),
+            root: u32::from_le_bytes(page[24..28].try_into().unwrap()),
+            page_count: u32::from_le_bytes(page[28..32].try_into().unwrap()),
+            free_head: u32::from_le_bytes(page[32..36].try_into().unwrap()),
+            free_count: u32::from_le_bytes(page[36..40].try_into().unwrap()),
+        })
+    }
+}
+
+pub struct Pager {
+    file: Box<dyn VfsFile>,
+    meta: Meta,       // last committed meta
+    page_count: u32,  // including pages allocated since the last commit
+    free: Vec<PageId>, // reusable now, sorted so the lowest page is handed out first
+    pending: Vec<PageId>, // reusable after the next commit
+    freelist_pages: Vec<PageId>, // chain holding the committed free list
+    committed_free: Vec<PageId>, // `free` as of the last commit, restored by rollback
+    cache: HashMap<PageId, Vec<u8>>,
+    cache_order: VecDeque<PageId>, // insertion order, the oldest page is evicted first
+    cache_capacity: usize,
+}
+
+impl Pager {
+    /// Open the paged file at `path`, creating it if it does not exist.
+    pub fn open(vfs: &dyn Vfs, path: &Path, cache_capacity: usize) -> Result<Pager> {
+        let mut file = vfs.open(path, OpenOptions::new().read(true).write(true).create(true))?;
+
+        let meta = if file.len()? == 0 {
+            // brand new file: one valid meta slot describing an empty tree
+            let meta = Meta { seq: 1, root: 0, page_count: META_SLOTS, free_head: 0, free_count: 0 };
+            file.write_all(&meta.encode())?;
+            file.write_all(&vec![0; PAGE_SIZE])?;
+            file.sync()?;
+            if let Some(dir) = path.parent() {
+                vfs.sync_dir(dir)?;
+            }
+            meta
+        } else {
+            let mut metas = Vec::new();
+            for slot in 0..META_SLOTS {
+                let mut page = vec![0; PAGE_SIZE];
+                file.seek(SeekFrom::Start(slot as u64 * PAGE_SIZE as u64))?;
+                if file.read_exact(&mut page).is_ok() {
+                    metas.extend(Meta::decode(&page));
+                }
+            }
+            metas
+                .into_iter()
+                .max_by_key(|meta| meta.seq)
+                .ok_or_else(|| Error::Corruption(format!("{}: no valid meta page", path.display())))?
+        };
+
+        let mut pager = Pager {
+            file,
+            meta,
+            page_count: meta.page_count,
+            free: Vec::new(),
+            pending: Vec::new(),
+            freelist_pages: Vec::new(),
+            committed_free: Vec::new(),
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            cache_capacity: cache_capacity.max(1),
+        };
+        pager.load_free_list()?;
+        Ok(pager)
+    }
+
+    fn load_free_list(&mut self) -> Result<()> {
+        let mut next = self.meta.free_head;
+        while next != 0 {
+            if self.freelist_pages.contains(&next) {
+                return Err(Error::Corruption(format!("free list loops at page {}", next)));
+            }
+            let page = self.read(next)?;
+            if page[0] != FREELIST {
+                return Err(Error::Corruption(format!("page {} is not a free list page", next)));
+            }
+            self.freelist_pages.push(next);
+            next = u32::from_le_bytes(page[1..5].try_into().unwrap());
+            let count = u16::from_le_bytes(page[5..7].try_into().unwrap()) as usize;
+            for i in 0..count.min(FREELIST_CAPACITY) {
+                let at = FREELIST_HEADER + i * 4;
+                self.free.push(u32::from_le_bytes(page[at..at + 4].try_into().unwrap()));
+            }
+        }
+        if self.free.len() != self.meta.free_count as usize {
+            return Err(Error::Corruption(format!(
+                "free list holds {} pages, the meta page expects {}",
+                self.free.len(),
+                self.meta.free_count
+            )));
+        }
+        self.free.sort_unstable_by(|a, b| b.cmp(a));
+        self.committed_free = self.free.clone();
+        Ok(())
+    }
+
+    pub fn meta(&self) -> Meta {
+        self.meta
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// Hand out a page that is not used by the committed state, a free one if possible,
+    /// otherwise a new one at the end of the file.
+    pub fn allocate(&mut self) -> PageId {
+        if let Some(id) = self.free.pop() {
+            return id;
+        }
+        let id = self.page_count;
+        self.page_count += 1;
+        id
+    }
+
+    /// Give back a page allocated since the last commit, it can be handed out again right away.
+    pub fn release(&mut self, id: PageId) {
+        let at = self.free.partition_point(|&free| free > id);
+        self.free.insert(at, id);
+    }
+
+    /// Give back a page of the committed state, it becomes reusable after the next commit.
+    pub fn free(&mut self, id: PageId) {
+        self.pending.push(id);
+    }
+
+    pub fn file_stats(&self) -> FileStats {
+        let free_pages = (self.free.len() + self.pending.len()) as u32;
+        let freelist_pages = self.freelist_pages.len() as u32;
+        FileStats {
+            page_size: PAGE_SIZE,
+            total_pages: self.page_count,
+            used_pages: self.page_count - META_SLOTS - free_pages - freelist_pages,
+            free_pages,
+            freelist_pages,
+        }
+    }
+
+    /// The payload of page `id`, from the cache or verified against its checksum.
+    pub fn read(&mut self, id: PageId) -> Result<Vec<u8>> {
+        self.read_traced(id, None)
+    }
+
+    // Same as read, recording it in `stats`, see explain.rs
+    pub(crate) fn read_traced(&mut self, id: PageId, stats: Option<&Cell<ReadStats>>) -> Result<Vec<u8>> {
+        if let Some(payload) = self.cache.get(&id) {
+            explain::record_block(stats, true, PAGE_SIZE);
+            return Ok(payload.clone());
+        }
+        explain::record_block(stats, false, PAGE_SIZE);
+        let payload = self.read_from_disk(id)?;
+        self.cache_insert(id, payload.clone());
+        Ok(payload)
+    }
+
+    /// Read page `id` from the file bypassing the cache, failing if its checksum is wrong.
+    pub fn read_from_disk(&mut self, id: PageId) -> Result<Vec<u8>> {
+        if id < META_SLOTS || id >= self.page_count {
+            return Err(Error::Corruption(format!("page {} is out of bounds", id)));
+        }
+        let mut page = vec![0; PAGE_SIZE];
+        self.file.seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))?;
+        self.file.read_exact(&mut page)?;
+
+        let stored = u32::from_le_bytes(page[..PAGE_HEADER].try_into().unwrap());
+        let payload = page.split_off(PAGE_HEADER);
+        if stored != page_checksum(id, &payload) {
+            return Err(Error::Corruption(format!("page {} fails its checksum", id)));
+        }
+        Ok(payload)
+    }
+
+    pub fn write(&mut self, id: PageId, mut payload: Vec<u8>) -> Result<()> {
+        assert!(id >= META_SLOTS && id < self.page_count, "page {} was not allocated", id);
+        assert!(payload.len() <= PAGE_PAYLOAD, "page {} overflows", id);
+        payload.resize(PAGE_PAYLOAD, 0);
+
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.extend_from_slice(&page_checksum(id, &payload).to_le_bytes());
+        page.extend_from_slice(&payload);
+        self.file.seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))?;
+        self.file.write_all(&page)?;
+        self.cache_insert(id, payload);
+        Ok(())
+    }
+
+    /// Pages that are free now or after the next commit.
+    pub fn free_pages(&self) -> Vec<PageId> {
+        self.free.iter().chain(&self.pending).copied().collect()
+    }
+
+    /// Pages holding the committed free list.
+    pub fn freelist_pages(&self) -> &[PageId] {
+        &self.freelist_pages
+    }
+
+    /// Make every page written so far durable, then atomically publish `root` as the new root
+    /// together with the new free list.
+    pub fn commit(&mut self, root: PageId) -> Result<()> {
+        // after this commit the pending pages and the old chain are free as well
+        let mut free = self.free.clone();
+        free.extend_from_slice(&self.pending);
+        free.extend_from_slice(&self.freelist_pages);
+        free.sort_unstable_by(|a, b| b.cmp(a));
+
+        // store the list in pages that are free right now, taking them out of the list
+        let mut chain = Vec::new();
+        let mut reusable = self.free.len();
+        while chain.len() * FREELIST_CAPACITY < free.len() {
+            if reusable > 0 {
+                reusable -= 1;
+                let id = self.free[reusable];
+                free.retain(|&free_id| free_id != id);
+                chain.push(id);
+            } else {
+                chain.push(self.page_count);
+                self.page_count += 1;
+            }
+        }
+        for (i, ids) in free.chunks(FREELIST_CAPACITY).enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(0);
+            let mut page = Vec::with_capacity(PAGE_PAYLOAD);
+            page.push(FREELIST);
+            page.extend_from_slice(&next.to_le_bytes());
+            page.extend_from_slice(&(ids.len() as u16).to_le_bytes());
+            for id in ids {
+                page.extend_from_slice(&id.to_le_bytes());
+            }
+            self.write(chain[i], page)?;
+        }
+
+        self.file.sync()?;
+
+        let meta = Meta {
+            seq: self.meta.seq + 1,
+            root,
+            page_count: self.page_count,
+            free_head: chain.first().copied().unwrap_or(0),
+            free_count: free.len() as u32,
+        };
+        let slot = meta.seq % META_SLOTS as u64;
+        self.file.seek(SeekFrom::Start(slot * PAGE_SIZE as u64))?;
+        self.file.write_all(&meta.encode())?;
+        self.file.sync()?;
+
+        self.meta = meta;
+        self.free = free;
+        self.pending.clear();
+        self.freelist_pages = chain;
+        self.committed_free = self.free.clone();
+        Ok(())
+    }
+
+    /// Forget the pages allocated and freed since the last commit.
+    pub fn rollback(&mut self) {
+        self.page_count = self.meta.page_count;
+        self.free = self.committed_free.clone();
+        self.pending.clear();
+        self.cache.retain(|&id, _| id < self.page_count);
+        self.cache_order.retain(|&id| id < self.page_count);
+    }
+
+    fn cache_insert(&mut self, id: PageId, page: Vec<u8>) {
+        if self.cache.insert(id, page).is_none() {
+            self.cache_order.push_back(id);
+        }
+        while self.cache.len() > self.cache_capacity {
+            if let Some(oldest) = self.cache_order.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+    }
+}
+
+fn page_checksum(id: PageId, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&id.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
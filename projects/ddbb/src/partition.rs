@@ -0,0 +1,245 @@
+// src/partition.rs
+
+/*
+* Partitions
+*
+* A LogManager takes `&mut self` for its writes, so the threads that share one (behind a
+* Mutex, like the servers of server.rs) write one at a time, and a machine with many cores logs
+* with one of them. A `PartitionedLog` splits the keyspace of one database over N LogManagers
+* instead, its partitions, each with its own tree, log and snapshot in a directory of its own
+* and behind a mutex of its own. Its methods take `&self`: the threads share it (in an Arc) as
+* a single handle, and the writes of keys in different partitions go on in parallel.
+*
+* The partition of a key is the hash of its text form (the hash of the ring of shard.rs)
+* modulo N. N is fixed when the database is created, in the PARTITIONS file, and opening it
+* with another number fails, the keys would not be where their hash says:
+*
+*   <dir>/PARTITIONS          the number of partitions
+*   <dir>/0/, <dir>/1/, ...   the LogManager of every partition
+*   <dir>/decisions/          the batches across partitions being committed, see below
+*
+* `search` reads the partition of its key. `range` and `count_range` lock every partition, in
+* order, and merge what they hold, so they see every partition as of the same point: none of
+* the writes they see is only done in some of the partitions. Indexes, watches and the rest of
+* the LogManager API are not offered, as every partition would have its own.
+*
+* Batches across partitions
+*
+* A WriteBatch (see batch.rs) whose keys are all in one partition is a batch of that
+* partition. Across partitions it is a two-phase commit, like a batch across the shards of
+* shard.rs. The partitions it writes are locked, in order so that two batches cannot each wait
+* for the other, and every one of them prepares its writes under the id of the batch (see
+* `LogManager::prepare`). Then the decision to commit is logged in the decisions LogManager
+* and synced, and every partition commits and syncs its commit before the decision is deleted,
+* as without the decision nothing could finish the batch after a crash. A batch that fails to
+* prepare somewhere is aborted everywhere.
+*
+* Opening the database finishes the batches a crash cut short: the prepared batches with a
+* decision are committed, the others aborted, as they were not prepared everywhere yet. A
+* partition that fails to commit leaves its part of the batch to the next open too.
+*/
+
+use crate::batch::{BatchOp, WriteBatch};
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use crate::options::Options;
+use crate::shard;
+use crate::vfs::{Vfs, VfsLock};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::mem;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const LOCK_FILE: &str = "LOCK";
+const PARTITIONS_FILE: &str = "PARTITIONS";
+const DECISIONS_DIR: &str = "decisions";
+
+/// One database over several LogManagers written in parallel, see the top of this file.
+pub struct PartitionedLog<K, V>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    partitions: Vec<Mutex<LogManager<K, V>>>,
+    decisions: Mutex<LogManager<String, String>>,
+    _lock: Mutex<Box<dyn VfsLock>>, // in a Mutex to be Sync, it is never locked
+}
+
+impl<K, V> PartitionedLog<K, V>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    /// Open (or create) the database stored in `dir`, split in `partitions`.
+    pub fn open(vfs: Arc<dyn Vfs>, dir: impl AsRef<Path>, partitions: usize) -> Result<Self> {
+        Self::open_with(vfs, dir, partitions, Options::default())
+    }
+
+    /// Same as `open`, with non-default `Options` for every partition.
+    pub fn open_with(
+        vfs: Arc<dyn Vfs>,
+        dir: impl AsRef<Path>,
+        partitions: usize,
+        options: Options,
+    ) -> Result<Self> {
+        if partitions == 0 {
+            return Err(Error::InvalidArgument("a database has at least one partition".to_string()));
+        }
+        let dir = dir.as_ref();
+        vfs.create_dir_all(dir)?;
+        let lock = vfs.lock(&dir.join(LOCK_FILE))?;
+        let path = dir.join(PARTITIONS_FILE);
+        if vfs.exists(&path) {
+            let text = String::from_utf8(vfs.read(&path)?).unwrap_or_default();
+            let created: Option<usize> = text.trim().parse().ok();
+            match created {
+                Some(created) if created == partitions => {}
+                Some(created) => {
+                    let message = format!("{} has {} partitions, not {}", dir.display(), created, partitions);
+                    return Err(Error::InvalidArgument(message));
+                }
+                None => return Err(Error::Corruption(format!("bad {}", path.display()))),
+            }
+        } else {
+            vfs.write(&path, format!("{}\n", partitions).as_bytes())?;
+            vfs.sync_dir(dir)?;
+        }
+
+        let open = |i: usize| LogManager::open_with(vfs.clone(), dir.join(i.to_string()), options.clone());
+        let partitions = (0..partitions).map(|i| open(i).map(Mutex::new)).collect::<Result<Vec<_>>>()?;
+        let decisions = Mutex::new(LogManager::open(vfs.clone(), dir.join(DECISIONS_DIR))?);
+        let log = PartitionedLog { partitions, decisions, _lock: Mutex::new(lock) };
+        log.resolve()?;
+        Ok(log)
+    }
+
+    /// The number of partitions.
+    pub fn partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// The partition of `key`.
+    pub fn partition(&self, key: &K) -> usize {
+        (shard::hash(&key.to_string()) % self.partitions.len() as u64) as usize
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Result<()> {
+        self.lock(&key).insert(key, value)
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.lock(key).delete(key)
+    }
+
+    pub fn search(&self, key: &K) -> Option<V> {
+        self.lock(key).search(key)
+    }
+
+    /// The pairs of every partition whose key is in `range`, in order.
+    pub fn range<R: RangeBounds<K> + Clone>(&self, range: R) -> Vec<(K, V)> {
+        let partitions = self.lock_all();
+        let mut pairs: Vec<(K, V)> =
+            partitions.iter().flat_map(|partition| partition.range(range.clone())).collect();
+        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        pairs
+    }
+
+    pub fn count_range<R: RangeBounds<K> + Clone>(&self, range: R) -> usize {
+        self.lock_all().iter().map(|partition| partition.count_range(range.clone())).sum()
+    }
+
+    /// Apply every write of `batch`, or none of them, see the top of this file.
+    pub fn write_batch(&self, batch: WriteBatch<K, V>) -> Result<()> {
+        let mut writes: BTreeMap<usize, WriteBatch<K, V>> = BTreeMap::new();
+        for op in batch.ops() {
+            let writes = writes.entry(self.partition(op.key())).or_default();
+            match op {
+                BatchOp::Insert(key, value) => writes.insert(key.clone(), value.clone()),
+                BatchOp::Delete(key) => writes.delete(key.clone()),
+            };
+        }
+        // in the order of the partitions, see the top of this file
+        let mut locked: Vec<_> =
+            writes.into_iter().map(|(i, writes)| (self.partitions[i].lock().unwrap(), writes)).collect();
+        if locked.len() <= 1 {
+            return match locked.pop() {
+                Some((mut partition, writes)) => partition.write_batch(writes),
+                None => Ok(()),
+            };
+        }
+
+        let id = format!("{:016x}", rand::random::<u64>());
+        let mut prepared = 0;
+        let mut failure = None;
+        for (partition, writes) in &mut locked {
+            match partition.prepare(&id, mem::take(writes)) {
+                Ok(()) => prepared += 1,
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        if let Some(e) = failure {
+            for (partition, _) in &mut locked[..prepared] {
+                partition.abort_prepared(&id)?;
+            }
+            return Err(e);
+        }
+        {
+            let mut decisions = self.decisions.lock().unwrap();
+            decisions.insert(id.clone(), "commit".to_string())?;
+            decisions.sync()?;
+        }
+        for (partition, _) in &mut locked {
+            partition.commit_prepared_durable(&id)?;
+        }
+        self.decisions.lock().unwrap().delete(&id)
+    }
+
+    /// Flush every partition, see `LogManager::flush`.
+    pub fn flush(&self) -> Result<()> {
+        self.partitions.iter().try_for_each(|partition| partition.lock().unwrap().flush())
+    }
+
+    /// Compact every partition, see `LogManager::compact`.
+    pub fn compact(&self) -> Result<()> {
+        self.partitions.iter().try_for_each(|partition| partition.lock().unwrap().compact())
+    }
+
+    fn lock(&self, key: &K) -> MutexGuard<'_, LogManager<K, V>> {
+        self.partitions[self.partition(key)].lock().unwrap()
+    }
+
+    fn lock_all(&self) -> Vec<MutexGuard<'_, LogManager<K, V>>> {
+        self.partitions.iter().map(|partition| partition.lock().unwrap()).collect()
+    }
+
+    // Commit the prepared batches that have a decision and abort the others, see the top of
+    // this file
+    fn resolve(&self) -> Result<()> {
+        let mut decisions = self.decisions.lock().unwrap();
+        let committed: HashSet<String> = decisions.range(..).into_iter().map(|(id, _)| id).collect();
+        for partition in &self.partitions {
+            let mut partition = partition.lock().unwrap();
+            for (id, _) in partition.prepared() {
+                if committed.contains(&id) {
+                    partition.commit_prepared_durable(&id)?;
+                } else {
+                    partition.abort_prepared(&id)?;
+                }
+            }
+        }
+        for id in &committed {
+            decisions.delete(id)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+// src/query.rs
+
+/*
+* Query language
+*
+* A small text language over a LogManager, so the command line tool and the network server
+* share one way of reading a command and one shape of answer:
+*
+*   GET <key>                       the value of a key                  -> Value
+*   SET <key> <value>               insert or overwrite a pair          -> Done
+*   DEL <key>                       delete a pair                       -> Done
+*   SCAN <keys> [LIMIT <n>] [OFFSET <n>] [AFTER <token>] [REV]
+*                                   the pairs in key order              -> Pairs
+*                                   (descending with REV)
+*   COUNT <keys>                    how many pairs there are            -> Count
+*
+* where <keys> is either a range or a prefix:
+*
+*   a..b    keys from a (included) to b (excluded), either side may be left out: a.. ..b ..
+*   abc*    keys starting with abc, a lone * is every key
+*
+* A SCAN with a LIMIT is paginated like `LogManager::scan` (see scan.rs): when the limit cut
+* it short, the result has a token, and the same SCAN with AFTER <token> returns the next page.
+*
+* Keywords are case insensitive, keys and values are not. Tokens are separated by whitespace,
+* so like in the log (see log.rs) keys and values cannot contain any.
+*
+* `parse` only checks the shape of a command and keeps keys and values as text, `execute`
+* turns them into the key and value types of the LogManager, so a type that does not parse is
+* an `InvalidArgument` from `execute`. A prefix matches the keys as they are printed (their
+* `Display`), for String keys that is the key itself.
+* `execute_batch` runs SETs and DELs together as a `WriteBatch` (see batch.rs), which is what
+* the MULTI ... EXEC of server.rs does.
+*
+* A `Query` prints as the command it was parsed from (in a canonical form: upper case keywords,
+* single spaces), which is how the client of client.rs sends it.
+*/
+
+use crate::batch::WriteBatch;
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use crate::scan::ScanOptions;
+use std::fmt::{self, Debug, Display};
+use std::ops::Bound;
+use std::str::FromStr;
+
+/// A parsed command, see the top of this file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    Get(String),
+    Set(String, String),
+    Del(String),
+    Scan { keys: Keys, options: ScanOptions, reverse: bool },
+    Count(Keys),
+}
+
+/// The keys a SCAN or a COUNT looks at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Keys {
+    /// From `start` (included) to `end` (excluded), None is unbounded.
+    Range { start: Option<String>, end: Option<String> },
+    Prefix(String),
+}
+
+/// The answer to a query, keys and values printed with their `Display`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryResult {
+    /// GET, None if the key is not there.
+    Value(Option<String>),
+    /// SET and DEL.
+    Done,
+    /// SCAN, `next` is the token of the next page if there is one.
+    Pairs { pairs: Vec<(String, String)>, next: Option<String> },
+    /// COUNT
+    Count(usize),
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::Get(key) => write!(f, "GET {}", key),
+            Query::Set(key, value) => write!(f, "SET {} {}", key, value),
+            Query::Del(key) => write!(f, "DEL {}", key),
+            Query::Scan { keys, options, reverse } => {
+                write!(f, "SCAN {}", keys)?;
+                if let Some(limit) = options.limit {
+                    write!(f, " LIMIT {}", limit)?;
+                }
+                if options.offset > 0 {
+                    write!(f, " OFFSET {}", options.offset)?;
+                }
+                if let Some(resume) = &options.resume {
+                    write!(f, " AFTER {}", resume)?;
+                }
+                if *reverse {
+                    write!(f, " REV")?;
+                }
+                Ok(())
+            }
+            Query::Count(keys) => write!(f, "COUNT {}", keys),
+        }
+    }
+}
+
+impl fmt::Display for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Keys::Range { start, end } => {
+                let (start, end) = (start.as_deref().unwrap_or_default(), end.as_deref().unwrap_or_default());
+                write!(f, "{}..{}", start, end)
+            }
+            Keys::Prefix(prefix) => write!(f, "{}*", prefix),
+        }
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidArgument(message)
+}
+
+pub fn parse(input: &str) -> Result<Query> {
+    let mut tokens = input.split_whitespace();
+    let command = tokens.next().ok_or_else(|| invalid("empty query".to_string()))?;
+    let mut operand = |what: &str| {
+        tokens.next().map(str::to_string).ok_or_else(|| invalid(format!("{} is missing its {}", command, what)))
+    };
+    let query = match command.to_ascii_uppercase().as_str() {
+        "GET" => Query::Get(operand("key")?),
+        "SET" => Query::Set(operand("key")?, operand("value")?),
+        "DEL" => Query::Del(operand("key")?),
+        "SCAN" => {
+            let keys = parse_keys(&operand("keys")?)?;
+            let mut options = ScanOptions::default();
+            let mut reverse = false;
+            let mut seen = Vec::new();
+            while let Some(word) = tokens.next() {
+                let clause = word.to_ascii_uppercase();
+                if seen.contains(&clause) {
+                    return Err(invalid(format!("{} given twice", clause)));
+                }
+                seen.push(clause.clone());
+                if clause == "REV" {
+                    reverse = true;
+                    continue;
+                }
+                let argument = tokens.next().ok_or_else(|| invalid(format!("{} is missing its argument", clause)))?;
+                let number = || argument.parse().map_err(|_| invalid(format!("bad {} {}", clause, argument)));
+                match clause.as_str() {
+                    "LIMIT" => options.limit = Some(number()?),
+                    "OFFSET" => options.offset = number()?,
+                    "AFTER" => options.resume = Some(argument.to_string()),
+                    _ => return Err(invalid(format!("expected LIMIT, OFFSET, AFTER or REV, got {}", word))),
+                }
+            }
+            Query::Scan { keys, options, reverse }
+        }
+        "COUNT" => Query::Count(parse_keys(&operand("keys")?)?),
+        _ => return Err(invalid(format!("unknown command {}", command))),
+    };
+    match tokens.next() {
+        Some(extra) => Err(invalid(format!("unexpected {} after the {} command", extra, command))),
+        None => Ok(query),
+    }
+}
+
+fn parse_keys(token: &str) -> Result<Keys> {
+    if let Some(prefix) = token.strip_suffix('*') {
+        return Ok(Keys::Prefix(prefix.to_string()));
+    }
+    let (start, end) = token
+        .split_once("..")
+        .ok_or_else(|| invalid(format!("{} is neither a range a..b nor a prefix abc*", token)))?;
+    let bound = |key: &str| (!key.is_empty()).then(|| key.to_string());
+    Ok(Keys::Range { start: bound(start), end: bound(end) })
+}
+
+fn parse_as<T: FromStr>(text: &str) -> Result<T>
+where
+    <T as FromStr>::Err: Debug,
+{
+    text.parse().map_err(|e| invalid(format!("cannot parse {}: {:?}", text, e)))
+}
+
+/// Run `query` against `db`.
+pub fn execute<K, V>(db: &mut LogManager<K, V>, query: &Query) -> Result<QueryResult>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    match query {
+        Query::Get(key) => Ok(QueryResult::Value(db.search(&parse_as(key)?).map(|value| value.to_string()))),
+        Query::Set(key, value) => {
+            db.insert(parse_as(key)?, parse_as(value)?)?;
+            Ok(QueryResult::Done)
+        }
+        Query::Del(key) => {
+            db.delete(&parse_as(key)?)?;
+            Ok(QueryResult::Done)
+        }
+        Query::Scan { keys, options, reverse } => {
+            let page = match keys {
+                Keys::Range { start, end } => {
+                    db.scan_filtered(bounds::<K>(start, end)?, options, *reverse, |_| true)?
+                }
+                Keys::Prefix(prefix) => db.scan_filtered(.., options, *reverse, |key| matches_prefix(key, prefix))?,
+            };
+            let pairs = page.pairs.into_iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            Ok(QueryResult::Pairs { pairs, next: page.next })
+        }
+        Query::Count(Keys::Range { start, end }) => Ok(QueryResult::Count(db.count_range(bounds::<K>(start, end)?))),
+        Query::Count(Keys::Prefix(prefix)) => {
+            Ok(QueryResult::Count(db.count_filtered(.., |key| matches_prefix(key, prefix))))
+        }
+    }
+}
+
+fn bounds<K: FromStr>(start: &Option<String>, end: &Option<String>) -> Result<(Bound<K>, Bound<K>)>
+where
+    <K as FromStr>::Err: Debug,
+{
+    let start = match start {
+        Some(start) => Bound::Included(parse_as(start)?),
+        None => Bound::Unbounded,
+    };
+    let end = match end {
+        Some(end) => Bound::Excluded(parse_as(end)?),
+        None => Bound::Unbounded,
+    };
+    Ok((start, end))
+}
+
+fn matches_prefix<K: Display>(key: &K, prefix: &str) -> bool {
+    key.to_string().starts_with(prefix)
+}
+
+/// Run the SETs and DELs of `queries` as one `WriteBatch`, all of them or none (see batch.rs).
+/// Any other query is an InvalidArgument, before anything is applied.
+pub fn execute_batch<K, V>(db: &mut LogManager<K, V>, queries: &[Query]) -> Result<QueryResult>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    db.write_batch(batch(queries)?)?;
+    Ok(QueryResult::Done)
+}
+
+/// The SETs and DELs of `queries` as a WriteBatch, like `execute_batch` applies them.
+pub fn batch<K, V>(queries: &[Query]) -> Result<WriteBatch<K, V>>
+where
+    K: FromStr,
+    V: FromStr,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    let mut batch = WriteBatch::new();
+    for query in queries {
+        match query {
+            Query::Set(key, value) => batch.insert(parse_as(key)?, parse_as(value)?),
+            Query::Del(key) => batch.delete(parse_as(key)?),
+            _ => return Err(Error::InvalidArgument(format!("only SET and DEL can be batched, not {}", query))),
+        };
+    }
+    Ok(batch)
+}
+
+/// `parse` and `execute` in one go.
+pub fn run<K, V>(db: &mut LogManager<K, V>, input: &str) -> Result<QueryResult>
+where
+    K: Ord + Clone + Debug + FromStr + Display,
+    V: Clone + Debug + FromStr + Display,
+    <K as FromStr>::Err: Debug,
+    <V as FromStr>::Err: Debug,
+{
+    execute(db, &parse(input)?)
+}
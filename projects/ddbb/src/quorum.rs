@@ -0,0 +1,298 @@
+// src/quorum.rs
+
+/*
+* Quorum reads and writes
+*
+* With replication (see replication.rs) a leader takes the writes and its followers have them
+* a little later, so what a client reads depends on which server it asks and when. A
+* `Coordinator` talks to all of them, the leader and the servers of its followers, and every
+* request says how many of them must take part, its `Consistency`:
+*
+*   ONE         one server, the fastest answer, possibly one that misses the last writes
+*   QUORUM      a majority of the servers, n / 2 + 1 of n
+*   ALL         every server, and a request fails if one of them is down
+*
+*   let mut coordinator = Coordinator::new("leader:7878", &["f1:7878", "f2:7878"], options);
+*   coordinator.set("user:1", "ann", Consistency::Quorum)?;
+*   coordinator.get("user:1", Consistency::Quorum)?;
+*
+* Writes
+*
+* A write goes to the leader, which makes it, and then the coordinator waits for as many
+* servers as the level says to have it: the leader's LSN after the write (see log.rs) is the
+* one every follower must reach, asked with STATS every POLL. The leader counts, so a write
+* at ONE returns as soon as the leader made it. If not enough followers get there in
+* `CoordinatorOptions::timeout` the write fails with an Io error, but it is not undone: the
+* leader has it, and the followers get it once they can, like any other write.
+*
+* Reads
+*
+* A read goes to the followers in the order they were given and then to the leader (so reads
+* at ONE stay off the leader while the followers answer), and to the next one if one fails,
+* until as many as the level says answered. Their answers are reconciled by LSN: each server
+* tells its LSN before it answers, and the answer of the one with the highest is taken, as
+* the servers all apply the same writes in the same order and the higher LSN has seen more of
+* them. So with QUORUM for the writes and the reads, every read finds the writes that
+* returned before it was made, as one of the majority it asks has them (a majority of the
+* writes' and a majority of the reads' always share a server), and answers with them. A
+* follower with `FollowerOptions::max_lag` refuses reads while it is too far behind (see
+* replication.rs), so a read at ONE is answered by the first follower fresh enough, or the
+* leader.
+*
+* The servers are asked one after the other, not all at once, so a read at QUORUM or ALL
+* takes about as long as the reads of that many servers. STATS is a command of admin.rs, with
+* authentication the credentials of `CoordinatorOptions::client` must allow it. A Raft cluster
+* (see raft.rs) needs none of this for its writes, a majority has every one it commits.
+*/
+
+use crate::batch::WriteBatch;
+use crate::client::{Client, ClientOptions};
+use crate::error::{Error, Result};
+use crate::query::{Keys, Query, QueryResult};
+use crate::scan::{Page, ScanOptions};
+use std::fmt::{self, Display};
+use std::io;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a write asks the followers how far they are.
+pub const POLL: Duration = Duration::from_millis(10);
+
+/// How many servers take part in a request, see the top of this file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    One,
+    Quorum,
+    All,
+}
+
+impl Consistency {
+    /// How many of `servers` servers a request needs at this level.
+    pub fn needed(self, servers: usize) -> usize {
+        match self {
+            Consistency::One => 1,
+            Consistency::Quorum => servers / 2 + 1,
+            Consistency::All => servers,
+        }
+    }
+}
+
+impl Display for Consistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Consistency::One => write!(f, "ONE"),
+            Consistency::Quorum => write!(f, "QUORUM"),
+            Consistency::All => write!(f, "ALL"),
+        }
+    }
+}
+
+impl FromStr for Consistency {
+    type Err = Error;
+
+    fn from_str(level: &str) -> Result<Self> {
+        match level.to_ascii_uppercase().as_str() {
+            "ONE" => Ok(Consistency::One),
+            "QUORUM" => Ok(Consistency::Quorum),
+            "ALL" => Ok(Consistency::All),
+            _ => Err(Error::InvalidArgument(format!("bad consistency level {}", level))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CoordinatorOptions {
+    /// How to connect to the servers.
+    pub client: ClientOptions,
+    /// How long a write waits for the followers it needs.
+    pub timeout: Duration,
+}
+
+impl Default for CoordinatorOptions {
+    fn default() -> Self {
+        CoordinatorOptions { client: ClientOptions::default(), timeout: Duration::from_secs(5) }
+    }
+}
+
+// A server, connected to when it is first needed and again after a failure
+struct Server {
+    address: String,
+    client: Option<Client>,
+}
+
+impl Server {
+    fn new(address: &str) -> Self {
+        Server { address: address.to_string(), client: None }
+    }
+
+    fn with<T>(
+        &mut self,
+        options: &ClientOptions,
+        request: impl FnOnce(&mut Client) -> Result<T>,
+    ) -> Result<T> {
+        if self.client.is_none() {
+            self.client = Some(Client::connect_with(self.address.as_str(), options.clone())?);
+        }
+        let result = request(self.client.as_mut().unwrap());
+        if let Err(Error::Io(_)) = result {
+            self.client = None;
+        }
+        result
+    }
+
+    fn lsn(&mut self, options: &ClientOptions) -> Result<u64> {
+        let stats = self.with(options, |client| client.stats())?;
+        let lsn = stats.iter().find(|(name, _)| name == "lsn").map(|(_, lsn)| lsn.parse());
+        match lsn {
+            Some(Ok(lsn)) => Ok(lsn),
+            _ => Err(Error::Corruption(format!("{} has no LSN in its STATS", self.address))),
+        }
+    }
+}
+
+/// Reads and writes a leader and its followers at a consistency level, see the top of this
+/// file.
+pub struct Coordinator {
+    leader: Server,
+    followers: Vec<Server>,
+    options: CoordinatorOptions,
+}
+
+impl Coordinator {
+    /// A coordinator of the server at `leader` and the servers of its `followers`. It only
+    /// connects to them once a request needs them.
+    pub fn new(leader: &str, followers: &[&str], options: CoordinatorOptions) -> Self {
+        let followers = followers.iter().map(|address| Server::new(address)).collect();
+        Coordinator { leader: Server::new(leader), followers, options }
+    }
+
+    /// Run `query` at `consistency`: a SET or a DEL as a write, anything else as a read.
+    pub fn query(&mut self, query: &Query, consistency: Consistency) -> Result<QueryResult> {
+        match query {
+            Query::Set(..) | Query::Del(_) => {
+                let client = &self.options.client;
+                let result = self.leader.with(client, |client| client.query(query))?;
+                self.replicated(consistency)?;
+                Ok(result)
+            }
+            _ => self.read(query, consistency),
+        }
+    }
+
+    pub fn get(&mut self, key: &str, consistency: Consistency) -> Result<Option<String>> {
+        match self.query(&Query::Get(key.to_string()), consistency)? {
+            QueryResult::Value(value) => Ok(value),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str, consistency: Consistency) -> Result<()> {
+        self.query(&Query::Set(key.to_string(), value.to_string()), consistency).map(|_| ())
+    }
+
+    pub fn del(&mut self, key: &str, consistency: Consistency) -> Result<()> {
+        self.query(&Query::Del(key.to_string()), consistency).map(|_| ())
+    }
+
+    pub fn scan(
+        &mut self,
+        keys: Keys,
+        options: ScanOptions,
+        consistency: Consistency,
+    ) -> Result<Page<String, String>> {
+        match self.query(&Query::Scan { keys, options, reverse: false }, consistency)? {
+            QueryResult::Pairs { pairs, next } => Ok(Page { pairs, next }),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn count(&mut self, keys: Keys, consistency: Consistency) -> Result<usize> {
+        match self.query(&Query::Count(keys), consistency)? {
+            QueryResult::Count(count) => Ok(count),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// Write `batch` on the leader at `consistency`, see `Client::write_batch`.
+    pub fn write_batch(
+        &mut self,
+        batch: &WriteBatch<String, String>,
+        consistency: Consistency,
+    ) -> Result<()> {
+        let client = &self.options.client;
+        self.leader.with(client, |client| client.write_batch(batch))?;
+        self.replicated(consistency)
+    }
+
+    // Wait for the servers a write at `consistency` needs to have it
+    fn replicated(&mut self, consistency: Consistency) -> Result<()> {
+        let servers = self.followers.len() + 1;
+        let needed = consistency.needed(servers);
+        if needed == 1 {
+            return Ok(());
+        }
+        let client = &self.options.client;
+        let target = self.leader.lsn(client)?;
+        let deadline = Instant::now() + self.options.timeout;
+        let mut waiting: Vec<&mut Server> = self.followers.iter_mut().collect();
+        loop {
+            // a follower that cannot answer now is asked again at the next poll
+            waiting.retain_mut(|follower| follower.lsn(client).map_or(true, |lsn| lsn < target));
+            let have = servers - waiting.len();
+            if have >= needed {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                let message = format!(
+                    "the write is on {} of {} servers after {:?}, {} needs {}",
+                    have, servers, self.options.timeout, consistency, needed
+                );
+                return Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, message)));
+            }
+            thread::sleep(POLL);
+        }
+    }
+
+    // Ask the servers a read at `consistency` needs, and take the answer of the one that is
+    // furthest along
+    fn read(&mut self, query: &Query, consistency: Consistency) -> Result<QueryResult> {
+        let servers = self.followers.len() + 1;
+        let needed = consistency.needed(servers);
+        let client = &self.options.client;
+        let mut answers: Vec<(u64, QueryResult)> = Vec::new();
+        let mut failure = None;
+        for server in self.followers.iter_mut().chain(std::iter::once(&mut self.leader)) {
+            // the LSN first: the answer has at least the writes up to it
+            let answer = server.lsn(client).and_then(|lsn| {
+                let result = server.with(client, |client| client.query(query))?;
+                Ok((lsn, result))
+            });
+            match answer {
+                Ok(answer) => answers.push(answer),
+                Err(e) => failure = Some(format!("{}: {}", server.address, e)),
+            }
+            if answers.len() == needed {
+                break;
+            }
+        }
+        if answers.len() < needed {
+            let message = format!(
+                "{} of {} servers answered, {} needs {} (last failure {})",
+                answers.len(),
+                servers,
+                consistency,
+                needed,
+                failure.unwrap_or_default()
+            );
+            return Err(Error::Io(io::Error::other(message)));
+        }
+        // the first of the highest, which is the leader's only if no follower is as far
+        let best = (0..answers.len()).rev().max_by_key(|&i| answers[i].0).unwrap();
+        Ok(answers.swap_remove(best).1)
+    }
+}
+
+fn unexpected(result: &QueryResult) -> Error {
+    Error::Corruption(format!("unexpected result {:?}", result))
+}
@@ -0,0 +1,820 @@
+// src/raft.rs
+
+/*
+* Raft
+*
+* The followers of replication.rs copy a leader someone chose, and when it is lost someone has
+* to pick one of them and point the others and the clients at it. The nodes of a Raft cluster
+* do that themselves: they elect one of them the leader, every write goes through the leader
+* to a majority of the nodes before it is applied, and when the leader is lost the others
+* elect a new one among those that have every write it applied. As long as a majority of the
+* nodes is up and can talk (two of three, three of five), the cluster takes writes and loses
+* none it acknowledged. This is the algorithm of "In Search of an Understandable Consensus
+* Algorithm" (Ongaro and Ousterhout), with its names: terms, votes, AppendEntries, commit.
+*
+* A node is a server (server.rs or http.rs) with `ServerOptions::raft`, which names the node
+* and its peers by the addresses of their TCP servers:
+*
+*   ddbb dir serve 0.0.0.0:7001 --raft-id host1:7001 --raft-peers host2:7001,host3:7001
+*
+* The Raft log is the log
+*
+* The entries of the Raft log are write records of the log of log.rs, and the index of an
+* entry is its LSN (see replication.rs): once an entry is committed (it is on a majority of
+* the nodes) every node applies it to its LogManager with `LogManager::apply_wal`, so the
+* `lsn()` of a node is how far it applied the Raft log. The entries themselves, each with the
+* term of the leader that made it, are in RAFT_FILE next to the log, along with the term of
+* the node and its vote. Every change of those is synced before the node answers for it, as
+* Raft wants. The file keeps RAFT_RETAINED applied entries for the nodes that are a little
+* behind, a node further behind gets all of the leader's LogManager instead (an
+* InstallSnapshot, see `LogManager::install_snapshot`), like a follower does.
+*
+* A write to the leader (a SET, a DEL, an EXEC) is one BATCH record, with the deletion time of
+* its DELs chosen by the leader, so every node applies the same thing. The client gets its OK
+* once the record is committed and applied on the leader. A write to another node fails with a
+* PermissionDenied that names the leader, if the node knows it: "the server is not the Raft
+* leader, writes go to <address>". A write the leader could not commit (it lost its majority,
+* or its leadership, or it took longer than COMMIT_TIMEOUT) is an Io error, and may still be
+* applied later, like a write whose connection failed. Reads are answered by every node from
+* its LogManager, so a read from a node that is not the leader may miss the last writes.
+*
+* A new leader first adds an empty batch ("BATCH 0") of its own term, which commits the entries
+* of earlier terms with it: a leader only counts the nodes that have an entry of its own term.
+*
+* Messages
+*
+* The nodes talk over the TCP protocol of server.rs, with `RaftOptions::client` (the
+* credentials of a peer need the access of the commands of admin.rs, like REPLICATE): "RAFT
+* <message>" is answered with "RAFT <reply>", both JSON on one line. A leader has a thread for
+* every peer, which sends it the entries it misses (MAX_ENTRIES at a time) and an empty
+* AppendEntries every `heartbeat` when it misses none; a candidate uses the same thread to ask
+* the peer for its vote. A node that heard of no leader for its election timeout (a random
+* time between `election_timeout` and twice that, so the nodes do not all stand at once) starts
+* an election.
+*
+* The members of the cluster are fixed, every node lists the others. The LogManager of a node
+* must not be written any other way, and a node joins with an empty directory or one of the
+* same cluster. Unique indexes are not checked, the LogManager of a server has none.
+* `Raft::status` (and the raft_ lines of the STATS of a server) tell the role and term of a
+* node and the leader it knows.
+*/
+
+use crate::client::{Client, ClientOptions};
+use crate::error::{Error, Result};
+use crate::log::{self, LogManager};
+use crate::query::{self, Query, QueryResult};
+use crate::replication::Snapshot;
+use crate::vfs::{OpenOptions, Vfs, VfsFile};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The file of the Raft log, term and vote of a node, in the directory of its LogManager.
+pub const RAFT_FILE: &str = "raft.txt";
+const TEMP_RAFT_FILE: &str = "temp_raft.txt";
+
+/// How many applied entries a node keeps for the nodes that are behind.
+pub const RAFT_RETAINED: u64 = 1024;
+
+/// Most entries in one AppendEntries.
+pub const MAX_ENTRIES: usize = 256;
+
+/// How long a write waits for the leader to commit it.
+pub const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often the election timeout is checked, and how often the threads of the peers look for
+// something to send
+const TICK: Duration = Duration::from_millis(10);
+
+// The no-op of a new leader, see the top of this file
+const NOOP: &str = "BATCH 0";
+
+type Db = LogManager<String, String>;
+
+/// A node of a Raft cluster, see the top of this file.
+#[derive(Clone, Debug)]
+pub struct RaftOptions {
+    /// Address of the server of this node, as the other nodes reach it.
+    pub id: String,
+    /// Addresses of the servers of the other nodes.
+    pub peers: Vec<String>,
+    /// Shortest wait for a leader before standing for election, see the top of this file.
+    pub election_timeout: Duration,
+    /// How often a leader tells its peers it is still there.
+    pub heartbeat: Duration,
+    /// How to connect to the peers: TLS, credentials, timeouts.
+    pub client: ClientOptions,
+}
+
+impl RaftOptions {
+    pub fn new(id: &str, peers: &[&str]) -> Self {
+        RaftOptions {
+            id: id.to_string(),
+            peers: peers.iter().map(|peer| peer.to_string()).collect(),
+            election_timeout: Duration::from_millis(300),
+            heartbeat: Duration::from_millis(50),
+            // a peer that does not answer is given up on for now, the next message goes again
+            client: ClientOptions {
+                retries: 0,
+                connect_timeout: Some(Duration::from_millis(500)),
+                io_timeout: Some(Duration::from_secs(5)),
+                ..ClientOptions::default()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Follower => write!(f, "follower"),
+            Role::Candidate => write!(f, "candidate"),
+            Role::Leader => write!(f, "leader"),
+        }
+    }
+}
+
+/// Where a node stands, see `Raft::status`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RaftStatus {
+    pub role: Role,
+    pub term: u64,
+    /// The leader of the term, as far as the node knows.
+    pub leader: Option<String>,
+    /// Index (LSN) of the last entry the node knows is committed.
+    pub commit: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    term: u64,
+    // a write record of the log of log.rs
+    record: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Message {
+    RequestVote { term: u64, candidate: String, last_index: u64, last_term: u64 },
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    InstallSnapshot { term: u64, leader: String, last_term: u64, snapshot: Snapshot<String, String> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Reply {
+    Vote { term: u64, granted: bool },
+    // `matched` is the last index known to be the same as the leader's when the entries were
+    // appended, where the leader should look next when they were not
+    Append { term: u64, success: bool, matched: u64 },
+}
+
+// What a leader knows of a peer
+#[derive(Clone, Copy, Debug)]
+struct Progress {
+    next: u64,
+    matched: u64,
+    sent: Option<Instant>,
+}
+
+struct State {
+    storage: Storage,
+    role: Role,
+    leader: Option<String>,
+    commit: u64,
+    // of a candidate, itself included
+    votes: HashSet<String>,
+    // when to stand for election, unless a leader is heard of
+    deadline: Instant,
+    // of a leader
+    progress: HashMap<String, Progress>,
+    stopped: bool,
+}
+
+/// A node of a Raft cluster, running on threads of its own until `stop`, see the top of this
+/// file.
+pub struct Raft {
+    options: RaftOptions,
+    db: Arc<Mutex<Db>>,
+    state: Mutex<State>,
+    // anything a waiting thread may look for: a commit, a new entry, a new role or term
+    changed: Condvar,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Raft {
+    /// Make `db` the state of a node of the cluster of `options`, and start taking part.
+    pub fn start(db: Arc<Mutex<Db>>, options: RaftOptions) -> Result<Arc<Self>> {
+        let (storage, applied) = {
+            let db = db.lock().unwrap();
+            let (vfs, dir) = db.location();
+            let mut storage = Storage::open(vfs, dir)?;
+            let applied = db.lsn();
+            if applied < storage.base.0 || applied > storage.last_index() {
+                eprintln!("The Raft log does not go with write {} of the log, starting it over", applied);
+                storage.reset(applied, 0)?;
+            }
+            (storage, applied)
+        };
+        let state = State {
+            storage,
+            role: Role::Follower,
+            leader: None,
+            commit: applied,
+            votes: HashSet::new(),
+            deadline: election_deadline(&options),
+            progress: HashMap::new(),
+            stopped: false,
+        };
+        let state = Mutex::new(state);
+        let raft = Arc::new(Raft { options, db, state, changed: Condvar::new(), threads: Mutex::default() });
+
+        let mut threads = Vec::new();
+        let ticking = raft.clone();
+        threads.push(thread::spawn(move || ticking.tick()));
+        for peer in raft.options.peers.clone() {
+            let raft = raft.clone();
+            threads.push(thread::spawn(move || raft.replicate_to(&peer)));
+        }
+        *raft.threads.lock().unwrap() = threads;
+        Ok(raft)
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        let state = self.state.lock().unwrap();
+        let leader = state.leader.clone();
+        RaftStatus { role: state.role, term: state.storage.term, leader, commit: state.commit }
+    }
+
+    /// Stop taking part. Once this returns the LogManager is not written anymore.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+        for thread in self.threads.lock().unwrap().drain(..) {
+            let _ = thread.join();
+        }
+    }
+
+    /// Apply the SETs and DELs of `queries` together, through the Raft log, see the top of
+    /// this file. Done once they are committed and applied.
+    pub fn write(&self, queries: &[Query]) -> Result<QueryResult> {
+        let batch = query::batch(queries)?;
+        if batch.is_empty() {
+            return Ok(QueryResult::Done);
+        }
+        let record = Db::batch_record(&batch);
+
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return Err(not_leader(&state));
+        }
+        let term = state.storage.term;
+        state.storage.append(vec![Entry { term, record }])?;
+        let index = state.storage.last_index();
+        self.advance_commit(&mut state)?;
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if state.commit >= index {
+                // a later leader may have committed another entry there
+                return match state.storage.term_at(index) {
+                    Some(committed) if committed != term => Err(not_committed("another leader replaced it")),
+                    _ => Ok(QueryResult::Done),
+                };
+            }
+            if state.role != Role::Leader {
+                return Err(not_committed("the node lost its leadership"));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(not_committed("no majority took it in time"));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// The raft_ statistics of the STATS of a server.
+    pub(crate) fn stats(&self) -> Vec<(String, String)> {
+        let status = self.status();
+        [
+            ("raft_role", status.role.to_string()),
+            ("raft_term", status.term.to_string()),
+            ("raft_leader", status.leader.unwrap_or_else(|| "none".to_string())),
+            ("raft_commit", status.commit.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+    }
+
+    /// Answer the message of a RAFT request from another node, see the top of this file.
+    pub(crate) fn handle(&self, message: &str) -> Result<String> {
+        let message: Message = serde_json::from_str(message)
+            .map_err(|e| Error::InvalidArgument(format!("bad Raft message: {}", e)))?;
+        let mut state = self.state.lock().unwrap();
+        let reply = match message {
+            Message::RequestVote { term, candidate, last_index, last_term } => {
+                self.vote(&mut state, term, candidate, (last_term, last_index))?
+            }
+            Message::AppendEntries { term, leader, prev_index, prev_term, entries, commit } => {
+                if term < state.storage.term {
+                    Reply::Append { term: state.storage.term, success: false, matched: 0 }
+                } else {
+                    self.follow(&mut state, term, leader)?;
+                    self.append_entries(&mut state, (prev_index, prev_term), entries, commit)?
+                }
+            }
+            Message::InstallSnapshot { term, leader, last_term, snapshot } => {
+                if term < state.storage.term {
+                    Reply::Append { term: state.storage.term, success: false, matched: 0 }
+                } else {
+                    self.follow(&mut state, term, leader)?;
+                    let lsn = snapshot.lsn;
+                    if lsn > state.commit {
+                        eprintln!("Raft: installing a snapshot of write {}", lsn);
+                        self.db.lock().unwrap().install_snapshot(snapshot)?;
+                        state.storage.reset(lsn, last_term)?;
+                        state.commit = lsn;
+                    }
+                    Reply::Append { term, success: true, matched: lsn }
+                }
+            }
+        };
+        serde_json::to_string(&reply).map_err(|e| Error::Io(io::Error::other(e)))
+    }
+
+    fn vote(&self, state: &mut State, term: u64, candidate: String, last: (u64, u64)) -> Result<Reply> {
+        if term > state.storage.term {
+            self.step_down(state, term)?;
+        }
+        // only a candidate with every entry this node has can have every committed one
+        let up_to_date = last >= (state.storage.last_term(), state.storage.last_index());
+        let free = state.storage.voted_for.as_ref().is_none_or(|voted_for| *voted_for == candidate);
+        let granted = term == state.storage.term && up_to_date && free;
+        if granted {
+            if state.storage.voted_for.is_none() {
+                state.storage.set_term(term, Some(candidate))?;
+            }
+            state.deadline = election_deadline(&self.options);
+        }
+        Ok(Reply::Vote { term: state.storage.term, granted })
+    }
+
+    // Take `leader` for the leader of `term`, which is not older than the node's
+    fn follow(&self, state: &mut State, term: u64, leader: String) -> Result<()> {
+        if term > state.storage.term || state.role != Role::Follower {
+            self.step_down(state, term)?;
+        }
+        state.leader = Some(leader);
+        state.deadline = election_deadline(&self.options);
+        Ok(())
+    }
+
+    fn append_entries(
+        &self,
+        state: &mut State,
+        (prev_index, prev_term): (u64, u64),
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<Reply> {
+        let term = state.storage.term;
+        let storage = &mut state.storage;
+        if prev_index > storage.last_index() {
+            return Ok(Reply::Append { term, success: false, matched: storage.last_index() });
+        }
+        // the entries up to the base are applied, committed, so the same as the leader's
+        if prev_index > storage.base.0 && storage.term_at(prev_index) != Some(prev_term) {
+            return Ok(Reply::Append { term, success: false, matched: prev_index - 1 });
+        }
+
+        let mut index = prev_index;
+        let mut new = Vec::new();
+        for entry in entries {
+            index += 1;
+            if index <= storage.base.0 || (new.is_empty() && storage.term_at(index) == Some(entry.term)) {
+                continue;
+            }
+            if new.is_empty() && index <= storage.last_index() {
+                if index <= state.commit {
+                    return Err(Error::Corruption(format!("the leader replaces committed entry {}", index)));
+                }
+                storage.truncate(index)?;
+            }
+            new.push(entry);
+        }
+        storage.append(new)?;
+
+        let matched = index.max(storage.base.0);
+        if commit > state.commit {
+            state.commit = state.commit.max(commit.min(matched));
+            self.apply(state)?;
+            self.changed.notify_all();
+        }
+        Ok(Reply::Append { term, success: true, matched })
+    }
+
+    // Stand for election when no leader was heard of for the election timeout
+    fn tick(&self) {
+        loop {
+            thread::sleep(TICK);
+            let mut state = self.state.lock().unwrap();
+            if state.stopped {
+                return;
+            }
+            if state.role != Role::Leader && Instant::now() >= state.deadline {
+                if let Err(e) = self.start_election(&mut state) {
+                    eprintln!("Raft: cannot start an election: {}", e);
+                }
+            }
+        }
+    }
+
+    fn start_election(&self, state: &mut State) -> Result<()> {
+        let term = state.storage.term + 1;
+        state.storage.set_term(term, Some(self.options.id.clone()))?;
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.votes = HashSet::from([self.options.id.clone()]);
+        state.deadline = election_deadline(&self.options);
+        eprintln!("Raft: {} stands for election in term {}", self.options.id, term);
+        self.count_votes(state)?;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn count_votes(&self, state: &mut State) -> Result<()> {
+        if state.role != Role::Candidate || state.votes.len() <= self.options.peers.len().div_ceil(2) {
+            return Ok(());
+        }
+        eprintln!("Raft: {} is the leader of term {}", self.options.id, state.storage.term);
+        state.role = Role::Leader;
+        state.leader = Some(self.options.id.clone());
+        let next = state.storage.last_index() + 1;
+        let progress = Progress { next, matched: 0, sent: None };
+        state.progress = self.options.peers.iter().map(|peer| (peer.clone(), progress)).collect();
+        let term = state.storage.term;
+        state.storage.append(vec![Entry { term, record: NOOP.to_string() }])?;
+        self.advance_commit(state)
+    }
+
+    // Become a follower, in `term` if it is a newer one
+    fn step_down(&self, state: &mut State, term: u64) -> Result<()> {
+        if term > state.storage.term {
+            state.storage.set_term(term, None)?;
+            state.leader = None;
+        }
+        if state.role == Role::Leader {
+            eprintln!("Raft: {} is not the leader anymore, term {}", self.options.id, state.storage.term);
+            state.deadline = election_deadline(&self.options);
+        }
+        state.role = Role::Follower;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // Commit what a majority has, of the leader's term, see the top of this file
+    fn advance_commit(&self, state: &mut State) -> Result<()> {
+        if state.role != Role::Leader {
+            return Ok(());
+        }
+        let mut matched: Vec<u64> = state.progress.values().map(|progress| progress.matched).collect();
+        matched.push(state.storage.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let majority = matched[matched.len() / 2];
+        if majority > state.commit && state.storage.term_at(majority) == Some(state.storage.term) {
+            state.commit = majority;
+            self.apply(state)?;
+            self.changed.notify_all();
+        }
+        Ok(())
+    }
+
+    // Apply the committed entries to the LogManager
+    fn apply(&self, state: &mut State) -> Result<()> {
+        let mut db = self.db.lock().unwrap();
+        while db.lsn() < state.commit {
+            let index = db.lsn() + 1;
+            let lacking = || Error::Corruption(format!("the Raft log lacks committed entry {}", index));
+            let entry = state.storage.entry(index).ok_or_else(lacking)?;
+            db.apply_wal(index, &entry.record)?;
+        }
+        state.storage.trim(db.lsn())
+    }
+
+    // The thread of a peer, see the top of this file
+    fn replicate_to(&self, peer: &str) {
+        let mut client: Option<Client> = None;
+        let mut asked = 0; // the last term the peer was asked for its vote in
+        let mut failing = false;
+        loop {
+            let message = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if state.stopped {
+                        return;
+                    }
+                    if let Some(message) = self.next_message(&mut state, peer, &mut asked) {
+                        break message;
+                    }
+                    state = self.changed.wait_timeout(state, TICK).unwrap().0;
+                }
+            };
+            match self.send(&mut client, peer, &message) {
+                Ok(reply) => {
+                    failing = false;
+                    let mut state = self.state.lock().unwrap();
+                    if let Err(e) = self.on_reply(&mut state, peer, &message, reply) {
+                        eprintln!("Raft: cannot take the reply of {}: {}", peer, e);
+                    }
+                }
+                Err(e) => {
+                    if !failing {
+                        eprintln!("Raft: cannot reach {}: {}", peer, e);
+                        failing = true;
+                    }
+                    client = None;
+                    thread::sleep(self.options.heartbeat);
+                }
+            }
+        }
+    }
+
+    // What the thread of `peer` should send now, if anything
+    fn next_message(&self, state: &mut State, peer: &str, asked: &mut u64) -> Option<Message> {
+        let term = state.storage.term;
+        match state.role {
+            Role::Candidate if *asked < term => {
+                *asked = term;
+                Some(Message::RequestVote {
+                    term,
+                    candidate: self.options.id.clone(),
+                    last_index: state.storage.last_index(),
+                    last_term: state.storage.last_term(),
+                })
+            }
+            Role::Leader => {
+                let last_index = state.storage.last_index();
+                let progress = state.progress.get_mut(peer)?;
+                let due = progress.sent.is_none_or(|sent| sent.elapsed() >= self.options.heartbeat);
+                if progress.next > last_index && !due {
+                    return None;
+                }
+                progress.sent = Some(Instant::now());
+                let (next, leader, commit) = (progress.next, self.options.id.clone(), state.commit);
+                let Some(prev_term) = state.storage.term_at(next - 1) else {
+                    // the peer is behind what the Raft log keeps
+                    let snapshot = self.db.lock().unwrap().snapshot();
+                    let last_term = state.storage.term_at(snapshot.lsn)?;
+                    return Some(Message::InstallSnapshot { term, leader, last_term, snapshot });
+                };
+                let last = last_index.min(next - 1 + MAX_ENTRIES as u64);
+                let entries = (next..=last).filter_map(|index| state.storage.entry(index).cloned()).collect();
+                let prev_index = next - 1;
+                Some(Message::AppendEntries { term, leader, prev_index, prev_term, entries, commit })
+            }
+            _ => None,
+        }
+    }
+
+    fn send(&self, client: &mut Option<Client>, peer: &str, message: &Message) -> Result<Reply> {
+        let connection = match client {
+            Some(connection) => connection,
+            None => client.insert(Client::connect_with(peer, self.options.client.clone())?),
+        };
+        let message = serde_json::to_string(message).map_err(|e| Error::Io(io::Error::other(e)))?;
+        let reply = connection.raft(&message)?;
+        serde_json::from_str(&reply).map_err(|e| Error::Corruption(format!("bad Raft reply: {}", e)))
+    }
+
+    fn on_reply(&self, state: &mut State, peer: &str, message: &Message, reply: Reply) -> Result<()> {
+        let (Reply::Vote { term, .. } | Reply::Append { term, .. }) = reply;
+        if term > state.storage.term {
+            return self.step_down(state, term);
+        }
+        match (message, reply) {
+            (Message::RequestVote { term, .. }, Reply::Vote { granted: true, .. })
+                if *term == state.storage.term =>
+            {
+                state.votes.insert(peer.to_string());
+                self.count_votes(state)
+            }
+            (
+                Message::AppendEntries { term, .. } | Message::InstallSnapshot { term, .. },
+                Reply::Append { success, matched, .. },
+            ) if *term == state.storage.term && state.role == Role::Leader => {
+                let Some(progress) = state.progress.get_mut(peer) else {
+                    return Ok(());
+                };
+                if !success {
+                    // go back to where the peer's log may be the same as the leader's
+                    if let Message::AppendEntries { prev_index, .. } = message {
+                        progress.next = (*prev_index).min(matched + 1).max(1);
+                    }
+                    return Ok(());
+                }
+                progress.matched = progress.matched.max(matched);
+                progress.next = progress.matched + 1;
+                self.advance_commit(state)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn election_deadline(options: &RaftOptions) -> Instant {
+    let timeout = options.election_timeout;
+    Instant::now() + rand::thread_rng().gen_range(timeout..timeout * 2)
+}
+
+fn not_leader(state: &State) -> Error {
+    Error::PermissionDenied(match &state.leader {
+        Some(leader) => format!("the server is not the Raft leader, writes go to {}", leader),
+        None => "the server is not the Raft leader, and there is no leader now".to_string(),
+    })
+}
+
+fn not_committed(why: &str) -> Error {
+    Error::Io(io::Error::other(format!("the write was not committed ({}), it may still be applied", why)))
+}
+
+// The Raft log and the term and vote of a node, in RAFT_FILE: a line for every change, framed
+// like the records of log.rs,
+//
+//   TERM <term> <vote or ->        the node is in <term> and voted for <vote>
+//   BASE <index> <term>            the entries up to <index> are applied, the last of <term>
+//   ENTRY <term> <record>          the entry after the last one
+//   TRUNCATE <index>               the entries from <index> on are gone
+//
+// rewritten to a TERM, a BASE and the entries when too many applied entries piled up.
+struct Storage {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    file: Box<dyn VfsFile>,
+    term: u64,
+    voted_for: Option<String>,
+    // index and term of the entry before the first of `entries`
+    base: (u64, u64),
+    entries: Vec<Entry>,
+}
+
+impl Storage {
+    fn open(vfs: Arc<dyn Vfs>, dir: &Path) -> Result<Self> {
+        let options = OpenOptions::new().read(true).append(true).create(true);
+        let mut file = vfs.open(&dir.join(RAFT_FILE), options)?;
+        vfs.sync_dir(dir)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let dir = dir.to_path_buf();
+        let (term, voted_for, base, entries) = (0, None, (0, 0), Vec::new());
+        let mut storage = Storage { vfs, dir, file, term, voted_for, base, entries };
+
+        // a line that was not written completely, and whatever follows, is dropped
+        let mut good = 0;
+        for line in content.split_inclusive(|&byte| byte == b'\n') {
+            let text = std::str::from_utf8(line).ok().filter(|line| line.ends_with('\n'));
+            match text.and_then(|line| log::unframe(line.trim_end_matches('\n'))) {
+                Some(payload) if storage.replay(payload).is_some() => good += line.len(),
+                _ => break,
+            }
+        }
+        if good < content.len() {
+            eprintln!("Discarding {} bytes of torn Raft log", content.len() - good);
+            storage.file.set_len(good as u64)?;
+            storage.file.sync()?;
+        }
+        Ok(storage)
+    }
+
+    fn replay(&mut self, payload: &str) -> Option<()> {
+        let fields: Vec<&str> = payload.splitn(3, ' ').collect();
+        match fields[..] {
+            ["TERM", term, vote] => {
+                self.term = term.parse().ok()?;
+                self.voted_for = (vote != "-").then(|| vote.to_string());
+            }
+            ["BASE", index, term] => {
+                self.base = (index.parse().ok()?, term.parse().ok()?);
+                self.entries.clear();
+            }
+            ["ENTRY", term, record] => {
+                self.entries.push(Entry { term: term.parse().ok()?, record: record.to_string() });
+            }
+            ["TRUNCATE", index] => {
+                let index: u64 = index.parse().ok()?;
+                self.entries.truncate(index.checked_sub(self.base.0 + 1)? as usize);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn last_index(&self) -> u64 {
+        self.base.0 + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.base.1, |entry| entry.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.base.0 {
+            return Some(self.base.1);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        let position = index.checked_sub(self.base.0 + 1)?;
+        self.entries.get(position as usize)
+    }
+
+    fn set_term(&mut self, term: u64, voted_for: Option<String>) -> Result<()> {
+        self.write(&[term_record(term, voted_for.as_deref())])?;
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<String> = entries.iter().map(entry_record).collect();
+        self.write(&records)?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    // Drop the entries from `index` on
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.write(&[format!("TRUNCATE {}", index)])?;
+        self.entries.truncate((index - self.base.0 - 1) as usize);
+        Ok(())
+    }
+
+    // Drop every entry, the one at `index` (of `term`) being applied
+    fn reset(&mut self, index: u64, term: u64) -> Result<()> {
+        self.write(&[format!("BASE {} {}", index, term)])?;
+        self.base = (index, term);
+        self.entries.clear();
+        Ok(())
+    }
+
+    // Keep RAFT_RETAINED of the entries up to `applied`, once there are twice as many
+    fn trim(&mut self, applied: u64) -> Result<()> {
+        if applied < self.base.0 + 2 * RAFT_RETAINED {
+            return Ok(());
+        }
+        let base = applied - RAFT_RETAINED;
+        let term = self.term_at(base).expect("the applied entries are in the Raft log");
+        let entries = self.entries.split_off((base - self.base.0) as usize);
+        let mut records = vec![term_record(self.term, self.voted_for.as_deref())];
+        records.push(format!("BASE {} {}", base, term));
+        records.extend(entries.iter().map(entry_record));
+
+        let (path, temp_path) = (self.dir.join(RAFT_FILE), self.dir.join(TEMP_RAFT_FILE));
+        let data: String = records.iter().map(|record| log::frame(record)).collect();
+        self.vfs.write(&temp_path, data.as_bytes())?;
+        self.vfs.rename(&temp_path, &path)?;
+        self.vfs.sync_dir(&self.dir)?;
+        self.file = self.vfs.open(&path, OpenOptions::new().read(true).append(true))?;
+        self.base = (base, term);
+        self.entries = entries;
+        Ok(())
+    }
+
+    fn write(&mut self, records: &[String]) -> Result<()> {
+        let data: String = records.iter().map(|record| log::frame(record)).collect();
+        self.file.write_all(data.as_bytes())?;
+        self.file.flush()?;
+        self.file.sync()?;
+        Ok(())
+    }
+}
+
+fn term_record(term: u64, voted_for: Option<&str>) -> String {
+    format!("TERM {} {}", term, voted_for.unwrap_or("-"))
+}
+
+fn entry_record(entry: &Entry) -> String {
+    format!("ENTRY {} {}", entry.term, entry.record)
+}
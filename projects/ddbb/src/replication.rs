@@ -0,0 +1,274 @@
+// src/replication.rs
+
+/*
+* Replication
+*
+* A leader is a server (server.rs) like any other, with clients writing to it. A follower is
+* a LogManager of its own, on another machine or in another directory, that a `Follower` keeps
+* a copy of the leader's in: it connects to the leader, which ships it every write of its log
+* as it is made, and applies them in the same order. Clients can read from the follower (that
+* is what the servers of a follower are for, see `ServerOptions::follow`), and if the leader is
+* lost the follower is a warm standby, its directory opens like the leader's would, less the
+* last writes it had not received yet.
+*
+*   let follower = Follower::start(db, FollowerOptions::new("leader.example.com:7878"));
+*
+* LSNs
+*
+* Every write of a log has an LSN, its number in the order of the writes (see log.rs), and a
+* follower applies the writes of the leader with the LSNs they had there: the `lsn()` of the
+* follower's LogManager is how far it got, and it survives a restart like the writes do. A
+* follower must not be written to any other way, its LSNs would not be the leader's anymore.
+*
+* The protocol
+*
+* A follower sends "REPLICATE <lsn>" to the leader (with credentials that allow the commands
+* of admin.rs, see auth.rs) and gets OK, then for as long as the connection lasts:
+*
+*   WAL <lsn> <record>          the write <lsn>, a record of the log of log.rs
+*   CHECKPOINT <lsn> <n>        the files of the database as of the write <lsn>, followed by
+*                               n files, each a line "FILE <name> <size>" and <size> bytes
+*   LSN <lsn>                   the leader is at <lsn>, before the writes up to it, or when it
+*                               has nothing new to ship
+*
+* The writes come one after the other from the one after <lsn>, as long as the leader still
+* has them: it keeps the last ones in memory (`Options::replication_backlog`, filled again
+* from its log when it opens), and a follower that needs older ones, one that starts empty
+* from a leader that already compacted, or one whose LSN is ahead of the leader's gets a
+* CHECKPOINT first, which replaces everything it had, and the writes after it. "REPLICATE"
+* without an LSN always starts with a CHECKPOINT. An idle leader
+* sends an LSN line every HEARTBEAT, so a follower can tell an idle leader from a dead one
+* (nothing for LEADER_TIMEOUT).
+*
+* Checkpoints
+*
+* A checkpoint is the files of the leader's directory as they are, see
+* `LogManager::checkpoint_files`: the snapshot table of its last compaction and its log since,
+* read under the lock of the LogManager so they go together, with the LSN they end at. The follower
+* writes them over its own files and recovers from them like an open does
+* (`LogManager::install_checkpoint`), so the table is loaded as a whole and only the writes
+* since the compaction are replayed, the follower does not rebuild the leader's history a
+* write at a time. The files go as they are, in bytes after their FILE line.
+*
+* Lag
+*
+* How far behind a follower is, its lag, is the LSN of its leader as of the last LSN line
+* minus the LSN it applied: the writes it knows it misses (`FollowerStatus::lag`). A leader
+* says its LSN before the writes it ships, so a follower that has many to apply knows how
+* many. A server of a follower with `FollowerOptions::max_lag` refuses the reads of its clients
+* while the lag is more than that, or while it is not connected to its leader (its lag is
+* unknown), so they go to the leader instead and never read older writes than that (see
+* server.rs, and quorum.rs for a client that does it). The followers take the reads of the
+* leader off it that way, as many as they are fresh enough for.
+*
+* Failures
+*
+* A follower whose connection fails connects again, waiting `ClientOptions::retry_backoff`
+* and twice as long every next time (up to MAX_BACKOFF), and asks for the writes after the
+* last one it applied. Applying a write logs it on the follower first like any write, so the
+* follower never has a write without the ones before it. A follower that died while
+* installing a checkpoint, before its new files were all in place, asks for one again
+* (`LogManager::needs_snapshot`).
+*
+* The LSNs tell how far the logs go, not that they are the same: a leader brought back from a
+* backup, which wrote other things under the LSNs it had already shipped, needs its followers
+* to start over from empty directories. A backup of the leader (see admin.rs) makes a good
+* start for a follower though, it only needs the writes made since.
+*/
+
+use crate::client::{Client, ClientOptions};
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often an idle leader tells its followers its LSN.
+pub const HEARTBEAT: Duration = Duration::from_millis(100);
+
+/// How long a follower waits for its leader to send something before it connects again.
+pub const LEADER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest wait of a follower before it connects again.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+type Db = LogManager<String, String>;
+
+/// The state of a LogManager as of the write `lsn`, see `LogManager::snapshot`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot<K, V> {
+    pub lsn: u64,
+    /// In key order.
+    pub pairs: Vec<(K, V)>,
+    /// The deleted keys the LogManager still tracks, with their deletion time, in key order.
+    pub tombstones: Vec<(K, u64)>,
+}
+
+/// The files of a LogManager as of the write `lsn`, see `LogManager::checkpoint_files`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub lsn: u64,
+    /// The names of the files in the directory, and what they hold.
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+/// What a leader sends a follower, see the top of this file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Shipment {
+    Checkpoint(Checkpoint),
+    Write(u64, String),
+    Heartbeat(u64),
+}
+
+/// Where a follower replicates from.
+#[derive(Clone, Debug)]
+pub struct FollowerOptions {
+    /// Address of the leader's server (server.rs).
+    pub leader: String,
+    /// How to connect to it: TLS, credentials, timeouts, and `retry_backoff` for the wait
+    /// before connecting again, see the top of this file.
+    pub client: ClientOptions,
+    /// With a server (server.rs), refuse the reads of clients when more than this many writes
+    /// behind the leader, see the top of this file. None serves them however far behind.
+    pub max_lag: Option<u64>,
+}
+
+impl FollowerOptions {
+    pub fn new(leader: &str) -> Self {
+        FollowerOptions { leader: leader.to_string(), client: ClientOptions::default(), max_lag: None }
+    }
+}
+
+/// How a Follower is doing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FollowerStatus {
+    /// Whether it is connected to its leader now.
+    pub connected: bool,
+    /// The LSN of the leader, as of the last thing it sent, None until it sent something.
+    pub leader_lsn: Option<u64>,
+    /// How many writes of the leader it misses, see the top of this file. None while it is not
+    /// connected.
+    pub lag: Option<u64>,
+    /// Checkpoints it installed since it started.
+    pub snapshots: u64,
+    /// Why its last connection failed.
+    pub last_error: Option<String>,
+}
+
+/// Keeps a LogManager a copy of a leader's, see the top of this file.
+pub struct Follower {
+    stop: Sender<()>,
+    status: Arc<Mutex<FollowerStatus>>,
+    thread: JoinHandle<()>,
+}
+
+impl Follower {
+    /// Replicate the leader of `options` into `db`, on a thread of its own, until `stop`.
+    pub fn start(db: Arc<Mutex<Db>>, options: FollowerOptions) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let status = Arc::new(Mutex::new(FollowerStatus::default()));
+        let thread = {
+            let status = status.clone();
+            thread::spawn(move || follow(&db, &options, &stopped, &status))
+        };
+        Follower { stop, status, thread }
+    }
+
+    pub fn status(&self) -> FollowerStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// The statistics of the STATS of a follower's server, see admin.rs.
+    pub(crate) fn stats(&self) -> Vec<(String, String)> {
+        let status = self.status();
+        [
+            ("replication_connected", status.connected.to_string()),
+            ("replication_lag", status.lag.map_or("none".to_string(), |lag| lag.to_string())),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+    }
+
+    /// An error if the reads of clients are refused, see the top of this file.
+    pub(crate) fn check_fresh(&self, options: &FollowerOptions) -> Result<()> {
+        let Some(max_lag) = options.max_lag else {
+            return Ok(());
+        };
+        let message = match self.status().lag {
+            Some(lag) if lag <= max_lag => return Ok(()),
+            Some(lag) => format!("the follower is {} writes behind, reads go to {}", lag, options.leader),
+            None => format!("the follower is not connected, reads go to {}", options.leader),
+        };
+        Err(Error::PermissionDenied(message))
+    }
+
+    /// Stop replicating. Once this returns the LogManager is not written anymore.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+fn follow(db: &Mutex<Db>, options: &FollowerOptions, stopped: &Receiver<()>, status: &Mutex<FollowerStatus>) {
+    let mut backoff = options.client.retry_backoff;
+    loop {
+        match follow_connection(db, options, stopped, status, &mut backoff) {
+            Ok(()) => return,
+            Err(e) => {
+                eprintln!("Replication from {} failed ({}), again in {:?}", options.leader, e, backoff);
+                let mut status = status.lock().unwrap();
+                status.connected = false;
+                status.lag = None;
+                status.last_error = Some(e.to_string());
+            }
+        }
+        if !matches!(stopped.recv_timeout(backoff), Err(RecvTimeoutError::Timeout)) {
+            return;
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Replicate over one connection, until it fails or the follower is stopped (Ok)
+fn follow_connection(
+    db: &Mutex<Db>,
+    options: &FollowerOptions,
+    stopped: &Receiver<()>,
+    status: &Mutex<FollowerStatus>,
+    backoff: &mut Duration,
+) -> Result<()> {
+    let (from, mut applied) = {
+        let db = db.lock().unwrap();
+        ((!db.needs_snapshot()).then(|| db.lsn()), db.lsn())
+    };
+    let client = Client::connect_with(options.leader.as_str(), options.client.clone())?;
+    let mut replication = client.replicate(from)?;
+    *backoff = options.client.retry_backoff;
+    status.lock().unwrap().connected = true;
+
+    while matches!(stopped.try_recv(), Err(TryRecvError::Empty)) {
+        let shipment = replication.recv()?;
+        // the leader's LSN, at least as far as the writes it sent
+        let leader_lsn = match shipment {
+            Shipment::Checkpoint(checkpoint) => {
+                applied = checkpoint.lsn;
+                db.lock().unwrap().install_checkpoint(checkpoint)?;
+                status.lock().unwrap().snapshots += 1;
+                applied
+            }
+            Shipment::Write(lsn, record) => {
+                db.lock().unwrap().apply_wal(lsn, &record)?;
+                applied = lsn;
+                status.lock().unwrap().leader_lsn.map_or(lsn, |leader_lsn| leader_lsn.max(lsn))
+            }
+            Shipment::Heartbeat(lsn) => lsn,
+        };
+        let mut status = status.lock().unwrap();
+        status.leader_lsn = Some(leader_lsn);
+        status.lag = Some(leader_lsn.saturating_sub(applied));
+    }
+    Ok(())
+}
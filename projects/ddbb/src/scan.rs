@@ -0,0 +1,98 @@
+// src/scan.rs
+
+/*
+* Paginated scans
+*
+* A web handler listing a large range returns it a page at a time, and keeps no state between
+* requests. `LogManager::scan` takes the page size (`limit`) and where to start, and returns
+* the page along with a resume token for the next one:
+*
+*   page 1:  scan(range, limit 3)                  -> a b c,  token after "c"
+*   page 2:  scan(range, limit 3, resume token)    -> d e f,  token after "f"
+*   page 3:  scan(range, limit 3, resume token)    -> g,      no token, the range is done
+*
+* `LogManager::scan_rev` is the same from the end of the range down, for "newest first"
+* listings, its pages go in descending key order.
+*
+* The token holds the last key of the page (its text form in hex, with a checksum so a token
+* that was cut or made up is rejected), and the next scan starts right after that key (right
+* before it for scan_rev). It goes down the tree to that key like any range scan, nothing of
+* the earlier pages is looked at again. Since it names a key and not a position, pairs inserted
+* or deleted between two requests do not shift the pages: nothing is returned twice or skipped,
+* apart from the changes themselves.
+*
+* `offset` skips that many pairs before the page starts. It is there for "jump to page n" and
+* does walk the skipped pairs (without copying them), resume tokens are the cheap way forward.
+*
+* A token only names a key, it is not tied to the range it came from: given with another range
+* the scan resumes after that key in that range.
+*/
+
+use crate::error::{Error, Result};
+use std::fmt::Debug;
+use std::ops::Bound;
+use std::str::FromStr;
+
+/// What part of a range `LogManager::scan` returns, see the top of this file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// At most this many pairs, None for all of them.
+    pub limit: Option<usize>,
+    /// Pairs to skip first.
+    pub offset: usize,
+    /// Start after the key this token (`Page::next` of the previous page) was made from, before
+    /// it for a reverse scan.
+    pub resume: Option<String>,
+}
+
+/// One page of a scan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Page<K, V> {
+    pub pairs: Vec<(K, V)>,
+    /// Token to pass as `ScanOptions::resume` for the next page, None if the range is done.
+    pub next: Option<String>,
+}
+
+pub(crate) fn resume_token(last_key: &str) -> String {
+    let mut token: String = last_key.bytes().map(|b| format!("{:02x}", b)).collect();
+    token.push_str(&format!("{:08x}", crc32fast::hash(last_key.as_bytes())));
+    token
+}
+
+/// The key a token was made from.
+pub(crate) fn resume_key<K: FromStr>(token: &str) -> Result<K>
+where
+    <K as FromStr>::Err: Debug,
+{
+    let bad = || Error::InvalidArgument(format!("invalid resume token {:?}", token));
+    if token.len() < 8 || !token.len().is_multiple_of(2) || !token.is_ascii() {
+        return Err(bad());
+    }
+    let (hex, checksum) = token.split_at(token.len() - 8);
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| bad()))
+        .collect::<Result<Vec<u8>>>()?;
+    if u32::from_str_radix(checksum, 16).ok() != Some(crc32fast::hash(&bytes)) {
+        return Err(bad());
+    }
+    String::from_utf8(bytes).map_err(|_| bad())?.parse().map_err(|_| bad())
+}
+
+/// The later of the start of a range and "right after `key`".
+pub(crate) fn start_after<K: Ord + Clone>(start: Bound<&K>, key: K) -> Bound<K> {
+    match start {
+        Bound::Included(start) if *start > key => Bound::Included(start.clone()),
+        Bound::Excluded(start) if *start >= key => Bound::Excluded(start.clone()),
+        _ => Bound::Excluded(key),
+    }
+}
+
+/// The earlier of the end of a range and "right before `key`".
+pub(crate) fn end_before<K: Ord + Clone>(end: Bound<&K>, key: K) -> Bound<K> {
+    match end {
+        Bound::Included(end) if *end < key => Bound::Included(end.clone()),
+        Bound::Excluded(end) if *end <= key => Bound::Excluded(end.clone()),
+        _ => Bound::Excluded(key),
+    }
+}
@@ -0,0 +1,817 @@
+// src/server.rs
+
+/*
+* TCP server
+*
+* `Server` serves a LogManager with String keys and values over TCP, so programs that are not
+* written in Rust, or run on other machines, can use the store. `ddbb <dir> serve <address>`
+* runs one (see main.rs).
+*
+* Protocol
+*
+* A connection carries lines of text, both ways. Every request is one line holding a query of
+* query.rs, and gets one response, in the order the requests came in:
+*
+*   request                          response
+*   GET <key>                        VALUE <value>       or  NOT_FOUND
+*   SET <key> <value>                OK
+*   DEL <key>                        OK
+*   SCAN <keys> [LIMIT <n>] ...      PAIRS <n> [NEXT <token>], then n lines "<key> <value>"
+*   COUNT <keys>                     COUNT <n>
+*   QUIT                             OK, then the server closes the connection
+*   AUTH <credentials>               OK, see auth.rs
+*   MULTI                            OK, then SET and DEL are queued (see below)
+*   EXEC                             OK once the queued writes are applied
+*   DISCARD                          OK, the queued writes are dropped
+*   PREPARE <id>                     OK once the queued writes are prepared as <id>
+*   COMMIT <id>, ABORT <id>          OK once the batch prepared as <id> is applied or dropped,
+*                                    and that is synced
+*   PREPARED                         PAIRS <n>, then n lines "<id> <writes>"
+*   COMPACT, FLUSH, BACKUP <dir>     OK, see admin.rs
+*   STATS                            PAIRS <n>, then n lines "<name> <value>"
+*   SUBSCRIBE [<prefix>]             OK, then EVENT lines (see below)
+*   REPLICATE [<lsn>]                OK, then the writes of the log, see replication.rs
+*   RAFT <message>                   RAFT <reply>, between the nodes of raft.rs
+*   GOSSIP <digests>                 GOSSIP <digests>, between the nodes of membership.rs
+*   CLUSTER                          PAIRS <n>, then n lines "<address> <state>"
+*
+* A request that fails gets "ERR <message>" (the message is the `Error` as printed, on one
+* line) and the connection stays usable. A request line longer than MAX_LINE bytes, or one
+* that is not UTF-8, gets an ERR and the connection is closed, there is no telling where the
+* next request starts. Lines end with "\n", a "\r\n" from a telnet session is accepted too.
+*
+* Since keys and values cannot hold whitespace (see log.rs), every field of a response is one
+* token and a client can split the lines on spaces.
+*
+* Pipelining and batches
+*
+* A client does not have to wait for a response before it sends the next request: the server
+* reads them in order and answers them in order, and it only flushes its responses when it has
+* read every request that came in, so a burst of requests gets its responses in a burst too.
+*
+* Between MULTI and EXEC the SETs and DELs of a connection are answered with QUEUED and kept
+* aside, and EXEC applies them together as one `WriteBatch` (see batch.rs): all of them or, if
+* one breaks a unique index, none. Any other query in there, or a request that fails (does not
+* parse, is not allowed by the Acl), gets its ERR and makes the EXEC fail too, without applying
+* anything, so a client pipelining MULTI ... EXEC never gets half of a batch (a second MULTI
+* is only refused). DISCARD, or the end of the connection, drops the queued writes.
+*
+* PREPARE <id> in place of EXEC prepares the queued writes as <id> instead of applying them
+* (see `LogManager::prepare`), the first phase of a two-phase commit across servers (see
+* shard.rs). COMMIT and ABORT end it, from any connection, and PREPARED lists the batches
+* waiting for one of them; the three need the access of the commands of admin.rs. A follower
+* refuses PREPARE, COMMIT and ABORT like any write, and a Raft node refuses them too: its
+* writes go through the Raft log, which has no prepared batches.
+*
+* Subscriptions
+*
+* After "SUBSCRIBE user:" (no prefix for every key) the connection gets a line for every write
+* of a key that starts with the prefix, from any connection, as it happens (see watch.rs):
+*
+*   EVENT SET <key> <value>
+*   EVENT DEL <key>
+*
+* and takes no other request than UNSUBSCRIBE (OK after the last event, then the connection is
+* back to requests) and QUIT. A subscriber that falls too far behind gets "ERR I/O error: ..."
+* instead of the events it missed, and the connection is back to requests too: it has to SCAN
+* the keys again before subscribing again. With authentication the prefix must be one the Acl
+* could SCAN.
+*
+* Connections
+*
+* Every connection has a thread of its own, reading a request, running it and writing the
+* response before reading the next one. The threads share the LogManager behind a mutex, held
+* for the one query only, so requests from different connections are applied one at a time,
+* in the order they get the lock, and a slow client (or one that does not read its responses)
+* only holds up its own thread.
+*
+* A subscription keeps the thread of its connection too, looking for events and for requests
+* in turn, every SUBSCRIPTION_POLL.
+*
+* A REPLICATE keeps the thread of its connection for good, looking for new writes to ship and
+* for a QUIT every REPLICATION_POLL. It takes no other request, and needs the access of the
+* commands of admin.rs.
+*
+* With `ServerOptions::auth` a connection has to AUTH before its first query, and every query
+* is checked against the Acl of its credentials before it runs (see auth.rs). With
+* `ServerOptions::tls` every connection is TLS, see tls.rs. The handshake is done on the
+* thread of the connection, a client that does not finish it only holds up its own thread too.
+*
+* With `ServerOptions::follow` the server is a follower (see replication.rs): it replicates its
+* leader into the LogManager for as long as it serves, and refuses the writes of its clients
+* with a PermissionDenied, they go to the leader. With `FollowerOptions::max_lag` it refuses
+* the reads the same way while it is too far behind. Its STATS have the replication_ lines of
+* `Follower::stats` too. The HTTP server of http.rs does the same.
+*
+* With `ServerOptions::raft` the server is a node of a Raft cluster (see raft.rs): it takes
+* part in the cluster for as long as it serves, and the RAFT requests of the other nodes (with
+* the access of the commands of admin.rs). The writes of its clients go through the Raft log
+* if it is the leader, and are refused with a PermissionDenied naming the leader if it is not.
+* Its STATS have the raft_ lines of `Raft::stats` too. The HTTP server does the same.
+*
+* With `ServerOptions::membership` the server is a member of a cluster (see membership.rs),
+* as well as anything else it is: it gossips with the other members for as long as it serves,
+* answers their GOSSIP requests (with the access of the commands of admin.rs, like RAFT) and
+* CLUSTER with the members it knows and their state. Its STATS have the cluster_ lines of
+* `Membership::stats` too. The HTTP server does the same, the other members reach it over the
+* TCP protocol only.
+*
+* Shutdown
+*
+* `serve` runs until `ShutdownHandle::shutdown` (from another thread, main.rs calls it on
+* SIGTERM and SIGINT). The server then stops accepting connections, stops reading requests,
+* lets every connection finish the ones it already read and write their responses, and waits
+* for the threads to end, and a follower stops replicating, a Raft node taking part, a member
+* gossiping. Last it shuts the LogManager down (`LogManager::shutdown`: the log is synced and
+* compacted into a snapshot, the directory unlocked), so the next open starts from a clean
+* checkpoint instead of replaying the log. `serve` returns the error of that, if any. The HTTP
+* server of http.rs stops the same way.
+*/
+
+use crate::admin::{self, Admin};
+use crate::auth::{Acl, Auth, Credentials};
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use crate::membership::{Membership, MembershipOptions};
+use crate::query::{self, Keys, Query, QueryResult};
+use crate::raft::{Raft, RaftOptions};
+use crate::replication::{self, Follower, FollowerOptions, Shipment};
+use crate::tls::{ServerTls, Stream};
+use crate::watch::{Event, Watch, WATCH_CAPACITY};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Longest request line accepted, in bytes.
+pub const MAX_LINE: usize = 1 << 20;
+
+/// How long a subscription waits for events before it looks for requests.
+pub const SUBSCRIPTION_POLL: Duration = Duration::from_millis(50);
+
+/// How often a REPLICATE looks for new writes, and for requests.
+pub const REPLICATION_POLL: Duration = Duration::from_millis(10);
+
+type Db = LogManager<String, String>;
+
+/// Options of the servers of this file and of http.rs.
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    /// Accept TLS connections only, with this certificate and key.
+    pub tls: Option<ServerTls>,
+    /// Serve only the clients with these credentials, what their Acl allows.
+    pub auth: Option<Auth>,
+    /// Be a follower of this leader, and refuse the writes of clients, see the top of this file.
+    pub follow: Option<FollowerOptions>,
+    /// Be a node of this Raft cluster, see the top of this file.
+    pub raft: Option<RaftOptions>,
+    /// Be a member of this cluster, see the top of this file.
+    pub membership: Option<MembershipOptions>,
+}
+
+pub struct Server {
+    listener: TcpListener,
+    db: Arc<Mutex<Db>>,
+    options: Arc<ServerOptions>,
+    shutdown: ShutdownHandle,
+}
+
+/// Stops the `serve` of a server from another thread, see the top of this file.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    stopping: Arc<AtomicBool>,
+    // where to connect to wake up the accepting thread
+    address: SocketAddr,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(listener: &TcpListener) -> Result<Self> {
+        let mut address = listener.local_addr()?;
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        Ok(ShutdownHandle { stopping: Arc::new(AtomicBool::new(false)), address })
+    }
+
+    /// Have the server stop, the `serve` running (or the next one) returns once it did.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // if the server is not listening anymore there is nothing to wake up
+        let _ = TcpStream::connect(self.address);
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// What a server runs next to its LogManager, the follower, the Raft node and the membership
+/// of its `ServerOptions`, for its connections to ask.
+#[derive(Default)]
+pub(crate) struct Node {
+    pub(crate) raft: Option<Arc<Raft>>,
+    pub(crate) follower: Option<Follower>,
+    pub(crate) membership: Option<Arc<Membership>>,
+}
+
+impl Node {
+    /// The statistics of STATS after the ones of admin.rs.
+    pub(crate) fn stats(&self) -> Vec<(String, String)> {
+        let raft = self.raft.iter().flat_map(|raft| raft.stats());
+        let follower = self.follower.iter().flat_map(Follower::stats);
+        let membership = self.membership.iter().flat_map(|membership| membership.stats());
+        raft.chain(follower).chain(membership).collect()
+    }
+}
+
+/// Accept connections and serve each of them with `serve` on a thread of its own, until
+/// `shutdown` says to stop. Then drain the connections and shut `db` down, see the top of this
+/// file. The accept loop of both servers, which also runs the follower, the Raft node and the
+/// membership of `options`, and gives `serve` the node.
+pub(crate) fn serve_until_shutdown(
+    listener: &TcpListener,
+    shutdown: &ShutdownHandle,
+    db: &Arc<Mutex<Db>>,
+    options: &ServerOptions,
+    serve: impl Fn(TcpStream, &Node) + Clone + Send + 'static,
+) -> Result<()> {
+    if options.follow.is_some() && options.raft.is_some() {
+        return Err(Error::InvalidArgument("a server is a follower or a Raft node, not both".to_string()));
+    }
+    let raft = options.raft.as_ref().map(|raft| Raft::start(db.clone(), raft.clone())).transpose()?;
+    let follower = options.follow.as_ref().map(|follow| Follower::start(db.clone(), follow.clone()));
+    let membership = options.membership.as_ref().map(|membership| Membership::start(membership.clone()));
+    let node = Arc::new(Node { raft, follower, membership });
+    // a second handle on every connection being served, to stop its reads
+    let connections: Arc<Mutex<HashMap<u64, TcpStream>>> = Arc::default();
+    let mut threads: Vec<JoinHandle<()>> = Vec::new();
+    for (id, stream) in (0..).zip(listener.incoming()) {
+        if shutdown.is_stopping() {
+            break;
+        }
+        let stream = match stream.and_then(|stream| Ok((stream.try_clone()?, stream))) {
+            Ok(streams) => streams,
+            Err(e) => {
+                // a connection that failed before being accepted, not the listener
+                eprintln!("Cannot accept a connection: {}", e);
+                continue;
+            }
+        };
+        let (handle, stream) = stream;
+        connections.lock().unwrap().insert(id, handle);
+        threads.retain(|thread| !thread.is_finished());
+        let (serve, connections, node) = (serve.clone(), connections.clone(), node.clone());
+        threads.push(thread::spawn(move || {
+            serve(stream, &node);
+            // the connection is closed once the last handle is gone
+            connections.lock().unwrap().remove(&id);
+        }));
+    }
+
+    eprintln!("Shutting down, waiting for {} connections", connections.lock().unwrap().len());
+    for stream in connections.lock().unwrap().values() {
+        // the reads of the connection end there, the writes still go out
+        let _ = stream.shutdown(Shutdown::Read);
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+    let Ok(node) = Arc::try_unwrap(node) else {
+        unreachable!("the threads of the connections are done");
+    };
+    if let Some(follower) = node.follower {
+        follower.stop();
+    }
+    if let Some(raft) = node.raft {
+        raft.stop();
+    }
+    if let Some(membership) = node.membership {
+        membership.stop();
+    }
+    db.lock().unwrap().shutdown()
+}
+
+impl Server {
+    /// Listen on `address` (port 0 picks a free port, see `local_addr`) for clients of `db`.
+    pub fn bind(address: impl ToSocketAddrs, db: Db) -> Result<Self> {
+        Self::bind_with(address, db, ServerOptions::default())
+    }
+
+    /// Same as `bind`, with non-default `ServerOptions`.
+    pub fn bind_with(address: impl ToSocketAddrs, db: Db, options: ServerOptions) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let shutdown = ShutdownHandle::new(&listener)?;
+        Ok(Server { listener, db: Arc::new(Mutex::new(db)), options: Arc::new(options), shutdown })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accept connections and serve each of them on a thread of its own, until shut down.
+    pub fn serve(&self) -> Result<()> {
+        let (db, options) = (self.db.clone(), self.options.clone());
+        serve_until_shutdown(&self.listener, &self.shutdown, &self.db, &self.options, move |stream, node| {
+            let peer = stream.peer_addr().map_or("unknown peer".to_string(), |peer| peer.to_string());
+            let stream = Stream::accept(stream, options.tls.as_ref());
+            if let Err(e) = stream.and_then(|stream| serve_connection(stream, &db, &options, node)) {
+                eprintln!("Connection from {} failed: {}", peer, e);
+            }
+        })
+    }
+}
+
+// What the server knows of a connection
+struct Session<'a> {
+    db: &'a Mutex<Db>,
+    options: &'a ServerOptions,
+    node: &'a Node,
+    // what the connection may do, None until it authenticates
+    acl: Option<Acl>,
+    // the writes queued since MULTI, and whether a request in there failed
+    multi: Option<(Vec<Query>, bool)>,
+}
+
+enum Response {
+    Result(QueryResult),
+    Queued,
+    Subscribed(Watch<String, String>),
+    // the LSN the follower has, None for a checkpoint
+    Replicating(Option<u64>),
+    Raft(String),
+    Gossip(String),
+}
+
+fn serve_connection(
+    stream: Stream,
+    db: &Mutex<Db>,
+    options: &ServerOptions,
+    node: &Node,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut line = String::new();
+    let acl = match options.auth {
+        Some(_) => None,
+        None => Some(Acl::default()),
+    };
+    let mut session = Session { db, options, node, acl, multi: None };
+    loop {
+        line.clear();
+        let read = match reader.by_ref().take(MAX_LINE as u64 + 1).read_line(&mut line) {
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                write_error(&mut writer, &Error::InvalidArgument("the request is not UTF-8".to_string()))?;
+                return Ok(writer.flush()?);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if read == 0 {
+            return Ok(()); // the client closed the connection
+        }
+        if read > MAX_LINE {
+            let message = format!("the request is longer than {} bytes", MAX_LINE);
+            write_error(&mut writer, &Error::InvalidArgument(message))?;
+            return Ok(writer.flush()?);
+        }
+
+        let request = line.trim_end_matches(['\r', '\n']);
+        if request.trim().eq_ignore_ascii_case("QUIT") {
+            writer.write_all(b"OK\n")?;
+            return Ok(writer.flush()?);
+        }
+        match session.dispatch(request) {
+            Ok(Response::Result(result)) => write_result(&mut writer, &result)?,
+            Ok(Response::Queued) => writer.write_all(b"QUEUED\n")?,
+            Ok(Response::Raft(reply)) => writeln!(writer, "RAFT {}", reply)?,
+            Ok(Response::Gossip(reply)) => writeln!(writer, "GOSSIP {}", reply)?,
+            Ok(Response::Subscribed(watch)) => {
+                writer.write_all(b"OK\n")?;
+                writer.flush()?;
+                if !serve_subscription(&mut reader, &mut writer, &watch)? {
+                    return Ok(());
+                }
+            }
+            Ok(Response::Replicating(from)) => {
+                writer.write_all(b"OK\n")?;
+                writer.flush()?;
+                return serve_replication(&mut reader, &mut writer, db, from);
+            }
+            Err(e) => write_error(&mut writer, &e)?,
+        }
+        // the responses to pipelined requests go out together, see the top of this file
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+impl Session<'_> {
+    // Run one request other than QUIT
+    fn dispatch(&mut self, request: &str) -> Result<Response> {
+        let fields: Vec<&str> = request.split_whitespace().collect();
+        let (command, arguments) = fields.split_first().unwrap_or((&"", &[]));
+        match (command.to_ascii_uppercase().as_str(), arguments) {
+            ("AUTH", credentials) => {
+                let no_auth = || Error::InvalidArgument("the server has no authentication".to_string());
+                let auth = self.options.auth.as_ref().ok_or_else(no_auth)?;
+                self.acl = None;
+                let credentials = Credentials::from_fields(credentials)?;
+                self.acl = Some(auth.authenticate(&credentials)?.clone());
+                Ok(Response::Result(QueryResult::Done))
+            }
+            ("SUBSCRIBE", [] | [_]) if self.multi.is_none() => {
+                let prefix = arguments.first().copied().unwrap_or_default();
+                self.allowed()?.check_keys(&Keys::Prefix(prefix.to_string()))?;
+                Ok(Response::Subscribed(self.db.lock().unwrap().watch(prefix)))
+            }
+            ("SUBSCRIBE", _) => {
+                Err(Error::InvalidArgument("SUBSCRIBE takes a prefix, or nothing for every key".to_string()))
+            }
+            ("REPLICATE", [] | [_]) if self.multi.is_none() => {
+                self.allowed()?.check_admin()?;
+                let bad = |lsn: &str| Error::InvalidArgument(format!("bad LSN {}", lsn));
+                let from = arguments.first().map(|lsn| lsn.parse::<u64>().map_err(|_| bad(lsn)));
+                Ok(Response::Replicating(from.transpose()?))
+            }
+            ("REPLICATE", _) => {
+                Err(Error::InvalidArgument("REPLICATE takes an LSN, or nothing for a checkpoint".to_string()))
+            }
+            ("RAFT", [_, ..]) if self.multi.is_none() => {
+                self.allowed()?.check_admin()?;
+                let no_raft = || Error::InvalidArgument("the server is not a Raft node".to_string());
+                let raft = self.node.raft.as_deref().ok_or_else(no_raft)?;
+                let message = request.trim_start()[command.len()..].trim();
+                Ok(Response::Raft(raft.handle(message)?))
+            }
+            ("GOSSIP", [_, ..]) if self.multi.is_none() => {
+                self.allowed()?.check_admin()?;
+                let message = request.trim_start()[command.len()..].trim();
+                Ok(Response::Gossip(self.membership()?.handle(message)?))
+            }
+            ("CLUSTER", []) if self.multi.is_none() => {
+                self.allowed()?.check_admin()?;
+                let members = self.membership()?.members().into_iter();
+                let pairs = members.map(|member| (member.address, member.liveness.to_string())).collect();
+                Ok(Response::Result(QueryResult::Pairs { pairs, next: None }))
+            }
+            ("MULTI", []) if self.multi.is_none() => {
+                self.allowed()?;
+                self.multi = Some((Vec::new(), false));
+                Ok(Response::Result(QueryResult::Done))
+            }
+            ("MULTI", []) => Err(Error::InvalidArgument("MULTI inside MULTI".to_string())),
+            ("EXEC", []) => {
+                let (queries, failed) = self.multi.take().ok_or_else(|| outside_multi("EXEC"))?;
+                if failed {
+                    let message = "the batch had errors, nothing was applied";
+                    return Err(Error::InvalidArgument(message.to_string()));
+                }
+                let result = match self.node.raft.as_deref() {
+                    Some(raft) => raft.write(&queries)?,
+                    None => query::execute_batch(&mut self.db.lock().unwrap(), &queries)?,
+                };
+                Ok(Response::Result(result))
+            }
+            ("PREPARE", [id]) => {
+                let (queries, failed) = self.multi.take().ok_or_else(|| outside_multi("PREPARE"))?;
+                if failed {
+                    let message = "the batch had errors, nothing was prepared";
+                    return Err(Error::InvalidArgument(message.to_string()));
+                }
+                self.check_prepared_batches()?;
+                self.db.lock().unwrap().prepare(id, query::batch(&queries)?)?;
+                Ok(Response::Result(QueryResult::Done))
+            }
+            ("COMMIT" | "ABORT", [id]) if self.multi.is_none() => {
+                self.allowed()?.check_admin()?;
+                self.check_prepared_batches()?;
+                check_leader(self.options)?;
+                let mut db = self.db.lock().unwrap();
+                if command.eq_ignore_ascii_case("COMMIT") {
+                    db.commit_prepared_durable(id)?;
+                } else {
+                    db.abort_prepared_durable(id)?;
+                }
+                Ok(Response::Result(QueryResult::Done))
+            }
+            ("PREPARED", []) if self.multi.is_none() => {
+                self.allowed()?.check_admin()?;
+                let prepared = self.db.lock().unwrap().prepared().into_iter();
+                let pairs = prepared.map(|(id, writes)| (id, writes.to_string())).collect();
+                Ok(Response::Result(QueryResult::Pairs { pairs, next: None }))
+            }
+            ("DISCARD", []) => {
+                self.multi.take().ok_or_else(|| outside_multi("DISCARD"))?;
+                Ok(Response::Result(QueryResult::Done))
+            }
+            _ if self.multi.is_some() => {
+                let queued = self.queue(request);
+                if queued.is_err() {
+                    self.multi.as_mut().expect("inside MULTI").1 = true;
+                }
+                queued
+            }
+            _ => {
+                if let Some(admin) = Admin::parse(request)? {
+                    self.allowed()?.check_admin()?;
+                    let mut result = admin::execute(&mut self.db.lock().unwrap(), &admin)?;
+                    if let (Admin::Stats, QueryResult::Pairs { pairs, .. }) = (&admin, &mut result) {
+                        pairs.extend(self.node.stats());
+                    }
+                    return Ok(Response::Result(result));
+                }
+                let query = query::parse(request)?;
+                self.allowed()?.check(&query)?;
+                Ok(Response::Result(execute(self.db, self.options, self.node, &query)?))
+            }
+        }
+    }
+
+    fn queue(&mut self, request: &str) -> Result<Response> {
+        if let Some(admin) = Admin::parse(request)? {
+            return Err(Error::InvalidArgument(format!("only SET and DEL can be queued, not {}", admin)));
+        }
+        let query = query::parse(request)?;
+        self.allowed()?.check(&query)?;
+        check_writable(self.options, &query)?;
+        if !matches!(query, Query::Set(..) | Query::Del(_)) {
+            return Err(Error::InvalidArgument(format!("only SET and DEL can be queued, not {}", query)));
+        }
+        self.multi.as_mut().expect("inside MULTI").0.push(query);
+        Ok(Response::Queued)
+    }
+
+    fn allowed(&self) -> Result<&Acl> {
+        self.acl.as_ref().ok_or_else(|| Error::PermissionDenied("AUTH first".to_string()))
+    }
+
+    // An InvalidArgument on a Raft node, see the top of this file
+    fn check_prepared_batches(&self) -> Result<()> {
+        if self.node.raft.is_some() {
+            let message = "a Raft node has no prepared batches, its writes go through the Raft log";
+            return Err(Error::InvalidArgument(message.to_string()));
+        }
+        Ok(())
+    }
+
+    fn membership(&self) -> Result<&Membership> {
+        let none = || Error::InvalidArgument("the server is not a member of a cluster".to_string());
+        self.node.membership.as_deref().ok_or_else(none)
+    }
+}
+
+// Push the events of `watch` until the client sends UNSUBSCRIBE (true) or QUIT or goes away
+// (false), see the top of this file
+fn serve_subscription(
+    reader: &mut BufReader<Stream>,
+    writer: &mut BufWriter<Stream>,
+    watch: &Watch<String, String>,
+) -> Result<bool> {
+    // a look for requests only takes a moment, the wait is on the events
+    reader.get_ref().set_read_timeout(Some(Duration::from_millis(1)))?;
+    let subscribed = push_events(reader, writer, watch);
+    reader.get_ref().set_read_timeout(None)?;
+    subscribed
+}
+
+fn push_events(
+    reader: &mut BufReader<Stream>,
+    writer: &mut BufWriter<Stream>,
+    watch: &Watch<String, String>,
+) -> Result<bool> {
+    let mut line = String::new();
+    loop {
+        match watch.recv_timeout(SUBSCRIPTION_POLL) {
+            Ok(event) => {
+                write_event(writer, &event)?;
+                while let Some(event) = watch.try_recv() {
+                    write_event(writer, &event)?;
+                }
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                let message = format!("the subscription fell more than {} events behind", WATCH_CAPACITY);
+                write_error(writer, &Error::Io(io::Error::other(message)))?;
+                writer.flush()?;
+                return Ok(true);
+            }
+        }
+
+        match poll_request(reader, writer, &mut line)? {
+            Incoming::Nothing => {}
+            Incoming::Closed => return Ok(false),
+            Incoming::Request(request) if request == "UNSUBSCRIBE" || request == "QUIT" => {
+                writer.write_all(b"OK\n")?;
+                writer.flush()?;
+                return Ok(request == "UNSUBSCRIBE");
+            }
+            Incoming::Request(_) => {
+                let message = "only UNSUBSCRIBE and QUIT are taken while subscribed";
+                write_error(writer, &Error::InvalidArgument(message.to_string()))?;
+                writer.flush()?;
+            }
+        }
+    }
+}
+
+// Ship the writes after `from` (or a checkpoint first, see replication.rs) until the follower
+// sends QUIT or goes away
+fn serve_replication(
+    reader: &mut BufReader<Stream>,
+    writer: &mut BufWriter<Stream>,
+    db: &Mutex<Db>,
+    from: Option<u64>,
+) -> Result<()> {
+    // the wait for requests is the wait between two looks for writes
+    reader.get_ref().set_read_timeout(Some(REPLICATION_POLL))?;
+    let mut line = String::new();
+    let mut shipped = from;
+    let mut last_sent = Instant::now();
+    loop {
+        let shipments = {
+            let mut db = db.lock().unwrap();
+            match shipped.and_then(|lsn| db.wal_since(lsn)) {
+                Some(writes) if writes.is_empty() && last_sent.elapsed() >= replication::HEARTBEAT => {
+                    vec![Shipment::Heartbeat(db.lsn())]
+                }
+                Some(writes) => {
+                    // how far the leader is, for the lag of the follower
+                    let writes = writes.into_iter().map(|(lsn, record)| Shipment::Write(lsn, record));
+                    std::iter::once(Shipment::Heartbeat(db.lsn())).chain(writes).collect()
+                }
+                None => vec![Shipment::Checkpoint(db.checkpoint_files()?)],
+            }
+        };
+        for shipment in &shipments {
+            write_shipment(writer, shipment)?;
+            match shipment {
+                Shipment::Write(lsn, _) => shipped = Some(*lsn),
+                Shipment::Checkpoint(checkpoint) => shipped = Some(checkpoint.lsn),
+                Shipment::Heartbeat(_) => {}
+            }
+        }
+        if !shipments.is_empty() {
+            writer.flush()?;
+            last_sent = Instant::now();
+        }
+
+        match poll_request(reader, writer, &mut line)? {
+            Incoming::Nothing => {}
+            Incoming::Closed => return Ok(()),
+            Incoming::Request(request) if request == "QUIT" => {
+                writer.write_all(b"OK\n")?;
+                return Ok(writer.flush()?);
+            }
+            Incoming::Request(_) => {
+                let message = "only QUIT is taken while replicating";
+                write_error(writer, &Error::InvalidArgument(message.to_string()))?;
+                writer.flush()?;
+            }
+        }
+    }
+}
+
+// What the client of a connection that pushes lines to it (a subscription, a replication) sent
+// in the meantime
+enum Incoming {
+    Nothing,
+    // in upper case
+    Request(String),
+    Closed,
+}
+
+// Look for a request, waiting no longer than the read timeout of the connection. A request may
+// come in a bit at a time, `line` keeps what there is of it.
+fn poll_request(
+    reader: &mut BufReader<Stream>,
+    writer: &mut BufWriter<Stream>,
+    line: &mut String,
+) -> Result<Incoming> {
+    let limit = (MAX_LINE + 1).saturating_sub(line.len()) as u64;
+    match reader.by_ref().take(limit).read_line(line) {
+        Ok(0) => Ok(Incoming::Closed),
+        Ok(_) if !line.ends_with('\n') => {
+            // the end of the connection, or a line that is too long
+            if line.len() > MAX_LINE {
+                let message = format!("the request is longer than {} bytes", MAX_LINE);
+                write_error(writer, &Error::InvalidArgument(message))?;
+                writer.flush()?;
+            }
+            Ok(Incoming::Closed)
+        }
+        Ok(_) => {
+            let request = line.trim().to_ascii_uppercase();
+            line.clear();
+            Ok(Incoming::Request(request))
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(Incoming::Nothing),
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            write_error(writer, &Error::InvalidArgument("the request is not UTF-8".to_string()))?;
+            writer.flush()?;
+            Ok(Incoming::Closed)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_event(out: &mut impl Write, event: &Event<String, String>) -> Result<()> {
+    match event {
+        Event::Set(key, value) => writeln!(out, "EVENT SET {} {}", key, value)?,
+        Event::Delete(key) => writeln!(out, "EVENT DEL {}", key)?,
+    }
+    Ok(())
+}
+
+fn write_shipment(out: &mut impl Write, shipment: &Shipment) -> Result<()> {
+    match shipment {
+        Shipment::Write(lsn, record) => writeln!(out, "WAL {} {}", lsn, record)?,
+        Shipment::Heartbeat(lsn) => writeln!(out, "LSN {}", lsn)?,
+        Shipment::Checkpoint(checkpoint) => {
+            writeln!(out, "CHECKPOINT {} {}", checkpoint.lsn, checkpoint.files.len())?;
+            for (name, content) in &checkpoint.files {
+                writeln!(out, "FILE {} {}", name, content.len())?;
+                out.write_all(content)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the query of a client: a write is refused by a follower and goes through the Raft log
+/// on a Raft node, a read is refused by a follower too far behind, see the top of this file.
+pub(crate) fn execute(
+    db: &Mutex<Db>,
+    options: &ServerOptions,
+    node: &Node,
+    query: &Query,
+) -> Result<QueryResult> {
+    check_writable(options, query)?;
+    match &node.raft {
+        Some(raft) if matches!(query, Query::Set(..) | Query::Del(_)) => {
+            raft.write(std::slice::from_ref(query))
+        }
+        _ => {
+            if let (Some(follower), Some(follow)) = (&node.follower, &options.follow) {
+                follower.check_fresh(follow)?;
+            }
+            query::execute(&mut db.lock().unwrap(), query)
+        }
+    }
+}
+
+/// A PermissionDenied for a write to a follower, see the top of this file.
+fn check_writable(options: &ServerOptions, query: &Query) -> Result<()> {
+    match query {
+        Query::Set(..) | Query::Del(_) => check_leader(options),
+        _ => Ok(()),
+    }
+}
+
+fn check_leader(options: &ServerOptions) -> Result<()> {
+    if options.follow.is_some() {
+        return Err(Error::PermissionDenied("the server is a follower, writes go to its leader".to_string()));
+    }
+    Ok(())
+}
+
+fn outside_multi(command: &str) -> Error {
+    Error::InvalidArgument(format!("{} without MULTI", command))
+}
+
+/// Write the response to a query, see the top of this file.
+pub(crate) fn write_result(out: &mut impl Write, result: &QueryResult) -> Result<()> {
+    match result {
+        QueryResult::Value(Some(value)) => writeln!(out, "VALUE {}", value)?,
+        QueryResult::Value(None) => writeln!(out, "NOT_FOUND")?,
+        QueryResult::Done => writeln!(out, "OK")?,
+        QueryResult::Pairs { pairs, next } => {
+            match next {
+                Some(next) => writeln!(out, "PAIRS {} NEXT {}", pairs.len(), next)?,
+                None => writeln!(out, "PAIRS {}", pairs.len())?,
+            }
+            for (key, value) in pairs {
+                writeln!(out, "{} {}", key, value)?;
+            }
+        }
+        QueryResult::Count(count) => writeln!(out, "COUNT {}", count)?,
+    }
+    Ok(())
+}
+
+pub(crate) fn write_error(out: &mut impl Write, error: &Error) -> Result<()> {
+    writeln!(out, "ERR {}", error.to_string().replace(['\r', '\n'], " "))?;
+    Ok(())
+}
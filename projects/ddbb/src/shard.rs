@@ -0,0 +1,935 @@
+// src/shard.rs
+
+/*
+* Sharding
+*
+* A `ShardedStore` spreads the keys of one keyspace (String keys and values, like the servers)
+* over several stores, its shards, so the data can outgrow one LogManager or one machine. A
+* shard is a LogManager of this process (`Shard::Local`) or a server of server.rs reached with
+* a client of client.rs (`Shard::Remote`), and both kinds mix:
+*
+*   let mut store = ShardedStore::new(vec![
+*       ("a".to_string(), Shard::local(db)),
+*       ("b".to_string(), Shard::remote("10.0.0.2:7878", ClientOptions::default())?),
+*   ])?;
+*   store.set("user:1", "ann")?;
+*
+* The ring
+*
+* Which shard a key goes to is consistent hashing: every shard has VNODES points on a ring of
+* 64-bit hashes, at the hashes of "<name>#<n>", and a key belongs to the shard of the first
+* point at or after the hash of the key, going round past the end. So the owner of a key only
+* depends on the names of the shards (not on their order, or on how the shards were added),
+* and every store made with the same names routes the same way. The many points of a shard
+* spread its keys over the whole ring, so the shards get about as many keys each.
+*
+* Adding a shard (`add_shard`) puts its points on the ring, and only the keys between one of
+* them and the point before it change owner, all of them to the new shard: about 1/n of the
+* keys for n shards after the add, instead of most of them with a hash modulo n. Those keys
+* are copied to the new shard and deleted from the one they were on, MOVE_BATCH at a time, and
+* `add_shard` returns how many moved. A failure in the middle leaves some of them on both
+* shards, which is harmless: the ring only reads the new one once the add is done, and a new
+* `add_shard` with the same name is refused, so the store is made anew with every name and the
+* add repeated, which moves the rest.
+*
+* Queries
+*
+* `query` takes the queries of query.rs. A GET, SET or DEL goes to the shard of its key. A SCAN
+* goes to every shard and the pages are merged in key order (descending with REV): with a
+* LIMIT every shard returns up to LIMIT + OFFSET pairs, which is enough to find the first
+* LIMIT after OFFSET of all of them, and the token of the merged page is the one a LogManager
+* makes, so a SCAN with AFTER continues it (a token names a key, see scan.rs). A COUNT is the
+* sum of the counts of the shards.
+*
+* Replicas and hints
+*
+* With `StoreOptions::replicas` above 1 every key is on that many shards, its replicas: the
+* shards of the first points at or after its hash that belong to different shards, the first
+* of them its owner. A SET or DEL goes to every replica of its key, and a GET to the first
+* that answers, a SCAN and a COUNT to every shard as before, with every key once (its value
+* from the first of its replicas, see below). A shard that cannot be reached (an Io error) is
+* skipped by the reads as long as fewer than `replicas` shards are down, so every key has a
+* replica that answers.
+*
+* A replica that cannot be reached does not get the write, and with `StoreOptions::hints` the
+* store keeps it for that replica instead, a hint, in that LogManager of its own: the key
+* "<shard>/<n>" with n counting up, and the value the JSON of the key and the value (null for
+* a DEL). The writes of a shard with hints are hints too, after the others, and every
+* `hint_retry` the store tries to replay them to it, in order, deleting each once the shard
+* has it. So a replica that was down for a while gets what it missed once it is back, without
+* copying anything else (`replay_hints` tries every shard at once). Until then its reads come
+* after those of the replicas without hints. A write is done once one replica has it, and
+* fails (without hints, once one replica cannot be reached) with an Io error, though the hints
+* it left may still apply it later, like a write whose connection failed. Hints live as long
+* as the LogManager they are in, and a store made with it again replays them.
+*
+* Adding a shard (replicas or not) first replays the hints, and fails if some are left. Then
+* every key gets the replicas of the new ring: the keys the new shard is now a replica of are
+* copied to it from their owner, and deleted from the shards that are not their replicas
+* anymore.
+*
+* Repair
+*
+* A replica that missed writes without hints (the store was made without, or the hints were
+* lost) is behind for good, and a read of its keys may find it first. `repair` brings the
+* replicas back in line without reading all of them into the store: every shard builds the
+* Merkle tree of its pairs (see merkle.rs, REPAIR_DEPTH deep, with the MERKLE command of
+* admin.rs for a remote shard), the trees of every two shards are compared, and only the
+* leaves where two replicas of the same keys differ are read, from the shards that are
+* replicas of some of their keys. (A leaf that holds a point of the ring has keys of different
+* replicas, so the trees differ there and it is read every time, but there are only VNODES of
+* them per shard.) Then every replica of every key in there is made to have
+* what a read of it returns, the value of the first of its replicas (see above) or no pair
+* when that one has none: there are no versions to tell which write came last, so a write
+* the first replica missed is lost, and a DEL only the first replica has deletes the key from
+* the others. A repair replays the hints first, and fails if a shard cannot be reached.
+*
+* Batches across shards
+*
+* `write_batch` applies a WriteBatch (see batch.rs) to the replicas of its keys all at once, or
+* not at all, with a two-phase commit. The store is the coordinator, and logs what it decided
+* in a LogManager of its own, `StoreOptions::decisions`, the key the id of the batch (random)
+* and the value "prepare" or "commit":
+*
+* 1. it logs "prepare", and every shard gets its writes of the batch prepared under the id
+*    (see `LogManager::prepare`, PREPARE for a remote shard): the shard checks them and syncs
+*    them to its log, without applying them
+* 2. once every shard has, it logs "commit", the decision, and syncs it before any shard
+*    commits the batch: a decision lost to a crash would have the shards that did not commit
+*    yet abort it. If a shard failed to prepare, every shard aborts it instead
+* 3. once every shard committed (or aborted) the batch and synced that, it deletes the id: a
+*    shard that lost its COMMIT or ABORT to a crash after that would keep the batch prepared
+*    with no decision left to finish it
+*
+* A crash of the coordinator or of a shard anywhere in there leaves the batch prepared on some
+* shards, which keep it across restarts. `resolve` finishes what the decisions say: the
+* batches logged as "commit" are committed on every shard that has them prepared, the others
+* aborted. A store does it when it is made, and `write_batch` returns an Io error when a shard
+* could not be reached in the second phase, after which `resolve` (once it can be) finishes the
+* batch. Until then the writes of the batch are on some shards and not on others.
+*
+* A batch with the keys of a single shard is a plain batch of that shard. A batch across
+* shards needs every replica of its keys: the hints (see above) are replayed first, and it
+* fails with an Io error if some are left for a shard it writes, or a shard cannot be reached.
+* Without `StoreOptions::decisions` a batch across shards is an InvalidArgument.
+*
+* A ShardedStore is one caller's view of the shards and takes `&mut self`, and only the writes
+* of a `write_batch` are atomic together across shards.
+*/
+
+use crate::admin::{self, Admin};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::client::{Client, ClientOptions};
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use crate::merkle::{self, MerkleTree};
+use crate::query::{self, Keys, Query, QueryResult};
+use crate::scan::{self, Page, ScanOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Points of a shard on the ring.
+pub const VNODES: usize = 128;
+
+/// Keys copied to a new shard at a time.
+pub const MOVE_BATCH: usize = 256;
+
+/// Depth of the Merkle trees of a repair, 2^REPAIR_DEPTH ranges of keys.
+pub const REPAIR_DEPTH: u32 = 12;
+
+type Db = LogManager<String, String>;
+
+/// A store of some of the keys of a `ShardedStore`, see the top of this file.
+pub enum Shard {
+    Local(Arc<Mutex<Db>>),
+    Remote(Box<Client>),
+}
+
+impl Shard {
+    pub fn local(db: Db) -> Self {
+        Shard::Local(Arc::new(Mutex::new(db)))
+    }
+
+    /// A shard on the server at `address`.
+    pub fn remote(address: impl ToSocketAddrs, options: ClientOptions) -> Result<Self> {
+        Ok(Shard::Remote(Box::new(Client::connect_with(address, options)?)))
+    }
+
+    fn query(&mut self, query: &Query) -> Result<QueryResult> {
+        match self {
+            Shard::Local(db) => query::execute(&mut db.lock().unwrap(), query),
+            Shard::Remote(client) => client.query(query),
+        }
+    }
+
+    fn merkle(&mut self, depth: u32) -> Result<MerkleTree> {
+        match self {
+            Shard::Local(db) => {
+                let admin = Admin::Merkle { depth, leaf: None };
+                MerkleTree::from_pairs(depth, &page(admin::execute(&mut db.lock().unwrap(), &admin)?)?.pairs)
+            }
+            Shard::Remote(client) => client.merkle(depth),
+        }
+    }
+
+    fn merkle_leaf(&mut self, depth: u32, leaf: usize) -> Result<Vec<(String, String)>> {
+        match self {
+            Shard::Local(db) => {
+                let admin = Admin::Merkle { depth, leaf: Some(leaf) };
+                Ok(page(admin::execute(&mut db.lock().unwrap(), &admin)?)?.pairs)
+            }
+            Shard::Remote(client) => client.merkle_leaf(depth, leaf),
+        }
+    }
+
+    fn prepare(&mut self, id: &str, batch: &WriteBatch<String, String>) -> Result<()> {
+        match self {
+            Shard::Local(db) => db.lock().unwrap().prepare(id, batch.clone()),
+            Shard::Remote(client) => client.prepare(id, batch),
+        }
+    }
+
+    fn commit_prepared(&mut self, id: &str) -> Result<()> {
+        match self {
+            Shard::Local(db) => db.lock().unwrap().commit_prepared_durable(id),
+            Shard::Remote(client) => client.commit_prepared(id),
+        }
+    }
+
+    fn abort_prepared(&mut self, id: &str) -> Result<()> {
+        match self {
+            Shard::Local(db) => db.lock().unwrap().abort_prepared_durable(id),
+            Shard::Remote(client) => client.abort_prepared(id),
+        }
+    }
+
+    // The ids of the batches the shard has prepared
+    fn prepared(&mut self) -> Result<BTreeSet<String>> {
+        let prepared = match self {
+            Shard::Local(db) => db.lock().unwrap().prepared(),
+            Shard::Remote(client) => client.prepared()?,
+        };
+        Ok(prepared.into_iter().map(|(id, _)| id).collect())
+    }
+
+    fn write_batch(&mut self, batch: &WriteBatch<String, String>) -> Result<()> {
+        match self {
+            Shard::Local(db) => db.lock().unwrap().write_batch(batch.clone()),
+            Shard::Remote(client) => client.write_batch(batch),
+        }
+    }
+
+    // The SETs and DELs of `queries` as one batch
+    fn write(&mut self, queries: &[Query]) -> Result<()> {
+        if queries.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Shard::Local(db) => query::execute_batch(&mut db.lock().unwrap(), queries).map(|_| ()),
+            Shard::Remote(client) => client.write_batch(&query::batch(queries)?),
+        }
+    }
+}
+
+/// Where the keys go, see the top of this file.
+#[derive(Clone, Debug)]
+pub struct Ring {
+    // the hashes of the points, sorted, with the index of their shard
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    /// The ring of the shards with these names, their indexes in `names`.
+    pub fn new(names: &[&str]) -> Self {
+        let point = |name: &str, n: usize| hash(&format!("{}#{}", name, n));
+        let mut points: Vec<(u64, usize)> = names
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, name)| (0..VNODES).map(move |n| (point(name, n), shard)))
+            .collect();
+        points.sort_unstable();
+        Ring { points }
+    }
+
+    /// The index of the shard of `key`.
+    pub fn owner(&self, key: &str) -> usize {
+        let hash = hash(key);
+        let at = self.points.partition_point(|&(point, _)| point < hash);
+        self.points[at % self.points.len()].1
+    }
+
+    /// The indexes of the `count` replicas of `key`, its owner first, fewer if there are not
+    /// that many shards, see the top of this file.
+    pub fn replicas(&self, key: &str, count: usize) -> Vec<usize> {
+        self.replicas_of_hash(hash(key), count)
+    }
+
+    // The replicas of the keys with this hash
+    fn replicas_of_hash(&self, hash: u64, count: usize) -> Vec<usize> {
+        let at = self.points.partition_point(|&(point, _)| point < hash);
+        let mut replicas = Vec::with_capacity(count);
+        for i in 0..self.points.len() {
+            let shard = self.points[(at + i) % self.points.len()].1;
+            if !replicas.contains(&shard) {
+                replicas.push(shard);
+                if replicas.len() == count {
+                    break;
+                }
+            }
+        }
+        replicas
+    }
+
+    // Every set of replicas of the keys with a hash from `first` to `last`: the one of
+    // `first`, and one more after each point in there
+    fn replica_sets(&self, (first, last): (u64, u64), count: usize) -> Vec<Vec<usize>> {
+        let from = self.points.partition_point(|&(point, _)| point < first);
+        let to = self.points.partition_point(|&(point, _)| point < last);
+        let mut sets = vec![self.replicas_of_hash(first, count)];
+        for &(point, _) in &self.points[from..to] {
+            sets.push(self.replicas_of_hash(point + 1, count));
+        }
+        sets.dedup();
+        sets
+    }
+}
+
+// FNV-1a, with the last mixing step of MurmurHash3 so that keys which differ in their last
+// character land far apart
+pub(crate) fn hash(text: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Options of a `ShardedStore`, see the top of this file.
+pub struct StoreOptions {
+    /// How many shards have every key.
+    pub replicas: usize,
+    /// Where to keep the writes of the replicas that cannot be reached, None to fail them.
+    pub hints: Option<Db>,
+    /// How long a shard with hints is left alone before the store tries it again.
+    pub hint_retry: Duration,
+    /// Where to log the decisions of the batches across shards, None to refuse them.
+    pub decisions: Option<Db>,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions { replicas: 1, hints: None, hint_retry: Duration::from_secs(1), decisions: None }
+    }
+}
+
+// The writes of the shards that missed them, see the top of this file
+struct Hints {
+    db: Db,
+    // the n of the next hint
+    next: u64,
+    // how many hints every shard has, and when the store last tried to replay them, by index
+    pending: Vec<usize>,
+    tried: Vec<Option<Instant>>,
+    retry: Duration,
+}
+
+impl Hints {
+    fn open(mut db: Db, names: &[&str], retry: Duration) -> Result<Self> {
+        let mut next = 0;
+        let mut pending = vec![0; names.len()];
+        for (key, _) in pairs(&mut db, "")? {
+            let Some((name, n)) = hint_key(&key) else {
+                eprintln!("{} is not a hint, leaving it", key);
+                continue;
+            };
+            next = next.max(n + 1);
+            match names.iter().position(|shard| *shard == name) {
+                Some(shard) => pending[shard] += 1,
+                None => eprintln!("Hint {} is for a shard the store does not have, leaving it", key),
+            }
+        }
+        Ok(Hints { db, next, pending, tried: vec![None; names.len()], retry })
+    }
+
+    fn add(&mut self, shard: usize, name: &str, query: &Query) -> Result<()> {
+        let (key, value) = match query {
+            Query::Set(key, value) => (key, Some(value)),
+            Query::Del(key) => (key, None),
+            _ => unreachable!("only writes are hinted"),
+        };
+        let hint = serde_json::to_string(&(key, value)).map_err(|e| Error::Io(io::Error::other(e)))?;
+        self.db.insert(format!("{}/{:020}", name, self.next), hint)?;
+        self.next += 1;
+        self.pending[shard] += 1;
+        Ok(())
+    }
+
+    // Whether the writes of the shard are hints for now, replaying its hints if it is time
+    // to try it again
+    fn hinting(&mut self, index: usize, name: &str, shard: &mut Shard) -> Result<bool> {
+        if self.pending[index] == 0 {
+            return Ok(false);
+        }
+        if self.tried[index].is_some_and(|tried| tried.elapsed() < self.retry) {
+            return Ok(true);
+        }
+        self.replay(index, name, shard)?;
+        Ok(self.pending[index] > 0)
+    }
+
+    // Apply the hints of the shard to it in order until one cannot be, returns how many were
+    fn replay(&mut self, index: usize, name: &str, shard: &mut Shard) -> Result<usize> {
+        self.tried[index] = Some(Instant::now());
+        let mut replayed = 0;
+        for (key, hint) in pairs(&mut self.db, &format!("{}/", name))? {
+            if !matches!(hint_key(&key), Some((of, _)) if of == name) {
+                continue; // of a shard whose name starts with this one's and a slash
+            }
+            let bad = |e: serde_json::Error| Error::Corruption(format!("bad hint {}: {}", key, e));
+            let query = match serde_json::from_str(&hint).map_err(bad)? {
+                (key, Some(value)) => Query::Set(key, value),
+                (key, None) => Query::Del(key),
+            };
+            match shard.query(&query) {
+                Ok(_) => {}
+                Err(Error::Io(e)) => {
+                    eprintln!("Cannot replay the hints of shard {}: {}", name, e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+            self.db.delete(&key)?;
+            self.pending[index] -= 1;
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+}
+
+// The shard and the n of the key of a hint
+fn hint_key(key: &str) -> Option<(&str, u64)> {
+    let (name, n) = key.rsplit_once('/')?;
+    Some((name, n.parse().ok()?)).filter(|_| n.len() == 20)
+}
+
+// Every pair of `db` whose key starts with `prefix`
+fn pairs(db: &mut Db, prefix: &str) -> Result<Vec<(String, String)>> {
+    let keys = Keys::Prefix(prefix.to_string());
+    let scan = Query::Scan { keys, options: ScanOptions::default(), reverse: false };
+    Ok(page(query::execute(db, &scan)?)?.pairs)
+}
+
+/// What a `repair` found and did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Repair {
+    /// Leaves of the Merkle trees where two replicas differed, which were read, see merkle.rs.
+    /// The leaves that hold a point of the ring are most of the time, replicas in line or not.
+    pub ranges: usize,
+    /// SETs and DELs made to the replicas that had a pair wrong, or missing, or too many.
+    pub writes: usize,
+}
+
+/// The keyspace spread over shards, see the top of this file.
+pub struct ShardedStore {
+    shards: Vec<(String, Shard)>,
+    ring: Ring,
+    replicas: usize,
+    hints: Option<Hints>,
+    decisions: Option<Db>,
+}
+
+impl ShardedStore {
+    /// A store over `shards`, with their names. The names place the shards on the ring, a
+    /// store made again over the same shards must give them the same names.
+    pub fn new(shards: Vec<(String, Shard)>) -> Result<Self> {
+        Self::with_options(shards, StoreOptions::default())
+    }
+
+    /// Same as `new`, with non-default `StoreOptions`.
+    pub fn with_options(shards: Vec<(String, Shard)>, options: StoreOptions) -> Result<Self> {
+        if shards.is_empty() {
+            return Err(Error::InvalidArgument("a sharded store needs a shard".to_string()));
+        }
+        if options.replicas == 0 {
+            return Err(Error::InvalidArgument("a sharded store needs a replica of every key".to_string()));
+        }
+        for (i, (name, _)) in shards.iter().enumerate() {
+            if shards[..i].iter().any(|(other, _)| other == name) {
+                return Err(Error::InvalidArgument(format!("two shards are named {}", name)));
+            }
+        }
+        let names: Vec<&str> = shards.iter().map(|(name, _)| name.as_str()).collect();
+        let ring = Ring::new(&names);
+        let hints = options.hints.map(|db| Hints::open(db, &names, options.hint_retry)).transpose()?;
+        let decisions = options.decisions;
+        let mut store = ShardedStore { shards, ring, replicas: options.replicas, hints, decisions };
+        if let Err(e) = store.resolve() {
+            eprintln!("Some batches across shards are left prepared: {}", e);
+        }
+        Ok(store)
+    }
+
+    /// The names of the shards, in the order they were given and added.
+    pub fn shard_names(&self) -> Vec<&str> {
+        self.shards.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// The name of the shard of `key`.
+    pub fn owner(&self, key: &str) -> &str {
+        &self.shards[self.ring.owner(key)].0
+    }
+
+    /// The names of the replicas of `key`, its owner first.
+    pub fn replicas(&self, key: &str) -> Vec<&str> {
+        let replicas = self.ring.replicas(key, self.replicas).into_iter();
+        replicas.map(|shard| self.shards[shard].0.as_str()).collect()
+    }
+
+    /// How many writes the store keeps as hints for the shards that missed them.
+    pub fn hints(&self) -> usize {
+        self.hints.as_ref().map_or(0, |hints| hints.pending.iter().sum())
+    }
+
+    /// Replay the hints of every shard that has some now, see the top of this file. Returns
+    /// how many were, the shards that still cannot be reached keep theirs.
+    pub fn replay_hints(&mut self) -> Result<usize> {
+        let Some(hints) = &mut self.hints else {
+            return Ok(0);
+        };
+        let mut replayed = 0;
+        for (index, (name, shard)) in self.shards.iter_mut().enumerate() {
+            if hints.pending[index] > 0 {
+                replayed += hints.replay(index, name, shard)?;
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Bring the replicas of every key in line, see the top of this file.
+    pub fn repair(&mut self) -> Result<Repair> {
+        self.replay_hints()?;
+        let mut trees = Vec::with_capacity(self.shards.len());
+        for (_, shard) in &mut self.shards {
+            trees.push(shard.merkle(REPAIR_DEPTH)?);
+        }
+        // the leaves where two shards that are replicas of some of the same keys differ, with
+        // the sets of replicas of their keys
+        let mut sets: HashMap<usize, Vec<Vec<usize>>> = HashMap::new();
+        let mut divergent = BTreeSet::new();
+        for i in 0..trees.len() {
+            for j in i + 1..trees.len() {
+                for leaf in trees[i].differences(&trees[j])? {
+                    let hashes = merkle::leaf_hashes(leaf, REPAIR_DEPTH);
+                    let (ring, replicas) = (&self.ring, self.replicas);
+                    let sets = sets.entry(leaf).or_insert_with(|| ring.replica_sets(hashes, replicas));
+                    if sets.iter().any(|set| set.contains(&i) && set.contains(&j)) {
+                        divergent.insert(leaf);
+                    }
+                }
+            }
+        }
+        let mut repair = Repair { ranges: divergent.len(), writes: 0 };
+        for leaf in divergent {
+            repair.writes += self.repair_leaf(leaf, &sets[&leaf])?;
+        }
+        Ok(repair)
+    }
+
+    /// Apply all of `batch` on every replica of its keys or nothing, see the top of this file.
+    pub fn write_batch(&mut self, batch: &WriteBatch<String, String>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // the writes of every shard, in the order of the batch
+        let mut writes: BTreeMap<usize, WriteBatch<String, String>> = BTreeMap::new();
+        for op in batch.ops() {
+            for shard in self.ring.replicas(op.key(), self.replicas) {
+                let writes = writes.entry(shard).or_default();
+                match op {
+                    BatchOp::Insert(key, value) => writes.insert(key.clone(), value.clone()),
+                    BatchOp::Delete(key) => writes.delete(key.clone()),
+                };
+            }
+        }
+        if let [(&shard, writes)] = Vec::from_iter(&writes)[..] {
+            if !self.hinted(shard) {
+                return self.shards[shard].1.write_batch(writes);
+            }
+        }
+        if self.decisions.is_none() {
+            let message = "a batch across shards needs StoreOptions::decisions";
+            return Err(Error::InvalidArgument(message.to_string()));
+        }
+        self.replay_hints()?;
+        if let Some(&shard) = writes.keys().find(|&&shard| self.hinted(shard)) {
+            let name = &self.shards[shard].0;
+            let message = format!("shard {} has hints left, a batch across shards needs it", name);
+            return Err(Error::Io(io::Error::other(message)));
+        }
+
+        let id = format!("{:016x}", rand::random::<u64>());
+        self.decide(&id, Some("prepare"))?;
+        let mut failure = None;
+        for (&shard, writes) in &writes {
+            if let Err(e) = self.shards[shard].1.prepare(&id, writes) {
+                failure = Some(e);
+                break;
+            }
+        }
+        if let Some(e) = failure {
+            // the shards that did not prepare it answer that they have no such batch
+            let mut aborted = true;
+            for &shard in writes.keys() {
+                match self.shards[shard].1.abort_prepared(&id) {
+                    Ok(()) | Err(Error::InvalidArgument(_)) => {}
+                    Err(_) => aborted = false,
+                }
+            }
+            if aborted {
+                self.decide(&id, None)?;
+            }
+            return Err(e);
+        }
+        self.decide(&id, Some("commit"))?;
+        let mut failure = None;
+        for &shard in writes.keys() {
+            if let Err(e) = self.shards[shard].1.commit_prepared(&id) {
+                eprintln!("Shard {} did not commit batch {}: {}", self.shards[shard].0, id, e);
+                failure = Some(e);
+            }
+        }
+        match failure {
+            Some(e) => {
+                let message = format!("batch {} is committed but not on every shard yet ({})", id, e);
+                Err(Error::Io(io::Error::other(message)))
+            }
+            None => self.decide(&id, None),
+        }
+    }
+
+    /// Commit or abort the batches across shards left prepared, as the decisions say, see the
+    /// top of this file. Returns how many were.
+    pub fn resolve(&mut self) -> Result<usize> {
+        let Some(decisions) = &mut self.decisions else {
+            return Ok(0);
+        };
+        let pending = pairs(decisions, "")?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let mut prepared = Vec::with_capacity(self.shards.len());
+        for (_, shard) in &mut self.shards {
+            prepared.push(shard.prepared()?);
+        }
+        for (id, decision) in &pending {
+            for ((_, shard), prepared) in self.shards.iter_mut().zip(&prepared) {
+                if !prepared.contains(id) {
+                    continue;
+                }
+                match decision.as_str() {
+                    "commit" => shard.commit_prepared(id)?,
+                    _ => shard.abort_prepared(id)?,
+                }
+            }
+            decisions.delete(id)?;
+        }
+        Ok(pending.len())
+    }
+
+    // Log the decision for batch `id`, synced, None once it is done
+    fn decide(&mut self, id: &str, decision: Option<&str>) -> Result<()> {
+        let decisions = self.decisions.as_mut().expect("a batch across shards has decisions");
+        match decision {
+            Some(decision) => {
+                decisions.insert(id.to_string(), decision.to_string())?;
+                decisions.sync()
+            }
+            None => decisions.delete(&id.to_string()),
+        }
+    }
+
+    /// Put `shard` on the ring and move the keys that are now its to it, see the top of this
+    /// file. Returns how many keys moved.
+    pub fn add_shard(&mut self, name: &str, mut shard: Shard) -> Result<usize> {
+        if self.shards.iter().any(|(other, _)| other == name) {
+            return Err(Error::InvalidArgument(format!("there is a shard {} already", name)));
+        }
+        self.replay_hints()?;
+        if self.hints() > 0 {
+            let message = "a shard with hints cannot be reached, they must be replayed before adding a shard";
+            return Err(Error::Io(io::Error::other(message)));
+        }
+        let mut names = self.shard_names();
+        names.push(name);
+        let ring = Ring::new(&names);
+        let added = self.shards.len();
+
+        let mut moved = 0;
+        for (index, (_, old)) in self.shards.iter_mut().enumerate() {
+            let mut resume = None;
+            loop {
+                let options = ScanOptions { limit: Some(MOVE_BATCH), resume, ..ScanOptions::default() };
+                let scan = Query::Scan { keys: Keys::Prefix(String::new()), options, reverse: false };
+                let Page { pairs, next } = page(old.query(&scan)?)?;
+                let (mut sets, mut deletes) = (Vec::new(), Vec::new());
+                for (key, value) in pairs {
+                    let replicas = ring.replicas(&key, self.replicas);
+                    // copied from the owner only, the other replicas have the same
+                    if replicas.contains(&added) && self.ring.owner(&key) == index {
+                        sets.push(Query::Set(key.clone(), value));
+                    }
+                    if !replicas.contains(&index) {
+                        deletes.push(Query::Del(key));
+                    }
+                }
+                // copied before they are deleted, a failure in between leaves them on both
+                shard.write(&sets)?;
+                old.write(&deletes)?;
+                moved += sets.len();
+                match next {
+                    Some(next) => resume = Some(next),
+                    None => break,
+                }
+            }
+        }
+        self.shards.push((name.to_string(), shard));
+        self.ring = ring;
+        if let Some(hints) = &mut self.hints {
+            hints.pending.push(0);
+            hints.tried.push(None);
+        }
+        Ok(moved)
+    }
+
+    /// Run `query` on the shards it needs, see the top of this file.
+    pub fn query(&mut self, query: &Query) -> Result<QueryResult> {
+        match query {
+            Query::Get(key) => self.read(key, query),
+            Query::Set(key, _) | Query::Del(key) => self.write(key, query),
+            Query::Count(keys) if self.replicas > 1 => {
+                // every key once, which only a scan tells
+                let page = self.scan_shards(keys, &ScanOptions::default(), false)?;
+                Ok(QueryResult::Count(page.pairs.len()))
+            }
+            Query::Count(_) => {
+                let mut total = 0;
+                for (_, shard) in &mut self.shards {
+                    match shard.query(query)? {
+                        QueryResult::Count(count) => total += count,
+                        other => return Err(unexpected(&other)),
+                    }
+                }
+                Ok(QueryResult::Count(total))
+            }
+            Query::Scan { keys, options, reverse } => {
+                let page = self.scan_shards(keys, options, *reverse)?;
+                Ok(QueryResult::Pairs { pairs: page.pairs, next: page.next })
+            }
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.query(&Query::Get(key.to_string()))? {
+            QueryResult::Value(value) => Ok(value),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.query(&Query::Set(key.to_string(), value.to_string())).map(|_| ())
+    }
+
+    pub fn del(&mut self, key: &str) -> Result<()> {
+        self.query(&Query::Del(key.to_string())).map(|_| ())
+    }
+
+    /// The pairs of `keys` in key order, a page of them with a limit, see scan.rs.
+    pub fn scan(&mut self, keys: Keys, options: ScanOptions) -> Result<Page<String, String>> {
+        self.scan_shards(&keys, &options, false)
+    }
+
+    /// Same as `scan`, in descending key order.
+    pub fn scan_rev(&mut self, keys: Keys, options: ScanOptions) -> Result<Page<String, String>> {
+        self.scan_shards(&keys, &options, true)
+    }
+
+    pub fn count(&mut self, keys: Keys) -> Result<usize> {
+        match self.query(&Query::Count(keys))? {
+            QueryResult::Count(count) => Ok(count),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn hinted(&self, shard: usize) -> bool {
+        self.hints.as_ref().is_some_and(|hints| hints.pending[shard] > 0)
+    }
+
+    // Where the value of `key` on the shard comes in the order of the reads: its replicas
+    // without hints, then those with, then any other shard
+    fn rank(&self, key: &str, shard: usize) -> (bool, usize) {
+        match self.ring.replicas(key, self.replicas).iter().position(|&replica| replica == shard) {
+            Some(position) => (self.hinted(shard), position),
+            None => (true, usize::MAX),
+        }
+    }
+
+    // Give every replica of the keys of `leaf` the pair of the first of them, returns how many
+    // writes that took
+    fn repair_leaf(&mut self, leaf: usize, sets: &[Vec<usize>]) -> Result<usize> {
+        let mut involved = sets.concat();
+        involved.sort_unstable();
+        involved.dedup();
+        let mut pairs: HashMap<usize, HashMap<String, String>> = HashMap::new();
+        for shard in involved {
+            let leaf_pairs = self.shards[shard].1.merkle_leaf(REPAIR_DEPTH, leaf)?;
+            pairs.insert(shard, leaf_pairs.into_iter().collect());
+        }
+        let keys: BTreeSet<&String> = pairs.values().flat_map(|pairs| pairs.keys()).collect();
+        let mut writes: HashMap<usize, Vec<Query>> = HashMap::new();
+        for key in keys {
+            let mut replicas = self.ring.replicas(key, self.replicas);
+            replicas.sort_by_key(|&shard| self.rank(key, shard));
+            let (first, others) = replicas.split_first().expect("a key has a replica");
+            let value = pairs[first].get(key);
+            for other in others {
+                if pairs[other].get(key) != value {
+                    let write = match value {
+                        Some(value) => Query::Set(key.clone(), value.clone()),
+                        None => Query::Del(key.clone()),
+                    };
+                    writes.entry(*other).or_default().push(write);
+                }
+            }
+        }
+        let mut count = 0;
+        for (shard, queries) in writes {
+            self.shards[shard].1.write(&queries)?;
+            count += queries.len();
+        }
+        Ok(count)
+    }
+
+    // A GET from the first replica of the key that answers
+    fn read(&mut self, key: &str, query: &Query) -> Result<QueryResult> {
+        let mut replicas = self.ring.replicas(key, self.replicas);
+        replicas.sort_by_key(|&shard| self.rank(key, shard));
+        let mut failure = None;
+        for shard in replicas {
+            match self.shards[shard].1.query(query) {
+                Err(Error::Io(e)) => failure = Some(e),
+                result => return result,
+            }
+        }
+        Err(Error::Io(failure.expect("a key has a replica")))
+    }
+
+    // A SET or DEL on every replica of the key, or as a hint for it
+    fn write(&mut self, key: &str, query: &Query) -> Result<QueryResult> {
+        let mut written = 0;
+        let mut failure = None;
+        for shard in self.ring.replicas(key, self.replicas) {
+            let (name, target) = &mut self.shards[shard];
+            if let Some(hints) = &mut self.hints {
+                if hints.hinting(shard, name, target)? {
+                    hints.add(shard, name, query)?;
+                    continue;
+                }
+            }
+            match target.query(query) {
+                Ok(_) => written += 1,
+                Err(Error::Io(e)) => {
+                    if let Some(hints) = &mut self.hints {
+                        eprintln!("Shard {} cannot be reached, keeping a hint: {}", name, e);
+                        hints.add(shard, name, query)?;
+                        hints.tried[shard] = Some(Instant::now());
+                    }
+                    failure = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        match failure {
+            Some(e) if self.hints.is_none() => Err(Error::Io(e)),
+            _ if written == 0 => {
+                let message = format!("no replica of {} can be reached, the write is kept as hints", key);
+                Err(Error::Io(io::Error::other(message)))
+            }
+            _ => Ok(QueryResult::Done),
+        }
+    }
+
+    // Every shard's page, merged, see the top of this file
+    fn scan_shards(
+        &mut self,
+        keys: &Keys,
+        options: &ScanOptions,
+        reverse: bool,
+    ) -> Result<Page<String, String>> {
+        let wanted = options.limit.map(|limit| limit + options.offset);
+        let per_shard = ScanOptions { limit: wanted, offset: 0, resume: options.resume.clone() };
+        let scan = Query::Scan { keys: keys.clone(), options: per_shard, reverse };
+        let mut pairs = Vec::new();
+        let mut more = false;
+        let mut down = Vec::new();
+        for (index, (_, shard)) in self.shards.iter_mut().enumerate() {
+            let page = match shard.query(&scan) {
+                // every key has a replica that answers while fewer than `replicas` are down
+                Err(Error::Io(e)) if self.replicas > 1 => {
+                    down.push(e);
+                    continue;
+                }
+                result => page(result?)?,
+            };
+            more |= page.next.is_some();
+            pairs.extend(page.pairs.into_iter().map(|(key, value)| (key, value, index)));
+        }
+        if down.len() >= self.replicas {
+            return Err(Error::Io(down.pop().expect("a shard is down")));
+        }
+        // with replicas, every key once with the value of its first replica
+        let mut pairs: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value, shard)| {
+                let rank = if self.replicas > 1 { self.rank(&key, shard) } else { (false, 0) };
+                (key, value, rank)
+            })
+            .collect();
+        pairs.sort_unstable_by(|(a, _, a_rank), (b, _, b_rank)| {
+            let order = if reverse { b.cmp(a) } else { a.cmp(b) };
+            order.then(a_rank.cmp(b_rank))
+        });
+        pairs.dedup_by(|(key, ..), (first, ..)| key == first);
+
+        let pairs = pairs.into_iter().map(|(key, value, _)| (key, value)).skip(options.offset);
+        let mut pairs: Vec<(String, String)> = pairs.collect();
+        if let Some(limit) = options.limit {
+            more |= pairs.len() > limit;
+            pairs.truncate(limit);
+        } else {
+            more = false;
+        }
+        let next = match pairs.last() {
+            Some((key, _)) if more => Some(scan::resume_token(key)),
+            _ => None,
+        };
+        Ok(Page { pairs, next })
+    }
+}
+
+fn page(result: QueryResult) -> Result<Page<String, String>> {
+    match result {
+        QueryResult::Pairs { pairs, next } => Ok(Page { pairs, next }),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn unexpected(result: &QueryResult) -> Error {
+    Error::Corruption(format!("unexpected result {:?}", result))
+}
@@ -0,0 +1,469 @@
+// src/sstable.rs
+
+/*
+* SSTable (sorted string table)
+*
+* An immutable file of entries sorted by key, written once by TableWriter and then only read.
+* The LSM tree stores its memtable flushes and compaction outputs as tables, and LogManager
+* writes its snapshot into one when it compacts the log.
+*
+* Layout:
+*
+* [data block 0][data block 1]...[data block n][index block][footer]
+*
+* - a data block is a run of entries, closed once it reaches BLOCK_SIZE bytes, followed by
+*   the crc32 of those entries:
+*       [key len: u32][value len: u32, TOMBSTONE for a delete][key][value] ... [crc32]
+* - the index block holds the smallest key of the table, then the last key and the position
+*   of every data block, followed by its crc32:
+*       [len][smallest key] ([len][last key][offset: u64][size: u32]) ... [crc32]
+* - the footer has a fixed size so it can be found from the end of the file:
+*       [index offset: u64][index size: u32][entry count: u64][TABLE_MAGIC]
+*
+* Opening a table only reads the footer and the index. A point lookup binary searches the
+* index for the first block whose last key is >= the key we look for, and reads that single
+* block. A scan reads the blocks one after the other. Decoded blocks can be kept in a
+* BlockCache (see cache.rs) shared with other tables, see `Table::with_cache`.
+*/
+
+use crate::cache::{self, BlockCache};
+use crate::error::{Error, Result};
+use crate::explain::{self, ReadStats};
+use crate::vfs::{OpenOptions, Vfs, VfsFile};
+use std::cell::Cell;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Data blocks are closed once they hold this many bytes.
+pub const BLOCK_SIZE: usize = 4096;
+const TOMBSTONE: u32 = u32::MAX;
+const ENTRY_HEADER: usize = 8;
+const TABLE_MAGIC: &[u8; 8] = b"DDBBSST1";
+const FOOTER: usize = 8 + 4 + 8 + TABLE_MAGIC.len();
+
+/// The value of a key in a table, None for a delete (a tombstone).
+pub type Entry = Option<Vec<u8>>;
+
+/// The decoded entries of a data block.
+pub type Block = Vec<(Vec<u8>, Entry)>;
+
+/// Writes a table, entries must be added in strictly ascending key order.
+pub struct TableWriter {
+    writer: BufWriter<Box<dyn VfsFile>>,
+    block: Vec<u8>,
+    index: Vec<u8>,
+    offset: u64,
+    entries: u64,
+    smallest: Vec<u8>,
+    last_key: Option<Vec<u8>>,
+}
+
+impl TableWriter {
+    /// Write a table into `file`, which should be empty.
+    pub fn new(file: Box<dyn VfsFile>) -> Self {
+        TableWriter {
+            writer: BufWriter::new(file),
+            block: Vec::new(),
+            index: Vec::new(),
+            offset: 0,
+            entries: 0,
+            smallest: Vec::new(),
+            last_key: None,
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(Error::InvalidArgument(format!("key {:?} is not above the previous one", key)));
+        }
+        if key.len() >= TOMBSTONE as usize || value.is_some_and(|v| v.len() >= TOMBSTONE as usize) {
+            return Err(Error::InvalidArgument(format!("entry of key {:?} is too large", key)));
+        }
+        if self.entries == 0 {
+            self.smallest = key.to_vec();
+        }
+        encode_entry(&mut self.block, key, value);
+        self.last_key = Some(key.to_vec());
+        self.entries += 1;
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Number of entries added so far.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Bytes of data written so far, without the index and footer `finish` adds.
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Write the index and the footer and sync the file. Returns the size of the table.
+    pub fn finish(mut self) -> Result<u64> {
+        self.finish_block()?;
+
+        let mut index = Vec::new();
+        put_bytes(&mut index, &self.smallest);
+        index.extend_from_slice(&self.index);
+        index.extend_from_slice(&crc32fast::hash(&index).to_le_bytes());
+        self.writer.write_all(&index)?;
+
+        let mut footer = Vec::with_capacity(FOOTER);
+        footer.extend_from_slice(&self.offset.to_le_bytes());
+        footer.extend_from_slice(&(index.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&self.entries.to_le_bytes());
+        footer.extend_from_slice(TABLE_MAGIC);
+        self.writer.write_all(&footer)?;
+
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync()?;
+        Ok(self.offset + (index.len() + FOOTER) as u64)
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let checksum = crc32fast::hash(&self.block);
+        self.block.extend_from_slice(&checksum.to_le_bytes());
+        self.writer.write_all(&self.block)?;
+
+        put_bytes(&mut self.index, self.last_key.as_deref().unwrap_or_default());
+        self.index.extend_from_slice(&self.offset.to_le_bytes());
+        self.index.extend_from_slice(&(self.block.len() as u32).to_le_bytes());
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u32,
+}
+
+/// An open table, safe to read from several threads.
+pub struct Table {
+    id: u64,
+    path: PathBuf,
+    file: Mutex<Box<dyn VfsFile>>,
+    cache: Option<Arc<BlockCache>>,
+    blocks: Vec<BlockHandle>,
+    entries: u64,
+    smallest: Vec<u8>,
+    size: u64,
+}
+
+impl Table {
+    /// Open the table at `path`, checking its footer and index.
+    pub fn open(vfs: &dyn Vfs, path: impl AsRef<Path>) -> Result<Table> {
+        Self::open_with(vfs, path, false)
+    }
+
+    /// Same as `open`, reading the file with direct I/O if `direct_io` is set.
+    pub fn open_with(vfs: &dyn Vfs, path: impl AsRef<Path>, direct_io: bool) -> Result<Table> {
+        let path = path.as_ref().to_path_buf();
+        let corrupt = |why: &str| Error::Corruption(format!("table {}: {}", path.display(), why));
+        let mut file = vfs.open(&path, OpenOptions::new().read(true).direct(direct_io))?;
+
+        let len = file.len()?;
+        if len < FOOTER as u64 {
+            return Err(corrupt("too short"));
+        }
+        let mut footer = [0; FOOTER];
+        file.seek(SeekFrom::Start(len - FOOTER as u64))?;
+        file.read_exact(&mut footer)?;
+        if &footer[FOOTER - TABLE_MAGIC.len()..] != TABLE_MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let index_size = u32::from_le_bytes(footer[8..12].try_into().unwrap()) as u64;
+        let entries = u64::from_le_bytes(footer[12..20].try_into().unwrap());
+        if index_size < 4 || index_offset + index_size + FOOTER as u64 != len {
+            return Err(corrupt("bad footer"));
+        }
+
+        let mut index = vec![0; index_size as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut index)?;
+        let index = check_crc(&index).ok_or_else(|| corrupt("index checksum mismatch"))?;
+
+        let mut reader = index;
+        let smallest = take_bytes(&mut reader).ok_or_else(|| corrupt("bad index"))?;
+        let mut blocks = Vec::new();
+        while !reader.is_empty() {
+            let last_key = take_bytes(&mut reader).ok_or_else(|| corrupt("bad index"))?;
+            let mut handle = [0; 12];
+            reader.read_exact(&mut handle).map_err(|_| corrupt("bad index"))?;
+            blocks.push(BlockHandle {
+                last_key,
+                offset: u64::from_le_bytes(handle[..8].try_into().unwrap()),
+                size: u32::from_le_bytes(handle[8..].try_into().unwrap()),
+            });
+        }
+
+        Ok(Table {
+            id: cache::next_table_id(),
+            path,
+            file: Mutex::new(file),
+            cache: None,
+            blocks,
+            entries,
+            smallest,
+            size: len,
+        })
+    }
+
+    /// Keep the blocks read from the table in `cache`.
+    pub fn with_cache(mut self, cache: Arc<BlockCache>) -> Table {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Some(entry) if the table has `key` (a tombstone included), None otherwise.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        self.get_traced(key, None)
+    }
+
+    // Same as get, recording what it read, see explain.rs
+    pub(crate) fn get_traced(&self, key: &[u8], stats: Option<&Cell<ReadStats>>) -> Result<Option<Entry>> {
+        let i = self.blocks.partition_point(|block| block.last_key.as_slice() < key);
+        if i == self.blocks.len() {
+            explain::record(stats, |stats| stats.tables_skipped += 1);
+            return Ok(None);
+        }
+        explain::record(stats, |stats| stats.tables_searched += 1);
+        let block = self.read_block(i, stats)?;
+        Ok(block
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|j| block[j].1.clone()))
+    }
+
+    /// Every entry in key order.
+    pub fn iter(&self) -> TableIter<'_> {
+        TableIter { table: self, next_block: 0, block: Arc::default(), pos: 0, from: None, stats: None }
+    }
+
+    /// The entries whose key is >= `key`, in key order.
+    pub fn iter_from(&self, key: &[u8]) -> TableIter<'_> {
+        let next_block = self.blocks.partition_point(|block| block.last_key.as_slice() < key);
+        let from = Some(key.to_vec());
+        TableIter { table: self, next_block, block: Arc::default(), pos: 0, from, stats: None }
+    }
+
+    /// The entries whose key is <= `key` (every entry for None), in descending key order.
+    pub fn iter_rev(&self, key: Option<&[u8]>) -> TableRevIter<'_> {
+        let blocks_left = match key {
+            // the first block that reaches `key` and every block before it
+            Some(key) => {
+                let i = self.blocks.partition_point(|block| block.last_key.as_slice() < key);
+                (i + 1).min(self.blocks.len())
+            }
+            None => self.blocks.len(),
+        };
+        let to = key.map(<[u8]>::to_vec);
+        TableRevIter { table: self, blocks_left, block: Arc::default(), pos: 0, to, stats: None }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn smallest(&self) -> &[u8] {
+        &self.smallest
+    }
+
+    pub fn largest(&self) -> &[u8] {
+        self.blocks.last().map_or(&[], |block| &block.last_key)
+    }
+
+    fn read_block(&self, i: usize, stats: Option<&Cell<ReadStats>>) -> Result<Arc<Block>> {
+        let handle = &self.blocks[i];
+        if let Some(block) = self.cache.as_ref().and_then(|cache| cache.get((self.id, handle.offset))) {
+            explain::record_block(stats, true, handle.size as usize);
+            return Ok(block);
+        }
+        explain::record_block(stats, false, handle.size as usize);
+        let mut data = vec![0; handle.size as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut data)?;
+        }
+        let corrupt = || {
+            Error::Corruption(format!("table {}: block at {} is damaged", self.path.display(), handle.offset))
+        };
+        let mut reader = check_crc(&data).ok_or_else(corrupt)?;
+        let mut entries = Vec::new();
+        while !reader.is_empty() {
+            entries.push(read_entry(&mut reader).map_err(|_| corrupt())?);
+        }
+        let block = Arc::new(entries);
+        if let Some(cache) = &self.cache {
+            cache.insert((self.id, handle.offset), block.clone(), handle.size as usize);
+        }
+        Ok(block)
+    }
+}
+
+/// Reads a table block by block, see `Table::iter`.
+pub struct TableIter<'a> {
+    table: &'a Table,
+    next_block: usize,
+    block: Arc<Block>,
+    pos: usize, // next entry of `block`
+    from: Option<Vec<u8>>, // entries below this key are skipped
+    stats: Option<&'a Cell<ReadStats>>,
+}
+
+impl<'a> TableIter<'a> {
+    // Record the blocks the iterator reads in `stats`, see explain.rs
+    pub(crate) fn traced(mut self, stats: Option<&'a Cell<ReadStats>>) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, entry)) = self.block.get(self.pos) {
+                self.pos += 1;
+                if self.from.as_ref().is_some_and(|from| key < from) {
+                    continue;
+                }
+                self.from = None;
+                return Some(Ok((key.clone(), entry.clone())));
+            }
+            if self.next_block == self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block, self.stats) {
+                Ok(block) => (self.block, self.pos) = (block, 0),
+                Err(e) => {
+                    // stop after reporting the error
+                    self.next_block = self.table.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+/// Reads a table block by block from the end, see `Table::iter_rev`.
+pub struct TableRevIter<'a> {
+    table: &'a Table,
+    blocks_left: usize, // the next block read is the one before them
+    block: Arc<Block>,
+    pos: usize, // entries of `block` not returned yet
+    to: Option<Vec<u8>>, // entries above this key are skipped
+    stats: Option<&'a Cell<ReadStats>>,
+}
+
+impl<'a> TableRevIter<'a> {
+    // Record the blocks the iterator reads in `stats`, see explain.rs
+    pub(crate) fn traced(mut self, stats: Option<&'a Cell<ReadStats>>) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+impl Iterator for TableRevIter<'_> {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.pos > 0 {
+                self.pos -= 1;
+                let (key, entry) = &self.block[self.pos];
+                if self.to.as_ref().is_some_and(|to| key > to) {
+                    continue;
+                }
+                self.to = None;
+                return Some(Ok((key.clone(), entry.clone())));
+            }
+            if self.blocks_left == 0 {
+                return None;
+            }
+            match self.table.read_block(self.blocks_left - 1, self.stats) {
+                Ok(block) => (self.pos, self.block) = (block.len(), block),
+                Err(e) => {
+                    // stop after reporting the error
+                    self.blocks_left = 0;
+                    return Some(Err(e));
+                }
+            }
+            self.blocks_left -= 1;
+        }
+    }
+}
+
+pub(crate) fn encode_entry(out: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) {
+    out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    out.extend_from_slice(&value.map_or(TOMBSTONE, |v| v.len() as u32).to_le_bytes());
+    out.extend_from_slice(key);
+    out.extend_from_slice(value.unwrap_or_default());
+}
+
+/// Size of the encoded entry starting at `data`, None if its header is incomplete.
+pub(crate) fn entry_size(data: &[u8]) -> Option<usize> {
+    let header = data.get(..ENTRY_HEADER)?;
+    let key_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let value_len = match u32::from_le_bytes(header[4..].try_into().unwrap()) {
+        TOMBSTONE => 0,
+        len => len as usize,
+    };
+    Some(ENTRY_HEADER + key_len + value_len)
+}
+
+pub(crate) fn read_entry(reader: &mut impl Read) -> Result<(Vec<u8>, Entry)> {
+    let mut header = [0; ENTRY_HEADER];
+    reader.read_exact(&mut header)?;
+    let key_len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let value_len = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    let mut key = vec![0; key_len as usize];
+    reader.read_exact(&mut key)?;
+    if value_len == TOMBSTONE {
+        return Ok((key, None));
+    }
+    let mut value = vec![0; value_len as usize];
+    reader.read_exact(&mut value)?;
+    Ok((key, Some(value)))
+}
+
+// The content of `data` without its trailing crc32, None if the checksum does not match
+fn check_crc(data: &[u8]) -> Option<&[u8]> {
+    let (content, checksum) = data.split_at(data.len().checked_sub(4)?);
+    (crc32fast::hash(content).to_le_bytes() == checksum).then_some(content)
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take_bytes(reader: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(reader.get(..4)?.try_into().unwrap()) as usize;
+    let bytes = reader.get(4..4 + len)?.to_vec();
+    *reader = &reader[4 + len..];
+    Some(bytes)
+}
@@ -0,0 +1,107 @@
+// src/table.rs
+
+/*
+* Typed tables
+*
+* A LogManager stores one key type and one value type, both as text. An application with
+* users, orders and sessions would need a LogManager per record type, or its own string format
+* for each of them. A `Table<T, K>` stores records of type T (anything serde can serialize) by
+* keys of type K (any `OrderedKey`, a String by default) in a LogManager it shares with the
+* other tables, a `TableStore`:
+*
+*   let users: Table<User> = Table::new("users");
+*   let orders: Table<Order, (String, u64)> = Table::new("orders");
+*   users.put(&mut store, &"ann".to_string(), &ann)?;
+*   orders.put(&mut store, &("ann".to_string(), 7), &order)?;
+*   let ann: Option<User> = users.get(&store, &"ann".to_string())?;
+*
+* Every key of the store is the pair (table name, encoded key), with keycodec.rs, so the
+* records of one table are contiguous and in the order of K: a scan of a table never sees the
+* records of another one, and the name is the only prefix a table needs. The records are kept
+* as Json values (json.rs), so `LogManager::get_path`/`set_path` and the text index work on
+* them too.
+*
+* A table is only a name and two types, nothing about it is stored: opening the store again
+* and making a `Table` with the same name and types finds the records. Reading a record with
+* a type it does not deserialize to (a field was renamed, a table made with the wrong type)
+* fails with `Error::Corruption` rather than returning a half filled record, an
+* `#[serde(default)]` on a new field is the way to keep old records readable.
+*/
+
+use crate::error::{Error, Result};
+use crate::json::Json;
+use crate::keycodec::{self, EncodedKey, OrderedKey};
+use crate::log::LogManager;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// The LogManager the tables are stored in, see the top of this file.
+pub type TableStore = LogManager<EncodedKey<(String, Vec<u8>)>, Json>;
+
+/// Records of type T by keys of type K, see the top of this file.
+pub struct Table<T, K = String> {
+    name: String,
+    types: PhantomData<fn() -> (T, K)>,
+}
+
+impl<T: Serialize + DeserializeOwned, K: OrderedKey> Table<T, K> {
+    pub fn new(name: &str) -> Self {
+        Table { name: name.to_string(), types: PhantomData }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, store: &TableStore, key: &K) -> Result<Option<T>> {
+        match store.search(&self.store_key(key)) {
+            Some(value) => self.record(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Insert or overwrite the record of `key`.
+    pub fn put(&self, store: &mut TableStore, key: &K, record: &T) -> Result<()> {
+        let value = serde_json::to_value(record).map_err(|e| {
+            Error::InvalidArgument(format!("cannot store a record in table {}: {}", self.name, e))
+        })?;
+        store.insert(self.store_key(key), Json(value))
+    }
+
+    pub fn delete(&self, store: &mut TableStore, key: &K) -> Result<()> {
+        store.delete(&self.store_key(key))
+    }
+
+    /// The records whose key is in `range`, in key order. `..` scans the whole table.
+    pub fn scan<R: RangeBounds<K>>(&self, store: &TableStore, range: R) -> Result<Vec<(K, T)>> {
+        let (table_start, table_end) = keycodec::prefix_range(&(self.name.clone(),));
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(self.store_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.store_key(key)),
+            Bound::Unbounded => table_start,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(self.store_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.store_key(key)),
+            Bound::Unbounded => table_end,
+        };
+        let mut records = Vec::new();
+        for (store_key, value) in store.range((start, end)) {
+            let (_, key) = store_key.get();
+            records.push((keycodec::decode(&key)?, self.record(value)?));
+        }
+        Ok(records)
+    }
+
+    fn store_key(&self, key: &K) -> EncodedKey<(String, Vec<u8>)> {
+        EncodedKey::new(&(self.name.clone(), keycodec::encode(key)))
+    }
+
+    fn record(&self, value: Json) -> Result<T> {
+        serde_json::from_value(value.0).map_err(|e| {
+            Error::Corruption(format!("record of table {} does not match its type: {}", self.name, e))
+        })
+    }
+}
@@ -0,0 +1,287 @@
+// src/testing.rs
+
+/*
+* Model-based testing
+*
+* A `ModelTest` runs a random sequence of operations, from a seed, against a store and against
+* a BTreeMap, the model of what the store should hold, and compares the two as it goes:
+*
+*   set / del       a write of a key, after which the store must read the key as the model does
+*   batch           a WriteBatch (see batch.rs) of a few writes, every key checked after it
+*   get / range     a read, which must give what the model gives
+*   flush / compact the store moves its data around, and must still hold all of the model
+*   restart         the store is dropped and opened again on the same files, and must still
+*                   hold all of the model
+*
+* The keys are drawn from a small set (`ModelTest::keys`), so that the writes keep hitting the
+* same keys and the deletes find something to delete, and a value is the number of the
+* operation that wrote it. The stores are opened on a MemFs (see vfs.rs) with the Options of the
+* test, so the configurations in use (a tiny write buffer, a tombstone retention, ...) get the
+* same treatment as the defaults.
+*
+* A store is driven through the `Subject` trait, implemented here for LogManager and LsmTree.
+* Implement it for a store of your own (one that wraps a LogManager with an index, say) to run
+* the test against it. The first difference from the model fails the run with a Corruption
+* error naming the seed and the operation, so the sequence can be run again to that point.
+*/
+
+use crate::batch::{BatchOp, WriteBatch};
+use crate::error::{Error, Result};
+use crate::log::LogManager;
+use crate::lsm::LsmTree;
+use crate::options::Options;
+use crate::vfs::{MemFs, Vfs};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+
+const DIR: &str = "db";
+
+/// A store a `ModelTest` can run against, see the top of this file.
+pub trait Subject: Sized {
+    /// Open (or create) the store in `dir`.
+    fn open(vfs: Arc<dyn Vfs>, dir: &Path, options: &Options) -> Result<Self>;
+    fn set(&mut self, key: &str, value: &str) -> Result<()>;
+    fn del(&mut self, key: &str) -> Result<()>;
+    fn get(&mut self, key: &str) -> Result<Option<String>>;
+    /// The pairs of the keys from `start` to `end`, `end` excluded, in order.
+    fn range(&mut self, start: &str, end: &str) -> Result<Vec<(String, String)>>;
+    fn flush(&mut self) -> Result<()>;
+    fn compact(&mut self) -> Result<()>;
+
+    /// Apply the writes of `batch`, one after the other for a store without batches.
+    fn write_batch(&mut self, batch: &WriteBatch<String, String>) -> Result<()> {
+        for op in batch.ops() {
+            match op {
+                BatchOp::Insert(key, value) => self.set(key, value)?,
+                BatchOp::Delete(key) => self.del(key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Subject for LogManager<String, String> {
+    fn open(vfs: Arc<dyn Vfs>, dir: &Path, options: &Options) -> Result<Self> {
+        LogManager::open_with(vfs, dir, options.clone())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.insert(key.to_string(), value.to_string())
+    }
+
+    fn del(&mut self, key: &str) -> Result<()> {
+        self.delete(&key.to_string())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self.search(&key.to_string()))
+    }
+
+    fn range(&mut self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        Ok(LogManager::range(self, start.to_string()..end.to_string()))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        LogManager::flush(self)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        LogManager::compact(self)
+    }
+
+    fn write_batch(&mut self, batch: &WriteBatch<String, String>) -> Result<()> {
+        LogManager::write_batch(self, batch.clone())
+    }
+}
+
+impl Subject for LsmTree {
+    fn open(vfs: Arc<dyn Vfs>, dir: &Path, options: &Options) -> Result<Self> {
+        LsmTree::open_with(vfs, dir, options.clone())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.insert(key.as_bytes(), value.as_bytes())
+    }
+
+    fn del(&mut self, key: &str) -> Result<()> {
+        self.delete(key.as_bytes())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        LsmTree::get(self, key.as_bytes())?.map(text).transpose()
+    }
+
+    fn range(&mut self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        let pairs = LsmTree::range(self, start.as_bytes().to_vec()..end.as_bytes().to_vec())?;
+        pairs.into_iter().map(|(key, value)| Ok((text(key)?, text(value)?))).collect()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        LsmTree::flush(self)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        LsmTree::compact(self)
+    }
+}
+
+fn text(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| Error::Corruption("a key or value is not UTF-8".to_string()))
+}
+
+/// The random operations of a run, see the top of this file.
+#[derive(Clone, Debug)]
+pub struct ModelTest {
+    pub seed: u64,
+    pub operations: usize,
+    /// How many different keys the operations use.
+    pub keys: usize,
+    /// The chance of every operation to be a restart, 0 for none.
+    pub restarts: f64,
+    /// The options the store is opened with.
+    pub options: Options,
+}
+
+impl Default for ModelTest {
+    fn default() -> Self {
+        ModelTest { seed: 0, operations: 1000, keys: 100, restarts: 0.02, options: Options::default() }
+    }
+}
+
+/// The operations a run did, by kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModelReport {
+    pub sets: usize,
+    pub dels: usize,
+    pub batches: usize,
+    pub gets: usize,
+    pub ranges: usize,
+    pub flushes: usize,
+    pub compactions: usize,
+    pub restarts: usize,
+}
+
+impl ModelTest {
+    /// Run the operations against a new store of type S, see the top of this file.
+    pub fn run<S: Subject>(&self) -> Result<ModelReport> {
+        let vfs: Arc<dyn Vfs> = Arc::new(MemFs::new());
+        let dir = Path::new(DIR);
+        let mut store = S::open(vfs.clone(), dir, &self.options)?;
+        let mut model = BTreeMap::new();
+        let mut report = ModelReport::default();
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let keys = self.keys.max(1);
+        let key = |rng: &mut StdRng| format!("key{:06}", rng.gen_range(0..keys));
+
+        for step in 0..self.operations {
+            let value = format!("v{}", step);
+            let check = |what: &str, store: &dyn Debug, model: &dyn Debug| {
+                let message = format!(
+                    "seed {}, operation {} ({}): the store has {:?}, the model {:?}",
+                    self.seed, step, what, store, model
+                );
+                Err(Error::Corruption(message))
+            };
+            let mut written = Vec::new();
+            let mut moved = None;
+            if rng.gen_bool(self.restarts.clamp(0.0, 1.0)) {
+                drop(store);
+                store = S::open(vfs.clone(), dir, &self.options)?;
+                report.restarts += 1;
+                moved = Some("restart");
+            } else {
+                match rng.gen_range(0..100) {
+                    0..=39 => {
+                        let key = key(&mut rng);
+                        store.set(&key, &value)?;
+                        model.insert(key.clone(), value.clone());
+                        written.push(key);
+                        report.sets += 1;
+                    }
+                    40..=54 => {
+                        let key = key(&mut rng);
+                        store.del(&key)?;
+                        model.remove(&key);
+                        written.push(key);
+                        report.dels += 1;
+                    }
+                    55..=64 => {
+                        let mut batch = WriteBatch::new();
+                        for _ in 0..rng.gen_range(1..=5) {
+                            let key = key(&mut rng);
+                            if rng.gen_bool(0.7) {
+                                batch.insert(key.clone(), value.clone());
+                                model.insert(key.clone(), value.clone());
+                            } else {
+                                batch.delete(key.clone());
+                                model.remove(&key);
+                            }
+                            written.push(key);
+                        }
+                        store.write_batch(&batch)?;
+                        report.batches += 1;
+                    }
+                    65..=79 => {
+                        let key = key(&mut rng);
+                        let got = store.get(&key)?;
+                        if got.as_ref() != model.get(&key) {
+                            return check(&format!("get {}", key), &got, &model.get(&key));
+                        }
+                        report.gets += 1;
+                    }
+                    80..=91 => {
+                        let (a, b) = (key(&mut rng), key(&mut rng));
+                        let (start, end) = if a <= b { (a, b) } else { (b, a) };
+                        let got = store.range(&start, &end)?;
+                        let in_range = model.range(start.clone()..end.clone());
+                        let expected: Vec<(String, String)> =
+                            in_range.map(|(k, v)| (k.clone(), v.clone())).collect();
+                        if got != expected {
+                            return check(&format!("range {}..{}", start, end), &got, &expected);
+                        }
+                        report.ranges += 1;
+                    }
+                    92..=96 => {
+                        store.flush()?;
+                        report.flushes += 1;
+                        moved = Some("flush");
+                    }
+                    _ => {
+                        store.compact()?;
+                        report.compactions += 1;
+                        moved = Some("compact");
+                    }
+                }
+            }
+            if let Some(what) = moved {
+                let all = store.range("", "~")?;
+                if !all.iter().cloned().eq(model.clone()) {
+                    return check(what, &all, &model);
+                }
+            }
+            for key in written {
+                let got = store.get(&key)?;
+                if got.as_ref() != model.get(&key) {
+                    return check(&format!("write of {}", key), &got, &model.get(&key));
+                }
+            }
+        }
+
+        // and all of it once more, from the files
+        drop(store);
+        let mut store = S::open(vfs, dir, &self.options)?;
+        let all = store.range("", "~")?;
+        if !all.iter().cloned().eq(model.clone()) {
+            let message = format!(
+                "seed {}, reopened at the end: the store has {:?}, the model {:?}",
+                self.seed, all, model
+            );
+            return Err(Error::Corruption(message));
+        }
+        Ok(report)
+    }
+}
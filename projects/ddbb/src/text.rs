@@ -0,0 +1,151 @@
+// src/text.rs
+
+/*
+* Text index
+*
+* `LogManager::search_text("error timeout")` finds the keys whose value mentions both words,
+* without scanning every pair. The text index behind it maps every token of the values to the
+* keys that have it, an inverted index:
+*
+*   "a" -> "Error: timeout after 30s"          "30s"     -> ["a"]
+*   "b" -> "timeout"                           "after"   -> ["a"]
+*   "c" -> "disk error"                        "disk"    -> ["c"]
+*                                              "error"   -> ["a", "c"]
+*                                              "timeout" -> ["a", "b"]
+*
+* The tokens of a value are the runs of letters and digits of its text form (the string itself
+* for a String), lowercased. The escapes in the text form of a Json (see json.rs), like "\n" or
+* "\u0020", separate tokens like the whitespace they stand for.
+*
+* A query is a list of terms, and a key matches if its value has every one of them. A term
+* ending in '*' matches every token it is a prefix of, "time*" finds "timeout" and "timer".
+* Terms are tokenized like the values, so "Error:" is the term "error".
+*
+* Like the secondary indexes of index.rs the index is derived from the pairs and lives in
+* memory, kept up to date by every insert and delete. Unlike them it needs nothing from the
+* caller to be rebuilt, so it does not have to be declared again after `open`: creating or
+* dropping it writes a TEXT_INDEX record to the log, and a compaction starts the new log with
+* one if the index exists. Replaying that record builds the index from the pairs recovered so
+* far, and the records after it keep it up to date, the same as when it was created.
+*/
+
+use crate::btree::BTree;
+use crate::error::{Error, Result};
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::ops::Bound;
+
+pub(crate) struct TextIndex<K: Ord + Clone + Debug> {
+    // token -> keys whose value has it, sorted
+    postings: BTree<String, Vec<K>>,
+}
+
+impl<K: Ord + Clone + Debug> TextIndex<K> {
+    pub fn new() -> Self {
+        TextIndex { postings: BTree::new() }
+    }
+
+    pub fn add(&mut self, key: &K, text: &str) {
+        for token in tokenize(text) {
+            match self.postings.search_mut(&token) {
+                Some(keys) => {
+                    if let Err(i) = keys.binary_search(key) {
+                        keys.insert(i, key.clone());
+                    }
+                }
+                None => {
+                    self.postings.insert(token, vec![key.clone()]);
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K, text: &str) {
+        for token in tokenize(text) {
+            if let Some(keys) = self.postings.search_mut(&token) {
+                if let Ok(i) = keys.binary_search(key) {
+                    keys.remove(i);
+                }
+                if keys.is_empty() {
+                    self.postings.delete(&token);
+                }
+            }
+        }
+    }
+
+    /// The keys matching every term of `query`, in key order.
+    pub fn search(&self, query: &str) -> Result<Vec<K>> {
+        let mut matches: Option<BTreeSet<K>> = None;
+        let mut terms = 0;
+        for term in query.split_whitespace() {
+            let (term, prefix) = match term.strip_suffix('*') {
+                Some(term) => (term, true),
+                None => (term, false),
+            };
+            // the '*' applies to the last run of the term only, "e-mai*" is "e" and "mai*"
+            let tokens = runs(term);
+            let last = tokens.len().saturating_sub(1);
+            for (i, token) in tokens.into_iter().enumerate() {
+                let keys = self.keys(token, prefix && i == last);
+                matches = Some(match matches {
+                    Some(matches) => matches.intersection(&keys).cloned().collect(),
+                    None => keys,
+                });
+                terms += 1;
+            }
+        }
+        if terms == 0 {
+            return Err(Error::InvalidArgument(format!("no terms to search for in {:?}", query)));
+        }
+        Ok(matches.unwrap_or_default().into_iter().collect())
+    }
+
+    // The keys having `token`, or any token starting with it if `prefix`
+    fn keys(&self, token: String, prefix: bool) -> BTreeSet<K> {
+        if !prefix {
+            return self.postings.search(&token).into_iter().flatten().cloned().collect();
+        }
+        let mut keys = BTreeSet::new();
+        self.postings.visit_range(&(Bound::Included(token.clone()), Bound::Unbounded), |other, with| {
+            if !other.starts_with(&token) {
+                return false;
+            }
+            keys.extend(with.iter().cloned());
+            true
+        });
+        keys
+    }
+}
+
+/// The distinct tokens of `text`, see the top of this file.
+pub(crate) fn tokenize(text: &str) -> BTreeSet<String> {
+    runs(text).into_iter().collect()
+}
+
+// The tokens of `text` in the order they appear
+fn runs(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() {
+            token.extend(c.to_lowercase());
+            continue;
+        }
+        if c == '\\' {
+            // an escape of a Json, a separator
+            if chars.next_if(|&c| c.is_ascii_alphabetic()) == Some('u') {
+                for _ in 0..4 {
+                    chars.next_if(char::is_ascii_hexdigit);
+                }
+            }
+        }
+        if !token.is_empty() {
+            tokens.push(std::mem::take(&mut token));
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
@@ -0,0 +1,226 @@
+// src/tls.rs
+
+/*
+* TLS
+*
+* The servers of server.rs and http.rs, and the client of client.rs, can run their connections
+* over TLS (rustls), so the keys and values they carry cannot be read or changed by whoever is
+* on the network in between. It is off unless configured:
+*
+*   let tls = ServerTls::from_pem_files("server.crt", "server.key")?;
+*   let server = Server::bind_with(address, db, ServerOptions { tls: Some(tls), ..Default::default() })?;
+*
+*   let tls = ClientTls::from_pem_file("ca.crt", "db.example.com")?;
+*   let client = Client::connect_with(address, ClientOptions { tls: Some(tls), ..Default::default() })?;
+*
+* `ServerTls` is the certificate chain the server presents and its private key. `ClientTls` is
+* the certificates the client trusts (only those, not the ones of the system: a database is
+* usually reached with a certificate of a private CA, or a self-signed one) and the name the
+* server's certificate must be for. There are no client certificates: TLS here hides the
+* traffic and proves to the client which server it talks to, it does not tell the server who
+* the client is.
+*
+* The cryptography is rustls' ring provider, given explicitly so it does not matter which
+* providers other crates of the program enable.
+*
+* Streams
+*
+* `Stream` is a connection either way, plain or TLS. The handshake is done when the Stream is
+* made (a certificate the client does not trust is an error of `Client::connect`, not of its
+* first request), and `try_clone` gives a second handle on the same connection, like the one
+* of TcpStream, so the code reading requests and the code writing responses each have theirs.
+* The handles of a TLS connection share its session behind a mutex, they are meant to be used
+* by one thread, one after the other. When the last handle is dropped the session is closed
+* with a close_notify, so the peer can tell the end of the connection from one that was cut.
+*/
+
+use crate::error::{Error, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, ConnectionCommon, RootCertStore, ServerConfig, ServerConnection};
+use rustls::{SideData, StreamOwned};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::DerefMut;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// What a server needs to accept TLS connections, see the top of this file.
+#[derive(Clone, Debug)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// From a PEM certificate chain (the server's certificate first) and a PEM private key.
+    pub fn from_pem(certificates: &[u8], key: &[u8]) -> Result<Self> {
+        let certificates = parse_certificates(certificates)?;
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| bad_pem("private key", e))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(bad_config)?
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .map_err(bad_config)?;
+        Ok(ServerTls { config: Arc::new(config) })
+    }
+
+    /// Same as `from_pem`, reading the two files.
+    pub fn from_pem_files(certificates: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        Self::from_pem(&std::fs::read(certificates)?, &std::fs::read(key)?)
+    }
+}
+
+/// What a client needs to make TLS connections, see the top of this file.
+#[derive(Clone, Debug)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    /// Trusting the PEM certificates of `roots`, for a server whose certificate is for
+    /// `server_name` (a DNS name or an IP address).
+    pub fn from_pem(roots: &[u8], server_name: &str) -> Result<Self> {
+        let mut store = RootCertStore::empty();
+        for certificate in parse_certificates(roots)? {
+            store.add(certificate).map_err(bad_config)?;
+        }
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|_| Error::InvalidArgument(format!("{:?} is not a server name", server_name)))?;
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(bad_config)?
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        Ok(ClientTls { config: Arc::new(config), server_name })
+    }
+
+    /// Same as `from_pem`, reading the file.
+    pub fn from_pem_file(roots: impl AsRef<Path>, server_name: &str) -> Result<Self> {
+        Self::from_pem(&std::fs::read(roots)?, server_name)
+    }
+}
+
+fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_slice_iter(pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| bad_pem("certificate", e))?;
+    if certificates.is_empty() {
+        return Err(Error::InvalidArgument("no certificate in the PEM".to_string()));
+    }
+    Ok(certificates)
+}
+
+fn bad_pem(what: &str, error: impl fmt::Display) -> Error {
+    Error::InvalidArgument(format!("bad {} PEM: {}", what, error))
+}
+
+fn bad_config(error: impl fmt::Display) -> Error {
+    Error::InvalidArgument(format!("bad TLS configuration: {}", error))
+}
+
+// ####################################################################################
+
+// A TLS session on its socket, either side
+trait Session: Read + Write + Send {
+    fn close(&mut self);
+}
+
+impl<C, S> Session for StreamOwned<C, TcpStream>
+where
+    C: DerefMut<Target = ConnectionCommon<S>> + Send,
+    S: SideData,
+{
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        // the peer may be gone already, there is no one to tell then
+        let _ = self.conn.complete_io(&mut self.sock);
+    }
+}
+
+/// A connection, plain or TLS, see the top of this file.
+pub(crate) struct Stream {
+    tcp: TcpStream,
+    session: Option<Arc<Mutex<dyn Session>>>,
+}
+
+impl Stream {
+    /// The server side of a connection, TLS if `tls` is given.
+    pub(crate) fn accept(tcp: TcpStream, tls: Option<&ServerTls>) -> Result<Self> {
+        let Some(tls) = tls else {
+            return Ok(Stream { tcp, session: None });
+        };
+        let mut connection = ServerConnection::new(tls.config.clone()).map_err(bad_config)?;
+        let mut socket = tcp.try_clone()?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+        Ok(Self::tls(tcp, StreamOwned::new(connection, socket)))
+    }
+
+    /// The client side of a connection, TLS if `tls` is given.
+    pub(crate) fn connect(tcp: TcpStream, tls: Option<&ClientTls>) -> Result<Self> {
+        let Some(tls) = tls else {
+            return Ok(Stream { tcp, session: None });
+        };
+        let server_name = tls.server_name.clone();
+        let mut connection = ClientConnection::new(tls.config.clone(), server_name).map_err(bad_config)?;
+        let mut socket = tcp.try_clone()?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+        Ok(Self::tls(tcp, StreamOwned::new(connection, socket)))
+    }
+
+    fn tls(tcp: TcpStream, session: impl Session + 'static) -> Self {
+        Stream { tcp, session: Some(Arc::new(Mutex::new(session))) }
+    }
+
+    pub(crate) fn try_clone(&self) -> Result<Self> {
+        Ok(Stream { tcp: self.tcp.try_clone()?, session: self.session.clone() })
+    }
+
+    /// Limit the reads of every handle of the connection, None for no limit. A read that runs
+    /// out of time fails with WouldBlock or TimedOut, and a TLS session is still usable then.
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.tcp.set_read_timeout(timeout)?)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.session {
+            Some(session) => session.lock().unwrap().read(buf),
+            None => self.tcp.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.session {
+            Some(session) => session.lock().unwrap().write(buf),
+            None => self.tcp.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.session {
+            Some(session) => session.lock().unwrap().flush(),
+            None => self.tcp.flush(),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            if Arc::strong_count(&session) == 1 {
+                session.lock().unwrap().close();
+            }
+        }
+    }
+}
@@ -0,0 +1,373 @@
+// src/txn.rs
+
+/*
+* Transactions
+*
+* A `TransactionDb` owns a LogManager and runs transactions on it: reads and writes of several
+* keys that other transactions see all at once when it commits, or not at all.
+*
+*   let db = TransactionDb::new(LogManager::open(vfs, "db")?);
+*   let mut txn = db.begin();
+*   let balance: i64 = txn.get("ann")?.map_or(0, |b| b.parse().unwrap());
+*   txn.set("ann", &(balance - 10).to_string())?;
+*   txn.commit()?;
+*
+* Isolation
+*
+* Transactions coordinate through the locks of lock.rs, one per key, and the store itself is
+* only locked for the time of a single read or of the commit. What a transaction sees of the
+* others running at the same time is its `Isolation`, given to `begin_with` (`begin` takes the
+* one of `TransactionOptions`):
+*
+*   Serializable        a read takes the Shared lock of its key and a write the Exclusive one,
+*                       until the transaction ends (two-phase locking): whatever ran at the
+*                       same time, the result is that of running the transactions one after
+*                       the other, in the order they committed. The default.
+*   SnapshotIsolation   reads take no lock and see the store as it was when the transaction
+*                       began, with none of the commits since. A write takes the Exclusive lock
+*                       of its key, and fails with `Error::Conflict` if another transaction
+*                       committed a write of the key since the snapshot (the first to write
+*                       wins), so no update is lost.
+*   ReadCommitted       reads take no lock and see the last commit, so reading a key twice can
+*                       give two values. A write takes the Exclusive lock of its key.
+*
+* The cheaper levels wait less, and let through more of what serializable transactions rule
+* out. Under snapshot isolation, two transactions can each read what the other writes and
+* both commit, as neither writes a key the other wrote (write skew): with two doctors on call
+* and the rule that one must stay, both can read that the other is on call, and both leave.
+* Serializable transactions wait for each other there instead. Read committed lets through
+* lost updates too: two transactions read the same counter, and both write it plus one.
+*
+* Snapshots
+*
+* The commits are numbered, and a snapshot is the number of the last one when the transaction
+* began. The LogManager only has the last value of a key, so while there are snapshots, every
+* commit keeps the values its writes replace, by key and commit number. A read at a snapshot
+* takes the value replaced by the first commit after it, or the value in the store if there is
+* none. The values are kept until no snapshot is older than their commit, so a long transaction
+* under snapshot isolation keeps every value written over since it began (`versions` counts
+* them).
+*
+* Optimistic transactions
+*
+* Where transactions seldom touch the same keys, taking the locks costs more than the rare
+* conflicts they prevent. The transactions of `begin_optimistic` take no lock while they run:
+* they read at a snapshot like under snapshot isolation, and remember the keys they read from
+* the store. At the commit the store checks that none of them was written by a commit since
+* the snapshot, and the commit fails with `Error::Conflict` (the transaction is rolled back)
+* if one was: what the transaction wrote may depend on a value that is no longer there, and
+* the way out is to run it again. So optimistic transactions are serializable, in the order
+* they committed, but they find out about a conflict at the end, having done all their work.
+* Only the commit takes the Exclusive locks of the keys it writes, for the time it takes, so
+* that it does not write under the other transactions holding them.
+*
+* Locking
+*
+* A transaction waits at most `TransactionOptions::lock_timeout` for a lock, and the read or
+* write fails with an Io error of kind TimedOut (see lock.rs), which is also how two
+* transactions waiting for each other find out. The transaction is still there after such a
+* failure, with what it did before; the usual thing to do is to roll it back, which lets the
+* other one go on, and to try again.
+*
+* Writes
+*
+* The writes of a transaction wait in it until the commit, and its own reads see them. The
+* commit applies them as one WriteBatch (see batch.rs), so after a crash either all of them
+* are there or none is. If the batch fails (a unique index, see index.rs) nothing of it is
+* applied and the transaction is rolled back. Dropping a transaction that did not commit rolls
+* it back.
+*/
+
+use crate::batch::WriteBatch;
+use crate::error::{Error, Result};
+use crate::lock::{LockManager, LockMode, TxnId};
+use crate::log::LogManager;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+type Db = LogManager<String, String>;
+
+/// What a transaction sees of the others, see the top of this file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Isolation {
+    ReadCommitted,
+    SnapshotIsolation,
+    #[default]
+    Serializable,
+}
+
+#[derive(Clone, Debug)]
+pub struct TransactionOptions {
+    /// How long a read or a write waits for the lock of its key.
+    pub lock_timeout: Duration,
+    /// The isolation of the transactions of `begin`.
+    pub isolation: Isolation,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        TransactionOptions { lock_timeout: Duration::from_secs(1), isolation: Isolation::default() }
+    }
+}
+
+struct Store {
+    db: Db,
+    // the number of commits so far
+    commits: u64,
+    // for the keys written since the oldest snapshot, (commit, the value it replaced), oldest
+    // first
+    history: HashMap<String, Vec<(u64, Option<String>)>>,
+    // the snapshots of the running transactions, as commit -> number of transactions
+    snapshots: BTreeMap<u64, usize>,
+}
+
+impl Store {
+    // The value of `key` after `snapshot` commits
+    fn get_at(&self, key: &str, snapshot: u64) -> Option<String> {
+        let versions = self.history.get(key).map(Vec::as_slice).unwrap_or_default();
+        match versions.iter().find(|(commit, _)| *commit > snapshot) {
+            Some((_, value)) => value.clone(),
+            None => self.db.search(&key.to_string()),
+        }
+    }
+
+    // Whether a commit after `snapshot`, which is a live one, wrote `key`
+    fn written_since(&self, key: &str, snapshot: u64) -> bool {
+        let last = self.history.get(key).and_then(|versions| versions.last());
+        last.is_some_and(|(commit, _)| *commit > snapshot)
+    }
+
+    fn release(&mut self, snapshot: u64) {
+        let oldest = self.snapshots.keys().next() == Some(&snapshot);
+        let count = self.snapshots.get_mut(&snapshot).unwrap();
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        self.snapshots.remove(&snapshot);
+        if oldest {
+            // nobody reads what was replaced up to the oldest snapshot left
+            let oldest = self.snapshots.keys().next().copied().unwrap_or(self.commits);
+            self.history.retain(|_, versions| {
+                versions.retain(|(commit, _)| *commit > oldest);
+                !versions.is_empty()
+            });
+        }
+    }
+}
+
+/// A LogManager that runs transactions, see the top of this file.
+pub struct TransactionDb {
+    store: Mutex<Store>,
+    locks: LockManager<String>,
+    next: AtomicU64,
+    options: TransactionOptions,
+}
+
+impl TransactionDb {
+    pub fn new(db: Db) -> Self {
+        Self::with_options(db, TransactionOptions::default())
+    }
+
+    pub fn with_options(db: Db, options: TransactionOptions) -> Self {
+        let store = Store { db, commits: 0, history: HashMap::new(), snapshots: BTreeMap::new() };
+        let (locks, next) = (LockManager::new(), AtomicU64::new(1));
+        TransactionDb { store: Mutex::new(store), locks, next, options }
+    }
+
+    /// Start a transaction at the isolation of the options.
+    pub fn begin(&self) -> Transaction<'_> {
+        self.begin_with(self.options.isolation)
+    }
+
+    /// Start a transaction at `isolation`.
+    pub fn begin_with(&self, isolation: Isolation) -> Transaction<'_> {
+        let snapshot = (isolation == Isolation::SnapshotIsolation).then(|| self.snapshot());
+        self.transaction(isolation, snapshot, None)
+    }
+
+    /// Start an optimistic transaction, see the top of this file.
+    pub fn begin_optimistic(&self) -> Transaction<'_> {
+        self.transaction(Isolation::Serializable, Some(self.snapshot()), Some(HashSet::new()))
+    }
+
+    fn transaction(
+        &self,
+        isolation: Isolation,
+        snapshot: Option<u64>,
+        reads: Option<HashSet<String>>,
+    ) -> Transaction<'_> {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        Transaction { db: self, id, isolation, snapshot, reads, writes: BTreeMap::new(), done: false }
+    }
+
+    // A snapshot at the last commit, given back by `Store::release`
+    fn snapshot(&self) -> u64 {
+        let mut store = self.store.lock().unwrap();
+        let commits = store.commits;
+        *store.snapshots.entry(commits).or_insert(0) += 1;
+        commits
+    }
+
+    /// The locks of the transactions, to see who holds or waits for a key.
+    pub fn locks(&self) -> &LockManager<String> {
+        &self.locks
+    }
+
+    /// The values kept for the snapshots of the running transactions.
+    pub fn versions(&self) -> usize {
+        self.store.lock().unwrap().history.values().map(Vec::len).sum()
+    }
+
+    /// The LogManager, once no transaction is left.
+    pub fn into_inner(self) -> Db {
+        self.store.into_inner().unwrap().db
+    }
+}
+
+/// Reads and writes applied together, see the top of this file.
+pub struct Transaction<'a> {
+    db: &'a TransactionDb,
+    id: TxnId,
+    isolation: Isolation,
+    // the commit the transaction reads at, with SnapshotIsolation or optimistic
+    snapshot: Option<u64>,
+    // the keys read from the store, for an optimistic transaction
+    reads: Option<HashSet<String>>,
+    // the writes to apply at the commit, None to delete the key
+    writes: BTreeMap<String, Option<String>>,
+    done: bool,
+}
+
+impl Transaction<'_> {
+    /// The id the transaction holds its locks as.
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    /// The isolation of the transaction, Serializable for an optimistic one.
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    pub fn is_optimistic(&self) -> bool {
+        self.reads.is_some()
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        if self.isolation == Isolation::Serializable && !self.is_optimistic() {
+            self.lock(key, LockMode::Shared)?;
+        }
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(reads) = &mut self.reads {
+            reads.insert(key.to_string());
+        }
+        let store = self.db.store.lock().unwrap();
+        Ok(match self.snapshot {
+            Some(snapshot) => store.get_at(key, snapshot),
+            None => store.db.search(&key.to_string()),
+        })
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.write(key, Some(value.to_string()))
+    }
+
+    pub fn del(&mut self, key: &str) -> Result<()> {
+        self.write(key, None)
+    }
+
+    /// Apply the writes of the transaction and give back its locks. An optimistic transaction
+    /// fails with `Error::Conflict` if a key it read was written since it began.
+    pub fn commit(mut self) -> Result<()> {
+        let result = self.apply();
+        self.finish();
+        result
+    }
+
+    /// Drop the writes of the transaction and give back its locks.
+    pub fn rollback(mut self) {
+        self.finish();
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        let writes = std::mem::take(&mut self.writes);
+        // the snapshot of a transaction that only reads is as good as any
+        if writes.is_empty() {
+            return Ok(());
+        }
+        if self.is_optimistic() {
+            for key in writes.keys() {
+                self.lock(key, LockMode::Exclusive)?;
+            }
+        }
+        let mut store = self.db.store.lock().unwrap();
+        if let (Some(reads), Some(snapshot)) = (&self.reads, self.snapshot) {
+            if let Some(key) = reads.iter().find(|key| store.written_since(key, snapshot)) {
+                return Err(conflict(key, self.id));
+            }
+        }
+        let replaced: Vec<(String, Option<String>)> =
+            writes.keys().map(|key| (key.clone(), store.db.search(key))).collect();
+        let mut batch = WriteBatch::new();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.delete(key),
+            };
+        }
+        let result = store.db.write_batch(batch);
+        if result.is_ok() {
+            store.commits += 1;
+            if !store.snapshots.is_empty() {
+                let commit = store.commits;
+                for (key, value) in replaced {
+                    store.history.entry(key).or_default().push((commit, value));
+                }
+            }
+        }
+        result
+    }
+
+    fn write(&mut self, key: &str, value: Option<String>) -> Result<()> {
+        if !self.is_optimistic() {
+            self.lock(key, LockMode::Exclusive)?;
+            // with the lock nobody else commits the key before this transaction ends
+            if let Some(snapshot) = self.snapshot {
+                if self.db.store.lock().unwrap().written_since(key, snapshot) {
+                    return Err(conflict(key, self.id));
+                }
+            }
+        }
+        self.writes.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn lock(&self, key: &str, mode: LockMode) -> Result<()> {
+        self.db.locks.lock(self.id, &key.to_string(), mode, self.db.options.lock_timeout)
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        self.db.locks.unlock_all(self.id);
+        if let Some(snapshot) = self.snapshot.take() {
+            self.db.store.lock().unwrap().release(snapshot);
+        }
+    }
+}
+
+fn conflict(key: &str, txn: TxnId) -> Error {
+    Error::Conflict(format!("{} was written since transaction {} began", key, txn))
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.finish();
+        }
+    }
+}
@@ -0,0 +1,754 @@
+// src/vfs.rs
+
+/*
+* Virtual File System
+*
+* Every piece of storage code (the LogManager today, other engines later) goes through the `Vfs`
+* trait instead of calling `std::fs` directly. This gives us two things:
+*
+* 1. `RealFs` simply forwards to the operating system, this is what is used in production.
+* 2. `MemFs` keeps every file in memory, so tests are deterministic, do not touch the working
+*    directory, and can inject faults (lost fsyncs, torn writes, crashes at any point).
+*
+* A file is opened with `Vfs::open` and returns a `VfsFile` handle, which is a normal
+* `Read + Write + Seek` object plus `sync` (the fsync equivalent). Directory level operations
+* (rename, remove, lock, ...) live on the `Vfs` itself.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Mirrors the subset of `std::fs::OpenOptions` that the storage code needs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub create: bool,
+    pub truncate: bool,
+    /// Bypass the OS page cache (O_DIRECT), see DirectFile. Only RealFs on Linux does
+    /// anything with it.
+    pub direct: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        OpenOptions::default()
+    }
+
+    pub fn read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn direct(mut self, direct: bool) -> Self {
+        self.direct = direct;
+        self
+    }
+}
+
+/// An open file handle returned by a `Vfs`.
+pub trait VfsFile: Read + Write + Seek + Send {
+    /// Make every write issued so far durable (fsync).
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Current length of the file in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncate or extend the file to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+/// An exclusive lock on a path, released when dropped.
+pub trait VfsLock: Send {}
+
+pub trait Vfs: Send + Sync {
+    fn open(&self, path: &Path, options: OpenOptions) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Read the whole file into memory.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut file = self.open(path, OpenOptions::new().read(true))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Replace the content of the file with `data` and sync it.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = self.open(path, OpenOptions::new().write(true).create(true).truncate(true))?;
+        file.write_all(data)?;
+        file.sync()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Give the file `from` a second name `to` (a hard link), or copy it where that is not
+    /// possible. `to` must not exist.
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// List the files directly inside `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Make directory entries (creations, renames, removals) inside `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Take an exclusive lock on `path`, failing with `WouldBlock` if someone else holds it.
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn VfsLock>>;
+}
+
+// ##############################################################################################
+// RealFs: forwards everything to std::fs
+// ##############################################################################################
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl VfsFile for fs::File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+}
+
+struct RealLock {
+    _file: fs::File, // the OS lock is released when the file is closed
+}
+
+impl VfsLock for RealLock {}
+
+impl Vfs for RealFs {
+    fn open(&self, path: &Path, options: OpenOptions) -> io::Result<Box<dyn VfsFile>> {
+        #[cfg(target_os = "linux")]
+        if options.direct {
+            return Ok(Box::new(DirectFile::open(path, options)?));
+        }
+
+        let file = fs::OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .create(options.create)
+            .truncate(options.truncate)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match fs::hard_link(from, to) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists && e.kind() != io::ErrorKind::NotFound => {
+                // e.g. across file systems
+                fs::copy(from, to)?;
+                fs::File::open(to)?.sync_all()
+            }
+            result => result,
+        }
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        // Directories can only be fsynced on unix, elsewhere this is a no-op
+        if cfg!(unix) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn VfsLock>> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(RealLock { _file: file })),
+            Err(fs::TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is locked by another process", path.display()),
+            )),
+            Err(fs::TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+/*
+* DirectFile: a file opened with O_DIRECT, so its data moves straight between our buffers and
+* the device instead of being cached a second time by the kernel (the engines have their own
+* caches). The catch is that O_DIRECT only transfers whole blocks, from buffers, at offsets
+* and with lengths that are all aligned to the block size of the device, while the storage
+* code reads and writes any range. DirectFile widens every transfer to the aligned blocks that
+* cover it:
+*
+* - a read reads the covering blocks into an aligned buffer and copies out the range
+* - a write first reads the partial blocks at both ends of its range, patches the range in and
+*   writes the blocks back, then trims the file to its real length if the last block went past
+*   it (with a preallocated file, see `Options::wal_preallocate`, that never happens)
+*
+* so small writes cost a read and a write of whole blocks; direct I/O pays off for large
+* sequential transfers and when the page cache would only duplicate the engine's caches.
+*
+* Positions are tracked here and every transfer is positioned (pread/pwrite), so O_APPEND is
+* emulated rather than passed to the kernel. File systems that refuse O_DIRECT (tmpfs for
+* example) get the same code over a normally opened file.
+*/
+
+#[cfg(target_os = "linux")]
+const DIRECT_ALIGN: u64 = 4096;
+
+#[cfg(target_os = "linux")]
+struct DirectFile {
+    file: fs::File,
+    pos: u64,
+    len: u64,
+    append: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectFile {
+    fn open(path: &Path, options: OpenOptions) -> io::Result<DirectFile> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        // writes read the blocks they partially cover, so the file is always readable
+        let mut open = fs::OpenOptions::new();
+        open.read(true)
+            .write(options.write || options.append)
+            .create(options.create)
+            .truncate(options.truncate);
+        let file = match open.clone().custom_flags(libc::O_DIRECT).open(path) {
+            Ok(file) => file,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => open.open(path)?,
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        Ok(DirectFile { file, pos: 0, len, append: options.append })
+    }
+
+    // Fill `buf` (aligned, starting at the aligned `offset`) with the file content, zeros past
+    // the end of the file
+    fn read_blocks(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let mut done = 0;
+        while done < buf.len() {
+            match self.file.read_at(&mut buf[done..], offset + done as u64)? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        buf[done..].fill(0);
+        Ok(())
+    }
+}
+
+// A heap buffer whose start is aligned for direct I/O
+#[cfg(target_os = "linux")]
+struct AlignedBuf {
+    raw: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let raw = vec![0; len + DIRECT_ALIGN as usize];
+        let start = raw.as_ptr().align_offset(DIRECT_ALIGN as usize);
+        AlignedBuf { raw, start, len }
+    }
+
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[self.start..self.start + self.len]
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn align_down(offset: u64) -> u64 {
+    offset / DIRECT_ALIGN * DIRECT_ALIGN
+}
+
+#[cfg(target_os = "linux")]
+fn align_up(offset: u64) -> u64 {
+    offset.div_ceil(DIRECT_ALIGN) * DIRECT_ALIGN
+}
+
+#[cfg(target_os = "linux")]
+impl Read for DirectFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        let start = align_down(self.pos);
+        let mut blocks = AlignedBuf::new((align_up(self.pos + n as u64) - start) as usize);
+        self.read_blocks(start, blocks.as_mut())?;
+        let skip = (self.pos - start) as usize;
+        buf[..n].copy_from_slice(&blocks.as_mut()[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+
+        if self.append {
+            self.pos = self.len;
+        }
+        let end = self.pos + buf.len() as u64;
+        let (start, stop) = (align_down(self.pos), align_up(end));
+        let mut blocks = AlignedBuf::new((stop - start) as usize);
+        let data = blocks.as_mut();
+
+        // keep what is already in the partially overwritten blocks at both ends
+        let last = data.len() - DIRECT_ALIGN as usize;
+        if self.pos > start {
+            self.read_blocks(start, &mut data[..DIRECT_ALIGN as usize])?;
+        }
+        if end < stop && (last > 0 || self.pos == start) {
+            self.read_blocks(stop - DIRECT_ALIGN, &mut data[last..])?;
+        }
+
+        let skip = (self.pos - start) as usize;
+        data[skip..skip + buf.len()].copy_from_slice(buf);
+        self.file.write_all_at(data, start)?;
+        self.len = self.len.max(end);
+        if stop > self.len {
+            self.file.set_len(self.len)?;
+        }
+        self.pos = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Seek for DirectFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl VfsFile for DirectFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+}
+
+// ##############################################################################################
+// MemFs: everything lives in memory, cloning a MemFs shares the same files
+// ##############################################################################################
+
+/*
+* Besides being fast and deterministic, MemFs models what a real disk keeps after a power loss,
+* which lets tests exercise crash consistency:
+*
+* - Every file remembers the content it had at its last `sync` (the durable content) next to
+*   its current content.
+* - The directory namespace (which name points to which file) is only durable once `sync_dir`
+*   has been called on the parent directory, so a rename or a newly created file may vanish.
+*
+* `fail_after(n)` arms a crash point: the n-th mutating operation (and every one after it) fails
+* as if the machine had died. `power_loss` then throws away everything that was not durable,
+* optionally keeping a random prefix of the unsynced tail of each file (a torn write), and the
+* file system is usable again so the database can be reopened and checked.
+*/
+
+/// What happens to unsynced data when the power goes out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerLoss {
+    /// Every write that was not synced is lost.
+    DropUnsynced,
+    /// Appended but unsynced bytes survive up to a random point (seeded), modelling torn writes.
+    TruncateUnsynced { seed: u64 },
+}
+
+#[derive(Default)]
+struct MemNode {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+}
+
+type NodeRef = Arc<Mutex<MemNode>>;
+
+#[derive(Default)]
+struct MemState {
+    files: HashMap<PathBuf, NodeRef>,
+    durable_files: HashMap<PathBuf, NodeRef>,
+    dirs: HashSet<PathBuf>,
+    locks: HashSet<PathBuf>,
+    mutations: usize,
+    crash_at: Option<usize>,
+}
+
+impl MemState {
+    // Called before every mutating operation, fails once the armed crash point is reached
+    fn mutate(&mut self) -> io::Result<()> {
+        if let Some(crash_at) = self.crash_at {
+            if self.mutations >= crash_at {
+                return Err(io::Error::other("simulated power loss"));
+            }
+        }
+        self.mutations += 1;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemFs {
+    state: Arc<Mutex<MemState>>,
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        MemFs::default()
+    }
+
+    /// Number of mutating operations (writes, syncs, renames, ...) performed so far.
+    pub fn mutations(&self) -> usize {
+        self.state.lock().unwrap().mutations
+    }
+
+    /// Let `ops` more mutating operations succeed, then fail every following one.
+    pub fn fail_after(&self, ops: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.crash_at = Some(state.mutations + ops);
+    }
+
+    /// Simulate a power loss: revert every file and directory entry to its durable state,
+    /// drop all locks and disarm the crash point.
+    pub fn power_loss(&self, mode: PowerLoss) {
+        let mut state = self.state.lock().unwrap();
+        let mut rng = match mode {
+            PowerLoss::DropUnsynced => None,
+            PowerLoss::TruncateUnsynced { seed } => Some(StdRng::seed_from_u64(seed)),
+        };
+
+        // visit the files in a stable order so a seed always produces the same outcome
+        let mut paths: Vec<PathBuf> = state.durable_files.keys().cloned().collect();
+        paths.sort();
+        for path in &paths {
+            let mut node = state.durable_files[path].lock().unwrap();
+            let MemNode { data, durable } = &mut *node;
+            let mut survived = durable.clone();
+            if let Some(rng) = rng.as_mut() {
+                // only appended bytes can be torn, an overwrite in place is lost completely
+                if data.len() > durable.len() && data.starts_with(durable) {
+                    let keep = rng.gen_range(0..=data.len() - durable.len());
+                    survived.extend_from_slice(&data[durable.len()..durable.len() + keep]);
+                }
+            }
+            *data = survived.clone();
+            *durable = survived;
+        }
+
+        state.files = state.durable_files.clone();
+        state.locks.clear();
+        state.crash_at = None;
+    }
+}
+
+struct MemFile {
+    state: Arc<Mutex<MemState>>,
+    node: NodeRef,
+    pos: u64,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.readable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file not opened for reading"));
+        }
+        let node = self.node.lock().unwrap();
+        let start = (self.pos as usize).min(node.data.len());
+        let n = buf.len().min(node.data.len() - start);
+        buf[..n].copy_from_slice(&node.data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file not opened for writing"));
+        }
+        self.state.lock().unwrap().mutate()?;
+        let mut node = self.node.lock().unwrap();
+        if self.append {
+            self.pos = node.data.len() as u64;
+        }
+        let start = self.pos as usize;
+        if node.data.len() < start {
+            node.data.resize(start, 0);
+        }
+        let overlap = buf.len().min(node.data.len() - start);
+        node.data[start..start + overlap].copy_from_slice(&buf[..overlap]);
+        node.data.extend_from_slice(&buf[overlap..]);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.node.lock().unwrap().data.len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl VfsFile for MemFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().mutate()?;
+        let mut node = self.node.lock().unwrap();
+        node.durable = node.data.clone();
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.node.lock().unwrap().data.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.state.lock().unwrap().mutate()?;
+        self.node.lock().unwrap().data.resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+struct MemLock {
+    state: Arc<Mutex<MemState>>,
+    path: PathBuf,
+}
+
+impl VfsLock for MemLock {}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().locks.remove(&self.path);
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+impl Vfs for MemFs {
+    fn open(&self, path: &Path, options: OpenOptions) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.state.lock().unwrap();
+        let node = match state.files.get(path) {
+            Some(node) => node.clone(),
+            None if options.create => {
+                state.mutate()?;
+                let node = NodeRef::default();
+                state.files.insert(path.to_path_buf(), node.clone());
+                node
+            }
+            None => return Err(not_found(path)),
+        };
+        if options.truncate {
+            state.mutate()?;
+            node.lock().unwrap().data.clear();
+        }
+        Ok(Box::new(MemFile {
+            state: self.state.clone(),
+            node,
+            pos: 0,
+            readable: options.read,
+            writable: options.write || options.append,
+            append: options.append,
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.files.contains_key(from) {
+            return Err(not_found(from));
+        }
+        state.mutate()?;
+        let node = state.files.remove(from).unwrap();
+        state.files.insert(to.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let node = state.files.get(from).cloned().ok_or_else(|| not_found(from))?;
+        if state.files.contains_key(to) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to.display())));
+        }
+        state.mutate()?;
+        state.files.insert(to.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.files.contains_key(path) {
+            return Err(not_found(path));
+        }
+        state.mutate()?;
+        state.files.remove(path);
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for ancestor in path.ancestors() {
+            state.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        let mut paths: Vec<PathBuf> = state
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.mutate()?;
+        let MemState { files, durable_files, .. } = &mut *state;
+        durable_files.retain(|path, _| path.parent() != Some(dir));
+        for (path, node) in files.iter() {
+            if path.parent() == Some(dir) {
+                durable_files.insert(path.clone(), node.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn VfsLock>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.locks.insert(path.to_path_buf()) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is already locked", path.display()),
+            ));
+        }
+        Ok(Box::new(MemLock { state: self.state.clone(), path: path.to_path_buf() }))
+    }
+}
@@ -0,0 +1,116 @@
+// src/watch.rs
+
+/*
+* Watches
+*
+* `LogManager::watch` gives a `Watch` on the keys that start with a prefix (in their text form,
+* like the prefixes of query.rs), which receives an `Event` for every write of such a key once
+* it is in the log:
+*
+*   let watch = db.watch("user:");
+*   db.insert("user:1".to_string(), "ann".to_string())?;
+*   assert_eq!(watch.recv(), Some(Event::Set("user:1".to_string(), "ann".to_string())));
+*
+* The events come in the order of the writes, the ones of a batch (see batch.rs) one after the
+* other. A DEL is an event even if the key was not there: a watch is for invalidating caches
+* and refreshing views, it tells what was written, not what changed. The writer does not wait
+* for the watches, it queues the event and goes on, so a watch that is not read only costs the
+* memory of its queue.
+*
+* That queue holds WATCH_CAPACITY events. A watch that falls further behind is dropped: it gets
+* what was queued and then ends (`recv` returns None), and whoever reads it has to look at the
+* keys again (a SCAN) before watching again, it missed some writes. A watch also ends with the
+* LogManager. Dropping a Watch is all it takes to stop it.
+*
+* This is what the SUBSCRIBE of the server is built on, see server.rs.
+*/
+
+use std::fmt::Display;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::Duration;
+
+/// How many events a Watch can be behind before it is dropped.
+pub const WATCH_CAPACITY: usize = 1024;
+
+/// A write seen by a Watch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event<K, V> {
+    Set(K, V),
+    Delete(K),
+}
+
+impl<K, V> Event<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Event::Set(key, _) | Event::Delete(key) => key,
+        }
+    }
+}
+
+/// The events of the keys of a prefix, see the top of this file.
+pub struct Watch<K, V> {
+    events: Receiver<Event<K, V>>,
+}
+
+impl<K, V> Watch<K, V> {
+    /// The next event, waiting for it. None once the watch ended.
+    pub fn recv(&self) -> Option<Event<K, V>> {
+        self.events.recv().ok()
+    }
+
+    /// Same as `recv`, waiting no longer than `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event<K, V>, RecvTimeoutError> {
+        self.events.recv_timeout(timeout)
+    }
+
+    /// The next event if there is one already, without waiting.
+    pub fn try_recv(&self) -> Option<Event<K, V>> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl<K, V> Iterator for Watch<K, V> {
+    type Item = Event<K, V>;
+
+    fn next(&mut self) -> Option<Event<K, V>> {
+        self.recv()
+    }
+}
+
+/// The sending ends of the watches of a LogManager.
+pub(crate) struct Watchers<K, V> {
+    watchers: Vec<(String, SyncSender<Event<K, V>>)>,
+}
+
+impl<K: Display + Clone, V: Clone> Watchers<K, V> {
+    pub fn new() -> Self {
+        Watchers { watchers: Vec::new() }
+    }
+
+    pub fn add(&mut self, prefix: &str) -> Watch<K, V> {
+        let (sender, events) = mpsc::sync_channel(WATCH_CAPACITY);
+        self.watchers.push((prefix.to_string(), sender));
+        Watch { events }
+    }
+
+    /// Queue `event` for the watches of its key, dropping the ones that are gone or full.
+    pub fn notify(&mut self, event: Event<K, V>) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        let key = event.key().to_string();
+        self.watchers.retain(|(prefix, sender)| {
+            if !key.starts_with(prefix.as_str()) {
+                return true;
+            }
+            match sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    eprintln!("Dropping a watch of {:?}, it is {} events behind", prefix, WATCH_CAPACITY);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
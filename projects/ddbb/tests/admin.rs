@@ -0,0 +1,149 @@
+mod common;
+
+use common::serve;
+use ddbb::admin::{self, Admin};
+use ddbb::auth::{Acl, Auth};
+use ddbb::client::{Client, ClientOptions};
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::query::QueryResult;
+use ddbb::server::ServerOptions;
+use ddbb::vfs::{MemFs, Vfs};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+fn stat(stats: &[(String, String)], name: &str) -> String {
+    stats.iter().find(|(stat, _)| stat == name).map(|(_, value)| value.clone()).unwrap()
+}
+
+#[test]
+fn test_backup() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db = LogManager::open(vfs.clone(), "db").unwrap();
+    db.insert("a".to_string(), 1).unwrap();
+    db.compact().unwrap();
+    db.insert("b".to_string(), 2).unwrap();
+    db.delete(&"a".to_string()).unwrap();
+    db.backup("backups/1").unwrap();
+    // what comes after the backup is not in it
+    db.insert("c".to_string(), 3).unwrap();
+    db.compact().unwrap();
+
+    let copy = LogManager::<String, i32>::open(vfs.clone(), "backups/1").unwrap();
+    assert_eq!(copy.range(..), vec![("b".to_string(), 2)]);
+    assert_eq!(copy.tombstone_stats().live, 1);
+    drop(copy);
+
+    // a directory holding a database is not overwritten, this one's own either
+    for dir in ["backups/1", "db"] {
+        assert!(matches!(db.backup(dir), Err(Error::InvalidArgument(_))), "{}", dir);
+    }
+    // a backup of a database that was never compacted, in a directory a failed one left behind
+    let fresh = Arc::new(MemFs::new());
+    fresh.create_dir_all(Path::new("copy")).unwrap();
+    fresh.write(Path::new("copy/data.sst"), b"junk").unwrap();
+    let mut db = LogManager::open(fresh.clone(), "db").unwrap();
+    db.insert("x".to_string(), 1).unwrap();
+    db.backup("copy").unwrap();
+    let copy = LogManager::<String, i32>::open(fresh.clone(), "copy").unwrap();
+    assert_eq!(copy.range(..), vec![("x".to_string(), 1)]);
+}
+
+#[test]
+fn test_commands() {
+    assert_eq!(Admin::parse("compact").unwrap(), Some(Admin::Compact));
+    assert_eq!(Admin::parse(" Backup  /tmp/b ").unwrap(), Some(Admin::Backup("/tmp/b".to_string())));
+    assert_eq!(Admin::parse("GET a").unwrap(), None);
+    assert_eq!(Admin::parse("").unwrap(), None);
+    for bad in ["BACKUP", "BACKUP a b", "STATS now", "FLUSH all"] {
+        assert!(matches!(Admin::parse(bad), Err(Error::InvalidArgument(_))), "{}", bad);
+    }
+    for admin in [Admin::Compact, Admin::Flush, Admin::Stats, Admin::Backup("dir".to_string())] {
+        assert_eq!(Admin::parse(&admin.to_string()).unwrap(), Some(admin));
+    }
+
+    let mut db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    db.insert("a".to_string(), "1".to_string()).unwrap();
+    assert_eq!(db.checkpoint(), None);
+    assert_eq!(admin::execute(&mut db, &Admin::Flush).unwrap(), QueryResult::Done);
+    assert_eq!(db.checkpoint(), Some(1));
+    // nothing to flush, but a compaction anyway
+    admin::execute(&mut db, &Admin::Flush).unwrap();
+    admin::execute(&mut db, &Admin::Compact).unwrap();
+    let QueryResult::Pairs { pairs, next: None } = admin::execute(&mut db, &Admin::Stats).unwrap() else {
+        panic!("STATS is pairs");
+    };
+    assert_eq!((stat(&pairs, "pairs"), stat(&pairs, "checkpoint")), ("1".to_string(), "2".to_string()));
+    assert_eq!(stat(&pairs, "flushes"), "1");
+    assert!(pairs.iter().all(|(name, value)| !name.contains(' ') && !value.contains(' ')));
+
+    let users = Acl { read_only: false, prefixes: Some(vec!["user:".to_string()]) };
+    for acl in [users, Acl { read_only: true, prefixes: None }] {
+        assert!(matches!(acl.check_admin(), Err(Error::PermissionDenied(_))));
+    }
+    assert!(Acl::default().check_admin().is_ok());
+}
+
+fn options() -> ServerOptions {
+    let auth = Auth::parse("token admin read-write\ntoken app read-write app:\n").unwrap();
+    ServerOptions { auth: Some(auth), ..ServerOptions::default() }
+}
+
+#[test]
+fn test_server() {
+    let vfs = Arc::new(MemFs::new());
+    let address = serve(LogManager::open(vfs.clone(), "db").unwrap(), options());
+    let stream = TcpStream::connect(address).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut request = |line: &str| {
+        writeln!(writer, "{}", line).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        response.trim_end().to_string()
+    };
+    assert_eq!(request("COMPACT"), "ERR permission denied: AUTH first");
+    assert_eq!(request("AUTH app"), "OK");
+    assert_eq!(request("SET app:1 x"), "OK");
+    assert!(request("compact").starts_with("ERR permission denied: admin commands"));
+    assert_eq!(request("AUTH admin"), "OK");
+    assert_eq!(request("compact"), "OK");
+    assert_eq!(request("BACKUP copy"), "OK");
+    assert!(request("BACKUP").starts_with("ERR invalid argument"));
+    assert_eq!(request("MULTI"), "OK");
+    assert!(request("FLUSH").starts_with("ERR invalid argument: only SET and DEL can be queued, not FLUSH"));
+    assert_eq!(request("DISCARD"), "OK");
+    assert!(vfs.exists(Path::new("copy/log.txt")));
+
+    let header = request("STATS");
+    let count: usize = header.strip_prefix("PAIRS ").unwrap().parse().unwrap();
+    let stats: Vec<String> = reader.lines().take(count).map(|line| line.unwrap()).collect();
+    assert!(stats.contains(&"pairs 1".to_string()), "{:?}", stats);
+    assert!(stats.contains(&"checkpoint 1".to_string()), "{:?}", stats);
+}
+
+#[test]
+fn test_client() {
+    let vfs = Arc::new(MemFs::new());
+    let address = serve(LogManager::open(vfs.clone(), "db").unwrap(), options());
+    let options = |token: &str| ClientOptions {
+        credentials: Some(ddbb::auth::Credentials::Token(token.to_string())),
+        ..ClientOptions::default()
+    };
+    let mut app = Client::connect_with(address, options("app")).unwrap();
+    app.set("app:1", "x").unwrap();
+    assert!(matches!(app.compact(), Err(Error::PermissionDenied(_))));
+
+    let mut admin = Client::connect_with(address, options("admin")).unwrap();
+    admin.flush().unwrap();
+    admin.compact().unwrap();
+    let stats = admin.stats().unwrap();
+    assert_eq!((stat(&stats, "pairs"), stat(&stats, "checkpoint")), ("1".to_string(), "2".to_string()));
+    admin.backup("copy").unwrap();
+    assert!(matches!(admin.backup("copy"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(admin.backup("a b"), Err(Error::InvalidArgument(_))));
+    let copy = LogManager::<String, String>::open(vfs, "copy").unwrap();
+    assert_eq!(copy.search(&"app:1".to_string()), Some("x".to_string()));
+}
@@ -0,0 +1,55 @@
+use ddbb::log::{Aggregate, LogManager};
+use ddbb::query::{self, QueryResult};
+use ddbb::vfs::MemFs;
+use std::sync::Arc;
+
+fn open() -> LogManager<u64, String> {
+    LogManager::open(Arc::new(MemFs::new()), "db").unwrap()
+}
+
+#[test]
+fn test_count_range() {
+    let mut db = open();
+    for key in 0..300u64 {
+        db.insert(key * 3, format!("v{}", key)).unwrap();
+    }
+    for key in (0..300u64).step_by(10) {
+        db.delete(&(key * 3)).unwrap();
+    }
+
+    assert_eq!(db.count_range(..), 270);
+    assert_eq!(db.count_range(0..30), 9);
+    assert_eq!(db.count_range(1..=30), 9);
+    assert_eq!(db.count_range(897..), 1);
+    assert_eq!(db.count_range(1000..), 0);
+    assert_eq!(db.count_range(31..32), 0);
+    for (start, end) in [(0, 900), (5, 500), (450, 451), (899, 900)] {
+        assert_eq!(db.count_range(start..end), db.range(start..end).len());
+    }
+    assert_eq!(query::run(&mut db, "COUNT 30..60").unwrap(), QueryResult::Count(9));
+}
+
+#[test]
+fn test_aggregate_range() {
+    let mut db = open();
+    // numbers, and a few values that are not
+    for key in 1..=100u64 {
+        let value = if key % 25 == 0 { "n/a".to_string() } else { format!("{}", key as f64 / 2.0) };
+        db.insert(key, value).unwrap();
+    }
+    let amount = |value: &String| value.parse::<f64>().ok();
+
+    let all = db.aggregate_range(.., amount);
+    assert_eq!(all.count, 96);
+    assert_eq!((all.min, all.max), (Some(0.5), Some(49.5)));
+    assert_eq!(all.sum, (1..=100).filter(|k| k % 25 != 0).map(|k| k as f64 / 2.0).sum::<f64>());
+
+    let some = db.aggregate_range(20..=30, amount);
+    assert_eq!(some, Aggregate { count: 10, min: Some(10.0), max: Some(15.0), sum: 125.0 });
+
+    let integers = db.aggregate_range(..10, |value| value.parse::<i64>().ok());
+    assert_eq!(integers, Aggregate { count: 4, min: Some(1), max: Some(4), sum: 10 });
+
+    let none = db.aggregate_range(200.., amount);
+    assert_eq!(none, Aggregate { count: 0, min: None, max: None, sum: 0.0 });
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::serve;
+use ddbb::auth::{Acl, Auth, Credentials};
+use ddbb::client::{Client, ClientOptions};
+use ddbb::error::Error;
+use ddbb::http::HttpServer;
+use ddbb::log::LogManager;
+use ddbb::query::{self, Query};
+use ddbb::server::{Server, ServerOptions};
+use ddbb::vfs::MemFs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+const CREDENTIALS: &str = "
+# the application, everything
+token s3cret read-write
+user ann hunter2 read-write user: team:   # their own keyspaces
+token viewer read-only
+";
+
+fn options() -> ServerOptions {
+    ServerOptions { auth: Some(Auth::parse(CREDENTIALS).unwrap()), ..ServerOptions::default() }
+}
+
+fn db() -> LogManager<String, String> {
+    let mut db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    query::run(&mut db, "SET user:1 ann").unwrap();
+    query::run(&mut db, "SET admin:1 root").unwrap();
+    db
+}
+
+fn parse(query: &str) -> Query {
+    query::parse(query).unwrap()
+}
+
+fn client(address: SocketAddr, credentials: Credentials) -> ddbb::error::Result<Client> {
+    let options = ClientOptions { credentials: Some(credentials), ..ClientOptions::default() };
+    Client::connect_with(address, options)
+}
+
+#[test]
+fn test_acl() {
+    let everything = Acl::default();
+    let reader = Acl { read_only: true, prefixes: None };
+    let users = Acl { read_only: false, prefixes: Some(vec!["user:".to_string(), "team:".to_string()]) };
+    for query in ["GET a", "SET a 1", "DEL a", "SCAN ..", "COUNT *"] {
+        assert!(everything.check(&parse(query)).is_ok(), "{}", query);
+    }
+    for (query, allowed) in [("GET a", true), ("SCAN ..", true), ("SET a 1", false), ("DEL a", false)] {
+        assert_eq!(reader.check(&parse(query)).is_ok(), allowed, "{}", query);
+    }
+    for (query, allowed) in [
+        ("GET user:1", true),
+        ("SET team:1 red", true),
+        ("DEL admin:1", false),
+        ("GET user", false),
+        ("SCAN user:1*", true),
+        ("COUNT user:*", true),
+        ("SCAN *", false),
+        ("SCAN user:1..user:5", true),
+        ("SCAN user:1..team:5", false),
+        ("SCAN user:1..", false),
+        ("COUNT ..user:5", false),
+    ] {
+        let checked = users.check(&parse(query));
+        assert_eq!(checked.is_ok(), allowed, "{}", query);
+        assert!(checked.is_ok() || matches!(checked, Err(Error::PermissionDenied(_))));
+    }
+}
+
+#[test]
+fn test_parse() {
+    let auth = Auth::parse(CREDENTIALS).unwrap();
+    let acl = auth.authenticate(&Credentials::Token("viewer".to_string())).unwrap();
+    assert_eq!(acl, &Acl { read_only: true, prefixes: None });
+    let ann = Credentials::User { name: "ann".to_string(), password: "hunter2".to_string() };
+    let prefixes = Some(vec!["user:".to_string(), "team:".to_string()]);
+    assert_eq!(auth.authenticate(&ann).unwrap().prefixes, prefixes);
+
+    // a password is not a token, nor the other way round
+    let wrong = Credentials::User { name: "ann".to_string(), password: "hunter3".to_string() };
+    let tokens = [Credentials::Token("hunter2".to_string()), Credentials::Token("s3cre".to_string())];
+    for credentials in [[wrong].as_slice(), &tokens].concat() {
+        assert!(matches!(auth.authenticate(&credentials), Err(Error::PermissionDenied(_))));
+    }
+    for bad in ["token", "token t", "token t read", "user ann read-only", "group g read-only"] {
+        assert!(matches!(Auth::parse(bad), Err(Error::InvalidArgument(_))), "{}", bad);
+    }
+    let options = ClientOptions { credentials: Some(ann), ..ClientOptions::default() };
+    assert!(!format!("{:?}", options).contains("hunter2"));
+}
+
+#[test]
+fn test_server() {
+    let address = serve(db(), options());
+    let stream = TcpStream::connect(address).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut request = |line: &str| {
+        writeln!(writer, "{}", line).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        response.trim_end().to_string()
+    };
+    assert_eq!(request("GET user:1"), "ERR permission denied: AUTH first");
+    assert_eq!(request("AUTH wrong"), "ERR permission denied: bad credentials");
+    assert_eq!(request("AUTH viewer"), "OK");
+    assert_eq!(request("GET user:1"), "VALUE ann");
+    assert_eq!(request("SET user:1 bo"), "ERR permission denied: the access is read-only");
+    assert_eq!(request("AUTH ann hunter2"), "OK");
+    assert_eq!(request("SET user:1 bo"), "OK");
+    assert!(request("GET admin:1").starts_with("ERR permission denied: no access"));
+    assert_eq!(request("AUTH ann nope"), "ERR permission denied: bad credentials");
+    assert_eq!(request("GET user:1"), "ERR permission denied: AUTH first");
+}
+
+#[test]
+fn test_client() {
+    let address = serve(db(), options());
+    let bad = Credentials::Token("wrong".to_string());
+    assert!(matches!(client(address, bad), Err(Error::PermissionDenied(_))));
+
+    let ann = Credentials::User { name: "ann".to_string(), password: "hunter2".to_string() };
+    let mut ann = client(address, ann).unwrap();
+    ann.set("team:1", "red").unwrap();
+    assert!(matches!(ann.get("admin:1"), Err(Error::PermissionDenied(_))));
+    let mut app = client(address, Credentials::Token("s3cret".to_string())).unwrap();
+    assert_eq!(app.get("team:1").unwrap(), Some("red".to_string()));
+    assert_eq!(app.get("admin:1").unwrap(), Some("root".to_string()));
+
+    // credentials on a server without authentication are a mistake worth hearing about
+    let server = Server::bind("127.0.0.1:0", db()).unwrap();
+    let open = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    assert!(matches!(client(open, Credentials::Token("s3cret".to_string())), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_http() {
+    let server = HttpServer::bind_with("127.0.0.1:0", db(), options()).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    let get = |target: &str, authorization: Option<&str>| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let header = authorization.map(|value| format!("Authorization: {}\r\n", value)).unwrap_or_default();
+        write!(stream, "GET {} HTTP/1.0\r\n{}\r\n", target, header).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    assert!(get("/health", None).starts_with("HTTP/1.1 200 "));
+    let anonymous = get("/keys/user:1", None);
+    assert!(anonymous.starts_with("HTTP/1.1 401 "), "{}", anonymous);
+    assert!(anonymous.contains("WWW-Authenticate: Bearer"), "{}", anonymous);
+    assert!(get("/keys/user:1", Some("Bearer wrong")).starts_with("HTTP/1.1 401 "));
+    assert!(get("/keys/user:1", Some("Bearer viewer")).starts_with("HTTP/1.1 200 "));
+
+    // ann:hunter2
+    let ann = Some("Basic YW5uOmh1bnRlcjI=");
+    assert!(get("/keys/user:1", ann).starts_with("HTTP/1.1 200 "));
+    assert!(get("/keys/admin:1", ann).starts_with("HTTP/1.1 403 "));
+    assert!(get("/scan?prefix=user:", ann).starts_with("HTTP/1.1 200 "));
+    assert!(get("/scan", ann).starts_with("HTTP/1.1 403 "));
+    assert!(get("/keys/user:1", Some("Basic !!!")).starts_with("HTTP/1.1 401 "));
+}
@@ -0,0 +1,168 @@
+use ddbb::batch::WriteBatch;
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::vfs::{MemFs, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+
+const LOG_PATH: &str = "db/log.txt";
+
+#[test]
+fn test_batch_applies_and_replays() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db = LogManager::open(vfs.clone(), "db").unwrap();
+    db.insert("alice".to_string(), 100).unwrap();
+    db.insert("bob".to_string(), 50).unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.insert("alice".to_string(), 90).insert("bob".to_string(), 60);
+    batch.delete("carol".to_string()).insert("dave".to_string(), 1);
+    batch.insert("dave".to_string(), 2);
+    assert_eq!(batch.len(), 5);
+    db.write_batch(batch).unwrap();
+    db.write_batch(WriteBatch::new()).unwrap();
+    let expected = vec![("alice".to_string(), 90), ("bob".to_string(), 60), ("dave".to_string(), 2)];
+    assert_eq!(db.range(..), expected);
+    drop(db);
+
+    // the batch is one record of the log, and comes back whole
+    let log = String::from_utf8(vfs.read(Path::new(LOG_PATH)).unwrap()).unwrap();
+    assert_eq!(log.lines().count(), 3);
+    let db = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    assert_eq!(db.range(..), expected);
+    assert_eq!(db.recovery_report().records_replayed, 7);
+    assert_eq!(db.tombstone_stats().live, 1);
+}
+
+#[test]
+fn test_damaged_batch_is_dropped_whole() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db = LogManager::open(vfs.clone(), "db").unwrap();
+    db.insert("a".to_string(), 1).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert("b".to_string(), 2).insert("c".to_string(), 3).delete("a".to_string());
+    db.write_batch(batch).unwrap();
+    drop(db);
+    let log = String::from_utf8(vfs.read(Path::new(LOG_PATH)).unwrap()).unwrap();
+
+    // a changed write, and a batch cut short by a crash
+    for damaged in [log.replace("INSERT c 3", "INSERT c 4"), log[..log.len() - 8].to_string()] {
+        vfs.write(Path::new(LOG_PATH), damaged.as_bytes()).unwrap();
+        let db = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+        assert_eq!(db.range(..), vec![("a".to_string(), 1)]);
+        assert_eq!(db.recovery_report().records_replayed, 1);
+    }
+}
+
+#[test]
+fn test_unique_violation_fails_the_whole_batch() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db = LogManager::open(vfs.clone(), "db").unwrap();
+    db.create_unique_index("value", |value: &i32| Some(*value)).unwrap();
+    db.insert("a".to_string(), 1).unwrap();
+    db.insert("b".to_string(), 2).unwrap();
+    db.delete(&"c".to_string()).unwrap();
+    let before = (db.range(..), db.tombstone_stats());
+
+    let mut batch = WriteBatch::new();
+    batch.insert("c".to_string(), 3).delete("a".to_string());
+    batch.insert("d".to_string(), 4).insert("e".to_string(), 2);
+    let result = db.write_batch(batch);
+    assert!(matches!(result, Err(Error::UniqueViolation { .. })), "{:?}", result);
+    // the earlier writes of the batch are undone, tombstones included
+    assert_eq!((db.range(..), db.tombstone_stats()), before);
+    assert_eq!(db.lookup_by_index("value", &1).unwrap(), vec![("a".to_string(), 1)]);
+    assert!(db.lookup_by_index("value", &3).unwrap().is_empty());
+
+    // uniqueness holds against what the batch did before, so a value can change hands
+    let mut batch = WriteBatch::new();
+    batch.delete("b".to_string()).insert("e".to_string(), 2);
+    db.write_batch(batch).unwrap();
+    assert_eq!(db.lookup_by_index("value", &2).unwrap(), vec![("e".to_string(), 2)]);
+    drop(db);
+
+    let db = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    assert_eq!(db.range(..), vec![("a".to_string(), 1), ("e".to_string(), 2)]);
+}
+
+#[test]
+fn test_prepare() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db = LogManager::open(vfs.clone(), "db").unwrap();
+    db.create_unique_index("value", |value: &i32| Some(*value)).unwrap();
+    db.insert("a".to_string(), 1).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert("b".to_string(), 2).delete("a".to_string());
+    db.prepare("t1", batch.clone()).unwrap();
+    db.prepare("t2", batch).unwrap();
+    assert!(matches!(db.prepare("t1", WriteBatch::new()), Err(Error::InvalidArgument(_))));
+    assert!(matches!(db.prepare("t 3", WriteBatch::new()), Err(Error::InvalidArgument(_))));
+    // a batch that could not be applied is not prepared
+    let mut taken = WriteBatch::new();
+    taken.insert("c".to_string(), 1);
+    assert!(matches!(db.prepare("t3", taken), Err(Error::UniqueViolation { .. })));
+
+    // prepared batches are not applied, and are still there after a restart and a compaction
+    assert_eq!(db.range(..), vec![("a".to_string(), 1)]);
+    let prepared = vec![("t1".to_string(), 2), ("t2".to_string(), 2)];
+    assert_eq!(db.prepared(), prepared);
+    drop(db);
+    let mut db = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    assert_eq!(db.prepared(), prepared);
+    db.compact().unwrap();
+    drop(db);
+    let mut db = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    assert_eq!((db.range(..), db.prepared()), (vec![("a".to_string(), 1)], prepared));
+
+    db.commit_prepared("t1").unwrap();
+    db.abort_prepared("t2").unwrap();
+    assert_eq!(db.range(..), vec![("b".to_string(), 2)]);
+    assert!(db.prepared().is_empty());
+    assert!(matches!(db.commit_prepared("t2"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(db.abort_prepared("t1"), Err(Error::InvalidArgument(_))));
+    drop(db);
+    let db = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    assert_eq!((db.range(..), db.prepared()), (vec![("b".to_string(), 2)], Vec::new()));
+}
+
+#[test]
+fn test_prepared_batches_hold_their_index_keys() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db = LogManager::open(vfs.clone(), "db").unwrap();
+    db.create_unique_index("value", |value: &i32| Some(*value)).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert("a".to_string(), 1);
+    db.prepare("t1", batch).unwrap();
+
+    // no other key may take the index key of a prepared insert, until the commit
+    assert!(matches!(db.insert("b".to_string(), 1), Err(Error::UniqueViolation { .. })));
+    let mut other = WriteBatch::new();
+    other.insert("c".to_string(), 1);
+    assert!(matches!(db.write_batch(other.clone()), Err(Error::UniqueViolation { .. })));
+    assert!(matches!(db.prepare("t2", other), Err(Error::UniqueViolation { .. })));
+    db.commit_prepared("t1").unwrap();
+    assert_eq!(db.lookup_by_index("value", &1).unwrap(), vec![("a".to_string(), 1)]);
+    assert!(matches!(db.insert("b".to_string(), 1), Err(Error::UniqueViolation { .. })));
+
+    // or the abort, which frees it
+    let mut batch = WriteBatch::new();
+    batch.insert("d".to_string(), 2);
+    db.prepare("t3", batch).unwrap();
+    assert!(matches!(db.insert("e".to_string(), 2), Err(Error::UniqueViolation { .. })));
+    db.abort_prepared("t3").unwrap();
+    db.insert("e".to_string(), 2).unwrap();
+
+    // an index declared while a batch is prepared holds its index keys too
+    let mut batch = WriteBatch::new();
+    batch.insert("f".to_string(), 3);
+    db.prepare("t4", batch).unwrap();
+    drop(db);
+    let mut db = LogManager::<String, i32>::open(vfs, "db").unwrap();
+    db.insert("g".to_string(), 3).unwrap();
+    let result = db.create_unique_index("value", |value: &i32| Some(*value));
+    assert!(matches!(result, Err(Error::UniqueViolation { .. })));
+    assert!(matches!(db.lookup_by_index("value", &3), Err(Error::InvalidArgument(_))));
+    db.delete(&"g".to_string()).unwrap();
+    db.create_unique_index("value", |value: &i32| Some(*value)).unwrap();
+    assert!(matches!(db.insert("g".to_string(), 3), Err(Error::UniqueViolation { .. })));
+}
@@ -0,0 +1,63 @@
+use ddbb::bench::{self, Distribution, Workload, Zipfian};
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::options::Options;
+use ddbb::testing::Subject;
+use ddbb::vfs::MemFs;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::path::Path;
+use std::sync::Arc;
+
+fn open() -> LogManager<String, String> {
+    Subject::open(Arc::new(MemFs::new()), Path::new("db"), &Options::default()).unwrap()
+}
+
+#[test]
+fn test_workloads() {
+    for name in ["a", "b", "c", "d", "e", "f"] {
+        let workload = Workload { records: 200, operations: 500, ..Workload::preset(name).unwrap() };
+        let mut db = open();
+        let report = workload.run(&mut db).unwrap();
+        assert_eq!((report.load.operations, report.run.operations), (200, 500));
+        assert_eq!(report.latencies.iter().map(|l| l.count).sum::<usize>(), 500, "{}", report);
+        for l in &report.latencies {
+            assert!(l.p50 <= l.p95 && l.p95 <= l.p99 && l.p99 <= l.p999 && l.p999 <= l.max, "{:?}", l);
+        }
+        // the inserts add the keys after the records
+        let inserts = report.latencies.iter().find(|l| l.operation == "insert").map_or(0, |l| l.count);
+        assert_eq!(db.count_range(..), 200 + inserts, "{}", name);
+        assert!(report.to_string().contains(" ops/s\n"));
+    }
+    let report = Workload { records: 10, operations: 100, read: 1.0, update: 0.0, ..Workload::default() }
+        .run(&mut open())
+        .unwrap();
+    let kinds: Vec<&str> = report.latencies.iter().map(|l| l.operation).collect();
+    assert_eq!(kinds, ["read"]);
+
+    assert!(matches!(Workload::preset("g"), Err(Error::InvalidArgument(_))));
+    let nothing = Workload { read: 0.0, update: 0.0, ..Workload::default() };
+    assert!(matches!(nothing.run(&mut open()), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_distributions() {
+    // the first ranks of a Zipfian take a good part of the draws
+    let mut zipfian = Zipfian::new(1000);
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut counts = vec![0; 1000];
+    for _ in 0..10_000 {
+        counts[zipfian.next(&mut rng, 1000) as usize] += 1;
+    }
+    assert!(counts[0] > 800 && counts[0] > counts[1] && counts[1] > counts[10], "{:?}", &counts[..20]);
+    assert!(counts[500..].iter().sum::<usize>() < 1500);
+
+    // and keep doing so as the items grow
+    for _ in 0..1000 {
+        assert!(zipfian.next(&mut rng, 1500) < 1500);
+    }
+    assert_eq!(bench::key(42), "user0000000042");
+    let distribution = Distribution::Uniform;
+    let uniform = Workload { records: 100, operations: 100, distribution, ..Workload::default() };
+    uniform.run(&mut open()).unwrap();
+}
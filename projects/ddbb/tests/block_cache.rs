@@ -0,0 +1,102 @@
+mod common;
+
+use common::key;
+use ddbb::cache::{BlockCache, CacheStats};
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::sstable::{Table, TableWriter, BLOCK_SIZE};
+use ddbb::vfs::{MemFs, OpenOptions, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+
+const PATH: &str = "db/table.sst";
+
+fn build(vfs: &MemFs, count: u32) {
+    vfs.create_dir_all(Path::new("db")).unwrap();
+    let file = vfs.open(Path::new(PATH), OpenOptions::new().write(true).create(true)).unwrap();
+    let mut writer = TableWriter::new(file);
+    for i in 0..count {
+        writer.add(&key(i), Some(format!("value{}", i).as_bytes())).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+#[test]
+fn test_block_cache_evicts_least_recently_used() {
+    let vfs = MemFs::new();
+    build(&vfs, 2000);
+    // room for two blocks, not three
+    let cache = Arc::new(BlockCache::new(BLOCK_SIZE * 5 / 2));
+    let table = Table::open(&vfs, PATH).unwrap().with_cache(cache.clone());
+    // keys far enough apart to be in different blocks
+    let (a, b, c) = (key(0), key(700), key(1400));
+
+    table.get(&a).unwrap();
+    table.get(&a).unwrap();
+    assert_eq!(cache.stats().hits, 1);
+    assert_eq!(cache.stats().misses, 1);
+
+    table.get(&b).unwrap();
+    table.get(&a).unwrap(); // b is now the least recently used
+    table.get(&c).unwrap();
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 3, 1));
+    assert!(stats.usage <= stats.capacity);
+
+    table.get(&a).unwrap();
+    assert_eq!(cache.stats().hits, 3);
+    table.get(&b).unwrap();
+    assert_eq!(cache.stats().misses, 4);
+
+    // scans go through the cache too, and see the same entries
+    let cached: Vec<_> = table.iter().map(Result::unwrap).collect();
+    let uncached: Vec<_> = Table::open(&vfs, PATH).unwrap().iter().map(Result::unwrap).collect();
+    assert_eq!(cached, uncached);
+    assert!(cache.stats().usage <= cache.stats().capacity);
+}
+
+#[test]
+fn test_lsm_reports_block_cache_hits() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { write_buffer_size: 4096, ..Options::default() };
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options).unwrap();
+    for i in 0..2000 {
+        tree.insert(&key(i), b"value").unwrap();
+    }
+    tree.compact().unwrap();
+
+    // a hot range read over and over only goes to disk the first time
+    for _ in 0..10 {
+        for i in 100..150 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(b"value".to_vec()));
+        }
+    }
+    let stats = tree.metrics().block_cache;
+    assert!(stats.misses > 0);
+    assert!(stats.hit_rate() > 0.9, "{:?}", stats);
+    assert_eq!(stats.capacity, 8 << 20);
+}
+
+#[test]
+fn test_lsm_block_cache_shared_or_disabled() {
+    let vfs = Arc::new(MemFs::new());
+    let cache = Arc::new(BlockCache::new(1 << 20));
+    let shared = Options { block_cache: Some(cache.clone()), ..Options::default() };
+    for dir in ["a", "b"] {
+        let mut tree = LsmTree::open_with(vfs.clone(), dir, shared.clone()).unwrap();
+        tree.insert(b"k", b"v").unwrap();
+        tree.compact().unwrap();
+        drop(tree);
+        let tree = LsmTree::open_with(vfs.clone(), dir, shared.clone()).unwrap();
+        tree.get(b"k").unwrap();
+        tree.get(b"k").unwrap();
+    }
+    // both trees counted in the one cache
+    assert_eq!(cache.stats().hits, 2);
+    assert_eq!(cache.stats().misses, 2);
+
+    let disabled = Options { block_cache_size: 0, ..Options::default() };
+    let tree = LsmTree::open_with(vfs.clone(), "a", disabled).unwrap();
+    tree.get(b"k").unwrap();
+    assert_eq!(tree.metrics().block_cache, CacheStats::default());
+}
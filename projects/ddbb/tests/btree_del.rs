@@ -131,7 +131,7 @@ fn test_large_insert_delete() {
     tree.print();
 
     for (key, value) in keys.iter().zip(values.iter()) {
-        assert_eq!(tree.search(&key), Some(value));
+        assert_eq!(tree.search(key), Some(value));
     }
 
     println!("B-Tree before delete");
@@ -143,7 +143,7 @@ fn test_large_insert_delete() {
     }
 
     for key in keys.iter() {
-        assert_eq!(tree.search(&key), None);
+        assert_eq!(tree.search(key), None);
     }
 }
 
@@ -162,7 +162,7 @@ fn test_large_random_insert_delete() {
     }
 
     for (key, value) in keys.iter().zip(values.iter()) {
-        assert_eq!(tree.search(&key), Some(value));
+        assert_eq!(tree.search(key), Some(value));
     }
 
     keys.shuffle(&mut rng);
@@ -172,6 +172,6 @@ fn test_large_random_insert_delete() {
     }
 
     for key in keys.iter() {
-        assert_eq!(tree.search(&key), None);
+        assert_eq!(tree.search(key), None);
     }
 }
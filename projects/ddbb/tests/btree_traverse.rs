@@ -12,7 +12,7 @@ fn test_traverse() {
         "orange".to_string(),
         "kiwi".to_string(),
     ];
-    let values = vec![2, 3, 7, 5, 4];
+    let values = [2, 3, 7, 5, 4];
 
     for (key, value) in keys.iter().zip(values.iter()) {
         tree.insert(key.clone(), *value);
@@ -28,7 +28,7 @@ fn test_traverse() {
     assert_eq!(sorted_keys, expected_keys);
 
     for (key, value) in sorted_keys.iter().zip(sorted_values.iter()) {
-        assert_eq!(tree.search(&key), Some(value));
+        assert_eq!(tree.search(key), Some(value));
     }
 }
 
@@ -55,3 +55,23 @@ fn test_traverse_sorted_keys() {
 
     assert_eq!(sorted_keys, expected_keys);
 }
+
+#[test]
+fn test_range() {
+    let mut tree = BTree::<i32, i32>::new();
+    let mut keys: Vec<i32> = (0..500).map(|i| i * 2).collect();
+    keys.shuffle(&mut thread_rng());
+    for key in &keys {
+        tree.insert(*key, -key);
+    }
+
+    let in_range = |range: Vec<(i32, i32)>| range.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(in_range(tree.range(&(10..20))), vec![10, 12, 14, 16, 18]);
+    assert_eq!(in_range(tree.range(&(11..=20))), vec![12, 14, 16, 18, 20]);
+    assert_eq!(in_range(tree.range(&(..4))), vec![0, 2]);
+    assert_eq!(in_range(tree.range(&(995..))), vec![996, 998]);
+    assert_eq!(tree.range(&(..)), tree.traverse());
+    assert!(tree.range(&(21..22)).is_empty());
+    assert!(tree.range(&(2000..)).is_empty());
+    assert_eq!(tree.range(&(100..101)), vec![(100, -100)]);
+}
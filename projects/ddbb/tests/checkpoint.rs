@@ -0,0 +1,90 @@
+mod common;
+
+use common::key;
+use ddbb::error::Error;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::vfs::{MemFs, PowerLoss, RealFs, Vfs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+fn options() -> Options {
+    Options { write_buffer_size: 2048, ..Options::default() }
+}
+
+#[test]
+fn test_checkpoint_is_a_restore_point() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options()).unwrap();
+    for i in 0..500 {
+        tree.insert(&key(i), b"before").unwrap();
+    }
+    tree.delete(&key(3)).unwrap();
+    // in runs, and the last writes in the memtable
+    assert!(tree.level_runs().iter().sum::<usize>() > 0);
+    tree.checkpoint_to("backup").unwrap();
+
+    // the tree keeps going, compactions remove files the checkpoint links to
+    for i in 0..500 {
+        tree.insert(&key(i), b"after").unwrap();
+    }
+    tree.compact().unwrap();
+    assert!(!vfs.exists(Path::new("backup/LOCK")));
+
+    let mut backup = LsmTree::open_with(vfs.clone(), "backup", options()).unwrap();
+    let all = backup.traverse().unwrap();
+    assert_eq!(all.len(), 499);
+    assert!(all.iter().all(|(_, value)| value == b"before"));
+    assert_eq!(backup.get(&key(3)).unwrap(), None);
+
+    // and both trees are independent from then on
+    backup.insert(&key(1000), b"backup").unwrap();
+    backup.compact().unwrap();
+    assert_eq!(tree.get(&key(1000)).unwrap(), None);
+    assert_eq!(tree.get(&key(3)).unwrap(), Some(b"after".to_vec()));
+    assert_eq!(tree.traverse().unwrap().len(), 500);
+}
+
+#[test]
+fn test_checkpoint_survives_power_loss() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options()).unwrap();
+    for i in 0..300 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    // not synced in the tree, the checkpoint makes its copy durable
+    tree.checkpoint_to("backup").unwrap();
+    drop(tree);
+    vfs.power_loss(PowerLoss::DropUnsynced);
+    let backup = LsmTree::open_with(vfs.clone(), "backup", options()).unwrap();
+    assert_eq!(backup.traverse().unwrap().len(), 300);
+}
+
+#[test]
+fn test_checkpoint_needs_empty_dir() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open(vfs.clone(), "db").unwrap();
+    tree.insert(b"a", b"1").unwrap();
+    tree.checkpoint_to("backup").unwrap();
+    assert!(matches!(tree.checkpoint_to("backup"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(tree.checkpoint_to("db"), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_checkpoint_on_real_files() {
+    let root = std::env::temp_dir().join(format!("ddbb-checkpoint-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (db, backup): (PathBuf, PathBuf) = (root.join("db"), root.join("backup"));
+
+    let mut tree = LsmTree::open_with(Arc::new(RealFs), &db, options()).unwrap();
+    for i in 0..500 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    tree.checkpoint_to(&backup).unwrap();
+    tree.insert(&key(1000), b"v").unwrap();
+    let restored = LsmTree::open_with(Arc::new(RealFs), &backup, options()).unwrap();
+    assert_eq!(restored.traverse().unwrap().len(), 500);
+    assert!(RealFs.list(&backup).unwrap().iter().any(|path| path.extension().is_some_and(|ext| ext == "sst")));
+    drop((tree, restored));
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,172 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// A fresh directory on the real file system, removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("ddbb-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn ddbb(dir: &TempDir, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ddbb")).arg(&dir.0).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn test_one_shot_commands() {
+    let dir = TempDir::new("cli-one-shot");
+    for (key, value) in [("user:1", "ann"), ("user:2", "bo"), ("team:1", "red")] {
+        let output = ddbb(&dir, &["set", key, value]);
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "OK\n");
+    }
+
+    // only the result is on stdout, the value as is for scripts
+    let output = ddbb(&dir, &["get", "user:2"]);
+    assert_eq!((output.status.code(), stdout(&output)), (Some(0), "bo\n".to_string()));
+    let output = ddbb(&dir, &["get", "nobody"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    assert_eq!(stdout(&ddbb(&dir, &["scan", "user*"])), "user:1 ann\nuser:2 bo\n");
+    let newest_first = stdout(&ddbb(&dir, &["SCAN", "..", "REV", "LIMIT", "5"]));
+    assert_eq!(newest_first, "user:2 bo\nuser:1 ann\nteam:1 red\n");
+    assert!(stdout(&ddbb(&dir, &["scan", "..", "limit", "1"])).contains("(more: after "));
+    assert_eq!(stdout(&ddbb(&dir, &["del", "user:1"])), "OK\n");
+    assert_eq!(stdout(&ddbb(&dir, &["count", "*"])), "2\n");
+
+    assert_eq!(stdout(&ddbb(&dir, &["compact"])), "OK\n");
+    let stats = stdout(&ddbb(&dir, &["stats"]));
+    assert!(stats.contains("pairs: 2\n"), "{}", stats);
+    assert!(stats.contains("checkpoint: 1\n"), "{}", stats);
+    assert_eq!(stdout(&ddbb(&dir, &["flush"])), "OK\n");
+
+    let backups = TempDir::new("cli-backup");
+    let copy = backups.0.join("copy");
+    assert_eq!(stdout(&ddbb(&dir, &["backup", copy.to_str().unwrap()])), "OK\n");
+    assert_eq!(ddbb(&dir, &["backup", copy.to_str().unwrap()]).status.code(), Some(1));
+    let copy = Command::new(env!("CARGO_BIN_EXE_ddbb")).arg(&copy).args(["scan", "*"]).output().unwrap();
+    assert_eq!(stdout(&copy), "team:1 red\nuser:2 bo\n");
+
+    let output = ddbb(&dir, &["frobnicate", "x"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown command"));
+}
+
+#[test]
+fn test_commands_from_stdin() {
+    let dir = TempDir::new("cli-stdin");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ddbb"))
+        .arg(&dir.0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let script = "set a 1\n\nset b 2\nget a\nbad command\nget zzz\ncount ..\nquit\nget b\n";
+    child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    // errors do not end the session, quit does
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "OK\nOK\n1\n(not found)\n2\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("error: invalid argument"));
+}
+
+#[test]
+fn test_serve_flags() {
+    let dir = TempDir::new("cli-serve-flags");
+    for (flags, error) in [
+        (&["--tls-cert", "server.crt"][..], "--tls-cert and --tls-key go together"),
+        (&["--tls-cert"][..], "--tls-cert is missing its file"),
+        (&["--port", "80"][..], "unknown flag --port"),
+        (&["--latency-histograms", "--tls-cert"][..], "--tls-cert is missing its file"),
+        (&["--tls-cert", "none.crt", "--tls-key", "none.key"][..], "I/O error"),
+    ] {
+        let output = ddbb(&dir, &[&["serve", "127.0.0.1:0"][..], flags].concat());
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(error), "{:?}", flags);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_serve_until_signal() {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpStream;
+
+    let dir = TempDir::new("cli-signal");
+    let mut server = Command::new(env!("CARGO_BIN_EXE_ddbb"))
+        .arg(&dir.0)
+        .args(["serve", "127.0.0.1:0"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(server.stderr.take().unwrap());
+    let address = loop {
+        let mut line = String::new();
+        assert!(stderr.read_line(&mut line).unwrap() > 0, "the server did not start");
+        if let Some(address) = line.trim_end().strip_prefix("Serving on ") {
+            break address.to_string();
+        }
+    };
+    let mut stream = TcpStream::connect(&address).unwrap();
+    stream.write_all(b"SET a 1\n").unwrap();
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).unwrap();
+    assert_eq!(response, "OK\n");
+
+    let kill = Command::new("kill").args(["-TERM", &server.id().to_string()]).status().unwrap();
+    assert!(kill.success());
+    let mut rest = String::new();
+    stderr.read_to_string(&mut rest).unwrap();
+    assert!(server.wait().unwrap().success(), "{}", rest);
+    assert!(rest.contains("shutting down"), "{}", rest);
+
+    let stats = stdout(&ddbb(&dir, &["stats"]));
+    assert!(stats.contains("pairs: 1\n"), "{}", stats);
+    assert!(stats.contains("checkpoint: 1\n"), "{}", stats);
+}
+
+#[test]
+fn test_usage() {
+    let output = Command::new(env!("CARGO_BIN_EXE_ddbb")).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("usage: ddbb <dir>"));
+}
+
+#[test]
+fn test_bench() {
+    let dir = TempDir::new("cli-bench");
+    let flags = ["bench", "--records", "100", "--operations", "300", "--workload", "b", "--seed", "3"];
+    let output = ddbb(&dir, &flags);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report = stdout(&output);
+    assert!(report.starts_with("load: 100 operations in "), "{}", report);
+    assert!(report.contains("\nrun: 300 operations in ") && report.contains("\nread   count "), "{}", report);
+    assert!(report.contains(" p99 ") && report.contains(" ops/s\n"), "{}", report);
+    assert_eq!(stdout(&ddbb(&dir, &["count", "user*"])), "100\n");
+
+    let lsm = TempDir::new("cli-bench-lsm");
+    let flags = ["bench", "--engine", "lsm", "--records", "50", "--operations", "50", "--read", "0"];
+    let output = ddbb(&lsm, &flags);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nupdate count 50 "), "{}", stdout(&output));
+    assert_eq!(ddbb(&lsm, &["bench", "--read", "101"]).status.code(), Some(1));
+    assert_eq!(ddbb(&lsm, &["bench", "--engine", "btree"]).status.code(), Some(1));
+}
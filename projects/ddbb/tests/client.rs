@@ -0,0 +1,191 @@
+mod common;
+
+use common::start_server;
+use ddbb::batch::WriteBatch;
+use ddbb::client::{AsyncClient, Client, ClientOptions};
+use ddbb::error::Error;
+use ddbb::query::{Keys, Query, QueryResult};
+use ddbb::scan::ScanOptions;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+// A server that reads one request per connection and answers it with `answers[n]` for the
+// n-th connection, closing the connection without an answer where it is None
+fn fake_server(answers: Vec<Option<&'static str>>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    thread::spawn(move || {
+        for (stream, answer) in listener.incoming().zip(answers) {
+            let mut stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            if let Some(answer) = answer {
+                stream.write_all(answer.as_bytes()).unwrap();
+            }
+        }
+    });
+    (address, connections)
+}
+
+fn quick_retries(retries: usize) -> ClientOptions {
+    ClientOptions { retries, retry_backoff: Duration::from_millis(1), ..ClientOptions::default() }
+}
+
+fn prefix(prefix: &str) -> Keys {
+    Keys::Prefix(prefix.to_string())
+}
+
+// Enough of an executor to run a future to completion on this thread
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[test]
+fn test_requests() {
+    let mut client = Client::connect(start_server()).unwrap();
+    for (key, value) in [("user:1", "ann"), ("user:2", "bo"), ("user:3", "cy"), ("team:1", "red")] {
+        client.set(key, value).unwrap();
+    }
+    assert_eq!(client.get("user:2").unwrap(), Some("bo".to_string()));
+    client.del("user:2").unwrap();
+    assert_eq!(client.get("user:2").unwrap(), None);
+    assert_eq!(client.count(prefix("user:")).unwrap(), 2);
+    assert_eq!(client.count(Keys::Range { start: None, end: Some("u".to_string()) }).unwrap(), 1);
+
+    let first = client.scan(prefix(""), ScanOptions { limit: Some(2), ..ScanOptions::default() }).unwrap();
+    let expected = [("team:1".to_string(), "red".to_string()), ("user:1".to_string(), "ann".to_string())];
+    assert_eq!(first.pairs, expected);
+    let rest = ScanOptions { limit: Some(2), resume: first.next, ..ScanOptions::default() };
+    let second = client.scan(prefix(""), rest).unwrap();
+    assert_eq!((second.pairs.len(), second.next), (1, None));
+    let newest_first = client.scan_rev(prefix("user:"), ScanOptions::default()).unwrap();
+    assert_eq!(newest_first.pairs[0].0, "user:3");
+
+    // errors of the server come back typed, and the connection stays usable
+    let bad_token = ScanOptions { resume: Some("0123".to_string()), ..ScanOptions::default() };
+    assert!(matches!(client.scan(prefix(""), bad_token), Err(Error::InvalidArgument(_))));
+    assert!(matches!(client.set("a b", "1"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(client.set("a", ""), Err(Error::InvalidArgument(_))));
+    assert_eq!(client.get("user:1").unwrap(), Some("ann".to_string()));
+}
+
+#[test]
+fn test_server_errors() {
+    let (address, connections) = fake_server(vec![
+        Some("ERR unique index email already has this value for key ann\n"),
+        Some("ERR I/O error: disk full\n"),
+        Some("WHAT\n"),
+    ]);
+    let mut client = Client::connect_with(address, quick_retries(3)).unwrap();
+    match client.set("bo", "ann@example.com") {
+        Err(Error::UniqueViolation { index, existing }) => {
+            assert_eq!((index.as_str(), existing.as_str()), ("email", "ann"))
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    // the fake server closes each connection after its answer, so every request reconnects;
+    // an Io error the server answered with is not retried
+    assert!(matches!(client.get("a"), Err(Error::Io(_))));
+    assert!(matches!(client.get("a"), Err(Error::Corruption(_))));
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_retries() {
+    // the first two connections are dropped without an answer
+    let (address, connections) = fake_server(vec![None, None, Some("VALUE 1\n")]);
+    let mut client = Client::connect_with(address, quick_retries(3)).unwrap();
+    assert_eq!(client.get("a").unwrap(), Some("1".to_string()));
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+    let (address, _) = fake_server(vec![None, None, Some("VALUE 1\n")]);
+    let mut client = Client::connect_with(address, quick_retries(1)).unwrap();
+    assert!(matches!(client.get("a"), Err(Error::Io(_))));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let nobody = listener.local_addr().unwrap();
+    drop(listener);
+    assert!(matches!(Client::connect_with(nobody, quick_retries(0)), Err(Error::Io(_))));
+}
+
+#[test]
+fn test_pipeline_and_batches() {
+    let mut client = Client::connect(start_server()).unwrap();
+    // more queries than a window
+    let mut queries: Vec<Query> = (0..150).map(|i| Query::Set(format!("k{:03}", i), i.to_string())).collect();
+    queries.push(Query::Get("k100".to_string()));
+    queries.push(Query::Get("nobody".to_string()));
+    let results = client.pipeline(&queries).unwrap();
+    assert_eq!(results.len(), 152);
+    assert!(results[..150].iter().all(|result| matches!(result, Ok(QueryResult::Done))));
+    assert!(matches!(&results[150], Ok(QueryResult::Value(Some(value))) if value == "100"));
+    assert!(matches!(results[151], Ok(QueryResult::Value(None))));
+    assert!(matches!(client.pipeline(&[Query::Del("a b".to_string())]), Err(Error::InvalidArgument(_))));
+
+    let mut batch = WriteBatch::new();
+    for i in 0..100 {
+        batch.delete(format!("k{:03}", i));
+    }
+    batch.insert("total".to_string(), "50".to_string());
+    client.write_batch(&batch).unwrap();
+    assert_eq!(client.count(prefix("k")).unwrap(), 50);
+    assert_eq!(client.get("total").unwrap(), Some("50".to_string()));
+    client.write_batch(&WriteBatch::new()).unwrap();
+
+    let mut bad = WriteBatch::new();
+    bad.insert("k999".to_string(), "1".to_string()).insert("total".to_string(), "a b".to_string());
+    assert!(matches!(client.write_batch(&bad), Err(Error::InvalidArgument(_))));
+    assert_eq!(client.get("k999").unwrap(), None);
+}
+
+#[test]
+fn test_async_client() {
+    let address = start_server();
+    block_on(async {
+        let client = AsyncClient::connect(address).await.unwrap();
+        client.set("a", "1").await.unwrap();
+        client.set("b", "2").await.unwrap();
+        assert_eq!(client.get("a").await.unwrap(), Some("1".to_string()));
+        assert_eq!(client.count(prefix("")).await.unwrap(), 2);
+        client.del("a").await.unwrap();
+        let page = client.scan(prefix(""), ScanOptions::default()).await.unwrap();
+        assert_eq!(page.pairs, [("b".to_string(), "2".to_string())]);
+        assert!(matches!(client.get("").await, Err(Error::InvalidArgument(_))));
+
+        let mut batch = WriteBatch::new();
+        batch.insert("c".to_string(), "3".to_string()).delete("b".to_string());
+        client.write_batch(batch).await.unwrap();
+        let results = client.pipeline(vec![Query::Get("c".to_string()), Query::Get("b".to_string())]).await;
+        let results = results.unwrap();
+        assert!(matches!(results[..], [Ok(QueryResult::Value(Some(_))), Ok(QueryResult::Value(None))]));
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let nobody = listener.local_addr().unwrap();
+    drop(listener);
+    assert!(matches!(block_on(AsyncClient::connect_with(nobody, quick_retries(0))), Err(Error::Io(_))));
+}
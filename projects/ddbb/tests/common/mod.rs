@@ -0,0 +1,60 @@
+// tests/common/mod.rs
+
+// The fixtures several test files share, each takes what it needs with `mod common;`
+#![allow(dead_code)]
+
+use ddbb::http::HttpServer;
+use ddbb::log::LogManager;
+use ddbb::server::{Server, ServerOptions};
+use ddbb::vfs::MemFs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub fn key(i: u32) -> Vec<u8> {
+    format!("key{:06}", i).into_bytes()
+}
+
+// A server of `db` on a free port of localhost, serving until the test exits
+pub fn serve(db: LogManager<String, String>, options: ServerOptions) -> SocketAddr {
+    let server = Server::bind_with("127.0.0.1:0", db, options).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    address
+}
+
+// A server of an empty database
+pub fn start_server_with(options: ServerOptions) -> SocketAddr {
+    serve(LogManager::open(Arc::new(MemFs::new()), "db").unwrap(), options)
+}
+
+pub fn start_server() -> SocketAddr {
+    start_server_with(ServerOptions::default())
+}
+
+// The same over HTTP, see http.rs
+pub fn start_http_server() -> SocketAddr {
+    let db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let server = HttpServer::bind("127.0.0.1:0", db).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    address
+}
+
+// Wait for `done` to hold, failing the test after a while
+pub fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+    wait_for(what, || done().then_some(()))
+}
+
+// Wait for `done` to return something, and return it
+pub fn wait_for<T>(what: &str, mut done: impl FnMut() -> Option<T>) -> T {
+    let started = Instant::now();
+    loop {
+        if let Some(value) = done() {
+            return value;
+        }
+        assert!(started.elapsed() < Duration::from_secs(20), "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,127 @@
+use ddbb::error::Result;
+use ddbb::log::LogManager;
+use ddbb::vfs::{MemFs, PowerLoss};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const DIR: &str = "db";
+const KEYS: usize = 8;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Insert(String, i32),
+    Delete(String),
+    Restart, // shutdown (compacts the log) and open again
+}
+
+fn workload(seed: u64, len: usize) -> Vec<Op> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len)
+        .map(|i| {
+            let key = format!("key{}", rng.gen_range(0..KEYS));
+            if i > 0 && i % 15 == 0 {
+                Op::Restart
+            } else if rng.gen_bool(0.3) {
+                Op::Delete(key)
+            } else {
+                Op::Insert(key, rng.gen_range(0..1000))
+            }
+        })
+        .collect()
+}
+
+// states[i] is the expected content after the first i operations
+fn model_states(ops: &[Op]) -> Vec<BTreeMap<String, i32>> {
+    let mut model = BTreeMap::new();
+    let mut states = vec![model.clone()];
+    for op in ops {
+        match op {
+            Op::Insert(key, value) => {
+                model.insert(key.clone(), *value);
+            }
+            Op::Delete(key) => {
+                model.remove(key);
+            }
+            Op::Restart => {}
+        }
+        states.push(model.clone());
+    }
+    states
+}
+
+#[derive(Default)]
+struct Progress {
+    attempted: usize, // operations started (the last one may have been cut by the crash)
+    durable: usize,   // operations guaranteed to survive, i.e. covered by a finished shutdown
+}
+
+fn run(vfs: &Arc<MemFs>, ops: &[Op], progress: &mut Progress) -> Result<()> {
+    let mut log_manager = LogManager::open(vfs.clone(), DIR)?;
+    for (i, op) in ops.iter().enumerate() {
+        progress.attempted = i + 1;
+        match op {
+            Op::Insert(key, value) => log_manager.insert(key.clone(), *value)?,
+            Op::Delete(key) => log_manager.delete(key)?,
+            Op::Restart => {
+                log_manager.shutdown()?;
+                progress.durable = i;
+                drop(log_manager);
+                log_manager = LogManager::open(vfs.clone(), DIR)?;
+            }
+        }
+    }
+    log_manager.shutdown()?;
+    progress.durable = ops.len();
+    Ok(())
+}
+
+fn recovered_state(vfs: &Arc<MemFs>) -> BTreeMap<String, i32> {
+    let log_manager = LogManager::<String, i32>::open(vfs.clone(), DIR)
+        .expect("recovery must always succeed after a crash");
+    (0..KEYS)
+        .map(|i| format!("key{}", i))
+        .filter_map(|key| log_manager.search(&key).map(|value| (key, value)))
+        .collect()
+}
+
+fn check_every_crash_point(mode: impl Fn(usize) -> PowerLoss) {
+    let ops = workload(42, 50);
+    let states = model_states(&ops);
+
+    // a clean run tells us how many mutating operations there are to cut at
+    let vfs = Arc::new(MemFs::new());
+    run(&vfs, &ops, &mut Progress::default()).unwrap();
+    let total = vfs.mutations();
+
+    for crash_point in 0..=total {
+        let vfs = Arc::new(MemFs::new());
+        vfs.fail_after(crash_point);
+        let mut progress = Progress::default();
+        let result = run(&vfs, &ops, &mut progress);
+        assert_eq!(result.is_ok(), crash_point == total);
+
+        vfs.power_loss(mode(crash_point));
+        let recovered = recovered_state(&vfs);
+
+        // the recovered state must be the state after some prefix of the operations, and that
+        // prefix must include everything that was made durable before the crash
+        let consistent = (progress.durable..=progress.attempted).any(|i| states[i] == recovered);
+        assert!(
+            consistent,
+            "crash point {}: recovered {:?} is not a prefix state between operations {} and {}",
+            crash_point, recovered, progress.durable, progress.attempted
+        );
+    }
+}
+
+#[test]
+fn test_crash_drop_unsynced_writes() {
+    check_every_crash_point(|_| PowerLoss::DropUnsynced);
+}
+
+#[test]
+fn test_crash_torn_unsynced_writes() {
+    check_every_crash_point(|crash_point| PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+}
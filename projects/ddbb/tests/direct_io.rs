@@ -0,0 +1,89 @@
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::vfs::{OpenOptions, RealFs, Vfs};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// A fresh directory on the real file system, removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("ddbb-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_direct_file_unaligned_access() {
+    let dir = TempDir::new("direct-file");
+    let path = dir.0.join("file");
+    let direct = OpenOptions::new().read(true).write(true).create(true).direct(true);
+    let mut expected = Vec::new();
+
+    // appends that start and end in the middle of blocks
+    let mut file = RealFs.open(&path, OpenOptions::new().append(true).create(true).direct(true)).unwrap();
+    for i in 0..3000u32 {
+        let record = format!("record {}\n", i).into_bytes();
+        file.write_all(&record).unwrap();
+        expected.extend_from_slice(&record);
+    }
+    file.sync().unwrap();
+    assert_eq!(file.len().unwrap(), expected.len() as u64);
+    drop(file);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+    // an overwrite across a block boundary keeps the bytes around it
+    let mut file = RealFs.open(&path, direct).unwrap();
+    file.seek(SeekFrom::Start(4090)).unwrap();
+    file.write_all(b"0123456789ab").unwrap();
+    expected[4090..4102].copy_from_slice(b"0123456789ab");
+    let mut middle = vec![0; 100];
+    file.seek(SeekFrom::Start(4050)).unwrap();
+    file.read_exact(&mut middle).unwrap();
+    assert_eq!(middle, expected[4050..4150]);
+
+    let mut all = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut all).unwrap();
+    assert_eq!(all, expected);
+
+    file.set_len(5000).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), expected[..5000]);
+}
+
+#[test]
+fn test_lsm_with_direct_io() {
+    let dir = TempDir::new("direct-lsm");
+    let options = Options {
+        write_buffer_size: 8192,
+        wal_preallocate: true,
+        wal_recycle: 1,
+        direct_io: true,
+        ..Options::default()
+    };
+    let key = |i: u32| format!("key{:06}", i).into_bytes();
+
+    let mut tree = LsmTree::open_with(Arc::new(RealFs), &dir.0, options.clone()).unwrap();
+    for i in 0..3000 {
+        tree.insert(&key(i), format!("value{}", i).as_bytes()).unwrap();
+    }
+    tree.delete(&key(7)).unwrap();
+    tree.sync().unwrap();
+    assert!(tree.level_runs().iter().sum::<usize>() > 0);
+    drop(tree);
+
+    let tree = LsmTree::open_with(Arc::new(RealFs), &dir.0, options).unwrap();
+    assert_eq!(tree.get(&key(2999)).unwrap(), Some(b"value2999".to_vec()));
+    assert_eq!(tree.get(&key(7)).unwrap(), None);
+    assert_eq!(tree.traverse().unwrap().len(), 2999);
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use common::key;
+use ddbb::disk_btree::{DiskBTree, MAX_ENTRY_SIZE};
+use ddbb::error::Error;
+use ddbb::vfs::{MemFs, PowerLoss};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const PATH: &str = "db/data.ddbb";
+
+#[test]
+fn test_disk_btree_insert_get_range() {
+    let vfs = Arc::new(MemFs::new());
+    // a tiny cache forces nodes to be read back from the file
+    let mut tree = DiskBTree::open_with_cache(vfs.clone(), PATH, 4).unwrap();
+
+    let mut ids: Vec<u32> = (0..5000).collect();
+    ids.shuffle(&mut StdRng::seed_from_u64(1));
+    for &i in &ids {
+        tree.insert(&key(i), format!("value{}", i).as_bytes()).unwrap();
+    }
+    tree.commit().unwrap();
+
+    for i in 0..5000 {
+        assert_eq!(tree.get(&key(i)).unwrap(), Some(format!("value{}", i).into_bytes()));
+    }
+    assert_eq!(tree.get(b"missing").unwrap(), None);
+
+    let pairs = tree.range(key(100)..key(200)).unwrap();
+    assert_eq!(pairs.len(), 100);
+    assert_eq!(pairs[0].0, key(100));
+    assert_eq!(pairs[99].0, key(199));
+    assert_eq!(tree.range(key(4990)..).unwrap().len(), 10);
+    assert_eq!(tree.range(..=key(9)).unwrap().len(), 10);
+
+    let all = tree.traverse().unwrap();
+    assert_eq!(all.len(), 5000);
+    assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+
+    // overwrite keeps one entry per key
+    tree.insert(&key(7), b"new").unwrap();
+    assert_eq!(tree.get(&key(7)).unwrap(), Some(b"new".to_vec()));
+    assert_eq!(tree.traverse().unwrap().len(), 5000);
+}
+
+#[test]
+fn test_disk_btree_matches_model() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    let mut model = BTreeMap::new();
+    let mut rng = StdRng::seed_from_u64(7);
+
+    for round in 0..20 {
+        for _ in 0..500 {
+            let k = key(rng.gen_range(0..2000));
+            if rng.gen_bool(0.4) {
+                assert_eq!(tree.delete(&k).unwrap(), model.remove(&k).is_some());
+            } else {
+                // values of varying size exercise the byte based splits and merges
+                let v = vec![round as u8; rng.gen_range(0..300)];
+                tree.insert(&k, &v).unwrap();
+                model.insert(k, v);
+            }
+        }
+        tree.commit().unwrap();
+        let expected: Vec<_> = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(tree.traverse().unwrap(), expected);
+    }
+
+    // deleting everything leaves an empty tree
+    for k in model.keys() {
+        assert!(tree.delete(k).unwrap());
+    }
+    tree.commit().unwrap();
+    assert!(tree.traverse().unwrap().is_empty());
+}
+
+#[test]
+fn test_disk_btree_reopen_and_rollback() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    for i in 0..1000 {
+        tree.insert(&key(i), b"committed").unwrap();
+    }
+    tree.commit().unwrap();
+
+    for i in 0..1000 {
+        tree.insert(&key(i), b"rolled back").unwrap();
+    }
+    tree.delete(&key(5)).unwrap();
+    tree.rollback();
+    assert_eq!(tree.get(&key(5)).unwrap(), Some(b"committed".to_vec()));
+
+    // the file is locked while the tree is open
+    assert!(DiskBTree::open(vfs.clone(), PATH).is_err());
+    drop(tree);
+
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    assert_eq!(tree.traverse().unwrap().len(), 1000);
+    assert!(tree.traverse().unwrap().iter().all(|(_, v)| v == b"committed"));
+}
+
+#[test]
+fn test_disk_btree_crash_keeps_last_commit() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    for i in 0..200 {
+        tree.insert(&key(i), b"first").unwrap();
+    }
+    tree.commit().unwrap();
+
+    // crash at every third point of the second transaction and its commit
+    let start = vfs.mutations();
+    for i in 0..200 {
+        tree.insert(&key(i), b"second").unwrap();
+    }
+    tree.commit().unwrap();
+    let total = vfs.mutations() - start;
+    drop(tree);
+
+    for crash_point in (0..total).step_by(3) {
+        let vfs = Arc::new(MemFs::new());
+        let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+        for i in 0..200 {
+            tree.insert(&key(i), b"first").unwrap();
+        }
+        tree.commit().unwrap();
+
+        vfs.fail_after(crash_point);
+        let result = (|| {
+            for i in 0..200 {
+                tree.insert(&key(i), b"second")?;
+            }
+            tree.commit()
+        })();
+        assert!(result.is_err());
+        drop(tree);
+
+        vfs.power_loss(PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+        let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+        let pairs = tree.traverse().unwrap();
+        assert_eq!(pairs.len(), 200);
+        assert!(pairs.iter().all(|(_, v)| v == b"first"));
+    }
+}
+
+#[test]
+fn test_disk_btree_rejects_oversized_entries() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    let value = vec![0; MAX_ENTRY_SIZE];
+    assert!(matches!(tree.insert(b"k", &value), Err(Error::InvalidArgument(_))));
+    tree.insert(b"k", &value[1..]).unwrap();
+}
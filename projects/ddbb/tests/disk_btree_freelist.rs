@@ -0,0 +1,89 @@
+mod common;
+
+use common::key;
+use ddbb::disk_btree::DiskBTree;
+use ddbb::pager::FileStats;
+use ddbb::vfs::MemFs;
+use std::sync::Arc;
+
+const PATH: &str = "db/data.ddbb";
+
+fn check_accounting(stats: FileStats) {
+    // two meta pages + everything else is either used, free or holds the free list
+    assert_eq!(stats.total_pages, 2 + stats.used_pages + stats.free_pages + stats.freelist_pages);
+}
+
+#[test]
+fn test_freelist_bounds_file_growth() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    for i in 0..2000 {
+        tree.insert(&key(i), b"round 0").unwrap();
+    }
+    tree.commit().unwrap();
+    let first = tree.file_stats();
+    check_accounting(first);
+
+    // rewriting every key copies every page, without recycling the file would keep growing
+    for round in 1..=10 {
+        for i in 0..2000 {
+            tree.insert(&key(i), format!("round {}", round).as_bytes()).unwrap();
+        }
+        tree.commit().unwrap();
+        let stats = tree.file_stats();
+        check_accounting(stats);
+        assert!(stats.total_pages <= first.total_pages * 2 + 2, "{:?}", stats);
+    }
+}
+
+#[test]
+fn test_freelist_survives_reopen() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    for i in 0..2000 {
+        tree.insert(&key(i), &[7; 100]).unwrap();
+    }
+    tree.commit().unwrap();
+    for i in 0..2000 {
+        tree.delete(&key(i)).unwrap();
+    }
+    tree.commit().unwrap();
+
+    let stats = tree.file_stats();
+    check_accounting(stats);
+    assert_eq!(stats.used_pages, 0);
+    assert!(stats.free_pages > 50);
+    drop(tree);
+
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    assert_eq!(tree.file_stats(), stats);
+
+    // the free pages are reused before the file grows
+    for i in 0..2000 {
+        tree.insert(&key(i), &[7; 100]).unwrap();
+    }
+    tree.commit().unwrap();
+    check_accounting(tree.file_stats());
+    assert_eq!(tree.file_stats().total_pages, stats.total_pages);
+}
+
+#[test]
+fn test_freelist_rollback_returns_pages() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    for i in 0..500 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    tree.commit().unwrap();
+    let committed = tree.file_stats();
+
+    for i in 0..500 {
+        tree.delete(&key(i)).unwrap();
+    }
+    for i in 500..3000 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    tree.rollback();
+    assert_eq!(tree.file_stats(), committed);
+    assert_eq!(tree.traverse().unwrap().len(), 500);
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::key;
+use ddbb::disk_btree::DiskBTree;
+use ddbb::error::Error;
+use ddbb::pager::PAGE_SIZE;
+use ddbb::vfs::{MemFs, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+
+const PATH: &str = "db/data.ddbb";
+
+fn build(vfs: &Arc<MemFs>) {
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    for i in 0..1000 {
+        tree.insert(&key(i), b"value").unwrap();
+    }
+    tree.commit().unwrap();
+    // deleting a few keys puts pages on the free list
+    for i in 0..300 {
+        tree.delete(&key(i)).unwrap();
+    }
+    tree.commit().unwrap();
+}
+
+fn page_of(data: &[u8], needle: &[u8]) -> usize {
+    data.windows(needle.len()).position(|w| w == needle).unwrap() / PAGE_SIZE
+}
+
+#[test]
+fn test_integrity_clean_file() {
+    let vfs = Arc::new(MemFs::new());
+    build(&vfs);
+
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    let report = tree.verify_integrity().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    assert!(report.pages_checked > 3);
+}
+
+#[test]
+fn test_integrity_detects_flipped_byte() {
+    let vfs = Arc::new(MemFs::new());
+    build(&vfs);
+
+    // damage one byte of the leaf holding key(500)
+    let mut data = vfs.read(Path::new(PATH)).unwrap();
+    let page = page_of(&data, &key(500));
+    data[page * PAGE_SIZE + 100] ^= 0x01;
+    vfs.write(Path::new(PATH), &data).unwrap();
+
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    assert!(matches!(tree.get(&key(500)), Err(Error::Corruption(_))));
+    // keys in other leaves are still readable
+    assert_eq!(tree.get(&key(999)).unwrap(), Some(b"value".to_vec()));
+
+    let report = tree.verify_integrity().unwrap();
+    assert_eq!(report.corrupted_pages, vec![page as u32]);
+    assert!(report.leaked_pages.is_empty());
+}
+
+#[test]
+fn test_integrity_detects_misplaced_page() {
+    let vfs = Arc::new(MemFs::new());
+    build(&vfs);
+
+    // a leaf copied over another one is intact, but its checksum belongs to the old position
+    let mut data = vfs.read(Path::new(PATH)).unwrap();
+    let (src, dst) = (page_of(&data, &key(400)), page_of(&data, &key(900)));
+    assert_ne!(src, dst);
+    let copy = data[src * PAGE_SIZE..(src + 1) * PAGE_SIZE].to_vec();
+    data[dst * PAGE_SIZE..(dst + 1) * PAGE_SIZE].copy_from_slice(&copy);
+    vfs.write(Path::new(PATH), &data).unwrap();
+
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    assert!(matches!(tree.get(&key(900)), Err(Error::Corruption(_))));
+    assert_eq!(tree.verify_integrity().unwrap().corrupted_pages, vec![dst as u32]);
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use common::key;
+use ddbb::disk_btree::DiskBTree;
+use ddbb::error::Error;
+use ddbb::pager::PAGE_SIZE;
+use ddbb::vfs::{MemFs, PowerLoss, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+
+const PATH: &str = "db/data.ddbb";
+
+// 5000 keys, then most of them deleted again
+fn sparse_tree(vfs: &Arc<MemFs>) -> DiskBTree {
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    for i in 0..5000 {
+        tree.insert(&key(i), format!("value {}", i).as_bytes()).unwrap();
+    }
+    tree.commit().unwrap();
+    for i in 0..5000 {
+        if i % 10 != 0 {
+            tree.delete(&key(i)).unwrap();
+        }
+    }
+    tree.commit().unwrap();
+    tree
+}
+
+#[test]
+fn test_vacuum_shrinks_file() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = sparse_tree(&vfs);
+    let before = tree.file_stats();
+    let expected = tree.traverse().unwrap();
+    assert_eq!(expected.len(), 500);
+
+    let report = tree.vacuum().unwrap();
+    assert_eq!(report.pages_before, before.total_pages);
+    assert!(report.pages_after * 4 < report.pages_before, "{:?}", report);
+    assert_eq!(report.bytes_reclaimed, (report.pages_before - report.pages_after) as u64 * PAGE_SIZE as u64);
+    assert_eq!(vfs.read(Path::new(PATH)).unwrap().len() as u64, report.pages_after as u64 * PAGE_SIZE as u64);
+    assert!(!vfs.exists(Path::new("db/data.ddbb.vacuum")));
+
+    let after = tree.file_stats();
+    assert_eq!((after.free_pages, after.freelist_pages), (0, 0));
+    assert!(tree.verify_integrity().unwrap().is_ok());
+    assert_eq!(tree.traverse().unwrap(), expected);
+    assert_eq!(tree.range(key(100)..key(200)).unwrap().len(), 10);
+
+    // the packed tree keeps working, and is what a reopen finds
+    for i in 0..5000 {
+        if i % 7 == 0 {
+            tree.insert(&key(i), b"new").unwrap();
+        }
+    }
+    tree.delete(&key(10)).unwrap();
+    tree.commit().unwrap();
+    drop(tree);
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    assert!(tree.verify_integrity().unwrap().is_ok());
+    assert_eq!(tree.get(&key(10)).unwrap(), None);
+    assert_eq!(tree.get(&key(20)).unwrap(), Some(b"value 20".to_vec()));
+    assert_eq!(tree.get(&key(70)).unwrap(), Some(b"new".to_vec()));
+}
+
+#[test]
+fn test_vacuum_needs_committed_tree() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+    // an empty tree vacuums to an empty file
+    assert_eq!(tree.vacuum().unwrap().pages_after, 2);
+
+    tree.insert(b"a", b"1").unwrap();
+    assert!(matches!(tree.vacuum(), Err(Error::InvalidArgument(_))));
+    tree.commit().unwrap();
+    tree.vacuum().unwrap();
+    assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_vacuum_crash_leaves_old_or_new_file() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = sparse_tree(&vfs);
+    let expected = tree.traverse().unwrap();
+    let start = vfs.mutations();
+    tree.vacuum().unwrap();
+    let total = vfs.mutations() - start;
+    drop(tree);
+
+    for crash_point in (0..total).step_by(23) {
+        let vfs = Arc::new(MemFs::new());
+        let mut tree = sparse_tree(&vfs);
+        vfs.fail_after(crash_point);
+        assert!(tree.vacuum().is_err());
+        drop(tree);
+        vfs.power_loss(PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+
+        let mut tree = DiskBTree::open(vfs.clone(), PATH).unwrap();
+        assert_eq!(tree.traverse().unwrap(), expected, "crash point {}", crash_point);
+        assert!(tree.verify_integrity().unwrap().is_ok());
+        assert!(!vfs.exists(Path::new("db/data.ddbb.vacuum")));
+    }
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::key;
+use ddbb::disk_btree::DiskBTree;
+use ddbb::log::LogManager;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::vfs::MemFs;
+use std::sync::Arc;
+
+#[test]
+fn test_lsm_explain_get() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { block_cache_size: 0, ..Options::default() };
+    let mut tree = LsmTree::open_with(vfs, "db", options).unwrap();
+    for i in 0..100 {
+        tree.insert(&key(i), b"flushed").unwrap();
+    }
+    tree.flush().unwrap();
+    tree.insert(&key(1000), b"in memory").unwrap();
+
+    // found in the memtable: no table looked at
+    let (value, stats) = tree.explain_get(&key(1000)).unwrap();
+    assert_eq!(value, Some(b"in memory".to_vec()));
+    assert!(stats.nodes_visited >= 1);
+    assert_eq!((stats.tables_searched, stats.tables_skipped, stats.blocks_read), (0, 0, 0));
+
+    // found in the run: one block read from the file
+    let (value, stats) = tree.explain_get(&key(50)).unwrap();
+    assert_eq!(value, Some(b"flushed".to_vec()));
+    assert_eq!((stats.tables_searched, stats.blocks_read, stats.cache_hits), (1, 1, 0));
+    assert!(stats.bytes_decoded > 0);
+
+    // outside the key range of the run: skipped without reading it
+    let (value, stats) = tree.explain_get(b"zzz").unwrap();
+    assert_eq!(value, None);
+    assert_eq!((stats.tables_searched, stats.tables_skipped, stats.blocks_read), (0, 1, 0));
+
+    // the plain reads agree
+    assert_eq!(tree.get(&key(50)).unwrap(), Some(b"flushed".to_vec()));
+    assert_eq!(tree.get(b"zzz").unwrap(), None);
+}
+
+#[test]
+fn test_lsm_explain_cache_hits() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open(vfs, "db").unwrap();
+    for i in 0..100 {
+        tree.insert(&key(i), b"value").unwrap();
+    }
+    tree.flush().unwrap();
+
+    let (_, first) = tree.explain_get(&key(7)).unwrap();
+    let (_, second) = tree.explain_get(&key(7)).unwrap();
+    assert_eq!((first.blocks_read, first.cache_hits), (1, 0));
+    assert_eq!((second.blocks_read, second.cache_hits, second.bytes_decoded), (1, 1, 0));
+}
+
+#[test]
+fn test_lsm_explain_range() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open(vfs, "db").unwrap();
+    for i in 0..10 {
+        tree.insert(&key(i), b"value").unwrap();
+    }
+    tree.flush().unwrap();
+    for i in 100..110 {
+        tree.insert(&key(i), b"value").unwrap();
+    }
+    tree.flush().unwrap();
+    for i in 0..5 {
+        tree.delete(&key(i)).unwrap();
+    }
+
+    // the deleted keys are scanned but not returned, the second run is out of range
+    let (pairs, stats) = tree.explain_range(key(0)..key(10)).unwrap();
+    assert_eq!(pairs.len(), 5);
+    assert_eq!(pairs, tree.range(key(0)..key(10)).unwrap());
+    assert_eq!((stats.tables_searched, stats.tables_skipped), (1, 1));
+    assert!(stats.entries_scanned >= 10);
+    assert!(stats.blocks_read >= 1);
+}
+
+#[test]
+fn test_disk_btree_explain() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open_with_cache(vfs, "tree.db", 1).unwrap();
+    for i in 0..2000 {
+        tree.insert(&key(i), &[b'v'; 32]).unwrap();
+    }
+
+    // one node per level, every one of them a page read
+    let (value, stats) = tree.explain_get(&key(1234)).unwrap();
+    assert_eq!(value, Some(vec![b'v'; 32]));
+    assert!(stats.nodes_visited >= 2, "{:?}", stats);
+    assert_eq!(stats.blocks_read, stats.nodes_visited);
+    assert!(stats.bytes_decoded > 0);
+    let (_, again) = tree.explain_get(&key(1234)).unwrap();
+    assert_eq!(again.nodes_visited, stats.nodes_visited);
+
+    let (pairs, stats) = tree.explain_range(key(100)..key(400)).unwrap();
+    assert_eq!(pairs.len(), 300);
+    assert_eq!(pairs, tree.range(key(100)..key(400)).unwrap());
+    assert!(stats.entries_scanned >= 300);
+    assert!(stats.nodes_visited > again.nodes_visited);
+}
+
+#[test]
+fn test_log_manager_explain_search() {
+    let mut db: LogManager<u64, u64> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let (value, stats) = db.explain_search(&1);
+    assert_eq!(value, None);
+    assert!(stats.nodes_visited <= 1);
+
+    for i in 0..1000 {
+        db.insert(i, i * 2).unwrap();
+    }
+    let (value, stats) = db.explain_search(&777);
+    assert_eq!(value, Some(1554));
+    assert!(stats.nodes_visited >= 2, "{:?}", stats);
+    assert_eq!(stats.blocks_read, 0);
+}
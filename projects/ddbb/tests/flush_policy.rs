@@ -0,0 +1,156 @@
+mod common;
+
+use common::key;
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::vfs::{MemFs, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// no compaction, so every flush stays visible as a level 0 run
+fn no_compaction() -> Options {
+    Options { l0_compaction_trigger: 1000, ..Options::default() }
+}
+
+#[test]
+fn test_lsm_flushes_by_entry_count() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { write_buffer_max_entries: Some(10), ..no_compaction() };
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options).unwrap();
+    for i in 0..35 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    assert_eq!(tree.level_runs()[0], 3);
+    let stats = tree.metrics().flush;
+    assert_eq!((stats.flushes, stats.by_entries, stats.by_size), (3, 3, 0));
+}
+
+#[test]
+fn test_lsm_flushes_by_age() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { write_buffer_max_age: Some(Duration::from_millis(50)), ..no_compaction() };
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options).unwrap();
+    tree.insert(b"a", b"1").unwrap();
+    tree.insert(b"b", b"2").unwrap();
+    assert_eq!(tree.level_runs()[0], 0);
+
+    // the age is checked by the next write
+    thread::sleep(Duration::from_millis(60));
+    tree.insert(b"c", b"3").unwrap();
+    assert_eq!(tree.level_runs()[0], 1);
+    assert_eq!(tree.metrics().flush.by_age, 1);
+    assert_eq!(tree.traverse().unwrap().len(), 3);
+}
+
+#[test]
+fn test_lsm_explicit_flush() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", no_compaction()).unwrap();
+    // nothing to flush
+    tree.flush().unwrap();
+    assert_eq!(tree.metrics().flush.flushes, 0);
+
+    tree.insert(b"a", b"1").unwrap();
+    tree.delete(b"b").unwrap();
+    tree.flush().unwrap();
+    assert_eq!(tree.level_runs()[0], 1);
+    let stats = tree.metrics().flush;
+    assert_eq!((stats.flushes, stats.manual), (1, 1));
+    // a flush asked for is not a stall
+    assert_eq!(stats.stall, Duration::ZERO);
+
+    // the run is durable, nothing is left in the WAL
+    drop(tree);
+    let wal_bytes: usize = vfs
+        .list(Path::new("db"))
+        .unwrap()
+        .iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+        .map(|path| vfs.read(path).unwrap().len())
+        .sum();
+    assert_eq!(wal_bytes, 0);
+    let tree = LsmTree::open_with(vfs.clone(), "db", no_compaction()).unwrap();
+    assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_log_manager_flushes_to_snapshot() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { write_buffer_size: 200, ..Options::default() };
+    let mut log_manager: LogManager<i32, i32> = LogManager::open_with(vfs.clone(), "db", options.clone()).unwrap();
+
+    // the log is flushed to the snapshot as it grows, instead of only on shutdown
+    for i in 0..20 {
+        log_manager.insert(i, i * 10).unwrap();
+    }
+    let stats = log_manager.flush_stats();
+    assert!(stats.by_size > 0);
+    assert_eq!(stats.flushes, stats.by_size);
+    assert!(vfs.exists(Path::new("db/data.sst")));
+    assert!(vfs.read(Path::new("db/log.txt")).unwrap().len() < 200);
+
+    log_manager.insert(100, 1).unwrap();
+    log_manager.flush().unwrap();
+    assert_eq!(log_manager.flush_stats().manual, 1);
+    log_manager.flush().unwrap();
+    assert_eq!(log_manager.flush_stats().manual, 1);
+    drop(log_manager);
+
+    let log_manager: LogManager<i32, i32> = LogManager::open_with(vfs.clone(), "db", options).unwrap();
+    assert_eq!(log_manager.search(&7), Some(70));
+    assert_eq!(log_manager.search(&100), Some(1));
+    // everything came from the snapshot, only the checkpoint was left in the log
+    assert!(log_manager.recovery_report().checkpoint.is_some());
+    assert_eq!(log_manager.recovery_report().records_replayed, 21);
+}
+
+#[test]
+fn test_lsm_write_stalls() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options {
+        write_buffer_max_entries: Some(10),
+        l0_compaction_trigger: 2,
+        auto_compaction: false,
+        l0_slowdown_trigger: 3,
+        l0_stop_trigger: 5,
+        max_write_buffer_size: 1000,
+        ..Options::default()
+    };
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options).unwrap();
+    for i in 0..30 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    // the compactions wait, and the writes are slowed down once the runs pile up
+    assert_eq!(tree.level_runs()[0], 3);
+    assert_eq!(tree.metrics().flush.slowed, 0);
+    tree.insert(&key(30), b"v").unwrap();
+    assert_eq!(tree.metrics().flush.slowed, 1);
+
+    // then the memtable is not flushed any more, and grows until it is over its budget
+    let mut written = 31;
+    let stall = loop {
+        match tree.insert(&key(written), b"v") {
+            Ok(()) => written += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(stall, Error::WriteStall(_)), "{:?}", stall);
+    assert_eq!(tree.level_runs()[0], 5);
+    assert!(written > 60, "{}", written);
+    assert_eq!(tree.get(&key(written - 1)).unwrap(), Some(b"v".to_vec()));
+    assert_eq!(tree.get(&key(written)).unwrap(), None);
+    let stats = tree.metrics().flush;
+    assert_eq!((stats.flushes, stats.stopped), (5, 1));
+    assert!(stats.stall >= Duration::from_millis(stats.slowed));
+
+    // the writes go on once the compactions caught up
+    assert!(tree.compact_pending().unwrap() > 0);
+    assert_eq!(tree.level_runs()[0], 0);
+    tree.insert(&key(written), b"v").unwrap();
+    assert_eq!(tree.level_runs()[0], 1);
+    assert_eq!(tree.traverse().unwrap().len(), written as usize + 1);
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use common::start_http_server;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+struct Response {
+    status: u16,
+    headers: Vec<String>,
+    body: Option<Value>,
+}
+
+// A persistent connection, one request at a time
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(address: SocketAddr) -> Self {
+        let stream = TcpStream::connect(address).unwrap();
+        Client { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream }
+    }
+
+    fn send(&mut self, method: &str, target: &str, body: Option<Value>) -> Response {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let head = format!("{} {} HTTP/1.1\r\nHost: test\r\n", method, target);
+        self.raw(format!("{}Content-Length: {}\r\n\r\n{}", head, body.len(), body).as_bytes())
+    }
+
+    fn raw(&mut self, request: &[u8]) -> Response {
+        self.writer.write_all(request).unwrap();
+        let mut status_line = String::new();
+        self.reader.read_line(&mut status_line).unwrap();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.parse().unwrap();
+            }
+            headers.push(line);
+        }
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body).unwrap();
+        let body = (length > 0).then(|| serde_json::from_slice(&body).unwrap());
+        Response { status, headers, body }
+    }
+}
+
+#[test]
+fn test_keys() {
+    let mut client = Client::connect(start_http_server());
+    assert_eq!(client.send("GET", "/health", None).body, Some(json!({"status": "ok"})));
+
+    let put = client.send("PUT", "/keys/user:1", Some(json!({"value": "ann"})));
+    assert_eq!((put.status, put.body), (204, None));
+    let get = client.send("GET", "/keys/user:1", None);
+    assert_eq!((get.status, get.body), (200, Some(json!({"key": "user:1", "value": "ann"}))));
+    assert!(get.headers.contains(&"Content-Type: application/json".to_string()));
+
+    // keys are percent-decoded
+    assert_eq!(client.send("PUT", "/keys/caf%C3%A9", Some(json!({"value": "\u{e9}t\u{e9}"}))).status, 204);
+    assert_eq!(client.send("GET", "/keys/café", None).body.unwrap()["value"], "été");
+
+    assert_eq!(client.send("DELETE", "/keys/user:1", None).status, 204);
+    let missing = client.send("GET", "/keys/user:1", None);
+    assert_eq!(missing.status, 404);
+    assert!(missing.body.unwrap()["error"].as_str().unwrap().contains("user:1"));
+}
+
+#[test]
+fn test_errors() {
+    let mut client = Client::connect(start_http_server());
+    assert_eq!(client.send("PUT", "/keys/a%20b", Some(json!({"value": "x"}))).status, 400);
+    assert_eq!(client.send("PUT", "/keys/a", Some(json!({"value": "x y"}))).status, 400);
+    assert_eq!(client.send("PUT", "/keys/a", Some(json!({"value": 5}))).status, 400);
+    assert_eq!(client.send("PUT", "/keys/a", Some(json!("x"))).status, 400);
+    assert_eq!(client.send("GET", "/keys/%zz", None).status, 400);
+    assert_eq!(client.send("GET", "/nothing", None).status, 404);
+    assert_eq!(client.send("GET", "/scan?limit=0", None).status, 400);
+    assert_eq!(client.send("GET", "/scan?after=bad", None).status, 400);
+    let not_allowed = client.send("POST", "/keys/a", None);
+    assert_eq!(not_allowed.status, 405);
+    assert!(not_allowed.headers.contains(&"Allow: GET, PUT, DELETE".to_string()));
+
+    // none of that closed the connection
+    assert_eq!(client.send("GET", "/health", None).status, 200);
+
+    // a request that cannot be parsed does
+    let bad = client.raw(b"GARBAGE\r\n\r\n");
+    assert_eq!(bad.status, 400);
+    assert!(bad.headers.contains(&"Connection: close".to_string()));
+}
+
+#[test]
+fn test_scan() {
+    let mut client = Client::connect(start_http_server());
+    for (key, value) in [("user:1", "ann"), ("user:2", "bo"), ("user:3", "cy"), ("team:1", "red")] {
+        client.send("PUT", &format!("/keys/{}", key), Some(json!({ "value": value })));
+    }
+    let users = client.send("GET", "/scan?prefix=user%3A", None).body.unwrap();
+    let expected = json!([
+        {"key": "user:1", "value": "ann"},
+        {"key": "user:2", "value": "bo"},
+        {"key": "user:3", "value": "cy"}
+    ]);
+    assert_eq!(users, json!({ "pairs": expected, "next": null }));
+    assert_eq!(client.send("GET", "/scan", None).body.unwrap()["pairs"].as_array().unwrap().len(), 4);
+
+    let first = client.send("GET", "/scan?prefix=user:&limit=2", None).body.unwrap();
+    assert_eq!(first["pairs"].as_array().unwrap().len(), 2);
+    let next = first["next"].as_str().unwrap();
+    let second = client.send("GET", &format!("/scan?prefix=user:&limit=2&after={}", next), None);
+    let second = second.body.unwrap();
+    assert_eq!(second, json!({ "pairs": [{"key": "user:3", "value": "cy"}], "next": null }));
+}
+
+#[test]
+fn test_connection_close() {
+    let address = start_http_server();
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET /health HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("{\"status\":\"ok\"}"), "{}", response);
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::key;
+use ddbb::error::Error;
+use ddbb::lsm::{LsmTree, LEVELS};
+use ddbb::options::Options;
+use ddbb::sstable::TableWriter;
+use ddbb::vfs::{MemFs, OpenOptions, PowerLoss, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+
+const EXTERNAL: &str = "bulk/external.sst";
+
+// A table built outside the tree, as a bulk load would
+fn build_table(vfs: &MemFs, keys: impl Iterator<Item = u32>, value: &str) {
+    vfs.create_dir_all(Path::new("bulk")).unwrap();
+    let file = vfs.open(Path::new(EXTERNAL), OpenOptions::new().write(true).create(true).truncate(true)).unwrap();
+    let mut writer = TableWriter::new(file);
+    for i in keys {
+        writer.add(&key(i), Some(format!("{}{}", value, i).as_bytes())).unwrap();
+    }
+    writer.finish().unwrap();
+    vfs.sync_dir(Path::new("bulk")).unwrap();
+}
+
+fn wal_bytes(vfs: &MemFs) -> usize {
+    let paths = vfs.list(Path::new("db")).unwrap();
+    paths.iter().filter(|path| path.extension().is_some_and(|ext| ext == "wal")).map(|p| vfs.read(p).unwrap().len()).sum()
+}
+
+#[test]
+fn test_ingest_into_empty_tree() {
+    let vfs = Arc::new(MemFs::new());
+    build_table(&vfs, 0..5000, "bulk");
+    let mut tree = LsmTree::open(vfs.clone(), "db").unwrap();
+    tree.ingest_file(EXTERNAL).unwrap();
+
+    // nothing overlaps it, so it goes straight to the last level, and nothing went to the WAL
+    assert_eq!(tree.level_runs()[LEVELS - 1], 1);
+    assert_eq!(tree.level_runs().iter().sum::<usize>(), 1);
+    assert_eq!(wal_bytes(&vfs), 0);
+    assert_eq!(tree.get(&key(4321)).unwrap(), Some(b"bulk4321".to_vec()));
+    assert_eq!(tree.range(key(10)..key(20)).unwrap().len(), 10);
+    // the file is linked, not moved
+    assert!(vfs.exists(Path::new(EXTERNAL)));
+    drop(tree);
+
+    let mut tree = LsmTree::open(vfs.clone(), "db").unwrap();
+    assert_eq!(tree.traverse().unwrap().len(), 5000);
+    tree.insert(&key(7), b"new").unwrap();
+    tree.compact().unwrap();
+    assert_eq!(tree.get(&key(7)).unwrap(), Some(b"new".to_vec()));
+    assert_eq!(tree.get(&key(8)).unwrap(), Some(b"bulk8".to_vec()));
+}
+
+#[test]
+fn test_ingest_overlapping_data() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { l0_compaction_trigger: 1000, ..Options::default() };
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options.clone()).unwrap();
+    for i in 0..100 {
+        tree.insert(&key(i), b"old").unwrap();
+    }
+    tree.flush().unwrap();
+    tree.insert(&key(50), b"memtable").unwrap();
+    tree.insert(&key(500), b"memtable").unwrap();
+    let before = tree.snapshot();
+
+    build_table(&vfs, 40..60, "bulk");
+    tree.ingest_file(EXTERNAL).unwrap();
+    // the memtable held one of its keys and was flushed below it
+    assert_eq!(tree.level_runs()[0], 3);
+    assert_eq!(tree.metrics().flush.manual, 2);
+    assert_eq!(tree.get(&key(50)).unwrap(), Some(b"bulk50".to_vec()));
+    assert_eq!(tree.get(&key(39)).unwrap(), Some(b"old".to_vec()));
+    assert_eq!(tree.get(&key(500)).unwrap(), Some(b"memtable".to_vec()));
+    assert_eq!(tree.get_at(&before, &key(50)).unwrap(), Some(b"memtable".to_vec()));
+    assert_eq!(tree.get_at(&before, &key(45)).unwrap(), Some(b"old".to_vec()));
+
+    // writes after the ingest win over it
+    tree.delete(&key(45)).unwrap();
+    drop(before);
+    drop(tree);
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options).unwrap();
+    tree.compact().unwrap();
+    let all = tree.traverse().unwrap();
+    assert_eq!(all.len(), 100);
+    assert_eq!(tree.get(&key(45)).unwrap(), None);
+    assert_eq!(tree.get(&key(46)).unwrap(), Some(b"bulk46".to_vec()));
+}
+
+#[test]
+fn test_ingest_rejects_bad_files() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open(vfs.clone(), "db").unwrap();
+    build_table(&vfs, 0..0, "bulk");
+    assert!(matches!(tree.ingest_file(EXTERNAL), Err(Error::InvalidArgument(_))));
+    assert!(tree.ingest_file("bulk/missing.sst").is_err());
+    vfs.write(Path::new("bulk/garbage.sst"), b"not a table").unwrap();
+    assert!(tree.ingest_file("bulk/garbage.sst").is_err());
+    assert_eq!(tree.level_runs().iter().sum::<usize>(), 0);
+}
+
+#[test]
+fn test_ingest_crash_is_all_or_nothing() {
+    let setup = || {
+        let vfs = Arc::new(MemFs::new());
+        build_table(&vfs, 0..300, "bulk");
+        let mut tree = LsmTree::open(vfs.clone(), "db").unwrap();
+        tree.insert(&key(1), b"old").unwrap();
+        tree.sync().unwrap();
+        (vfs, tree)
+    };
+    let (vfs, mut tree) = setup();
+    let original = vfs.read(Path::new(EXTERNAL)).unwrap();
+    let start = vfs.mutations();
+    tree.ingest_file(EXTERNAL).unwrap();
+    let total = vfs.mutations() - start;
+
+    for crash_point in 0..total {
+        let (vfs, mut tree) = setup();
+        vfs.fail_after(crash_point);
+        assert!(tree.ingest_file(EXTERNAL).is_err());
+        drop(tree);
+        vfs.power_loss(PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+
+        let tree = LsmTree::open(vfs.clone(), "db").unwrap();
+        let all = tree.traverse().unwrap();
+        match tree.get(&key(1)).unwrap().as_deref() {
+            Some(b"old") => assert_eq!(all.len(), 1, "crash point {}", crash_point),
+            Some(b"bulk1") => assert_eq!(all.len(), 300, "crash point {}", crash_point),
+            other => panic!("crash point {}: {:?}", crash_point, other),
+        }
+        // the ingested file itself is never harmed
+        assert_eq!(vfs.read(Path::new(EXTERNAL)).unwrap(), original);
+    }
+}
@@ -0,0 +1,101 @@
+use ddbb::error::Error;
+use ddbb::json::{Json, JsonPath};
+use ddbb::log::LogManager;
+use ddbb::vfs::{MemFs, Vfs};
+use serde_json::json;
+use std::path::Path;
+use std::sync::Arc;
+
+fn path(s: &str) -> JsonPath {
+    s.parse().unwrap()
+}
+
+fn log_text(vfs: &MemFs) -> String {
+    String::from_utf8(vfs.read(Path::new("db/log.txt")).unwrap()).unwrap()
+}
+
+#[test]
+fn test_text_form_is_one_token() {
+    let document = Json(json!({"name": "Ann Lee", "bio": "line\nbreak\u{2028}tab\there", "tags": ["a b", 1.5, null]}));
+    let text = document.to_string();
+    assert!(!text.contains(char::is_whitespace), "{}", text);
+    assert_eq!(text.parse::<Json>().unwrap(), document);
+    assert!(matches!("{\"a\":".parse::<Json>(), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_paths() {
+    for s in ["$", "$.user", "$.user.name", "$.items[2].id", "$[0][1]"] {
+        assert_eq!(path(s).to_string(), s);
+    }
+    for bad in ["", "user", "$.", "$..a", "$.a b", "$[x]", "$[1", "$a"] {
+        assert!(matches!(bad.parse::<JsonPath>(), Err(Error::InvalidArgument(_))), "{:?}", bad);
+    }
+
+    let document = Json(json!({"user": {"name": "ann"}, "items": [{"id": 7}, {"id": 8}]}));
+    assert_eq!(document.get_path(&path("$.user.name")), Some(&json!("ann")));
+    assert_eq!(document.get_path(&path("$.items[1].id")), Some(&json!(8)));
+    assert_eq!(document.get_path(&path("$")), Some(&document.0));
+    assert_eq!(document.get_path(&path("$.items[2]")), None);
+    assert_eq!(document.get_path(&path("$.user[0]")), None);
+    assert_eq!(document.get_path(&path("$.user.name.first")), None);
+}
+
+#[test]
+fn test_set_path() {
+    let mut document = Json::default();
+    document.set_path(&path("$.user.name"), json!("ann")).unwrap();
+    document.set_path(&path("$.user.langs"), json!([])).unwrap();
+    document.set_path(&path("$.user.langs[0]"), json!("rust")).unwrap();
+    document.set_path(&path("$.user.langs[1]"), json!("c")).unwrap();
+    document.set_path(&path("$.user.langs[0]"), json!("go")).unwrap();
+    assert_eq!(document.0, json!({"user": {"name": "ann", "langs": ["go", "c"]}}));
+
+    // a failed patch leaves the document as it was
+    let before = document.clone();
+    for bad in ["$.user.langs[5]", "$.user.name.first", "$.user[0]", "$.user.langs.first"] {
+        assert!(matches!(document.set_path(&path(bad), json!(1)), Err(Error::InvalidArgument(_))), "{}", bad);
+    }
+    assert_eq!(document, before);
+
+    document.set_path(&path("$"), json!(3)).unwrap();
+    assert_eq!(document.0, json!(3));
+}
+
+#[test]
+fn test_log_manager_patches() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db: LogManager<String, Json> = LogManager::open(vfs.clone(), "db").unwrap();
+    let big = "x".repeat(1000);
+    db.insert("ann".to_string(), Json(json!({"bio": big, "visits": 0}))).unwrap();
+    for visits in 1..=20 {
+        db.set_path("ann".to_string(), "$.visits", Json(json!(visits))).unwrap();
+    }
+    db.set_path("bo".to_string(), "$.address.city", Json(json!("New York"))).unwrap();
+    assert!(matches!(db.set_path("bo".to_string(), "$.address[0]", Json(json!(1))), Err(Error::InvalidArgument(_))));
+    assert!(matches!(db.get_path(&"bo".to_string(), "address"), Err(Error::InvalidArgument(_))));
+
+    assert_eq!(db.get_path(&"ann".to_string(), "$.visits").unwrap(), Some(Json(json!(20))));
+    assert_eq!(db.get_path(&"bo".to_string(), "$.address.city").unwrap(), Some(Json(json!("New York"))));
+    assert_eq!(db.get_path(&"bo".to_string(), "$.name").unwrap(), None);
+    assert_eq!(db.get_path(&"cy".to_string(), "$").unwrap(), None);
+
+    // the patches did not write the document again
+    let log = log_text(&vfs);
+    assert_eq!(log.matches(&big).count(), 1);
+    assert_eq!(log.matches(" PATCH ann $.visits ").count(), 20);
+    drop(db);
+
+    let mut db: LogManager<String, Json> = LogManager::open(vfs.clone(), "db").unwrap();
+    let expected = Json(json!({"bio": big, "visits": 20}));
+    assert_eq!(db.search(&"ann".to_string()), Some(expected.clone()));
+    assert_eq!(db.get_path(&"bo".to_string(), "$.address.city").unwrap(), Some(Json(json!("New York"))));
+    assert_eq!(db.recovery_report().records_replayed, 22);
+
+    // after a compaction the snapshot has the patched documents
+    db.compact().unwrap();
+    db.set_path("ann".to_string(), "$.visits", Json(json!(21))).unwrap();
+    drop(db);
+    let db: LogManager<String, Json> = LogManager::open(vfs, "db").unwrap();
+    assert_eq!(db.get_path(&"ann".to_string(), "$").unwrap(), Some(Json(json!({"bio": big, "visits": 21}))));
+}
@@ -0,0 +1,113 @@
+use ddbb::error::Error;
+use ddbb::keycodec::{decode, encode, OrderedKey};
+use ddbb::sstable::{Table, TableWriter};
+use ddbb::vfs::{MemFs, OpenOptions, Vfs};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt::Debug;
+use std::path::Path;
+
+// Encodings compare like the values, and decode back to them
+fn check_order<T: OrderedKey + Ord + Debug>(mut values: Vec<T>) {
+    values.sort();
+    for pair in values.windows(2) {
+        let (a, b) = (encode(&pair[0]), encode(&pair[1]));
+        assert_eq!(a.cmp(&b), pair[0].cmp(&pair[1]), "{:?} vs {:?}", pair[0], pair[1]);
+    }
+    for value in &values {
+        assert_eq!(&decode::<T>(&encode(value)).unwrap(), value);
+    }
+}
+
+fn random_bytes(rng: &mut StdRng) -> Vec<u8> {
+    // lots of zeros and 0x01 / 0xFF, the bytes the escaping deals with
+    (0..rng.gen_range(0..5)).map(|_| [0x00, 0x01, 0xFF, b'a', b'b'][rng.gen_range(0..5)]).collect()
+}
+
+#[test]
+fn test_integers_sort_numerically() {
+    check_order(vec![0u64, 1, 9, 10, 255, 256, u64::MAX]);
+    check_order(vec![i64::MIN, -300, -1, 0, 1, 300, i64::MAX]);
+    check_order(vec![i8::MIN, -1, 0, 1, i8::MAX]);
+    check_order(vec![-5i32, 7, 0]);
+    check_order(vec![u128::MAX, 0, 1 << 100]);
+    check_order(vec![true, false]);
+}
+
+#[test]
+fn test_strings_and_bytes() {
+    check_order(vec![
+        String::new(),
+        "a".to_string(),
+        "a\0".to_string(),
+        "a\0b".to_string(),
+        "ab".to_string(),
+        "b".to_string(),
+        "é".to_string(),
+    ]);
+    let mut rng = StdRng::seed_from_u64(5);
+    check_order((0..300).map(|_| random_bytes(&mut rng)).collect());
+}
+
+#[test]
+fn test_tuples_sort_field_by_field() {
+    // concatenated as text these would be "abc" twice, and "10" < "9"
+    check_order(vec![("ab".to_string(), "c".to_string()), ("a".to_string(), "bc".to_string())]);
+    check_order(vec![("t".to_string(), 9u64), ("t".to_string(), 10u64)]);
+    check_order(vec![None, Some(0i32), Some(-1), Some(3)]);
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let tuples: Vec<(Vec<u8>, Option<i16>, Vec<u8>)> = (0..500)
+        .map(|_| {
+            let middle = rng.gen_bool(0.8).then(|| rng.gen_range(-3..3));
+            (random_bytes(&mut rng), middle, random_bytes(&mut rng))
+        })
+        .collect();
+    check_order(tuples);
+}
+
+#[test]
+fn test_tuple_prefix_is_byte_prefix() {
+    let key = ("tenant".to_string(), 1700000000u64, -4i32);
+    let full = encode(&key);
+    assert!(full.starts_with(&encode(&("tenant".to_string(),))));
+    assert!(full.starts_with(&encode(&("tenant".to_string(), 1700000000u64))));
+    assert!(!full.starts_with(&encode(&("ten".to_string(),))));
+
+    // the keys of one tenant are contiguous in a table, and sorted by time
+    let vfs = MemFs::new();
+    let file = vfs.open(Path::new("t.sst"), OpenOptions::new().write(true).create(true)).unwrap();
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for tenant in ["a", "a\0", "ab", "b"] {
+        for time in [5u64, 40, 300] {
+            keys.push(encode(&(tenant.to_string(), time)));
+        }
+    }
+    keys.sort();
+    let mut writer = TableWriter::new(file);
+    for key in &keys {
+        writer.add(key, Some(b"")).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let table = Table::open(&vfs, "t.sst").unwrap();
+    let prefix = encode(&("a".to_string(),));
+    let found: Vec<(String, u64)> = table
+        .iter_from(&prefix)
+        .map(Result::unwrap)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, _)| decode(&key).unwrap())
+        .collect();
+    assert_eq!(found, vec![("a".to_string(), 5), ("a".to_string(), 40), ("a".to_string(), 300)]);
+}
+
+#[test]
+fn test_decode_rejects_bad_input() {
+    assert!(matches!(decode::<u64>(&[1, 2, 3]), Err(Error::Corruption(_))));
+    assert!(matches!(decode::<u32>(&[0, 0, 0, 0, 0]), Err(Error::Corruption(_))));
+    assert!(matches!(decode::<String>(b"abc"), Err(Error::Corruption(_))));
+    assert!(matches!(decode::<String>(&[0xC3, 0x00, 0x01]), Err(Error::Corruption(_))));
+    assert!(matches!(decode::<Vec<u8>>(&[0x00, 0x07]), Err(Error::Corruption(_))));
+    assert!(matches!(decode::<Option<u8>>(&[2, 0]), Err(Error::Corruption(_))));
+    assert!(matches!(decode::<bool>(&[2]), Err(Error::Corruption(_))));
+}
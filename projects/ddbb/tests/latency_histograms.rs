@@ -0,0 +1,137 @@
+use ddbb::admin;
+use ddbb::batch::WriteBatch;
+use ddbb::histogram::Histogram;
+use ddbb::json::Json;
+use ddbb::log::LogManager;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::scan::ScanOptions;
+use ddbb::vfs::MemFs;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn recording() -> Options {
+    Options { latency_histograms: true, ..Options::default() }
+}
+
+#[test]
+fn test_histogram() {
+    let mut histogram = Histogram::new();
+    assert_eq!(histogram.count(), 0);
+    assert_eq!((histogram.percentile(99.0), histogram.max()), (Duration::ZERO, Duration::ZERO));
+    for micros in 1..=1000 {
+        histogram.record(Duration::from_micros(micros));
+    }
+    assert_eq!(histogram.count(), 1000);
+    assert_eq!(histogram.mean(), Duration::from_nanos(500_500));
+    assert_eq!(histogram.max(), Duration::from_millis(1));
+    // within 1/16 of the exact percentiles, never below them
+    for (percent, exact) in [(50.0, 500), (90.0, 900), (99.0, 990), (99.9, 999)] {
+        let p = histogram.percentile(percent);
+        let exact = Duration::from_micros(exact);
+        assert!(p >= exact && p <= exact + exact / 16, "p{} {:?}", percent, p);
+    }
+    assert_eq!(histogram.percentile(100.0), Duration::from_millis(1));
+
+    // small latencies are exact, large ones do not overflow
+    let mut small = Histogram::new();
+    for nanos in [3, 7, 7, 20] {
+        small.record(Duration::from_nanos(nanos));
+    }
+    assert_eq!(small.percentile(50.0), Duration::from_nanos(7));
+    assert_eq!(small.percentile(75.0), Duration::from_nanos(7));
+    small.record(Duration::MAX);
+    assert_eq!(small.percentile(100.0), Duration::from_nanos(u64::MAX));
+
+    let mut merged = Histogram::new();
+    merged.merge(&histogram);
+    merged.merge(&Histogram::new());
+    assert_eq!(merged, histogram);
+    merged.merge(&histogram);
+    assert_eq!((merged.count(), merged.mean()), (2000, histogram.mean()));
+    assert_eq!(merged.percentile(50.0), histogram.percentile(50.0));
+}
+
+#[test]
+fn test_log_manager_latencies() {
+    let mut db: LogManager<String, String> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    db.insert("a".to_string(), "1".to_string()).unwrap();
+    assert_eq!(db.latency_histograms(), None);
+    assert!(!admin::stats(&db).iter().any(|(name, _)| name.starts_with("insert_")));
+
+    let mut db: LogManager<String, String> =
+        LogManager::open_with(Arc::new(MemFs::new()), "db", recording()).unwrap();
+    for i in 0..10 {
+        db.insert(format!("key{}", i), "v".to_string()).unwrap();
+    }
+    db.delete(&"key0".to_string()).unwrap();
+    db.search(&"key1".to_string());
+    db.search(&"none".to_string());
+    db.range(..);
+    db.scan(.., &ScanOptions::default()).unwrap();
+    db.scan_rev(.., &ScanOptions::default()).unwrap();
+    // a failed scan is not counted
+    db.scan(.., &ScanOptions { limit: Some(0), ..ScanOptions::default() }).unwrap_err();
+    let mut batch = WriteBatch::new();
+    batch.insert("key1".to_string(), "w".to_string()).delete("key2".to_string());
+    db.write_batch(batch.clone()).unwrap();
+    db.prepare("t1", batch).unwrap();
+    db.commit_prepared("t1").unwrap();
+
+    let latencies = db.latency_histograms().unwrap();
+    let counts: Vec<(&str, u64)> =
+        latencies.by_operation().iter().map(|(operation, l)| (*operation, l.count())).collect();
+    assert_eq!(counts, [("insert", 10), ("search", 2), ("delete", 1), ("scan", 3), ("batch", 2)]);
+    assert!(latencies.insert.percentile(50.0) <= latencies.insert.max());
+    assert!(latencies.insert.max() > Duration::ZERO);
+
+    let stats = admin::stats(&db);
+    let stat = |name: &str| stats.iter().find(|(stat, _)| stat == name).map(|(_, value)| value.clone());
+    assert_eq!(stat("insert_count"), Some("10".to_string()));
+    assert_eq!(stat("scan_count"), Some("3".to_string()));
+    assert_eq!(stat("batch_count"), Some("2".to_string()));
+    for name in ["mean", "p50", "p99", "p999", "max"] {
+        let value = stat(&format!("search_{}_us", name)).unwrap();
+        assert!(value.parse::<u64>().is_ok(), "{} {}", name, value);
+    }
+}
+
+#[test]
+fn test_json_latencies() {
+    let mut db: LogManager<String, Json> =
+        LogManager::open_with(Arc::new(MemFs::new()), "db", recording()).unwrap();
+    db.insert("ann".to_string(), Json(json!({"visits": 0}))).unwrap();
+    db.set_path("ann".to_string(), "$.visits", Json(json!(1))).unwrap();
+    assert_eq!(db.latency_histograms().unwrap().insert.count(), 2);
+}
+
+#[test]
+fn test_lsm_latencies() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", Options::default()).unwrap();
+    tree.insert(b"a", b"1").unwrap();
+    assert_eq!(tree.metrics().latencies, None);
+    drop(tree);
+
+    let options = Options { write_buffer_max_entries: Some(5), ..recording() };
+    let mut tree = LsmTree::open_with(vfs, "db", options).unwrap();
+    for i in 0..20u32 {
+        tree.insert(format!("key{:03}", i).as_bytes(), b"v").unwrap();
+    }
+    tree.delete(b"key000").unwrap();
+    tree.get(b"key001").unwrap();
+    let snapshot = tree.snapshot();
+    tree.get_at(&snapshot, b"key002").unwrap();
+    tree.range(..).unwrap();
+    tree.range_rev(..).unwrap();
+    tree.range_at(&snapshot, ..).unwrap();
+    tree.insert(&[b'k'; 8], &[]).unwrap();
+
+    let latencies = tree.metrics().latencies.unwrap();
+    assert_eq!(latencies.insert.count(), 21);
+    assert_eq!((latencies.search.count(), latencies.delete.count(), latencies.scan.count()), (2, 1, 3));
+    // the flushes of the inserts make the tail
+    assert!(tree.metrics().flush.flushes >= 4);
+    assert!(latencies.insert.percentile(100.0) >= latencies.insert.percentile(50.0));
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use common::eventually;
+use ddbb::error::Error;
+use ddbb::lock::{LockManager, LockMode};
+use ddbb::log::LogManager;
+use ddbb::txn::{TransactionDb, TransactionOptions};
+use ddbb::vfs::MemFs;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const LONG: Duration = Duration::from_secs(10);
+const SHORT: Duration = Duration::from_millis(50);
+
+fn timed_out(result: &ddbb::error::Result<()>) -> bool {
+    matches!(result, Err(Error::Io(e)) if e.kind() == ErrorKind::TimedOut)
+}
+
+// Wait for `done`, failing the test if it takes too long
+#[test]
+fn test_shared_and_exclusive() {
+    let locks = LockManager::new();
+    let key = "k".to_string();
+    locks.lock(1, &key, LockMode::Shared, SHORT).unwrap();
+    locks.lock(2, &key, LockMode::Shared, SHORT).unwrap();
+    assert_eq!(locks.holders(&key), vec![(1, LockMode::Shared), (2, LockMode::Shared)]);
+    let result = locks.lock(3, &key, LockMode::Exclusive, SHORT);
+    assert!(timed_out(&result), "{:?}", result);
+    assert!(locks.waiting(&key).is_empty());
+
+    // an upgrade waits for the other readers only
+    let result = locks.lock(1, &key, LockMode::Exclusive, SHORT);
+    assert!(timed_out(&result), "{:?}", result);
+    locks.unlock(2, &key);
+    locks.lock(1, &key, LockMode::Exclusive, SHORT).unwrap();
+    locks.lock(1, &key, LockMode::Shared, SHORT).unwrap();
+    assert_eq!(locks.holders(&key), vec![(1, LockMode::Exclusive)]);
+    let result = locks.lock(2, &key, LockMode::Shared, SHORT);
+    assert!(timed_out(&result), "{:?}", result);
+
+    // other keys are not in the way
+    locks.lock(2, &"other".to_string(), LockMode::Exclusive, SHORT).unwrap();
+    locks.unlock_all(1);
+    locks.unlock_all(2);
+    assert!(locks.holders(&key).is_empty());
+    assert!(locks.holders(&"other".to_string()).is_empty());
+}
+
+#[test]
+fn test_queue_order() {
+    let locks = Arc::new(LockManager::new());
+    let key = "k".to_string();
+    locks.lock(1, &key, LockMode::Shared, LONG).unwrap();
+    let waiter = |txn, mode| {
+        let (locks, key) = (locks.clone(), key.clone());
+        thread::spawn(move || {
+            locks.lock(txn, &key, mode, LONG).unwrap();
+            locks.unlock_all(txn);
+        })
+    };
+    // a writer waits for the reader, and a reader that comes after it waits for it
+    let writer = waiter(2, LockMode::Exclusive);
+    eventually("the writer to wait", || locks.waiting(&key).len() == 1);
+    let reader = waiter(3, LockMode::Shared);
+    eventually("the reader to wait", || locks.waiting(&key).len() == 2);
+    assert_eq!(locks.waiting(&key), vec![(2, LockMode::Exclusive), (3, LockMode::Shared)]);
+    assert_eq!(locks.holders(&key), vec![(1, LockMode::Shared)]);
+
+    locks.unlock_all(1);
+    writer.join().unwrap();
+    reader.join().unwrap();
+    assert!(locks.holders(&key).is_empty() && locks.waiting(&key).is_empty());
+}
+
+#[test]
+fn test_transactions() {
+    let db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let options = TransactionOptions { lock_timeout: SHORT, ..TransactionOptions::default() };
+    let db = TransactionDb::with_options(db, options);
+    let mut txn = db.begin();
+    txn.set("a", "100").unwrap();
+    txn.set("b", "100").unwrap();
+    assert_eq!(txn.get("a").unwrap().as_deref(), Some("100"));
+    txn.commit().unwrap();
+
+    // what a transaction reads or writes is locked until it ends
+    let mut first = db.begin();
+    assert_eq!(first.get("a").unwrap().as_deref(), Some("100"));
+    first.del("b").unwrap();
+    let mut second = db.begin();
+    assert_eq!(second.get("a").unwrap().as_deref(), Some("100"));
+    assert!(timed_out(&second.set("a", "0")));
+    assert!(timed_out(&second.get("b").map(|_| ())));
+    first.commit().unwrap();
+    assert_eq!(second.get("b").unwrap(), None);
+    second.set("a", "0").unwrap();
+    second.rollback();
+    let mut txn = db.begin();
+    assert_eq!(txn.get("a").unwrap().as_deref(), Some("100"));
+    txn.set("b", "100").unwrap();
+    txn.commit().unwrap();
+    assert!(db.locks().holders(&"a".to_string()).is_empty());
+
+    // transfers between accounts keep the total, whatever runs at the same time
+    let db = Arc::new(db);
+    let mut transfers = Vec::new();
+    for thread in 0..4 {
+        let db = db.clone();
+        transfers.push(thread::spawn(move || {
+            for i in 0..25 {
+                let (from, to) = if (thread + i) % 2 == 0 { ("a", "b") } else { ("b", "a") };
+                loop {
+                    let mut txn = db.begin();
+                    let attempt = (|| {
+                        let balance = |value: Option<String>| value.map_or(0, |v| v.parse::<i64>().unwrap());
+                        let from_balance = balance(txn.get(from)?);
+                        let to_balance = balance(txn.get(to)?);
+                        txn.set(from, &(from_balance - 1).to_string())?;
+                        txn.set(to, &(to_balance + 1).to_string())
+                    })();
+                    match attempt {
+                        Ok(()) => break txn.commit().unwrap(),
+                        Err(Error::Io(e)) if e.kind() == ErrorKind::TimedOut => txn.rollback(),
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            }
+        }));
+    }
+    for transfer in transfers {
+        transfer.join().unwrap();
+    }
+    let mut txn = db.begin();
+    let balances = ["a", "b"].map(|key| txn.get(key).unwrap().unwrap().parse::<i64>().unwrap());
+    let total: i64 = balances.iter().sum();
+    assert_eq!(total, 200);
+}
@@ -1,9 +1,10 @@
-use ddbb::btree::BTree;
+use ddbb::batch::WriteBatch;
+use ddbb::error::Error;
 use ddbb::log::LogManager;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use ddbb::vfs::MemFs;
 use std::path::Path;
 use std::fs;
+use std::sync::Arc;
 
 const LOG_FILE: &str = "log.txt";
 
@@ -17,7 +18,7 @@ fn test_log_manager_complex() {
 
     // Insert 100 key-value pairs
     for i in 1..=100 {
-        log_manager.insert(format!("key{}", i), i);
+        log_manager.insert(format!("key{}", i), i).unwrap();
     }
 
     // Check if all key-value pairs were inserted correctly
@@ -28,7 +29,7 @@ fn test_log_manager_complex() {
     // Delete some key-value pairs
     for i in 1..=100 {
         if i % 2 == 0 {
-            log_manager.delete(&format!("key{}", i));
+            log_manager.delete(&format!("key{}", i)).unwrap();
         }
     }
 
@@ -41,7 +42,7 @@ fn test_log_manager_complex() {
         }
     }
 
-    log_manager.shutdown();
+    log_manager.shutdown().unwrap();
     println!("Log manager shutdown");
 
     let log_manager2 = LogManager::new();
@@ -55,3 +56,27 @@ fn test_log_manager_complex() {
         }
     }
 }
+
+#[test]
+fn test_keys_and_values_are_tokens() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db: LogManager<String, String> = LogManager::open(vfs.clone(), "db").unwrap();
+    let text = |s: &str| s.to_string();
+    let refused = |result| matches!(result, Err(Error::InvalidArgument(_)));
+    // each of them would be logged as another write, or as none
+    assert!(refused(db.insert(text("k 1"), text("y z"))));
+    assert!(refused(db.insert(text("k"), text("a\nINSERT b"))));
+    assert!(refused(db.insert(text(""), text("v"))));
+    assert!(refused(db.insert(text("k"), text(""))));
+    assert!(refused(db.delete(&text("k\t1"))));
+    let mut batch = WriteBatch::new();
+    batch.insert(text("a"), text("1")).insert(text("b"), text("2 3"));
+    assert!(refused(db.write_batch(batch.clone())));
+    assert!(refused(db.prepare("t1", batch)));
+
+    db.insert(text("k"), text("v")).unwrap();
+    drop(db);
+    let db: LogManager<String, String> = LogManager::open(vfs, "db").unwrap();
+    assert_eq!(db.range(..), vec![(text("k"), text("v"))]);
+    assert_eq!(db.recovery_report().corrupt_records_skipped, 0);
+}
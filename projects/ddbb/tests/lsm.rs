@@ -0,0 +1,169 @@
+mod common;
+
+use common::key;
+use ddbb::lsm::{LsmTree, LEVELS};
+use ddbb::options::Options;
+use ddbb::vfs::{MemFs, PowerLoss};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const DIR: &str = "db";
+
+fn small_buffer() -> Options {
+    // a few dozen writes per memtable, so tests go through many flushes and compactions
+    Options { write_buffer_size: 1024, ..Options::default() }
+}
+
+#[test]
+fn test_lsm_reads_across_levels() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, small_buffer()).unwrap();
+
+    for i in 0..1000 {
+        tree.insert(&key(i), b"old").unwrap();
+    }
+    for i in (0..1000).step_by(2) {
+        tree.insert(&key(i), b"new").unwrap();
+    }
+    for i in (0..1000).step_by(5) {
+        tree.delete(&key(i)).unwrap();
+    }
+    tree.flush().unwrap();
+    tree.delete(&key(995)).unwrap();
+    // the data is spread over the memtable and runs of both levels
+    let runs = tree.level_runs();
+    assert!(runs[0] > 0 && runs[1] > 0, "{:?}", runs);
+
+    assert_eq!(tree.get(&key(1)).unwrap(), Some(b"old".to_vec()));
+    assert_eq!(tree.get(&key(2)).unwrap(), Some(b"new".to_vec()));
+    assert_eq!(tree.get(&key(5)).unwrap(), None);
+    assert_eq!(tree.get(b"missing").unwrap(), None);
+
+    let pairs = tree.range(key(100)..key(110)).unwrap();
+    let keys: Vec<_> = pairs.iter().map(|(k, _)| k.clone()).collect();
+    assert_eq!(keys, [101, 102, 103, 104, 106, 107, 108, 109].map(key));
+    assert_eq!(tree.traverse().unwrap().len(), 800);
+
+    // a full compaction keeps the contents and leaves a single run
+    tree.compact().unwrap();
+    assert_eq!(tree.level_runs(), [0, 1, 0, 0, 0, 0, 0]);
+    assert_eq!(tree.traverse().unwrap().len(), 800);
+    assert_eq!(tree.get(&key(2)).unwrap(), Some(b"new".to_vec()));
+}
+
+#[test]
+fn test_lsm_matches_model_across_reopens() {
+    let vfs = Arc::new(MemFs::new());
+    let mut model = BTreeMap::new();
+    let mut rng = StdRng::seed_from_u64(3);
+
+    for _ in 0..10 {
+        let mut tree = LsmTree::open_with(vfs.clone(), DIR, small_buffer()).unwrap();
+        for _ in 0..300 {
+            let k = key(rng.gen_range(0..500));
+            if rng.gen_bool(0.3) {
+                tree.delete(&k).unwrap();
+                model.remove(&k);
+            } else {
+                let v = vec![rng.gen::<u8>(); rng.gen_range(0..40)];
+                tree.insert(&k, &v).unwrap();
+                model.insert(k, v);
+            }
+        }
+        let expected: Vec<_> = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(tree.traverse().unwrap(), expected);
+        for i in 0..500 {
+            assert_eq!(tree.get(&key(i)).unwrap(), model.get(&key(i)).cloned());
+        }
+        // the memtable is only in the WAL when the tree is dropped
+    }
+}
+
+#[test]
+fn test_lsm_recovers_memtable_from_wal() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open(vfs.clone(), DIR).unwrap();
+    for i in 0..100 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    tree.delete(&key(7)).unwrap();
+    tree.sync().unwrap();
+    // everything still fits in the default write buffer
+    assert_eq!(tree.level_runs(), [0; LEVELS]);
+
+    // the directory is locked while the tree is open
+    assert!(LsmTree::open(vfs.clone(), DIR).is_err());
+    drop(tree);
+    vfs.power_loss(PowerLoss::DropUnsynced);
+
+    let tree = LsmTree::open(vfs.clone(), DIR).unwrap();
+    assert_eq!(tree.traverse().unwrap().len(), 99);
+    assert_eq!(tree.get(&key(7)).unwrap(), None);
+    // open writes the replayed WAL out as a run
+    assert_eq!(tree.level_runs(), [1, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_lsm_crash_recovers_a_prefix() {
+    // ops[i] is applied when states[i + 1] is reached, sync() after every 50 ops
+    let mut rng = StdRng::seed_from_u64(11);
+    let ops: Vec<(Vec<u8>, Option<Vec<u8>>)> = (0..400)
+        .map(|i| {
+            let k = key(rng.gen_range(0..100));
+            (k, (!rng.gen_bool(0.2)).then(|| format!("v{}", i).into_bytes()))
+        })
+        .collect();
+    let mut states = vec![BTreeMap::new()];
+    for (k, v) in &ops {
+        let mut state = states.last().unwrap().clone();
+        match v {
+            Some(v) => state.insert(k.clone(), v.clone()),
+            None => state.remove(k),
+        };
+        states.push(state);
+    }
+
+    let run = |tree: &mut LsmTree, synced: &mut usize| -> ddbb::error::Result<()> {
+        for (i, (k, v)) in ops.iter().enumerate() {
+            match v {
+                Some(v) => tree.insert(k, v)?,
+                None => tree.delete(k)?,
+            }
+            if (i + 1) % 50 == 0 {
+                tree.sync()?;
+                *synced = i + 1;
+            }
+        }
+        Ok(())
+    };
+
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, small_buffer()).unwrap();
+    let start = vfs.mutations();
+    run(&mut tree, &mut 0).unwrap();
+    let total = vfs.mutations() - start;
+    drop(tree);
+
+    for crash_point in (0..total).step_by(7) {
+        let vfs = Arc::new(MemFs::new());
+        let mut tree = LsmTree::open_with(vfs.clone(), DIR, small_buffer()).unwrap();
+        vfs.fail_after(crash_point);
+        let mut synced = 0;
+        assert!(run(&mut tree, &mut synced).is_err());
+        drop(tree);
+        vfs.power_loss(PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+
+        let tree = LsmTree::open_with(vfs.clone(), DIR, small_buffer()).unwrap();
+        let recovered: BTreeMap<_, _> = tree.traverse().unwrap().into_iter().collect();
+        // some ops leave the state as it was, so several prefixes may match
+        let prefix = states.iter().rposition(|state| *state == recovered);
+        assert!(
+            prefix.is_some_and(|p| p >= synced),
+            "crash point {}: recovered state is not a prefix after {} synced ops",
+            crash_point,
+            synced
+        );
+    }
+}
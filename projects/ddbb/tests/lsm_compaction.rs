@@ -0,0 +1,149 @@
+mod common;
+
+use common::key;
+use ddbb::lsm::{LsmTree, LEVELS};
+use ddbb::options::Options;
+use ddbb::vfs::{MemFs, PowerLoss};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const DIR: &str = "db";
+
+// tiny limits, so a few thousand writes fill several levels
+fn leveled() -> Options {
+    Options {
+        write_buffer_size: 512,
+        l0_compaction_trigger: 2,
+        level_base_bytes: 4096,
+        level_size_multiplier: 2,
+        target_file_size: 1024,
+        ..Options::default()
+    }
+}
+
+#[test]
+fn test_leveled_compaction_bounds_levels() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, leveled()).unwrap();
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut model = BTreeMap::new();
+
+    for i in 0..4000 {
+        let k = key(rng.gen_range(0..3000));
+        let v = format!("value{}", i).into_bytes();
+        tree.insert(&k, &v).unwrap();
+        model.insert(k, v);
+
+        // after every write no level is over its limit
+        let metrics = tree.metrics();
+        assert!(metrics.levels[0].runs < 2, "{:?}", metrics);
+        for level in &metrics.levels[1..LEVELS - 1] {
+            assert!(level.bytes < level.max_bytes, "{:?}", metrics);
+        }
+    }
+
+    let metrics = tree.metrics();
+    assert!(metrics.levels[3].runs > 0, "{:?}", metrics);
+    let stats = metrics.compaction;
+    assert!(stats.compactions > 10);
+    assert!(stats.runs_read >= stats.compactions);
+    assert!(stats.bytes_read > 0 && stats.bytes_written > 0);
+    // overwritten values are dropped on the way down
+    let stored: u64 = metrics.levels.iter().map(|level| level.bytes).sum();
+    assert!(stats.bytes_written > stored);
+
+    let expected: Vec<_> = model.into_iter().collect();
+    assert_eq!(tree.traverse().unwrap(), expected);
+    for (k, v) in expected.iter().step_by(7) {
+        assert_eq!(tree.get(k).unwrap().as_ref(), Some(v));
+    }
+}
+
+#[test]
+fn test_leveled_compaction_drops_tombstones() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, leveled()).unwrap();
+    for i in 0..2000 {
+        tree.insert(&key(i), b"value").unwrap();
+    }
+    for i in 0..2000 {
+        tree.delete(&key(i)).unwrap();
+    }
+    // deleted keys stay deleted while their tombstones move down the levels
+    assert_eq!(tree.get(&key(10)).unwrap(), None);
+    assert!(tree.traverse().unwrap().is_empty());
+    drop(tree);
+
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, leveled()).unwrap();
+    assert!(tree.traverse().unwrap().is_empty());
+    tree.compact().unwrap();
+    assert_eq!(tree.level_runs(), [0; LEVELS]);
+}
+
+#[test]
+fn test_leveled_compaction_crash_recovers_a_prefix() {
+    // a small key space, so level 1 is made smaller still to get a third level
+    let options = Options { level_base_bytes: 1024, ..leveled() };
+    let mut rng = StdRng::seed_from_u64(17);
+    let ops: Vec<(Vec<u8>, Option<Vec<u8>>)> = (0..600)
+        .map(|i| {
+            let k = key(rng.gen_range(0..150));
+            (k, (!rng.gen_bool(0.2)).then(|| format!("v{}", i).into_bytes()))
+        })
+        .collect();
+    // states[i] is the content after the first i ops
+    let mut states = vec![BTreeMap::new()];
+    for (k, v) in &ops {
+        let mut state = states.last().unwrap().clone();
+        match v {
+            Some(v) => state.insert(k.clone(), v.clone()),
+            None => state.remove(k),
+        };
+        states.push(state);
+    }
+
+    let run = |tree: &mut LsmTree, synced: &mut usize| -> ddbb::error::Result<()> {
+        for (i, (k, v)) in ops.iter().enumerate() {
+            match v {
+                Some(v) => tree.insert(k, v)?,
+                None => tree.delete(k)?,
+            }
+            if (i + 1) % 60 == 0 {
+                tree.sync()?;
+                *synced = i + 1;
+            }
+        }
+        Ok(())
+    };
+
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, options.clone()).unwrap();
+    let start = vfs.mutations();
+    run(&mut tree, &mut 0).unwrap();
+    let total = vfs.mutations() - start;
+    assert!(tree.metrics().levels[2].runs > 0);
+    drop(tree);
+
+    for crash_point in (0..total).step_by(5) {
+        let vfs = Arc::new(MemFs::new());
+        let mut tree = LsmTree::open_with(vfs.clone(), DIR, options.clone()).unwrap();
+        vfs.fail_after(crash_point);
+        let mut synced = 0;
+        assert!(run(&mut tree, &mut synced).is_err());
+        drop(tree);
+        vfs.power_loss(PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+
+        let tree = LsmTree::open_with(vfs.clone(), DIR, options.clone()).unwrap();
+        let recovered: BTreeMap<_, _> = tree.traverse().unwrap().into_iter().collect();
+        // some ops leave the state as it was, so several prefixes may match
+        let prefix = states.iter().rposition(|state| *state == recovered);
+        assert!(
+            prefix.is_some_and(|p| p >= synced),
+            "crash point {}: recovered state is not a prefix after {} synced ops",
+            crash_point,
+            synced
+        );
+    }
+}
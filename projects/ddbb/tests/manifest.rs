@@ -0,0 +1,137 @@
+mod common;
+
+use common::key;
+use ddbb::error::Error;
+use ddbb::lsm::LsmTree;
+use ddbb::manifest::{Manifest, Version, VersionEdit};
+use ddbb::options::Options;
+use ddbb::vfs::{MemFs, PowerLoss, Vfs};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+
+const DIR: &str = "db";
+
+fn add(level: usize, number: u64) -> VersionEdit {
+    VersionEdit { next_file: Some(number + 1), added: vec![(level, number)], ..VersionEdit::default() }
+}
+
+#[test]
+fn test_manifest_replays_edits() {
+    let vfs = Arc::new(MemFs::new());
+    vfs.create_dir_all(Path::new(DIR)).unwrap();
+    let mut manifest = Manifest::create(vfs.clone(), DIR, Version::default()).unwrap();
+    manifest.log_edit(&add(0, 3)).unwrap();
+    manifest.log_edit(&add(0, 4)).unwrap();
+    manifest
+        .log_edit(&VersionEdit {
+            next_file: Some(7),
+            wal: Some(2),
+            added: vec![(1, 5), (1, 6)],
+            removed: vec![(0, 3), (0, 4)],
+            last_sequence: Some(40),
+            old_versions: vec![(6, 12)],
+            ingested: vec![(5, 40)],
+        })
+        .unwrap();
+    let expected = Version {
+        levels: vec![vec![], vec![5, 6]],
+        wal: 2,
+        next_file: 7,
+        last_sequence: 40,
+        old_versions: [(6, 12)].into_iter().collect(),
+        ingested: [(5, 40)].into_iter().collect(),
+    };
+    assert_eq!(*manifest.version(), expected);
+    drop(manifest);
+
+    let manifest = Manifest::open(vfs.clone(), DIR).unwrap().unwrap();
+    assert_eq!(*manifest.version(), expected);
+    assert!(Manifest::open(vfs.clone(), "elsewhere").unwrap().is_none());
+}
+
+#[test]
+fn test_manifest_ignores_torn_edit() {
+    let vfs = Arc::new(MemFs::new());
+    vfs.create_dir_all(Path::new(DIR)).unwrap();
+    let mut manifest = Manifest::create(vfs.clone(), DIR, Version::default()).unwrap();
+    manifest.log_edit(&add(0, 1)).unwrap();
+    manifest.log_edit(&add(0, 2)).unwrap();
+    drop(manifest);
+
+    // cut the last edit in half, as a crash in the middle of its write would
+    let path = Path::new("db/MANIFEST");
+    let data = vfs.read(path).unwrap();
+    vfs.write(path, &data[..data.len() - 5]).unwrap();
+    let mut manifest = Manifest::open(vfs.clone(), DIR).unwrap().unwrap();
+    assert_eq!(manifest.version().levels, [vec![1]]);
+
+    // the next edit does not get glued to the torn one
+    manifest.log_edit(&add(0, 3)).unwrap();
+    drop(manifest);
+    let manifest = Manifest::open(vfs.clone(), DIR).unwrap().unwrap();
+    assert_eq!(manifest.version().levels, [vec![1, 3]]);
+    drop(manifest);
+
+    // damage before the last edit is not a torn write
+    let mut data = vfs.read(path).unwrap();
+    let newline = data.iter().position(|&b| b == b'\n').unwrap();
+    data[newline - 1] ^= 0x01;
+    vfs.write(path, &data).unwrap();
+    assert!(matches!(Manifest::open(vfs.clone(), DIR), Err(Error::Corruption(_))));
+}
+
+#[test]
+fn test_lsm_keeps_only_manifest_files_after_crash() {
+    let options = Options { write_buffer_size: 512, l0_compaction_trigger: 2, ..Options::default() };
+    let write = |tree: &mut LsmTree| -> ddbb::error::Result<()> {
+        for i in 0..400 {
+            tree.insert(&key(i % 120), format!("value{}", i).as_bytes())?;
+        }
+        Ok(())
+    };
+
+    for crash_point in (0..300).step_by(3) {
+        let vfs = Arc::new(MemFs::new());
+        let mut tree = LsmTree::open_with(vfs.clone(), DIR, options.clone()).unwrap();
+        vfs.fail_after(crash_point);
+        assert!(write(&mut tree).is_err());
+        drop(tree);
+        vfs.power_loss(PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+
+        let tree = LsmTree::open_with(vfs.clone(), DIR, options.clone()).unwrap();
+        drop(tree);
+        // every run in the directory is in the manifest and the other way around
+        let manifest = Manifest::open(vfs.clone(), DIR).unwrap().unwrap();
+        let live: BTreeSet<String> = manifest.version().runs().map(|(_, n)| format!("{:06}.sst", n)).collect();
+        let files: BTreeSet<String> = vfs
+            .list(Path::new(DIR))
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .filter(|name| name.ends_with(".sst"))
+            .collect();
+        assert_eq!(files, live, "crash point {}", crash_point);
+    }
+}
+
+#[test]
+fn test_lsm_removes_files_missing_from_manifest() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open(vfs.clone(), DIR).unwrap();
+    tree.insert(b"k", b"v").unwrap();
+    drop(tree);
+    // reopening flushes the WAL to run 3
+    drop(LsmTree::open(vfs.clone(), DIR).unwrap());
+
+    // a run that looks complete, but that no edit ever added
+    vfs.write(Path::new("db/999999.sst"), &vfs.read(Path::new("db/000003.sst")).unwrap()).unwrap();
+    let tree = LsmTree::open(vfs.clone(), DIR).unwrap();
+    assert!(!vfs.exists(Path::new("db/999999.sst")));
+    assert_eq!(tree.get(b"k").unwrap(), Some(b"v".to_vec()));
+    drop(tree);
+
+    // without the manifest nothing tells which runs are live
+    vfs.remove(Path::new("db/MANIFEST")).unwrap();
+    assert!(matches!(LsmTree::open(vfs.clone(), DIR), Err(Error::Corruption(_))));
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::eventually;
+use ddbb::client::Client;
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::membership::MembershipOptions;
+use ddbb::server::{Server, ServerOptions, ShutdownHandle};
+use ddbb::vfs::MemFs;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Wait for `done`, failing the test if it takes too long
+struct Member {
+    address: String,
+    options: MembershipOptions,
+    running: Option<(ShutdownHandle, JoinHandle<ddbb::error::Result<()>>)>,
+}
+
+impl Member {
+    fn new(seeds: &[&str]) -> Self {
+        // the port is free once this is dropped, and the server takes it right after
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let mut options = MembershipOptions::new(&address, seeds);
+        options.interval = Duration::from_millis(20);
+        options.suspect_after = Duration::from_millis(300);
+        options.dead_after = Duration::from_millis(600);
+        let mut member = Member { address, options, running: None };
+        member.start();
+        member
+    }
+
+    fn start(&mut self) {
+        let db = LogManager::open(Arc::new(MemFs::new()), "member").unwrap();
+        let options = ServerOptions { membership: Some(self.options.clone()), ..ServerOptions::default() };
+        let server = Server::bind_with(self.address.as_str(), db, options).unwrap();
+        let shutdown = server.shutdown_handle();
+        self.running = Some((shutdown, thread::spawn(move || server.serve())));
+    }
+
+    fn stop(&mut self) {
+        let (shutdown, serving) = self.running.take().expect("the member runs");
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+    }
+
+    // What the member knows of the cluster
+    fn cluster(&self) -> Vec<(String, String)> {
+        Client::connect(self.address.as_str()).unwrap().cluster().unwrap()
+    }
+}
+
+// Whether every one of `members` sees `address` in `state`
+fn all_see(members: &[&Member], address: &str, state: &str) -> bool {
+    let seen = |member: &&Member| member.cluster().iter().any(|(other, s)| other == address && s == state);
+    members.iter().all(seen)
+}
+
+#[test]
+fn test_gossip() {
+    // each joins through the one before, and they all find each other
+    let mut members: Vec<Member> = Vec::new();
+    for _ in 0..4 {
+        let seeds: Vec<&str> = members.last().map(|last| last.address.as_str()).into_iter().collect();
+        members.push(Member::new(&seeds));
+    }
+    let mut addresses: Vec<String> = members.iter().map(|member| member.address.clone()).collect();
+    addresses.sort();
+    eventually("every member to know every other", || {
+        members.iter().all(|member| {
+            let cluster = member.cluster();
+            cluster.iter().map(|(address, _)| address).eq(addresses.iter())
+                && cluster.iter().all(|(_, state)| state == "alive")
+        })
+    });
+    let stats = Client::connect(members[0].address.as_str()).unwrap().stats().unwrap();
+    let stat = |name: &str| stats.iter().find(|(stat, _)| stat == name).map(|(_, value)| value.as_str());
+    assert_eq!((stat("cluster_members"), stat("cluster_alive")), (Some("4"), Some("4")));
+
+    // a member that stops is suspect, then dead, to the others
+    let mut gone = members.pop().unwrap();
+    gone.stop();
+    let rest: Vec<&Member> = members.iter().collect();
+    eventually("the others to suspect the stopped member", || all_see(&rest, &gone.address, "suspect"));
+    eventually("the others to find the stopped member dead", || all_see(&rest, &gone.address, "dead"));
+    assert!(rest.iter().all(|member| member.cluster().len() == 4));
+
+    // and alive again once it is back, with nothing but its old seed
+    gone.start();
+    eventually("the others to find the member back", || all_see(&rest, &gone.address, "alive"));
+    eventually("the member to know the others", || {
+        let cluster = gone.cluster();
+        cluster.len() == 4 && cluster.iter().all(|(_, state)| state == "alive")
+    });
+
+    gone.stop();
+    for member in &mut members {
+        member.stop();
+    }
+}
+
+#[test]
+fn test_not_a_member() {
+    let db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let server = Server::bind("127.0.0.1:0", db).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    let cluster = Client::connect(address).unwrap().cluster();
+    assert!(matches!(cluster, Err(Error::InvalidArgument(_))), "{:?}", cluster);
+}
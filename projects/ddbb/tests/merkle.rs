@@ -0,0 +1,86 @@
+use ddbb::admin::{self, Admin};
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::merkle::{self, MerkleTree};
+use ddbb::query::QueryResult;
+use ddbb::vfs::MemFs;
+use std::sync::Arc;
+
+fn pairs(count: usize) -> Vec<(String, String)> {
+    (0..count).map(|i| (format!("key{:04}", i), format!("value{}", i))).collect()
+}
+
+fn tree(depth: u32, pairs: &[(String, String)]) -> MerkleTree {
+    MerkleTree::build(depth, pairs.iter().map(|(key, value)| (key.as_str(), value.as_str()))).unwrap()
+}
+
+#[test]
+fn test_tree() {
+    let all = pairs(500);
+    let same = tree(8, &all);
+    assert_eq!(same.nodes().len(), 511);
+    // the order of the pairs does not matter
+    let reversed: Vec<_> = all.iter().rev().cloned().collect();
+    assert_eq!(tree(8, &reversed), same);
+    assert!(same.differences(&tree(8, &reversed)).unwrap().is_empty());
+
+    // a changed, missing or extra pair changes its leaf only
+    let mut changed = all.clone();
+    changed[10].1 = "other".to_string();
+    changed.remove(20);
+    changed.push(("extra".to_string(), "1".to_string()));
+    let mut expected = vec![merkle::leaf("key0010", 8), merkle::leaf("key0020", 8), merkle::leaf("extra", 8)];
+    expected.sort();
+    expected.dedup();
+    let other = tree(8, &changed);
+    assert_ne!(other.root(), same.root());
+    assert_eq!(other.differences(&same).unwrap(), expected);
+
+    // the leaves are ranges of hashes
+    for leaf in [0, 17, 255] {
+        let (first, last) = merkle::leaf_hashes(leaf, 8);
+        assert_eq!((merkle::leaf_of_hash(first, 8), merkle::leaf_of_hash(last, 8)), (leaf, leaf));
+    }
+    assert_eq!(merkle::leaf_hashes(0, 0), (0, u64::MAX));
+
+    // the nodes make the tree again, if they add up
+    assert_eq!(MerkleTree::from_nodes(8, same.nodes()).unwrap(), same);
+    let mut bad = same.nodes().to_vec();
+    bad[300] ^= 1;
+    assert!(matches!(MerkleTree::from_nodes(8, &bad), Err(Error::Corruption(_))));
+    assert!(matches!(MerkleTree::from_nodes(7, same.nodes()), Err(Error::Corruption(_))));
+    assert!(matches!(same.differences(&tree(7, &all)), Err(Error::InvalidArgument(_))));
+    assert!(matches!(MerkleTree::build(merkle::MAX_DEPTH + 1, []), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_command() {
+    let mut db: LogManager<String, String> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let all = pairs(300);
+    for (key, value) in &all {
+        db.insert(key.clone(), value.clone()).unwrap();
+    }
+    let run = |db: &mut LogManager<String, String>, command: &str| {
+        match admin::execute(db, &Admin::parse(command).unwrap().unwrap()).unwrap() {
+            QueryResult::Pairs { pairs, next: None } => pairs,
+            other => panic!("{:?}", other),
+        }
+    };
+    let nodes = run(&mut db, "MERKLE 6");
+    assert_eq!(MerkleTree::from_pairs(6, &nodes).unwrap(), tree(6, &all));
+
+    let leaf = merkle::leaf("key0042", 6);
+    let in_leaf = run(&mut db, &format!("merkle 6 {}", leaf));
+    assert!(in_leaf.contains(&("key0042".to_string(), "value42".to_string())));
+    assert!(in_leaf.iter().all(|(key, _)| merkle::leaf(key, 6) == leaf));
+    let expected = all.iter().filter(|(key, _)| merkle::leaf(key, 6) == leaf).count();
+    assert_eq!(in_leaf.len(), expected);
+
+    for bad in ["MERKLE", "MERKLE x", "MERKLE 6 1 2"] {
+        assert!(matches!(Admin::parse(bad), Err(Error::InvalidArgument(_))), "{}", bad);
+    }
+    for bad in ["MERKLE 21", "MERKLE 6 64"] {
+        let admin = Admin::parse(bad).unwrap().unwrap();
+        assert!(matches!(admin::execute(&mut db, &admin), Err(Error::InvalidArgument(_))), "{}", bad);
+    }
+}
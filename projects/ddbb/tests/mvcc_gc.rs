@@ -0,0 +1,116 @@
+mod common;
+
+use common::key;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::vfs::MemFs;
+use std::sync::Arc;
+
+fn options() -> Options {
+    Options { write_buffer_size: 1024, ..Options::default() }
+}
+
+#[test]
+fn test_snapshot_reads_survive_flush_and_compaction() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options()).unwrap();
+    for i in 0..100 {
+        tree.insert(&key(i), b"old").unwrap();
+    }
+    let snapshot = tree.snapshot();
+    for i in 0..100 {
+        if i % 2 == 0 {
+            tree.insert(&key(i), b"new").unwrap();
+        } else {
+            tree.delete(&key(i)).unwrap();
+        }
+    }
+    tree.insert(&key(500), b"new").unwrap();
+
+    let check = |tree: &LsmTree| {
+        assert_eq!(tree.get_at(&snapshot, &key(1)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(tree.get_at(&snapshot, &key(2)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(tree.get_at(&snapshot, &key(500)).unwrap(), None);
+        assert_eq!(tree.get(&key(1)).unwrap(), None);
+        assert_eq!(tree.get(&key(2)).unwrap(), Some(b"new".to_vec()));
+        let old = tree.range_at(&snapshot, ..).unwrap();
+        assert_eq!(old.len(), 100);
+        assert!(old.iter().all(|(_, value)| value == b"old"));
+        assert_eq!(tree.traverse().unwrap().len(), 51);
+    };
+    check(&tree);
+    tree.flush().unwrap();
+    check(&tree);
+    tree.compact().unwrap();
+    check(&tree);
+}
+
+#[test]
+fn test_versions_collected_once_snapshot_released() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", Options::default()).unwrap();
+    // without snapshots an overwrite replaces the value
+    for round in 0..5 {
+        for i in 0..20 {
+            tree.insert(&key(i), format!("v{}", round).as_bytes()).unwrap();
+        }
+    }
+    tree.flush().unwrap();
+    tree.compact().unwrap();
+    assert_eq!(tree.metrics().versions.retained, 0);
+
+    // a long-lived snapshot makes history pile up, in the memtable and in the runs
+    let snapshot = tree.snapshot();
+    for round in 5..10 {
+        for i in 0..20 {
+            tree.insert(&key(i), format!("v{}", round).as_bytes()).unwrap();
+        }
+    }
+    let stats = tree.metrics().versions;
+    assert_eq!((stats.snapshots, stats.oldest_snapshot), (1, Some(snapshot.sequence())));
+    // v5 to v8 of every key, v4 is in a run
+    assert_eq!(stats.retained, 80);
+    tree.flush().unwrap();
+    tree.compact().unwrap();
+    // v4 to v8, merged into one run
+    assert_eq!(tree.metrics().versions.retained, 100);
+    assert_eq!(tree.get_at(&snapshot, &key(3)).unwrap(), Some(b"v4".to_vec()));
+
+    // the retained count is kept in the manifest
+    drop(snapshot);
+    drop(tree);
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", Options::default()).unwrap();
+    let stats = tree.metrics().versions;
+    assert_eq!((stats.snapshots, stats.retained), (0, 100));
+    assert_eq!(stats.last_sequence, 200);
+
+    let dropped = stats.dropped;
+    tree.compact().unwrap();
+    let stats = tree.metrics().versions;
+    assert_eq!(stats.retained, 0);
+    assert_eq!(stats.dropped - dropped, 100);
+    assert_eq!(tree.get(&key(3)).unwrap(), Some(b"v9".to_vec()));
+}
+
+#[test]
+fn test_compaction_keeps_value_a_snapshot_reads() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), "db", options()).unwrap();
+    tree.insert(b"a", b"1").unwrap();
+    tree.insert(b"a", b"2").unwrap();
+    let before_delete = tree.snapshot();
+    tree.delete(b"a").unwrap();
+    tree.flush().unwrap();
+    tree.compact().unwrap();
+    // the tombstone and the value under it stay, the first value is gone
+    assert_eq!(tree.get_at(&before_delete, b"a").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(tree.get(b"a").unwrap(), None);
+    assert_eq!(tree.metrics().versions.retained, 1);
+
+    drop(before_delete);
+    tree.compact().unwrap();
+    // nothing deeper, the key is gone altogether
+    assert_eq!(tree.metrics().versions.retained, 0);
+    assert_eq!(tree.metrics().levels.iter().map(|level| level.runs).sum::<usize>(), 0);
+    assert_eq!(tree.get(b"a").unwrap(), None);
+}
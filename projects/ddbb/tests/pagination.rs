@@ -0,0 +1,100 @@
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::scan::{Page, ScanOptions};
+use ddbb::vfs::MemFs;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+type Db = LogManager<u64, String>;
+
+fn open() -> Db {
+    let mut db: Db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    for key in 0..100 {
+        db.insert(key * 2, format!("v{}", key * 2)).unwrap();
+    }
+    db
+}
+
+fn page_size(limit: usize) -> ScanOptions {
+    ScanOptions { limit: Some(limit), ..ScanOptions::default() }
+}
+
+fn keys(page: &Page<u64, String>) -> Vec<u64> {
+    page.pairs.iter().map(|(key, _)| *key).collect()
+}
+
+// every page of `range`, following the resume tokens
+fn all_pages<R: RangeBounds<u64> + Clone>(db: &Db, range: R, limit: usize) -> Vec<Vec<u64>> {
+    let mut pages = Vec::new();
+    let mut options = page_size(limit);
+    loop {
+        let page = db.scan(range.clone(), &options).unwrap();
+        pages.push(keys(&page));
+        match page.next {
+            Some(token) => options.resume = Some(token),
+            None => return pages,
+        }
+    }
+}
+
+#[test]
+fn test_pages_cover_the_range() {
+    let db = open();
+    let pages = all_pages(&db, 10..50, 7);
+    assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), [7, 7, 6]);
+    assert_eq!(pages.concat(), (5..25).map(|k| k * 2).collect::<Vec<_>>());
+
+    // a page that ends exactly at the end of the range has no next page
+    assert_eq!(all_pages(&db, 0..=9, 5), [vec![0, 2, 4, 6, 8]]);
+    assert_eq!(all_pages(&db, 500.., 5), [Vec::<u64>::new()]);
+    assert_eq!(all_pages(&db, .., 1).len(), 100);
+
+    let everything = db.scan(.., &ScanOptions::default()).unwrap();
+    assert_eq!((everything.pairs.len(), everything.next), (100, None));
+    assert_eq!(everything.pairs[3], (6, "v6".to_string()));
+}
+
+#[test]
+fn test_offset() {
+    let db = open();
+    let page = db.scan(100.., &ScanOptions { limit: Some(3), offset: 4, resume: None }).unwrap();
+    assert_eq!(keys(&page), [108, 110, 112]);
+    let rest = db.scan(100.., &ScanOptions { offset: 45, ..ScanOptions::default() }).unwrap();
+    assert_eq!((keys(&rest), rest.next), (vec![190, 192, 194, 196, 198], None));
+    assert!(db.scan(100.., &ScanOptions { offset: 500, ..ScanOptions::default() }).unwrap().pairs.is_empty());
+}
+
+#[test]
+fn test_resume_token_survives_writes() {
+    let mut db = open();
+    let first = db.scan(.., &page_size(5)).unwrap();
+    assert_eq!(keys(&first), [0, 2, 4, 6, 8]);
+    let token = first.next.unwrap();
+
+    // the last key of the page goes away and new keys appear on both sides of it
+    db.delete(&8).unwrap();
+    db.insert(7, "new".to_string()).unwrap();
+    db.insert(9, "new".to_string()).unwrap();
+    let second = db.scan(.., &ScanOptions { resume: Some(token.clone()), ..page_size(3) }).unwrap();
+    assert_eq!(keys(&second), [9, 10, 12]);
+
+    // with another range, the token resumes after its key in that range
+    let elsewhere = db.scan(50..60, &ScanOptions { resume: Some(token.clone()), ..page_size(3) }).unwrap();
+    assert_eq!(keys(&elsewhere), [50, 52, 54]);
+    let before = db.scan(..5, &ScanOptions { resume: Some(token), ..page_size(3) }).unwrap();
+    assert_eq!((keys(&before), before.next), (vec![], None));
+}
+
+#[test]
+fn test_bad_options() {
+    let db = open();
+    let token = db.scan(.., &page_size(2)).unwrap().next.unwrap();
+    let mut damaged = token.clone().into_bytes();
+    damaged[0] = if damaged[0] == b'3' { b'4' } else { b'3' };
+    let damaged = String::from_utf8(damaged).unwrap();
+    for bad in ["", "zz", "31", &token[1..], &damaged] {
+        let options = ScanOptions { resume: Some(bad.to_string()), ..ScanOptions::default() };
+        assert!(matches!(db.scan(.., &options), Err(Error::InvalidArgument(_))), "{:?}", bad);
+    }
+    assert!(matches!(db.scan(.., &page_size(0)), Err(Error::InvalidArgument(_))));
+}
@@ -0,0 +1,116 @@
+use ddbb::batch::WriteBatch;
+use ddbb::error::Error;
+use ddbb::partition::PartitionedLog;
+use ddbb::vfs::{MemFs, PowerLoss};
+use std::sync::Arc;
+use std::thread;
+
+fn key(i: usize) -> String {
+    format!("key{:04}", i)
+}
+
+#[test]
+fn test_parallel_writes() {
+    let vfs = Arc::new(MemFs::new());
+    let log = Arc::new(PartitionedLog::open(vfs.clone(), "db", 4).unwrap());
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let log = log.clone();
+            thread::spawn(move || {
+                for i in (writer..800).step_by(8) {
+                    log.insert(key(i), i).unwrap();
+                }
+                log.delete(&key(writer)).unwrap();
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let expected: Vec<(String, usize)> = (8..800).map(|i| (key(i), i)).collect();
+    assert_eq!(log.range(..), expected);
+    assert_eq!(log.count_range(key(100)..key(200)), 100);
+    assert_eq!(log.search(&key(42)), Some(42));
+    assert_eq!(log.search(&key(3)), None);
+    // every partition has its share of the keys
+    for partition in 0..4 {
+        assert!((0..800).filter(|&i| log.partition(&key(i)) == partition).count() > 100);
+    }
+
+    // the number of partitions is the one the database was made with
+    drop(log);
+    let log = PartitionedLog::<String, usize>::open(vfs.clone(), "db", 4).unwrap();
+    assert_eq!(log.range(..), expected);
+    log.compact().unwrap();
+    drop(log);
+    let other = PartitionedLog::<String, usize>::open(vfs.clone(), "db", 3);
+    assert!(matches!(other, Err(Error::InvalidArgument(_))));
+    let log = PartitionedLog::<String, usize>::open(vfs.clone(), "db", 4).unwrap();
+    assert_eq!(log.range(..), expected);
+}
+
+#[test]
+fn test_batch_across_partitions() {
+    let mut batch = WriteBatch::new();
+    for i in 0..10 {
+        batch.insert(key(i), 2);
+    }
+    batch.delete(key(10));
+
+    let vfs = Arc::new(MemFs::new());
+    let log = PartitionedLog::open(vfs.clone(), "db", 4).unwrap();
+    for i in 0..=10 {
+        log.insert(key(i), 1).unwrap();
+    }
+    log.write_batch(batch.clone()).unwrap();
+    assert_eq!(log.range(..), (0..10).map(|i| (key(i), 2)).collect::<Vec<_>>());
+
+    // a crash anywhere in a batch leaves all of it or none of it
+    let before: Vec<(String, usize)> = (0..=10).map(|i| (key(i), 1)).collect();
+    let after: Vec<(String, usize)> = (0..10).map(|i| (key(i), 2)).collect();
+    let mut finished = false;
+    for ops in 0.. {
+        let vfs = Arc::new(MemFs::new());
+        let log = PartitionedLog::open(vfs.clone(), "db", 4).unwrap();
+        for i in 0..=10 {
+            log.insert(key(i), 1).unwrap();
+        }
+        log.flush().unwrap();
+        vfs.fail_after(ops);
+        let result = log.write_batch(batch.clone());
+        drop(log);
+        vfs.power_loss(PowerLoss::DropUnsynced);
+        let log = PartitionedLog::<String, usize>::open(vfs.clone(), "db", 4).unwrap();
+        let pairs = log.range(..);
+        assert!(pairs == before || pairs == after, "{} operations: {:?}", ops, pairs);
+        finished |= pairs == after && result.is_err();
+        if result.is_ok() {
+            assert_eq!(pairs, after);
+            break;
+        }
+    }
+    // some crashes came after the decision, and opening the database finished the batch
+    assert!(finished);
+}
+
+#[test]
+fn test_committed_batch_survives_power_loss() {
+    let vfs = Arc::new(MemFs::new());
+    let log = PartitionedLog::open(vfs.clone(), "db", 4).unwrap();
+    let mut batch = WriteBatch::new();
+    for i in 0..10 {
+        batch.insert(key(i), 1);
+    }
+    log.write_batch(batch).unwrap();
+    // the next batch syncs the decisions, and the one of the first batch is gone from them
+    let mut batch = WriteBatch::new();
+    for i in 10..12 {
+        batch.insert(key(i), 2);
+    }
+    log.write_batch(batch).unwrap();
+    drop(log);
+    vfs.power_loss(PowerLoss::DropUnsynced);
+
+    let log = PartitionedLog::<String, usize>::open(vfs.clone(), "db", 4).unwrap();
+    assert_eq!(log.range(key(0)..key(10)), (0..10).map(|i| (key(i), 1)).collect::<Vec<_>>());
+}
@@ -0,0 +1,116 @@
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::query::{self, Keys, Query, QueryResult};
+use ddbb::scan::ScanOptions;
+use ddbb::vfs::MemFs;
+use std::sync::Arc;
+
+fn pairs(list: &[(&str, &str)]) -> QueryResult {
+    let pairs = list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    QueryResult::Pairs { pairs, next: None }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(query::parse("GET a").unwrap(), Query::Get("a".to_string()));
+    assert_eq!(query::parse("  set  a  1 ").unwrap(), Query::Set("a".to_string(), "1".to_string()));
+    assert_eq!(query::parse("Del a").unwrap(), Query::Del("a".to_string()));
+    assert_eq!(
+        query::parse("SCAN a..b LIMIT 10").unwrap(),
+        Query::Scan {
+            keys: Keys::Range { start: Some("a".to_string()), end: Some("b".to_string()) },
+            options: ScanOptions { limit: Some(10), ..ScanOptions::default() },
+            reverse: false
+        }
+    );
+    assert_eq!(
+        query::parse("scan ..b").unwrap(),
+        Query::Scan {
+            keys: Keys::Range { start: None, end: Some("b".to_string()) },
+            options: ScanOptions::default(),
+            reverse: false
+        }
+    );
+    assert_eq!(
+        query::parse("SCAN p* after 0a1b offset 2 rev LIMIT 3").unwrap(),
+        Query::Scan {
+            keys: Keys::Prefix("p".to_string()),
+            options: ScanOptions { limit: Some(3), offset: 2, resume: Some("0a1b".to_string()) },
+            reverse: true
+        }
+    );
+    assert_eq!(query::parse("COUNT user*").unwrap(), Query::Count(Keys::Prefix("user".to_string())));
+    assert_eq!(query::parse("COUNT *").unwrap(), Query::Count(Keys::Prefix(String::new())));
+    assert_eq!(query::parse("COUNT ..").unwrap(), Query::Count(Keys::Range { start: None, end: None }));
+
+    for bad in ["", "   ", "PUT a 1", "GET", "SET a", "GET a b", "SCAN abc", "SCAN a.. LIMIT", "SCAN a.. LIMIT x",
+        "SCAN a.. TOP 3", "COUNT a* b", "SCAN a.. LIMIT 1 LIMIT 2", "SCAN a.. OFFSET -1", "SCAN a.. AFTER", "SCAN a.. REV REV"]
+    {
+        assert!(matches!(query::parse(bad), Err(Error::InvalidArgument(_))), "{:?}", bad);
+    }
+}
+
+#[test]
+fn test_display() {
+    for input in ["GET a", "SET a 1", "DEL a", "SCAN a..b", "SCAN ..b LIMIT 10", "SCAN p* LIMIT 3 OFFSET 2 AFTER 0a1b REV",
+        "SCAN .. REV", "COUNT user*", "COUNT *", "COUNT a.."]
+    {
+        let query = query::parse(input).unwrap();
+        assert_eq!(query.to_string(), input);
+        assert_eq!(query::parse(&query.to_string()).unwrap(), query);
+    }
+    assert_eq!(query::parse("scan p* rev after 0a1b limit 3").unwrap().to_string(), "SCAN p* LIMIT 3 AFTER 0a1b REV");
+}
+
+#[test]
+fn test_execute() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db: LogManager<String, String> = LogManager::open(vfs.clone(), "db").unwrap();
+    for (key, value) in [("user:1", "ann"), ("user:2", "bo"), ("user:3", "cy"), ("team:1", "red"), ("z", "last")] {
+        assert_eq!(query::run(&mut db, &format!("SET {} {}", key, value)).unwrap(), QueryResult::Done);
+    }
+    assert_eq!(query::run(&mut db, "GET user:2").unwrap(), QueryResult::Value(Some("bo".to_string())));
+    assert_eq!(query::run(&mut db, "DEL user:2").unwrap(), QueryResult::Done);
+    assert_eq!(query::run(&mut db, "GET user:2").unwrap(), QueryResult::Value(None));
+
+    assert_eq!(query::run(&mut db, "SCAN user:..user:9").unwrap(), pairs(&[("user:1", "ann"), ("user:3", "cy")]));
+    assert_eq!(query::run(&mut db, "SCAN ..user:3").unwrap(), pairs(&[("team:1", "red"), ("user:1", "ann")]));
+    let first_page = query::run(&mut db, "SCAN .. LIMIT 1").unwrap();
+    let QueryResult::Pairs { pairs: first, next: Some(token) } = first_page else {
+        panic!("a limited SCAN of a longer range has a next page");
+    };
+    assert_eq!(first, [("team:1".to_string(), "red".to_string())]);
+    let next_page = query::run(&mut db, &format!("SCAN .. LIMIT 3 AFTER {}", token)).unwrap();
+    assert_eq!(next_page, pairs(&[("user:1", "ann"), ("user:3", "cy"), ("z", "last")]));
+    assert_eq!(query::run(&mut db, "SCAN user* LIMIT 1 OFFSET 1").unwrap(), pairs(&[("user:3", "cy")]));
+    let newest_first = pairs(&[("user:3", "cy"), ("user:1", "ann"), ("team:1", "red")]);
+    assert_eq!(query::run(&mut db, "SCAN ..z REV").unwrap(), newest_first);
+    assert!(matches!(query::run(&mut db, "SCAN .. AFTER abc"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(query::run(&mut db, "SCAN .. LIMIT 0"), Err(Error::InvalidArgument(_))));
+    assert_eq!(query::run(&mut db, "SCAN user* LIMIT 5").unwrap(), pairs(&[("user:1", "ann"), ("user:3", "cy")]));
+    assert_eq!(query::run(&mut db, "COUNT user*").unwrap(), QueryResult::Count(2));
+    assert_eq!(query::run(&mut db, "COUNT *").unwrap(), QueryResult::Count(4));
+    assert_eq!(query::run(&mut db, "COUNT u..").unwrap(), QueryResult::Count(3));
+    assert_eq!(query::run(&mut db, "COUNT x*").unwrap(), QueryResult::Count(0));
+    drop(db);
+
+    // SETs and DELs went through the log like any other write
+    let mut db: LogManager<String, String> = LogManager::open(vfs, "db").unwrap();
+    assert_eq!(query::run(&mut db, "COUNT *").unwrap(), QueryResult::Count(4));
+}
+
+#[test]
+fn test_execute_with_typed_keys() {
+    let mut db: LogManager<u64, i32> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    for key in [2u64, 9, 10, 11, 100] {
+        query::run(&mut db, &format!("SET {} -{}", key, key)).unwrap();
+    }
+    // ranges compare the keys as numbers, prefixes match them as printed
+    assert_eq!(query::run(&mut db, "SCAN 9..11").unwrap(), pairs(&[("9", "-9"), ("10", "-10")]));
+    assert_eq!(query::run(&mut db, "COUNT 1*").unwrap(), QueryResult::Count(3));
+
+    for bad in ["GET x", "SET 1 one", "SCAN a..", "DEL -1"] {
+        assert!(matches!(query::run(&mut db, bad), Err(Error::InvalidArgument(_))), "{:?}", bad);
+    }
+    assert_eq!(query::run(&mut db, "COUNT *").unwrap(), QueryResult::Count(5));
+}
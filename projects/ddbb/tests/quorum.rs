@@ -0,0 +1,94 @@
+use ddbb::client::{Client, ClientOptions};
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::query::Keys;
+use ddbb::quorum::{Consistency, Coordinator, CoordinatorOptions};
+use ddbb::replication::FollowerOptions;
+use ddbb::scan::ScanOptions;
+use ddbb::server::{Server, ServerOptions, ShutdownHandle};
+use ddbb::vfs::MemFs;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type Serving = (SocketAddr, ShutdownHandle, JoinHandle<ddbb::error::Result<()>>);
+
+// A server, the follower of `leader` if there is one
+fn start(leader: Option<String>) -> Serving {
+    let db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let follow = leader.map(|leader| FollowerOptions::new(&leader));
+    let options = ServerOptions { follow, ..ServerOptions::default() };
+    let server = Server::bind_with("127.0.0.1:0", db, options).unwrap();
+    let (address, shutdown) = (server.local_addr().unwrap(), server.shutdown_handle());
+    (address, shutdown, thread::spawn(move || server.serve()))
+}
+
+// An address nothing listens on
+fn nowhere() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[test]
+fn test_levels() {
+    let levels = [Consistency::One, Consistency::Quorum, Consistency::All];
+    assert_eq!(["one", "Quorum", "ALL"].map(|level| level.parse::<Consistency>().unwrap()), levels);
+    assert!(matches!("TWO".parse::<Consistency>(), Err(Error::InvalidArgument(_))));
+    assert_eq!(Consistency::Quorum.to_string(), "QUORUM");
+    let needed = |servers| levels.map(|level| level.needed(servers));
+    assert_eq!(needed(1), [1, 1, 1]);
+    assert_eq!(needed(3), [1, 2, 3]);
+    assert_eq!(needed(4), [1, 3, 4]);
+}
+
+#[test]
+fn test_reconcile() {
+    let leader = start(None);
+    // a follower of a leader that is not there, which never gets a write
+    let stale = start(Some(nowhere()));
+    let follower = start(Some(leader.0.to_string()));
+    let followers = [stale.0.to_string(), follower.0.to_string()];
+    let options = CoordinatorOptions {
+        client: ClientOptions { retries: 0, ..ClientOptions::default() },
+        timeout: Duration::from_millis(300),
+    };
+    let followers: Vec<&str> = followers.iter().map(String::as_str).collect();
+    let mut coordinator = Coordinator::new(&leader.0.to_string(), &followers, options);
+
+    // a majority has the write once it returns, and a majority has a server that answers with it
+    coordinator.set("a", "1", Consistency::Quorum).unwrap();
+    assert_eq!(Client::connect(follower.0).unwrap().get("a").unwrap().as_deref(), Some("1"));
+    assert_eq!(coordinator.get("a", Consistency::One).unwrap(), None);
+    assert_eq!(coordinator.get("a", Consistency::Quorum).unwrap().as_deref(), Some("1"));
+    assert_eq!(coordinator.get("a", Consistency::All).unwrap().as_deref(), Some("1"));
+    assert_eq!(coordinator.count(Keys::Prefix(String::new()), Consistency::One).unwrap(), 0);
+    assert_eq!(coordinator.count(Keys::Prefix(String::new()), Consistency::Quorum).unwrap(), 1);
+
+    // a write that cannot reach all of them fails, but the leader has it
+    let failed = coordinator.set("b", "2", Consistency::All);
+    assert!(matches!(&failed, Err(Error::Io(e)) if e.to_string().contains("on 2 of 3")), "{:?}", failed);
+    assert_eq!(Client::connect(leader.0).unwrap().get("b").unwrap().as_deref(), Some("2"));
+    let page = coordinator.scan(Keys::Prefix(String::new()), ScanOptions::default(), Consistency::All);
+    assert_eq!(page.unwrap().pairs.len(), 2);
+    coordinator.del("a", Consistency::One).unwrap();
+
+    // without the follower that has the writes, the leader is the only one that does
+    let (_, shutdown, serving) = follower;
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+    assert_eq!(coordinator.get("b", Consistency::Quorum).unwrap().as_deref(), Some("2"));
+    let failed = coordinator.get("b", Consistency::All);
+    let answered = |e: &std::io::Error| e.to_string().contains("2 of 3 servers answered");
+    assert!(matches!(&failed, Err(Error::Io(e)) if answered(e)), "{:?}", failed);
+    let failed = coordinator.set("c", "3", Consistency::Quorum);
+    assert!(matches!(failed, Err(Error::Io(_))), "{:?}", failed);
+    coordinator.set("d", "4", Consistency::One).unwrap();
+    assert_eq!(coordinator.get("a", Consistency::Quorum).unwrap(), None);
+    assert_eq!(coordinator.count(Keys::Prefix(String::new()), Consistency::Quorum).unwrap(), 3);
+
+    for (_, shutdown, serving) in [leader, stale] {
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+    }
+}
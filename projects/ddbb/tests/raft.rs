@@ -0,0 +1,177 @@
+mod common;
+
+use common::{eventually, wait_for};
+use ddbb::client::Client;
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::query::{Keys, Query};
+use ddbb::raft::{RaftOptions, RAFT_RETAINED};
+use ddbb::scan::ScanOptions;
+use ddbb::server::{Server, ServerOptions, ShutdownHandle};
+use ddbb::vfs::MemFs;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Wait for `done`, failing the test if it takes too long
+struct Node {
+    vfs: Arc<MemFs>,
+    address: String,
+    options: RaftOptions,
+    running: Option<(ShutdownHandle, JoinHandle<ddbb::error::Result<()>>)>,
+}
+
+impl Node {
+    fn start(&mut self) {
+        let db = LogManager::open(self.vfs.clone(), "node").unwrap();
+        let options = ServerOptions { raft: Some(self.options.clone()), ..ServerOptions::default() };
+        let server = Server::bind_with(self.address.as_str(), db, options).unwrap();
+        let shutdown = server.shutdown_handle();
+        self.running = Some((shutdown, thread::spawn(move || server.serve())));
+    }
+
+    fn stop(&mut self) {
+        let (shutdown, serving) = self.running.take().expect("the node runs");
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+    }
+
+    fn client(&self) -> Client {
+        Client::connect(self.address.as_str()).unwrap()
+    }
+
+    fn stat(&self, name: &str) -> String {
+        let stats = self.client().stats().unwrap();
+        stats.into_iter().find(|(stat, _)| stat == name).expect("the stat is there").1
+    }
+}
+
+// A cluster of `size` nodes on free ports of this machine, all running
+fn cluster(size: usize) -> Vec<Node> {
+    // the ports are free once these are dropped, and the servers take them right after
+    let listeners: Vec<TcpListener> = (0..size).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+    let addresses: Vec<String> =
+        listeners.iter().map(|listener| listener.local_addr().unwrap().to_string()).collect();
+    drop(listeners);
+    let mut nodes: Vec<Node> = addresses
+        .iter()
+        .map(|address| {
+            let peers: Vec<&str> =
+                addresses.iter().filter(|peer| *peer != address).map(String::as_str).collect();
+            let mut options = RaftOptions::new(address, &peers);
+            options.election_timeout = Duration::from_millis(150);
+            options.heartbeat = Duration::from_millis(30);
+            Node { vfs: Arc::new(MemFs::new()), address: address.clone(), options, running: None }
+        })
+        .collect();
+    nodes.iter_mut().for_each(Node::start);
+    nodes
+}
+
+// The node every running node takes for the leader, once they agree
+fn leader(nodes: &[Node]) -> usize {
+    wait_for("a leader", || {
+        let running: Vec<&Node> = nodes.iter().filter(|node| node.running.is_some()).collect();
+        let leads = |node: &Node| node.running.is_some() && node.stat("raft_role") == "leader";
+        let leader = nodes.iter().position(leads)?;
+        let agreed = running.iter().all(|node| node.stat("raft_leader") == nodes[leader].address);
+        agreed.then_some(leader)
+    })
+}
+
+fn everything(node: &Node) -> Vec<(String, String)> {
+    node.client().scan(Keys::Prefix(String::new()), ScanOptions::default()).unwrap().pairs
+}
+
+#[test]
+fn test_single_node() {
+    let mut nodes = cluster(1);
+    assert_eq!(leader(&nodes), 0);
+    let mut client = nodes[0].client();
+    client.set("a", "1").unwrap();
+    client.set("b", "2").unwrap();
+    client.del("a").unwrap();
+    assert_eq!(everything(&nodes[0]), vec![("b".to_string(), "2".to_string())]);
+    let term = nodes[0].stat("raft_term");
+
+    // the term, the log and the state survive a restart, and the node leads again, later
+    nodes[0].stop();
+    nodes[0].start();
+    leader(&nodes);
+    assert!(nodes[0].stat("raft_term").parse::<u64>().unwrap() > term.parse().unwrap());
+    assert_eq!(everything(&nodes[0]), vec![("b".to_string(), "2".to_string())]);
+    nodes[0].client().set("c", "3").unwrap();
+    // the writes, and the empty batch of each leader
+    assert_eq!(nodes[0].stat("lsn"), "6");
+    nodes[0].stop();
+}
+
+#[test]
+fn test_failover() {
+    let mut nodes = cluster(3);
+    let first = leader(&nodes);
+    let mut client = nodes[first].client();
+    client.set("a", "1").unwrap();
+    let mut batch = ddbb::batch::WriteBatch::new();
+    batch.insert("b".to_string(), "2".to_string()).delete("a".to_string());
+    client.write_batch(&batch).unwrap();
+    let expected = vec![("b".to_string(), "2".to_string())];
+    for node in &nodes {
+        eventually("the writes on every node", || everything(node) == expected);
+    }
+
+    // the others refuse writes, and say where they go
+    let other = (first + 1) % 3;
+    let refused = nodes[other].client().set("c", "3");
+    let Err(Error::PermissionDenied(message)) = refused else {
+        panic!("{:?}", refused);
+    };
+    assert!(message.ends_with(&nodes[first].address), "{}", message);
+
+    // without its leader the cluster elects another one, which has the writes
+    nodes[first].stop();
+    let second = leader(&nodes);
+    assert_ne!(second, first);
+    nodes[second].client().set("c", "3").unwrap();
+    assert_eq!(everything(&nodes[second]).len(), 2);
+
+    // and the old leader comes back as a follower, and catches up
+    nodes[first].start();
+    eventually("the old leader to catch up", || everything(&nodes[first]).len() == 2);
+    assert_eq!(leader(&nodes), second);
+    assert_eq!(nodes[first].stat("raft_role"), "follower");
+
+    // with one node left there is no majority, writes are not committed
+    let third = 3 - first - second;
+    nodes[third].stop();
+    nodes[first].stop();
+    let lost = nodes[second].client().set("d", "4");
+    assert!(matches!(lost, Err(Error::Io(_)) | Err(Error::PermissionDenied(_))), "{:?}", lost);
+    nodes[second].stop();
+}
+
+#[test]
+fn test_far_behind() {
+    let mut nodes = cluster(3);
+    let first = leader(&nodes);
+    let behind = (first + 1) % 3;
+    nodes[behind].stop();
+
+    // more writes than the Raft logs keep, the node that missed them gets a snapshot
+    let mut client = nodes[first].client();
+    let queries: Vec<Query> =
+        (0..2 * RAFT_RETAINED + 100).map(|i| Query::Set(format!("key{:05}", i), i.to_string())).collect();
+    for result in client.pipeline(&queries).unwrap() {
+        result.unwrap();
+    }
+    nodes[behind].start();
+    let lsn = nodes[first].stat("lsn");
+    eventually("the snapshot", || nodes[behind].stat("lsn") == lsn);
+    client.set("last", "write").unwrap();
+    wait_for("the write after it", || nodes[behind].client().get("last").unwrap());
+    assert_eq!(everything(&nodes[behind]), everything(&nodes[first]));
+    for node in &mut nodes {
+        node.stop();
+    }
+}
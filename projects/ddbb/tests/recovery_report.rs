@@ -0,0 +1,100 @@
+use ddbb::log::LogManager;
+use ddbb::vfs::{MemFs, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+
+const LOG_PATH: &str = "db/log.txt";
+
+#[test]
+fn test_recovery_report_counts_replayed_records() {
+    let vfs = Arc::new(MemFs::new());
+
+    let log_manager = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    assert_eq!(log_manager.recovery_report().records_replayed, 0);
+    assert_eq!(log_manager.recovery_report().checkpoint, None);
+    drop(log_manager);
+
+    let mut log_manager = LogManager::open(vfs.clone(), "db").unwrap();
+    for i in 1..=10 {
+        log_manager.insert(format!("key{}", i), i).unwrap();
+    }
+    log_manager.delete(&"key1".to_string()).unwrap();
+    drop(log_manager);
+
+    let log_manager = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    let report = log_manager.recovery_report();
+    assert_eq!(report.records_replayed, 11);
+    assert_eq!(report.corrupt_records_skipped, 0);
+    assert_eq!(report.bytes_scanned, vfs.read(Path::new(LOG_PATH)).unwrap().len() as u64);
+}
+
+#[test]
+fn test_recovery_report_checkpoint_after_shutdown() {
+    let vfs = Arc::new(MemFs::new());
+
+    for generation in 1..=2 {
+        let mut log_manager = LogManager::open(vfs.clone(), "db").unwrap();
+        log_manager.insert(format!("key{}", generation), generation).unwrap();
+        log_manager.shutdown().unwrap();
+        drop(log_manager);
+
+        let log_manager = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+        assert_eq!(log_manager.recovery_report().checkpoint, Some(generation as u64));
+        assert_eq!(log_manager.recovery_report().records_replayed, generation as usize);
+    }
+}
+
+#[test]
+fn test_recovery_report_skips_corrupt_records() {
+    let vfs = Arc::new(MemFs::new());
+
+    let mut log_manager = LogManager::open(vfs.clone(), "db").unwrap();
+    for i in 1..=5 {
+        log_manager.insert(format!("key{}", i), i).unwrap();
+    }
+    drop(log_manager);
+
+    // flip a value on the third record and chop the last one in half
+    let log = String::from_utf8(vfs.read(Path::new(LOG_PATH)).unwrap()).unwrap();
+    let mut damaged = log.replace("INSERT key3 3", "INSERT key3 9");
+    damaged.truncate(damaged.len() - 4);
+    vfs.write(Path::new(LOG_PATH), damaged.as_bytes()).unwrap();
+
+    let log_manager = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    let report = log_manager.recovery_report().clone();
+    assert_eq!(report.records_replayed, 3);
+    assert_eq!(report.corrupt_records_skipped, 1);
+    assert!(report.torn_bytes_discarded > 0);
+
+    assert_eq!(log_manager.search(&"key2".to_string()), Some(2));
+    assert_eq!(log_manager.search(&"key3".to_string()), None);
+    assert_eq!(log_manager.search(&"key5".to_string()), None);
+
+    // legacy records written before checksums existed are still understood
+    drop(log_manager);
+    // (their DELETE records have the key as {:?} wrote it)
+    vfs.write(Path::new(LOG_PATH), b"INSERT old 1\nDELETE \"old\"\nINSERT new 2\n").unwrap();
+    let log_manager = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    assert_eq!(log_manager.recovery_report().records_replayed, 3);
+    assert_eq!(log_manager.search(&"old".to_string()), None);
+    assert_eq!(log_manager.search(&"new".to_string()), Some(2));
+}
+
+#[test]
+fn test_recovery_report_skipped_records_change_nothing() {
+    let vfs = Arc::new(MemFs::new());
+
+    // records with a valid checksum and more tokens than they should have
+    let mut log = String::new();
+    let payloads = ["INSERT k 1 y z", "DELETE a 5 b", "INSERT b 2", "BATCH 1 INSERT c 3 d", "CHECKPOINT 3 0 x"];
+    for payload in payloads {
+        log += &format!("{:08x} {}\n", crc32fast::hash(payload.as_bytes()), payload);
+    }
+    vfs.create_dir_all(Path::new("db")).unwrap();
+    vfs.write(Path::new(LOG_PATH), log.as_bytes()).unwrap();
+    let log_manager = LogManager::<String, String>::open(vfs.clone(), "db").unwrap();
+    let report = log_manager.recovery_report();
+    assert_eq!((report.records_replayed, report.corrupt_records_skipped), (1, 4));
+    assert_eq!(report.checkpoint, None);
+    assert_eq!(log_manager.range(..), vec![("b".to_string(), "2".to_string())]);
+}
@@ -0,0 +1,370 @@
+mod common;
+
+use common::eventually;
+use ddbb::batch::WriteBatch;
+use ddbb::client::Client;
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::options::Options;
+use ddbb::query::Keys;
+use ddbb::quorum::{Consistency, Coordinator, CoordinatorOptions};
+use ddbb::replication::{Follower, FollowerOptions};
+use ddbb::scan::ScanOptions;
+use ddbb::server::{Server, ServerOptions};
+use ddbb::vfs::{MemFs, PowerLoss};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type Db = LogManager<String, String>;
+
+// Wait for `done`, failing the test if it takes too long
+#[test]
+fn test_lsn_and_backlog() {
+    let vfs = Arc::new(MemFs::new());
+    let mut leader: Db = LogManager::open(vfs.clone(), "leader").unwrap();
+    assert_eq!((leader.lsn(), leader.wal_since(0)), (0, Some(vec![])));
+    leader.insert("a".to_string(), "1".to_string()).unwrap();
+    leader.delete(&"b".to_string()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert("c".to_string(), "3".to_string()).insert("d".to_string(), "4".to_string());
+    leader.write_batch(batch).unwrap();
+    leader.create_text_index().unwrap(); // not a write
+
+    let writes = leader.wal_since(0).unwrap();
+    assert_eq!(writes.iter().map(|(lsn, _)| *lsn).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert_eq!(writes[0].1, "INSERT a 1");
+    assert!(writes[2].1.starts_with("BATCH 2 INSERT c 3"), "{:?}", writes);
+    assert_eq!(leader.wal_since(2).unwrap().len(), 1);
+    assert_eq!(leader.wal_since(3), Some(vec![]));
+    assert_eq!(leader.wal_since(4), None);
+
+    // the LSNs go on across compactions and opens, the backlog comes back from the log
+    leader.compact().unwrap();
+    assert_eq!(leader.wal_since(1).unwrap().len(), 2);
+    leader.insert("e".to_string(), "5".to_string()).unwrap();
+    drop(leader);
+    let leader: Db = LogManager::open(vfs.clone(), "leader").unwrap();
+    assert_eq!(leader.lsn(), 4);
+    assert_eq!(leader.wal_since(2), None);
+    assert_eq!(leader.wal_since(3).unwrap(), vec![(4, "INSERT e 5".to_string())]);
+
+    // the backlog only holds what fits
+    let options = Options { replication_backlog: 40, ..Options::default() };
+    let mut small: Db = LogManager::open_with(vfs.clone(), "small", options).unwrap();
+    for i in 0..10 {
+        small.insert(format!("key{}", i), i.to_string()).unwrap();
+    }
+    // three records of 13 bytes
+    assert_eq!(small.wal_since(7).unwrap().len(), 3);
+    assert_eq!(small.wal_since(6), None);
+}
+
+#[test]
+fn test_apply_and_install() {
+    let vfs = Arc::new(MemFs::new());
+    let mut leader: Db = LogManager::open(vfs.clone(), "leader").unwrap();
+    leader.insert("a".to_string(), "1".to_string()).unwrap();
+    leader.insert("b".to_string(), "2".to_string()).unwrap();
+    leader.delete(&"a".to_string()).unwrap();
+
+    // writes apply in order, with the LSNs of the leader
+    let mut follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    let writes = leader.wal_since(0).unwrap();
+    let out_of_order = follower.apply_wal(2, &writes[1].1);
+    assert!(matches!(out_of_order, Err(Error::InvalidArgument(_))), "{:?}", out_of_order);
+    assert!(follower.apply_wal(1, "CHECKPOINT 1 0").is_err());
+    for (lsn, record) in &writes {
+        follower.apply_wal(*lsn, record).unwrap();
+    }
+    assert_eq!((follower.range(..), follower.lsn()), (leader.range(..), 3));
+    assert_eq!(follower.tombstone_stats().live, 1);
+    drop(follower);
+    let mut follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    assert_eq!((follower.range(..), follower.lsn()), (leader.range(..), 3));
+
+    // a snapshot replaces everything, indexes included, and the LSN goes on from it
+    follower.create_index("value", |value: &String| Some(value.clone())).unwrap();
+    follower.apply_wal(4, "INSERT z 9").unwrap();
+    leader.insert("c".to_string(), "3".to_string()).unwrap();
+    leader.insert("d".to_string(), "4".to_string()).unwrap();
+    follower.install_snapshot(leader.snapshot()).unwrap();
+    assert_eq!((follower.range(..), follower.lsn()), (leader.range(..), 5));
+    assert!(follower.lookup_by_index("value", &"9".to_string()).unwrap().is_empty());
+    let expected = vec![("c".to_string(), "3".to_string())];
+    assert_eq!(follower.lookup_by_index("value", &"3".to_string()).unwrap(), expected);
+    leader.insert("e".to_string(), "5".to_string()).unwrap();
+    let (lsn, record) = leader.wal_since(5).unwrap().remove(0);
+    follower.apply_wal(lsn, &record).unwrap();
+    drop(follower);
+    let follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    assert_eq!((follower.range(..), follower.lsn()), (leader.range(..), 6));
+    assert!(!follower.needs_snapshot());
+}
+
+#[test]
+fn test_checkpoint() {
+    let vfs = Arc::new(MemFs::new());
+    let mut leader: Db = LogManager::open(vfs.clone(), "leader").unwrap();
+    leader.create_text_index().unwrap();
+    leader.insert("a".to_string(), "1".to_string()).unwrap();
+    leader.insert("b".to_string(), "2".to_string()).unwrap();
+    leader.compact().unwrap();
+    leader.delete(&"a".to_string()).unwrap();
+    leader.insert("c".to_string(), "3".to_string()).unwrap();
+
+    // the table of the compaction and the log since, the state of both
+    let checkpoint = leader.checkpoint_files().unwrap();
+    let names: Vec<&str> = checkpoint.files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!((checkpoint.lsn, names), (4, vec!["data.sst", "log.txt"]));
+
+    let mut follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    follower.create_index("value", |value: &String| Some(value.clone())).unwrap();
+    follower.insert("z".to_string(), "9".to_string()).unwrap();
+    follower.compact().unwrap();
+    follower.install_checkpoint(checkpoint).unwrap();
+    assert_eq!((follower.range(..), follower.lsn()), (leader.range(..), 4));
+    assert_eq!(follower.tombstone_stats().live, 1);
+    assert!(follower.lookup_by_index("value", &"9".to_string()).unwrap().is_empty());
+    let expected = vec![("c".to_string(), "3".to_string())];
+    assert_eq!(follower.lookup_by_index("value", &"3".to_string()).unwrap(), expected);
+    assert_eq!(follower.search_text("3").unwrap(), vec!["c".to_string()]);
+
+    // the writes go on from it, and it all opens again
+    leader.insert("d".to_string(), "4".to_string()).unwrap();
+    let (lsn, record) = leader.wal_since(4).unwrap().remove(0);
+    follower.apply_wal(lsn, &record).unwrap();
+    assert_eq!(follower.wal_since(3).unwrap().len(), 2);
+    drop(follower);
+    let follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    assert_eq!((follower.range(..), follower.lsn()), (leader.range(..), 5));
+    assert!(!follower.needs_snapshot());
+
+    // files that are not a database's are refused before anything changes
+    let mut follower = follower;
+    let bad = ddbb::replication::Checkpoint { lsn: 1, files: vec![("other".to_string(), vec![])] };
+    assert!(matches!(follower.install_checkpoint(bad), Err(Error::InvalidArgument(_))));
+    assert_eq!(follower.lsn(), 5);
+}
+
+#[test]
+fn test_install_interrupted() {
+    let vfs = Arc::new(MemFs::new());
+    let mut leader: Db = LogManager::open(vfs.clone(), "leader").unwrap();
+    leader.insert("a".to_string(), "1".to_string()).unwrap();
+    let mut follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    follower.apply_wal(1, "INSERT old 1").unwrap();
+
+    // the crash comes once the RESYNC record is in, before the new state is
+    vfs.fail_after(3);
+    assert!(follower.install_snapshot(leader.snapshot()).is_err());
+    drop(follower);
+    vfs.power_loss(PowerLoss::DropUnsynced);
+    let mut follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    assert!(follower.needs_snapshot());
+    follower.compact().unwrap();
+    drop(follower);
+    let mut follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+    assert!(follower.needs_snapshot());
+    follower.install_snapshot(leader.snapshot()).unwrap();
+    assert!(!follower.needs_snapshot());
+    assert_eq!(follower.range(..), vec![("a".to_string(), "1".to_string())]);
+
+    // a crash anywhere in the install of a checkpoint leaves the old state, the new one, or a
+    // follower that knows it needs another
+    leader.compact().unwrap();
+    leader.insert("b".to_string(), "2".to_string()).unwrap();
+    for crash_after in 0.. {
+        let vfs = Arc::new(MemFs::new());
+        let mut follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+        follower.insert("old".to_string(), "1".to_string()).unwrap();
+        follower.compact().unwrap();
+        vfs.fail_after(crash_after);
+        let installed = follower.install_checkpoint(leader.checkpoint_files().unwrap()).is_ok();
+        drop(follower);
+        vfs.power_loss(PowerLoss::DropUnsynced);
+        let follower: Db = LogManager::open(vfs.clone(), "follower").unwrap();
+        let state = (follower.range(..), follower.lsn());
+        let new = (leader.range(..), leader.lsn());
+        if installed {
+            assert_eq!(state, new);
+            break;
+        }
+        let old = (vec![("old".to_string(), "1".to_string())], 1);
+        let expected = follower.needs_snapshot() || state == old || state == new;
+        assert!(expected, "crash after {}: {:?}", crash_after, state);
+    }
+}
+
+fn start_leader(options: Options) -> SocketAddr {
+    let db = LogManager::open_with(Arc::new(MemFs::new()), "leader", options).unwrap();
+    let server = Server::bind("127.0.0.1:0", db).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    address
+}
+
+fn everything(client: &mut Client) -> Vec<(String, String)> {
+    client.scan(Keys::Prefix(String::new()), ScanOptions::default()).unwrap().pairs
+}
+
+#[test]
+fn test_follower() {
+    let address = start_leader(Options { replication_backlog: 200, ..Options::default() });
+    let mut leader = Client::connect(address).unwrap();
+    for name in ["a", "b", "c"] {
+        leader.set(name, name).unwrap();
+    }
+
+    // a new follower gets the writes from the first one
+    let vfs = Arc::new(MemFs::new());
+    let db = Arc::new(Mutex::new(LogManager::open(vfs.clone(), "follower").unwrap()));
+    let follower = Follower::start(db.clone(), FollowerOptions::new(&address.to_string()));
+    eventually("the first writes", || db.lock().unwrap().lsn() == 3);
+    leader.del("a").unwrap();
+    eventually("the delete", || db.lock().unwrap().search(&"a".to_string()).is_none());
+    eventually("a heartbeat", || follower.status().leader_lsn == Some(4));
+    let status = follower.status();
+    assert!(status.connected);
+    assert_eq!((status.snapshots, status.last_error, status.lag), (0, None, Some(0)));
+
+    // the leader says how far it is before the writes, a follower that cannot apply them
+    // knows it is behind
+    let held = db.lock().unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert("x".to_string(), "1".to_string()).insert("y".to_string(), "2".to_string());
+    leader.write_batch(&batch).unwrap();
+    eventually("the lag", || follower.status().lag == Some(1));
+    drop(held);
+    eventually("the batch", || follower.status().lag == Some(0));
+    follower.stop();
+
+    // one that missed more than the backlog gets a snapshot
+    for i in 0..50 {
+        leader.set(&format!("key{:02}", i), "value").unwrap();
+    }
+    let follower = Follower::start(db.clone(), FollowerOptions::new(&address.to_string()));
+    eventually("the snapshot", || db.lock().unwrap().lsn() == 55);
+    leader.set("z", "last").unwrap();
+    eventually("the writes after it", || db.lock().unwrap().lsn() == 56);
+    assert_eq!(follower.status().snapshots, 1);
+    follower.stop();
+    assert_eq!(db.lock().unwrap().range(..), everything(&mut leader));
+
+    // and one that restarts goes on from where it was
+    leader.set("after", "restart").unwrap();
+    db.lock().unwrap().shutdown().unwrap();
+    drop(db);
+    let db = Arc::new(Mutex::new(LogManager::open(vfs.clone(), "follower").unwrap()));
+    let follower = Follower::start(db.clone(), FollowerOptions::new(&address.to_string()));
+    eventually("the write it missed", || db.lock().unwrap().lsn() == 57);
+    assert_eq!(follower.status().snapshots, 0);
+    follower.stop();
+}
+
+#[test]
+fn test_follower_server() {
+    let address = start_leader(Options::default());
+    let mut leader = Client::connect(address).unwrap();
+    leader.set("a", "1").unwrap();
+
+    let db = LogManager::open(Arc::new(MemFs::new()), "follower").unwrap();
+    let follow = Some(FollowerOptions::new(&address.to_string()));
+    let server = Server::bind_with("127.0.0.1:0", db, ServerOptions { follow, ..ServerOptions::default() });
+    let server = server.unwrap();
+    let (follower_address, shutdown) = (server.local_addr().unwrap(), server.shutdown_handle());
+    let serving = thread::spawn(move || server.serve());
+
+    let mut follower = Client::connect(follower_address).unwrap();
+    eventually("the first write", || follower.get("a").unwrap().is_some());
+    let refused = follower.set("b", "2");
+    assert!(matches!(refused, Err(Error::PermissionDenied(_))), "{:?}", refused);
+    leader.set("b", "2").unwrap();
+    eventually("the second write", || follower.get("b").unwrap().is_some());
+    let stats = follower.stats().unwrap();
+    assert!(stats.contains(&("lsn".to_string(), "2".to_string())), "{:?}", stats);
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_protocol() {
+    let address = start_leader(Options::default());
+    let mut leader = Client::connect(address).unwrap();
+    leader.set("a", "1").unwrap();
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut line = || {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    };
+
+    writeln!(writer, "REPLICATE 1 2\nREPLICATE x\nREPLICATE").unwrap();
+    assert!(line().starts_with("ERR invalid argument: REPLICATE takes an LSN"));
+    assert_eq!(line(), "ERR invalid argument: bad LSN x");
+    assert_eq!(line(), "OK");
+    // the files of the leader, its log here, which ends with a newline
+    assert_eq!((line(), line()), ("CHECKPOINT 1 1".to_string(), "FILE log.txt 20".to_string()));
+    assert!(line().ends_with(" INSERT a 1"));
+    leader.del("a").unwrap();
+    let shipped = std::iter::repeat_with(&mut line).find(|line| !line.starts_with("LSN ")).unwrap();
+    assert!(shipped.starts_with("WAL 2 DELETE a "), "{}", shipped);
+    writeln!(writer, "GET a").unwrap();
+    let answer = std::iter::repeat_with(&mut line).find(|line| !line.starts_with("LSN ")).unwrap();
+    assert_eq!(answer, "ERR invalid argument: only QUIT is taken while replicating");
+    writeln!(writer, "QUIT").unwrap();
+    assert_eq!(std::iter::repeat_with(&mut line).find(|line| !line.starts_with("LSN ")).unwrap(), "OK");
+    assert_eq!(line(), "");
+}
+
+#[test]
+fn test_max_lag() {
+    // a server, the follower of `leader` if there is one
+    let start = |leader: Option<&str>| {
+        let db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+        let follower = |leader| FollowerOptions { max_lag: Some(0), ..FollowerOptions::new(leader) };
+        let follow = leader.map(follower);
+        let options = ServerOptions { follow, ..ServerOptions::default() };
+        let server = Server::bind_with("127.0.0.1:0", db, options).unwrap();
+        let (address, shutdown) = (server.local_addr().unwrap(), server.shutdown_handle());
+        (address, shutdown, thread::spawn(move || server.serve()))
+    };
+    let (address, leader_shutdown, leader_serving) = start(None);
+    Client::connect(address).unwrap().set("a", "1").unwrap();
+
+    // a follower that keeps up serves reads
+    let (fresh, shutdown, serving) = start(Some(&address.to_string()));
+    let mut follower = Client::connect(fresh).unwrap();
+    eventually("the first write", || matches!(follower.get("a"), Ok(Some(_))));
+    let stats = follower.stats().unwrap();
+    assert!(stats.contains(&("replication_lag".to_string(), "0".to_string())), "{:?}", stats);
+    assert!(stats.contains(&("replication_connected".to_string(), "true".to_string())), "{:?}", stats);
+
+    // one that has no leader refuses them, and a read at ONE goes to the leader instead
+    let nowhere = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (stale, stale_shutdown, stale_serving) = start(Some(&nowhere.to_string()));
+    let refused = Client::connect(stale).unwrap().get("a");
+    let Err(Error::PermissionDenied(message)) = refused else {
+        panic!("{:?}", refused);
+    };
+    assert!(message.ends_with(&nowhere.to_string()), "{}", message);
+    let stats = Client::connect(stale).unwrap().stats().unwrap();
+    assert!(stats.contains(&("replication_lag".to_string(), "none".to_string())), "{:?}", stats);
+    let stale = stale.to_string();
+    let mut coordinator = Coordinator::new(&address.to_string(), &[&stale], CoordinatorOptions::default());
+    assert_eq!(coordinator.get("a", Consistency::One).unwrap().as_deref(), Some("1"));
+    stale_shutdown.shutdown();
+    stale_serving.join().unwrap().unwrap();
+
+    // and so does one whose leader went away
+    leader_shutdown.shutdown();
+    leader_serving.join().unwrap().unwrap();
+    eventually("the refusal", || matches!(follower.get("a"), Err(Error::PermissionDenied(_))));
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use common::key;
+use ddbb::btree::BTree;
+use ddbb::disk_btree::DiskBTree;
+use ddbb::log::LogManager;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::scan::ScanOptions;
+use ddbb::vfs::MemFs;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::ops::Bound;
+use std::sync::Arc;
+
+fn random_bounds(rng: &mut StdRng, max: u32) -> (Bound<u32>, Bound<u32>) {
+    let mut bound = || match rng.gen_range(0..3) {
+        0 => Bound::Included(rng.gen_range(0..max)),
+        1 => Bound::Excluded(rng.gen_range(0..max)),
+        _ => Bound::Unbounded,
+    };
+    (bound(), bound())
+}
+
+fn bytes((lo, hi): (Bound<u32>, Bound<u32>)) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (lo.map(key), hi.map(key))
+}
+
+#[test]
+fn test_btree_visit_range_rev() {
+    let mut tree = BTree::<u32, u32>::new();
+    let mut keys: Vec<u32> = (0..2000).map(|i| i * 3).collect();
+    let mut rng = StdRng::seed_from_u64(4);
+    keys.shuffle(&mut rng);
+    for key in keys {
+        tree.insert(key, key + 1);
+    }
+
+    for _ in 0..300 {
+        let range = random_bounds(&mut rng, 6100);
+        let mut expected = tree.range(&range);
+        expected.reverse();
+        let mut found = Vec::new();
+        tree.visit_range_rev(&range, |key, value| {
+            found.push((*key, *value));
+            true
+        });
+        assert_eq!(found, expected, "{:?}", range);
+    }
+
+    // stops when told to
+    let mut first = Vec::new();
+    tree.visit_range_rev(&(..100), |key, _| {
+        first.push(*key);
+        first.len() < 3
+    });
+    assert_eq!(first, [99, 96, 93]);
+}
+
+#[test]
+fn test_log_manager_scan_rev() {
+    let mut db: LogManager<u64, String> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    for i in 1..=50u64 {
+        db.insert(i * 10, format!("event{}", i)).unwrap();
+    }
+
+    // newest first, a page at a time
+    let mut options = ScanOptions { limit: Some(8), ..ScanOptions::default() };
+    let mut seen = Vec::new();
+    loop {
+        let page = db.scan_rev(100..=400, &options).unwrap();
+        assert!(page.pairs.windows(2).all(|pair| pair[0].0 > pair[1].0));
+        seen.extend(page.pairs.into_iter().map(|(key, _)| key));
+        match page.next {
+            Some(token) => options.resume = Some(token),
+            None => break,
+        }
+    }
+    assert_eq!(seen, (10..=40).rev().map(|i| i * 10).collect::<Vec<_>>());
+
+    let page = db.scan_rev(.., &ScanOptions { limit: Some(2), offset: 1, resume: None }).unwrap();
+    assert_eq!(page.pairs, [(490, "event49".to_string()), (480, "event48".to_string())]);
+    assert!(db.scan_rev(..10, &ScanOptions::default()).unwrap().pairs.is_empty());
+}
+
+#[test]
+fn test_disk_btree_range_rev() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = DiskBTree::open_with_cache(vfs, "db/data.ddbb", 8).unwrap();
+    let mut rng = StdRng::seed_from_u64(9);
+    let mut ids: Vec<u32> = (0..3000).collect();
+    ids.shuffle(&mut rng);
+    for &i in &ids {
+        tree.insert(&key(i * 2), &i.to_le_bytes()).unwrap();
+    }
+    tree.commit().unwrap();
+
+    for _ in 0..100 {
+        let range = bytes(random_bounds(&mut rng, 6100));
+        let mut expected = tree.range(range.clone()).unwrap();
+        expected.reverse();
+        assert_eq!(tree.range_rev(range.clone()).unwrap(), expected, "{:?}", range);
+    }
+    assert_eq!(tree.range_rev(..).unwrap().first().map(|(k, _)| k.clone()), Some(key(5998)));
+}
+
+#[test]
+fn test_lsm_range_rev() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { write_buffer_size: 1024, ..Options::default() };
+    let mut tree = LsmTree::open_with(vfs, "db", options).unwrap();
+    for i in 0..500 {
+        tree.insert(&key(i), b"v").unwrap();
+    }
+    for i in (0..500).step_by(3) {
+        tree.delete(&key(i)).unwrap();
+    }
+    let mut expected = tree.range(key(100)..key(200)).unwrap();
+    expected.reverse();
+    assert_eq!(tree.range_rev(key(100)..key(200)).unwrap(), expected);
+    assert_eq!(tree.range_rev(..).unwrap()[0].0, key(499));
+}
+
+#[test]
+fn test_lsm_range_rev_merges_runs() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { write_buffer_size: 2048, ..Options::default() };
+    let mut tree = LsmTree::open_with(vfs, "db", options).unwrap();
+    let mut rng = StdRng::seed_from_u64(18);
+    // versions of the same keys end up in the memtable and in several runs and levels
+    for round in 0..4 {
+        let mut keys: Vec<u32> = (0..600).collect();
+        keys.shuffle(&mut rng);
+        for i in keys {
+            match rng.gen_range(0..4) {
+                0 => tree.delete(&key(i)).unwrap(),
+                _ => tree.insert(&key(i), format!("v{}", round).as_bytes()).unwrap(),
+            }
+        }
+        if round == 2 {
+            tree.flush().unwrap();
+        }
+    }
+    assert!(tree.level_runs().iter().sum::<usize>() > 1);
+
+    for _ in 0..100 {
+        let range = bytes(random_bounds(&mut rng, 620));
+        let mut expected = tree.range(range.clone()).unwrap();
+        expected.reverse();
+        assert_eq!(tree.range_rev(range.clone()).unwrap(), expected, "{:?}", range);
+        let limit = rng.gen_range(1..20);
+        let limited = tree.range_rev_limit(range.clone(), limit).unwrap();
+        assert_eq!(limited, expected[..limit.min(expected.len())], "{:?}", range);
+    }
+}
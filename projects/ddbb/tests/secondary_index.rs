@@ -0,0 +1,148 @@
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::options::Options;
+use ddbb::vfs::{MemFs, PowerLoss};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, PartialEq)]
+struct User {
+    city: String,
+    age: u32,
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.city, self.age)
+    }
+}
+
+impl FromStr for User {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (city, age) = s.split_once(',').ok_or("no comma")?;
+        Ok(User { city: city.to_string(), age: age.parse().map_err(|_| "bad age")? })
+    }
+}
+
+fn user(city: &str, age: u32) -> User {
+    User { city: city.to_string(), age }
+}
+
+type Users = LogManager<String, User>;
+
+fn declare_indexes(users: &mut Users) {
+    users.create_index("city", |user: &User| Some(user.city.clone())).unwrap();
+    // minors are left out of this one
+    users.create_index("adult_age", |user: &User| (user.age >= 18).then_some(user.age)).unwrap();
+}
+
+fn keys(pairs: Vec<(String, User)>) -> Vec<String> {
+    pairs.into_iter().map(|(key, _)| key).collect()
+}
+
+#[test]
+fn test_index_follows_writes() {
+    let vfs = Arc::new(MemFs::new());
+    let mut users: Users = LogManager::open(vfs.clone(), "db").unwrap();
+    users.insert("alice".to_string(), user("Oslo", 30)).unwrap();
+    declare_indexes(&mut users);
+    users.insert("carol".to_string(), user("Lima", 17)).unwrap();
+    users.insert("bob".to_string(), user("Oslo", 30)).unwrap();
+
+    let oslo = users.lookup_by_index("city", &"Oslo".to_string()).unwrap();
+    assert_eq!(oslo, vec![("alice".to_string(), user("Oslo", 30)), ("bob".to_string(), user("Oslo", 30))]);
+    assert_eq!(keys(users.lookup_by_index("adult_age", &30u32).unwrap()), ["alice", "bob"]);
+    assert!(users.lookup_by_index("adult_age", &17u32).unwrap().is_empty());
+
+    // an overwrite moves the key, a delete removes it
+    users.insert("alice".to_string(), user("Lima", 31)).unwrap();
+    users.delete(&"bob".to_string()).unwrap();
+    assert!(users.lookup_by_index("city", &"Oslo".to_string()).unwrap().is_empty());
+    assert_eq!(keys(users.lookup_by_index("city", &"Lima".to_string()).unwrap()), ["alice", "carol"]);
+    assert!(users.lookup_by_index("adult_age", &30u32).unwrap().is_empty());
+    assert_eq!(keys(users.lookup_by_index("adult_age", &31u32).unwrap()), ["alice"]);
+
+    users.drop_index("adult_age").unwrap();
+    assert!(matches!(users.lookup_by_index("adult_age", &31u32), Err(Error::InvalidArgument(_))));
+    assert!(matches!(users.drop_index("adult_age"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(users.create_index("city", |_: &User| Some(0u8)), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_index_rebuilt_after_crash() {
+    let vfs = Arc::new(MemFs::new());
+    let options = Options { write_buffer_size: 300, ..Options::default() };
+    let mut users: Users = LogManager::open_with(vfs.clone(), "db", options.clone()).unwrap();
+    declare_indexes(&mut users);
+    let cities = ["Oslo", "Lima", "Pune"];
+    for i in 0..40u32 {
+        users.insert(format!("user{:02}", i), user(cities[i as usize % 3], 10 + i)).unwrap();
+    }
+    for i in (0..40).step_by(4) {
+        users.delete(&format!("user{:02}", i)).unwrap();
+    }
+    // some of it in the snapshot, the rest in the log, and the last writes lost
+    assert!(users.flush_stats().flushes > 0);
+    drop(users);
+    vfs.power_loss(PowerLoss::TruncateUnsynced { seed: 3 });
+
+    let mut users: Users = LogManager::open_with(vfs.clone(), "db", options).unwrap();
+    declare_indexes(&mut users);
+    for city in cities {
+        let indexed = users.lookup_by_index("city", &city.to_string()).unwrap();
+        let mut expected = Vec::new();
+        for i in 0..40u32 {
+            let key = format!("user{:02}", i);
+            if let Some(value) = users.search(&key).filter(|value| value.city == city) {
+                expected.push((key, value));
+            }
+        }
+        assert_eq!(indexed, expected);
+    }
+}
+
+#[test]
+fn test_unique_index_rejects_duplicates() {
+    let vfs = Arc::new(MemFs::new());
+    let mut users: Users = LogManager::open(vfs.clone(), "db").unwrap();
+    users.insert("alice".to_string(), user("Oslo", 30)).unwrap();
+    users.insert("bob".to_string(), user("Oslo", 30)).unwrap();
+    // the pairs already break it
+    let result = users.create_unique_index("city", |user: &User| Some(user.city.clone()));
+    let violation = |index: &str, existing: &str| Error::UniqueViolation {
+        index: index.to_string(),
+        existing: existing.to_string(),
+    }
+    .to_string();
+    assert_eq!(result.unwrap_err().to_string(), violation("city", "alice"));
+    assert!(matches!(users.lookup_by_index("city", &"Oslo".to_string()), Err(Error::InvalidArgument(_))));
+
+    users.insert("bob".to_string(), user("Lima", 40)).unwrap();
+    users.create_unique_index("city", |user: &User| Some(user.city.clone())).unwrap();
+    users.create_index("age", |user: &User| Some(user.age)).unwrap();
+
+    let result = users.insert("carol".to_string(), user("Oslo", 50));
+    assert_eq!(result.unwrap_err().to_string(), violation("city", "alice"));
+    // nothing of the failed insert is left, in the tree, the other index or the log
+    assert_eq!(users.search(&"carol".to_string()), None);
+    assert!(users.lookup_by_index("age", &50u32).unwrap().is_empty());
+
+    // a key may keep its own index key, or move to a free one, which frees the old one
+    users.insert("alice".to_string(), user("Oslo", 31)).unwrap();
+    users.insert("alice".to_string(), user("Pune", 31)).unwrap();
+    users.insert("carol".to_string(), user("Oslo", 50)).unwrap();
+    users.delete(&"bob".to_string()).unwrap();
+    users.insert("dave".to_string(), user("Lima", 20)).unwrap();
+    assert!(matches!(users.insert("erin".to_string(), user("Pune", 20)), Err(Error::UniqueViolation { .. })));
+    drop(users);
+
+    let mut users: Users = LogManager::open(vfs, "db").unwrap();
+    users.create_unique_index("city", |user: &User| Some(user.city.clone())).unwrap();
+    assert_eq!(keys(users.lookup_by_index("city", &"Oslo".to_string()).unwrap()), ["carol"]);
+    assert_eq!(keys(users.lookup_by_index("city", &"Pune".to_string()).unwrap()), ["alice"]);
+    assert_eq!(keys(users.lookup_by_index("city", &"Lima".to_string()).unwrap()), ["dave"]);
+    assert_eq!(users.search(&"erin".to_string()), None);
+}
@@ -0,0 +1,190 @@
+mod common;
+
+use common::start_server;
+use ddbb::http::HttpServer;
+use ddbb::log::LogManager;
+use ddbb::server::{Server, MAX_LINE};
+use ddbb::vfs::MemFs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(address: SocketAddr) -> Self {
+        let stream = TcpStream::connect(address).unwrap();
+        Connection { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream }
+    }
+
+    fn send(&mut self, request: &str) {
+        self.writer.write_all(request.as_bytes()).unwrap();
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line
+    }
+
+    fn request(&mut self, request: &str) -> String {
+        self.send(&format!("{}\n", request));
+        self.line()
+    }
+}
+
+#[test]
+fn test_protocol() {
+    let mut conn = Connection::open(start_server());
+    assert_eq!(conn.request("SET user:1 ann"), "OK\n");
+    assert_eq!(conn.request("set user:2 bo"), "OK\n");
+    assert_eq!(conn.request("SET team:1 red\r"), "OK\n");
+    assert_eq!(conn.request("GET user:1"), "VALUE ann\n");
+    assert_eq!(conn.request("GET nobody"), "NOT_FOUND\n");
+    assert_eq!(conn.request("COUNT user*"), "COUNT 2\n");
+
+    assert_eq!(conn.request("SCAN user*"), "PAIRS 2\n");
+    assert_eq!((conn.line(), conn.line()), ("user:1 ann\n".to_string(), "user:2 bo\n".to_string()));
+    let header = conn.request("SCAN .. LIMIT 1");
+    let token = header.strip_prefix("PAIRS 1 NEXT ").unwrap().trim_end().to_string();
+    assert_eq!(conn.line(), "team:1 red\n");
+    assert_eq!(conn.request(&format!("SCAN .. LIMIT 5 AFTER {}", token)), "PAIRS 2\n");
+    assert_eq!((conn.line(), conn.line()), ("user:1 ann\n".to_string(), "user:2 bo\n".to_string()));
+
+    // a failed request leaves the connection usable
+    assert!(conn.request("FETCH a").starts_with("ERR invalid argument: unknown command FETCH"));
+    assert!(conn.request("").starts_with("ERR "));
+    assert_eq!(conn.request("DEL user:1"), "OK\n");
+    assert_eq!(conn.request("GET user:1"), "NOT_FOUND\n");
+
+    assert_eq!(conn.request("QUIT"), "OK\n");
+    assert_eq!(conn.line(), "", "the server closed the connection");
+}
+
+#[test]
+fn test_bad_lines_close_the_connection() {
+    let address = start_server();
+    let mut conn = Connection::open(address);
+    // no more than the server reads, unread data would make it reset the connection
+    conn.send(&"x".repeat(MAX_LINE + 1));
+    assert!(conn.line().starts_with("ERR "));
+    assert_eq!(conn.line(), "");
+
+    let mut conn = Connection::open(address);
+    conn.writer.write_all(b"GET \xff\xfe\n").unwrap();
+    assert!(conn.line().starts_with("ERR "));
+    assert_eq!(conn.line(), "");
+}
+
+#[test]
+fn test_concurrent_connections() {
+    let address = start_server();
+    let writers: Vec<_> = (0..8)
+        .map(|client| {
+            thread::spawn(move || {
+                let mut conn = Connection::open(address);
+                for i in 0..50 {
+                    assert_eq!(conn.request(&format!("SET c{}:{:03} {}", client, i, i)), "OK\n");
+                }
+                assert_eq!(conn.request(&format!("COUNT c{}:*", client)), "COUNT 50\n");
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    // every connection sees the writes of the others
+    let mut conn = Connection::open(address);
+    assert_eq!(conn.request("COUNT *"), "COUNT 400\n");
+    assert_eq!(conn.request("GET c7:049"), "VALUE 49\n");
+}
+
+#[test]
+fn test_pipelining() {
+    let mut conn = Connection::open(start_server());
+    let requests: String = (0..200).map(|i| format!("SET k{:03} {}\n", i, i)).collect();
+    conn.send(&format!("{}GET k007\nFETCH a\nCOUNT k*\n", requests));
+    for _ in 0..200 {
+        assert_eq!(conn.line(), "OK\n");
+    }
+    assert_eq!(conn.line(), "VALUE 7\n");
+    assert!(conn.line().starts_with("ERR "));
+    assert_eq!(conn.line(), "COUNT 200\n");
+}
+
+#[test]
+fn test_multi() {
+    let mut conn = Connection::open(start_server());
+    assert_eq!(conn.request("SET a 1"), "OK\n");
+    assert_eq!(conn.request("MULTI"), "OK\n");
+    assert!(conn.request("MULTI").starts_with("ERR "));
+    assert_eq!(conn.request("SET b 2"), "QUEUED\n");
+    assert_eq!(conn.request("del a"), "QUEUED\n");
+    // nothing is applied before EXEC
+    let mut other = Connection::open(conn.writer.peer_addr().unwrap());
+    assert_eq!(other.request("GET a"), "VALUE 1\n");
+    assert_eq!(conn.request("EXEC"), "OK\n");
+    assert_eq!(other.request("GET a"), "NOT_FOUND\n");
+    assert_eq!(other.request("GET b"), "VALUE 2\n");
+
+    // a request that fails in there fails the EXEC, and nothing is applied
+    conn.send("MULTI\nSET c 3\nGET b\nSET d\nEXEC\n");
+    assert_eq!((conn.line(), conn.line()), ("OK\n".to_string(), "QUEUED\n".to_string()));
+    assert!(conn.line().starts_with("ERR invalid argument: only SET and DEL"));
+    assert!(conn.line().starts_with("ERR "));
+    assert!(conn.line().starts_with("ERR "));
+    assert_eq!(conn.request("GET c"), "NOT_FOUND\n");
+
+    assert_eq!(conn.request("MULTI"), "OK\n");
+    assert_eq!(conn.request("SET c 3"), "QUEUED\n");
+    assert_eq!(conn.request("DISCARD"), "OK\n");
+    assert!(conn.request("EXEC").starts_with("ERR invalid argument: EXEC without MULTI"));
+    assert!(conn.request("DISCARD").starts_with("ERR "));
+    assert_eq!(conn.request("GET c"), "NOT_FOUND\n");
+}
+
+#[test]
+fn test_shutdown() {
+    let vfs = Arc::new(MemFs::new());
+    let server = Server::bind("127.0.0.1:0", LogManager::open(vfs.clone(), "db").unwrap()).unwrap();
+    let (address, shutdown) = (server.local_addr().unwrap(), server.shutdown_handle());
+    let serving = thread::spawn(move || server.serve());
+
+    let mut idle = Connection::open(address);
+    let mut subscriber = Connection::open(address);
+    assert_eq!(subscriber.request("SUBSCRIBE"), "OK\n");
+    let mut busy = Connection::open(address);
+    assert_eq!(busy.request("SET a 1"), "OK\n");
+    // requests that were sent before the shutdown are answered
+    busy.send("SET b 2\nSET c 3\n");
+    thread::sleep(std::time::Duration::from_millis(100));
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+    assert_eq!(busy.line(), "OK\n");
+    assert_eq!(busy.line(), "OK\n");
+    assert_eq!(busy.line(), "");
+    assert_eq!(idle.line(), "");
+    assert_eq!(subscriber.line(), "EVENT SET a 1\n");
+    assert_eq!(subscriber.line(), "EVENT SET b 2\n");
+    assert_eq!(subscriber.line(), "EVENT SET c 3\n");
+    assert_eq!(subscriber.line(), "");
+    assert!(TcpStream::connect(address).is_err());
+
+    // the database was shut down cleanly: unlocked, and compacted
+    let db = LogManager::<String, String>::open(vfs.clone(), "db").unwrap();
+    assert_eq!(db.recovery_report().checkpoint, Some(1));
+    assert_eq!(db.range(..).len(), 3);
+    drop(db);
+
+    // the same for HTTP, shut down before it even started
+    let server = HttpServer::bind("127.0.0.1:0", LogManager::open(vfs.clone(), "db").unwrap()).unwrap();
+    server.shutdown_handle().shutdown();
+    server.serve().unwrap();
+    let db = LogManager::<String, String>::open(vfs, "db").unwrap();
+    assert_eq!(db.recovery_report().checkpoint, Some(2));
+}
@@ -0,0 +1,495 @@
+use ddbb::batch::WriteBatch;
+use ddbb::client::{Client, ClientOptions};
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::options::Options;
+use ddbb::query::{self, Keys, QueryResult};
+use ddbb::scan::ScanOptions;
+use ddbb::server::{Server, ShutdownHandle};
+use ddbb::shard::{Ring, Shard, ShardedStore, StoreOptions};
+use ddbb::vfs::{MemFs, PowerLoss};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+fn local(name: &str) -> (String, Shard) {
+    (name.to_string(), Shard::local(LogManager::open(Arc::new(MemFs::new()), name).unwrap()))
+}
+
+fn remote(name: &str) -> (String, Shard) {
+    let db = LogManager::open(Arc::new(MemFs::new()), name).unwrap();
+    let server = Server::bind("127.0.0.1:0", db).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    (name.to_string(), Shard::remote(address, ClientOptions::default()).unwrap())
+}
+
+// A server that can be stopped and started again, with its data
+struct Stoppable {
+    vfs: Arc<MemFs>,
+    address: String,
+    running: Option<(ShutdownHandle, JoinHandle<ddbb::error::Result<()>>)>,
+}
+
+impl Stoppable {
+    fn start(&mut self) {
+        let db = LogManager::open(self.vfs.clone(), "db").unwrap();
+        let server = Server::bind(self.address.as_str(), db).unwrap();
+        self.address = server.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        self.running = Some((shutdown, thread::spawn(move || server.serve())));
+    }
+
+    fn stop(&mut self) {
+        let (shutdown, serving) = self.running.take().expect("the server runs");
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+    }
+
+    fn started() -> Self {
+        let vfs = Arc::new(MemFs::new());
+        let mut server = Stoppable { vfs, address: "127.0.0.1:0".to_string(), running: None };
+        server.start();
+        server
+    }
+
+    // A shard on the server that gives up at once when it is down
+    fn shard(&self) -> Shard {
+        let options = ClientOptions { retries: 0, ..ClientOptions::default() };
+        Shard::remote(self.address.as_str(), options).unwrap()
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        Client::connect(self.address.as_str()).unwrap().get(key).unwrap()
+    }
+}
+
+fn keys(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("key{:04}", i)).collect()
+}
+
+#[test]
+fn test_ring() {
+    let ring = Ring::new(&["a", "b", "c", "d"]);
+    let mut owned = [0; 4];
+    for key in keys(4000) {
+        owned[ring.owner(&key)] += 1;
+    }
+    // about a quarter each
+    assert!(owned.iter().all(|&count| (600..1400).contains(&count)), "{:?}", owned);
+
+    // the names place the shards, not their order
+    let other = Ring::new(&["d", "c", "b", "a"]);
+    assert!(keys(1000).iter().all(|key| ring.owner(key) == 3 - other.owner(key)));
+
+    // a fifth shard only takes keys, about a fifth of them
+    let grown = Ring::new(&["a", "b", "c", "d", "e"]);
+    let mut moved = 0;
+    for key in keys(4000) {
+        let owner = grown.owner(&key);
+        if owner != ring.owner(&key) {
+            assert_eq!(owner, 4, "{} moved between old shards", key);
+            moved += 1;
+        }
+    }
+    assert!((500..1100).contains(&moved), "{}", moved);
+}
+
+#[test]
+fn test_routing() {
+    let mut store = ShardedStore::new(vec![local("a"), remote("b"), local("c")]).unwrap();
+    assert_eq!(store.shard_names(), vec!["a", "b", "c"]);
+    for key in keys(300) {
+        store.set(&key, &format!("value-of-{}", key)).unwrap();
+    }
+    store.del("key0007").unwrap();
+    assert_eq!(store.get("key0007").unwrap(), None);
+    assert_eq!(store.get("key0123").unwrap(), Some("value-of-key0123".to_string()));
+    assert_eq!(store.count(Keys::Prefix("key".to_string())).unwrap(), 299);
+    assert_eq!(store.count(Keys::Prefix("key001".to_string())).unwrap(), 10);
+
+    // every key is on its shard only
+    let owner = store.owner("key0123").to_string();
+    let mut alone = ShardedStore::new(vec![remote(&owner)]).unwrap();
+    assert_eq!(alone.get("key0123").unwrap(), None);
+
+    // queries go through the same way
+    let result = store.query(&query::parse("GET key0042").unwrap()).unwrap();
+    assert_eq!(result, QueryResult::Value(Some("value-of-key0042".to_string())));
+
+    let empty = ShardedStore::new(vec![]);
+    assert!(matches!(empty, Err(Error::InvalidArgument(_))));
+    let twice = ShardedStore::new(vec![local("a"), local("a")]);
+    assert!(matches!(twice, Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_scan() {
+    let mut store = ShardedStore::new(vec![local("a"), remote("b"), local("c"), local("d")]).unwrap();
+    let mut expected = BTreeMap::new();
+    for (i, key) in keys(250).into_iter().enumerate() {
+        store.set(&key, &i.to_string()).unwrap();
+        expected.insert(key, i.to_string());
+    }
+    store.set("other", "x").unwrap();
+    let prefix = || Keys::Prefix("key".to_string());
+
+    // the pages of every shard merge in key order, and a token goes on from the last key
+    let mut seen = Vec::new();
+    let mut resume = None;
+    loop {
+        let options = ScanOptions { limit: Some(40), resume, ..ScanOptions::default() };
+        let page = store.scan(prefix(), options).unwrap();
+        assert!(page.pairs.len() <= 40);
+        seen.extend(page.pairs);
+        match page.next {
+            Some(next) => resume = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(seen, expected.clone().into_iter().collect::<Vec<_>>());
+
+    // and backwards
+    let mut seen = Vec::new();
+    let mut resume = None;
+    loop {
+        let options = ScanOptions { limit: Some(33), resume, ..ScanOptions::default() };
+        let page = store.scan_rev(prefix(), options).unwrap();
+        seen.extend(page.pairs);
+        match page.next {
+            Some(next) => resume = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(seen, expected.clone().into_iter().rev().collect::<Vec<_>>());
+
+    // an offset counts over all the shards
+    let options = ScanOptions { limit: Some(5), offset: 100, ..ScanOptions::default() };
+    let page = store.scan(prefix(), options).unwrap();
+    assert_eq!(page.pairs, expected.clone().into_iter().skip(100).take(5).collect::<Vec<_>>());
+    let range = Keys::Range { start: Some("key0010".to_string()), end: Some("key0013".to_string()) };
+    assert_eq!(store.scan(range, ScanOptions::default()).unwrap().pairs.len(), 3);
+}
+
+#[test]
+fn test_add_shard() {
+    let mut store = ShardedStore::new(vec![local("a"), local("b"), remote("c"), local("d")]).unwrap();
+    for key in keys(2000) {
+        store.set(&key, &key).unwrap();
+    }
+    let before: Vec<String> = keys(2000).iter().map(|key| store.owner(key).to_string()).collect();
+
+    let moved = store.add_shard("e", remote("e").1).unwrap();
+    assert!((250..550).contains(&moved), "{}", moved);
+    let mut changed = 0;
+    for (key, old) in keys(2000).iter().zip(&before) {
+        let owner = store.owner(key);
+        assert!(owner == old || owner == "e", "{} moved from {} to {}", key, old, owner);
+        changed += (owner != old) as usize;
+        assert_eq!(store.get(key).unwrap().as_deref(), Some(key.as_str()));
+    }
+    assert_eq!(changed, moved);
+    // moved, not copied
+    assert_eq!(store.count(Keys::Prefix(String::new())).unwrap(), 2000);
+
+    // the new shard takes its writes, and is there once
+    let added = keys(2000).into_iter().find(|key| store.owner(key) == "e").unwrap();
+    assert!(store.shard_names().contains(&"e"));
+    let again = store.add_shard("e", local("e").1);
+    assert!(matches!(again, Err(Error::InvalidArgument(_))), "{:?}", again);
+    store.set(&added, "new").unwrap();
+    assert_eq!(store.get(&added).unwrap().as_deref(), Some("new"));
+}
+
+#[test]
+fn test_replicas() {
+    let names = ["a", "b", "c", "d"];
+    let dbs = names.map(|name| Arc::new(Mutex::new(LogManager::open(Arc::new(MemFs::new()), name).unwrap())));
+    let shard = |i: usize| (names[i].to_string(), Shard::Local(dbs[i].clone()));
+    let options = StoreOptions { replicas: 2, ..StoreOptions::default() };
+    let mut store = ShardedStore::with_options(vec![shard(0), shard(1), shard(2)], options).unwrap();
+    for key in keys(200) {
+        store.set(&key, &key).unwrap();
+    }
+    store.del("key0005").unwrap();
+
+    // every key is on its two replicas, the owner first, and nowhere else
+    let on = |key: &str| -> Vec<&str> {
+        let key = key.to_string();
+        let has = |db: &Mutex<LogManager<String, String>>| db.lock().unwrap().search(&key).is_some();
+        names.into_iter().zip(&dbs).filter(|(_, db)| has(db)).map(|(name, _)| name).collect()
+    };
+    let placed = |store: &ShardedStore| {
+        for key in keys(200).iter().filter(|key| *key != "key0005") {
+            let mut replicas = store.replicas(key);
+            assert_eq!(replicas.len(), 2);
+            assert_eq!(replicas[0], store.owner(key));
+            replicas.sort();
+            assert_eq!(on(key), replicas, "{}", key);
+        }
+    };
+    placed(&store);
+    assert!(on("key0005").is_empty());
+
+    // and the reads see every key once
+    assert_eq!(store.get("key0100").unwrap().as_deref(), Some("key0100"));
+    assert_eq!(store.count(Keys::Prefix(String::new())).unwrap(), 199);
+    let options = ScanOptions { limit: Some(10), offset: 20, ..ScanOptions::default() };
+    let page = store.scan(Keys::Prefix(String::new()), options).unwrap();
+    let expected = keys(200).into_iter().filter(|key| key != "key0005").skip(20).take(10);
+    let expected: Vec<String> = expected.collect();
+    assert_eq!(page.pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>(), expected);
+
+    // a new shard gets the keys it is now a replica of, and the others keep theirs only
+    let (name, added) = shard(3);
+    assert!(store.add_shard(&name, added).unwrap() > 0);
+    placed(&store);
+    assert_eq!(store.count(Keys::Prefix(String::new())).unwrap(), 199);
+
+    // more replicas than shards is every shard
+    let all = StoreOptions { replicas: 5, ..StoreOptions::default() };
+    let mut store = ShardedStore::with_options(vec![local("x"), local("y")], all).unwrap();
+    assert_eq!(store.replicas("key").len(), 2);
+    store.set("key", "1").unwrap();
+    let none = StoreOptions { replicas: 0, ..StoreOptions::default() };
+    assert!(matches!(ShardedStore::with_options(vec![local("x")], none), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_hints() {
+    let mut down = Stoppable::started();
+    let hints = LogManager::open(Arc::new(MemFs::new()), "hints").unwrap();
+    let hint_retry = Duration::from_millis(50);
+    let options = StoreOptions { replicas: 2, hints: Some(hints), hint_retry, ..StoreOptions::default() };
+    let shards = vec![local("a"), ("b".to_string(), down.shard()), local("c")];
+    let mut store = ShardedStore::with_options(shards, options).unwrap();
+    let on_b: Vec<String> = keys(100).into_iter().filter(|key| store.replicas(key).contains(&"b")).collect();
+    for key in &on_b {
+        store.set(key, "old").unwrap();
+    }
+
+    // while b is down its writes are hints, and its keys are read from their other replica
+    down.stop();
+    for key in &on_b[..20] {
+        store.set(key, "new").unwrap();
+    }
+    store.del(&on_b[20]).unwrap();
+    assert_eq!(store.hints(), 21);
+    assert_eq!(store.get(&on_b[0]).unwrap().as_deref(), Some("new"));
+    assert_eq!(store.get(&on_b[20]).unwrap(), None);
+    assert_eq!(store.count(Keys::Prefix(String::new())).unwrap(), on_b.len() - 1);
+    assert_eq!(store.replay_hints().unwrap(), 0);
+
+    // once it is back it gets them, in order, with the next write after `hint_retry`
+    down.start();
+    assert_eq!(down.get(&on_b[0]).as_deref(), Some("old"));
+    thread::sleep(Duration::from_millis(60));
+    store.set(&on_b[30], "newer").unwrap();
+    assert_eq!(store.hints(), 0);
+    assert_eq!(down.get(&on_b[0]).as_deref(), Some("new"));
+    assert_eq!(down.get(&on_b[20]), None);
+    assert_eq!(down.get(&on_b[30]).as_deref(), Some("newer"));
+
+    // without hints a replica that is down fails the write
+    let mut store = ShardedStore::with_options(
+        vec![local("a"), ("b".to_string(), down.shard())],
+        StoreOptions { replicas: 2, ..StoreOptions::default() },
+    )
+    .unwrap();
+    down.stop();
+    assert!(matches!(store.set("key", "1"), Err(Error::Io(_))));
+}
+
+#[test]
+fn test_repair() {
+    let [a, b]: [Arc<Mutex<LogManager<String, String>>>; 2] =
+        ["a", "b"].map(|name| Arc::new(Mutex::new(LogManager::open(Arc::new(MemFs::new()), name).unwrap())));
+    let c = Stoppable::started();
+    let shards = vec![
+        ("a".to_string(), Shard::Local(a.clone())),
+        ("b".to_string(), Shard::Local(b.clone())),
+        ("c".to_string(), c.shard()),
+    ];
+    let options = StoreOptions { replicas: 2, ..StoreOptions::default() };
+    let mut store = ShardedStore::with_options(shards, options).unwrap();
+    for key in keys(1000) {
+        store.set(&key, &key).unwrap();
+    }
+    // in sync, only the leaves that hold a point of the ring differ
+    let in_sync = store.repair().unwrap();
+    assert_eq!(in_sync.writes, 0);
+    assert!(in_sync.ranges < 3 * 128, "{:?}", in_sync);
+
+    // the replicas that are not the first of their keys drift: a value, a missing pair, one
+    // too many, one of them on the server
+    let second = |owner: &str| {
+        keys(1000).into_iter().find(|key| store.replicas(key)[1] == "b" && store.owner(key) == owner)
+    };
+    let (changed, missing) = (second("a").unwrap(), second("c").unwrap());
+    b.lock().unwrap().insert(changed.clone(), "wrong".to_string()).unwrap();
+    b.lock().unwrap().delete(&missing).unwrap();
+    let extra = (0..).map(|i| format!("extra{}", i)).find(|key| store.replicas(key)[1] == "c").unwrap();
+    Client::connect(c.address.as_str()).unwrap().set(&extra, "1").unwrap();
+
+    let repair = store.repair().unwrap();
+    assert_eq!(repair.writes, 3);
+    assert!(repair.ranges <= in_sync.ranges + 3, "{:?}", repair);
+    assert_eq!(b.lock().unwrap().search(&changed).as_deref(), Some(changed.as_str()));
+    assert_eq!(b.lock().unwrap().search(&missing).as_deref(), Some(missing.as_str()));
+    assert_eq!(c.get(&extra), None);
+    assert_eq!(store.repair().unwrap(), in_sync);
+    assert_eq!(store.count(Keys::Prefix(String::new())).unwrap(), 1000);
+
+    // the first replica of a key wins, as a read would find it
+    a.lock().unwrap().insert(changed.clone(), "first".to_string()).unwrap();
+    assert_eq!(store.get(&changed).unwrap().as_deref(), Some("first"));
+    assert_eq!(store.repair().unwrap().writes, 1);
+    assert_eq!(b.lock().unwrap().search(&changed).as_deref(), Some("first"));
+}
+
+#[test]
+fn test_write_batch() {
+    let [a, b]: [Arc<Mutex<LogManager<String, String>>>; 2] =
+        ["a", "b"].map(|name| Arc::new(Mutex::new(LogManager::open(Arc::new(MemFs::new()), name).unwrap())));
+    let mut c = Stoppable::started();
+    let decisions_vfs = Arc::new(MemFs::new());
+    let store = |c: &Stoppable, decisions: bool| {
+        let shards = vec![
+            ("a".to_string(), Shard::Local(a.clone())),
+            ("b".to_string(), Shard::Local(b.clone())),
+            ("c".to_string(), c.shard()),
+        ];
+        let decisions = decisions.then(|| LogManager::open(decisions_vfs.clone(), "decisions").unwrap());
+        ShardedStore::with_options(shards, StoreOptions { decisions, ..StoreOptions::default() }).unwrap()
+    };
+    let mut sharded = store(&c, true);
+    let on = |owner: &str| -> Vec<String> {
+        keys(1000).into_iter().filter(|key| sharded.owner(key) == owner).collect()
+    };
+    let (on_a, on_b, on_c) = (on("a"), on("b"), on("c"));
+    let mut batch = WriteBatch::new();
+    batch.insert(on_a[0].clone(), "1".to_string()).insert(on_b[0].clone(), "1".to_string());
+    batch.insert(on_c[0].clone(), "1".to_string()).delete(on_a[0].clone());
+
+    // applied on every shard, and nothing is left prepared or decided
+    sharded.write_batch(&batch).unwrap();
+    assert_eq!(a.lock().unwrap().search(&on_a[0]), None);
+    assert_eq!(b.lock().unwrap().search(&on_b[0]).as_deref(), Some("1"));
+    assert_eq!(c.get(&on_c[0]).as_deref(), Some("1"));
+    assert!(a.lock().unwrap().prepared().is_empty() && b.lock().unwrap().prepared().is_empty());
+    assert!(Client::connect(c.address.as_str()).unwrap().prepared().unwrap().is_empty());
+    assert_eq!(sharded.resolve().unwrap(), 0);
+
+    // a shard that is down fails the batch, and the others abort it
+    c.stop();
+    let mut batch = WriteBatch::new();
+    batch.insert(on_a[1].clone(), "2".to_string()).insert(on_c[1].clone(), "2".to_string());
+    assert!(matches!(sharded.write_batch(&batch), Err(Error::Io(_))));
+    assert_eq!(a.lock().unwrap().search(&on_a[1]), None);
+    assert!(a.lock().unwrap().prepared().is_empty());
+    // which is left to `resolve` for the shard that could not be told
+    c.start();
+    assert_eq!(sharded.resolve().unwrap(), 1);
+    assert_eq!(c.get(&on_c[1]), None);
+    drop(sharded);
+
+    // without decisions a batch may only write one shard
+    let mut plain = store(&c, false);
+    let mut one = WriteBatch::new();
+    one.insert(on_b[2].clone(), "3".to_string()).insert(on_b[3].clone(), "3".to_string());
+    plain.write_batch(&one).unwrap();
+    assert_eq!(b.lock().unwrap().search(&on_b[3]).as_deref(), Some("3"));
+    assert!(matches!(plain.write_batch(&batch), Err(Error::InvalidArgument(_))));
+    drop(plain);
+
+    // a coordinator that crashed between the phases: its decisions are carried out by the next
+    let mut committed = WriteBatch::new();
+    committed.insert(on_a[4].clone(), "4".to_string());
+    a.lock().unwrap().prepare("t1", committed).unwrap();
+    let mut on_server = WriteBatch::new();
+    on_server.insert(on_c[4].clone(), "4".to_string());
+    Client::connect(c.address.as_str()).unwrap().prepare("t1", &on_server).unwrap();
+    let mut undecided = WriteBatch::new();
+    undecided.insert(on_b[4].clone(), "4".to_string());
+    b.lock().unwrap().prepare("t2", undecided).unwrap();
+    let mut decisions = LogManager::open(decisions_vfs.clone(), "decisions").unwrap();
+    decisions.insert("t1".to_string(), "commit".to_string()).unwrap();
+    decisions.insert("t2".to_string(), "prepare".to_string()).unwrap();
+    drop(decisions);
+
+    let mut sharded = store(&c, true);
+    assert_eq!(a.lock().unwrap().search(&on_a[4]).as_deref(), Some("4"));
+    assert_eq!(c.get(&on_c[4]).as_deref(), Some("4"));
+    assert_eq!(b.lock().unwrap().search(&on_b[4]), None);
+    assert!(b.lock().unwrap().prepared().is_empty());
+    assert!(Client::connect(c.address.as_str()).unwrap().prepared().unwrap().is_empty());
+    assert_eq!(sharded.resolve().unwrap(), 0);
+}
+
+#[test]
+fn test_write_batch_power_loss() {
+    // shards that persist every write, a coordinator that does not on its own
+    let options = Options { write_buffer_max_entries: Some(1), ..Options::default() };
+    let open = |vfs: &Arc<MemFs>, name: &str| {
+        Shard::local(LogManager::open_with(vfs.clone(), name, options.clone()).unwrap())
+    };
+    let store = |va: &Arc<MemFs>, vb: &Arc<MemFs>, vd: &Arc<MemFs>| {
+        let shards = vec![("a".to_string(), open(va, "a")), ("b".to_string(), open(vb, "b"))];
+        let decisions = Some(LogManager::open(vd.clone(), "decisions").unwrap());
+        ShardedStore::with_options(shards, StoreOptions { decisions, ..StoreOptions::default() }).unwrap()
+    };
+    let probe = store(&Arc::new(MemFs::new()), &Arc::new(MemFs::new()), &Arc::new(MemFs::new()));
+    let on = |owner: &str| keys(1000).into_iter().find(|key| probe.owner(key) == owner).unwrap();
+    let (on_a, on_b) = (on("a"), on("b"));
+
+    // the power goes out at every point of the batch on shard b
+    for ops in 0..=8 {
+        let [va, vb, vd] = [(); 3].map(|_| Arc::new(MemFs::new()));
+        let mut sharded = store(&va, &vb, &vd);
+        let mut batch = WriteBatch::new();
+        batch.insert(on_a.clone(), "1".to_string()).insert(on_b.clone(), "1".to_string());
+        vb.fail_after(ops);
+        let written = sharded.write_batch(&batch).is_ok();
+        drop(sharded);
+        for vfs in [&va, &vb, &vd] {
+            vfs.power_loss(PowerLoss::DropUnsynced);
+        }
+
+        // all of the batch or none of it, all of it once it was acknowledged
+        let mut sharded = store(&va, &vb, &vd);
+        let (a, b) = (sharded.get(&on_a).unwrap(), sharded.get(&on_b).unwrap());
+        assert_eq!(a, b, "failing after {} operations", ops);
+        if written {
+            assert_eq!(a.as_deref(), Some("1"), "failing after {} operations", ops);
+        }
+    }
+
+    // the power goes out after a second batch made the end of the first one durable in the
+    // decisions, with shards that only sync what a batch needs
+    let [va, vb, vc, vd] = [(); 4].map(|_| Arc::new(MemFs::new()));
+    let open = |vfs: &Arc<MemFs>, name: &str| Shard::local(LogManager::open(vfs.clone(), name).unwrap());
+    let store = || {
+        let shards = vec![("a", &va), ("b", &vb), ("c", &vc)];
+        let shards = shards.into_iter().map(|(name, vfs)| (name.to_string(), open(vfs, name))).collect();
+        let decisions = Some(LogManager::open(vd.clone(), "decisions").unwrap());
+        ShardedStore::with_options(shards, StoreOptions { decisions, ..StoreOptions::default() }).unwrap()
+    };
+    let mut sharded = store();
+    let on = |owner: &str| keys(1000).into_iter().find(|key| sharded.owner(key) == owner).unwrap();
+    let (on_a, on_b, on_c) = (on("a"), on("b"), on("c"));
+    let mut batch = WriteBatch::new();
+    batch.insert(on_a.clone(), "1".to_string()).insert(on_b.clone(), "1".to_string());
+    sharded.write_batch(&batch).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert(on_a.clone(), "2".to_string()).insert(on_c.clone(), "2".to_string());
+    sharded.write_batch(&batch).unwrap();
+    drop(sharded);
+    for vfs in [&va, &vb, &vc, &vd] {
+        vfs.power_loss(PowerLoss::DropUnsynced);
+    }
+
+    let mut sharded = store();
+    assert_eq!(sharded.get(&on_b).unwrap().as_deref(), Some("1"));
+    assert_eq!(sharded.get(&on_a).unwrap().as_deref(), Some("2"));
+}
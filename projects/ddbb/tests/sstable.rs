@@ -0,0 +1,97 @@
+mod common;
+
+use common::key;
+use ddbb::error::Error;
+use ddbb::sstable::{Table, TableWriter, BLOCK_SIZE};
+use ddbb::vfs::{MemFs, OpenOptions, Vfs};
+use std::path::Path;
+
+const PATH: &str = "db/table.sst";
+
+// keys 0, 2, 4, ... with every tenth one deleted
+fn build(vfs: &MemFs, count: u32) -> u64 {
+    vfs.create_dir_all(Path::new("db")).unwrap();
+    let file = vfs.open(Path::new(PATH), OpenOptions::new().write(true).create(true)).unwrap();
+    let mut writer = TableWriter::new(file);
+    for i in (0..count).map(|i| i * 2) {
+        let value = format!("value{}", i).into_bytes();
+        writer.add(&key(i), (i % 10 != 0).then_some(value.as_slice())).unwrap();
+    }
+    writer.finish().unwrap()
+}
+
+#[test]
+fn test_sstable_point_lookups() {
+    let vfs = MemFs::new();
+    let size = build(&vfs, 5000);
+    assert_eq!(size, vfs.read(Path::new(PATH)).unwrap().len() as u64);
+    assert!(size > 10 * BLOCK_SIZE as u64);
+
+    let table = Table::open(&vfs, PATH).unwrap();
+    assert_eq!(table.len(), 5000);
+    assert_eq!(table.smallest(), key(0).as_slice());
+    assert_eq!(table.largest(), key(9998).as_slice());
+
+    assert_eq!(table.get(&key(4)).unwrap(), Some(Some(b"value4".to_vec())));
+    assert_eq!(table.get(&key(9998)).unwrap(), Some(Some(b"value9998".to_vec())));
+    // a tombstone is found, a missing key is not
+    assert_eq!(table.get(&key(20)).unwrap(), Some(None));
+    assert_eq!(table.get(&key(21)).unwrap(), None);
+    assert_eq!(table.get(b"a").unwrap(), None);
+    assert_eq!(table.get(b"z").unwrap(), None);
+}
+
+#[test]
+fn test_sstable_scans() {
+    let vfs = MemFs::new();
+    build(&vfs, 5000);
+    let table = Table::open(&vfs, PATH).unwrap();
+
+    let all: Vec<_> = table.iter().map(Result::unwrap).collect();
+    assert_eq!(all.len(), 5000);
+    assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+
+    // starting between two keys, inside a block that is not the first one
+    let from: Vec<_> = table.iter_from(&key(5001)).take(2).map(|item| item.unwrap().0).collect();
+    assert_eq!(from, [key(5002), key(5004)]);
+    assert_eq!(table.iter_from(b"z").count(), 0);
+}
+
+#[test]
+fn test_sstable_empty_and_unordered() {
+    let vfs = MemFs::new();
+    build(&vfs, 0);
+    let table = Table::open(&vfs, PATH).unwrap();
+    assert!(table.is_empty());
+    assert_eq!(table.get(b"k").unwrap(), None);
+    assert_eq!(table.iter().count(), 0);
+
+    let file = vfs.open(Path::new("db/other.sst"), OpenOptions::new().write(true).create(true)).unwrap();
+    let mut writer = TableWriter::new(file);
+    writer.add(b"b", Some(b"1")).unwrap();
+    assert!(matches!(writer.add(b"a", Some(b"2")), Err(Error::InvalidArgument(_))));
+    assert!(matches!(writer.add(b"b", Some(b"2")), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_sstable_detects_corruption() {
+    let vfs = MemFs::new();
+    build(&vfs, 5000);
+
+    // damage a byte in the middle of the data blocks
+    let mut data = vfs.read(Path::new(PATH)).unwrap();
+    let needle = b"value5002";
+    let offset = data.windows(needle.len()).position(|w| w == needle).unwrap();
+    data[offset] ^= 0x01;
+    vfs.write(Path::new(PATH), &data).unwrap();
+
+    let table = Table::open(&vfs, PATH).unwrap();
+    assert!(matches!(table.get(&key(5002)), Err(Error::Corruption(_))));
+    // other blocks are still readable
+    assert_eq!(table.get(&key(2)).unwrap(), Some(Some(b"value2".to_vec())));
+    assert!(table.iter().any(|item| matches!(item, Err(Error::Corruption(_)))));
+
+    // a truncated file is rejected when it is opened
+    vfs.write(Path::new(PATH), &data[..data.len() - 1]).unwrap();
+    assert!(matches!(Table::open(&vfs, PATH), Err(Error::Corruption(_))));
+}
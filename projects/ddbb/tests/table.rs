@@ -0,0 +1,87 @@
+use ddbb::error::Error;
+use ddbb::table::{Table, TableStore};
+use ddbb::vfs::MemFs;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Order {
+    item: String,
+    cents: u64,
+}
+
+fn user(name: &str, age: u32) -> User {
+    User { name: name.to_string(), age, tags: vec!["new user".to_string()] }
+}
+
+fn order(item: &str, cents: u64) -> Order {
+    Order { item: item.to_string(), cents }
+}
+
+#[test]
+fn test_get_put_delete() {
+    let vfs = Arc::new(MemFs::new());
+    let mut store: TableStore = TableStore::open(vfs.clone(), "db").unwrap();
+    let users: Table<User> = Table::new("users");
+    let orders: Table<Order, (String, u64)> = Table::new("orders");
+
+    users.put(&mut store, &"ann lee".to_string(), &user("Ann Lee", 31)).unwrap();
+    users.put(&mut store, &"bo".to_string(), &user("Bo", 20)).unwrap();
+    orders.put(&mut store, &("ann lee".to_string(), 2), &order("lamp", 1999)).unwrap();
+    assert_eq!(users.get(&store, &"ann lee".to_string()).unwrap(), Some(user("Ann Lee", 31)));
+    assert_eq!(orders.get(&store, &("ann lee".to_string(), 2)).unwrap(), Some(order("lamp", 1999)));
+    assert_eq!(users.get(&store, &"cy".to_string()).unwrap(), None);
+
+    users.put(&mut store, &"bo".to_string(), &user("Bo", 21)).unwrap();
+    users.delete(&mut store, &"ann lee".to_string()).unwrap();
+    assert_eq!(users.get(&store, &"ann lee".to_string()).unwrap(), None);
+    drop(store);
+
+    // nothing about a table is stored but its records
+    let store: TableStore = TableStore::open(vfs, "db").unwrap();
+    let users: Table<User> = Table::new("users");
+    assert_eq!(users.get(&store, &"bo".to_string()).unwrap(), Some(user("Bo", 21)));
+    assert_eq!(orders.get(&store, &("ann lee".to_string(), 2)).unwrap(), Some(order("lamp", 1999)));
+
+    // the records of a table do not deserialize to another type
+    let wrong: Table<Order> = Table::new("users");
+    assert!(matches!(wrong.get(&store, &"bo".to_string()), Err(Error::Corruption(_))));
+}
+
+#[test]
+fn test_scan() {
+    let mut store: TableStore = TableStore::open(Arc::new(MemFs::new()), "db").unwrap();
+    let users: Table<User> = Table::new("users");
+    let user_tags: Table<String> = Table::new("users2");
+    let orders: Table<Order, (String, u64)> = Table::new("orders");
+
+    for (name, age) in [("cy", 40), ("ann", 31), ("bo", 20)] {
+        users.put(&mut store, &name.to_string(), &user(name, age)).unwrap();
+        user_tags.put(&mut store, &name.to_string(), &"vip".to_string()).unwrap();
+    }
+    // order numbers sort as numbers, not as text
+    for (number, cents) in [(10, 100), (9, 90), (100, 1000)] {
+        orders.put(&mut store, &("ann".to_string(), number), &order("pen", cents)).unwrap();
+    }
+    orders.put(&mut store, &("bo".to_string(), 1), &order("ink", 5)).unwrap();
+
+    let all = users.scan(&store, ..).unwrap();
+    let names: Vec<_> = all.iter().map(|(key, record)| (key.as_str(), record.age)).collect();
+    assert_eq!(names, [("ann", 31), ("bo", 20), ("cy", 40)]);
+    let some = users.scan(&store, "b".to_string().."c".to_string()).unwrap();
+    assert_eq!(some, [("bo".to_string(), user("bo", 20))]);
+    assert_eq!(user_tags.scan(&store, ..).unwrap().len(), 3);
+
+    let of_ann = orders.scan(&store, ("ann".to_string(), 0)..("ann".to_string(), u64::MAX)).unwrap();
+    let numbers: Vec<u64> = of_ann.iter().map(|((_, number), _)| *number).collect();
+    assert_eq!(numbers, [9, 10, 100]);
+    assert_eq!(orders.scan(&store, ..).unwrap().len(), 4);
+    assert!(Table::<Order>::new("none").scan(&store, ..).unwrap().is_empty());
+}
@@ -0,0 +1,89 @@
+use ddbb::error::{Error, Result};
+use ddbb::log::LogManager;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::testing::{ModelTest, Subject};
+use ddbb::vfs::Vfs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+fn test_log_manager_against_model() {
+    let configurations = [
+        Options::default(),
+        Options { write_buffer_size: 300, ..Options::default() },
+        Options {
+            write_buffer_max_entries: Some(7),
+            tombstone_retention: Duration::from_secs(60),
+            ..Options::default()
+        },
+    ];
+    for (seed, options) in configurations.into_iter().enumerate() {
+        let test = ModelTest { seed: seed as u64, options, ..ModelTest::default() };
+        let report = test.run::<LogManager<String, String>>().unwrap();
+        assert!(report.restarts > 5 && report.batches > 50 && report.compactions > 5, "{:?}", report);
+    }
+}
+
+#[test]
+fn test_lsm_against_model() {
+    let options = Options {
+        write_buffer_size: 512,
+        l0_compaction_trigger: 2,
+        level_base_bytes: 2048,
+        level_size_multiplier: 2,
+        target_file_size: 512,
+        ..Options::default()
+    };
+    for seed in 0..3 {
+        let options = options.clone();
+        let test = ModelTest { seed, operations: 2000, keys: 300, options, ..ModelTest::default() };
+        test.run::<LsmTree>().unwrap();
+    }
+}
+
+// A LogManager that drops the deletes once it has flushed, since it was opened
+struct Forgetful(LogManager<String, String>);
+
+impl Subject for Forgetful {
+    fn open(vfs: Arc<dyn Vfs>, dir: &Path, options: &Options) -> Result<Self> {
+        Ok(Forgetful(Subject::open(vfs, dir, options)?))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn del(&mut self, key: &str) -> Result<()> {
+        match self.0.flush_stats().flushes {
+            0 => self.0.del(key),
+            _ => Ok(()),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn range(&mut self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        Subject::range(&mut self.0, start, end)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Subject::flush(&mut self.0)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        Subject::compact(&mut self.0)
+    }
+}
+
+#[test]
+fn test_model_finds_differences() {
+    let result = ModelTest::default().run::<Forgetful>();
+    match result {
+        Err(Error::Corruption(message)) => assert!(message.starts_with("seed 0, operation "), "{}", message),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,90 @@
+use ddbb::error::Error;
+use ddbb::json::Json;
+use ddbb::log::LogManager;
+use ddbb::vfs::MemFs;
+use serde_json::json;
+use std::sync::Arc;
+
+fn keys(list: &[&str]) -> Vec<String> {
+    list.iter().map(|k| k.to_string()).collect()
+}
+
+#[test]
+fn test_search_text() {
+    let mut db: LogManager<String, String> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    db.insert("a".to_string(), "Error:timeout,after,30s".to_string()).unwrap();
+    db.insert("b".to_string(), "timeout".to_string()).unwrap();
+    assert!(matches!(db.search_text("timeout"), Err(Error::InvalidArgument(_))));
+
+    // pairs already there are indexed, later writes keep the index up to date
+    db.create_text_index().unwrap();
+    assert!(matches!(db.create_text_index(), Err(Error::InvalidArgument(_))));
+    db.insert("c".to_string(), "disk-error".to_string()).unwrap();
+    db.insert("d".to_string(), "timer".to_string()).unwrap();
+
+    assert_eq!(db.search_text("error").unwrap(), keys(&["a", "c"]));
+    assert_eq!(db.search_text("ERROR timeout").unwrap(), keys(&["a"]));
+    assert_eq!(db.search_text("time*").unwrap(), keys(&["a", "b", "d"]));
+    assert_eq!(db.search_text("Error: time*").unwrap(), keys(&["a"]));
+    assert_eq!(db.search_text("disk-err*").unwrap(), keys(&["c"]));
+    assert_eq!(db.search_text("timeout-err*").unwrap(), keys(&["a"]));
+    assert_eq!(db.search_text("time").unwrap(), keys(&[]));
+    assert_eq!(db.search_text("error nothing").unwrap(), keys(&[]));
+    for bad in ["", "  ", "*", "-- ,"] {
+        assert!(matches!(db.search_text(bad), Err(Error::InvalidArgument(_))), "{:?}", bad);
+    }
+
+    // an overwritten value loses its old tokens, a deleted one all of them
+    db.insert("a".to_string(), "fine".to_string()).unwrap();
+    db.delete(&"c".to_string()).unwrap();
+    assert_eq!(db.search_text("error").unwrap(), keys(&[]));
+    assert_eq!(db.search_text("fine").unwrap(), keys(&["a"]));
+
+    db.drop_text_index().unwrap();
+    assert!(matches!(db.search_text("fine"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(db.drop_text_index(), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_text_index_survives_restarts() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db: LogManager<u64, String> = LogManager::open(vfs.clone(), "db").unwrap();
+    db.insert(1, "connection-timeout".to_string()).unwrap();
+    db.create_text_index().unwrap();
+    db.insert(2, "read-timeout".to_string()).unwrap();
+    drop(db);
+
+    // rebuilt by the replay of the log, without being created again
+    let mut db: LogManager<u64, String> = LogManager::open(vfs.clone(), "db").unwrap();
+    assert_eq!(db.search_text("timeout").unwrap(), [1, 2]);
+    assert_eq!(db.recovery_report().records_replayed, 2);
+
+    // and of the compacted log
+    db.compact().unwrap();
+    db.insert(3, "write-timeout".to_string()).unwrap();
+    drop(db);
+    let mut db: LogManager<u64, String> = LogManager::open(vfs.clone(), "db").unwrap();
+    assert_eq!(db.search_text("timeout").unwrap(), [1, 2, 3]);
+    assert_eq!(db.search_text("read").unwrap(), [2]);
+
+    // a dropped index stays dropped
+    db.drop_text_index().unwrap();
+    drop(db);
+    let mut db: LogManager<u64, String> = LogManager::open(vfs.clone(), "db").unwrap();
+    assert!(matches!(db.search_text("timeout"), Err(Error::InvalidArgument(_))));
+    db.compact().unwrap();
+    drop(db);
+    let db: LogManager<u64, String> = LogManager::open(vfs, "db").unwrap();
+    assert!(matches!(db.search_text("timeout"), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn test_search_json_text() {
+    let mut db: LogManager<String, Json> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    db.create_text_index().unwrap();
+    db.insert("log1".to_string(), Json(json!({"msg": "Error timeout\nafter retry"}))).unwrap();
+    db.insert("log2".to_string(), Json(json!({"msg": "all good"}))).unwrap();
+    assert_eq!(db.search_text("error timeout after").unwrap(), keys(&["log1"]));
+    assert_eq!(db.search_text("msg").unwrap(), keys(&["log1", "log2"]));
+    assert_eq!(db.search_text("good").unwrap(), keys(&["log2"]));
+}
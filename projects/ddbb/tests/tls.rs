@@ -0,0 +1,113 @@
+mod common;
+
+use common::start_server_with;
+use ddbb::client::{Client, ClientOptions};
+use ddbb::error::Error;
+use ddbb::http::HttpServer;
+use ddbb::log::LogManager;
+use ddbb::server::ServerOptions;
+use ddbb::tls::{ClientTls, ServerTls};
+use ddbb::vfs::MemFs;
+use rcgen::CertifiedKey;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+type Certified = CertifiedKey<rcgen::KeyPair>;
+
+fn certificate() -> Certified {
+    rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap()
+}
+
+fn server_tls(certified: &Certified) -> ServerTls {
+    let key = certified.signing_key.serialize_pem();
+    ServerTls::from_pem(certified.cert.pem().as_bytes(), key.as_bytes()).unwrap()
+}
+
+fn options(tls: ServerTls) -> ServerOptions {
+    ServerOptions { tls: Some(tls), ..ServerOptions::default() }
+}
+
+fn connect(address: SocketAddr, roots: &Certified, name: &str) -> ddbb::error::Result<Client> {
+    let tls = ClientTls::from_pem(roots.cert.pem().as_bytes(), name)?;
+    Client::connect_with(address, ClientOptions { retries: 0, tls: Some(tls), ..ClientOptions::default() })
+}
+
+#[test]
+fn test_client_and_server() {
+    let certified = certificate();
+    let address = start_server_with(options(server_tls(&certified)));
+    let mut client = connect(address, &certified, "localhost").unwrap();
+    client.set("user:1", "ann").unwrap();
+    client.set("user:2", "bo").unwrap();
+    assert_eq!(client.get("user:1").unwrap(), Some("ann".to_string()));
+    assert!(matches!(client.set("a b", "1"), Err(Error::InvalidArgument(_))));
+    drop(client);
+
+    // a new connection sees the same store
+    let mut client = connect(address, &certified, "localhost").unwrap();
+    assert_eq!(client.get("user:2").unwrap(), Some("bo".to_string()));
+}
+
+#[test]
+fn test_refused_connections() {
+    let certified = certificate();
+    let address = start_server_with(options(server_tls(&certified)));
+
+    // a certificate the client does not trust, or one for another name
+    assert!(matches!(connect(address, &certificate(), "localhost"), Err(Error::Io(_))));
+    assert!(matches!(connect(address, &certified, "db.example.com"), Err(Error::Io(_))));
+
+    // a client without TLS gets no answer it can read
+    let no_tls = ClientOptions { retries: 0, ..ClientOptions::default() };
+    let mut plain = Client::connect_with(address, no_tls).unwrap();
+    assert!(plain.get("a").is_err());
+
+    // the server is still there for the right clients
+    assert_eq!(connect(address, &certified, "localhost").unwrap().get("a").unwrap(), None);
+}
+
+#[test]
+fn test_bad_configuration() {
+    let certified = certificate();
+    let (cert, key) = (certified.cert.pem(), certified.signing_key.serialize_pem());
+    let other_key = certificate().signing_key.serialize_pem();
+    let (cert, key, other_key) = (cert.as_str(), key.as_str(), other_key.as_str());
+    for (cert, key) in [("junk", key), (cert, "junk"), (cert, other_key)] {
+        let tls = ServerTls::from_pem(cert.as_bytes(), key.as_bytes());
+        assert!(matches!(tls, Err(Error::InvalidArgument(_))));
+    }
+    assert!(matches!(ClientTls::from_pem(b"", "localhost"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(ClientTls::from_pem(cert.as_bytes(), "not a name"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(ServerTls::from_pem_files("/nonexistent/cert", "/nonexistent/key"), Err(Error::Io(_))));
+}
+
+#[test]
+fn test_https() {
+    let certified = certificate();
+    let db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let server = HttpServer::bind_with("127.0.0.1:0", db, options(server_tls(&certified))).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+    stream.write_all(b"GET /health HTTP/1.0\r\n\r\n").unwrap();
+
+    // the server ends the connection with a close_notify, so this is a clean end of stream
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("{\"status\":\"ok\"}"), "{}", response);
+}
@@ -0,0 +1,90 @@
+use ddbb::log::LogManager;
+use ddbb::options::Options;
+use ddbb::sstable::Table;
+use ddbb::vfs::{MemFs, Vfs};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn log_content(vfs: &MemFs) -> String {
+    String::from_utf8(vfs.read(Path::new("db/log.txt")).unwrap()).unwrap()
+}
+
+// keys in the snapshot table written by the last compaction, pairs and tombstones
+fn snapshot_keys(vfs: &MemFs) -> Vec<String> {
+    let table = Table::open(vfs, "db/data.sst").unwrap();
+    table.iter().map(|item| String::from_utf8(item.unwrap().0).unwrap()).collect()
+}
+
+fn open(vfs: &Arc<MemFs>, retention: Duration) -> LogManager<String, i32> {
+    let options = Options { tombstone_retention: retention, ..Options::default() };
+    LogManager::open_with(vfs.clone(), "db", options).unwrap()
+}
+
+#[test]
+fn test_tombstones_dropped_without_retention() {
+    let vfs = Arc::new(MemFs::new());
+    let mut log_manager = open(&vfs, Duration::ZERO);
+
+    log_manager.insert("a".to_string(), 1).unwrap();
+    log_manager.insert("b".to_string(), 2).unwrap();
+    log_manager.delete(&"a".to_string()).unwrap();
+    assert_eq!(log_manager.tombstone_stats().live, 1);
+
+    log_manager.compact().unwrap();
+    let stats = log_manager.tombstone_stats();
+    assert_eq!(stats.live, 0);
+    assert_eq!(stats.collected_last_compaction, 1);
+    assert_eq!(stats.collected_total, 1);
+    assert!(!log_content(&vfs).contains("DELETE"));
+    assert_eq!(snapshot_keys(&vfs), ["b"]);
+}
+
+#[test]
+fn test_tombstones_survive_compaction_inside_window() {
+    let vfs = Arc::new(MemFs::new());
+    let mut log_manager = open(&vfs, Duration::from_secs(3600));
+
+    log_manager.insert("a".to_string(), 1).unwrap();
+    log_manager.delete(&"a".to_string()).unwrap();
+    log_manager.delete(&"never-existed".to_string()).unwrap();
+    log_manager.compact().unwrap();
+
+    let stats = log_manager.tombstone_stats();
+    assert_eq!(stats.live, 2);
+    assert_eq!(stats.retained_last_compaction, 2);
+    assert_eq!(stats.collected_last_compaction, 0);
+    assert_eq!(snapshot_keys(&vfs), ["a", "never-existed"]);
+
+    // the tombstones are still known after a restart
+    log_manager.shutdown().unwrap();
+    drop(log_manager);
+    let mut log_manager = open(&vfs, Duration::from_secs(3600));
+    assert_eq!(log_manager.tombstone_stats().live, 2);
+    assert_eq!(log_manager.search(&"a".to_string()), None);
+
+    // writing the key again clears its tombstone
+    log_manager.insert("a".to_string(), 2).unwrap();
+    assert_eq!(log_manager.tombstone_stats().live, 1);
+    assert_eq!(log_manager.search(&"a".to_string()), Some(2));
+}
+
+#[test]
+fn test_tombstones_collected_after_window() {
+    let vfs = Arc::new(MemFs::new());
+    let mut log_manager = open(&vfs, Duration::from_millis(50));
+
+    log_manager.insert("a".to_string(), 1).unwrap();
+    log_manager.delete(&"a".to_string()).unwrap();
+    log_manager.compact().unwrap();
+    assert_eq!(log_manager.tombstone_stats().retained_last_compaction, 1);
+
+    thread::sleep(Duration::from_millis(60));
+    log_manager.compact().unwrap();
+    let stats = log_manager.tombstone_stats();
+    assert_eq!(stats.live, 0);
+    assert_eq!(stats.collected_last_compaction, 1);
+    assert!(!log_content(&vfs).contains("DELETE"));
+    assert!(snapshot_keys(&vfs).is_empty());
+}
@@ -0,0 +1,83 @@
+use ddbb::error::Error;
+use ddbb::keycodec::{self, EncodedKey};
+use ddbb::log::LogManager;
+use ddbb::vfs::MemFs;
+use std::sync::Arc;
+
+type Events = LogManager<EncodedKey<(String, u64)>, String>;
+
+fn key(tenant: &str, time: u64) -> EncodedKey<(String, u64)> {
+    EncodedKey::new(&(tenant.to_string(), time))
+}
+
+fn keys<V>(pairs: Vec<(EncodedKey<(String, u64)>, V)>) -> Vec<(String, u64)> {
+    pairs.into_iter().map(|(key, _)| key.get()).collect()
+}
+
+#[test]
+fn test_encoded_key_text_form() {
+    let k = key("ann", 5);
+    assert_eq!(k.to_string(), "616e6e00010000000000000005");
+    assert_eq!(k.to_string().parse::<EncodedKey<(String, u64)>>().unwrap(), k);
+    assert_eq!(format!("{:?}", k), "EncodedKey((\"ann\", 5))");
+    // text order is key order
+    assert!(key("ann", 9) < key("ann", 10) && key("ann", 9).to_string() < key("ann", 10).to_string());
+    assert!(key("a", 10) < key("a\0", 9) && key("a\0", 9) < key("ab", 0));
+
+    for bad in ["", "6", "zz", "616e6e", "616e6e0001000000000000000500", "é1"] {
+        let parsed = bad.parse::<EncodedKey<(String, u64)>>();
+        assert!(matches!(parsed, Err(Error::InvalidArgument(_)) | Err(Error::Corruption(_))), "{:?}", bad);
+    }
+}
+
+#[test]
+fn test_prefix_scans() {
+    let vfs = Arc::new(MemFs::new());
+    let mut db: Events = LogManager::open(vfs.clone(), "db").unwrap();
+    for tenant in ["an", "ann", "anna", "bo"] {
+        for time in [100, 9, 10] {
+            db.insert(key(tenant, time), format!("{}@{}", tenant, time)).unwrap();
+        }
+    }
+    db.delete(&key("bo", 10)).unwrap();
+
+    let ann = vec![("ann".to_string(), 9), ("ann".to_string(), 10), ("ann".to_string(), 100)];
+    assert_eq!(keys(db.scan_prefix(&("ann".to_string(),))), ann);
+    assert_eq!(keys(db.scan_prefix(&("bo".to_string(),))), [("bo".to_string(), 9), ("bo".to_string(), 100)]);
+    assert_eq!(keys(db.scan_prefix(&("ann".to_string(), 10u64))), [("ann".to_string(), 10)]);
+    assert!(db.scan_prefix(&("cy".to_string(),)).is_empty());
+    assert_eq!(db.search(&key("anna", 9)), Some("anna@9".to_string()));
+    assert_eq!(keys(db.range(key("ann", 10)..key("anna", 10))), [
+        ("ann".to_string(), 10),
+        ("ann".to_string(), 100),
+        ("anna".to_string(), 9)
+    ]);
+
+    // the same after replaying the log, and after loading the snapshot
+    drop(db);
+    let mut db: Events = LogManager::open(vfs.clone(), "db").unwrap();
+    assert_eq!(keys(db.scan_prefix(&("ann".to_string(),))), ann);
+    assert_eq!(db.range(..).len(), 11);
+    db.compact().unwrap();
+    drop(db);
+    let db: Events = LogManager::open(vfs, "db").unwrap();
+    assert_eq!(keys(db.scan_prefix(&("ann".to_string(),))), ann);
+    assert_eq!(db.recovery_report().corrupt_records_skipped, 0);
+}
+
+#[test]
+fn test_prefix_range_at_the_top_byte() {
+    let mut db: LogManager<EncodedKey<(u8, u8)>, u32> = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    for a in [0u8, 254, 255] {
+        for b in [0u8, 255] {
+            db.insert(EncodedKey::new(&(a, b)), a as u32 * 1000 + b as u32).unwrap();
+        }
+    }
+    let scan = |db: &LogManager<EncodedKey<(u8, u8)>, u32>, prefix: &(u8,)| {
+        db.scan_prefix(prefix).into_iter().map(|(_, value)| value).collect::<Vec<_>>()
+    };
+    assert_eq!(scan(&db, &(254,)), [254000, 254255]);
+    assert_eq!(scan(&db, &(255,)), [255000, 255255]);
+    assert_eq!(scan(&db, &(0,)), [0, 255]);
+    assert_eq!(db.range(keycodec::prefix_range(&(255u8, 255u8))).len(), 1);
+}
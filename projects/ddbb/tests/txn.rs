@@ -0,0 +1,175 @@
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::txn::{Isolation, TransactionDb, TransactionOptions};
+use ddbb::vfs::MemFs;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn open() -> TransactionDb {
+    let db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    let lock_timeout = Duration::from_millis(50);
+    let options = TransactionOptions { lock_timeout, ..TransactionOptions::default() };
+    let db = TransactionDb::with_options(db, options);
+    let mut txn = db.begin();
+    txn.set("alice", "on").unwrap();
+    txn.set("bob", "on").unwrap();
+    txn.commit().unwrap();
+    db
+}
+
+fn timed_out(result: &ddbb::error::Result<()>) -> bool {
+    matches!(result, Err(Error::Io(e)) if e.kind() == ErrorKind::TimedOut)
+}
+
+#[test]
+fn test_read_committed() {
+    let db = open();
+    let mut reader = db.begin_with(Isolation::ReadCommitted);
+    assert_eq!(reader.isolation(), Isolation::ReadCommitted);
+    assert_eq!(reader.get("alice").unwrap().as_deref(), Some("on"));
+
+    // a writer does not wait for the reader, and the reader sees its commit
+    let mut writer = db.begin_with(Isolation::ReadCommitted);
+    writer.set("alice", "off").unwrap();
+    assert_eq!(reader.get("alice").unwrap().as_deref(), Some("on"));
+    writer.commit().unwrap();
+    assert_eq!(reader.get("alice").unwrap().as_deref(), Some("off"));
+
+    // writes still wait for each other
+    reader.set("bob", "off").unwrap();
+    let mut writer = db.begin_with(Isolation::ReadCommitted);
+    assert!(timed_out(&writer.set("bob", "on")));
+    reader.commit().unwrap();
+    writer.set("bob", "on").unwrap();
+    writer.commit().unwrap();
+    assert_eq!(db.versions(), 0);
+}
+
+#[test]
+fn test_snapshot_isolation() {
+    let db = open();
+    let mut reader = db.begin_with(Isolation::SnapshotIsolation);
+    let mut writer = db.begin_with(Isolation::ReadCommitted);
+    writer.set("alice", "off").unwrap();
+    writer.del("bob").unwrap();
+    writer.set("carol", "on").unwrap();
+    writer.commit().unwrap();
+    assert_eq!(db.versions(), 3);
+
+    // the reader sees none of the commit, a transaction that starts after it all of it
+    assert_eq!(reader.get("alice").unwrap().as_deref(), Some("on"));
+    assert_eq!(reader.get("bob").unwrap().as_deref(), Some("on"));
+    assert_eq!(reader.get("carol").unwrap(), None);
+    let mut later = db.begin_with(Isolation::SnapshotIsolation);
+    assert_eq!(later.get("alice").unwrap().as_deref(), Some("off"));
+    assert_eq!(later.get("carol").unwrap().as_deref(), Some("on"));
+    later.set("carol", "off").unwrap();
+
+    // writing a key committed since the snapshot is a conflict, the first to write wins
+    let conflict = reader.set("alice", "on");
+    assert!(matches!(&conflict, Err(Error::Conflict(_))), "{:?}", conflict);
+    reader.set("dave", "on").unwrap();
+    assert_eq!(reader.get("dave").unwrap().as_deref(), Some("on"));
+    reader.rollback();
+    later.commit().unwrap();
+    assert_eq!(db.versions(), 0);
+    assert_eq!(db.begin().get("carol").unwrap().as_deref(), Some("off"));
+}
+
+// How many doctors are on call: at least one of them must stay, so one can leave if it is two
+fn on_call(txn: &mut ddbb::txn::Transaction) -> usize {
+    ["alice", "bob"].iter().filter(|doctor| txn.get(doctor).unwrap().as_deref() == Some("on")).count()
+}
+
+#[test]
+fn test_write_skew() {
+    // under snapshot isolation both leave, as they write different keys
+    let db = open();
+    let mut first = db.begin_with(Isolation::SnapshotIsolation);
+    let mut second = db.begin_with(Isolation::SnapshotIsolation);
+    assert_eq!((on_call(&mut first), on_call(&mut second)), (2, 2));
+    first.set("alice", "off").unwrap();
+    second.set("bob", "off").unwrap();
+    first.commit().unwrap();
+    second.commit().unwrap();
+    let mut txn = db.begin();
+    let doctors = (txn.get("alice").unwrap(), txn.get("bob").unwrap());
+    assert_eq!(doctors, (Some("off".into()), Some("off".into())));
+    drop(txn);
+
+    // serializable transactions wait for each other's reads, one rolls back and the other leaves
+    let db = open();
+    let mut first = db.begin_with(Isolation::Serializable);
+    let mut second = db.begin_with(Isolation::Serializable);
+    assert_eq!((on_call(&mut first), on_call(&mut second)), (2, 2));
+    assert!(timed_out(&first.set("alice", "off")));
+    assert!(timed_out(&second.set("bob", "off")));
+    second.rollback();
+    first.set("alice", "off").unwrap();
+    first.commit().unwrap();
+    let mut txn = db.begin();
+    assert_eq!((txn.get("alice").unwrap(), txn.get("bob").unwrap()), (Some("off".into()), Some("on".into())));
+}
+
+#[test]
+fn test_optimistic() {
+    let db = open();
+    let mut first = db.begin_optimistic();
+    let mut second = db.begin_optimistic();
+    assert!(first.is_optimistic() && first.isolation() == Isolation::Serializable);
+    assert_eq!((on_call(&mut first), on_call(&mut second)), (2, 2));
+    first.set("alice", "off").unwrap();
+    second.set("bob", "off").unwrap();
+    // nothing is locked until the commit, and the write skew is caught there
+    assert!(db.locks().holders(&"alice".to_string()).is_empty());
+    first.commit().unwrap();
+    let conflict = second.commit();
+    let on_alice = |message: &str| message.contains("alice");
+    assert!(matches!(&conflict, Err(Error::Conflict(message)) if on_alice(message)), "{:?}", conflict);
+    let mut txn = db.begin();
+    assert_eq!(txn.get("bob").unwrap().as_deref(), Some("on"));
+    drop(txn);
+
+    // a commit of keys it did not read, or a write of a key it did not read, is no conflict
+    let mut txn = db.begin_optimistic();
+    assert_eq!(txn.get("alice").unwrap().as_deref(), Some("off"));
+    let mut other = db.begin_optimistic();
+    other.set("bob", "off").unwrap();
+    other.commit().unwrap();
+    assert_eq!(txn.get("bob").unwrap().as_deref(), Some("on"));
+    txn.commit().unwrap();
+    let mut txn = db.begin_optimistic();
+    txn.set("carol", "on").unwrap();
+    let mut other = db.begin();
+    other.set("carol", "off").unwrap();
+    other.commit().unwrap();
+    txn.commit().unwrap();
+    assert_eq!(db.versions(), 0);
+
+    // increments retried on conflicts lose none of them
+    let db = Arc::new(db);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    loop {
+                        let mut txn = db.begin_optimistic();
+                        let count: u64 = txn.get("count").unwrap().map_or(0, |count| count.parse().unwrap());
+                        txn.set("count", &(count + 1).to_string()).unwrap();
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(Error::Conflict(_)) => continue,
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(db.begin().get("count").unwrap().as_deref(), Some("100"));
+}
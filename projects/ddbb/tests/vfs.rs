@@ -0,0 +1,65 @@
+use ddbb::log::LogManager;
+use ddbb::vfs::{MemFs, OpenOptions, Vfs};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+#[test]
+fn test_memfs_read_write_rename() {
+    let vfs = MemFs::new();
+    let path = Path::new("db/a.txt");
+
+    let mut file = vfs
+        .open(path, OpenOptions::new().read(true).write(true).create(true))
+        .unwrap();
+    file.write_all(b"hello world").unwrap();
+    file.sync().unwrap();
+    assert_eq!(file.len().unwrap(), 11);
+
+    file.seek(SeekFrom::Start(6)).unwrap();
+    let mut buf = String::new();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "world");
+
+    vfs.rename(path, Path::new("db/b.txt")).unwrap();
+    assert!(!vfs.exists(path));
+    assert_eq!(vfs.read(Path::new("db/b.txt")).unwrap(), b"hello world");
+    assert_eq!(vfs.list(Path::new("db")).unwrap(), vec![Path::new("db/b.txt").to_path_buf()]);
+
+    // opening a missing file without `create` fails
+    assert!(vfs.open(path, OpenOptions::new().read(true)).is_err());
+}
+
+#[test]
+fn test_memfs_lock_is_exclusive() {
+    let vfs = MemFs::new();
+    let lock = vfs.lock(Path::new("LOCK")).unwrap();
+    assert!(vfs.lock(Path::new("LOCK")).is_err());
+    drop(lock);
+    assert!(vfs.lock(Path::new("LOCK")).is_ok());
+}
+
+#[test]
+fn test_log_manager_on_memfs() {
+    let vfs = Arc::new(MemFs::new());
+
+    let mut log_manager = LogManager::open(vfs.clone(), "db").unwrap();
+    for i in 1..=20 {
+        log_manager.insert(format!("key{}", i), i).unwrap();
+    }
+
+    // the directory is locked while the first manager is alive
+    assert!(LogManager::<String, i32>::open(vfs.clone(), "db").is_err());
+
+    log_manager.shutdown().unwrap();
+    assert!(!vfs.exists(Path::new("db/temp_log.txt")));
+    assert!(!vfs.exists(Path::new("db/dummy.txt")));
+
+    let log_manager2 = LogManager::<String, i32>::open(vfs.clone(), "db").unwrap();
+    for i in 1..=20 {
+        assert_eq!(log_manager2.search(&format!("key{}", i)), Some(i));
+    }
+
+    // nothing leaked onto the real file system
+    assert!(!Path::new("db").exists());
+}
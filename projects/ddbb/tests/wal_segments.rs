@@ -0,0 +1,130 @@
+mod common;
+
+use common::key;
+use ddbb::lsm::LsmTree;
+use ddbb::options::Options;
+use ddbb::vfs::{MemFs, PowerLoss, Vfs};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const DIR: &str = "db";
+
+fn wal_files(vfs: &MemFs) -> Vec<PathBuf> {
+    let paths = vfs.list(Path::new(DIR)).unwrap();
+    paths.into_iter().filter(|path| path.extension().is_some_and(|ext| ext == "wal")).collect()
+}
+
+fn segments() -> Options {
+    Options { write_buffer_size: 1024, wal_preallocate: true, wal_recycle: 2, ..Options::default() }
+}
+
+#[test]
+fn test_wal_segments_are_recycled() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, segments()).unwrap();
+    for i in 0..1000 {
+        tree.insert(&key(i % 300), format!("value{}", i).as_bytes()).unwrap();
+    }
+    let stats = tree.metrics().wal;
+    assert!(stats.recycled > 20, "{:?}", stats);
+    // the first WALs, before there was anything to recycle
+    assert!(stats.created <= 3, "{:?}", stats);
+    // the live WAL and at most wal_recycle waiting to be reused
+    assert!(wal_files(&vfs).len() <= 3);
+    tree.sync().unwrap();
+    drop(tree);
+
+    // the records a recycled WAL held before are not replayed
+    vfs.power_loss(PowerLoss::DropUnsynced);
+    let tree = LsmTree::open_with(vfs.clone(), DIR, segments()).unwrap();
+    for i in 700..1000 {
+        assert_eq!(tree.get(&key(i % 300)).unwrap(), Some(format!("value{}", i).into_bytes()));
+    }
+    assert!(wal_files(&vfs).len() <= 3);
+}
+
+#[test]
+fn test_wal_preallocated_to_write_buffer_size() {
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, segments()).unwrap();
+    let wals = wal_files(&vfs);
+    assert_eq!(wals.len(), 1);
+    assert_eq!(vfs.read(&wals[0]).unwrap(), vec![0; 1024]);
+
+    tree.insert(b"a", b"1").unwrap();
+    tree.sync().unwrap();
+    // written in place, the file keeps its size
+    assert_eq!(vfs.read(&wals[0]).unwrap().len(), 1024);
+    drop(tree);
+
+    // without preallocation WALs grow as they are written
+    let plain = Options { write_buffer_size: 1024, ..Options::default() };
+    let tree = LsmTree::open_with(vfs.clone(), DIR, plain).unwrap();
+    assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(vfs.read(&wal_files(&vfs)[0]).unwrap().len(), 0);
+}
+
+#[test]
+fn test_wal_recycling_crash_recovers_a_prefix() {
+    let mut rng = StdRng::seed_from_u64(23);
+    let ops: Vec<(Vec<u8>, Option<Vec<u8>>)> = (0..300)
+        .map(|i| {
+            let k = key(rng.gen_range(0..80));
+            (k, (!rng.gen_bool(0.2)).then(|| format!("v{}", i).into_bytes()))
+        })
+        .collect();
+    let mut states = vec![BTreeMap::new()];
+    for (k, v) in &ops {
+        let mut state = states.last().unwrap().clone();
+        match v {
+            Some(v) => state.insert(k.clone(), v.clone()),
+            None => state.remove(k),
+        };
+        states.push(state);
+    }
+
+    let run = |tree: &mut LsmTree, synced: &mut usize| -> ddbb::error::Result<()> {
+        for (i, (k, v)) in ops.iter().enumerate() {
+            match v {
+                Some(v) => tree.insert(k, v)?,
+                None => tree.delete(k)?,
+            }
+            if (i + 1) % 40 == 0 {
+                tree.sync()?;
+                *synced = i + 1;
+            }
+        }
+        Ok(())
+    };
+
+    let vfs = Arc::new(MemFs::new());
+    let mut tree = LsmTree::open_with(vfs.clone(), DIR, segments()).unwrap();
+    let start = vfs.mutations();
+    run(&mut tree, &mut 0).unwrap();
+    let total = vfs.mutations() - start;
+    assert!(tree.metrics().wal.recycled > 0);
+    drop(tree);
+
+    for crash_point in (0..total).step_by(3) {
+        let vfs = Arc::new(MemFs::new());
+        let mut tree = LsmTree::open_with(vfs.clone(), DIR, segments()).unwrap();
+        vfs.fail_after(crash_point);
+        let mut synced = 0;
+        assert!(run(&mut tree, &mut synced).is_err());
+        drop(tree);
+        vfs.power_loss(PowerLoss::TruncateUnsynced { seed: crash_point as u64 });
+
+        let tree = LsmTree::open_with(vfs.clone(), DIR, segments()).unwrap();
+        let recovered: BTreeMap<_, _> = tree.traverse().unwrap().into_iter().collect();
+        let prefix = states.iter().rposition(|state| *state == recovered);
+        assert!(
+            prefix.is_some_and(|p| p >= synced),
+            "crash point {}: recovered state is not a prefix after {} synced ops",
+            crash_point,
+            synced
+        );
+    }
+}
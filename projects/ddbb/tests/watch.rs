@@ -0,0 +1,130 @@
+mod common;
+
+use common::{start_server, start_server_with};
+use ddbb::auth::{Auth, Credentials};
+use ddbb::batch::WriteBatch;
+use ddbb::client::{Client, ClientOptions};
+use ddbb::error::Error;
+use ddbb::log::LogManager;
+use ddbb::server::ServerOptions;
+use ddbb::vfs::MemFs;
+use ddbb::watch::{Event, WATCH_CAPACITY};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn set(key: &str, value: &str) -> Event<String, String> {
+    Event::Set(key.to_string(), value.to_string())
+}
+
+#[test]
+fn test_watch() {
+    let mut db = LogManager::open(Arc::new(MemFs::new()), "db").unwrap();
+    db.insert("user:0".to_string(), "before".to_string()).unwrap();
+    let users = db.watch("user:");
+    let everything = db.watch("");
+    db.insert("user:1".to_string(), "ann".to_string()).unwrap();
+    db.insert("team:1".to_string(), "red".to_string()).unwrap();
+    db.delete(&"user:2".to_string()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert("user:3".to_string(), "cy".to_string()).delete("user:1".to_string());
+    db.write_batch(batch).unwrap();
+
+    let (deleted_1, deleted_2) = (Event::Delete("user:1".to_string()), Event::Delete("user:2".to_string()));
+    let expected = [set("user:1", "ann"), deleted_2, set("user:3", "cy"), deleted_1];
+    let seen: Vec<_> = std::iter::from_fn(|| users.try_recv()).collect();
+    assert_eq!(seen, expected);
+    assert_eq!(std::iter::from_fn(|| everything.try_recv()).count(), 5);
+
+    // a write that failed is no event
+    db.create_unique_index("value", |value: &String| Some(value.clone())).unwrap();
+    assert!(db.insert("user:4".to_string(), "cy".to_string()).is_err());
+    assert_eq!(users.try_recv(), None);
+
+    // a watch that is dropped is forgotten, one that falls behind ends
+    drop(everything);
+    for i in 0..WATCH_CAPACITY + 10 {
+        db.insert(format!("user:{}", i + 10), i.to_string()).unwrap();
+    }
+    assert_eq!(users.count(), WATCH_CAPACITY);
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(address: SocketAddr) -> Self {
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Connection { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream }
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    fn request(&mut self, request: &str) -> String {
+        writeln!(self.writer, "{}", request).unwrap();
+        self.line()
+    }
+}
+
+#[test]
+fn test_subscribe() {
+    let address = start_server();
+    let mut subscriber = Connection::open(address);
+    let mut writer = Connection::open(address);
+    assert!(subscriber.request("SUBSCRIBE a b").starts_with("ERR invalid argument: SUBSCRIBE takes"));
+    assert_eq!(subscriber.request("SUBSCRIBE user:"), "OK");
+    assert_eq!(writer.request("SET user:1 ann"), "OK");
+    assert_eq!(writer.request("SET team:1 red"), "OK");
+    assert_eq!(writer.request("DEL user:1"), "OK");
+    assert_eq!(subscriber.line(), "EVENT SET user:1 ann");
+    assert_eq!(subscriber.line(), "EVENT DEL user:1");
+
+    assert!(subscriber.request("GET user:1").starts_with("ERR invalid argument: only UNSUBSCRIBE and QUIT"));
+    assert_eq!(subscriber.request("unsubscribe"), "OK");
+    assert_eq!(writer.request("SET user:2 bo"), "OK");
+    assert_eq!(subscriber.request("GET user:2"), "VALUE bo");
+
+    // every key, and a QUIT that ends it all
+    assert_eq!(subscriber.request("SUBSCRIBE"), "OK");
+    assert_eq!(writer.request("SET team:2 blue"), "OK");
+    assert_eq!(subscriber.line(), "EVENT SET team:2 blue");
+    assert_eq!(subscriber.request("QUIT"), "OK");
+    assert_eq!(subscriber.line(), "");
+}
+
+#[test]
+fn test_client() {
+    let auth = Auth::parse("token admin read-write\ntoken app read-only app:\n").unwrap();
+    let address = start_server_with(ServerOptions { auth: Some(auth), ..ServerOptions::default() });
+    let options = |token: &str| ClientOptions {
+        credentials: Some(Credentials::Token(token.to_string())),
+        io_timeout: Some(Duration::from_millis(200)),
+        ..ClientOptions::default()
+    };
+    let app = Client::connect_with(address, options("app")).unwrap();
+    assert!(matches!(app.subscribe("other:"), Err(Error::PermissionDenied(_))));
+    assert!(matches!(app.subscribe(""), Err(Error::PermissionDenied(_))));
+    assert!(matches!(app.subscribe("a b"), Err(Error::InvalidArgument(_))));
+    let mut subscription = app.subscribe("app:").unwrap();
+
+    let mut admin = Client::connect_with(address, options("admin")).unwrap();
+    admin.set("other:1", "x").unwrap();
+    let writes = thread::spawn(move || {
+        // longer than the io_timeout of the subscription, which does not apply to events
+        thread::sleep(Duration::from_millis(400));
+        admin.set("app:1", "ann").unwrap();
+        admin.del("app:1").unwrap();
+    });
+    assert_eq!(subscription.recv().unwrap(), set("app:1", "ann"));
+    assert_eq!(subscription.recv().unwrap(), Event::Delete("app:1".to_string()));
+    writes.join().unwrap();
+}